@@ -1,30 +1,371 @@
-use log::error;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{error, warn};
 use reqwest::Client;
+use ring::digest::{digest, SHA256};
+use tokio::time::sleep;
 
 use plugin_types::authenticator::*;
 
-pub async fn connect<'a>(
-    url: &String,
-    req: LoginRequest<'a>,
-) -> AuthenticatorResult<LoginResponse> {
-    match Client::new().post(url.clone()).json(&req).send().await {
-        Ok(res) => res.json().await.or_else(|_| {
-            Ok(LoginResponse {
-                connection_allowed: false,
-                max_packet_size: None,
-                topics_acl: None,
-            })
-        }),
-        Err(err) => {
-            error!(
-                "[Authenticator Worker]: Authentication Endpoint Error. {:?}",
-                err
+/// Delay before the first retry, doubled for every next one.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+/// Records are logged under the authentication target of the broker, so they are
+/// filtered along with other records of the authentication path.
+const LOG_TARGET: &str = "telemq::auth";
+/// Expired responses are evicted once the cache grows over this size.
+const MAX_CACHE_ENTRIES: usize = 100_000;
+
+pub struct HttpAuthenticatorConfig {
+    /// Max time of a single request to the endpoint.
+    pub timeout: Duration,
+    /// Requests failed with a transport error or a 5xx status are retried this many times.
+    pub max_retries: usize,
+    /// If `None`, allowed logins are not cached.
+    pub cache_ttl: Option<Duration>,
+    /// If `None`, denied logins are not cached.
+    pub negative_cache_ttl: Option<Duration>,
+    /// If `true`, the last allowed login of every client is kept, so it can be reused
+    /// while the endpoint is unreachable.
+    pub keep_known_clients: bool,
+}
+
+impl Default for HttpAuthenticatorConfig {
+    fn default() -> Self {
+        HttpAuthenticatorConfig {
+            timeout: Duration::from_secs(5),
+            max_retries: 2,
+            cache_ttl: None,
+            negative_cache_ttl: None,
+            keep_known_clients: false,
+        }
+    }
+}
+
+/// Client id and a digest of credentials, so passwords are not kept in memory.
+type CacheKey = (String, Vec<u8>);
+
+struct CachedResponse {
+    response: LoginResponse,
+    expires_at: Instant,
+}
+
+/// Authenticates clients by an external HTTP endpoint. Responses of the endpoint may be
+/// cached, so a slow endpoint only delays first connections of clients.
+pub struct HttpAuthenticator {
+    url: String,
+    client: Client,
+    config: HttpAuthenticatorConfig,
+    cache: Mutex<HashMap<CacheKey, CachedResponse>>,
+    /// Last allowed responses, they never expire.
+    known_clients: Mutex<HashMap<CacheKey, LoginResponse>>,
+}
+
+impl HttpAuthenticator {
+    pub fn new(url: String, config: HttpAuthenticatorConfig) -> reqwest::Result<Self> {
+        let client = Client::builder().timeout(config.timeout).build()?;
+
+        Ok(HttpAuthenticator {
+            url,
+            client,
+            config,
+            cache: Mutex::new(HashMap::new()),
+            known_clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Fails if the endpoint is unreachable, i.e. requests failed and retries are exhausted.
+    pub async fn connect<'a>(&self, req: LoginRequest<'a>) -> AuthenticatorResult<LoginResponse> {
+        let key = cache_key(&req);
+        if let Some(response) = self.cached(&key) {
+            return Ok(response);
+        }
+
+        match self.request(&req).await {
+            Ok(response) => {
+                self.remember(&key, &response);
+                self.cache(key, &response);
+                Ok(response)
+            }
+            Err(err) => {
+                error!(
+                target: LOG_TARGET,
+                    "[Authenticator Worker]: Authentication Endpoint Error. {}",
+                    err
+                );
+                Err(AuthenticatorError)
+            }
+        }
+    }
+
+    /// The last response which allowed a client with the same credentials, if known clients
+    /// are kept.
+    pub fn known_client<'a>(&self, req: &LoginRequest<'a>) -> Option<LoginResponse> {
+        let known_clients = self.known_clients.lock().unwrap();
+        known_clients.get(&cache_key(req)).cloned()
+    }
+
+    async fn request<'a>(&self, req: &LoginRequest<'a>) -> Result<LoginResponse, String> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.client.post(&self.url).json(req).send().await {
+                Ok(res) if res.status().is_server_error() => format!("status {}", res.status()),
+                Ok(res) => {
+                    return res
+                        .json()
+                        .await
+                        .map_err(|err| format!("unexpected response. {:?}", err));
+                }
+                Err(err) => format!("{:?}", err),
+            };
+
+            if attempt >= self.config.max_retries {
+                return Err(err);
+            }
+            let backoff = RETRY_BACKOFF * 2u32.saturating_pow(attempt as u32);
+            warn!(
+                target: LOG_TARGET,
+                "[Authenticator Worker]: Authentication Endpoint request failed, {}. Retrying in {:?}",
+                err, backoff
             );
-            Ok(LoginResponse {
-                connection_allowed: false,
-                max_packet_size: None,
-                topics_acl: None,
-            })
+            sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    fn remember(&self, key: &CacheKey, response: &LoginResponse) {
+        if !self.config.keep_known_clients {
+            return;
+        }
+
+        let mut known_clients = self.known_clients.lock().unwrap();
+        if !response.connection_allowed {
+            // credentials revoked by the endpoint are not reused
+            known_clients.remove(key);
+            return;
+        }
+        if known_clients.len() >= MAX_CACHE_ENTRIES && !known_clients.contains_key(key) {
+            return;
+        }
+        known_clients.insert(key.clone(), response.clone());
+    }
+
+    fn cached(&self, key: &CacheKey) -> Option<LoginResponse> {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(key)
+            .filter(|cached| cached.expires_at > Instant::now())
+            .map(|cached| cached.response.clone())
+    }
+
+    fn cache(&self, key: CacheKey, response: &LoginResponse) {
+        let ttl = if response.connection_allowed {
+            self.config.cache_ttl
+        } else {
+            self.config.negative_cache_ttl
+        };
+        let ttl = match ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.retain(|_, cached| cached.expires_at > now);
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                cache.clear();
+            }
+        }
+        cache.insert(
+            key,
+            CachedResponse {
+                response: response.clone(),
+                expires_at: now + ttl,
+            },
+        );
+    }
+}
+
+fn cache_key(req: &LoginRequest) -> CacheKey {
+    let mut credentials = Vec::new();
+    for field in [req.username, req.password, req.tls_fingerprint] {
+        match field {
+            // lengths keep ("ab", "c") and ("a", "bc") apart
+            Some(value) => {
+                credentials.push(1);
+                credentials.extend_from_slice(&(value.len() as u64).to_be_bytes());
+                credentials.extend_from_slice(value.as_bytes());
+            }
+            None => credentials.push(0),
+        }
+    }
+
+    (
+        req.client_id.clone(),
+        digest(&SHA256, &credentials).as_ref().to_vec(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serves `statuses` in turn, the last one is repeated. Returns the endpoint url and
+    /// a number of served requests.
+    async fn endpoint(statuses: Vec<u16>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/login", listener.local_addr().unwrap());
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = served.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                let status = statuses[n.min(statuses.len() - 1)];
+                let mut buf = vec![0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let body = r#"{"connectionAllowed":true,"topicsAcl":null,"maxPacketSize":null}"#;
+                let response = format!(
+                    "HTTP/1.1 {} X\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        (url, served)
+    }
+
+    fn login<'a>(
+        client_id: &'a String,
+        password: &'a Option<String>,
+        socket_addr: &'a String,
+    ) -> LoginRequest<'a> {
+        LoginRequest {
+            socket_addr,
+            client_id,
+            username: &None,
+            password,
+            tls_fingerprint: &None,
         }
     }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (url, served) = endpoint(vec![503, 503, 200]).await;
+        let authenticator =
+            HttpAuthenticator::new(url, HttpAuthenticatorConfig::default()).unwrap();
+        let (client_id, password, addr) = ("device".into(), Some("secret".into()), "a".into());
+
+        let response = authenticator
+            .connect(login(&client_id, &password, &addr))
+            .await
+            .unwrap();
+        assert!(response.connection_allowed);
+        assert_eq!(served.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_are_bounded() {
+        let (url, served) = endpoint(vec![500]).await;
+        let config = HttpAuthenticatorConfig {
+            max_retries: 1,
+            ..HttpAuthenticatorConfig::default()
+        };
+        let authenticator = HttpAuthenticator::new(url, config).unwrap();
+        let (client_id, password, addr) = ("device".into(), Some("secret".into()), "a".into());
+
+        let response = authenticator
+            .connect(login(&client_id, &password, &addr))
+            .await;
+        assert!(response.is_err());
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn responses_are_cached_per_credentials() {
+        let (url, served) = endpoint(vec![200]).await;
+        let config = HttpAuthenticatorConfig {
+            cache_ttl: Some(Duration::from_secs(60)),
+            ..HttpAuthenticatorConfig::default()
+        };
+        let authenticator = HttpAuthenticator::new(url, config).unwrap();
+        let (client_id, addr) = ("device".into(), "a".into());
+        let (password, other_password) = (Some("secret".into()), Some("other".into()));
+
+        for _ in 0..2 {
+            authenticator
+                .connect(login(&client_id, &password, &addr))
+                .await
+                .unwrap();
+        }
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+
+        authenticator
+            .connect(login(&client_id, &other_password, &addr))
+            .await
+            .unwrap();
+        assert_eq!(
+            served.load(Ordering::SeqCst),
+            2,
+            "other credentials should not hit the cache"
+        );
+    }
+
+    #[tokio::test]
+    async fn known_clients_are_kept_for_unreachable_endpoint() {
+        let (url, _) = endpoint(vec![200, 500]).await;
+        let config = HttpAuthenticatorConfig {
+            max_retries: 0,
+            keep_known_clients: true,
+            ..HttpAuthenticatorConfig::default()
+        };
+        let authenticator = HttpAuthenticator::new(url, config).unwrap();
+        let (client_id, addr) = ("device".into(), "a".into());
+        let (password, other_password) = (Some("secret".into()), Some("other".into()));
+
+        let known = login(&client_id, &password, &addr);
+        assert!(authenticator.connect(known.clone()).await.is_ok());
+        assert!(authenticator.connect(known.clone()).await.is_err());
+        assert!(authenticator
+            .known_client(&known)
+            .is_some_and(|response| response.connection_allowed));
+        assert!(authenticator
+            .known_client(&login(&client_id, &other_password, &addr))
+            .is_none());
+    }
+
+    #[test]
+    fn cache_key_separates_fields() {
+        let (client_id, addr) = ("device".to_string(), "a".to_string());
+        let (ab, c) = (Some("ab".to_string()), Some("c".to_string()));
+        let (a, bc) = (Some("a".to_string()), Some("bc".to_string()));
+
+        let key = |username: &Option<String>, password: &Option<String>| {
+            cache_key(&LoginRequest {
+                socket_addr: &addr,
+                client_id: &client_id,
+                username,
+                password,
+                tls_fingerprint: &None,
+            })
+        };
+        assert_ne!(key(&ab, &c), key(&a, &bc));
+        assert_ne!(key(&None, &c), key(&c, &None));
+    }
 }
@@ -6,5 +6,15 @@ extern crate regex;
 extern crate serde;
 extern crate tokio_util;
 
+/// Target of records logged by codecs, levels of which can be set apart from other records.
+pub const LOG_TARGET: &str = "mqtt_packets::codec";
+
+mod packet_codec_error;
+pub use self::packet_codec_error::PacketCodecError;
+
+#[cfg(feature = "mqtt_sn")]
+pub mod mqtt_sn;
 #[cfg(feature = "v_3_1_1")]
 pub mod v_3_1_1;
+#[cfg(feature = "v_5_0")]
+pub mod v_5_0;
@@ -0,0 +1,121 @@
+use std::io;
+
+use super::malformed;
+use crate::v_3_1_1::QoS;
+
+/// How a topic is identified by PUBLISH, SUBSCRIBE, UNSUBSCRIBE and SUBACK messages.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TopicIdType {
+    /// A topic id registered by REGISTER (a topic name in SUBSCRIBE and UNSUBSCRIBE).
+    Normal,
+    /// A topic id agreed on beforehand by a client and a gateway.
+    Predefined,
+    /// A topic name of two characters sent in place of a topic id.
+    Short,
+}
+
+impl TopicIdType {
+    fn bits(&self) -> u8 {
+        match *self {
+            TopicIdType::Normal => 0b00,
+            TopicIdType::Predefined => 0b01,
+            TopicIdType::Short => 0b10,
+        }
+    }
+}
+
+/// The Flags field of CONNECT, PUBLISH, SUBSCRIBE, UNSUBSCRIBE and SUBACK messages.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Flags {
+    pub dup: bool,
+    /// `None` is QoS -1, a publish of a client which is not connected.
+    pub qos: Option<QoS>,
+    pub retain: bool,
+    pub will: bool,
+    pub clean_session: bool,
+    pub topic_id_type: TopicIdType,
+}
+
+impl Flags {
+    const DUP_MASK: u8 = 0b1000_0000;
+    const QOS_MASK: u8 = 0b0110_0000;
+    const RETAIN_MASK: u8 = 0b0001_0000;
+    const WILL_MASK: u8 = 0b0000_1000;
+    const CLEAN_SESSION_MASK: u8 = 0b0000_0100;
+    const TOPIC_ID_TYPE_MASK: u8 = 0b0000_0011;
+    const QOS_MINUS_ONE: u8 = 0b11;
+
+    pub fn new(qos: Option<QoS>, topic_id_type: TopicIdType) -> Self {
+        Flags {
+            dup: false,
+            qos,
+            retain: false,
+            will: false,
+            clean_session: false,
+            topic_id_type,
+        }
+    }
+
+    pub fn encode(&self) -> u8 {
+        let qos = match self.qos {
+            Some(ref qos) => qos.bits(),
+            None => Self::QOS_MINUS_ONE,
+        };
+        let mut flags = qos << 5 | self.topic_id_type.bits();
+        for (is_set, mask) in [
+            (self.dup, Self::DUP_MASK),
+            (self.retain, Self::RETAIN_MASK),
+            (self.will, Self::WILL_MASK),
+            (self.clean_session, Self::CLEAN_SESSION_MASK),
+        ] {
+            if is_set {
+                flags |= mask;
+            }
+        }
+
+        flags
+    }
+
+    pub fn decode(flags: u8) -> io::Result<Self> {
+        let qos = match (flags & Self::QOS_MASK) >> 5 {
+            Self::QOS_MINUS_ONE => None,
+            bits => Some(QoS::try_from(bits)?),
+        };
+        let topic_id_type = match flags & Self::TOPIC_ID_TYPE_MASK {
+            0b00 => TopicIdType::Normal,
+            0b01 => TopicIdType::Predefined,
+            0b10 => TopicIdType::Short,
+            _ => return Err(malformed("reserved topic id type 0b11")),
+        };
+
+        Ok(Flags {
+            dup: flags & Self::DUP_MASK != 0,
+            qos,
+            retain: flags & Self::RETAIN_MASK != 0,
+            will: flags & Self::WILL_MASK != 0,
+            clean_session: flags & Self::CLEAN_SESSION_MASK != 0,
+            topic_id_type,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_round_trip() {
+        let flags = Flags {
+            dup: true,
+            qos: None,
+            retain: true,
+            will: false,
+            clean_session: true,
+            topic_id_type: TopicIdType::Short,
+        };
+        assert_eq!(flags.encode(), 0b1111_0110);
+        assert_eq!(Flags::decode(flags.encode()).unwrap(), flags);
+
+        assert!(Flags::decode(0b0000_0011).is_err());
+    }
+}
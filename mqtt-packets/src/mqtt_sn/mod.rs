@@ -0,0 +1,687 @@
+//! MQTT-SN 1.2 messages, MQTT for sensor networks over datagram transports (e.g. UDP).
+//!
+//! A message is a whole datagram. Topic names are mostly replaced by two byte topic ids,
+//! which a client registers with a gateway by REGISTER, so a gateway translates messages
+//! into MQTT packets and back. Messages of the will exchange and of the forwarder
+//! encapsulation are not supported.
+pub mod flags;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::trace;
+use std::io;
+
+use crate::v_3_1_1::QoS;
+use crate::{PacketCodecError, LOG_TARGET};
+
+pub use self::flags::{Flags, TopicIdType};
+
+/// The only Protocol Id of CONNECT.
+pub const PROTOCOL_ID: u8 = 0x01;
+/// A topic id of SUBACK to a topic filter with wildcards, which has no id.
+pub const NO_TOPIC_ID: u16 = 0x0000;
+
+fn malformed<T: AsRef<str>>(message: T) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Malformed MQTT-SN message: {}", message.as_ref()),
+    )
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReturnCode {
+    Accepted,
+    Congestion,
+    InvalidTopicId,
+    NotSupported,
+}
+
+impl ReturnCode {
+    fn encode(&self) -> u8 {
+        match *self {
+            ReturnCode::Accepted => 0x00,
+            ReturnCode::Congestion => 0x01,
+            ReturnCode::InvalidTopicId => 0x02,
+            ReturnCode::NotSupported => 0x03,
+        }
+    }
+
+    fn decode(src: &mut BytesMut) -> io::Result<Self> {
+        match decode_u8(src)? {
+            0x00 => Ok(ReturnCode::Accepted),
+            0x01 => Ok(ReturnCode::Congestion),
+            0x02 => Ok(ReturnCode::InvalidTopicId),
+            0x03 => Ok(ReturnCode::NotSupported),
+            code => Err(malformed(format!("unknown return code {:#04x}", code))),
+        }
+    }
+}
+
+/// A topic of PUBLISH.
+#[derive(Debug, PartialEq, Clone)]
+pub enum TopicId {
+    /// Registered by REGISTER or assigned by SUBACK.
+    Normal(u16),
+    Predefined(u16),
+    Short([u8; 2]),
+}
+
+impl TopicId {
+    fn id_type(&self) -> TopicIdType {
+        match *self {
+            TopicId::Normal(_) => TopicIdType::Normal,
+            TopicId::Predefined(_) => TopicIdType::Predefined,
+            TopicId::Short(_) => TopicIdType::Short,
+        }
+    }
+
+    fn encode(&self, dst: &mut BytesMut) {
+        match *self {
+            TopicId::Normal(id) | TopicId::Predefined(id) => dst.put_u16(id),
+            TopicId::Short(name) => dst.put_slice(&name),
+        }
+    }
+
+    fn decode(id_type: TopicIdType, src: &mut BytesMut) -> io::Result<Self> {
+        let id = decode_u16(src)?;
+        Ok(match id_type {
+            TopicIdType::Normal => TopicId::Normal(id),
+            TopicIdType::Predefined => TopicId::Predefined(id),
+            TopicIdType::Short => TopicId::Short(id.to_be_bytes()),
+        })
+    }
+}
+
+/// A topic of SUBSCRIBE and UNSUBSCRIBE.
+#[derive(Debug, PartialEq, Clone)]
+pub enum TopicFilter {
+    /// A topic name or a topic filter with wildcards.
+    Name(String),
+    Predefined(u16),
+    Short([u8; 2]),
+}
+
+impl TopicFilter {
+    fn id_type(&self) -> TopicIdType {
+        match *self {
+            TopicFilter::Name(_) => TopicIdType::Normal,
+            TopicFilter::Predefined(_) => TopicIdType::Predefined,
+            TopicFilter::Short(_) => TopicIdType::Short,
+        }
+    }
+
+    fn encode(&self, dst: &mut BytesMut) {
+        match *self {
+            TopicFilter::Name(ref name) => dst.put_slice(name.as_bytes()),
+            TopicFilter::Predefined(id) => dst.put_u16(id),
+            TopicFilter::Short(name) => dst.put_slice(&name),
+        }
+    }
+
+    fn decode(id_type: TopicIdType, src: &mut BytesMut) -> io::Result<Self> {
+        Ok(match id_type {
+            TopicIdType::Normal => TopicFilter::Name(decode_rest_string(src)?),
+            TopicIdType::Predefined => TopicFilter::Predefined(decode_u16(src)?),
+            TopicIdType::Short => TopicFilter::Short(decode_u16(src)?.to_be_bytes()),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Connect {
+    pub will: bool,
+    pub clean_session: bool,
+    /// Keep alive timer in seconds.
+    pub duration: u16,
+    pub client_id: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Register {
+    /// `0x0000` if sent by a client, a gateway assigns an id in REGACK.
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub topic_name: String,
+}
+
+/// REGACK and PUBACK.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ack {
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub return_code: ReturnCode,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Publish {
+    pub dup: bool,
+    /// `None` is QoS -1, a publish of a client which is not connected.
+    pub qos: Option<QoS>,
+    pub retain: bool,
+    pub topic_id: TopicId,
+    /// `0x0000` for QoS 0 and -1.
+    pub msg_id: u16,
+    pub data: Bytes,
+}
+
+/// SUBSCRIBE and UNSUBSCRIBE, QoS is ignored by the latter.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Subscribe {
+    pub dup: bool,
+    pub qos: QoS,
+    pub msg_id: u16,
+    pub topic: TopicFilter,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Suback {
+    /// Granted QoS.
+    pub qos: QoS,
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub return_code: ReturnCode,
+}
+
+/// MQTT-SN message.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Packet {
+    Advertise {
+        gw_id: u8,
+        duration: u16,
+    },
+    SearchGw {
+        radius: u8,
+    },
+    /// `gw_add` is an address of a gateway, present only if sent by a client.
+    GwInfo {
+        gw_id: u8,
+        gw_add: Vec<u8>,
+    },
+    Connect(Connect),
+    Connack(ReturnCode),
+    Register(Register),
+    Regack(Ack),
+    Publish(Publish),
+    Puback(Ack),
+    Pubcomp(u16),
+    Pubrec(u16),
+    Pubrel(u16),
+    Subscribe(Subscribe),
+    Suback(Suback),
+    Unsubscribe(Subscribe),
+    Unsuback(u16),
+    /// A client id is present if a sleeping client wakes up.
+    Pingreq(Option<String>),
+    Pingresp,
+    /// A duration is present if a client goes to sleep.
+    Disconnect(Option<u16>),
+}
+
+impl Packet {
+    const ADVERTISE: u8 = 0x00;
+    const SEARCHGW: u8 = 0x01;
+    const GWINFO: u8 = 0x02;
+    const CONNECT: u8 = 0x04;
+    const CONNACK: u8 = 0x05;
+    const REGISTER: u8 = 0x0A;
+    const REGACK: u8 = 0x0B;
+    const PUBLISH: u8 = 0x0C;
+    const PUBACK: u8 = 0x0D;
+    const PUBCOMP: u8 = 0x0E;
+    const PUBREC: u8 = 0x0F;
+    const PUBREL: u8 = 0x10;
+    const SUBSCRIBE: u8 = 0x12;
+    const SUBACK: u8 = 0x13;
+    const UNSUBSCRIBE: u8 = 0x14;
+    const UNSUBACK: u8 = 0x15;
+    const PINGREQ: u8 = 0x16;
+    const PINGRESP: u8 = 0x17;
+    const DISCONNECT: u8 = 0x18;
+
+    /// The first Length byte is `0x01` if a length is encoded by the next two bytes.
+    const THREE_BYTE_LENGTH: u8 = 0x01;
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Packet::Advertise { .. } => "ADVERTISE",
+            Packet::SearchGw { .. } => "SEARCHGW",
+            Packet::GwInfo { .. } => "GWINFO",
+            Packet::Connect(_) => "CONNECT",
+            Packet::Connack(_) => "CONNACK",
+            Packet::Register(_) => "REGISTER",
+            Packet::Regack(_) => "REGACK",
+            Packet::Publish(_) => "PUBLISH",
+            Packet::Puback(_) => "PUBACK",
+            Packet::Pubcomp(_) => "PUBCOMP",
+            Packet::Pubrec(_) => "PUBREC",
+            Packet::Pubrel(_) => "PUBREL",
+            Packet::Subscribe(_) => "SUBSCRIBE",
+            Packet::Suback(_) => "SUBACK",
+            Packet::Unsubscribe(_) => "UNSUBSCRIBE",
+            Packet::Unsuback(_) => "UNSUBACK",
+            Packet::Pingreq(_) => "PINGREQ",
+            Packet::Pingresp => "PINGRESP",
+            Packet::Disconnect(_) => "DISCONNECT",
+        }
+    }
+
+    fn msg_type(&self) -> u8 {
+        match *self {
+            Packet::Advertise { .. } => Self::ADVERTISE,
+            Packet::SearchGw { .. } => Self::SEARCHGW,
+            Packet::GwInfo { .. } => Self::GWINFO,
+            Packet::Connect(_) => Self::CONNECT,
+            Packet::Connack(_) => Self::CONNACK,
+            Packet::Register(_) => Self::REGISTER,
+            Packet::Regack(_) => Self::REGACK,
+            Packet::Publish(_) => Self::PUBLISH,
+            Packet::Puback(_) => Self::PUBACK,
+            Packet::Pubcomp(_) => Self::PUBCOMP,
+            Packet::Pubrec(_) => Self::PUBREC,
+            Packet::Pubrel(_) => Self::PUBREL,
+            Packet::Subscribe(_) => Self::SUBSCRIBE,
+            Packet::Suback(_) => Self::SUBACK,
+            Packet::Unsubscribe(_) => Self::UNSUBSCRIBE,
+            Packet::Unsuback(_) => Self::UNSUBACK,
+            Packet::Pingreq(_) => Self::PINGREQ,
+            Packet::Pingresp => Self::PINGRESP,
+            Packet::Disconnect(_) => Self::DISCONNECT,
+        }
+    }
+
+    fn encode_body(&self, dst: &mut BytesMut) {
+        match *self {
+            Packet::Advertise { gw_id, duration } => {
+                dst.put_u8(gw_id);
+                dst.put_u16(duration);
+            }
+            Packet::SearchGw { radius } => dst.put_u8(radius),
+            Packet::GwInfo { gw_id, ref gw_add } => {
+                dst.put_u8(gw_id);
+                dst.put_slice(gw_add);
+            }
+            Packet::Connect(ref connect) => {
+                let mut flags = Flags::new(Some(QoS::Zero), TopicIdType::Normal);
+                flags.will = connect.will;
+                flags.clean_session = connect.clean_session;
+                dst.put_u8(flags.encode());
+                dst.put_u8(PROTOCOL_ID);
+                dst.put_u16(connect.duration);
+                dst.put_slice(connect.client_id.as_bytes());
+            }
+            Packet::Connack(ref return_code) => dst.put_u8(return_code.encode()),
+            Packet::Register(ref register) => {
+                dst.put_u16(register.topic_id);
+                dst.put_u16(register.msg_id);
+                dst.put_slice(register.topic_name.as_bytes());
+            }
+            Packet::Regack(ref ack) | Packet::Puback(ref ack) => {
+                dst.put_u16(ack.topic_id);
+                dst.put_u16(ack.msg_id);
+                dst.put_u8(ack.return_code.encode());
+            }
+            Packet::Publish(ref publish) => {
+                let mut flags = Flags::new(publish.qos.clone(), publish.topic_id.id_type());
+                flags.dup = publish.dup;
+                flags.retain = publish.retain;
+                dst.put_u8(flags.encode());
+                publish.topic_id.encode(dst);
+                dst.put_u16(publish.msg_id);
+                dst.put_slice(&publish.data);
+            }
+            Packet::Pubcomp(msg_id)
+            | Packet::Pubrec(msg_id)
+            | Packet::Pubrel(msg_id)
+            | Packet::Unsuback(msg_id) => dst.put_u16(msg_id),
+            Packet::Subscribe(ref subscribe) | Packet::Unsubscribe(ref subscribe) => {
+                let mut flags = Flags::new(Some(subscribe.qos.clone()), subscribe.topic.id_type());
+                flags.dup = subscribe.dup;
+                dst.put_u8(flags.encode());
+                dst.put_u16(subscribe.msg_id);
+                subscribe.topic.encode(dst);
+            }
+            Packet::Suback(ref suback) => {
+                dst.put_u8(Flags::new(Some(suback.qos.clone()), TopicIdType::Normal).encode());
+                dst.put_u16(suback.topic_id);
+                dst.put_u16(suback.msg_id);
+                dst.put_u8(suback.return_code.encode());
+            }
+            Packet::Pingreq(ref client_id) => {
+                if let Some(ref client_id) = *client_id {
+                    dst.put_slice(client_id.as_bytes());
+                }
+            }
+            Packet::Pingresp => {}
+            Packet::Disconnect(duration) => {
+                if let Some(duration) = duration {
+                    dst.put_u16(duration);
+                }
+            }
+        }
+    }
+
+    fn decode_body(msg_type: u8, src: &mut BytesMut) -> io::Result<Self> {
+        let packet = match msg_type {
+            Self::ADVERTISE => Packet::Advertise {
+                gw_id: decode_u8(src)?,
+                duration: decode_u16(src)?,
+            },
+            Self::SEARCHGW => Packet::SearchGw {
+                radius: decode_u8(src)?,
+            },
+            Self::GWINFO => Packet::GwInfo {
+                gw_id: decode_u8(src)?,
+                gw_add: src.split().to_vec(),
+            },
+            Self::CONNECT => {
+                let flags = Flags::decode(decode_u8(src)?)?;
+                let protocol_id = decode_u8(src)?;
+                if protocol_id != PROTOCOL_ID {
+                    return Err(malformed(format!("unknown protocol id {}", protocol_id)));
+                }
+                Packet::Connect(Connect {
+                    will: flags.will,
+                    clean_session: flags.clean_session,
+                    duration: decode_u16(src)?,
+                    client_id: decode_rest_string(src)?,
+                })
+            }
+            Self::CONNACK => Packet::Connack(ReturnCode::decode(src)?),
+            Self::REGISTER => Packet::Register(Register {
+                topic_id: decode_u16(src)?,
+                msg_id: decode_u16(src)?,
+                topic_name: decode_rest_string(src)?,
+            }),
+            Self::REGACK => Packet::Regack(decode_ack(src)?),
+            Self::PUBLISH => {
+                let flags = Flags::decode(decode_u8(src)?)?;
+                Packet::Publish(Publish {
+                    dup: flags.dup,
+                    qos: flags.qos,
+                    retain: flags.retain,
+                    topic_id: TopicId::decode(flags.topic_id_type, src)?,
+                    msg_id: decode_u16(src)?,
+                    data: src.split().freeze(),
+                })
+            }
+            Self::PUBACK => Packet::Puback(decode_ack(src)?),
+            Self::PUBCOMP => Packet::Pubcomp(decode_u16(src)?),
+            Self::PUBREC => Packet::Pubrec(decode_u16(src)?),
+            Self::PUBREL => Packet::Pubrel(decode_u16(src)?),
+            Self::SUBSCRIBE => Packet::Subscribe(decode_subscribe(src)?),
+            Self::SUBACK => {
+                let flags = Flags::decode(decode_u8(src)?)?;
+                Packet::Suback(Suback {
+                    qos: flags.qos.ok_or_else(|| malformed("SUBACK grants QoS -1"))?,
+                    topic_id: decode_u16(src)?,
+                    msg_id: decode_u16(src)?,
+                    return_code: ReturnCode::decode(src)?,
+                })
+            }
+            Self::UNSUBSCRIBE => Packet::Unsubscribe(decode_subscribe(src)?),
+            Self::UNSUBACK => Packet::Unsuback(decode_u16(src)?),
+            Self::PINGREQ => Packet::Pingreq(if src.is_empty() {
+                None
+            } else {
+                Some(decode_rest_string(src)?)
+            }),
+            Self::PINGRESP => Packet::Pingresp,
+            Self::DISCONNECT => Packet::Disconnect(if src.is_empty() {
+                None
+            } else {
+                Some(decode_u16(src)?)
+            }),
+            _ => {
+                return Err(malformed(format!(
+                    "unsupported message type {:#04x}",
+                    msg_type
+                )))
+            }
+        };
+
+        if !src.is_empty() {
+            return Err(malformed(format!(
+                "{} has {} unexpected trailing bytes",
+                packet.name(),
+                src.len()
+            )));
+        }
+
+        Ok(packet)
+    }
+}
+
+fn decode_u8(src: &mut BytesMut) -> io::Result<u8> {
+    if src.is_empty() {
+        return Err(malformed("a byte is missing"));
+    }
+    Ok(src.get_u8())
+}
+
+fn decode_u16(src: &mut BytesMut) -> io::Result<u16> {
+    if src.len() < 2 {
+        return Err(malformed("a two byte integer is missing"));
+    }
+    Ok(src.get_u16())
+}
+
+/// Strings are not prefixed by their length, they take the rest of a message.
+fn decode_rest_string(src: &mut BytesMut) -> io::Result<String> {
+    String::from_utf8(src.split().to_vec()).map_err(|_| malformed("a string is not UTF-8"))
+}
+
+fn decode_ack(src: &mut BytesMut) -> io::Result<Ack> {
+    Ok(Ack {
+        topic_id: decode_u16(src)?,
+        msg_id: decode_u16(src)?,
+        return_code: ReturnCode::decode(src)?,
+    })
+}
+
+fn decode_subscribe(src: &mut BytesMut) -> io::Result<Subscribe> {
+    let flags = Flags::decode(decode_u8(src)?)?;
+    Ok(Subscribe {
+        dup: flags.dup,
+        qos: flags.qos.unwrap_or(QoS::Zero),
+        msg_id: decode_u16(src)?,
+        topic: TopicFilter::decode(flags.topic_id_type, src)?,
+    })
+}
+
+/// `Packet` Tokio codec, a whole message is expected in a buffer (e.g. a UDP datagram).
+#[derive(Debug, Default)]
+pub struct PacketCodec;
+
+impl PacketCodec {
+    pub fn new() -> Self {
+        PacketCodec {}
+    }
+}
+
+impl tokio_util::codec::Decoder for PacketCodec {
+    type Item = Packet;
+    type Error = PacketCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>, PacketCodecError> {
+        let (length, header_len) = match src.first() {
+            None => return Ok(None),
+            Some(&Packet::THREE_BYTE_LENGTH) if src.len() < 3 => return Ok(None),
+            Some(&Packet::THREE_BYTE_LENGTH) => (u16::from_be_bytes([src[1], src[2]]) as usize, 3),
+            Some(&length) => (length as usize, 1),
+        };
+        // the length includes the Length field itself and the MsgType byte
+        if length < header_len + 1 {
+            return Err(PacketCodecError::malformed(malformed(format!(
+                "length {} is too short",
+                length
+            ))));
+        }
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
+        }
+
+        let mut packet = src.split_to(length);
+        packet.advance(header_len);
+        let msg_type = packet.get_u8();
+
+        let packet =
+            Packet::decode_body(msg_type, &mut packet).map_err(PacketCodecError::malformed)?;
+        trace!(target: LOG_TARGET, "Decoded {:?}", packet);
+
+        Ok(Some(packet))
+    }
+}
+
+impl<'a> tokio_util::codec::Encoder<&'a Packet> for PacketCodec {
+    type Error = PacketCodecError;
+
+    fn encode(&mut self, item: &'a Packet, dst: &mut BytesMut) -> Result<(), PacketCodecError> {
+        trace!(target: LOG_TARGET, "Encoding {:?}", item);
+        let mut body = BytesMut::new();
+        item.encode_body(&mut body);
+
+        // the length includes the Length field itself and the MsgType byte
+        let short_length = body.len() + 2;
+        if short_length <= u8::MAX as usize {
+            dst.put_u8(short_length as u8);
+        } else if body.len() + 4 <= u16::MAX as usize {
+            dst.put_u8(Packet::THREE_BYTE_LENGTH);
+            dst.put_u16((body.len() + 4) as u16);
+        } else {
+            return Err(PacketCodecError::encoding(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is longer than 65535 bytes", item.name()),
+            )));
+        }
+        dst.put_u8(item.msg_type());
+        dst.extend_from_slice(&body);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn round_trip(packet: Packet) {
+        let mut codec = PacketCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(&packet, &mut buf).unwrap();
+
+        let mut partial = BytesMut::from(&buf[..buf.len() - 1]);
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(packet));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn connect_and_register_round_trip() {
+        round_trip(Packet::Connect(Connect {
+            will: false,
+            clean_session: true,
+            duration: 60,
+            client_id: "sensor-1".into(),
+        }));
+        round_trip(Packet::Connack(ReturnCode::Accepted));
+        round_trip(Packet::Register(Register {
+            topic_id: 0,
+            msg_id: 1,
+            topic_name: "sensors/1/temperature".into(),
+        }));
+        round_trip(Packet::Regack(Ack {
+            topic_id: 1,
+            msg_id: 1,
+            return_code: ReturnCode::Accepted,
+        }));
+        round_trip(Packet::SearchGw { radius: 0 });
+        round_trip(Packet::GwInfo {
+            gw_id: 1,
+            gw_add: vec![],
+        });
+        round_trip(Packet::Pingreq(None));
+        round_trip(Packet::Pingreq(Some("sensor-1".into())));
+        round_trip(Packet::Disconnect(None));
+        round_trip(Packet::Disconnect(Some(300)));
+    }
+
+    #[test]
+    fn publish_and_subscribe_round_trip() {
+        round_trip(Packet::Publish(Publish {
+            dup: false,
+            qos: Some(QoS::One),
+            retain: true,
+            topic_id: TopicId::Normal(1),
+            msg_id: 7,
+            data: Bytes::from_static(b"21.5"),
+        }));
+        round_trip(Packet::Publish(Publish {
+            dup: false,
+            qos: None,
+            retain: false,
+            topic_id: TopicId::Short(*b"t1"),
+            msg_id: 0,
+            data: Bytes::new(),
+        }));
+        round_trip(Packet::Subscribe(Subscribe {
+            dup: false,
+            qos: QoS::One,
+            msg_id: 2,
+            topic: TopicFilter::Name("sensors/+/commands".into()),
+        }));
+        round_trip(Packet::Suback(Suback {
+            qos: QoS::Zero,
+            topic_id: NO_TOPIC_ID,
+            msg_id: 2,
+            return_code: ReturnCode::Accepted,
+        }));
+        round_trip(Packet::Unsubscribe(Subscribe {
+            dup: false,
+            qos: QoS::Zero,
+            msg_id: 3,
+            topic: TopicFilter::Predefined(5),
+        }));
+        round_trip(Packet::Unsuback(3));
+        round_trip(Packet::Pubrel(7));
+    }
+
+    #[test]
+    fn long_message_has_three_byte_length() {
+        let publish = Packet::Publish(Publish {
+            dup: false,
+            qos: Some(QoS::Zero),
+            retain: false,
+            topic_id: TopicId::Normal(1),
+            msg_id: 0,
+            data: Bytes::from(vec![0u8; 300]),
+        });
+        let mut buf = BytesMut::new();
+        PacketCodec::new().encode(&publish, &mut buf).unwrap();
+
+        assert_eq!(&buf[..3], &[0x01, 0x01, 0x35]);
+        assert_eq!(buf.len(), 309);
+        round_trip(publish);
+    }
+
+    fn is_malformed(bytes: &[u8]) -> bool {
+        let mut buf = BytesMut::from(bytes);
+        matches!(
+            PacketCodec::new().decode(&mut buf),
+            Err(PacketCodecError::MalformedPacket(_))
+        )
+    }
+
+    #[test]
+    fn malformed_messages() {
+        // length shorter than the header
+        assert!(is_malformed(&[1u8, 0, 0]));
+        // unknown protocol id of CONNECT
+        assert!(is_malformed(&[6u8, 0x04, 0, 2, 0, 60]));
+        // WILLTOPICREQ is not supported
+        assert!(is_malformed(&[2u8, 0x06]));
+        // trailing bytes in PINGRESP
+        assert!(is_malformed(&[3u8, 0x17, 0]));
+    }
+}
@@ -0,0 +1,78 @@
+use std::{error, fmt, io};
+
+use log::debug;
+
+use crate::LOG_TARGET;
+
+/// An error of `ControlPacketCodec` and `PacketCodec`. Packets are parsed and serialized
+/// with `std::io::Error`s, which are sorted into categories once they leave a codec, so
+/// a caller can tell a misbehaving peer from a broken transport or from its own bug.
+#[derive(Debug)]
+pub enum PacketCodecError {
+    /// Reading from or writing to a transport has failed.
+    Io(io::Error),
+    /// Received bytes are not a valid packet, a peer violates the protocol and should be
+    /// disconnected.
+    MalformedPacket(String),
+    /// A packet can't be serialized (e.g. a field doesn't fit into its protocol limit).
+    /// It's caused by a packet a caller has built, not by a peer.
+    Encoding(String),
+}
+
+impl PacketCodecError {
+    /// Categorizes an error a packet has been parsed with.
+    pub fn malformed(err: io::Error) -> Self {
+        debug!(target: LOG_TARGET, "Malformed packet: {}", err);
+        PacketCodecError::MalformedPacket(err.to_string())
+    }
+
+    /// Categorizes an error a packet has been serialized with.
+    pub fn encoding(err: io::Error) -> Self {
+        debug!(target: LOG_TARGET, "Unable to encode a packet: {}", err);
+        PacketCodecError::Encoding(err.to_string())
+    }
+}
+
+impl fmt::Display for PacketCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PacketCodecError::Io(ref err) => write!(f, "I/O error: {}", err),
+            PacketCodecError::MalformedPacket(ref reason) => {
+                write!(f, "Malformed packet: {}", reason)
+            }
+            PacketCodecError::Encoding(ref reason) => {
+                write!(f, "Unable to encode a packet: {}", reason)
+            }
+        }
+    }
+}
+
+impl error::Error for PacketCodecError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PacketCodecError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Tokio codecs require transport errors to be convertible into a codec error.
+impl From<io::Error> for PacketCodecError {
+    fn from(err: io::Error) -> Self {
+        PacketCodecError::Io(err)
+    }
+}
+
+impl From<PacketCodecError> for io::Error {
+    fn from(err: PacketCodecError) -> Self {
+        match err {
+            PacketCodecError::Io(err) => err,
+            PacketCodecError::MalformedPacket(_) => {
+                io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+            }
+            PacketCodecError::Encoding(_) => {
+                io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+            }
+        }
+    }
+}
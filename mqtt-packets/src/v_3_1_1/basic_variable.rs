@@ -22,13 +22,13 @@ impl VariableCodec {
     }
 
     pub fn encode(&mut self, item: &Variable, dst: &mut BytesMut) -> Result<(), std::io::Error> {
-        dst.extend_from_slice(item.packet_id.as_slice());
+        item.packet_id.encode(dst);
 
         Ok(())
     }
 
     pub fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Variable>, std::io::Error> {
-        let packet_id = src.split_to(Self::PACKET_ID_LEN).to_vec();
+        let packet_id = PacketId::decode(src)?;
 
         Ok(Some(Variable { packet_id }))
     }
@@ -2,7 +2,8 @@ use crate::v_3_1_1::cp_fixed_header::FixedHeader;
 use crate::v_3_1_1::cp_flag::Flag;
 use crate::v_3_1_1::cp_rem_len::CPRemLen;
 use crate::v_3_1_1::cp_type::CPType;
-use crate::v_3_1_1::ControlPacket;
+use crate::v_3_1_1::topic::Topic;
+use crate::v_3_1_1::{ControlPacket, QoS};
 
 use crate::v_3_1_1::connect::connect_flags::ConnectFlags;
 use crate::v_3_1_1::connect::keep_alive::KeepAlive;
@@ -61,6 +62,19 @@ impl ConnectBuilder {
         }
     }
 
+    /// Sets a will message the server publishes once the connection is lost without
+    /// a DISCONNECT packet.
+    pub fn with_will(mut self, topic: Topic, message: Vec<u8>, qos: &QoS, retain: bool) -> Self {
+        if let Variable::Connect(ref mut variable) = self.control_packet.variable {
+            variable.connect_flags.set_will_flag(true);
+            variable.connect_flags.set_qos_value(qos);
+            variable.connect_flags.set_will_retain(retain);
+            variable.will_topic = Some(topic);
+            variable.will_message = Some(message);
+        }
+        self
+    }
+
     /// It finalizes build process and returns resulting `ControlPacket`.
     pub fn build(self) -> ControlPacket {
         self.control_packet
@@ -0,0 +1,37 @@
+use crate::v_3_1_1::cp_fixed_header::FixedHeader;
+use crate::v_3_1_1::variable::Variable;
+use crate::v_3_1_1::Flag;
+use crate::v_3_1_1::{CPRemLen, CPType, ControlPacket};
+
+pub struct DisconnectPacketBuilder {
+    packet: ControlPacket,
+}
+
+impl DisconnectPacketBuilder {
+    pub fn new() -> Self {
+        DisconnectPacketBuilder {
+            packet: ControlPacket {
+                fixed_header: FixedHeader {
+                    cp_type: CPType::Disconnect,
+                    flag: Flag {
+                        control_packet: CPType::Disconnect,
+                        is_reserved: true,
+                        bits: 0,
+                    },
+                    remaining_length: CPRemLen::new(0),
+                },
+                variable: Variable::Disconnect,
+            },
+        }
+    }
+
+    pub fn build(self) -> ControlPacket {
+        self.packet
+    }
+}
+
+impl Default for DisconnectPacketBuilder {
+    fn default() -> DisconnectPacketBuilder {
+        DisconnectPacketBuilder::new()
+    }
+}
@@ -1,5 +1,7 @@
 mod connack;
 mod connect;
+mod disconnect;
+mod pingreq;
 mod pingresp;
 mod puback;
 mod pubcomp;
@@ -7,10 +9,13 @@ mod publish;
 mod pubrec;
 mod pubrel;
 mod suback;
+mod subscribe;
 mod unsuback;
 
 pub use self::connack::ConnackBuilder;
 pub use self::connect::ConnectBuilder;
+pub use self::disconnect::DisconnectPacketBuilder;
+pub use self::pingreq::PingreqPacketBuilder;
 pub use self::pingresp::PingrespPacketBuilder;
 pub use self::puback::PubackPacketBuilder;
 pub use self::pubcomp::PubcompPacketBuilder;
@@ -18,4 +23,5 @@ pub use self::publish::PublishPacketBuilder;
 pub use self::pubrec::PubrecPacketBuilder;
 pub use self::pubrel::PubrelPacketBuilder;
 pub use self::suback::SubackPacketBuilder;
+pub use self::subscribe::SubscribePacketBuilder;
 pub use self::unsuback::UnsubackPacketBuilder;
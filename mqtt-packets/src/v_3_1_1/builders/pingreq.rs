@@ -0,0 +1,37 @@
+use crate::v_3_1_1::cp_fixed_header::FixedHeader;
+use crate::v_3_1_1::variable::Variable;
+use crate::v_3_1_1::Flag;
+use crate::v_3_1_1::{CPRemLen, CPType, ControlPacket};
+
+pub struct PingreqPacketBuilder {
+    packet: ControlPacket,
+}
+
+impl PingreqPacketBuilder {
+    pub fn new() -> Self {
+        PingreqPacketBuilder {
+            packet: ControlPacket {
+                fixed_header: FixedHeader {
+                    cp_type: CPType::Pingreq,
+                    flag: Flag {
+                        control_packet: CPType::Pingreq,
+                        is_reserved: true,
+                        bits: 0,
+                    },
+                    remaining_length: CPRemLen::new(0),
+                },
+                variable: Variable::Pingreq,
+            },
+        }
+    }
+
+    pub fn build(self) -> ControlPacket {
+        self.packet
+    }
+}
+
+impl Default for PingreqPacketBuilder {
+    fn default() -> PingreqPacketBuilder {
+        PingreqPacketBuilder::new()
+    }
+}
@@ -22,7 +22,7 @@ impl PubackPacketBuilder {
                     remaining_length: CPRemLen::new(2),
                 },
                 variable: Variable::Puback(BasicVariable {
-                    packet_id: packet_id.clone(),
+                    packet_id: *packet_id,
                 }),
             },
         }
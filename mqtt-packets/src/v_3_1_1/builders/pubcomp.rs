@@ -22,7 +22,7 @@ impl PubcompPacketBuilder {
                     remaining_length: CPRemLen::new(2),
                 },
                 variable: Variable::Pubcomp(BasicVariable {
-                    packet_id: packet_id.clone(),
+                    packet_id: *packet_id,
                 }),
             },
         }
@@ -1,3 +1,5 @@
+use bytes::Bytes;
+
 use crate::v_3_1_1::cp_fixed_header::FixedHeader;
 use crate::v_3_1_1::publish::fixed_header::{set_dup, set_qos_level, set_retained};
 use crate::v_3_1_1::publish::variable::Variable as PublishVariable;
@@ -26,7 +28,7 @@ impl PublishPacketBuilder {
                 variable: Variable::Publish(PublishVariable {
                     packet_id: None,
                     topic_name: Topic::try_from("EMPTY").unwrap(),
-                    payload: vec![],
+                    payload: Bytes::new(),
                 }),
             },
         }
@@ -69,9 +71,9 @@ impl PublishPacketBuilder {
         self
     }
 
-    pub fn with_payload(&mut self, payload: Vec<u8>) -> &mut Self {
+    pub fn with_payload<P: Into<Bytes>>(&mut self, payload: P) -> &mut Self {
         if let Variable::Publish(ref mut variable) = self.packet.variable {
-            variable.payload = payload;
+            variable.payload = payload.into();
         } else {
             unreachable!();
         }
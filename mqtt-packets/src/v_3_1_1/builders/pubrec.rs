@@ -22,7 +22,7 @@ impl PubrecPacketBuilder {
                     remaining_length: CPRemLen::new(2),
                 },
                 variable: Variable::Pubrec(BasicVariable {
-                    packet_id: packet_id.clone(),
+                    packet_id: *packet_id,
                 }),
             },
         }
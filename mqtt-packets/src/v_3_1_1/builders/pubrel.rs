@@ -17,12 +17,13 @@ impl PubrelPacketBuilder {
                     flag: Flag {
                         control_packet: CPType::Pubrel,
                         is_reserved: true,
-                        bits: 0,
+                        // reserved bits of PUBREL are 0010
+                        bits: 2,
                     },
                     remaining_length: CPRemLen::new(2),
                 },
                 variable: Variable::Pubrel(BasicVariable {
-                    packet_id: packet_id.clone(),
+                    packet_id: *packet_id,
                 }),
             },
         }
@@ -29,7 +29,7 @@ impl SubackPacketBuilder {
         }
     }
 
-    pub fn with_packet_id(mut self, packet_id: Vec<u8>) -> Self {
+    pub fn with_packet_id(mut self, packet_id: PacketId) -> Self {
         if let Variable::Suback(ref mut variable) = self.packet.variable {
             variable.packet_id = packet_id;
         }
@@ -0,0 +1,48 @@
+use crate::v_3_1_1::cp_fixed_header::FixedHeader;
+use crate::v_3_1_1::subscribe::{
+    topic_subscription::TopicSubscription, variable::Variable as SubscribeVariable,
+};
+use crate::v_3_1_1::topic::Subscription;
+use crate::v_3_1_1::variable::Variable;
+use crate::v_3_1_1::{CPRemLen, CPType, ControlPacket, Flag, PacketId, QoS};
+
+pub struct SubscribePacketBuilder {
+    packet: ControlPacket,
+}
+
+impl SubscribePacketBuilder {
+    pub fn new(packet_id: PacketId) -> Self {
+        SubscribePacketBuilder {
+            packet: ControlPacket {
+                fixed_header: FixedHeader {
+                    cp_type: CPType::Subscribe,
+                    flag: Flag {
+                        control_packet: CPType::Subscribe,
+                        is_reserved: true,
+                        bits: 2,
+                    },
+                    // will be overriden by the encoder
+                    remaining_length: CPRemLen::new(0),
+                },
+                variable: Variable::Subscribe(SubscribeVariable {
+                    packet_id,
+                    subscriptions: vec![],
+                }),
+            },
+        }
+    }
+
+    pub fn with_subscription(mut self, topic_filter: Subscription, qos: QoS) -> Self {
+        if let Variable::Subscribe(ref mut variable) = self.packet.variable {
+            variable
+                .subscriptions
+                .push(TopicSubscription::new(topic_filter, qos));
+        }
+
+        self
+    }
+
+    pub fn build(self) -> ControlPacket {
+        self.packet
+    }
+}
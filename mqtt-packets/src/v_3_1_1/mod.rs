@@ -19,6 +19,7 @@ mod cp_type;
 mod packet_id;
 
 use bytes::BytesMut;
+use log::trace;
 
 use self::cp_fixed_header::{FixedHeader, FixedHeaderCodec};
 pub use self::cp_flag::Flag;
@@ -30,6 +31,8 @@ use self::variable::{Variable, VariableCodec};
 
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::{PacketCodecError, LOG_TARGET};
+
 /// A structure which represents MQTT Control Packet
 #[derive(Debug, Clone)]
 pub struct ControlPacket {
@@ -64,6 +67,18 @@ impl<'vi> serde::de::Visitor<'vi> for Bytes {
     {
         Ok(v.to_vec())
     }
+
+    // self-describing formats (e.g. JSON) represent bytes as a sequence of numbers
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'vi>,
+    {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
 }
 
 impl<'de> Deserialize<'de> for ControlPacket {
@@ -89,18 +104,27 @@ pub struct ControlPacketCodec {
 
 impl tokio_util::codec::Decoder for ControlPacketCodec {
     type Item = ControlPacket;
-    type Error = std::io::Error;
+    type Error = PacketCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ControlPacket>, PacketCodecError> {
+        let packet = self
+            .inner_decode(src)
+            .map_err(PacketCodecError::malformed)?;
+        if let Some(ref packet) = packet {
+            trace!(target: LOG_TARGET, "Decoded {:?}", packet);
+        }
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ControlPacket>, std::io::Error> {
-        self.inner_decode(src)
+        Ok(packet)
     }
 }
 
 impl tokio_util::codec::Encoder<&ControlPacket> for ControlPacketCodec {
-    type Error = std::io::Error;
+    type Error = PacketCodecError;
 
     fn encode(&mut self, item: &ControlPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        trace!(target: LOG_TARGET, "Encoding {:?}", item);
         self.inner_encode(item, dst)
+            .map_err(PacketCodecError::encoding)
     }
 }
 
@@ -134,9 +158,30 @@ impl ControlPacketCodec {
     pub fn inner_decode(
         &mut self,
         src: &mut BytesMut,
+    ) -> Result<Option<ControlPacket>, std::io::Error> {
+        let result = self.decode_packet(src);
+        if result.is_err() {
+            // a half decoded packet must not leak into the next one
+            self.reset();
+        }
+
+        result
+    }
+
+    fn decode_packet(
+        &mut self,
+        src: &mut BytesMut,
     ) -> Result<Option<ControlPacket>, std::io::Error> {
         if self.can_decode_fixed_header() {
-            self.fixed_header = self.fixed_header_codec.decode(src)?;
+            // a multi-byte remaining length takes several turns of the fixed header codec.
+            // All available bytes are consumed, since a caller doesn't call the codec again
+            // until more bytes are received.
+            loop {
+                self.fixed_header = self.fixed_header_codec.decode(src)?;
+                if !self.can_decode_fixed_header() || src.is_empty() {
+                    break;
+                }
+            }
 
             // still not decoded, keep waiting
             if self.can_decode_fixed_header() {
@@ -187,3 +232,174 @@ impl ControlPacketCodec {
         self.fixed_header.is_none()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// QoS 0 PUBLISH to `a/b`.
+    fn publish_bytes(payload_len: usize) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        cp_rem_len::CPRemLenCodec::default()
+            .encode(&CPRemLen::new(5 + payload_len as u32), &mut buf)
+            .unwrap();
+
+        let mut bytes = vec![0x30];
+        bytes.extend_from_slice(&buf);
+        bytes.extend_from_slice(&[0, 3, b'a', b'/', b'b']);
+        bytes.extend(std::iter::repeat_n(7, payload_len));
+        bytes
+    }
+
+    #[test]
+    fn multi_byte_remaining_length_is_decoded_in_one_turn() {
+        let mut codec = ControlPacketCodec::new();
+        let mut buf = BytesMut::from(publish_bytes(200).as_slice());
+
+        let packet = codec.inner_decode(&mut buf).unwrap().unwrap();
+        match packet.variable {
+            Variable::Publish(ref variable) => assert_eq!(variable.payload.len(), 200),
+            _ => panic!("Publish is expected"),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn cloned_publish_shares_payload() {
+        let mut codec = ControlPacketCodec::new();
+        let mut buf = BytesMut::from(publish_bytes(200).as_slice());
+
+        let packet = codec.inner_decode(&mut buf).unwrap().unwrap();
+        let copy = packet.clone();
+        match (packet.variable, copy.variable) {
+            (Variable::Publish(ref original), Variable::Publish(ref copy)) => {
+                assert_eq!(original.payload.as_ptr(), copy.payload.as_ptr());
+            }
+            _ => panic!("Publish is expected"),
+        }
+    }
+
+    #[test]
+    fn remaining_length_split_between_turns_is_decoded() {
+        let mut codec = ControlPacketCodec::new();
+        let bytes = publish_bytes(20_000);
+        let mut buf = BytesMut::from(&bytes[..2]);
+
+        assert!(codec.inner_decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&bytes[2..]);
+        let packet = codec.inner_decode(&mut buf).unwrap().unwrap();
+        assert_eq!(packet.fixed_header.remaining_length.as_value(), 20_005);
+    }
+
+    #[test]
+    fn decoding_errors_are_malformed_packets() {
+        use tokio_util::codec::Decoder;
+
+        // packet type 0 is reserved
+        let mut buf = BytesMut::from(&[0x00u8, 0][..]);
+        match ControlPacketCodec::new().decode(&mut buf) {
+            Err(PacketCodecError::MalformedPacket(_)) => {}
+            result => panic!("MalformedPacket is expected, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn publish_with_wildcard_topic_is_malformed() {
+        use tokio_util::codec::Decoder;
+
+        for topic in ["a/+/c", "a/#"] {
+            let mut bytes = vec![0x30, 2 + topic.len() as u8, 0, topic.len() as u8];
+            bytes.extend_from_slice(topic.as_bytes());
+            // a valid packet follows in the same buffer
+            bytes.extend(publish_bytes(1));
+            let mut codec = ControlPacketCodec::new();
+            let mut buf = BytesMut::from(bytes.as_slice());
+
+            match codec.decode(&mut buf) {
+                Err(PacketCodecError::MalformedPacket(reason)) => {
+                    assert!(reason.contains("wildcard"), "{}", reason)
+                }
+                result => panic!(
+                    "MalformedPacket is expected for {}, got {:?}",
+                    topic, result
+                ),
+            }
+            let packet = codec.decode(&mut buf).unwrap().unwrap();
+            match packet.variable {
+                Variable::Publish(ref variable) => assert_eq!(variable.topic_name.original, "a/b"),
+                _ => panic!("Publish is expected"),
+            }
+        }
+    }
+
+    #[test]
+    fn built_packets_are_decoded() {
+        use self::builders::{DisconnectPacketBuilder, PubrelPacketBuilder};
+
+        let packets = vec![
+            PubrelPacketBuilder::new(&PacketId::new(1)).build(),
+            DisconnectPacketBuilder::new().build(),
+        ];
+        for packet in packets {
+            let mut codec = ControlPacketCodec::new();
+            let mut buf = BytesMut::new();
+            codec.inner_encode(&packet, &mut buf).unwrap();
+
+            let decoded = codec.inner_decode(&mut buf).unwrap().unwrap();
+            assert_eq!(decoded.fixed_header.cp_type, packet.fixed_header.cp_type);
+            assert_eq!(
+                decoded.fixed_header.flag.bits,
+                packet.fixed_header.flag.bits
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_publish_is_malformed() {
+        use tokio_util::codec::Decoder;
+
+        // topic name length exceeds the remaining length
+        let mut buf = BytesMut::from(&[0x30u8, 5, 0, 10, b'a', b'/', b'b'][..]);
+        assert!(matches!(
+            ControlPacketCodec::new().decode(&mut buf),
+            Err(PacketCodecError::MalformedPacket(_))
+        ));
+
+        // QoS 1 without a packet id
+        let mut buf = BytesMut::from(&[0x32u8, 5, 0, 3, b'a', b'/', b'b'][..]);
+        assert!(matches!(
+            ControlPacketCodec::new().decode(&mut buf),
+            Err(PacketCodecError::MalformedPacket(_))
+        ));
+    }
+
+    #[test]
+    fn connect_with_will_is_encoded() {
+        let packet = builders::ConnectBuilder::new("bridge".into(), 60, true, None, None)
+            .with_will(
+                topic::Topic::try_from("$SYS/broker/b1/state").unwrap(),
+                b"offline".to_vec(),
+                &QoS::One,
+                true,
+            )
+            .build();
+        let mut codec = ControlPacketCodec::new();
+        let mut buf = BytesMut::new();
+        codec.inner_encode(&packet, &mut buf).unwrap();
+
+        let decoded = codec.inner_decode(&mut buf).unwrap().unwrap();
+        match decoded.variable {
+            Variable::Connect(ref variable) => {
+                assert!(variable.connect_flags.has_will_flag());
+                assert!(variable.connect_flags.has_will_retain());
+                assert_eq!(variable.connect_flags.qos_value().unwrap(), QoS::One);
+                assert_eq!(
+                    variable.will_topic.as_ref().unwrap().original,
+                    "$SYS/broker/b1/state"
+                );
+                assert_eq!(variable.will_message.as_deref(), Some(&b"offline"[..]));
+            }
+            _ => panic!("Connect is expected"),
+        }
+    }
+}
@@ -1,2 +1,129 @@
-pub type PacketId = Vec<u8>;
+use std::fmt;
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::Serialize;
+
 pub const PACKET_ID_LEN: usize = 2;
+
+/// The Packet Identifier of PUBLISH (QoS > 0), PUBACK, PUBREC, PUBREL, PUBCOMP, SUBSCRIBE,
+/// SUBACK, UNSUBSCRIBE and UNSUBACK packets, a Two Byte Integer in big-endian order.
+///
+/// SUBSCRIBE, UNSUBSCRIBE, and PUBLISH (in cases where QoS > 0) Control Packets MUST contain
+/// a non-zero 16-bit Packet Identifier [MQTT-2.3.1-1].
+///
+/// It's serialized as a number. The two bytes it used to be serialized as are accepted too, so
+/// sessions saved by older brokers can still be restored.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct PacketId(u16);
+
+impl PacketId {
+    pub fn new(value: u16) -> Self {
+        PacketId(value)
+    }
+
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) {
+        dst.put_u16(self.0);
+    }
+
+    pub fn decode(src: &mut BytesMut) -> io::Result<PacketId> {
+        if src.len() < PACKET_ID_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Codec: packet id is missing",
+            ));
+        }
+
+        Ok(PacketId(src.get_u16()))
+    }
+}
+
+impl<'de> Deserialize<'de> for PacketId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(PacketIdVisitor)
+    }
+}
+
+struct PacketIdVisitor;
+
+impl<'de> Visitor<'de> for PacketIdVisitor {
+    type Value = PacketId;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a 16-bit packet id or its two bytes in big-endian order")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<PacketId, E> {
+        if value > u64::from(u16::MAX) {
+            return Err(E::invalid_value(de::Unexpected::Unsigned(value), &self));
+        }
+        Ok(PacketId(value as u16))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<PacketId, E> {
+        if value < 0 {
+            return Err(E::invalid_value(de::Unexpected::Signed(value), &self));
+        }
+        self.visit_u64(value as u64)
+    }
+
+    // keys of maps, such as the in-flight messages of a session, are strings in JSON
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<PacketId, E> {
+        value
+            .parse()
+            .map(PacketId)
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PacketId, A::Error> {
+        let hi: u8 = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let lo: u8 = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        if seq.next_element::<u8>()?.is_some() {
+            return Err(de::Error::invalid_length(3, &self));
+        }
+        Ok(PacketId(u16::from_be_bytes([hi, lo])))
+    }
+}
+
+impl From<u16> for PacketId {
+    fn from(value: u16) -> Self {
+        PacketId(value)
+    }
+}
+
+impl fmt::Display for PacketId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_id_is_big_endian() {
+        let mut buf = BytesMut::new();
+        PacketId::new(258).encode(&mut buf);
+        assert_eq!(buf.as_ref(), &[1, 2]);
+
+        assert_eq!(PacketId::decode(&mut buf).unwrap(), PacketId::new(258));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn truncated_packet_id_is_an_error() {
+        let mut buf = BytesMut::from(&[1u8][..]);
+        assert!(PacketId::decode(&mut buf).is_err());
+    }
+}
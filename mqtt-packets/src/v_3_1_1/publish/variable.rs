@@ -1,4 +1,4 @@
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 
 use crate::v_3_1_1::topic::Topic;
 use crate::v_3_1_1::utils::codec as codec_utils;
@@ -18,7 +18,8 @@ pub struct Variable {
     /// The Packet Identifier field is only present in PUBLISH Packets where the QoS level is 1 or 2.
     pub packet_id: Option<PacketId>,
 
-    pub payload: Vec<u8>,
+    /// Reference-counted, so a packet routed to many subscribers shares a single copy of it.
+    pub payload: Bytes,
 }
 
 pub struct VariableCodec {
@@ -26,8 +27,6 @@ pub struct VariableCodec {
 }
 
 impl VariableCodec {
-    const PACKET_ID_LEN: usize = 2;
-
     pub fn new(qos: QoS) -> Self {
         VariableCodec { qos }
     }
@@ -35,22 +34,29 @@ impl VariableCodec {
     pub fn encode(&mut self, item: &Variable, dst: &mut BytesMut) -> Result<(), std::io::Error> {
         codec_utils::encode_optional_string(&Some(&item.topic_name.original), dst);
         if let Some(ref packet_id) = item.packet_id {
-            dst.extend_from_slice(packet_id.as_slice());
+            packet_id.encode(dst);
         }
-        dst.extend_from_slice(item.payload.as_slice());
+        dst.extend_from_slice(&item.payload);
         Ok(())
     }
 
     pub fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Variable>, std::io::Error> {
-        // TODO: refactor downstream codecs to avoid unwrapping
-        let topic_name = Topic::try_from(codec_utils::decode_optional_string(src).unwrap())?;
+        let topic_string = codec_utils::decode_optional_string(src).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Codec: Publish topic name is longer than the packet",
+            )
+        })?;
+        // topic names with wildcards are a protocol violation, the connection gets closed
+        let topic_name = Topic::try_from(topic_string)?;
         let should_have_packet_id = self.qos == QoS::One || self.qos == QoS::Two;
         let packet_id = if should_have_packet_id {
-            Some(src.split_to(Self::PACKET_ID_LEN).to_vec())
+            Some(PacketId::decode(src)?)
         } else {
             None
         };
-        let payload = src.to_vec();
+        // the rest of a packet is split off a read buffer without copying
+        let payload = src.split().freeze();
 
         Ok(Some(Variable {
             packet_id,
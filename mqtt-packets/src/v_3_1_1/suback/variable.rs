@@ -25,7 +25,7 @@ impl VariableCodec {
     }
 
     pub fn encode(&mut self, item: &Variable, dst: &mut BytesMut) -> Result<(), std::io::Error> {
-        dst.extend_from_slice(item.packet_id.as_slice());
+        item.packet_id.encode(dst);
 
         {
             let mut bytes: Vec<u8> = Vec::with_capacity(item.return_codes.len());
@@ -41,7 +41,7 @@ impl VariableCodec {
     }
 
     pub fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Variable>, std::io::Error> {
-        let packet_id = src.split_to(Self::PACKET_ID_LEN).to_vec();
+        let packet_id = PacketId::decode(src)?;
         let bytes = src.to_vec();
         let mut return_codes = Vec::with_capacity(src.len());
 
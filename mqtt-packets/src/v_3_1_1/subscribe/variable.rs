@@ -25,7 +25,7 @@ impl VariableCodec {
     }
 
     pub fn encode(&mut self, item: &Variable, dst: &mut BytesMut) -> Result<(), std::io::Error> {
-        dst.extend_from_slice(item.packet_id.as_slice());
+        item.packet_id.encode(dst);
         for topic in &item.subscriptions {
             topic.encode(dst)?;
         }
@@ -34,7 +34,7 @@ impl VariableCodec {
     }
 
     pub fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Variable>, std::io::Error> {
-        let packet_id = src.split_to(Self::PACKET_ID_LEN).to_vec();
+        let packet_id = PacketId::decode(src)?;
         let mut subscriptions = vec![];
         while src.len() > 0 {
             subscriptions.push(TopicSubscription::decode(src)?);
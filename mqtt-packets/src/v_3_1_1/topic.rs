@@ -115,6 +115,12 @@ impl Subscription {
         return topics_match(&topic.path, &self.path);
     }
 
+    /// It returns `true` if every topic matching `other` subscription also matches current
+    /// subscription, i.e. `other` is a subset of current subscription.
+    pub fn contains(&self, other: &Subscription) -> bool {
+        filter_contains(&self.path, &other.path)
+    }
+
     fn wild_card_validity(subscription: &str) -> bool {
         if !subscription.contains(WILD_CARD) || subscription == WILD_CARD {
             return true;
@@ -158,7 +164,48 @@ pub fn topics_match(left: &Vec<String>, right: &Vec<String>) -> bool {
         }
     }
 
-    true
+    // a filter may only be longer by `#`, which matches a parent level, e.g. `a/#` matches `a`
+    match right.get(left.len()) {
+        Some(pattern) => pattern == WILD_CARD && right.len() == left.len() + 1,
+        None => true,
+    }
+}
+
+/// It returns `true` if every topic matching `other` filter also matches `filter`. Unlike
+/// `topics_match` both arguments may contain wildcards, so it tells whether a requested
+/// subscription is granted by a wider one, e.g. `a/b/+` is contained by `a/#`, but `a/#`
+/// is not contained by `a/+`.
+pub fn filter_contains(filter: &[String], other: &[String]) -> bool {
+    for (i, pattern) in filter.iter().enumerate() {
+        if pattern == WILD_CARD {
+            // `#` also matches a parent level, so `a/#` contains `a`
+            return i != 0 || !other.first().is_some_and(|p| p.starts_with(SYSTEM_PREFIX));
+        }
+
+        let level = match other.get(i) {
+            Some(level) => level,
+            None => return false,
+        };
+
+        if level == WILD_CARD {
+            // `+/#` matches all topics just as `#`, but `a/+/#` does not match `a` unlike `a/#`
+            return i == 0 && pattern == SINGLE_LEVEL_WILD_CARD && filter[1..] == [WILD_CARD];
+        }
+
+        if pattern == SINGLE_LEVEL_WILD_CARD {
+            if i == 0 && level.starts_with(SYSTEM_PREFIX) {
+                return false;
+            }
+            continue;
+        }
+
+        // `+` in `other` is wider than any exact level
+        if level != pattern {
+            return false;
+        }
+    }
+
+    filter.len() == other.len()
 }
 
 #[cfg(test)]
@@ -324,6 +371,10 @@ mod subscription_tests {
                 ],
             ),
             ("sport/#", vec!["sport"], vec!["sport2"]),
+            ("sport/tennis/#", vec!["sport/tennis"], vec!["sport"]),
+            // longer filters
+            ("sport/tennis", vec!["sport/tennis"], vec!["sport"]),
+            ("sport/+", vec!["sport/tennis"], vec!["sport"]),
             ("#", vec!["sport", "sport2"], vec!["$", "$SYS"]),
             // + patterns
             (
@@ -382,4 +433,82 @@ mod subscription_tests {
             }
         }
     }
+
+    #[test]
+    fn contains_subscription() {
+        // Vec<(Subscription, Vec<contained Subscription>, Vec<not contained Subscription>)>
+        let cases = vec![
+            (
+                "a/b",
+                vec!["a/b"],
+                vec!["a", "a/b/c", "a/+", "a/#", "+/b", "#", "a/c"],
+            ),
+            (
+                "a/+",
+                vec!["a/b", "a/+", "a/"],
+                vec!["a", "a/#", "a/b/c", "a/+/c", "+/b", "b/c"],
+            ),
+            (
+                "a/#",
+                vec!["a", "a/b", "a/+", "a/#", "a/b/c", "a/+/c", "a/b/#", "a/+/#"],
+                vec!["#", "+", "+/b", "b/c", "ab/c"],
+            ),
+            (
+                "a/+/c",
+                vec!["a/b/c", "a/+/c"],
+                vec!["a/b", "a/b/#", "a/#", "a/b/d", "a/+/+", "+/b/c"],
+            ),
+            (
+                "a/+/#",
+                vec!["a/b", "a/+", "a/b/c", "a/+/c/d", "a/+/#", "a/b/#"],
+                vec!["a", "a/#", "#", "b/c"],
+            ),
+            (
+                "+/b",
+                vec!["a/b", "+/b", "/b"],
+                vec!["$SYS/b", "a/b/c", "+/#", "#", "+/+"],
+            ),
+            (
+                "+/#",
+                vec!["a", "a/b", "+", "+/b", "+/#", "#"],
+                vec!["$SYS", "$SYS/b", "$SYS/#"],
+            ),
+            (
+                "#",
+                vec!["a", "a/b", "+", "+/#", "#"],
+                vec!["$SYS", "$SYS/#"],
+            ),
+            (
+                "$SYS/#",
+                vec!["$SYS", "$SYS/broker", "$SYS/+/clients"],
+                vec!["#", "+/broker", "$other/broker"],
+            ),
+        ];
+
+        for case in cases {
+            let subscription = Subscription::try_from(case.0).unwrap();
+
+            for positive in case.1 {
+                let other = Subscription::try_from(positive).unwrap();
+
+                assert!(
+                    subscription.contains(&other),
+                    "subscription {:?} should contain {:?}",
+                    subscription.original,
+                    other.original
+                );
+            }
+
+            for negative in case.2 {
+                let other = Subscription::try_from(negative).unwrap();
+
+                assert!(
+                    !subscription.contains(&other),
+                    "subscription {:?} should not contain {:?}",
+                    subscription.original,
+                    other.original
+                );
+            }
+        }
+    }
 }
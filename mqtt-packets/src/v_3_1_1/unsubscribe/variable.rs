@@ -26,7 +26,7 @@ impl VariableCodec {
     }
 
     pub fn encode(&mut self, item: &Variable, dst: &mut BytesMut) -> Result<(), std::io::Error> {
-        dst.extend_from_slice(item.packet_id.as_slice());
+        item.packet_id.encode(dst);
         for topic_filter in &item.subscriptions {
             let encoded = topic_filter.original.as_bytes();
             dst.put_u16(encoded.len() as u16);
@@ -37,7 +37,7 @@ impl VariableCodec {
     }
 
     pub fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Variable>, std::io::Error> {
-        let packet_id = src.split_to(Self::PACKET_ID_LEN).to_vec();
+        let packet_id = PacketId::decode(src)?;
         let mut subscriptions = vec![];
 
         while src.len() > 0 {
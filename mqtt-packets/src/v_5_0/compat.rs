@@ -0,0 +1,489 @@
+//! Conversion between MQTT 5.0 packets and MQTT 3.1.1 `ControlPacket`s. It lets a broker
+//! built around MQTT 3.1.1 packets serve MQTT 5.0 clients.
+//!
+//! MQTT 5.0 properties have no MQTT 3.1.1 counterpart and are dropped (a caller may read
+//! them before a conversion). MQTT 5.0 sessions are mapped onto MQTT 3.1.1 ones as follows:
+//! a session is persistent if `clean_start` is not set and `SessionExpiryInterval` is not 0.
+use bytes::BytesMut;
+use std::io;
+
+use super::{
+    Connack, Connect, Packet, Properties, Property, Publish, PublishResponse, ReasonCode,
+    ReasonPacket, Subscribe, SubscribeResponse, SubscriptionOptions, Unsubscribe, Will,
+};
+use crate::v_3_1_1::builders::{
+    ConnackBuilder, PingrespPacketBuilder, PubackPacketBuilder, PubcompPacketBuilder,
+    PublishPacketBuilder, PubrecPacketBuilder, PubrelPacketBuilder, SubackPacketBuilder,
+    UnsubackPacketBuilder,
+};
+use crate::v_3_1_1::connack::{flags::Flags, return_code::ReturnCode as ConnackReturnCode};
+use crate::v_3_1_1::connect::{
+    connect_flags::ConnectFlags, keep_alive::KeepAlive, protocol_level::ProtocolLevel,
+    protocol_name::ProtocolName, variable::Variable as ConnectVariable,
+};
+use crate::v_3_1_1::cp_fixed_header::FixedHeader;
+use crate::v_3_1_1::publish::fixed_header::{get_qos_level, is_dup, is_retained};
+use crate::v_3_1_1::suback::return_code::ReturnCode as SubackReturnCode;
+use crate::v_3_1_1::subscribe::{
+    topic_subscription::TopicSubscription, variable::Variable as SubscribeVariable,
+};
+use crate::v_3_1_1::topic::Topic;
+use crate::v_3_1_1::unsubscribe::variable::Variable as UnsubscribeVariable;
+use crate::v_3_1_1::variable::{Variable, VariableCodec};
+use crate::v_3_1_1::{CPRemLen, CPType, ControlPacket, Flag, PacketId};
+
+/// Session Expiry Interval which means a session never expires.
+const SESSION_NEVER_EXPIRES: u32 = u32::MAX;
+
+impl Packet {
+    /// It converts a packet into an MQTT 3.1.1 `ControlPacket`. AUTH packets have no
+    /// MQTT 3.1.1 counterpart and can't be converted. Topic aliases of PUBLISH packets
+    /// should be resolved beforehand (see `TopicAliases`).
+    pub fn into_v_3_1_1(self) -> io::Result<ControlPacket> {
+        match self {
+            Packet::Connect(connect) => connect_into_v_3_1_1(connect),
+            Packet::Connack(connack) => Ok(ConnackBuilder::new()
+                .with_session_presented(connack.session_present)
+                .with_return_code(connack_return_code(connack.reason_code))
+                .build()),
+            Packet::Publish(publish) => {
+                if publish.topic_name.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Publish topic alias is not resolved",
+                    ));
+                }
+                let mut builder = PublishPacketBuilder::new();
+                builder
+                    .with_dup(publish.dup)
+                    .with_qos(&publish.qos)
+                    .with_retained(publish.retain)
+                    .with_topic(Topic::try_from(&publish.topic_name)?)
+                    .with_payload(publish.payload);
+                if let Some(packet_id) = publish.packet_id {
+                    builder.with_packet_id(PacketId::new(packet_id));
+                }
+                with_remaining_length(builder.build())
+            }
+            Packet::Puback(response) => {
+                Ok(PubackPacketBuilder::new(&PacketId::new(response.packet_id)).build())
+            }
+            Packet::Pubrec(response) => {
+                Ok(PubrecPacketBuilder::new(&PacketId::new(response.packet_id)).build())
+            }
+            Packet::Pubrel(response) => {
+                Ok(PubrelPacketBuilder::new(&PacketId::new(response.packet_id)).build())
+            }
+            Packet::Pubcomp(response) => {
+                Ok(PubcompPacketBuilder::new(&PacketId::new(response.packet_id)).build())
+            }
+            Packet::Subscribe(subscribe) => control_packet(
+                CPType::Subscribe,
+                Variable::Subscribe(SubscribeVariable {
+                    packet_id: PacketId::new(subscribe.packet_id),
+                    subscriptions: subscribe
+                        .subscriptions
+                        .into_iter()
+                        .map(|(topic_filter, options)| {
+                            TopicSubscription::new(topic_filter, options.qos)
+                        })
+                        .collect(),
+                }),
+            ),
+            Packet::Suback(suback) => Ok(SubackPacketBuilder::new(PacketId::new(suback.packet_id))
+                .with_return_codes(
+                    suback
+                        .reason_codes
+                        .into_iter()
+                        .map(suback_return_code)
+                        .collect(),
+                )
+                .build()),
+            Packet::Unsubscribe(unsubscribe) => control_packet(
+                CPType::Unsubscribe,
+                Variable::Unsubscribe(UnsubscribeVariable {
+                    packet_id: PacketId::new(unsubscribe.packet_id),
+                    subscriptions: unsubscribe.topic_filters,
+                }),
+            ),
+            Packet::Unsuback(unsuback) => {
+                Ok(UnsubackPacketBuilder::new(PacketId::new(unsuback.packet_id)).build())
+            }
+            Packet::Pingreq => control_packet(CPType::Pingreq, Variable::Pingreq),
+            Packet::Pingresp => Ok(PingrespPacketBuilder::new().build()),
+            Packet::Disconnect(_) => control_packet(CPType::Disconnect, Variable::Disconnect),
+            Packet::Auth(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "AUTH packet has no MQTT 3.1.1 counterpart",
+            )),
+        }
+    }
+
+    /// It converts an MQTT 3.1.1 `ControlPacket` into an MQTT 5.0 packet.
+    ///
+    /// MQTT 3.1.1 UNSUBACK doesn't carry return codes, so a resulting UNSUBACK has no reason
+    /// codes. A caller is responsible to add one per topic filter of a related UNSUBSCRIBE.
+    pub fn from_v_3_1_1(packet: &ControlPacket) -> io::Result<Self> {
+        Ok(match packet.variable {
+            Variable::Connect(ref variable) => Packet::Connect(connect_from_v_3_1_1(variable)?),
+            Variable::Connack(ref variable) => Packet::Connack(Connack::new(
+                variable.flags == Flags::SessionPresent,
+                connack_reason_code(&variable.return_code),
+            )),
+            Variable::Publish(ref variable) => Packet::Publish(Publish {
+                dup: is_dup(&packet.fixed_header),
+                qos: get_qos_level(&packet.fixed_header)?,
+                retain: is_retained(&packet.fixed_header),
+                topic_name: variable.topic_name.original.clone(),
+                packet_id: variable
+                    .packet_id
+                    .as_ref()
+                    .map(|packet_id| packet_id.value()),
+                properties: Properties::new(),
+                payload: variable.payload.clone(),
+            }),
+            Variable::Puback(ref variable) => {
+                Packet::Puback(PublishResponse::new(variable.packet_id.value()))
+            }
+            Variable::Pubrec(ref variable) => {
+                Packet::Pubrec(PublishResponse::new(variable.packet_id.value()))
+            }
+            Variable::Pubrel(ref variable) => {
+                Packet::Pubrel(PublishResponse::new(variable.packet_id.value()))
+            }
+            Variable::Pubcomp(ref variable) => {
+                Packet::Pubcomp(PublishResponse::new(variable.packet_id.value()))
+            }
+            Variable::Subscribe(ref variable) => Packet::Subscribe(Subscribe {
+                packet_id: variable.packet_id.value(),
+                properties: Properties::new(),
+                subscriptions: variable
+                    .subscriptions
+                    .iter()
+                    .map(|subscription| {
+                        (
+                            subscription.topic_filter.clone(),
+                            SubscriptionOptions::new(subscription.qos.clone()),
+                        )
+                    })
+                    .collect(),
+            }),
+            Variable::Suback(ref variable) => Packet::Suback(SubscribeResponse {
+                packet_id: variable.packet_id.value(),
+                properties: Properties::new(),
+                reason_codes: variable
+                    .return_codes
+                    .iter()
+                    .map(suback_reason_code)
+                    .collect(),
+            }),
+            Variable::Unsubscribe(ref variable) => Packet::Unsubscribe(Unsubscribe {
+                packet_id: variable.packet_id.value(),
+                properties: Properties::new(),
+                topic_filters: variable.subscriptions.clone(),
+            }),
+            Variable::Unsuback(ref variable) => Packet::Unsuback(SubscribeResponse {
+                packet_id: variable.packet_id.value(),
+                properties: Properties::new(),
+                reason_codes: vec![],
+            }),
+            Variable::Pingreq => Packet::Pingreq,
+            Variable::Pingresp => Packet::Pingresp,
+            Variable::Disconnect => Packet::Disconnect(ReasonPacket::new(ReasonCode::Success)),
+        })
+    }
+}
+
+fn connect_into_v_3_1_1(connect: Connect) -> io::Result<ControlPacket> {
+    let persistent_session =
+        !connect.clean_start && connect.properties.session_expiry_interval().unwrap_or(0) > 0;
+    let password = match connect.password {
+        Some(password) => Some(String::from_utf8(password).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Connect: password is not a valid UTF-8 string",
+            )
+        })?),
+        None => None,
+    };
+
+    let mut connect_flags = ConnectFlags::new(0);
+    connect_flags.set_clean_session(!persistent_session);
+    connect_flags.set_username(connect.username.is_some());
+    connect_flags.set_password(password.is_some());
+    if let Some(ref will) = connect.will {
+        connect_flags.set_will_flag(true);
+        connect_flags.set_qos_value(&will.qos);
+        connect_flags.set_will_retain(will.retain);
+    }
+    let (will_topic, will_message) = match connect.will {
+        Some(will) => (Some(will.topic), Some(will.payload)),
+        None => (None, None),
+    };
+
+    control_packet(
+        CPType::Connect,
+        Variable::Connect(ConnectVariable {
+            protocol_name: ProtocolName::new(ProtocolName::SUPPORTED_PROTOCOL_NAME),
+            protocol_level: ProtocolLevel::new(),
+            connect_flags,
+            keep_alive: KeepAlive::new(connect.keep_alive),
+            client_identifier: connect.client_identifier,
+            will_topic,
+            will_message,
+            username: connect.username,
+            password,
+        }),
+    )
+}
+
+fn connect_from_v_3_1_1(variable: &ConnectVariable) -> io::Result<Connect> {
+    let flags = &variable.connect_flags;
+    let mut connect = Connect::new(
+        variable.client_identifier.clone(),
+        flags.has_clean_session(),
+        variable.keep_alive.as_duration().as_secs() as u16,
+    );
+    if !flags.has_clean_session() {
+        connect
+            .properties
+            .push(Property::SessionExpiryInterval(SESSION_NEVER_EXPIRES));
+    }
+    connect.will = match (&variable.will_topic, &variable.will_message) {
+        (Some(topic), Some(payload)) => Some(Will {
+            topic: topic.clone(),
+            payload: payload.clone(),
+            qos: flags.qos_value()?,
+            retain: flags.has_will_retain(),
+            properties: Properties::new(),
+        }),
+        _ => None,
+    };
+    connect.username = variable.username.clone();
+    connect.password = variable
+        .password
+        .as_ref()
+        .map(|password| password.as_bytes().to_vec());
+
+    Ok(connect)
+}
+
+fn connack_return_code(reason_code: ReasonCode) -> ConnackReturnCode {
+    match reason_code {
+        ReasonCode::Success => ConnackReturnCode::Accepted,
+        ReasonCode::UnsupportedProtocolVersion => ConnackReturnCode::UnacceptableProtocol,
+        ReasonCode::ClientIdentifierNotValid => ConnackReturnCode::IdRejected,
+        ReasonCode::BadUserNameOrPassword => ConnackReturnCode::BadUsernameOrPassword,
+        ReasonCode::NotAuthorized | ReasonCode::Banned | ReasonCode::BadAuthenticationMethod => {
+            ConnackReturnCode::NotAuthorized
+        }
+        _ => ConnackReturnCode::Unavailable,
+    }
+}
+
+fn connack_reason_code(return_code: &ConnackReturnCode) -> ReasonCode {
+    match *return_code {
+        ConnackReturnCode::Accepted => ReasonCode::Success,
+        ConnackReturnCode::UnacceptableProtocol => ReasonCode::UnsupportedProtocolVersion,
+        ConnackReturnCode::IdRejected => ReasonCode::ClientIdentifierNotValid,
+        ConnackReturnCode::Unavailable => ReasonCode::ServerUnavailable,
+        ConnackReturnCode::BadUsernameOrPassword => ReasonCode::BadUserNameOrPassword,
+        ConnackReturnCode::NotAuthorized => ReasonCode::NotAuthorized,
+    }
+}
+
+fn suback_return_code(reason_code: ReasonCode) -> SubackReturnCode {
+    match reason_code {
+        ReasonCode::Success => SubackReturnCode::SuccessZero,
+        ReasonCode::GrantedQoS1 => SubackReturnCode::SuccessOne,
+        ReasonCode::GrantedQoS2 => SubackReturnCode::SuccessTwo,
+        _ => SubackReturnCode::Failure,
+    }
+}
+
+fn suback_reason_code(return_code: &SubackReturnCode) -> ReasonCode {
+    match *return_code {
+        SubackReturnCode::SuccessZero => ReasonCode::Success,
+        SubackReturnCode::SuccessOne => ReasonCode::GrantedQoS1,
+        SubackReturnCode::SuccessTwo => ReasonCode::GrantedQoS2,
+        SubackReturnCode::Failure => ReasonCode::UnspecifiedError,
+    }
+}
+
+/// It creates a `ControlPacket` with default flags of a packet type.
+fn control_packet(cp_type: CPType, variable: Variable) -> io::Result<ControlPacket> {
+    let flag_bits = match cp_type {
+        CPType::Pubrel | CPType::Subscribe | CPType::Unsubscribe => 0b0010,
+        _ => 0,
+    };
+    let flag = Flag::decode(&flag_bits, &cp_type)?;
+
+    with_remaining_length(ControlPacket {
+        fixed_header: FixedHeader {
+            flag,
+            cp_type,
+            remaining_length: CPRemLen::new(0),
+        },
+        variable,
+    })
+}
+
+/// It sets remaining length of a packet to a length of its encoded variable header and payload.
+fn with_remaining_length(mut packet: ControlPacket) -> io::Result<ControlPacket> {
+    let mut buf = BytesMut::new();
+    VariableCodec::create(&packet.fixed_header.flag)?.encode(&packet.variable, &mut buf)?;
+    packet.fixed_header.remaining_length = CPRemLen::new(buf.len() as u32);
+
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v_3_1_1::topic::Subscription;
+    use crate::v_3_1_1::utils::getters_setters::get_packet_id;
+    use crate::v_3_1_1::{ControlPacketCodec, QoS};
+    use bytes::Bytes;
+
+    #[test]
+    fn connect_into_v_3_1_1() {
+        let mut connect = Connect::new("client1".into(), false, 30);
+        connect
+            .properties
+            .push(Property::SessionExpiryInterval(3600));
+        connect.username = Some("user".into());
+        connect.password = Some(b"secret".to_vec());
+        connect.will = Some(Will {
+            topic: Topic::try_from("status").unwrap(),
+            payload: b"offline".to_vec(),
+            qos: QoS::One,
+            retain: true,
+            properties: Properties::new(),
+        });
+
+        let packet = Packet::Connect(connect).into_v_3_1_1().unwrap();
+        match packet.variable {
+            Variable::Connect(ref variable) => {
+                assert_eq!(variable.client_identifier, "client1");
+                assert!(!variable.connect_flags.has_clean_session());
+                assert_eq!(variable.connect_flags.qos_value().unwrap(), QoS::One);
+                assert!(variable.connect_flags.has_will_retain());
+                assert_eq!(variable.password, Some("secret".into()));
+                assert_eq!(variable.will_message, Some(b"offline".to_vec()));
+            }
+            _ => panic!("Connect is expected"),
+        }
+    }
+
+    #[test]
+    fn session_expiry_defines_clean_session() {
+        let clean_session = |clean_start, expiry: Option<u32>| {
+            let mut connect = Connect::new("c".into(), clean_start, 0);
+            if let Some(expiry) = expiry {
+                connect
+                    .properties
+                    .push(Property::SessionExpiryInterval(expiry));
+            }
+            match Packet::Connect(connect).into_v_3_1_1().unwrap().variable {
+                Variable::Connect(ref variable) => variable.connect_flags.has_clean_session(),
+                _ => panic!("Connect is expected"),
+            }
+        };
+
+        assert!(clean_session(true, None));
+        assert!(clean_session(false, None));
+        assert!(clean_session(true, Some(60)));
+        assert!(!clean_session(false, Some(60)));
+    }
+
+    #[test]
+    fn publish_conversion_keeps_flags() {
+        let publish = Publish {
+            dup: true,
+            qos: QoS::Two,
+            retain: true,
+            topic_name: "a/b".into(),
+            packet_id: Some(258),
+            properties: Properties::from(vec![Property::MessageExpiryInterval(1)]),
+            payload: Bytes::from_static(&[1, 2]),
+        };
+        let packet = Packet::Publish(publish.clone()).into_v_3_1_1().unwrap();
+        assert_eq!(get_packet_id(&packet.variable), Some(&PacketId::new(258)));
+
+        // remaining length is correct
+        let mut buf = BytesMut::new();
+        ControlPacketCodec::new()
+            .inner_encode(&packet, &mut buf)
+            .unwrap();
+        assert_eq!(
+            packet.fixed_header.remaining_length.as_value() as usize,
+            buf.len() - 2
+        );
+
+        let mut expected = publish;
+        expected.properties = Properties::new();
+        assert_eq!(
+            Packet::from_v_3_1_1(&packet).unwrap(),
+            Packet::Publish(expected)
+        );
+    }
+
+    #[test]
+    fn unresolved_topic_alias_is_rejected() {
+        let publish = Publish {
+            dup: false,
+            qos: QoS::Zero,
+            retain: false,
+            topic_name: "".into(),
+            packet_id: None,
+            properties: Properties::from(vec![Property::TopicAlias(1)]),
+            payload: Bytes::new(),
+        };
+        assert!(Packet::Publish(publish).into_v_3_1_1().is_err());
+        assert!(Packet::Auth(ReasonPacket::new(ReasonCode::Success))
+            .into_v_3_1_1()
+            .is_err());
+    }
+
+    #[test]
+    fn subscribe_and_suback_conversion() {
+        let subscribe = Packet::Subscribe(Subscribe {
+            packet_id: 1,
+            properties: Properties::new(),
+            subscriptions: vec![(
+                Subscription::try_from("a/+").unwrap(),
+                SubscriptionOptions::new(QoS::One),
+            )],
+        });
+        let packet = subscribe.clone().into_v_3_1_1().unwrap();
+        assert_eq!(packet.fixed_header.cp_type, CPType::Subscribe);
+        assert_eq!(Packet::from_v_3_1_1(&packet).unwrap(), subscribe);
+
+        let suback = SubackPacketBuilder::new(PacketId::new(1))
+            .with_return_codes(vec![
+                SubackReturnCode::SuccessOne,
+                SubackReturnCode::Failure,
+            ])
+            .build();
+        assert_eq!(
+            Packet::from_v_3_1_1(&suback).unwrap(),
+            Packet::Suback(SubscribeResponse {
+                packet_id: 1,
+                properties: Properties::new(),
+                reason_codes: vec![ReasonCode::GrantedQoS1, ReasonCode::UnspecifiedError],
+            })
+        );
+    }
+
+    #[test]
+    fn connack_conversion() {
+        let connack = ConnackBuilder::new()
+            .with_session_presented(true)
+            .with_return_code(ConnackReturnCode::BadUsernameOrPassword)
+            .build();
+        assert_eq!(
+            Packet::from_v_3_1_1(&connack).unwrap(),
+            Packet::Connack(Connack::new(true, ReasonCode::BadUserNameOrPassword))
+        );
+    }
+}
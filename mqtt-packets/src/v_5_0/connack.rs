@@ -0,0 +1,51 @@
+use bytes::{BufMut, BytesMut};
+use std::io;
+
+use super::properties::Properties;
+use super::reason_code::ReasonCode;
+use super::utils::{self, malformed};
+
+/// Connack Control Packet.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Connack {
+    pub session_present: bool,
+    pub reason_code: ReasonCode,
+    pub properties: Properties,
+}
+
+impl Connack {
+    const SESSION_PRESENT_MASK: u8 = 0b00000001;
+
+    pub fn new(session_present: bool, reason_code: ReasonCode) -> Self {
+        Connack {
+            session_present,
+            reason_code,
+            properties: Properties::new(),
+        }
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) -> io::Result<()> {
+        dst.put_u8(if self.session_present {
+            Self::SESSION_PRESENT_MASK
+        } else {
+            0
+        });
+        dst.put_u8(self.reason_code.as_u8());
+        self.properties.encode(dst)
+    }
+
+    pub fn decode(src: &mut BytesMut) -> io::Result<Self> {
+        let flags = utils::decode_u8(src)?;
+        if flags & !Self::SESSION_PRESENT_MASK != 0 {
+            return Err(malformed("reserved connack flags are not zero"));
+        }
+        let reason_code = ReasonCode::try_from(utils::decode_u8(src)?)?;
+        let properties = Properties::decode(src)?;
+
+        Ok(Connack {
+            session_present: flags & Self::SESSION_PRESENT_MASK != 0,
+            reason_code,
+            properties,
+        })
+    }
+}
@@ -0,0 +1,163 @@
+use bytes::{BufMut, BytesMut};
+use std::io;
+
+use super::properties::Properties;
+use super::utils::{self, malformed};
+use crate::v_3_1_1::topic::Topic;
+use crate::v_3_1_1::QoS;
+
+/// Will Message of a Client.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Will {
+    pub topic: Topic,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+    /// Will Properties, e.g. Will Delay Interval or Message Expiry Interval.
+    pub properties: Properties,
+}
+
+/// Connect Control Packet.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Connect {
+    pub client_identifier: String,
+    pub clean_start: bool,
+    pub keep_alive: u16,
+    pub properties: Properties,
+    pub will: Option<Will>,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+}
+
+impl Connect {
+    pub const PROTOCOL_NAME: &'static str = "MQTT";
+    pub const PROTOCOL_LEVEL: u8 = 5;
+
+    const RESERVED_MASK: u8 = 0b00000001;
+    const CLEAN_START_MASK: u8 = 0b00000010;
+    const WILL_FLAG_MASK: u8 = 0b00000100;
+    const WILL_QOS_MASK: u8 = 0b00011000;
+    const WILL_RETAIN_MASK: u8 = 0b00100000;
+    const PASSWORD_MASK: u8 = 0b01000000;
+    const USERNAME_MASK: u8 = 0b10000000;
+
+    pub fn new(client_identifier: String, clean_start: bool, keep_alive: u16) -> Self {
+        Connect {
+            client_identifier,
+            clean_start,
+            keep_alive,
+            properties: Properties::new(),
+            will: None,
+            username: None,
+            password: None,
+        }
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) -> io::Result<()> {
+        utils::encode_string(Self::PROTOCOL_NAME, dst)?;
+        dst.put_u8(Self::PROTOCOL_LEVEL);
+
+        let mut flags = 0u8;
+        if self.clean_start {
+            flags |= Self::CLEAN_START_MASK;
+        }
+        if let Some(ref will) = self.will {
+            flags |= Self::WILL_FLAG_MASK;
+            flags |= will.qos.bits() << 3;
+            if will.retain {
+                flags |= Self::WILL_RETAIN_MASK;
+            }
+        }
+        if self.password.is_some() {
+            flags |= Self::PASSWORD_MASK;
+        }
+        if self.username.is_some() {
+            flags |= Self::USERNAME_MASK;
+        }
+        dst.put_u8(flags);
+        dst.put_u16(self.keep_alive);
+        self.properties.encode(dst)?;
+
+        utils::encode_string(&self.client_identifier, dst)?;
+        if let Some(ref will) = self.will {
+            will.properties.encode(dst)?;
+            utils::encode_string(&will.topic.original, dst)?;
+            utils::encode_binary(&will.payload, dst)?;
+        }
+        if let Some(ref username) = self.username {
+            utils::encode_string(username, dst)?;
+        }
+        if let Some(ref password) = self.password {
+            utils::encode_binary(password, dst)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn decode(src: &mut BytesMut) -> io::Result<Self> {
+        let protocol_name = utils::decode_string(src)?;
+        if protocol_name != Self::PROTOCOL_NAME {
+            return Err(malformed(format!(
+                "unexpected protocol name {:?}",
+                protocol_name
+            )));
+        }
+        let protocol_level = utils::decode_u8(src)?;
+        if protocol_level != Self::PROTOCOL_LEVEL {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported Protocol Version {}", protocol_level),
+            ));
+        }
+
+        let flags = utils::decode_u8(src)?;
+        if flags & Self::RESERVED_MASK != 0 {
+            return Err(malformed("reserved connect flag is not zero"));
+        }
+        let has_will = flags & Self::WILL_FLAG_MASK != 0;
+        let will_qos = QoS::try_from((flags & Self::WILL_QOS_MASK) >> 3)?;
+        let will_retain = flags & Self::WILL_RETAIN_MASK != 0;
+        if !has_will && (will_qos != QoS::Zero || will_retain) {
+            return Err(malformed("will QoS and will retain require will flag"));
+        }
+
+        let keep_alive = utils::decode_u16(src)?;
+        let properties = Properties::decode(src)?;
+        let client_identifier = utils::decode_string(src)?;
+
+        let will = if has_will {
+            let properties = Properties::decode(src)?;
+            let topic = Topic::try_from(utils::decode_string(src)?)?;
+            let payload = utils::decode_binary(src)?;
+            Some(Will {
+                topic,
+                payload,
+                qos: will_qos,
+                retain: will_retain,
+                properties,
+            })
+        } else {
+            None
+        };
+        let username = if flags & Self::USERNAME_MASK != 0 {
+            Some(utils::decode_string(src)?)
+        } else {
+            None
+        };
+        let password = if flags & Self::PASSWORD_MASK != 0 {
+            Some(utils::decode_binary(src)?)
+        } else {
+            None
+        };
+
+        Ok(Connect {
+            client_identifier,
+            clean_start: flags & Self::CLEAN_START_MASK != 0,
+            keep_alive,
+            properties,
+            will,
+            username,
+            password,
+        })
+    }
+}
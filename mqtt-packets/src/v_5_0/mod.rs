@@ -0,0 +1,443 @@
+//! MQTT 5.0 packets.
+//!
+//! Packets are represented as plain structures, one per packet type, and are
+//! encoded/decoded by `PacketCodec`. `compat` converts them from/to MQTT 3.1.1
+//! `ControlPacket`s.
+pub mod compat;
+pub mod connack;
+pub mod connect;
+pub mod properties;
+pub mod publish;
+pub mod publish_response;
+pub mod reason_code;
+pub mod reason_packet;
+pub mod subscribe;
+pub mod topic_alias;
+pub mod unsubscribe;
+pub mod utils;
+
+use bytes::{Buf, BufMut, BytesMut};
+use log::trace;
+use std::io;
+
+use crate::{PacketCodecError, LOG_TARGET};
+
+pub use self::connack::Connack;
+pub use self::connect::{Connect, Will};
+pub use self::properties::{Properties, Property};
+pub use self::publish::Publish;
+pub use self::publish_response::PublishResponse;
+pub use self::reason_code::ReasonCode;
+pub use self::reason_packet::ReasonPacket;
+pub use self::subscribe::{Subscribe, SubscribeResponse, SubscriptionOptions};
+pub use self::topic_alias::TopicAliases;
+pub use self::unsubscribe::Unsubscribe;
+use self::utils::malformed;
+
+/// MQTT 5.0 Control Packet.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Packet {
+    Connect(Connect),
+    Connack(Connack),
+    Publish(Publish),
+    Puback(PublishResponse),
+    Pubrec(PublishResponse),
+    Pubrel(PublishResponse),
+    Pubcomp(PublishResponse),
+    Subscribe(Subscribe),
+    Suback(SubscribeResponse),
+    Unsubscribe(Unsubscribe),
+    Unsuback(SubscribeResponse),
+    Pingreq,
+    Pingresp,
+    Disconnect(ReasonPacket),
+    /// Authentication exchange, new in MQTT 5.0.
+    Auth(ReasonPacket),
+}
+
+impl Packet {
+    const CONNECT: u8 = 1;
+    const CONNACK: u8 = 2;
+    const PUBLISH: u8 = 3;
+    const PUBACK: u8 = 4;
+    const PUBREC: u8 = 5;
+    const PUBREL: u8 = 6;
+    const PUBCOMP: u8 = 7;
+    const SUBSCRIBE: u8 = 8;
+    const SUBACK: u8 = 9;
+    const UNSUBSCRIBE: u8 = 10;
+    const UNSUBACK: u8 = 11;
+    const PINGREQ: u8 = 12;
+    const PINGRESP: u8 = 13;
+    const DISCONNECT: u8 = 14;
+    const AUTH: u8 = 15;
+
+    /// Fixed header flags of Pubrel, Subscribe and Unsubscribe packets.
+    const REQUIRED_FLAGS: u8 = 0b0010;
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Packet::Connect(_) => "CONNECT",
+            Packet::Connack(_) => "CONNACK",
+            Packet::Publish(_) => "PUBLISH",
+            Packet::Puback(_) => "PUBACK",
+            Packet::Pubrec(_) => "PUBREC",
+            Packet::Pubrel(_) => "PUBREL",
+            Packet::Pubcomp(_) => "PUBCOMP",
+            Packet::Subscribe(_) => "SUBSCRIBE",
+            Packet::Suback(_) => "SUBACK",
+            Packet::Unsubscribe(_) => "UNSUBSCRIBE",
+            Packet::Unsuback(_) => "UNSUBACK",
+            Packet::Pingreq => "PINGREQ",
+            Packet::Pingresp => "PINGRESP",
+            Packet::Disconnect(_) => "DISCONNECT",
+            Packet::Auth(_) => "AUTH",
+        }
+    }
+
+    /// Packet type and flags, i.e. the first byte of a fixed header.
+    fn first_byte(&self) -> u8 {
+        let (packet_type, flags) = match *self {
+            Packet::Connect(_) => (Self::CONNECT, 0),
+            Packet::Connack(_) => (Self::CONNACK, 0),
+            Packet::Publish(ref publish) => (Self::PUBLISH, publish.flags()),
+            Packet::Puback(_) => (Self::PUBACK, 0),
+            Packet::Pubrec(_) => (Self::PUBREC, 0),
+            Packet::Pubrel(_) => (Self::PUBREL, Self::REQUIRED_FLAGS),
+            Packet::Pubcomp(_) => (Self::PUBCOMP, 0),
+            Packet::Subscribe(_) => (Self::SUBSCRIBE, Self::REQUIRED_FLAGS),
+            Packet::Suback(_) => (Self::SUBACK, 0),
+            Packet::Unsubscribe(_) => (Self::UNSUBSCRIBE, Self::REQUIRED_FLAGS),
+            Packet::Unsuback(_) => (Self::UNSUBACK, 0),
+            Packet::Pingreq => (Self::PINGREQ, 0),
+            Packet::Pingresp => (Self::PINGRESP, 0),
+            Packet::Disconnect(_) => (Self::DISCONNECT, 0),
+            Packet::Auth(_) => (Self::AUTH, 0),
+        };
+
+        packet_type << 4 | flags
+    }
+
+    fn encode_body(&self, dst: &mut BytesMut) -> io::Result<()> {
+        match *self {
+            Packet::Connect(ref packet) => packet.encode(dst),
+            Packet::Connack(ref packet) => packet.encode(dst),
+            Packet::Publish(ref packet) => packet.encode(dst),
+            Packet::Puback(ref packet)
+            | Packet::Pubrec(ref packet)
+            | Packet::Pubrel(ref packet)
+            | Packet::Pubcomp(ref packet) => packet.encode(dst),
+            Packet::Subscribe(ref packet) => packet.encode(dst),
+            Packet::Suback(ref packet) | Packet::Unsuback(ref packet) => packet.encode(dst),
+            Packet::Unsubscribe(ref packet) => packet.encode(dst),
+            Packet::Pingreq | Packet::Pingresp => Ok(()),
+            Packet::Disconnect(ref packet) | Packet::Auth(ref packet) => packet.encode(dst),
+        }
+    }
+
+    fn decode_body(first_byte: u8, src: &mut BytesMut) -> io::Result<Self> {
+        let packet_type = first_byte >> 4;
+        let flags = first_byte & 0b1111;
+
+        let expected_flags = match packet_type {
+            Self::PUBLISH => flags,
+            Self::PUBREL | Self::SUBSCRIBE | Self::UNSUBSCRIBE => Self::REQUIRED_FLAGS,
+            _ => 0,
+        };
+        if flags != expected_flags {
+            return Err(malformed(format!(
+                "unexpected flags {:#06b} of packet type {}",
+                flags, packet_type
+            )));
+        }
+
+        let packet = match packet_type {
+            Self::CONNECT => Packet::Connect(Connect::decode(src)?),
+            Self::CONNACK => Packet::Connack(Connack::decode(src)?),
+            Self::PUBLISH => Packet::Publish(Publish::decode(flags, src)?),
+            Self::PUBACK => Packet::Puback(PublishResponse::decode(src)?),
+            Self::PUBREC => Packet::Pubrec(PublishResponse::decode(src)?),
+            Self::PUBREL => Packet::Pubrel(PublishResponse::decode(src)?),
+            Self::PUBCOMP => Packet::Pubcomp(PublishResponse::decode(src)?),
+            Self::SUBSCRIBE => Packet::Subscribe(Subscribe::decode(src)?),
+            Self::SUBACK => Packet::Suback(SubscribeResponse::decode(src)?),
+            Self::UNSUBSCRIBE => Packet::Unsubscribe(Unsubscribe::decode(src)?),
+            Self::UNSUBACK => Packet::Unsuback(SubscribeResponse::decode(src)?),
+            Self::PINGREQ => Packet::Pingreq,
+            Self::PINGRESP => Packet::Pingresp,
+            Self::DISCONNECT => Packet::Disconnect(ReasonPacket::decode(src)?),
+            Self::AUTH => Packet::Auth(ReasonPacket::decode(src)?),
+            _ => return Err(malformed(format!("unknown packet type {}", packet_type))),
+        };
+
+        if !src.is_empty() {
+            return Err(malformed(format!(
+                "{} has {} unexpected trailing bytes",
+                packet.name(),
+                src.len()
+            )));
+        }
+
+        Ok(packet)
+    }
+}
+
+/// `Packet` Tokio codec.
+#[derive(Debug, Default)]
+pub struct PacketCodec;
+
+impl PacketCodec {
+    pub fn new() -> Self {
+        PacketCodec {}
+    }
+}
+
+impl tokio_util::codec::Decoder for PacketCodec {
+    type Item = Packet;
+    type Error = PacketCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>, PacketCodecError> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        let peeked = utils::peek_variable_int(&src[1..]).map_err(PacketCodecError::malformed)?;
+        let (remaining_length, remaining_length_len) = match peeked {
+            Some(remaining_length) => remaining_length,
+            None => return Ok(None),
+        };
+        let packet_len = 1 + remaining_length_len + remaining_length as usize;
+
+        if src.len() < packet_len {
+            src.reserve(packet_len - src.len());
+            return Ok(None);
+        }
+
+        let mut packet = src.split_to(packet_len);
+        let first_byte = packet.get_u8();
+        packet.advance(remaining_length_len);
+
+        let packet =
+            Packet::decode_body(first_byte, &mut packet).map_err(PacketCodecError::malformed)?;
+        trace!(target: LOG_TARGET, "Decoded {:?}", packet);
+
+        Ok(Some(packet))
+    }
+}
+
+impl<'a> tokio_util::codec::Encoder<&'a Packet> for PacketCodec {
+    type Error = PacketCodecError;
+
+    fn encode(&mut self, item: &'a Packet, dst: &mut BytesMut) -> Result<(), PacketCodecError> {
+        trace!(target: LOG_TARGET, "Encoding {:?}", item);
+        let mut body = BytesMut::new();
+        item.encode_body(&mut body)
+            .map_err(PacketCodecError::encoding)?;
+
+        dst.put_u8(item.first_byte());
+        utils::encode_variable_int(body.len() as u32, dst).map_err(PacketCodecError::encoding)?;
+        dst.extend_from_slice(&body);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v_3_1_1::topic::{Subscription, Topic};
+    use crate::v_3_1_1::QoS;
+    use bytes::Bytes;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn round_trip(packet: Packet) {
+        let mut codec = PacketCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(&packet, &mut buf).unwrap();
+
+        // incomplete packet
+        let mut partial = BytesMut::from(&buf[..buf.len() - 1]);
+        if !partial.is_empty() {
+            assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        }
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(packet));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn connect_round_trip() {
+        let mut connect = Connect::new("client1".into(), true, 60);
+        connect
+            .properties
+            .push(Property::SessionExpiryInterval(120));
+        connect.properties.push(Property::TopicAliasMaximum(10));
+        connect.username = Some("user".into());
+        connect.password = Some(vec![1, 2, 3]);
+        connect.will = Some(Will {
+            topic: Topic::try_from("devices/1/status").unwrap(),
+            payload: b"offline".to_vec(),
+            qos: QoS::One,
+            retain: true,
+            properties: Properties::from(vec![Property::WillDelayInterval(5)]),
+        });
+        round_trip(Packet::Connect(connect));
+    }
+
+    #[test]
+    fn decode_connect() {
+        // CONNECT, protocol level 5, clean start, keep alive 10, no properties, client id "a"
+        let mut buf = BytesMut::from(
+            &[
+                0x10, 14, 0, 4, b'M', b'Q', b'T', b'T', 5, 0b10, 0, 10, 0, 0, 1, b'a',
+            ][..],
+        );
+        assert_eq!(
+            PacketCodec::new().decode(&mut buf).unwrap(),
+            Some(Packet::Connect(Connect::new("a".into(), true, 10)))
+        );
+    }
+
+    #[test]
+    fn publish_round_trip() {
+        round_trip(Packet::Publish(Publish {
+            dup: true,
+            qos: QoS::Two,
+            retain: true,
+            topic_name: "a/b".into(),
+            packet_id: Some(7),
+            properties: Properties::from(vec![
+                Property::UserProperty("trace_id".into(), "1".into()),
+                Property::MessageExpiryInterval(30),
+            ]),
+            payload: Bytes::from_static(&[1, 2, 3]),
+        }));
+        round_trip(Packet::Publish(Publish {
+            dup: false,
+            qos: QoS::Zero,
+            retain: false,
+            topic_name: "".into(),
+            packet_id: None,
+            properties: Properties::from(vec![Property::TopicAlias(1)]),
+            payload: Bytes::new(),
+        }));
+    }
+
+    #[test]
+    fn publish_response_round_trip() {
+        round_trip(Packet::Puback(PublishResponse::new(1)));
+        round_trip(Packet::Pubrel(PublishResponse::new(2)));
+        round_trip(Packet::Pubrec(PublishResponse {
+            packet_id: 3,
+            reason_code: ReasonCode::NoMatchingSubscribers,
+            properties: Properties::new(),
+        }));
+        round_trip(Packet::Pubcomp(PublishResponse {
+            packet_id: 4,
+            reason_code: ReasonCode::PacketIdentifierNotFound,
+            properties: Properties::from(vec![Property::ReasonString("unknown".into())]),
+        }));
+
+        // reason code and properties are omitted on success
+        let mut buf = BytesMut::new();
+        PacketCodec::new()
+            .encode(&Packet::Puback(PublishResponse::new(1)), &mut buf)
+            .unwrap();
+        assert_eq!(buf.to_vec(), vec![0x40, 2, 0, 1]);
+    }
+
+    #[test]
+    fn subscribe_round_trip() {
+        let mut options = SubscriptionOptions::new(QoS::One);
+        options.no_local = true;
+        options.retain_handling = 2;
+        round_trip(Packet::Subscribe(Subscribe {
+            packet_id: 10,
+            properties: Properties::from(vec![Property::SubscriptionIdentifier(3)]),
+            subscriptions: vec![
+                (Subscription::try_from("a/+").unwrap(), options),
+                (
+                    Subscription::try_from("b/#").unwrap(),
+                    SubscriptionOptions::new(QoS::Zero),
+                ),
+            ],
+        }));
+        round_trip(Packet::Suback(SubscribeResponse {
+            packet_id: 10,
+            properties: Properties::new(),
+            reason_codes: vec![ReasonCode::GrantedQoS1, ReasonCode::NotAuthorized],
+        }));
+        round_trip(Packet::Unsubscribe(Unsubscribe {
+            packet_id: 11,
+            properties: Properties::new(),
+            topic_filters: vec![Subscription::try_from("a/+").unwrap()],
+        }));
+        round_trip(Packet::Unsuback(SubscribeResponse {
+            packet_id: 11,
+            properties: Properties::new(),
+            reason_codes: vec![ReasonCode::NoSubscriptionExisted],
+        }));
+    }
+
+    #[test]
+    fn auth_and_disconnect_round_trip() {
+        round_trip(Packet::Pingreq);
+        round_trip(Packet::Pingresp);
+        round_trip(Packet::Disconnect(ReasonPacket::new(ReasonCode::Success)));
+        round_trip(Packet::Disconnect(ReasonPacket::new(
+            ReasonCode::ServerShuttingDown,
+        )));
+        round_trip(Packet::Auth(ReasonPacket {
+            reason_code: ReasonCode::ContinueAuthentication,
+            properties: Properties::from(vec![
+                Property::AuthenticationMethod("SCRAM-SHA-1".into()),
+                Property::AuthenticationData(vec![1, 2]),
+            ]),
+        }));
+
+        let mut buf = BytesMut::from(&[0xE0u8, 0][..]);
+        assert_eq!(
+            PacketCodec::new().decode(&mut buf).unwrap(),
+            Some(Packet::Disconnect(ReasonPacket::new(ReasonCode::Success)))
+        );
+    }
+
+    fn is_malformed(bytes: &[u8]) -> bool {
+        let mut buf = BytesMut::from(bytes);
+        matches!(
+            PacketCodec::new().decode(&mut buf),
+            Err(PacketCodecError::MalformedPacket(_))
+        )
+    }
+
+    #[test]
+    fn malformed_packets() {
+        // wrong flags of SUBSCRIBE
+        assert!(is_malformed(&[0x80u8, 0]));
+        // trailing bytes in PINGREQ
+        assert!(is_malformed(&[0xC0u8, 1, 0]));
+        // publish QoS 3
+        assert!(is_malformed(&[0x36u8, 4, 0, 1, b'a', 0]));
+        // remaining length longer than 4 bytes
+        assert!(is_malformed(&[0x30u8, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]));
+    }
+
+    #[test]
+    fn oversized_field_is_an_encoding_error() {
+        let publish = Packet::Publish(Publish {
+            dup: false,
+            qos: QoS::Zero,
+            retain: false,
+            topic_name: "a".repeat(70_000),
+            packet_id: None,
+            properties: Properties::new(),
+            payload: Bytes::new(),
+        });
+        let mut buf = BytesMut::new();
+
+        match PacketCodec::new().encode(&publish, &mut buf) {
+            Err(PacketCodecError::Encoding(_)) => {}
+            result => panic!("Encoding error is expected, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use bytes::{BufMut, BytesMut};
+use std::io;
+
+use super::utils::{self, malformed};
+
+/// MQTT 5.0 property (section 2.2.2.2 of the specification).
+#[derive(Debug, PartialEq, Clone)]
+pub enum Property {
+    PayloadFormatIndicator(u8),
+    MessageExpiryInterval(u32),
+    ContentType(String),
+    ResponseTopic(String),
+    CorrelationData(Vec<u8>),
+    SubscriptionIdentifier(u32),
+    SessionExpiryInterval(u32),
+    AssignedClientIdentifier(String),
+    ServerKeepAlive(u16),
+    AuthenticationMethod(String),
+    AuthenticationData(Vec<u8>),
+    RequestProblemInformation(u8),
+    WillDelayInterval(u32),
+    RequestResponseInformation(u8),
+    ResponseInformation(String),
+    ServerReference(String),
+    ReasonString(String),
+    ReceiveMaximum(u16),
+    TopicAliasMaximum(u16),
+    TopicAlias(u16),
+    MaximumQoS(u8),
+    RetainAvailable(u8),
+    UserProperty(String, String),
+    MaximumPacketSize(u32),
+    WildcardSubscriptionAvailable(u8),
+    SubscriptionIdentifierAvailable(u8),
+    SharedSubscriptionAvailable(u8),
+}
+
+impl Property {
+    const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+    const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+    const CONTENT_TYPE: u8 = 0x03;
+    const RESPONSE_TOPIC: u8 = 0x08;
+    const CORRELATION_DATA: u8 = 0x09;
+    const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+    const SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+    const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+    const SERVER_KEEP_ALIVE: u8 = 0x13;
+    const AUTHENTICATION_METHOD: u8 = 0x15;
+    const AUTHENTICATION_DATA: u8 = 0x16;
+    const REQUEST_PROBLEM_INFORMATION: u8 = 0x17;
+    const WILL_DELAY_INTERVAL: u8 = 0x18;
+    const REQUEST_RESPONSE_INFORMATION: u8 = 0x19;
+    const RESPONSE_INFORMATION: u8 = 0x1A;
+    const SERVER_REFERENCE: u8 = 0x1C;
+    const REASON_STRING: u8 = 0x1F;
+    const RECEIVE_MAXIMUM: u8 = 0x21;
+    const TOPIC_ALIAS_MAXIMUM: u8 = 0x22;
+    const TOPIC_ALIAS: u8 = 0x23;
+    const MAXIMUM_QOS: u8 = 0x24;
+    const RETAIN_AVAILABLE: u8 = 0x25;
+    const USER_PROPERTY: u8 = 0x26;
+    const MAXIMUM_PACKET_SIZE: u8 = 0x27;
+    const WILDCARD_SUBSCRIPTION_AVAILABLE: u8 = 0x28;
+    const SUBSCRIPTION_IDENTIFIER_AVAILABLE: u8 = 0x29;
+    const SHARED_SUBSCRIPTION_AVAILABLE: u8 = 0x2A;
+
+    pub fn identifier(&self) -> u8 {
+        match *self {
+            Property::PayloadFormatIndicator(_) => Self::PAYLOAD_FORMAT_INDICATOR,
+            Property::MessageExpiryInterval(_) => Self::MESSAGE_EXPIRY_INTERVAL,
+            Property::ContentType(_) => Self::CONTENT_TYPE,
+            Property::ResponseTopic(_) => Self::RESPONSE_TOPIC,
+            Property::CorrelationData(_) => Self::CORRELATION_DATA,
+            Property::SubscriptionIdentifier(_) => Self::SUBSCRIPTION_IDENTIFIER,
+            Property::SessionExpiryInterval(_) => Self::SESSION_EXPIRY_INTERVAL,
+            Property::AssignedClientIdentifier(_) => Self::ASSIGNED_CLIENT_IDENTIFIER,
+            Property::ServerKeepAlive(_) => Self::SERVER_KEEP_ALIVE,
+            Property::AuthenticationMethod(_) => Self::AUTHENTICATION_METHOD,
+            Property::AuthenticationData(_) => Self::AUTHENTICATION_DATA,
+            Property::RequestProblemInformation(_) => Self::REQUEST_PROBLEM_INFORMATION,
+            Property::WillDelayInterval(_) => Self::WILL_DELAY_INTERVAL,
+            Property::RequestResponseInformation(_) => Self::REQUEST_RESPONSE_INFORMATION,
+            Property::ResponseInformation(_) => Self::RESPONSE_INFORMATION,
+            Property::ServerReference(_) => Self::SERVER_REFERENCE,
+            Property::ReasonString(_) => Self::REASON_STRING,
+            Property::ReceiveMaximum(_) => Self::RECEIVE_MAXIMUM,
+            Property::TopicAliasMaximum(_) => Self::TOPIC_ALIAS_MAXIMUM,
+            Property::TopicAlias(_) => Self::TOPIC_ALIAS,
+            Property::MaximumQoS(_) => Self::MAXIMUM_QOS,
+            Property::RetainAvailable(_) => Self::RETAIN_AVAILABLE,
+            Property::UserProperty(_, _) => Self::USER_PROPERTY,
+            Property::MaximumPacketSize(_) => Self::MAXIMUM_PACKET_SIZE,
+            Property::WildcardSubscriptionAvailable(_) => Self::WILDCARD_SUBSCRIPTION_AVAILABLE,
+            Property::SubscriptionIdentifierAvailable(_) => Self::SUBSCRIPTION_IDENTIFIER_AVAILABLE,
+            Property::SharedSubscriptionAvailable(_) => Self::SHARED_SUBSCRIPTION_AVAILABLE,
+        }
+    }
+
+    fn encode(&self, dst: &mut BytesMut) -> io::Result<()> {
+        dst.put_u8(self.identifier());
+        match *self {
+            Property::PayloadFormatIndicator(value)
+            | Property::RequestProblemInformation(value)
+            | Property::RequestResponseInformation(value)
+            | Property::MaximumQoS(value)
+            | Property::RetainAvailable(value)
+            | Property::WildcardSubscriptionAvailable(value)
+            | Property::SubscriptionIdentifierAvailable(value)
+            | Property::SharedSubscriptionAvailable(value) => dst.put_u8(value),
+            Property::ServerKeepAlive(value)
+            | Property::ReceiveMaximum(value)
+            | Property::TopicAliasMaximum(value)
+            | Property::TopicAlias(value) => dst.put_u16(value),
+            Property::MessageExpiryInterval(value)
+            | Property::SessionExpiryInterval(value)
+            | Property::WillDelayInterval(value)
+            | Property::MaximumPacketSize(value) => dst.put_u32(value),
+            Property::SubscriptionIdentifier(value) => utils::encode_variable_int(value, dst)?,
+            Property::ContentType(ref value)
+            | Property::ResponseTopic(ref value)
+            | Property::AssignedClientIdentifier(ref value)
+            | Property::AuthenticationMethod(ref value)
+            | Property::ResponseInformation(ref value)
+            | Property::ServerReference(ref value)
+            | Property::ReasonString(ref value) => utils::encode_string(value, dst)?,
+            Property::CorrelationData(ref value) | Property::AuthenticationData(ref value) => {
+                utils::encode_binary(value, dst)?
+            }
+            Property::UserProperty(ref key, ref value) => {
+                utils::encode_string(key, dst)?;
+                utils::encode_string(value, dst)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn decode(src: &mut BytesMut) -> io::Result<Self> {
+        // identifier is a Variable Byte Integer, but all defined identifiers fit into one byte
+        let identifier = utils::decode_variable_int(src)?;
+        let identifier = if identifier > u8::MAX as u32 {
+            return Err(malformed(format!("unknown property {:#x}", identifier)));
+        } else {
+            identifier as u8
+        };
+
+        Ok(match identifier {
+            Self::PAYLOAD_FORMAT_INDICATOR => {
+                Property::PayloadFormatIndicator(utils::decode_u8(src)?)
+            }
+            Self::MESSAGE_EXPIRY_INTERVAL => {
+                Property::MessageExpiryInterval(utils::decode_u32(src)?)
+            }
+            Self::CONTENT_TYPE => Property::ContentType(utils::decode_string(src)?),
+            Self::RESPONSE_TOPIC => Property::ResponseTopic(utils::decode_string(src)?),
+            Self::CORRELATION_DATA => Property::CorrelationData(utils::decode_binary(src)?),
+            Self::SUBSCRIPTION_IDENTIFIER => {
+                Property::SubscriptionIdentifier(utils::decode_variable_int(src)?)
+            }
+            Self::SESSION_EXPIRY_INTERVAL => {
+                Property::SessionExpiryInterval(utils::decode_u32(src)?)
+            }
+            Self::ASSIGNED_CLIENT_IDENTIFIER => {
+                Property::AssignedClientIdentifier(utils::decode_string(src)?)
+            }
+            Self::SERVER_KEEP_ALIVE => Property::ServerKeepAlive(utils::decode_u16(src)?),
+            Self::AUTHENTICATION_METHOD => {
+                Property::AuthenticationMethod(utils::decode_string(src)?)
+            }
+            Self::AUTHENTICATION_DATA => Property::AuthenticationData(utils::decode_binary(src)?),
+            Self::REQUEST_PROBLEM_INFORMATION => {
+                Property::RequestProblemInformation(utils::decode_u8(src)?)
+            }
+            Self::WILL_DELAY_INTERVAL => Property::WillDelayInterval(utils::decode_u32(src)?),
+            Self::REQUEST_RESPONSE_INFORMATION => {
+                Property::RequestResponseInformation(utils::decode_u8(src)?)
+            }
+            Self::RESPONSE_INFORMATION => Property::ResponseInformation(utils::decode_string(src)?),
+            Self::SERVER_REFERENCE => Property::ServerReference(utils::decode_string(src)?),
+            Self::REASON_STRING => Property::ReasonString(utils::decode_string(src)?),
+            Self::RECEIVE_MAXIMUM => Property::ReceiveMaximum(utils::decode_u16(src)?),
+            Self::TOPIC_ALIAS_MAXIMUM => Property::TopicAliasMaximum(utils::decode_u16(src)?),
+            Self::TOPIC_ALIAS => Property::TopicAlias(utils::decode_u16(src)?),
+            Self::MAXIMUM_QOS => Property::MaximumQoS(utils::decode_u8(src)?),
+            Self::RETAIN_AVAILABLE => Property::RetainAvailable(utils::decode_u8(src)?),
+            Self::USER_PROPERTY => {
+                let key = utils::decode_string(src)?;
+                let value = utils::decode_string(src)?;
+                Property::UserProperty(key, value)
+            }
+            Self::MAXIMUM_PACKET_SIZE => Property::MaximumPacketSize(utils::decode_u32(src)?),
+            Self::WILDCARD_SUBSCRIPTION_AVAILABLE => {
+                Property::WildcardSubscriptionAvailable(utils::decode_u8(src)?)
+            }
+            Self::SUBSCRIPTION_IDENTIFIER_AVAILABLE => {
+                Property::SubscriptionIdentifierAvailable(utils::decode_u8(src)?)
+            }
+            Self::SHARED_SUBSCRIPTION_AVAILABLE => {
+                Property::SharedSubscriptionAvailable(utils::decode_u8(src)?)
+            }
+            _ => return Err(malformed(format!("unknown property {:#04x}", identifier))),
+        })
+    }
+}
+
+/// A set of properties of a packet. Properties are kept in the order they were
+/// received, a property may appear more than once (e.g. `UserProperty`).
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Properties(Vec<Property>);
+
+impl Properties {
+    pub fn new() -> Self {
+        Properties::default()
+    }
+
+    pub fn push(&mut self, property: Property) {
+        self.0.push(property);
+    }
+
+    /// Adds a property, replacing a previous property of the same type.
+    pub fn set(&mut self, property: Property) {
+        let identifier = property.identifier();
+        self.0.retain(|p| p.identifier() != identifier);
+        self.0.push(property);
+    }
+
+    /// Removes a topic alias and returns it. A topic alias is meaningful only on a single
+    /// network connection, so it's removed once it has been resolved.
+    pub fn take_topic_alias(&mut self) -> Option<u16> {
+        let alias = self.topic_alias();
+        self.0.retain(|p| !matches!(*p, Property::TopicAlias(_)));
+        alias
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Property> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn user_properties(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().filter_map(|p| match *p {
+            Property::UserProperty(ref key, ref value) => Some((key.as_str(), value.as_str())),
+            _ => None,
+        })
+    }
+
+    pub fn topic_alias(&self) -> Option<u16> {
+        self.0.iter().find_map(|p| match *p {
+            Property::TopicAlias(alias) => Some(alias),
+            _ => None,
+        })
+    }
+
+    pub fn message_expiry_interval(&self) -> Option<u32> {
+        self.0.iter().find_map(|p| match *p {
+            Property::MessageExpiryInterval(interval) => Some(interval),
+            _ => None,
+        })
+    }
+
+    pub fn session_expiry_interval(&self) -> Option<u32> {
+        self.0.iter().find_map(|p| match *p {
+            Property::SessionExpiryInterval(interval) => Some(interval),
+            _ => None,
+        })
+    }
+
+    pub fn authentication_method(&self) -> Option<&str> {
+        self.0.iter().find_map(|p| match *p {
+            Property::AuthenticationMethod(ref method) => Some(method.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        for property in &self.0 {
+            property.encode(&mut buf)?;
+        }
+        utils::encode_variable_int(buf.len() as u32, dst)?;
+        dst.extend_from_slice(&buf);
+
+        Ok(())
+    }
+
+    pub fn decode(src: &mut BytesMut) -> io::Result<Self> {
+        let len = utils::decode_variable_int(src)? as usize;
+        if src.len() < len {
+            return Err(malformed("properties are shorter than their length"));
+        }
+        let mut buf = src.split_to(len);
+        let mut properties = vec![];
+        while !buf.is_empty() {
+            properties.push(Property::decode(&mut buf)?);
+        }
+
+        Ok(Properties(properties))
+    }
+}
+
+impl From<Vec<Property>> for Properties {
+    fn from(properties: Vec<Property>) -> Self {
+        Properties(properties)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn properties_round_trip() {
+        let properties = Properties::from(vec![
+            Property::MessageExpiryInterval(60),
+            Property::TopicAlias(3),
+            Property::UserProperty("trace_id".into(), "1".into()),
+            Property::SubscriptionIdentifier(16_384),
+            Property::CorrelationData(vec![1, 2, 3]),
+            Property::UserProperty("trace_id".into(), "2".into()),
+        ]);
+
+        let mut buf = BytesMut::new();
+        properties.encode(&mut buf).unwrap();
+        buf.extend_from_slice(&[0xFF]);
+
+        assert_eq!(Properties::decode(&mut buf).unwrap(), properties);
+        assert_eq!(buf.to_vec(), vec![0xFF], "should leave remaining bytes");
+        assert_eq!(properties.topic_alias(), Some(3));
+        assert_eq!(properties.message_expiry_interval(), Some(60));
+        assert_eq!(
+            properties.user_properties().collect::<Vec<_>>(),
+            vec![("trace_id", "1"), ("trace_id", "2")]
+        );
+    }
+
+    #[test]
+    fn empty_properties() {
+        let mut buf = BytesMut::new();
+        Properties::new().encode(&mut buf).unwrap();
+        assert_eq!(buf.to_vec(), vec![0]);
+        assert!(Properties::decode(&mut buf).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_property() {
+        let mut buf = BytesMut::from(&[2u8, 0x7F, 0][..]);
+        assert!(Properties::decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn set_and_take() {
+        let mut properties = Properties::new();
+        properties.set(Property::TopicAliasMaximum(1));
+        properties.set(Property::TopicAliasMaximum(2));
+        properties.push(Property::TopicAlias(5));
+        assert_eq!(
+            properties.iter().collect::<Vec<_>>(),
+            vec![&Property::TopicAliasMaximum(2), &Property::TopicAlias(5)]
+        );
+        assert_eq!(properties.take_topic_alias(), Some(5));
+        assert_eq!(properties.topic_alias(), None);
+        assert_eq!(
+            properties.iter().collect::<Vec<_>>(),
+            vec![&Property::TopicAliasMaximum(2)]
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::io;
+
+use super::properties::Properties;
+use super::utils::{self, malformed};
+use crate::v_3_1_1::QoS;
+
+/// Publish Control Packet.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Publish {
+    pub dup: bool,
+    pub qos: QoS,
+    pub retain: bool,
+    /// Topic Name. It's empty if a topic is identified by a Topic Alias only.
+    pub topic_name: String,
+    /// The Packet Identifier field is only present in PUBLISH Packets where the QoS level is 1 or 2.
+    pub packet_id: Option<u16>,
+    pub properties: Properties,
+    pub payload: Bytes,
+}
+
+impl Publish {
+    const DUP_MASK: u8 = 0b00001000;
+    const QOS_MASK: u8 = 0b00000110;
+    const RETAIN_MASK: u8 = 0b00000001;
+
+    /// Fixed header flags of the packet.
+    pub fn flags(&self) -> u8 {
+        let mut flags = self.qos.bits() << 1;
+        if self.dup {
+            flags |= Self::DUP_MASK;
+        }
+        if self.retain {
+            flags |= Self::RETAIN_MASK;
+        }
+        flags
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) -> io::Result<()> {
+        utils::encode_string(&self.topic_name, dst)?;
+        match (&self.qos, self.packet_id) {
+            (&QoS::Zero, _) => {}
+            (_, Some(packet_id)) => dst.put_u16(packet_id),
+            (_, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Publish with QoS > 0 requires a packet identifier",
+                ));
+            }
+        }
+        self.properties.encode(dst)?;
+        dst.extend_from_slice(&self.payload);
+
+        Ok(())
+    }
+
+    pub fn decode(flags: u8, src: &mut BytesMut) -> io::Result<Self> {
+        let qos = QoS::try_from((flags & Self::QOS_MASK) >> 1)
+            .map_err(|_| malformed("publish QoS is 3"))?;
+        let topic_name = utils::decode_string(src)?;
+        let packet_id = if qos == QoS::Zero {
+            None
+        } else {
+            Some(utils::decode_u16(src)?)
+        };
+        let properties = Properties::decode(src)?;
+        let payload = src.split().freeze();
+
+        Ok(Publish {
+            dup: flags & Self::DUP_MASK != 0,
+            qos,
+            retain: flags & Self::RETAIN_MASK != 0,
+            topic_name,
+            packet_id,
+            properties,
+            payload,
+        })
+    }
+}
@@ -0,0 +1,57 @@
+use bytes::{BufMut, BytesMut};
+use std::io;
+
+use super::properties::Properties;
+use super::reason_code::ReasonCode;
+use super::utils;
+
+/// Variable header of Puback, Pubrec, Pubrel and Pubcomp Control Packets.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PublishResponse {
+    pub packet_id: u16,
+    pub reason_code: ReasonCode,
+    pub properties: Properties,
+}
+
+impl PublishResponse {
+    pub fn new(packet_id: u16) -> Self {
+        PublishResponse {
+            packet_id,
+            reason_code: ReasonCode::Success,
+            properties: Properties::new(),
+        }
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) -> io::Result<()> {
+        dst.put_u16(self.packet_id);
+        // reason code and properties may be omitted on success
+        if self.reason_code != ReasonCode::Success || !self.properties.is_empty() {
+            dst.put_u8(self.reason_code.as_u8());
+            if !self.properties.is_empty() {
+                self.properties.encode(dst)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn decode(src: &mut BytesMut) -> io::Result<Self> {
+        let packet_id = utils::decode_u16(src)?;
+        let reason_code = if src.is_empty() {
+            ReasonCode::Success
+        } else {
+            ReasonCode::try_from(utils::decode_u8(src)?)?
+        };
+        let properties = if src.is_empty() {
+            Properties::new()
+        } else {
+            Properties::decode(src)?
+        };
+
+        Ok(PublishResponse {
+            packet_id,
+            reason_code,
+            properties,
+        })
+    }
+}
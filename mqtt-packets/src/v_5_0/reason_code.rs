@@ -0,0 +1,174 @@
+use std::io;
+
+use super::utils::malformed;
+
+/// MQTT 5.0 Reason Code (section 2.4 of the specification). Codes below `0x80` indicate
+/// success, the rest indicate failure.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ReasonCode {
+    /// Success, Normal disconnection or Granted QoS 0 depending on a packet.
+    #[default]
+    Success,
+    GrantedQoS1,
+    GrantedQoS2,
+    DisconnectWithWillMessage,
+    NoMatchingSubscribers,
+    NoSubscriptionExisted,
+    ContinueAuthentication,
+    ReAuthenticate,
+    UnspecifiedError,
+    MalformedPacket,
+    ProtocolError,
+    ImplementationSpecificError,
+    UnsupportedProtocolVersion,
+    ClientIdentifierNotValid,
+    BadUserNameOrPassword,
+    NotAuthorized,
+    ServerUnavailable,
+    ServerBusy,
+    Banned,
+    ServerShuttingDown,
+    BadAuthenticationMethod,
+    KeepAliveTimeout,
+    SessionTakenOver,
+    TopicFilterInvalid,
+    TopicNameInvalid,
+    PacketIdentifierInUse,
+    PacketIdentifierNotFound,
+    ReceiveMaximumExceeded,
+    TopicAliasInvalid,
+    PacketTooLarge,
+    MessageRateTooHigh,
+    QuotaExceeded,
+    AdministrativeAction,
+    PayloadFormatInvalid,
+    RetainNotSupported,
+    QoSNotSupported,
+    UseAnotherServer,
+    ServerMoved,
+    SharedSubscriptionsNotSupported,
+    ConnectionRateExceeded,
+    MaximumConnectTime,
+    SubscriptionIdentifiersNotSupported,
+    WildcardSubscriptionsNotSupported,
+}
+
+impl ReasonCode {
+    pub fn try_from(byte: u8) -> io::Result<Self> {
+        Ok(match byte {
+            0x00 => ReasonCode::Success,
+            0x01 => ReasonCode::GrantedQoS1,
+            0x02 => ReasonCode::GrantedQoS2,
+            0x04 => ReasonCode::DisconnectWithWillMessage,
+            0x10 => ReasonCode::NoMatchingSubscribers,
+            0x11 => ReasonCode::NoSubscriptionExisted,
+            0x18 => ReasonCode::ContinueAuthentication,
+            0x19 => ReasonCode::ReAuthenticate,
+            0x80 => ReasonCode::UnspecifiedError,
+            0x81 => ReasonCode::MalformedPacket,
+            0x82 => ReasonCode::ProtocolError,
+            0x83 => ReasonCode::ImplementationSpecificError,
+            0x84 => ReasonCode::UnsupportedProtocolVersion,
+            0x85 => ReasonCode::ClientIdentifierNotValid,
+            0x86 => ReasonCode::BadUserNameOrPassword,
+            0x87 => ReasonCode::NotAuthorized,
+            0x88 => ReasonCode::ServerUnavailable,
+            0x89 => ReasonCode::ServerBusy,
+            0x8A => ReasonCode::Banned,
+            0x8B => ReasonCode::ServerShuttingDown,
+            0x8C => ReasonCode::BadAuthenticationMethod,
+            0x8D => ReasonCode::KeepAliveTimeout,
+            0x8E => ReasonCode::SessionTakenOver,
+            0x8F => ReasonCode::TopicFilterInvalid,
+            0x90 => ReasonCode::TopicNameInvalid,
+            0x91 => ReasonCode::PacketIdentifierInUse,
+            0x92 => ReasonCode::PacketIdentifierNotFound,
+            0x93 => ReasonCode::ReceiveMaximumExceeded,
+            0x94 => ReasonCode::TopicAliasInvalid,
+            0x95 => ReasonCode::PacketTooLarge,
+            0x96 => ReasonCode::MessageRateTooHigh,
+            0x97 => ReasonCode::QuotaExceeded,
+            0x98 => ReasonCode::AdministrativeAction,
+            0x99 => ReasonCode::PayloadFormatInvalid,
+            0x9A => ReasonCode::RetainNotSupported,
+            0x9B => ReasonCode::QoSNotSupported,
+            0x9C => ReasonCode::UseAnotherServer,
+            0x9D => ReasonCode::ServerMoved,
+            0x9E => ReasonCode::SharedSubscriptionsNotSupported,
+            0x9F => ReasonCode::ConnectionRateExceeded,
+            0xA0 => ReasonCode::MaximumConnectTime,
+            0xA1 => ReasonCode::SubscriptionIdentifiersNotSupported,
+            0xA2 => ReasonCode::WildcardSubscriptionsNotSupported,
+            _ => return Err(malformed(format!("unknown reason code {:#04x}", byte))),
+        })
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match *self {
+            ReasonCode::Success => 0x00,
+            ReasonCode::GrantedQoS1 => 0x01,
+            ReasonCode::GrantedQoS2 => 0x02,
+            ReasonCode::DisconnectWithWillMessage => 0x04,
+            ReasonCode::NoMatchingSubscribers => 0x10,
+            ReasonCode::NoSubscriptionExisted => 0x11,
+            ReasonCode::ContinueAuthentication => 0x18,
+            ReasonCode::ReAuthenticate => 0x19,
+            ReasonCode::UnspecifiedError => 0x80,
+            ReasonCode::MalformedPacket => 0x81,
+            ReasonCode::ProtocolError => 0x82,
+            ReasonCode::ImplementationSpecificError => 0x83,
+            ReasonCode::UnsupportedProtocolVersion => 0x84,
+            ReasonCode::ClientIdentifierNotValid => 0x85,
+            ReasonCode::BadUserNameOrPassword => 0x86,
+            ReasonCode::NotAuthorized => 0x87,
+            ReasonCode::ServerUnavailable => 0x88,
+            ReasonCode::ServerBusy => 0x89,
+            ReasonCode::Banned => 0x8A,
+            ReasonCode::ServerShuttingDown => 0x8B,
+            ReasonCode::BadAuthenticationMethod => 0x8C,
+            ReasonCode::KeepAliveTimeout => 0x8D,
+            ReasonCode::SessionTakenOver => 0x8E,
+            ReasonCode::TopicFilterInvalid => 0x8F,
+            ReasonCode::TopicNameInvalid => 0x90,
+            ReasonCode::PacketIdentifierInUse => 0x91,
+            ReasonCode::PacketIdentifierNotFound => 0x92,
+            ReasonCode::ReceiveMaximumExceeded => 0x93,
+            ReasonCode::TopicAliasInvalid => 0x94,
+            ReasonCode::PacketTooLarge => 0x95,
+            ReasonCode::MessageRateTooHigh => 0x96,
+            ReasonCode::QuotaExceeded => 0x97,
+            ReasonCode::AdministrativeAction => 0x98,
+            ReasonCode::PayloadFormatInvalid => 0x99,
+            ReasonCode::RetainNotSupported => 0x9A,
+            ReasonCode::QoSNotSupported => 0x9B,
+            ReasonCode::UseAnotherServer => 0x9C,
+            ReasonCode::ServerMoved => 0x9D,
+            ReasonCode::SharedSubscriptionsNotSupported => 0x9E,
+            ReasonCode::ConnectionRateExceeded => 0x9F,
+            ReasonCode::MaximumConnectTime => 0xA0,
+            ReasonCode::SubscriptionIdentifiersNotSupported => 0xA1,
+            ReasonCode::WildcardSubscriptionsNotSupported => 0xA2,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.as_u8() < 0x80
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reason_code_round_trip() {
+        for byte in 0..=255u8 {
+            if let Ok(code) = ReasonCode::try_from(byte) {
+                assert_eq!(code.as_u8(), byte);
+            }
+        }
+        assert!(ReasonCode::try_from(0x03).is_err());
+        assert!(ReasonCode::GrantedQoS2.is_success());
+        assert!(!ReasonCode::NotAuthorized.is_success());
+    }
+}
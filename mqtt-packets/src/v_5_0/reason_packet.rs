@@ -0,0 +1,52 @@
+use bytes::{BufMut, BytesMut};
+use std::io;
+
+use super::properties::Properties;
+use super::reason_code::ReasonCode;
+use super::utils;
+
+/// Variable header of Disconnect and Auth Control Packets.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReasonPacket {
+    pub reason_code: ReasonCode,
+    pub properties: Properties,
+}
+
+impl ReasonPacket {
+    pub fn new(reason_code: ReasonCode) -> Self {
+        ReasonPacket {
+            reason_code,
+            properties: Properties::new(),
+        }
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) -> io::Result<()> {
+        // the whole variable header may be omitted on success
+        if self.reason_code != ReasonCode::Success || !self.properties.is_empty() {
+            dst.put_u8(self.reason_code.as_u8());
+            if !self.properties.is_empty() {
+                self.properties.encode(dst)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn decode(src: &mut BytesMut) -> io::Result<Self> {
+        let reason_code = if src.is_empty() {
+            ReasonCode::Success
+        } else {
+            ReasonCode::try_from(utils::decode_u8(src)?)?
+        };
+        let properties = if src.is_empty() {
+            Properties::new()
+        } else {
+            Properties::decode(src)?
+        };
+
+        Ok(ReasonPacket {
+            reason_code,
+            properties,
+        })
+    }
+}
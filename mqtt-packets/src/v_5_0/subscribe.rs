@@ -0,0 +1,140 @@
+use bytes::{BufMut, BytesMut};
+use std::io;
+
+use super::properties::Properties;
+use super::reason_code::ReasonCode;
+use super::utils::{self, malformed};
+use crate::v_3_1_1::topic::Subscription;
+use crate::v_3_1_1::QoS;
+
+/// Subscription Options byte of a topic filter.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SubscriptionOptions {
+    /// Maximum QoS.
+    pub qos: QoS,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    /// 0 - send retained messages on subscribe, 1 - send retained messages only if
+    /// a subscription doesn't exist yet, 2 - don't send retained messages.
+    pub retain_handling: u8,
+}
+
+impl SubscriptionOptions {
+    const QOS_MASK: u8 = 0b00000011;
+    const NO_LOCAL_MASK: u8 = 0b00000100;
+    const RETAIN_AS_PUBLISHED_MASK: u8 = 0b00001000;
+    const RETAIN_HANDLING_MASK: u8 = 0b00110000;
+    const RESERVED_MASK: u8 = 0b11000000;
+
+    pub fn new(qos: QoS) -> Self {
+        SubscriptionOptions {
+            qos,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: 0,
+        }
+    }
+
+    fn encode(&self, dst: &mut BytesMut) {
+        let mut byte = self.qos.bits() | (self.retain_handling << 4);
+        if self.no_local {
+            byte |= Self::NO_LOCAL_MASK;
+        }
+        if self.retain_as_published {
+            byte |= Self::RETAIN_AS_PUBLISHED_MASK;
+        }
+        dst.put_u8(byte);
+    }
+
+    fn decode(src: &mut BytesMut) -> io::Result<Self> {
+        let byte = utils::decode_u8(src)?;
+        let retain_handling = (byte & Self::RETAIN_HANDLING_MASK) >> 4;
+        if byte & Self::RESERVED_MASK != 0 || retain_handling > 2 {
+            return Err(malformed("invalid subscription options"));
+        }
+
+        Ok(SubscriptionOptions {
+            qos: QoS::try_from(byte & Self::QOS_MASK)?,
+            no_local: byte & Self::NO_LOCAL_MASK != 0,
+            retain_as_published: byte & Self::RETAIN_AS_PUBLISHED_MASK != 0,
+            retain_handling,
+        })
+    }
+}
+
+/// Subscribe Control Packet.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Subscribe {
+    pub packet_id: u16,
+    pub properties: Properties,
+    pub subscriptions: Vec<(Subscription, SubscriptionOptions)>,
+}
+
+impl Subscribe {
+    pub fn encode(&self, dst: &mut BytesMut) -> io::Result<()> {
+        dst.put_u16(self.packet_id);
+        self.properties.encode(dst)?;
+        for (topic_filter, options) in &self.subscriptions {
+            utils::encode_string(&topic_filter.original, dst)?;
+            options.encode(dst);
+        }
+
+        Ok(())
+    }
+
+    pub fn decode(src: &mut BytesMut) -> io::Result<Self> {
+        let packet_id = utils::decode_u16(src)?;
+        let properties = Properties::decode(src)?;
+        let mut subscriptions = vec![];
+        while !src.is_empty() {
+            let topic_filter = Subscription::try_from(utils::decode_string(src)?)?;
+            let options = SubscriptionOptions::decode(src)?;
+            subscriptions.push((topic_filter, options));
+        }
+        if subscriptions.is_empty() {
+            return Err(malformed("subscribe contains no topic filters"));
+        }
+
+        Ok(Subscribe {
+            packet_id,
+            properties,
+            subscriptions,
+        })
+    }
+}
+
+/// Variable header and payload of Suback and Unsuback Control Packets.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SubscribeResponse {
+    pub packet_id: u16,
+    pub properties: Properties,
+    /// A reason code per topic filter of a request.
+    pub reason_codes: Vec<ReasonCode>,
+}
+
+impl SubscribeResponse {
+    pub fn encode(&self, dst: &mut BytesMut) -> io::Result<()> {
+        dst.put_u16(self.packet_id);
+        self.properties.encode(dst)?;
+        for reason_code in &self.reason_codes {
+            dst.put_u8(reason_code.as_u8());
+        }
+
+        Ok(())
+    }
+
+    pub fn decode(src: &mut BytesMut) -> io::Result<Self> {
+        let packet_id = utils::decode_u16(src)?;
+        let properties = Properties::decode(src)?;
+        let mut reason_codes = vec![];
+        while !src.is_empty() {
+            reason_codes.push(ReasonCode::try_from(utils::decode_u8(src)?)?);
+        }
+
+        Ok(SubscribeResponse {
+            packet_id,
+            properties,
+            reason_codes,
+        })
+    }
+}
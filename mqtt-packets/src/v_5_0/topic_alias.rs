@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use super::publish::Publish;
+use super::reason_code::ReasonCode;
+
+/// Topic aliases a Client has established on a single network connection.
+///
+/// A Server announces how many aliases a Client may use via `TopicAliasMaximum` property
+/// of CONNACK. `0` means topic aliases are not accepted.
+#[derive(Debug, Default)]
+pub struct TopicAliases {
+    maximum: u16,
+    aliases: HashMap<u16, String>,
+}
+
+impl TopicAliases {
+    pub fn new(maximum: u16) -> Self {
+        TopicAliases {
+            maximum,
+            aliases: HashMap::new(),
+        }
+    }
+
+    pub fn maximum(&self) -> u16 {
+        self.maximum
+    }
+
+    /// It resolves a topic name of a PUBLISH packet sent by a Client and removes
+    /// its topic alias. If a packet has both a topic name and an alias, the alias
+    /// is (re)assigned to the topic name.
+    ///
+    /// Returns a reason code a Client should be disconnected with if a packet
+    /// violates the protocol.
+    pub fn resolve(&mut self, publish: &mut Publish) -> Result<(), ReasonCode> {
+        match publish.properties.take_topic_alias() {
+            None if publish.topic_name.is_empty() => Err(ReasonCode::ProtocolError),
+            None => Ok(()),
+            Some(alias) if alias == 0 || alias > self.maximum => Err(ReasonCode::TopicAliasInvalid),
+            Some(alias) if publish.topic_name.is_empty() => match self.aliases.get(&alias) {
+                Some(topic_name) => {
+                    publish.topic_name = topic_name.clone();
+                    Ok(())
+                }
+                None => Err(ReasonCode::ProtocolError),
+            },
+            Some(alias) => {
+                self.aliases.insert(alias, publish.topic_name.clone());
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v_3_1_1::QoS;
+    use crate::v_5_0::properties::{Properties, Property};
+    use bytes::Bytes;
+
+    fn publish(topic_name: &str, alias: Option<u16>) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::Zero,
+            retain: false,
+            topic_name: topic_name.into(),
+            packet_id: None,
+            properties: Properties::from(
+                alias
+                    .map(Property::TopicAlias)
+                    .into_iter()
+                    .collect::<Vec<_>>(),
+            ),
+            payload: Bytes::new(),
+        }
+    }
+
+    #[test]
+    fn alias_is_registered_and_resolved() {
+        let mut aliases = TopicAliases::new(2);
+
+        let mut packet = publish("a/b", Some(1));
+        assert_eq!(aliases.resolve(&mut packet), Ok(()));
+        assert!(packet.properties.is_empty());
+
+        let mut packet = publish("", Some(1));
+        assert_eq!(aliases.resolve(&mut packet), Ok(()));
+        assert_eq!(packet.topic_name, "a/b");
+
+        // reassigned
+        let mut packet = publish("c", Some(1));
+        assert_eq!(aliases.resolve(&mut packet), Ok(()));
+        let mut packet = publish("", Some(1));
+        assert_eq!(aliases.resolve(&mut packet), Ok(()));
+        assert_eq!(packet.topic_name, "c");
+    }
+
+    #[test]
+    fn invalid_aliases() {
+        let mut aliases = TopicAliases::new(2);
+        assert_eq!(
+            aliases.resolve(&mut publish("a", Some(0))),
+            Err(ReasonCode::TopicAliasInvalid)
+        );
+        assert_eq!(
+            aliases.resolve(&mut publish("a", Some(3))),
+            Err(ReasonCode::TopicAliasInvalid)
+        );
+        assert_eq!(
+            aliases.resolve(&mut publish("", Some(2))),
+            Err(ReasonCode::ProtocolError)
+        );
+        assert_eq!(
+            aliases.resolve(&mut publish("", None)),
+            Err(ReasonCode::ProtocolError)
+        );
+        assert_eq!(
+            TopicAliases::new(0).resolve(&mut publish("a", Some(1))),
+            Err(ReasonCode::TopicAliasInvalid)
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use bytes::{BufMut, BytesMut};
+use std::io;
+
+use super::properties::Properties;
+use super::utils::{self, malformed};
+use crate::v_3_1_1::topic::Subscription;
+
+/// Unsubscribe Control Packet.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Unsubscribe {
+    pub packet_id: u16,
+    pub properties: Properties,
+    pub topic_filters: Vec<Subscription>,
+}
+
+impl Unsubscribe {
+    pub fn encode(&self, dst: &mut BytesMut) -> io::Result<()> {
+        dst.put_u16(self.packet_id);
+        self.properties.encode(dst)?;
+        for topic_filter in &self.topic_filters {
+            utils::encode_string(&topic_filter.original, dst)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn decode(src: &mut BytesMut) -> io::Result<Self> {
+        let packet_id = utils::decode_u16(src)?;
+        let properties = Properties::decode(src)?;
+        let mut topic_filters = vec![];
+        while !src.is_empty() {
+            topic_filters.push(Subscription::try_from(utils::decode_string(src)?)?);
+        }
+        if topic_filters.is_empty() {
+            return Err(malformed("unsubscribe contains no topic filters"));
+        }
+
+        Ok(Unsubscribe {
+            packet_id,
+            properties,
+            topic_filters,
+        })
+    }
+}
@@ -0,0 +1,174 @@
+//! Encoding primitives of MQTT 5.0 data representation (section 1.5 of the specification).
+//!
+//! Decoders expect a whole packet to be in a buffer, so a missing byte is reported
+//! as a malformed packet rather than "not enough data".
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+
+/// Max value which fits into a Variable Byte Integer.
+pub const MAX_VARIABLE_INT: u32 = 268_435_455;
+
+pub fn malformed<T: AsRef<str>>(message: T) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Malformed Packet: {}", message.as_ref()),
+    )
+}
+
+pub fn encode_variable_int(value: u32, dst: &mut BytesMut) -> io::Result<()> {
+    if value > MAX_VARIABLE_INT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Variable Byte Integer is out of range",
+        ));
+    }
+
+    let mut value = value;
+    loop {
+        let mut encoded_byte = (value % 128) as u8;
+        value /= 128;
+        if value > 0 {
+            encoded_byte |= 128;
+        }
+        dst.put_u8(encoded_byte);
+        if value == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Number of bytes a Variable Byte Integer takes.
+pub fn variable_int_len(value: u32) -> usize {
+    match value {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    }
+}
+
+/// It reads a Variable Byte Integer from the beginning of `src` without consuming it.
+/// Returns a value and a number of bytes it takes or `None` if `src` is too short.
+pub fn peek_variable_int(src: &[u8]) -> io::Result<Option<(u32, usize)>> {
+    let mut value = 0u32;
+    let mut multiplier = 1u32;
+
+    for (index, byte) in src.iter().enumerate() {
+        if index == 4 {
+            return Err(malformed("Variable Byte Integer is longer than 4 bytes"));
+        }
+        value += (byte & 127) as u32 * multiplier;
+        if byte & 128 == 0 {
+            return Ok(Some((value, index + 1)));
+        }
+        multiplier *= 128;
+    }
+
+    if src.len() >= 4 {
+        Err(malformed("Variable Byte Integer is longer than 4 bytes"))
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn decode_variable_int(src: &mut BytesMut) -> io::Result<u32> {
+    match peek_variable_int(src)? {
+        Some((value, len)) => {
+            src.advance(len);
+            Ok(value)
+        }
+        None => Err(malformed("incomplete Variable Byte Integer")),
+    }
+}
+
+pub fn encode_string(value: &str, dst: &mut BytesMut) -> io::Result<()> {
+    encode_binary(value.as_bytes(), dst)
+}
+
+pub fn encode_binary(value: &[u8], dst: &mut BytesMut) -> io::Result<()> {
+    if value.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "string or binary data is longer than 65535 bytes",
+        ));
+    }
+    dst.put_u16(value.len() as u16);
+    dst.extend_from_slice(value);
+    Ok(())
+}
+
+pub fn decode_u8(src: &mut BytesMut) -> io::Result<u8> {
+    if src.is_empty() {
+        return Err(malformed("expected a byte"));
+    }
+    Ok(src.get_u8())
+}
+
+pub fn decode_u16(src: &mut BytesMut) -> io::Result<u16> {
+    if src.len() < 2 {
+        return Err(malformed("expected a Two Byte Integer"));
+    }
+    Ok(src.get_u16())
+}
+
+pub fn decode_u32(src: &mut BytesMut) -> io::Result<u32> {
+    if src.len() < 4 {
+        return Err(malformed("expected a Four Byte Integer"));
+    }
+    Ok(src.get_u32())
+}
+
+pub fn decode_binary(src: &mut BytesMut) -> io::Result<Vec<u8>> {
+    let len = decode_u16(src)? as usize;
+    if src.len() < len {
+        return Err(malformed("binary data is shorter than its length"));
+    }
+    Ok(src.split_to(len).to_vec())
+}
+
+pub fn decode_string(src: &mut BytesMut) -> io::Result<String> {
+    String::from_utf8(decode_binary(src)?)
+        .map_err(|_| malformed("string is not a valid UTF-8 string"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variable_int_round_trip() {
+        for value in &[
+            0,
+            127,
+            128,
+            16_383,
+            16_384,
+            2_097_151,
+            2_097_152,
+            MAX_VARIABLE_INT,
+        ] {
+            let mut buf = BytesMut::new();
+            encode_variable_int(*value, &mut buf).unwrap();
+            assert_eq!(buf.len(), variable_int_len(*value));
+            assert_eq!(decode_variable_int(&mut buf).unwrap(), *value);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn variable_int_errors() {
+        let mut buf = BytesMut::new();
+        assert!(encode_variable_int(MAX_VARIABLE_INT + 1, &mut buf).is_err());
+        assert_eq!(peek_variable_int(&[0x80, 0x80]).unwrap(), None);
+        assert!(peek_variable_int(&[0x80, 0x80, 0x80, 0x80, 0x01]).is_err());
+    }
+
+    #[test]
+    fn string_round_trip() {
+        let mut buf = BytesMut::new();
+        encode_string("foo", &mut buf).unwrap();
+        assert_eq!(buf.to_vec(), vec![0, 3, b'f', b'o', b'o']);
+        assert_eq!(decode_string(&mut buf).unwrap(), "foo");
+        assert!(decode_string(&mut BytesMut::from(&[0u8, 3, b'f'][..])).is_err());
+    }
+}
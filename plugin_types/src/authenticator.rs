@@ -13,24 +13,35 @@ pub struct LoginRequest<'a> {
     pub client_id: &'a String,
     pub username: &'a Option<String>,
     pub password: &'a Option<String>,
+    /// JA3 hash of ClientHello of a TLS connection, if `tls_fingerprint` is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_fingerprint: &'a Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LoginResponse {
     pub connection_allowed: bool,
+    /// If a connection is not allowed because a client id is not bound to credentials,
+    /// a client gets "identifier rejected" instead of "bad user name or password".
+    #[serde(default)]
+    pub client_id_rejected: bool,
     pub topics_acl: Option<Vec<TopicACL>>,
     pub max_packet_size: Option<usize>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TopicACL {
     pub topic: Topic,
     pub access: TopicAccess,
+    /// Max QoS granted to subscriptions allowed by the rule, QoS of a subscription is
+    /// downgraded to it.
+    #[serde(default)]
+    pub max_qos: Option<u8>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub enum TopicAccess {
     Read,
     Write,
@@ -0,0 +1,154 @@
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_LENGTH},
+    Client, Response, StatusCode, Url,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[derive(Deserialize)]
+struct ErrorView {
+    error: String,
+}
+
+#[derive(Deserialize)]
+pub struct RetainedPurgeView {
+    pub removed: usize,
+}
+
+#[derive(Deserialize)]
+pub struct RetainedSetView {
+    pub topic: String,
+    pub size: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TrafficView {
+    pub received: u64,
+    pub sent: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ClientsView {
+    pub connected: u64,
+    pub maximum: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SubscriptionTreeView {
+    pub nodes: usize,
+    pub subscriptions: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ListenerView {
+    pub transport: String,
+    pub addr: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct StatusView {
+    pub uptime: u64,
+    pub clients: ClientsView,
+    pub messages: TrafficView,
+    pub bytes: TrafficView,
+    pub subscription_tree: SubscriptionTreeView,
+    pub listeners: Vec<ListenerView>,
+}
+
+/// Client of TeleMQ Admin API.
+pub struct AdminClient {
+    base_url: String,
+    client: Client,
+}
+
+impl AdminClient {
+    /// `token` is sent as a bearer token, if Admin API requires authentication.
+    pub fn new(base_url: &str, token: Option<&str>) -> Result<Self, String> {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|_| "Invalid Admin API token".to_string())?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let client = Client::builder()
+            .default_headers(headers)
+            .build()
+            .map_err(|err| format!("Unable to create an Admin API client. {}", err))?;
+
+        Ok(AdminClient {
+            base_url: base_url.trim_end_matches('/').into(),
+            client,
+        })
+    }
+
+    pub async fn status(&self) -> Result<StatusView, String> {
+        let response = self
+            .client
+            .get(self.url("/v1/status"))
+            .send()
+            .await
+            .map_err(|err| format!("Unable to reach TeleMQ Admin API. {}", err))?;
+
+        parse_response(response).await
+    }
+
+    /// Removes retained messages which topics match `filter`.
+    pub async fn purge_retained(&self, filter: &str) -> Result<RetainedPurgeView, String> {
+        let response = self
+            .client
+            .delete(self.url("/v1/retained"))
+            .query(&[("filter", filter)])
+            .send()
+            .await
+            .map_err(|err| format!("Unable to reach TeleMQ Admin API. {}", err))?;
+
+        parse_response(response).await
+    }
+
+    /// Retains a message in `topic`, an empty payload removes a retained message.
+    pub async fn set_retained(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        qos: u8,
+    ) -> Result<RetainedSetView, String> {
+        let mut url = Url::parse(&self.url("/v1/retained"))
+            .map_err(|err| format!("Invalid Admin API URL. {}", err))?;
+        // topic levels are percent-encoded, e.g. $SYS or spaces
+        url.path_segments_mut()
+            .map_err(|_| "Invalid Admin API URL".to_string())?
+            .extend(topic.split('/'));
+        let response = self
+            .client
+            .put(url)
+            .query(&[("qos", qos)])
+            // Admin API requires it, but it's not sent for an empty body otherwise
+            .header(CONTENT_LENGTH, payload.len())
+            .body(payload)
+            .send()
+            .await
+            .map_err(|err| format!("Unable to reach TeleMQ Admin API. {}", err))?;
+
+        parse_response(response).await
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+}
+
+async fn parse_response<T: DeserializeOwned>(response: Response) -> Result<T, String> {
+    let status = response.status();
+
+    if status == StatusCode::OK {
+        return response
+            .json()
+            .await
+            .map_err(|err| format!("Unexpected Admin API response. {}", err));
+    }
+
+    match response.json::<ErrorView>().await {
+        Ok(ErrorView { error }) => Err(format!("{}: {}", status, error)),
+        Err(_) => Err(status.to_string()),
+    }
+}
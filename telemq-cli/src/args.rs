@@ -0,0 +1,207 @@
+use clap::{App, AppSettings, Arg, ArgMatches};
+
+pub const DEFAULT_ADMIN_API: &str = "http://localhost:8080";
+pub const DEFAULT_BROKER: &str = "localhost:1883";
+
+pub fn parse_args() -> ArgMatches {
+    App::new("telemq-cli")
+        .about("Command line tool for TeleMQ Admin API and a test MQTT client")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::new("API")
+                .long("api")
+                .help("TeleMQ Admin API base URL")
+                .default_value(DEFAULT_ADMIN_API)
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("TOKEN")
+                .long("token")
+                .help("Bearer token of TeleMQ Admin API, TELEMQ_ADMIN_TOKEN by default")
+                .takes_value(true)
+                .global(true),
+        )
+        .subcommand(
+            App::new("broker")
+                .about("Inspect the broker")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("status")
+                        .about("Show uptime, connected clients, counters and listeners")
+                        .arg(
+                            Arg::new("JSON")
+                                .long("json")
+                                .help("Print the status as JSON"),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new("retained")
+                .about("Manage retained messages")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("purge")
+                        .about("Remove retained messages which topics match a topic filter")
+                        .arg(
+                            Arg::new("FILTER")
+                                .short('f')
+                                .long("filter")
+                                .help("Topic filter, e.g. devices/+/status")
+                                .takes_value(true)
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    App::new("set")
+                        .about("Retain a message with a payload read from a file")
+                        .arg(
+                            Arg::new("TOPIC")
+                                .short('t')
+                                .long("topic")
+                                .help("Topic name, e.g. config/site-1")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("FILE")
+                                .short('f')
+                                .long("file")
+                                .help("Payload file, an empty file removes the retained message")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("QOS")
+                                .long("qos")
+                                .help("QoS of the message")
+                                .possible_values(["0", "1", "2"])
+                                .default_value("0")
+                                .takes_value(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new("store")
+                .about("Manage the state store of a stopped broker")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("migrate")
+                        .about("Copy persistent sessions, client history and retained messages to another store")
+                        .arg(
+                            Arg::new("FROM")
+                                .long("from")
+                                .help("Source store, e.g. file:/var/lib/telemq. Only file: stores are supported")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("TO")
+                                .long("to")
+                                .help("Destination store, e.g. file:/mnt/telemq. Only file: stores are supported")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("RETAINED_FILE")
+                                .long("retained-file")
+                                .help("File name of retained messages (retained_store_file) in both stores")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::new("OVERWRITE")
+                                .long("overwrite")
+                                .help("Replace entries the destination store has already"),
+                        ),
+                ),
+        )
+        .subcommand(
+            with_connection_args(App::new("pub"))
+                .about("Publish a message")
+                .arg(
+                    Arg::new("TOPIC")
+                        .short('t')
+                        .long("topic")
+                        .help("Topic name, e.g. devices/1/status")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("PAYLOAD")
+                        .short('m')
+                        .long("payload")
+                        .help("Payload of the message")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(qos_arg())
+                .arg(
+                    Arg::new("RETAIN")
+                        .short('r')
+                        .long("retain")
+                        .help("Retain the message"),
+                ),
+        )
+        .subcommand(
+            with_connection_args(App::new("sub"))
+                .about("Subscribe to a topic filter and print received messages")
+                .arg(
+                    Arg::new("TOPIC")
+                        .short('t')
+                        .long("topic")
+                        .help("Topic filter, e.g. devices/+/status")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(qos_arg().help("Max QoS of received messages"))
+                .arg(
+                    Arg::new("COUNT")
+                        .short('C')
+                        .long("count")
+                        .help("Exit after receiving a number of messages")
+                        .takes_value(true),
+                ),
+        )
+        .get_matches()
+}
+
+/// Arguments of commands which connect to an MQTT listener of the broker.
+fn with_connection_args(app: App<'static>) -> App<'static> {
+    app.arg(
+        Arg::new("BROKER")
+            .short('b')
+            .long("broker")
+            .help("Address of a TCP listener of the broker")
+            .default_value(DEFAULT_BROKER)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("CLIENT_ID")
+            .short('i')
+            .long("client-id")
+            .help("Client id, telemqcli{pid} by default")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("USERNAME")
+            .short('u')
+            .long("username")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("PASSWORD")
+            .short('P')
+            .long("password")
+            .takes_value(true),
+    )
+}
+
+fn qos_arg() -> Arg<'static> {
+    Arg::new("QOS")
+        .short('q')
+        .long("qos")
+        .help("QoS of the message")
+        .possible_values(["0", "1", "2"])
+        .default_value("0")
+        .takes_value(true)
+}
@@ -0,0 +1,82 @@
+use clap::ArgMatches;
+
+use crate::admin_client::{AdminClient, StatusView};
+
+pub async fn run(args: &ArgMatches, client: AdminClient) -> Result<(), String> {
+    match args.subcommand() {
+        Some(("status", args)) => {
+            let status = client.status().await?;
+            if args.is_present("JSON") {
+                let json = serde_json::to_string_pretty(&status)
+                    .map_err(|err| format!("Unable to format the status. {}", err))?;
+                println!("{}", json);
+            } else {
+                print_status(&status);
+            }
+            Ok(())
+        }
+        _ => Err("Unknown broker command".into()),
+    }
+}
+
+fn print_status(status: &StatusView) {
+    let mut rows = vec![
+        ("Uptime", format_uptime(status.uptime)),
+        (
+            "Clients",
+            format!(
+                "{} connected, {} maximum",
+                status.clients.connected, status.clients.maximum
+            ),
+        ),
+        (
+            "Messages",
+            format!(
+                "{} received, {} sent",
+                status.messages.received, status.messages.sent
+            ),
+        ),
+        (
+            "Bytes",
+            format!(
+                "{} received, {} sent",
+                status.bytes.received, status.bytes.sent
+            ),
+        ),
+        (
+            "Subscriptions",
+            format!(
+                "{} ({} tree nodes)",
+                status.subscription_tree.subscriptions, status.subscription_tree.nodes
+            ),
+        ),
+    ];
+    for (i, listener) in status.listeners.iter().enumerate() {
+        rows.push((
+            if i == 0 { "Listeners" } else { "" },
+            format!("{} {}", listener.transport, listener.addr),
+        ));
+    }
+
+    for (name, value) in rows {
+        println!("{:<15}{}", name, value);
+    }
+}
+
+/// E.g. `2d 3h 4m 5s`, leading zero units are omitted.
+fn format_uptime(uptime: u64) -> String {
+    let units = [
+        (uptime / 86_400, "d"),
+        (uptime % 86_400 / 3_600, "h"),
+        (uptime % 3_600 / 60, "m"),
+    ];
+    let mut formatted = String::new();
+    for (value, unit) in units {
+        if value > 0 || !formatted.is_empty() {
+            formatted.push_str(&format!("{}{} ", value, unit));
+        }
+    }
+    formatted.push_str(&format!("{}s", uptime % 60));
+
+    formatted
+}
@@ -0,0 +1,78 @@
+mod admin_client;
+mod args;
+mod broker;
+mod mqtt_client;
+mod pubsub;
+mod store;
+
+use std::{env, fs, process};
+
+use admin_client::AdminClient;
+use clap::ArgMatches;
+
+#[tokio::main]
+async fn main() {
+    let args = args::parse_args();
+
+    if let Err(err) = run(&args).await {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+async fn run(args: &ArgMatches) -> Result<(), String> {
+    match args.subcommand() {
+        Some(("broker", args)) => broker::run(args, admin_client(args)?).await,
+        Some(("retained", args)) => run_retained(args).await,
+        Some(("pub", args)) => pubsub::run_pub(args).await,
+        Some(("sub", args)) => pubsub::run_sub(args).await,
+        Some(("store", args)) => store::run(args),
+        _ => Err("Unknown command".into()),
+    }
+}
+
+async fn run_retained(args: &ArgMatches) -> Result<(), String> {
+    let client = admin_client(args)?;
+
+    match args.subcommand() {
+        Some(("purge", args)) => {
+            let filter = args.value_of("FILTER").unwrap_or_default();
+            let result = client.purge_retained(filter).await?;
+            println!(
+                "{} retained messages matching {} have been removed",
+                result.removed, filter
+            );
+            Ok(())
+        }
+        Some(("set", args)) => {
+            let topic = args.value_of("TOPIC").unwrap_or_default();
+            let file = args.value_of("FILE").unwrap_or_default();
+            let qos = args.value_of("QOS").unwrap_or("0").parse().unwrap_or(0);
+            let payload =
+                fs::read(file).map_err(|err| format!("Unable to read {}. {}", file, err))?;
+            let result = client.set_retained(topic, payload, qos).await?;
+            if result.size == 0 {
+                println!("Retained message of {} has been removed", result.topic);
+            } else {
+                println!(
+                    "{} bytes have been retained in {}",
+                    result.size, result.topic
+                );
+            }
+            Ok(())
+        }
+        _ => Err("Unknown retained command".into()),
+    }
+}
+
+fn admin_client(args: &ArgMatches) -> Result<AdminClient, String> {
+    // a token in the environment doesn't show up in a list of processes
+    let token = args
+        .value_of("TOKEN")
+        .map(String::from)
+        .or_else(|| env::var("TELEMQ_ADMIN_TOKEN").ok());
+    AdminClient::new(
+        args.value_of("API").unwrap_or(args::DEFAULT_ADMIN_API),
+        token.as_deref(),
+    )
+}
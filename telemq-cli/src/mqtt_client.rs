@@ -0,0 +1,256 @@
+//! A minimal MQTT 3.1.1 client over the codec of the broker, enough to smoke-test
+//! a listener with `pub` and `sub` commands without installing other MQTT clients.
+//!
+//! Only plain TCP listeners are supported. Incoming messages are acknowledged right away
+//! and handed over once, a QoS 2 message is not deduplicated if the broker resends it.
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use mqtt_packets::v_3_1_1::{
+    builders::{
+        ConnectBuilder, DisconnectPacketBuilder, PingreqPacketBuilder, PubackPacketBuilder,
+        PubcompPacketBuilder, PublishPacketBuilder, PubrecPacketBuilder, PubrelPacketBuilder,
+        SubscribePacketBuilder,
+    },
+    connack::return_code::ReturnCode,
+    publish::fixed_header::get_qos_level,
+    suback::return_code::ReturnCode as SubackReturnCode,
+    topic::{Subscription, Topic},
+    variable::Variable,
+    CPType, ControlPacket, ControlPacketCodec, PacketId, QoS,
+};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_util::codec::Framed;
+
+/// Max time to wait for a response of the broker, e.g. CONNACK or PUBACK.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct ConnectOptions {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Keep alive interval in seconds, 0 turns keep alive off.
+    pub keep_alive: u16,
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+pub struct MqttClient {
+    connection: Framed<TcpStream, ControlPacketCodec>,
+    keep_alive: Duration,
+    next_packet_id: u16,
+}
+
+impl MqttClient {
+    /// Connects with a clean session and waits for the broker to accept it.
+    pub async fn connect(addr: &str, options: ConnectOptions) -> Result<Self, String> {
+        let stream = timeout(RESPONSE_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| format!("Connection to {} timed out", addr))?
+            .map_err(|err| format!("Unable to connect to {}. {}", addr, err))?;
+        let mut client = MqttClient {
+            connection: Framed::new(stream, ControlPacketCodec::new()),
+            keep_alive: Duration::from_secs(options.keep_alive as u64),
+            next_packet_id: 1,
+        };
+
+        let connect = ConnectBuilder::new(
+            options.client_id,
+            options.keep_alive,
+            true,
+            options.username,
+            options.password,
+        );
+        client.send(&connect.build()).await?;
+        match client.expect(CPType::Connack, None).await? {
+            Variable::Connack(variable) => match variable.return_code {
+                ReturnCode::Accepted => Ok(client),
+                return_code => Err(format!(
+                    "The broker has rejected the connection: {:?}",
+                    return_code
+                )),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Publishes a message and waits until the broker acknowledges it (QoS 1 and 2).
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        payload: Vec<u8>,
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), String> {
+        let topic =
+            Topic::try_from(topic).map_err(|err| format!("Invalid topic {}. {}", topic, err))?;
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(topic)
+            .with_payload(payload)
+            .with_qos(&qos)
+            .with_retained(retain);
+        let packet_id = self.next_packet_id();
+        if qos != QoS::Zero {
+            builder.with_packet_id(packet_id);
+        }
+        self.send(&builder.build()).await?;
+
+        match qos {
+            QoS::Zero => Ok(()),
+            QoS::One => self
+                .expect(CPType::Puback, Some(&packet_id))
+                .await
+                .map(|_| ()),
+            QoS::Two => {
+                self.expect(CPType::Pubrec, Some(&packet_id)).await?;
+                self.send(&PubrelPacketBuilder::new(&packet_id).build())
+                    .await?;
+                self.expect(CPType::Pubcomp, Some(&packet_id))
+                    .await
+                    .map(|_| ())
+            }
+        }
+    }
+
+    /// Subscribes to a topic filter and returns a QoS granted by the broker.
+    pub async fn subscribe(&mut self, filter: &str, qos: QoS) -> Result<QoS, String> {
+        let subscription = Subscription::try_from(filter)
+            .map_err(|err| format!("Invalid topic filter {}. {}", filter, err))?;
+        let packet_id = self.next_packet_id();
+        let subscribe = SubscribePacketBuilder::new(packet_id)
+            .with_subscription(subscription, qos)
+            .build();
+        self.send(&subscribe).await?;
+
+        match self.expect(CPType::Suback, Some(&packet_id)).await? {
+            Variable::Suback(variable) => match variable.return_codes.first() {
+                Some(SubackReturnCode::SuccessZero) => Ok(QoS::Zero),
+                Some(SubackReturnCode::SuccessOne) => Ok(QoS::One),
+                Some(SubackReturnCode::SuccessTwo) => Ok(QoS::Two),
+                _ => Err(format!(
+                    "The broker has rejected a subscription to {}",
+                    filter
+                )),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Waits for a next message, pinging the broker while there are none.
+    /// Returns `None` once the broker closes the connection.
+    pub async fn next_message(&mut self) -> Result<Option<Message>, String> {
+        loop {
+            let packet = match self.next_packet().await? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+            let qos = get_qos_level(&packet.fixed_header).unwrap_or(QoS::Zero);
+            match packet.variable {
+                Variable::Publish(variable) => {
+                    match (&qos, &variable.packet_id) {
+                        (QoS::One, Some(packet_id)) => {
+                            self.send(&PubackPacketBuilder::new(packet_id).build())
+                                .await?
+                        }
+                        (QoS::Two, Some(packet_id)) => {
+                            self.send(&PubrecPacketBuilder::new(packet_id).build())
+                                .await?
+                        }
+                        _ => {}
+                    }
+                    return Ok(Some(Message {
+                        topic: variable.topic_name.original,
+                        payload: variable.payload.to_vec(),
+                    }));
+                }
+                Variable::Pubrel(variable) => {
+                    self.send(&PubcompPacketBuilder::new(&variable.packet_id).build())
+                        .await?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub async fn disconnect(mut self) -> Result<(), String> {
+        self.send(&DisconnectPacketBuilder::new().build()).await
+    }
+
+    async fn send(&mut self, packet: &ControlPacket) -> Result<(), String> {
+        self.connection
+            .send(packet)
+            .await
+            .map_err(|err| format!("Unable to send {:?}. {}", packet.fixed_header.cp_type, err))
+    }
+
+    /// Reads packets until one of `cp_type` (with `packet_id` if given) arrives. Messages
+    /// arriving in the meantime are dropped, commands publish or subscribe only once.
+    async fn expect(
+        &mut self,
+        cp_type: CPType,
+        packet_id: Option<&PacketId>,
+    ) -> Result<Variable, String> {
+        let response = timeout(RESPONSE_TIMEOUT, async {
+            loop {
+                match self.next_packet().await? {
+                    Some(packet) if packet.fixed_header.cp_type == cp_type => {
+                        if packet_id.is_none() || packet_id == response_packet_id(&packet) {
+                            return Ok(packet.variable);
+                        }
+                    }
+                    Some(_) => {}
+                    None => return Err("The broker has closed the connection".to_string()),
+                }
+            }
+        });
+
+        response
+            .await
+            .map_err(|_| format!("The broker has not sent {:?} in time", cp_type))?
+    }
+
+    /// Sends PINGREQ whenever the connection is idle for a half of keep alive interval.
+    async fn next_packet(&mut self) -> Result<Option<ControlPacket>, String> {
+        loop {
+            let packet = if self.keep_alive.is_zero() {
+                self.connection.next().await
+            } else {
+                match timeout(self.keep_alive / 2, self.connection.next()).await {
+                    Ok(packet) => packet,
+                    Err(_) => {
+                        self.send(&PingreqPacketBuilder::new().build()).await?;
+                        continue;
+                    }
+                }
+            };
+
+            return match packet {
+                Some(Ok(packet)) => Ok(Some(packet)),
+                Some(Err(err)) => Err(format!("Unable to read a packet. {}", err)),
+                None => Ok(None),
+            };
+        }
+    }
+
+    fn next_packet_id(&mut self) -> PacketId {
+        let packet_id = self.next_packet_id;
+        // packet id 0 is not allowed
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        PacketId::new(packet_id)
+    }
+}
+
+fn response_packet_id(packet: &ControlPacket) -> Option<&PacketId> {
+    match packet.variable {
+        Variable::Puback(ref variable)
+        | Variable::Pubrec(ref variable)
+        | Variable::Pubcomp(ref variable) => Some(&variable.packet_id),
+        Variable::Suback(ref variable) => Some(&variable.packet_id),
+        _ => None,
+    }
+}
@@ -0,0 +1,80 @@
+use std::process;
+
+use clap::ArgMatches;
+use mqtt_packets::v_3_1_1::QoS;
+
+use crate::{
+    args,
+    mqtt_client::{ConnectOptions, MqttClient},
+};
+
+const KEEP_ALIVE: u16 = 60;
+
+pub async fn run_pub(args: &ArgMatches) -> Result<(), String> {
+    let topic = args.value_of("TOPIC").unwrap_or_default();
+    let payload = args.value_of("PAYLOAD").unwrap_or_default();
+    let mut client = connect(args).await?;
+    client
+        .publish(
+            topic,
+            payload.as_bytes().to_vec(),
+            qos(args)?,
+            args.is_present("RETAIN"),
+        )
+        .await?;
+    client.disconnect().await
+}
+
+/// Prints a line `{topic} {payload}` per message, a payload which is not UTF-8 is printed
+/// with replacement characters.
+pub async fn run_sub(args: &ArgMatches) -> Result<(), String> {
+    let filter = args.value_of("TOPIC").unwrap_or_default();
+    let count = match args.value_of("COUNT") {
+        Some(count) => Some(
+            count
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid count {}", count))?,
+        ),
+        None => None,
+    };
+    let mut client = connect(args).await?;
+    client.subscribe(filter, qos(args)?).await?;
+
+    let mut received = 0;
+    while count.is_none_or(|count| received < count) {
+        match client.next_message().await? {
+            Some(message) => {
+                println!(
+                    "{} {}",
+                    message.topic,
+                    String::from_utf8_lossy(&message.payload)
+                );
+                received += 1;
+            }
+            None => return Err("The broker has closed the connection".into()),
+        }
+    }
+    client.disconnect().await
+}
+
+async fn connect(args: &ArgMatches) -> Result<MqttClient, String> {
+    let options = ConnectOptions {
+        client_id: args
+            .value_of("CLIENT_ID")
+            .map(String::from)
+            .unwrap_or_else(|| format!("telemqcli{}", process::id())),
+        username: args.value_of("USERNAME").map(String::from),
+        password: args.value_of("PASSWORD").map(String::from),
+        keep_alive: KEEP_ALIVE,
+    };
+    MqttClient::connect(
+        args.value_of("BROKER").unwrap_or(args::DEFAULT_BROKER),
+        options,
+    )
+    .await
+}
+
+fn qos(args: &ArgMatches) -> Result<QoS, String> {
+    let qos = args.value_of("QOS").unwrap_or("0").parse().unwrap_or(0);
+    QoS::try_from(qos).map_err(|err| err.to_string())
+}
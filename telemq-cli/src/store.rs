@@ -0,0 +1,162 @@
+//! Migration of the state store between backends, so persistent sessions and retained
+//! messages survive moving a broker to another store.
+//!
+//! A backend is given as a URL. `file:<directory>` is a directory a broker runs in: its
+//! persistent sessions and client history are JSON objects in `session_state_store.json`
+//! and `client_history.json`, retained messages are in `retained_store_file` if it's
+//! configured. Entries are copied as they are and compared with the source once written.
+//!
+//! The broker persists its state to files only, so there is no other backend yet. `redis://`
+//! URLs are rejected with an error saying so rather than as unknown backends.
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use clap::ArgMatches;
+use serde_json::{Map, Value};
+
+const SESSIONS_FILE: &str = "session_state_store.json";
+const HISTORY_FILE: &str = "client_history.json";
+
+enum StoreBackend {
+    File(PathBuf),
+}
+
+impl StoreBackend {
+    fn parse(url: &str) -> Result<Self, String> {
+        if url.starts_with("redis://") || url.starts_with("rediss://") {
+            return Err(format!(
+                "Redis store backend {} is not supported: the broker keeps its state in files only. Only file:<directory> is supported",
+                url
+            ));
+        }
+        match url.strip_prefix("file:") {
+            Some(dir) if !dir.is_empty() => Ok(StoreBackend::File(PathBuf::from(dir))),
+            _ => Err(format!(
+                "Unsupported store backend {}. Only file:<directory> is supported",
+                url
+            )),
+        }
+    }
+
+    /// Entries of a store file, `None` if nothing has been persisted to it.
+    fn read(&self, name: &str) -> Result<Option<Map<String, Value>>, String> {
+        match self {
+            StoreBackend::File(dir) => {
+                let path = dir.join(name);
+                let content = match fs::read_to_string(&path) {
+                    Ok(content) if content.trim().is_empty() => return Ok(None),
+                    Ok(content) => content,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                    Err(err) => return Err(format!("Unable to read {}. {}", path.display(), err)),
+                };
+                serde_json::from_str(&content)
+                    .map(Some)
+                    .map_err(|err| format!("Unable to parse {}. {}", path.display(), err))
+            }
+        }
+    }
+
+    fn write(&self, name: &str, entries: &Map<String, Value>) -> Result<(), String> {
+        match self {
+            StoreBackend::File(dir) => {
+                let path = dir.join(name);
+                // a half written file is never left in place of a store file
+                let tmp_path = dir.join(format!("{}.migrating", name));
+                let write = || -> io::Result<()> {
+                    fs::create_dir_all(dir)?;
+                    let mut file = File::create(&tmp_path)?;
+                    file.write_all(&serde_json::to_vec(entries)?)?;
+                    file.sync_all()?;
+                    fs::rename(&tmp_path, &path)
+                };
+                write().map_err(|err| format!("Unable to write {}. {}", path.display(), err))
+            }
+        }
+    }
+
+    fn is_same(&self, other: &StoreBackend) -> bool {
+        match (self, other) {
+            (StoreBackend::File(dir), StoreBackend::File(other_dir)) => {
+                match (fs::canonicalize(dir), fs::canonicalize(other_dir)) {
+                    (Ok(dir), Ok(other_dir)) => dir == other_dir,
+                    _ => dir == other_dir,
+                }
+            }
+        }
+    }
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), String> {
+    match args.subcommand() {
+        Some(("migrate", args)) => {
+            let from = StoreBackend::parse(args.value_of("FROM").unwrap_or_default())?;
+            let to = StoreBackend::parse(args.value_of("TO").unwrap_or_default())?;
+            let mut files = vec![SESSIONS_FILE, HISTORY_FILE];
+            if let Some(retained_file) = args.value_of("RETAINED_FILE") {
+                files.push(retained_file);
+            }
+            migrate(&from, &to, &files, args.is_present("OVERWRITE"))
+        }
+        _ => Err("Unknown store command".into()),
+    }
+}
+
+/// Copies every file or nothing: sources are read and destinations are checked before
+/// anything is written.
+fn migrate(
+    from: &StoreBackend,
+    to: &StoreBackend,
+    files: &[&str],
+    overwrite: bool,
+) -> Result<(), String> {
+    if from.is_same(to) {
+        return Err("Source and destination stores are the same".into());
+    }
+
+    let mut migrated = vec![];
+    for name in files {
+        let entries = match from.read(name)? {
+            Some(entries) => entries,
+            None => {
+                println!("{}: nothing persisted", name);
+                continue;
+            }
+        };
+        if let Some(existing) = to.read(name)? {
+            if !existing.is_empty() && !overwrite {
+                return Err(format!(
+                    "{}: destination has {} entries already, use --overwrite to replace them",
+                    name,
+                    existing.len()
+                ));
+            }
+        }
+        migrated.push((name, entries));
+    }
+
+    for (name, entries) in &migrated {
+        to.write(name, entries)?;
+    }
+
+    for (name, entries) in &migrated {
+        let written = to.read(name)?.unwrap_or_default();
+        let mismatched = entries
+            .iter()
+            .filter(|(key, value)| written.get(*key) != Some(value))
+            .count();
+        if mismatched > 0 || written.len() != entries.len() {
+            return Err(format!(
+                "{}: verification failed, {} of {} entries differ from the source",
+                name,
+                mismatched,
+                entries.len()
+            ));
+        }
+        println!("{}: {} entries migrated and verified", name, entries.len());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Recovery of listeners from failed `accept` calls.
+//!
+//! Most `accept` errors are caused by the process or the system running out of resources
+//! (e.g. file descriptors). They are returned immediately, so a listener which retries
+//! right away spins on a CPU until resources are released. Such errors pause a listener
+//! for an exponentially growing delay, which is reset by the next accepted connection.
+use std::{io, time::Duration};
+
+/// Delay after the first error in a row.
+const MIN_DELAY: Duration = Duration::from_millis(10);
+/// Delay is not doubled beyond this value, it also bounds how long a server worker
+/// doesn't handle other events.
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+pub struct AcceptBackoff {
+    next_delay: Duration,
+}
+
+impl AcceptBackoff {
+    pub fn new() -> Self {
+        AcceptBackoff {
+            next_delay: MIN_DELAY,
+        }
+    }
+
+    /// Called when a connection is accepted.
+    pub fn reset(&mut self) {
+        self.next_delay = MIN_DELAY;
+    }
+
+    /// Returns how long a listener should be paused after an error.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next_delay;
+        self.next_delay = (delay * 2).min(MAX_DELAY);
+        delay
+    }
+}
+
+/// Errors of a single incoming connection (e.g. a client has reset it before it's accepted),
+/// a listener can accept the next one right away.
+pub fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let mut backoff = AcceptBackoff::new();
+        let delays: Vec<Duration> = (0..9).map(|_| backoff.next_delay()).collect();
+
+        assert_eq!(delays[0], MIN_DELAY);
+        assert_eq!(delays[1], MIN_DELAY * 2);
+        assert_eq!(delays[6], MIN_DELAY * 64);
+        assert_eq!(delays[7], MAX_DELAY);
+        assert_eq!(delays[8], MAX_DELAY);
+    }
+
+    #[test]
+    fn reset_restores_min_delay() {
+        let mut backoff = AcceptBackoff::new();
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+
+        assert_eq!(backoff.next_delay(), MIN_DELAY);
+    }
+
+    #[test]
+    fn connection_errors_are_distinguished() {
+        assert!(is_connection_error(&io::ErrorKind::ConnectionReset.into()));
+        assert!(is_connection_error(
+            &io::ErrorKind::ConnectionAborted.into()
+        ));
+        assert!(!is_connection_error(&io::Error::from_raw_os_error(
+            libc::EMFILE
+        )));
+    }
+}
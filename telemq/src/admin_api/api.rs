@@ -0,0 +1,144 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use log::{info, warn};
+use serde::Serialize;
+use tokio::sync::RwLock;
+use warp::{http::StatusCode, reply, Filter};
+
+use super::{
+    auth::{self, AdminApiAuth},
+    connections, devices, features, metrics, publish, retained, status, subscriptions,
+    v1::{self, ErrorView},
+    version,
+};
+use crate::{
+    broker_features::BrokerFeatures, config::TeleMQServerConfig,
+    connection_gate::ConnectionTransport, connection_limit::ConnectionLimit,
+    control::ControlSender, session_state_store::SessionStateStore, stats::StatsSender,
+};
+
+/// Broker handles shared by all Admin API routes.
+#[derive(Clone)]
+pub struct AdminApiContext {
+    pub state_store: Arc<RwLock<SessionStateStore>>,
+    pub control_sender: ControlSender,
+    pub stats_sender: StatsSender,
+    pub connection_limit: Arc<ConnectionLimit>,
+    pub features: Arc<BrokerFeatures>,
+    /// MQTT listeners of the broker.
+    pub listeners: Arc<Vec<(ConnectionTransport, SocketAddr)>>,
+    pub auth: Arc<AdminApiAuth>,
+    /// Topic filters of retained messages erased with data of a client, with `{client_id}`.
+    pub erase_retained_topics: Arc<Vec<String>>,
+}
+
+/// Certificate and key Admin API is served over TLS with, the same as of MQTT listeners.
+#[derive(Clone)]
+pub struct AdminApiTls {
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+impl AdminApiTls {
+    /// `None` unless `admin_api_tls` is enabled.
+    pub fn new(config: &TeleMQServerConfig) -> Option<Self> {
+        if !config.admin_api_tls {
+            return None;
+        }
+        // validated by the config
+        Some(AdminApiTls {
+            cert_file: config.cert_file.clone()?,
+            key_file: config.key_file.clone()?,
+        })
+    }
+}
+
+pub async fn run(addr: SocketAddr, context: AdminApiContext, tls: Option<AdminApiTls>) {
+    let auth = context.auth.clone();
+    let api = connections::routes(context.clone())
+        .or(devices::routes(context.clone()))
+        .or(subscriptions::routes(context.clone()))
+        .or(retained::routes(context.clone()))
+        .or(publish::routes(context.clone()))
+        .or(status::routes(context.clone()))
+        .or(features::routes(context.clone()));
+    // unversioned routes are kept as aliases of v1 for tools which predate versioning
+    let routes = warp::path(v1::PREFIX)
+        .and(api.clone())
+        .or(api)
+        .or(version::routes())
+        .or(metrics::routes(context));
+    let routes = auth::with_auth(auth.clone())
+        .and(routes)
+        .recover(auth::recover);
+
+    log_listening("Admin API", addr, &auth, &tls);
+    match tls {
+        Some(tls) => {
+            warp::serve(routes)
+                .tls()
+                .cert_path(tls.cert_file)
+                .key_path(tls.key_file)
+                .run(addr)
+                .await
+        }
+        None => warp::serve(routes).run(addr).await,
+    }
+}
+
+/// Serves only `/metrics` for Prometheus, when it should not share a port with the rest of
+/// Admin API.
+pub async fn run_metrics(addr: SocketAddr, context: AdminApiContext, tls: Option<AdminApiTls>) {
+    let auth = context.auth.clone();
+    let routes = auth::with_auth(auth.clone())
+        .and(metrics::routes(context))
+        .recover(auth::recover);
+
+    log_listening("Metrics API", addr, &auth, &tls);
+    match tls {
+        Some(tls) => {
+            warp::serve(routes)
+                .tls()
+                .cert_path(tls.cert_file)
+                .key_path(tls.key_file)
+                .run(addr)
+                .await
+        }
+        None => warp::serve(routes).run(addr).await,
+    }
+}
+
+fn log_listening(name: &str, addr: SocketAddr, auth: &AdminApiAuth, tls: &Option<AdminApiTls>) {
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("[{}]: listening on {}://{}", name, scheme, addr);
+    if !auth.is_enabled() && !addr.ip().is_loopback() {
+        warn!(
+            "[{}]: {} is reachable beyond localhost without authentication, configure admin_api_auth",
+            name, addr
+        );
+    }
+}
+
+pub fn with_context(
+    context: AdminApiContext,
+) -> impl Filter<Extract = (AdminApiContext,), Error = Infallible> + Clone {
+    warp::any().map(move || context.clone())
+}
+
+/// JSON reply with a given status code.
+pub fn json_reply<T: Serialize>(value: &T, status: StatusCode) -> reply::WithStatus<reply::Json> {
+    reply::with_status(reply::json(value), status)
+}
+
+/// JSON reply of `{"error": "..."}` form.
+pub fn error_reply<D: ToString>(
+    description: D,
+    status: StatusCode,
+) -> reply::WithStatus<reply::Json> {
+    json_reply(
+        &ErrorView {
+            error: description.to_string(),
+        },
+        status,
+    )
+}
@@ -0,0 +1,229 @@
+//! Authentication and authorization of Admin API requests with `admin_api_auth` credentials.
+//! Without credentials every request is allowed, as Admin API is expected to be reachable
+//! only locally then.
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use warp::{
+    http::{header, Method, StatusCode},
+    path::FullPath,
+    reject::{self, Reject},
+    reply::{self, Response},
+    Filter, Rejection, Reply,
+};
+
+use super::{api::error_reply, v1};
+use crate::config::{AdminApiCredentials, AdminApiRole};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// No credentials or unknown ones.
+    Unauthenticated,
+    /// Credentials don't grant access to a route or a method.
+    Forbidden,
+}
+
+impl Reject for AuthError {}
+
+#[derive(Debug, Default)]
+pub struct AdminApiAuth {
+    credentials: Vec<AdminApiCredentials>,
+}
+
+impl AdminApiAuth {
+    pub fn new(credentials: Vec<AdminApiCredentials>) -> Self {
+        AdminApiAuth { credentials }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.credentials.is_empty()
+    }
+
+    /// Checks a value of the `Authorization` header against a request.
+    pub fn authorize(
+        &self,
+        authorization: Option<&str>,
+        method: &Method,
+        path: &str,
+    ) -> Result<(), AuthError> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let credentials = authorization
+            .and_then(|authorization| self.authenticate(authorization))
+            .ok_or(AuthError::Unauthenticated)?;
+
+        let method_allowed = match credentials.role {
+            AdminApiRole::Admin => true,
+            AdminApiRole::ReadOnly => method == Method::GET || method == Method::HEAD,
+        };
+        // routes are the same with and without the version prefix
+        let route = path
+            .strip_prefix(&format!("/{}", v1::PREFIX))
+            .filter(|route| route.is_empty() || route.starts_with('/'))
+            .unwrap_or(path);
+        let route_allowed = match credentials.routes {
+            Some(ref routes) => routes.iter().any(|allowed| {
+                let allowed = allowed.trim_end_matches('/');
+                route == allowed || route.starts_with(&format!("{}/", allowed))
+            }),
+            None => true,
+        };
+
+        if method_allowed && route_allowed {
+            Ok(())
+        } else {
+            Err(AuthError::Forbidden)
+        }
+    }
+
+    fn authenticate(&self, authorization: &str) -> Option<&AdminApiCredentials> {
+        let (scheme, value) = authorization.trim().split_once(' ')?;
+        let value = value.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            self.credentials.iter().find(|credentials| {
+                credentials
+                    .token
+                    .as_ref()
+                    .is_some_and(|token| secure_eq(token.as_bytes(), value.as_bytes()))
+            })
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = STANDARD.decode(value).ok()?;
+            let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+            self.credentials.iter().find(|credentials| {
+                let password_matches = credentials
+                    .password
+                    .as_ref()
+                    .is_some_and(|expected| secure_eq(expected.as_bytes(), password.as_bytes()));
+                credentials.username.as_deref() == Some(username) && password_matches
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Compares secrets in a time which doesn't depend on a position of the first difference.
+fn secure_eq(expected: &[u8], actual: &[u8]) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual)
+            .fold(0, |diff, (left, right)| diff | (left ^ right))
+            == 0
+}
+
+/// Rejects requests which are not authorized by `auth`.
+pub fn with_auth(auth: Arc<AdminApiAuth>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>(header::AUTHORIZATION.as_str())
+        .and(warp::method())
+        .and(warp::path::full())
+        .and_then(
+            move |authorization: Option<String>, method: Method, path: FullPath| {
+                let auth = auth.clone();
+                async move {
+                    auth.authorize(authorization.as_deref(), &method, path.as_str())
+                        .map_err(reject::custom)
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Replies to requests rejected by `with_auth`, other rejections are passed through.
+pub async fn recover(rejection: Rejection) -> Result<Response, Rejection> {
+    match rejection.find::<AuthError>() {
+        Some(AuthError::Unauthenticated) => Ok(reply::with_header(
+            error_reply("Unauthorized", StatusCode::UNAUTHORIZED),
+            header::WWW_AUTHENTICATE,
+            "Bearer, Basic realm=\"TeleMQ Admin API\"",
+        )
+        .into_response()),
+        Some(AuthError::Forbidden) => {
+            Ok(error_reply("Forbidden", StatusCode::FORBIDDEN).into_response())
+        }
+        None => Err(rejection),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> AdminApiAuth {
+        AdminApiAuth::new(vec![
+            AdminApiCredentials {
+                token: Some("monitoring-token".into()),
+                username: None,
+                password: None,
+                role: AdminApiRole::ReadOnly,
+                routes: Some(vec!["/metrics".into(), "/status".into()]),
+            },
+            AdminApiCredentials {
+                token: None,
+                username: Some("ops".into()),
+                password: Some("secret".into()),
+                role: AdminApiRole::Admin,
+                routes: None,
+            },
+        ])
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", STANDARD.encode(credentials))
+    }
+
+    #[test]
+    fn requests_are_allowed_without_credentials_configured() {
+        let auth = AdminApiAuth::default();
+
+        assert_eq!(
+            auth.authorize(None, &Method::DELETE, "/v1/devices/a/queue"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn unknown_credentials_are_unauthenticated() {
+        let auth = auth();
+
+        for authorization in [
+            None,
+            Some("Bearer wrong-token".to_string()),
+            Some(basic("ops:wrong")),
+            Some("Digest monitoring-token".to_string()),
+        ] {
+            assert_eq!(
+                auth.authorize(authorization.as_deref(), &Method::GET, "/metrics"),
+                Err(AuthError::Unauthenticated)
+            );
+        }
+    }
+
+    #[test]
+    fn routes_and_methods_are_authorized_by_role() {
+        let auth = auth();
+        let token = Some("Bearer monitoring-token");
+
+        assert_eq!(auth.authorize(token, &Method::GET, "/metrics"), Ok(()));
+        assert_eq!(auth.authorize(token, &Method::GET, "/v1/status"), Ok(()));
+        assert_eq!(
+            auth.authorize(token, &Method::GET, "/v1/devices/a"),
+            Err(AuthError::Forbidden)
+        );
+        assert_eq!(
+            auth.authorize(token, &Method::GET, "/statuses"),
+            Err(AuthError::Forbidden)
+        );
+        assert_eq!(
+            auth.authorize(token, &Method::POST, "/v1/status"),
+            Err(AuthError::Forbidden)
+        );
+
+        let ops = basic("ops:secret");
+        assert_eq!(
+            auth.authorize(Some(&ops), &Method::DELETE, "/v1/devices/a/queue"),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use std::{convert::Infallible, sync::Arc, time::UNIX_EPOCH};
+
+use tokio::sync::oneshot;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::{
+    api::{error_reply, json_reply, with_context, AdminApiContext},
+    v1::{ConnectionLimitRequest, ConnectionLimitView, ConnectionView, TlsView},
+};
+use crate::{
+    connection_gate::{ConnectionTransport, TlsMetadata},
+    connection_info::ConnectionInfo,
+    control::ControlMessage,
+    mqtt_codec::ProtocolVersion,
+};
+
+pub fn transport_name(transport: ConnectionTransport) -> &'static str {
+    match transport {
+        ConnectionTransport::Tcp => "tcp",
+        ConnectionTransport::Tls => "tls",
+        ConnectionTransport::Ws => "ws",
+        ConnectionTransport::Wss => "wss",
+        ConnectionTransport::MqttSn => "mqtt_sn",
+        ConnectionTransport::InProcess => "in_process",
+    }
+}
+
+impl From<&ConnectionInfo> for ConnectionView {
+    fn from(info: &ConnectionInfo) -> Self {
+        ConnectionView {
+            client_id: info.client_id.clone(),
+            addr: info.addr.to_string(),
+            transport: transport_name(info.transport).into(),
+            protocol: info.protocol.map(|protocol| {
+                match protocol {
+                    ProtocolVersion::V3_1_1 => "3.1.1",
+                    ProtocolVersion::V5_0 => "5.0",
+                }
+                .into()
+            }),
+            connected_at: info
+                .connected_at
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or(0),
+            tls: info.tls.as_ref().map(TlsView::from),
+        }
+    }
+}
+
+impl From<&TlsMetadata> for TlsView {
+    fn from(tls: &TlsMetadata) -> Self {
+        TlsView {
+            server_name: tls.server_name.clone(),
+            alpn_protocol: tls
+                .alpn_protocol
+                .as_ref()
+                .map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+            protocol_version: tls.protocol_version.clone(),
+            peer_certificates: tls.peer_certificates.len(),
+            ja3: tls.ja3.as_ref().map(|ja3| ja3.hash.clone()),
+        }
+    }
+}
+
+pub fn routes(
+    context: AdminApiContext,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list_connections = warp::path!("connections")
+        .and(warp::get())
+        .and(with_context(context.clone()))
+        .and_then(list_connections);
+
+    let get_connection = warp::path!("connections" / String)
+        .and(warp::get())
+        .and(with_context(context.clone()))
+        .and_then(get_connection);
+
+    let get_connection_limit = warp::path!("connection_limit")
+        .and(warp::get())
+        .and(with_context(context.clone()))
+        .and_then(get_connection_limit);
+
+    let set_connection_limit = warp::path!("connection_limit")
+        .and(warp::put())
+        .and(warp::body::json::<ConnectionLimitRequest>())
+        .and(with_context(context))
+        .and_then(set_connection_limit);
+
+    list_connections
+        .or(get_connection)
+        .or(get_connection_limit)
+        .or(set_connection_limit)
+}
+
+async fn list_connections(context: AdminApiContext) -> Result<impl Reply, Infallible> {
+    match request_connections(&context).await {
+        Some(connections) => {
+            let views: Vec<ConnectionView> = connections
+                .iter()
+                .map(|info| ConnectionView::from(info.as_ref()))
+                .collect();
+            Ok(json_reply(&views, StatusCode::OK))
+        }
+        None => Ok(error_reply(
+            "Broker is not available",
+            StatusCode::SERVICE_UNAVAILABLE,
+        )),
+    }
+}
+
+async fn get_connection(
+    client_id: String,
+    context: AdminApiContext,
+) -> Result<impl Reply, Infallible> {
+    let connections = match request_connections(&context).await {
+        Some(connections) => connections,
+        None => {
+            return Ok(error_reply(
+                "Broker is not available",
+                StatusCode::SERVICE_UNAVAILABLE,
+            ));
+        }
+    };
+
+    match connections.iter().find(|info| info.client_id == client_id) {
+        Some(info) => Ok(json_reply(
+            &ConnectionView::from(info.as_ref()),
+            StatusCode::OK,
+        )),
+        None => Ok(error_reply(
+            format!("Client {} is not connected", client_id),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+async fn get_connection_limit(context: AdminApiContext) -> Result<impl Reply, Infallible> {
+    Ok(json_reply(&connection_limit_view(&context), StatusCode::OK))
+}
+
+async fn set_connection_limit(
+    request: ConnectionLimitRequest,
+    context: AdminApiContext,
+) -> Result<impl Reply, Infallible> {
+    if request.max_connections == 0 {
+        return Ok(error_reply(
+            "max_connections should be greater than 0",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    if request.drain_rate == Some(0) {
+        return Ok(error_reply(
+            "drain_rate should be greater than 0",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    context.connection_limit.set_max(
+        request.max_connections,
+        request.drain_rate,
+        &context.control_sender,
+    );
+
+    Ok(json_reply(&connection_limit_view(&context), StatusCode::OK))
+}
+
+fn connection_limit_view(context: &AdminApiContext) -> ConnectionLimitView {
+    ConnectionLimitView {
+        connections: context.connection_limit.current(),
+        max_connections: context.connection_limit.max(),
+    }
+}
+
+async fn request_connections(context: &AdminApiContext) -> Option<Vec<Arc<ConnectionInfo>>> {
+    let (reply, response) = oneshot::channel();
+    context
+        .control_sender
+        .send(ControlMessage::ListConnections { reply })
+        .ok()?;
+    response.await.ok()
+}
@@ -0,0 +1,265 @@
+use std::{
+    convert::Infallible,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+use mqtt_packets::v_3_1_1::{
+    publish::fixed_header::get_qos_level, topic::Subscription, variable::Variable,
+};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::{
+    api::{error_reply, json_reply, with_context, AdminApiContext},
+    retained,
+    v1::{
+        DeviceErasureView, DeviceView, LastDisconnectView, QueuePurgeQuery, QueuePurgeView,
+        QueuedMessageView,
+    },
+};
+
+/// Replaced by a client id in `erase_retained_topics`.
+const CLIENT_ID_PATTERN: &str = "{client_id}";
+
+pub fn routes(
+    context: AdminApiContext,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let get_device = warp::path!("devices" / String)
+        .and(warp::get())
+        .and(with_context(context.clone()))
+        .and_then(get_device);
+
+    let get_last_disconnect = warp::path!("devices" / String / "last_disconnect")
+        .and(warp::get())
+        .and(with_context(context.clone()))
+        .and_then(get_last_disconnect);
+
+    let get_queue = warp::path!("devices" / String / "queue")
+        .and(warp::get())
+        .and(with_context(context.clone()))
+        .and_then(get_queue);
+
+    let purge_queue = warp::path!("devices" / String / "queue")
+        .and(warp::delete())
+        .and(warp::query::<QueuePurgeQuery>())
+        .and(with_context(context.clone()))
+        .and_then(purge_queue);
+
+    let erase_data = warp::path!("devices" / String / "data")
+        .and(warp::delete())
+        .and(with_context(context))
+        .and_then(erase_data);
+
+    get_device
+        .or(get_last_disconnect)
+        .or(get_queue)
+        .or(purge_queue)
+        .or(erase_data)
+}
+
+async fn get_device(client_id: String, context: AdminApiContext) -> Result<impl Reply, Infallible> {
+    let state_store = context.state_store.read().await;
+    let history = match state_store.get_history(&client_id) {
+        Some(history) => history,
+        None => {
+            return Ok(error_reply(
+                format!("Client {} has never connected", client_id),
+                StatusCode::NOT_FOUND,
+            ));
+        }
+    };
+
+    let view = DeviceView {
+        session_stored: state_store.has_session(&client_id),
+        client_id,
+        online: history.online,
+        last_connected_at: history.last_connected_at.map(unix_timestamp),
+        last_disconnected_at: history.last_disconnected_at.map(unix_timestamp),
+        last_disconnect_reason: history
+            .last_disconnect_reason
+            .map(|reason| reason.to_string()),
+        messages_received: history.messages_received,
+        messages_sent: history.messages_sent,
+    };
+
+    Ok(json_reply(&view, StatusCode::OK))
+}
+
+async fn get_last_disconnect(
+    client_id: String,
+    context: AdminApiContext,
+) -> Result<impl Reply, Infallible> {
+    let history = context.state_store.read().await.get_history(&client_id);
+    let (reason, disconnected_at) = match history.and_then(|history| {
+        history
+            .last_disconnect_reason
+            .zip(history.last_disconnected_at)
+    }) {
+        Some(last_disconnect) => last_disconnect,
+        None => {
+            return Ok(error_reply(
+                format!("Client {} has never disconnected", client_id),
+                StatusCode::NOT_FOUND,
+            ));
+        }
+    };
+
+    let view = LastDisconnectView {
+        client_id,
+        reason: reason.to_string(),
+        by_broker: reason.by_broker(),
+        disconnected_at: unix_timestamp(disconnected_at),
+    };
+
+    Ok(json_reply(&view, StatusCode::OK))
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0)
+}
+
+async fn get_queue(client_id: String, context: AdminApiContext) -> Result<impl Reply, Infallible> {
+    let state_store = context.state_store.read().await;
+    let queue = match state_store.get_queue(&client_id).await {
+        Some(queue) => queue,
+        None => {
+            return Ok(error_reply(
+                format!("No stored session for client {}", client_id),
+                StatusCode::NOT_FOUND,
+            ));
+        }
+    };
+
+    let now = state_store.clock().now();
+    let views: Vec<QueuedMessageView> = queue
+        .iter()
+        .filter_map(|pending| match pending.packet.variable {
+            Variable::Publish(ref variable) => Some(QueuedMessageView {
+                topic: variable.topic_name.original.clone(),
+                size: variable.payload.len(),
+                age: now
+                    .duration_since(pending.queued_at)
+                    .map(|age| age.as_secs())
+                    .unwrap_or(0),
+                qos: get_qos_level(&pending.packet.fixed_header)
+                    .map(|qos| qos.bits())
+                    .unwrap_or(0),
+            }),
+            _ => None,
+        })
+        .collect();
+
+    Ok(json_reply(&views, StatusCode::OK))
+}
+
+async fn purge_queue(
+    client_id: String,
+    query: QueuePurgeQuery,
+    context: AdminApiContext,
+) -> Result<impl Reply, Infallible> {
+    let filter = match query.topic {
+        Some(topic) => match Subscription::try_from(&topic) {
+            Ok(filter) if filter.is_valid() => Some(filter),
+            _ => {
+                return Ok(error_reply(
+                    format!("Invalid topic filter {}", topic),
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+        },
+        None => None,
+    };
+
+    match context
+        .state_store
+        .read()
+        .await
+        .purge_queue(&client_id, filter.as_ref())
+        .await
+    {
+        Some(removed) => Ok(json_reply(&QueuePurgeView { removed }, StatusCode::OK)),
+        None => Ok(error_reply(
+            format!("No stored session for client {}", client_id),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+/// Erases a stored session, queued messages, history and retained messages under
+/// `erase_retained_topics` of an offline client, e.g. on a data deletion request of its owner.
+/// The reply is an audit record, which is logged as well.
+async fn erase_data(client_id: String, context: AdminApiContext) -> Result<impl Reply, Infallible> {
+    // a client id with wildcards would make filters match topics of other clients
+    if client_id.contains(['+', '#']) {
+        return Ok(error_reply(
+            format!(
+                "Client id {} contains wildcards, its retained messages can't be matched",
+                client_id
+            ),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    let mut filters = Vec::with_capacity(context.erase_retained_topics.len());
+    for topic in context.erase_retained_topics.iter() {
+        let topic = topic.replace(CLIENT_ID_PATTERN, &client_id);
+        match Subscription::try_from(&topic) {
+            Ok(filter) if filter.is_valid() => filters.push(filter),
+            _ => {
+                return Ok(error_reply(
+                    format!("Invalid topic filter {}", topic),
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+        }
+    }
+
+    let (erased, erased_at) = {
+        let mut state_store = context.state_store.write().await;
+        (
+            state_store.erase_client(&client_id),
+            state_store.clock().now(),
+        )
+    };
+    let erased = match erased {
+        Some(erased) => erased,
+        None => {
+            return Ok(error_reply(
+                format!("Client {} is connected", client_id),
+                StatusCode::CONFLICT,
+            ));
+        }
+    };
+
+    let mut retained_messages = 0;
+    for filter in filters {
+        match retained::purge(&context, filter).await {
+            Some(removed) => retained_messages += removed,
+            // the session and history are gone already, so a retry erases the rest
+            None => {
+                return Ok(error_reply(
+                    "Broker is not available",
+                    StatusCode::SERVICE_UNAVAILABLE,
+                ));
+            }
+        }
+    }
+
+    let view = DeviceErasureView {
+        client_id,
+        erased_at: unix_timestamp(erased_at),
+        session_erased: erased.session,
+        queued_messages: erased.queued_messages,
+        retained_messages,
+        history_erased: erased.history,
+    };
+    info!(
+        event = "device_data_erased", client_id = view.client_id.as_str(),
+        session_erased = view.session_erased, queued_messages = view.queued_messages,
+        retained_messages = view.retained_messages, history_erased = view.history_erased;
+        "[Admin API]: Data of client {} has been erased", view.client_id
+    );
+
+    Ok(json_reply(&view, StatusCode::OK))
+}
@@ -0,0 +1,12 @@
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::api::{json_reply, with_context, AdminApiContext};
+
+pub fn routes(
+    context: AdminApiContext,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("features")
+        .and(warp::get())
+        .and(with_context(context))
+        .map(|context: AdminApiContext| json_reply(&*context.features, StatusCode::OK))
+}
@@ -0,0 +1,41 @@
+use std::convert::Infallible;
+
+use tokio::sync::oneshot;
+use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
+
+use super::api::{error_reply, with_context, AdminApiContext};
+use crate::stats::StatsMessage;
+
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub fn routes(
+    context: AdminApiContext,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(with_context(context))
+        .and_then(metrics)
+}
+
+async fn metrics(context: AdminApiContext) -> Result<reply::Response, Infallible> {
+    match request_metrics(&context).await {
+        Some(exposition) => {
+            Ok(
+                reply::with_header(exposition, "content-type", PROMETHEUS_CONTENT_TYPE)
+                    .into_response(),
+            )
+        }
+        None => Ok(
+            error_reply("Broker is not available", StatusCode::SERVICE_UNAVAILABLE).into_response(),
+        ),
+    }
+}
+
+async fn request_metrics(context: &AdminApiContext) -> Option<String> {
+    let (reply, response) = oneshot::channel();
+    context
+        .stats_sender
+        .send(StatsMessage::Scrape { reply })
+        .ok()?;
+    response.await.ok()
+}
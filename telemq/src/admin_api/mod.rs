@@ -1,3 +1,16 @@
-use std::net::SocketAddr;
+mod api;
+mod auth;
+mod connections;
+mod devices;
+mod features;
+mod metrics;
+mod publish;
+mod retained;
+mod status;
+mod subscriptions;
+mod v1;
+mod version;
 
-pub async fn run(_port: SocketAddr) {}
+pub use api::{run, run_metrics, AdminApiContext, AdminApiTls};
+pub use auth::AdminApiAuth;
+pub use v1::PayloadEncoding;
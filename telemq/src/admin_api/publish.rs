@@ -0,0 +1,108 @@
+use std::convert::Infallible;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic, QoS};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::{
+    api::{error_reply, json_reply, with_context, AdminApiContext},
+    v1::{PayloadEncoding, PublishRequest, PublishView},
+};
+use crate::{control::ControlMessage, publish_metadata::PublishMetadata};
+
+/// Limit of a request body, the payload may be base64 encoded.
+pub const MAX_REQUEST_SIZE: u64 = 16 * 1024 * 1024;
+/// Metadata key of messages published via Admin API, so in-process subscribers can tell them
+/// from messages of clients.
+const ADMIN_API_METADATA_KEY: &str = "admin_api";
+
+pub fn routes(
+    context: AdminApiContext,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("publish")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_REQUEST_SIZE))
+        .and(warp::body::json::<PublishRequest>())
+        .and(with_context(context))
+        .and_then(publish)
+}
+
+async fn publish(
+    request: PublishRequest,
+    context: AdminApiContext,
+) -> Result<impl Reply, Infallible> {
+    let topic = match Topic::try_from(&request.topic) {
+        Ok(topic) if topic.is_valid() => topic,
+        _ => {
+            return Ok(error_reply(
+                format!("Invalid topic name {}", request.topic),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+    let qos = match QoS::try_from(request.qos) {
+        Ok(qos) => qos,
+        Err(_) => {
+            return Ok(error_reply(
+                format!("Invalid QoS {}", request.qos),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+    let payload = match request.encoding {
+        PayloadEncoding::Utf8 => request.payload.into_bytes(),
+        PayloadEncoding::Base64 => match STANDARD.decode(&request.payload) {
+            Ok(payload) => payload,
+            Err(err) => {
+                return Ok(error_reply(
+                    format!("Invalid base64 payload. {}", err),
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+        },
+    };
+
+    let size = payload.len();
+    if !inject(&context, topic, payload, qos, request.retain) {
+        return Ok(error_reply(
+            "Broker is not available",
+            StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    }
+
+    Ok(json_reply(
+        &PublishView {
+            topic: request.topic,
+            size,
+        },
+        StatusCode::OK,
+    ))
+}
+
+/// Hands a message over to Control as if it was published by a client. Returns `false` if
+/// Control is not available.
+pub fn inject(
+    context: &AdminApiContext,
+    topic: Topic,
+    payload: Vec<u8>,
+    qos: QoS,
+    retain: bool,
+) -> bool {
+    let mut builder = PublishPacketBuilder::new();
+    builder
+        .with_topic(topic)
+        .with_payload(payload)
+        .with_qos(&qos)
+        .with_retained(retain);
+    let mut metadata = PublishMetadata::new();
+    metadata.insert(ADMIN_API_METADATA_KEY, "true");
+
+    let message = ControlMessage::Publish {
+        publisher: None,
+        packet: builder.build(),
+        metadata,
+        sequence: None,
+        subscribers: None,
+    };
+    context.control_sender.send(message).is_ok()
+}
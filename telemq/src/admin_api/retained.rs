@@ -0,0 +1,126 @@
+use std::convert::Infallible;
+
+use bytes::Bytes;
+use mqtt_packets::v_3_1_1::{
+    topic::{Subscription, Topic},
+    QoS,
+};
+use percent_encoding::percent_decode_str;
+use tokio::sync::oneshot;
+use warp::{filters::path::Tail, http::StatusCode, Filter, Rejection, Reply};
+
+use super::{
+    api::{error_reply, json_reply, with_context, AdminApiContext},
+    publish::{inject, MAX_REQUEST_SIZE},
+    v1::{RetainedPurgeQuery, RetainedPurgeView, RetainedSetQuery, RetainedSetView},
+};
+use crate::control::ControlMessage;
+
+pub fn routes(
+    context: AdminApiContext,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let purge = warp::path!("retained")
+        .and(warp::delete())
+        .and(warp::query::<RetainedPurgeQuery>())
+        .and(with_context(context.clone()))
+        .and_then(purge_retained);
+    // a topic name spans the rest of the path, e.g. /retained/config/site-1
+    let set = warp::path("retained")
+        .and(warp::path::tail())
+        .and(warp::put())
+        .and(warp::query::<RetainedSetQuery>())
+        .and(warp::body::content_length_limit(MAX_REQUEST_SIZE))
+        .and(warp::body::bytes())
+        .and(with_context(context))
+        .and_then(set_retained);
+
+    purge.or(set)
+}
+
+/// Retains a message with a raw request body as a payload. An empty body removes a retained
+/// message of the topic.
+async fn set_retained(
+    tail: Tail,
+    query: RetainedSetQuery,
+    body: Bytes,
+    context: AdminApiContext,
+) -> Result<impl Reply, Infallible> {
+    let topic_name = match percent_decode_str(tail.as_str()).decode_utf8() {
+        Ok(topic_name) => topic_name.into_owned(),
+        Err(_) => {
+            return Ok(error_reply(
+                "Topic name is not valid UTF-8",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+    let topic = match Topic::try_from(&topic_name) {
+        Ok(topic) if topic.is_valid() => topic,
+        _ => {
+            return Ok(error_reply(
+                format!("Invalid topic name {}", topic_name),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+    let qos = match QoS::try_from(query.qos) {
+        Ok(qos) => qos,
+        Err(_) => {
+            return Ok(error_reply(
+                format!("Invalid QoS {}", query.qos),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    let size = body.len();
+    if !inject(&context, topic, body.to_vec(), qos, true) {
+        return Ok(error_reply(
+            "Broker is not available",
+            StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    }
+
+    Ok(json_reply(
+        &RetainedSetView {
+            topic: topic_name,
+            size,
+        },
+        StatusCode::OK,
+    ))
+}
+
+async fn purge_retained(
+    query: RetainedPurgeQuery,
+    context: AdminApiContext,
+) -> Result<impl Reply, Infallible> {
+    let filter = match Subscription::try_from(&query.filter) {
+        Ok(filter) if filter.is_valid() => filter,
+        _ => {
+            return Ok(error_reply(
+                format!("Invalid topic filter {}", query.filter),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    match purge(&context, filter).await {
+        Some(removed) => Ok(json_reply(&RetainedPurgeView { removed }, StatusCode::OK)),
+        None => Ok(error_reply(
+            "Broker is not available",
+            StatusCode::SERVICE_UNAVAILABLE,
+        )),
+    }
+}
+
+/// Removes retained messages matching a topic filter by Control Worker and returns their
+/// number, or `None` if Control Worker is gone.
+pub async fn purge(context: &AdminApiContext, filter: Subscription) -> Option<usize> {
+    let (reply, response) = oneshot::channel();
+    context
+        .control_sender
+        .send(ControlMessage::PurgeRetained { filter, reply })
+        .ok()?;
+
+    response.await.ok()
+}
@@ -0,0 +1,78 @@
+use std::convert::Infallible;
+
+use tokio::sync::oneshot;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::{
+    api::{error_reply, json_reply, with_context, AdminApiContext},
+    connections::transport_name,
+    v1::{ClientsView, ListenerView, StatusView, SubscriptionTreeView, TrafficView},
+};
+use crate::{control::ControlMessage, stats::StatsMessage};
+
+pub fn routes(
+    context: AdminApiContext,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("status")
+        .and(warp::get())
+        .and(with_context(context))
+        .and_then(status)
+}
+
+async fn status(context: AdminApiContext) -> Result<impl Reply, Infallible> {
+    let (tree_reply, tree_response) = oneshot::channel();
+    let (stats_reply, stats_response) = oneshot::channel();
+    if context
+        .control_sender
+        .send(ControlMessage::SubscriptionTreeUsage { reply: tree_reply })
+        .is_err()
+        || context
+            .stats_sender
+            .send(StatsMessage::Summary { reply: stats_reply })
+            .is_err()
+    {
+        return Ok(error_reply(
+            "Broker is not available",
+            StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    }
+
+    match (tree_response.await, stats_response.await) {
+        (Ok((usage, is_over_warning)), Ok(summary)) => Ok(json_reply(
+            &StatusView {
+                subscription_tree: SubscriptionTreeView {
+                    nodes: usage.nodes,
+                    subscriptions: usage.subscriptions,
+                    estimated_memory_bytes: usage.estimated_bytes,
+                    over_warning_threshold: is_over_warning,
+                },
+                uptime: summary.uptime.as_secs(),
+                clients: ClientsView {
+                    connected: summary.clients_connected,
+                    maximum: summary.clients_maximum,
+                },
+                messages: TrafficView {
+                    received: summary.messages_received,
+                    sent: summary.messages_sent,
+                },
+                bytes: TrafficView {
+                    received: summary.bytes_received,
+                    sent: summary.bytes_sent,
+                },
+                listeners: context
+                    .listeners
+                    .iter()
+                    .map(|(transport, addr)| ListenerView {
+                        transport: transport_name(*transport).into(),
+                        addr: addr.to_string(),
+                    })
+                    .collect(),
+            },
+            StatusCode::OK,
+        )),
+        _ => Ok(error_reply(
+            "Broker is not available",
+            StatusCode::SERVICE_UNAVAILABLE,
+        )),
+    }
+}
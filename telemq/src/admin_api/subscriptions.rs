@@ -0,0 +1,100 @@
+use std::convert::Infallible;
+
+use mqtt_packets::v_3_1_1::topic::Subscription;
+use tokio::sync::oneshot;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::{
+    api::{error_reply, json_reply, with_context, AdminApiContext},
+    v1::{SubscribersCountQuery, SubscribersCountView, SubscriptionView},
+};
+use crate::control::ControlMessage;
+
+pub fn routes(
+    context: AdminApiContext,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let count_subscribers = warp::path!("subscriptions" / "count")
+        .and(warp::get())
+        .and(warp::query::<SubscribersCountQuery>())
+        .and(with_context(context.clone()))
+        .and_then(count_subscribers);
+
+    let dump_subscriptions = warp::path!("subscriptions" / "tree")
+        .and(warp::get())
+        .and(with_context(context))
+        .and_then(dump_subscriptions);
+
+    count_subscribers.or(dump_subscriptions)
+}
+
+async fn count_subscribers(
+    query: SubscribersCountQuery,
+    context: AdminApiContext,
+) -> Result<impl Reply, Infallible> {
+    let filter = match Subscription::try_from(&query.filter) {
+        Ok(filter) if filter.is_valid() => filter,
+        _ => {
+            return Ok(error_reply(
+                format!("Invalid topic filter {}", query.filter),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    let (reply, response) = oneshot::channel();
+    if context
+        .control_sender
+        .send(ControlMessage::CountSubscribers { filter, reply })
+        .is_err()
+    {
+        return Ok(error_reply(
+            "Broker is not available",
+            StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    }
+
+    match response.await {
+        Ok(subscribers) => Ok(json_reply(
+            &SubscribersCountView {
+                filter: query.filter,
+                subscribers,
+            },
+            StatusCode::OK,
+        )),
+        Err(_) => Ok(error_reply(
+            "Broker is not available",
+            StatusCode::SERVICE_UNAVAILABLE,
+        )),
+    }
+}
+
+async fn dump_subscriptions(context: AdminApiContext) -> Result<impl Reply, Infallible> {
+    let (reply, response) = oneshot::channel();
+    if context
+        .control_sender
+        .send(ControlMessage::DumpSubscriptions { reply })
+        .is_err()
+    {
+        return Ok(error_reply(
+            "Broker is not available",
+            StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    }
+
+    match response.await {
+        Ok(entries) => {
+            let views: Vec<SubscriptionView> = entries
+                .into_iter()
+                .map(|(filter, subscribers)| SubscriptionView {
+                    filter,
+                    subscribers,
+                })
+                .collect();
+            Ok(json_reply(&views, StatusCode::OK))
+        }
+        Err(_) => Ok(error_reply(
+            "Broker is not available",
+            StatusCode::SERVICE_UNAVAILABLE,
+        )),
+    }
+}
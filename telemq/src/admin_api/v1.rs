@@ -0,0 +1,518 @@
+//! Request and response schema of Admin API v1, served under `/v1/`.
+//!
+//! Within v1 fields are never removed or renamed and their types don't change. New fields
+//! may be added to responses, so clients should ignore unknown ones. Breaking changes go to
+//! a new API version. Tests below pin JSON shapes of responses.
+use serde::{Deserialize, Serialize};
+
+/// Path prefix of v1 routes.
+pub const PREFIX: &str = "v1";
+
+#[derive(Serialize, Deserialize)]
+pub struct ErrorView {
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VersionView {
+    /// Version of the broker, e.g. `0.2.0`.
+    pub broker_version: String,
+    /// Admin API versions served by the broker, e.g. `["v1"]`.
+    pub api_versions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TlsView {
+    pub server_name: Option<String>,
+    pub alpn_protocol: Option<String>,
+    pub protocol_version: Option<String>,
+    /// Number of certificates in a client certificates chain.
+    pub peer_certificates: usize,
+    /// JA3 hash of ClientHello, if `tls_fingerprint` is enabled.
+    pub ja3: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConnectionView {
+    pub client_id: String,
+    pub addr: String,
+    /// tcp, tls, ws, wss or in_process
+    pub transport: String,
+    /// 3.1.1 or 5.0, `null` for in-process clients
+    pub protocol: Option<String>,
+    /// Unix timestamp in seconds.
+    pub connected_at: u64,
+    pub tls: Option<TlsView>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceView {
+    pub client_id: String,
+    pub online: bool,
+    /// A persistent session is stored for the client while it's offline.
+    pub session_stored: bool,
+    /// Unix timestamps in seconds.
+    pub last_connected_at: Option<u64>,
+    pub last_disconnected_at: Option<u64>,
+    pub last_disconnect_reason: Option<String>,
+    /// PUBLISH packets exchanged over closed connections of the client.
+    pub messages_received: u64,
+    pub messages_sent: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LastDisconnectView {
+    pub client_id: String,
+    /// One of reasons listed in the docs, e.g. `keep_alive_timeout`.
+    pub reason: String,
+    /// The broker has closed the connection, rather than the client or a network failure.
+    pub by_broker: bool,
+    /// Unix timestamp in seconds.
+    pub disconnected_at: u64,
+}
+
+/// Audit record of data of a client erased by `DELETE /devices/{client_id}/data`.
+#[derive(Serialize, Deserialize)]
+pub struct DeviceErasureView {
+    pub client_id: String,
+    /// Unix timestamp in seconds.
+    pub erased_at: u64,
+    pub session_erased: bool,
+    /// Messages queued for the client which have been erased with its session.
+    pub queued_messages: usize,
+    pub retained_messages: usize,
+    pub history_erased: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QueuedMessageView {
+    pub topic: String,
+    /// Payload size in bytes.
+    pub size: usize,
+    /// Time in seconds the message has been waiting in the queue.
+    pub age: u64,
+    pub qos: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QueuePurgeQuery {
+    /// Topic name or topic filter. If omitted the whole queue is purged.
+    pub topic: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QueuePurgeView {
+    pub removed: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubscribersCountQuery {
+    /// Topic name or topic filter.
+    pub filter: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubscribersCountView {
+    pub filter: String,
+    pub subscribers: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubscriptionView {
+    pub filter: String,
+    pub subscribers: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RetainedPurgeQuery {
+    /// Topic name or topic filter.
+    pub filter: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RetainedPurgeView {
+    pub removed: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RetainedSetQuery {
+    /// 0 if omitted.
+    #[serde(default)]
+    pub qos: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RetainedSetView {
+    pub topic: String,
+    /// Payload size in bytes, 0 if the retained message has been removed.
+    pub size: usize,
+}
+
+/// Encoding of a payload of `PublishRequest`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadEncoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PublishRequest {
+    /// Topic name, wildcards are not allowed.
+    pub topic: String,
+    pub payload: String,
+    /// `utf8` if omitted.
+    #[serde(default)]
+    pub encoding: PayloadEncoding,
+    /// 0 if omitted.
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PublishView {
+    pub topic: String,
+    /// Payload size in bytes.
+    pub size: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConnectionLimitRequest {
+    pub max_connections: usize,
+    /// Connections per second disconnected while more than `max_connections` are open.
+    /// If omitted, open connections are kept and only new ones are refused.
+    #[serde(default)]
+    pub drain_rate: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConnectionLimitView {
+    /// Open network connections, including ones which have not sent CONNECT yet.
+    pub connections: usize,
+    pub max_connections: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubscriptionTreeView {
+    /// Topic levels of all topic filters.
+    pub nodes: usize,
+    /// Pairs of a topic filter and a client id.
+    pub subscriptions: usize,
+    pub estimated_memory_bytes: usize,
+    /// `nodes` is over `subscription_tree_warning_nodes`.
+    pub over_warning_threshold: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClientsView {
+    /// Currently connected clients.
+    pub connected: u64,
+    /// Maximal number of simultaneously connected clients.
+    pub maximum: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TrafficView {
+    pub received: u64,
+    pub sent: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListenerView {
+    /// `tcp`, `tls`, `ws` or `wss`.
+    pub transport: String,
+    pub addr: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StatusView {
+    pub subscription_tree: SubscriptionTreeView,
+    /// Seconds since the broker has started.
+    pub uptime: u64,
+    pub clients: ClientsView,
+    /// Packets exchanged with clients since the broker has started.
+    pub messages: TrafficView,
+    /// Bytes exchanged with clients since the broker has started.
+    pub bytes: TrafficView,
+    pub listeners: Vec<ListenerView>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, to_value};
+
+    #[test]
+    fn error_shape() {
+        let view = ErrorView {
+            error: "Broker is not available".into(),
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({"error": "Broker is not available"})
+        );
+    }
+
+    #[test]
+    fn version_shape() {
+        let view = VersionView {
+            broker_version: "0.2.0".into(),
+            api_versions: vec!["v1".into()],
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({"broker_version": "0.2.0", "api_versions": ["v1"]})
+        );
+    }
+
+    #[test]
+    fn connection_shape() {
+        let view = ConnectionView {
+            client_id: "DEVICE_1".into(),
+            addr: "10.0.0.7:51234".into(),
+            transport: "tls".into(),
+            protocol: Some("3.1.1".into()),
+            connected_at: 1700000000,
+            tls: Some(TlsView {
+                server_name: Some("mqtt.example.com".into()),
+                alpn_protocol: None,
+                protocol_version: Some("TLSv1_3".into()),
+                peer_certificates: 0,
+                ja3: None,
+            }),
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({
+                "client_id": "DEVICE_1",
+                "addr": "10.0.0.7:51234",
+                "transport": "tls",
+                "protocol": "3.1.1",
+                "connected_at": 1700000000,
+                "tls": {
+                    "server_name": "mqtt.example.com",
+                    "alpn_protocol": null,
+                    "protocol_version": "TLSv1_3",
+                    "peer_certificates": 0,
+                    "ja3": null
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn device_shape() {
+        let view = DeviceView {
+            client_id: "DEVICE_1".into(),
+            online: false,
+            session_stored: true,
+            last_connected_at: Some(1700000000),
+            last_disconnected_at: Some(1700003600),
+            last_disconnect_reason: Some("keep_alive_timeout".into()),
+            messages_received: 120,
+            messages_sent: 4,
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({
+                "client_id": "DEVICE_1",
+                "online": false,
+                "session_stored": true,
+                "last_connected_at": 1700000000,
+                "last_disconnected_at": 1700003600,
+                "last_disconnect_reason": "keep_alive_timeout",
+                "messages_received": 120,
+                "messages_sent": 4
+            })
+        );
+    }
+
+    #[test]
+    fn last_disconnect_shape() {
+        let view = LastDisconnectView {
+            client_id: "DEVICE_1".into(),
+            reason: "session_taken_over".into(),
+            by_broker: true,
+            disconnected_at: 1700003600,
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({
+                "client_id": "DEVICE_1",
+                "reason": "session_taken_over",
+                "by_broker": true,
+                "disconnected_at": 1700003600
+            })
+        );
+    }
+
+    #[test]
+    fn device_erasure_shape() {
+        let view = DeviceErasureView {
+            client_id: "DEVICE_1".into(),
+            erased_at: 1700007200,
+            session_erased: true,
+            queued_messages: 3,
+            retained_messages: 2,
+            history_erased: true,
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({
+                "client_id": "DEVICE_1",
+                "erased_at": 1700007200,
+                "session_erased": true,
+                "queued_messages": 3,
+                "retained_messages": 2,
+                "history_erased": true
+            })
+        );
+    }
+
+    #[test]
+    fn queue_shapes() {
+        let view = QueuedMessageView {
+            topic: "device/DEVICE_1/firmware".into(),
+            size: 128,
+            age: 3600,
+            qos: 1,
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({"topic": "device/DEVICE_1/firmware", "size": 128, "age": 3600, "qos": 1})
+        );
+        assert_eq!(
+            to_value(QueuePurgeView { removed: 1 }).unwrap(),
+            json!({"removed": 1})
+        );
+    }
+
+    #[test]
+    fn subscription_shapes() {
+        let view = SubscribersCountView {
+            filter: "devices/+/telemetry".into(),
+            subscribers: 42,
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({"filter": "devices/+/telemetry", "subscribers": 42})
+        );
+        let view = SubscriptionView {
+            filter: "devices/+/telemetry".into(),
+            subscribers: vec!["DASHBOARD".into()],
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({"filter": "devices/+/telemetry", "subscribers": ["DASHBOARD"]})
+        );
+    }
+
+    #[test]
+    fn retained_shape() {
+        assert_eq!(
+            to_value(RetainedPurgeView { removed: 12 }).unwrap(),
+            json!({"removed": 12})
+        );
+        let view = RetainedSetView {
+            topic: "config/site-1".into(),
+            size: 2048,
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({"topic": "config/site-1", "size": 2048})
+        );
+    }
+
+    #[test]
+    fn publish_shapes() {
+        let request: PublishRequest = serde_json::from_value(json!({
+            "topic": "devices/DEVICE_1/commands",
+            "payload": "cmVib290",
+            "encoding": "base64",
+            "qos": 1
+        }))
+        .unwrap();
+        assert_eq!(request.encoding, PayloadEncoding::Base64);
+        assert_eq!(request.qos, 1);
+        assert!(!request.retain);
+
+        let request: PublishRequest =
+            serde_json::from_value(json!({"topic": "a", "payload": "reboot"})).unwrap();
+        assert_eq!(request.encoding, PayloadEncoding::Utf8);
+        assert_eq!(request.qos, 0);
+
+        let view = PublishView {
+            topic: "devices/DEVICE_1/commands".into(),
+            size: 6,
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({"topic": "devices/DEVICE_1/commands", "size": 6})
+        );
+    }
+
+    #[test]
+    fn connection_limit_shapes() {
+        let request: ConnectionLimitRequest =
+            serde_json::from_value(json!({"max_connections": 5000})).unwrap();
+        assert_eq!(request.max_connections, 5000);
+        assert_eq!(request.drain_rate, None);
+
+        let view = ConnectionLimitView {
+            connections: 6000,
+            max_connections: 5000,
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({"connections": 6000, "max_connections": 5000})
+        );
+    }
+
+    #[test]
+    fn status_shape() {
+        let view = StatusView {
+            subscription_tree: SubscriptionTreeView {
+                nodes: 1200,
+                subscriptions: 800,
+                estimated_memory_bytes: 262144,
+                over_warning_threshold: false,
+            },
+            uptime: 3600,
+            clients: ClientsView {
+                connected: 12,
+                maximum: 40,
+            },
+            messages: TrafficView {
+                received: 1500,
+                sent: 3000,
+            },
+            bytes: TrafficView {
+                received: 96000,
+                sent: 192000,
+            },
+            listeners: vec![ListenerView {
+                transport: "tcp".into(),
+                addr: "0.0.0.0:1883".into(),
+            }],
+        };
+        assert_eq!(
+            to_value(view).unwrap(),
+            json!({
+                "subscription_tree": {
+                    "nodes": 1200,
+                    "subscriptions": 800,
+                    "estimated_memory_bytes": 262144,
+                    "over_warning_threshold": false
+                },
+                "uptime": 3600,
+                "clients": {"connected": 12, "maximum": 40},
+                "messages": {"received": 1500, "sent": 3000},
+                "bytes": {"received": 96000, "sent": 192000},
+                "listeners": [{"transport": "tcp", "addr": "0.0.0.0:1883"}]
+            })
+        );
+    }
+}
@@ -0,0 +1,24 @@
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::{
+    api::json_reply,
+    v1::{self, VersionView},
+};
+
+/// Admin API versions served by the broker.
+const API_VERSIONS: [&str; 1] = [v1::PREFIX];
+
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("version").and(warp::get()).map(|| {
+        json_reply(
+            &VersionView {
+                broker_version: env!("CARGO_PKG_VERSION").into(),
+                api_versions: API_VERSIONS
+                    .iter()
+                    .map(|version| version.to_string())
+                    .collect(),
+            },
+            StatusCode::OK,
+        )
+    })
+}
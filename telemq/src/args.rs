@@ -9,6 +9,24 @@ pub fn parse_args() -> ArgMatches {
                 .help("TeleMQ configuration file")
                 .takes_value(true),
         )
+        .arg(Arg::new("STRICT_CONFIG").long("strict-config").help(
+            "Reject config files with fields TeleMQ doesn't know, e.g. misspelled ones",
+        ))
+        .arg(Arg::new("PRINT_CONFIG_SCHEMA").long("print-config-schema").help(
+            "Print a JSON schema of config fields with their types and default values and exit",
+        ))
+        .arg(Arg::new("TAKE_OVER").long("take-over").help(
+            "Take listeners and sessions over from a running TeleMQ process via handover_socket",
+        ))
+        .arg(Arg::new("FSCK_STATE_STORE").long("fsck-state-store").help(
+            "Check persisted sessions, client history and retained messages for corrupted entries and exit",
+        ))
+        .arg(
+            Arg::new("QUARANTINE")
+                .long("quarantine")
+                .requires("FSCK_STATE_STORE")
+                .help("Move corrupted entries found by --fsck-state-store to <file>.quarantine"),
+        )
         .arg(
             Arg::new("TCP_PORT")
                 .help("TCP port TeleMQ will start listening on.")
@@ -0,0 +1,136 @@
+use mqtt_packets::v_3_1_1::{
+    topic::{filter_contains, topics_match, Subscription, Topic},
+    QoS,
+};
+use plugin_types::authenticator::{TopicACL, TopicAccess};
+
+use crate::reserved_topics::is_reserved;
+
+/// Whether a client may publish to a topic. The first rule which filter matches the topic
+/// applies, a topic without a matching rule is denied. `None` means the client has no ACL
+/// and may publish anywhere but the reserved `$telemq/...` namespace.
+pub fn publish_allowed(topics_acl: Option<&[TopicACL]>, topic: &Topic) -> bool {
+    match topics_acl.map(|topics| {
+        topics
+            .iter()
+            .find(|r| topics_match(&topic.path, &r.topic.path))
+    }) {
+        Some(Some(topic_rule)) => match topic_rule.access {
+            TopicAccess::ReadWrite | TopicAccess::Write => true,
+            TopicAccess::Deny | TopicAccess::Read => false,
+        },
+        Some(None) => false,
+        None => !is_reserved(&topic.path),
+    }
+}
+
+/// Whether a client may subscribe to a topic filter, same as `publish_allowed` for reading.
+pub fn subscribe_allowed(topics_acl: Option<&[TopicACL]>, sub: &Subscription) -> bool {
+    match topics_acl.map(|topics| subscribe_rule(topics, sub)) {
+        Some(Some(topic_rule)) => match topic_rule.access {
+            TopicAccess::ReadWrite | TopicAccess::Read => true,
+            TopicAccess::Deny | TopicAccess::Write => false,
+        },
+        Some(None) => false,
+        None => !is_reserved(&sub.path),
+    }
+}
+
+/// QoS granted to an allowed subscription: requested QoS, downgraded to `max_qos` of the rule
+/// which allows it.
+pub fn granted_qos(topics_acl: Option<&[TopicACL]>, sub: &Subscription, qos: &QoS) -> QoS {
+    let max_qos = topics_acl
+        .and_then(|topics| subscribe_rule(topics, sub))
+        .and_then(|topic_rule| topic_rule.max_qos)
+        .and_then(|max_qos| QoS::try_from(max_qos).ok());
+    match max_qos {
+        Some(max_qos) if max_qos < *qos => max_qos,
+        _ => qos.clone(),
+    }
+}
+
+fn subscribe_rule<'a>(topics: &'a [TopicACL], sub: &Subscription) -> Option<&'a TopicACL> {
+    topics
+        .iter()
+        // a rule applies only if its filter grants every topic of
+        // a requested filter, e.g. `a/#` is not granted by `a/b`
+        .find(|r| filter_contains(&r.topic.path, &sub.path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(topic: &str, access: TopicAccess) -> TopicACL {
+        TopicACL {
+            topic: Topic::make_from_string(topic),
+            access,
+            max_qos: None,
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let acl = vec![
+            rule("devices/1/config", TopicAccess::Read),
+            rule("devices/#", TopicAccess::ReadWrite),
+        ];
+
+        let topic = |t: &str| Topic::make_from_string(t);
+        assert!(!publish_allowed(Some(&acl), &topic("devices/1/config")));
+        assert!(publish_allowed(Some(&acl), &topic("devices/1/state")));
+        assert!(!publish_allowed(Some(&acl), &topic("other")));
+        assert!(publish_allowed(None, &topic("other")));
+    }
+
+    #[test]
+    fn subscription_has_to_be_granted_entirely() {
+        let acl = vec![
+            rule("devices/+/state", TopicAccess::Read),
+            rule("commands/#", TopicAccess::Write),
+        ];
+
+        let sub = |s: &str| Subscription::try_from(s).unwrap();
+        assert!(subscribe_allowed(Some(&acl), &sub("devices/1/state")));
+        assert!(!subscribe_allowed(Some(&acl), &sub("devices/#")));
+        assert!(!subscribe_allowed(Some(&acl), &sub("commands/1")));
+        assert!(subscribe_allowed(None, &sub("#")));
+    }
+
+    #[test]
+    fn granted_qos_is_downgraded_to_max_qos_of_rule() {
+        let acl = vec![
+            TopicACL {
+                max_qos: Some(1),
+                ..rule("devices/+/state", TopicAccess::Read)
+            },
+            rule("devices/#", TopicAccess::Read),
+        ];
+
+        let sub = |s: &str| Subscription::try_from(s).unwrap();
+        let granted = |s: &str, qos: QoS| granted_qos(Some(&acl), &sub(s), &qos);
+        assert_eq!(granted("devices/1/state", QoS::Two), QoS::One);
+        assert_eq!(granted("devices/1/state", QoS::Zero), QoS::Zero);
+        assert_eq!(granted("devices/1/config", QoS::Two), QoS::Two);
+        assert_eq!(
+            granted_qos(None, &sub("devices/1/state"), &QoS::Two),
+            QoS::Two
+        );
+    }
+
+    #[test]
+    fn reserved_namespace_has_to_be_granted_explicitly() {
+        let topic = |t: &str| Topic::make_from_string(t);
+        let sub = |s: &str| Subscription::try_from(s).unwrap();
+        assert!(!publish_allowed(None, &topic("$telemq/delayed/10/a")));
+        assert!(!subscribe_allowed(None, &sub("$telemq/#")));
+
+        let acl = vec![rule("#", TopicAccess::ReadWrite)];
+        assert!(!publish_allowed(Some(&acl), &topic("$telemq/delayed/10/a")));
+        assert!(!subscribe_allowed(Some(&acl), &sub("$telemq/#")));
+
+        let acl = vec![rule("$telemq/#", TopicAccess::ReadWrite)];
+        assert!(publish_allowed(Some(&acl), &topic("$telemq/delayed/10/a")));
+        assert!(subscribe_allowed(Some(&acl), &sub("$telemq/#")));
+    }
+}
@@ -1,14 +1,21 @@
-use log::info;
+use authenticator_http::{HttpAuthenticator, HttpAuthenticatorConfig};
+use log::{info, warn};
 use std::net::SocketAddr;
 
+use mqtt_packets::v_3_1_1::topic::Topic;
 use plugin_types::authenticator::{
-    AuthenticatorResult, LoginRequest, LoginResponse, TopicACL, TopicAccess,
+    AuthenticatorError, AuthenticatorResult, LoginRequest, LoginResponse, TopicACL, TopicAccess,
 };
 
-use super::{authenticator_error::AuthenticatorInitResult, authenticator_file::AuthenticatorFile};
-use crate::config::TeleMQServerConfig;
+use super::{
+    authenticator_error::{AuthenticatorInitError, AuthenticatorInitResult},
+    authenticator_file::AuthenticatorFile,
+    authenticator_jwt::AuthenticatorJwt,
+};
+use crate::config::{AuthUnreachablePolicy, TeleMQServerConfig};
+use crate::logger::TARGET_AUTH;
 
-pub use super::authenticator_file::{AccessType, ClientCredentials, ClientRules};
+pub use super::authenticator_file::{AccessType, ClientCredentials, ClientRules, LoginOutcome};
 
 impl From<&AccessType> for TopicAccess {
     fn from(ta: &AccessType) -> TopicAccess {
@@ -21,29 +28,103 @@ impl From<&AccessType> for TopicAccess {
     }
 }
 
+/// A client without rules in an auth file is not allowed to publish or subscribe. Rules of
+/// the client apply before pattern rules, rules which don't apply to the client are skipped.
+fn file_topics_acl(
+    auth_file: &AuthenticatorFile,
+    client_id: &String,
+    username: Option<&str>,
+) -> Vec<TopicACL> {
+    let client_rules = auth_file
+        .get_topics_acl(client_id)
+        .map(|r| r.topic_rules.as_slice())
+        .unwrap_or_default();
+    client_rules
+        .iter()
+        .chain(auth_file.get_pattern_rules())
+        .filter_map(|r| {
+            let topic = if r.topic.original.contains('%') {
+                Topic::make_from_string(AuthenticatorFile::substitute(
+                    &r.topic.original,
+                    client_id,
+                    username,
+                )?)
+            } else {
+                r.topic.clone()
+            };
+            Some(TopicACL {
+                topic,
+                access: r
+                    .access
+                    .as_ref()
+                    .map(|x| TopicAccess::from(x))
+                    .unwrap_or_else(|| TopicAccess::ReadWrite),
+                max_qos: r.max_qos,
+            })
+        })
+        .collect()
+}
+
 pub struct Authenticator {
     anonymous_allowed: bool,
     max_packet_size: Option<usize>,
     auth_file: Option<AuthenticatorFile>,
-    auth_server: Option<String>,
+    auth_jwt: Option<AuthenticatorJwt>,
+    auth_server: Option<HttpAuthenticator>,
+    /// Candidate topic rules evaluated alongside the active ones, but never enforced.
+    shadow_file: Option<AuthenticatorFile>,
+    unreachable_policy: AuthUnreachablePolicy,
+    unreachable_topics: Vec<String>,
 }
 
 impl Authenticator {
     pub fn new(config: &TeleMQServerConfig) -> AuthenticatorInitResult<Self> {
-        info!("[Authenticator]: Initializing with config\n{:?}", config);
+        info!(target: TARGET_AUTH, "[Authenticator]: Initializing with config\n{:?}", config);
         let mut this = Authenticator {
             anonymous_allowed: config.anonymous_allowed,
             max_packet_size: config.max_packet_size.clone(),
             auth_file: None,
-            auth_server: config.auth_endpoint.clone(),
+            auth_jwt: None,
+            auth_server: None,
+            shadow_file: None,
+            unreachable_policy: config.auth_unreachable_policy,
+            unreachable_topics: config.auth_unreachable_topics.clone(),
         };
 
+        if let Some(ref auth_endpoint) = config.auth_endpoint {
+            info!(target: TARGET_AUTH, "Initializing Authenticator HTTP");
+            let auth_server = HttpAuthenticator::new(
+                auth_endpoint.clone(),
+                HttpAuthenticatorConfig {
+                    timeout: config.auth_endpoint_timeout,
+                    max_retries: config.auth_endpoint_max_retries,
+                    cache_ttl: config.auth_endpoint_cache_ttl,
+                    negative_cache_ttl: config.auth_endpoint_negative_cache_ttl,
+                    keep_known_clients: config.auth_unreachable_policy
+                        == AuthUnreachablePolicy::AllowCachedKnownClients,
+                },
+            )
+            .map_err(|err| AuthenticatorInitError::Server(format!("[Authenticator] {:?}", err)))?;
+            this.auth_server = Some(auth_server);
+        }
+
         if let Some(ref auth_file_path) = config.auth_file {
-            info!("Initializing Authenticator File");
+            info!(target: TARGET_AUTH, "Initializing Authenticator File");
             let file = AuthenticatorFile::new(auth_file_path, config.anonymous_allowed)?;
             this.auth_file = Some(file);
         }
 
+        if let Some(ref jwt_config) = config.auth_jwt {
+            info!(target: TARGET_AUTH, "Initializing Authenticator JWT");
+            this.auth_jwt = Some(AuthenticatorJwt::new(jwt_config)?);
+        }
+
+        if let Some(ref shadow_file_path) = config.auth_file_shadow {
+            info!(target: TARGET_AUTH, "Initializing shadow ACL of Authenticator File");
+            let file = AuthenticatorFile::new(shadow_file_path, config.anonymous_allowed)?;
+            this.shadow_file = Some(file);
+        }
+
         Ok(this)
     }
 
@@ -53,27 +134,46 @@ impl Authenticator {
         client_id: String,
         username: Option<String>,
         password: Option<String>,
+        tls_fingerprint: Option<String>,
     ) -> AuthenticatorResult<LoginResponse> {
-        let connection_allowed = match self.auth_file {
-            Some(ref auth_file) => auth_file.login(socket_addr, &client_id, username, password),
+        if let Some(ref auth_jwt) = self.auth_jwt {
+            let topics_acl = auth_jwt.login(&client_id, password);
+            return Ok(LoginResponse {
+                connection_allowed: topics_acl.is_some(),
+                client_id_rejected: false,
+                topics_acl,
+                max_packet_size: self.max_packet_size,
+            });
+        }
+
+        let outcome = match self.auth_file {
+            Some(ref auth_file) => {
+                auth_file.login(socket_addr, &client_id, username.clone(), password)
+            }
             None => match self.auth_server {
-                Some(ref addr) => {
+                Some(ref auth_server) => {
                     let req = LoginRequest {
                         socket_addr: &format!("{}", socket_addr),
                         client_id: &client_id,
                         username: &username,
                         password: &password,
+                        tls_fingerprint: &tls_fingerprint,
+                    };
+                    return match auth_server.connect(req.clone()).await {
+                        Err(AuthenticatorError) => self.on_unreachable(auth_server, &req),
+                        response => response,
                     };
-                    return authenticator_http::connect(addr, req).await;
                 }
 
-                None => self.anonymous_allowed,
+                None if self.anonymous_allowed => LoginOutcome::Allowed,
+                None => LoginOutcome::Denied,
             },
         };
 
-        if !connection_allowed {
+        if outcome != LoginOutcome::Allowed {
             return Ok(LoginResponse {
                 connection_allowed: false,
+                client_id_rejected: outcome == LoginOutcome::ClientIdRejected,
                 topics_acl: None,
                 max_packet_size: self.max_packet_size.clone(),
             });
@@ -81,30 +181,66 @@ impl Authenticator {
 
         Ok(LoginResponse {
             connection_allowed: true,
-            topics_acl: self.auth_file.as_ref().map(|ref auth_file| {
-                let client_rules = match auth_file.get_topics_acl(&client_id) {
-                    Some(r) => r,
-                    None => {
-                        return vec![];
-                    }
-                };
-                client_rules
-                    .topic_rules
-                    .iter()
-                    .map(|r| TopicACL {
-                        topic: r.topic.clone(),
-                        access: r
-                            .access
-                            .as_ref()
-                            .map(|x| TopicAccess::from(x))
-                            .unwrap_or_else(|| TopicAccess::ReadWrite),
-                    })
-                    .collect()
-            }),
+            client_id_rejected: false,
+            topics_acl: self
+                .auth_file
+                .as_ref()
+                .map(|auth_file| file_topics_acl(auth_file, &client_id, username.as_deref())),
             max_packet_size: self.max_packet_size.clone(),
         })
     }
 
+    /// Degraded authentication by `auth_unreachable_policy` while `auth_endpoint` is down.
+    fn on_unreachable(
+        &self,
+        auth_server: &HttpAuthenticator,
+        req: &LoginRequest,
+    ) -> AuthenticatorResult<LoginResponse> {
+        let response = match self.unreachable_policy {
+            AuthUnreachablePolicy::Deny => None,
+            AuthUnreachablePolicy::AllowAnonymousTopicsOnly => Some(LoginResponse {
+                connection_allowed: true,
+                client_id_rejected: false,
+                topics_acl: Some(
+                    self.unreachable_topics
+                        .iter()
+                        .map(|topic| TopicACL {
+                            topic: Topic::make_from_string(
+                                topic.replace(AuthenticatorFile::CLIENT_ID_PATTERN, req.client_id),
+                            ),
+                            access: TopicAccess::ReadWrite,
+                            max_qos: None,
+                        })
+                        .collect(),
+                ),
+                max_packet_size: self.max_packet_size,
+            }),
+            AuthUnreachablePolicy::AllowCachedKnownClients => auth_server.known_client(req),
+        };
+
+        match response {
+            Some(response) => {
+                warn!(target: TARGET_AUTH,
+                    "[Authenticator]: Authentication Endpoint is unreachable. Client {:?} is accepted by auth_unreachable_policy {:?}",
+                    req.client_id, self.unreachable_policy
+                );
+                Ok(response)
+            }
+            None => Err(AuthenticatorError),
+        }
+    }
+
+    /// Topic rules of a client in `auth_file_shadow`, `None` if there is no shadow ACL.
+    pub fn shadow_topics_acl(
+        &self,
+        client_id: &String,
+        username: Option<&str>,
+    ) -> Option<Vec<TopicACL>> {
+        self.shadow_file
+            .as_ref()
+            .map(|shadow_file| file_topics_acl(shadow_file, client_id, username))
+    }
+
     #[allow(dead_code)]
     pub async fn register_device(
         &mut self,
@@ -123,3 +259,96 @@ impl Authenticator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An endpoint nothing listens on, so requests fail right away.
+    fn unreachable_config(policy: AuthUnreachablePolicy) -> TeleMQServerConfig {
+        TeleMQServerConfig {
+            anonymous_allowed: false,
+            auth_endpoint: Some("http://127.0.0.1:1/login".into()),
+            auth_endpoint_max_retries: 0,
+            auth_unreachable_policy: policy,
+            ..TeleMQServerConfig::default()
+        }
+    }
+
+    async fn connect(authenticator: &Authenticator) -> AuthenticatorResult<LoginResponse> {
+        authenticator
+            .connect(
+                "127.0.0.1:5000".parse().unwrap(),
+                "device-1".into(),
+                Some("user".into()),
+                Some("secret".into()),
+                None,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn unreachable_endpoint_is_handled_by_policy() {
+        let config = unreachable_config(AuthUnreachablePolicy::Deny);
+        let authenticator = Authenticator::new(&config).unwrap();
+        assert!(connect(&authenticator).await.is_err());
+
+        let config = unreachable_config(AuthUnreachablePolicy::AllowCachedKnownClients);
+        let authenticator = Authenticator::new(&config).unwrap();
+        assert!(connect(&authenticator).await.is_err());
+
+        let mut config = unreachable_config(AuthUnreachablePolicy::AllowAnonymousTopicsOnly);
+        config.auth_unreachable_topics = vec!["devices/{client_id}/#".into()];
+        let authenticator = Authenticator::new(&config).unwrap();
+        let response = connect(&authenticator).await.unwrap();
+        assert!(response.connection_allowed);
+        let topics_acl = response.topics_acl.unwrap();
+        assert_eq!(topics_acl.len(), 1);
+        assert_eq!(topics_acl[0].topic.original, "devices/device-1/#");
+    }
+
+    #[test]
+    fn pattern_rules_follow_rules_of_client() {
+        let path = std::env::temp_dir().join(format!(
+            "telemq_auth_file_patterns_{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r##"
+            topic_client_rules = [
+              {client_id = "ADMIN", topic_rules = [{access = "ReadWrite", topic = "#"}]},
+              {client_id = "DEVICE_1", topic_rules = [{access = "Deny", topic = "devices/%c/config"}]},
+            ]
+            topic_pattern_rules = [
+              {access = "ReadWrite", topic = "devices/%c/#"},
+              {access = "Read", topic = "users/%u/#"},
+            ]
+            "##,
+        )
+        .unwrap();
+        let auth_file = AuthenticatorFile::new(&path, true).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let rules = |client_id: &str, username: Option<&str>| {
+            file_topics_acl(&auth_file, &client_id.to_string(), username)
+                .into_iter()
+                .map(|rule| (rule.topic.original, format!("{:?}", rule.access)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            rules("DEVICE_1", Some("alice")),
+            vec![
+                ("devices/DEVICE_1/config".to_string(), "Deny".to_string()),
+                ("devices/DEVICE_1/#".to_string(), "ReadWrite".to_string()),
+                ("users/alice/#".to_string(), "Read".to_string()),
+            ]
+        );
+        assert_eq!(
+            rules("DEVICE_2", None),
+            vec![("devices/DEVICE_2/#".to_string(), "ReadWrite".to_string())]
+        );
+        assert_eq!(rules("ADMIN", Some("root"))[0].0, "#");
+        assert!(rules("+", Some("#")).is_empty());
+    }
+}
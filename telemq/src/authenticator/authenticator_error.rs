@@ -7,6 +7,7 @@ use toml::de::Error as TomlError;
 pub enum AuthenticatorInitError {
     AuthFile(String),
     Server(String),
+    Jwt(String),
 }
 
 impl From<IoError> for AuthenticatorInitError {
@@ -8,6 +8,7 @@ use serde::{Deserialize, Serialize};
 use toml::from_str;
 
 use super::authenticator_error::*;
+use crate::logger::TARGET_AUTH;
 
 #[derive(Debug)]
 pub struct AuthenticatorFile {
@@ -15,16 +16,47 @@ pub struct AuthenticatorFile {
     #[allow(unused)]
     topic_all_rules: Option<Vec<TopicRule>>,
     topic_client_rules: Option<Vec<ClientRules>>,
+    /// Rules of every client, which topics are substituted once a client connects.
+    topic_pattern_rules: Vec<TopicRule>,
     credentials: Option<Vec<ClientCredentials>>,
     ip_whitelist: Option<Vec<IpNet>>,
     ip_blacklist: Option<Vec<IpNet>>,
 }
 
 impl AuthenticatorFile {
-    const CLIENT_ID_PATTERN: &'static str = "{client_id}";
+    pub(crate) const CLIENT_ID_PATTERN: &'static str = "{client_id}";
+    const CLIENT_ID_SUBSTITUTION: &'static str = "%c";
+    const USERNAME_SUBSTITUTION: &'static str = "%u";
 
     pub fn new<P: AsRef<Path>>(file: P, anonymous_allowed: bool) -> AuthenticatorInitResult<Self> {
         let src = AuthenticatorFileSrc::try_from_file(file)?;
+        for credentials in src.credentials.iter().flatten() {
+            if !credentials.is_bound() {
+                return Err(AuthenticatorInitError::AuthFile(format!(
+                    "[Authenticator File] credentials of {} should have a client_id, a client_id_prefix or client_id_equals_username",
+                    credentials.username
+                )));
+            }
+        }
+        let rules = src
+            .topic_all_rules
+            .iter()
+            .chain(src.topic_pattern_rules.iter())
+            .flatten()
+            .chain(
+                src.topic_client_rules
+                    .iter()
+                    .flatten()
+                    .flat_map(|c| &c.topic_rules),
+            );
+        for rule in rules {
+            if rule.max_qos.is_some_and(|max_qos| max_qos > 2) {
+                return Err(AuthenticatorInitError::AuthFile(format!(
+                    "[Authenticator File] max_qos of topic {:?} should be 0, 1 or 2",
+                    rule.topic
+                )));
+            }
+        }
         Ok(AuthenticatorFile {
             anonymous_allowed,
             topic_all_rules: match src.topic_all_rules {
@@ -33,6 +65,7 @@ impl AuthenticatorFile {
                     for rule in all_rules {
                         r.push(TopicRule {
                             access: rule.access,
+                            max_qos: rule.max_qos,
                             topic: Topic::make_from_string(&rule.topic),
                         });
                     }
@@ -50,6 +83,7 @@ impl AuthenticatorFile {
                         for rule in client.topic_rules {
                             topic_rules.push(TopicRule {
                                 access: rule.access,
+                                max_qos: rule.max_qos,
                                 topic: Topic::make_from_string(
                                     &rule
                                         .topic
@@ -66,6 +100,16 @@ impl AuthenticatorFile {
                 }
                 None => None,
             },
+            topic_pattern_rules: src
+                .topic_pattern_rules
+                .unwrap_or_default()
+                .into_iter()
+                .map(|rule| TopicRule {
+                    access: rule.access,
+                    max_qos: rule.max_qos,
+                    topic: Topic::make_from_string(&rule.topic),
+                })
+                .collect(),
             credentials: src.credentials,
             ip_whitelist: src
                 .ip_whitelist
@@ -79,7 +123,7 @@ impl AuthenticatorFile {
     #[allow(dead_code)]
     pub fn add_device(&mut self, mut credentials: ClientCredentials, client_topics: ClientRules) {
         if let Some(ref mut all_clients_topic_rules) = self.topic_client_rules {
-            all_clients_topic_rules.retain(|t| t.client_id != credentials.client_id);
+            all_clients_topic_rules.retain(|t| t.client_id != client_topics.client_id);
             all_clients_topic_rules.push(client_topics);
         } else {
             let mut all_clients_topic_rules = vec![];
@@ -106,19 +150,17 @@ impl AuthenticatorFile {
         }
 
         if let Some(ref mut all_credentials) = self.credentials {
-            all_credentials.retain(|c| c.client_id != client_id);
+            all_credentials.retain(|c| c.client_id.as_ref() != Some(&client_id));
         }
     }
 
-    // false - not authorized to log in
-    // true - authorized to log in
     pub fn login(
         &self,
         socket_addr: SocketAddr,
         client_id: &String,
         maybe_username: Option<String>,
         maybe_password: Option<String>,
-    ) -> bool {
+    ) -> LoginOutcome {
         let ip_net_addr = IpNet::from(socket_addr.ip());
         let blacklisted = self
             .ip_blacklist
@@ -131,11 +173,11 @@ impl AuthenticatorFile {
             .unwrap_or(false);
 
         if blacklisted {
-            error!(
+            error!(target: TARGET_AUTH,
                 "[Authenticator File] IP blacklisted. Client ID {}, IP {:?}",
                 client_id, socket_addr
             );
-            return false;
+            return LoginOutcome::Denied;
         }
 
         let whitelisted = match &self.ip_whitelist {
@@ -147,11 +189,11 @@ impl AuthenticatorFile {
         };
 
         if !whitelisted {
-            error!(
+            error!(target: TARGET_AUTH,
                 "IP is not whitelisted. Client ID {}, IP {:?}",
                 client_id, socket_addr
             );
-            return false;
+            return LoginOutcome::Denied;
         }
 
         match self.credentials {
@@ -162,20 +204,32 @@ impl AuthenticatorFile {
                         // credentials list is provided in the file,
                         // which means no anonymous clients are allowed and
                         // username and password should be provided
-                        return false;
+                        return LoginOutcome::Denied;
                     }
                 };
                 let password_hash = Self::get_hash_password(password.as_str());
-                return credentials_list
+                let mut matching = credentials_list
                     .iter()
-                    .find(|credentials_entry| {
-                        &credentials_entry.client_id == client_id
-                            && credentials_entry.username == username
+                    .filter(|credentials_entry| {
+                        credentials_entry.username == username
                             && password_hash == credentials_entry.password
                     })
-                    .is_some();
+                    .peekable();
+                if matching.peek().is_none() {
+                    return LoginOutcome::Denied;
+                }
+                if matching.any(|credentials_entry| credentials_entry.accepts(client_id)) {
+                    LoginOutcome::Allowed
+                } else {
+                    error!(target: TARGET_AUTH,
+                        "[Authenticator File] Client ID {} is not bound to username {}",
+                        client_id, username
+                    );
+                    LoginOutcome::ClientIdRejected
+                }
             }
-            None => return self.anonymous_allowed,
+            None if self.anonymous_allowed => LoginOutcome::Allowed,
+            None => LoginOutcome::Denied,
         }
     }
 
@@ -186,6 +240,41 @@ impl AuthenticatorFile {
         }
     }
 
+    /// Rules of `topic_pattern_rules`, they apply to every client after its own rules.
+    pub fn get_pattern_rules(&self) -> &[TopicRule] {
+        &self.topic_pattern_rules
+    }
+
+    /// Substitutes `%c` (and `{client_id}`) with a client id and `%u` with a username. `None`
+    /// if the rule doesn't apply to a client: it has no username, or a substituted value
+    /// contains `/`, `+` or `#`, which would widen the rule to topics of other clients.
+    pub fn substitute(topic: &str, client_id: &str, username: Option<&str>) -> Option<String> {
+        let is_level = |value: &str| !value.contains(['/', '+', '#']);
+        let mut topic = topic.to_string();
+        if Self::has_client_id(&topic) {
+            if !is_level(client_id) {
+                return None;
+            }
+            topic = topic
+                .replace(Self::CLIENT_ID_SUBSTITUTION, client_id)
+                .replace(Self::CLIENT_ID_PATTERN, client_id);
+        }
+        if topic.contains(Self::USERNAME_SUBSTITUTION) {
+            match username {
+                Some(username) if is_level(username) => {
+                    topic = topic.replace(Self::USERNAME_SUBSTITUTION, username)
+                }
+                _ => return None,
+            }
+        }
+        Some(topic)
+    }
+
+    /// Whether a topic has `%c` (or `{client_id}`), so it's a topic of a single client.
+    pub fn has_client_id(topic: &str) -> bool {
+        topic.contains(Self::CLIENT_ID_SUBSTITUTION) || topic.contains(Self::CLIENT_ID_PATTERN)
+    }
+
     fn get_hash_password(raw_password: &str) -> String {
         let mut hasher = Sha256::new();
         hasher.input_str(raw_password);
@@ -197,6 +286,7 @@ impl AuthenticatorFile {
 pub struct AuthenticatorFileSrc {
     topic_all_rules: Option<Vec<TopicRuleSrc>>,
     topic_client_rules: Option<Vec<ClientRulesSrc>>,
+    topic_pattern_rules: Option<Vec<TopicRuleSrc>>,
     credentials: Option<Vec<ClientCredentials>>,
     ip_whitelist: Option<Vec<String>>,
     ip_blacklist: Option<Vec<String>>,
@@ -214,6 +304,8 @@ impl AuthenticatorFileSrc {
 pub struct TopicRuleSrc {
     pub access: Option<AccessType>,
     pub topic: String,
+    /// Max QoS granted to subscriptions allowed by the rule.
+    pub max_qos: Option<u8>,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -230,17 +322,50 @@ pub struct ClientRulesSrc {
     pub topic_rules: Vec<TopicRuleSrc>,
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoginOutcome {
+    Allowed,
+    /// wrong credentials or a client IP is not allowed
+    Denied,
+    /// credentials are valid, but they are not bound to a client id
+    ClientIdRejected,
+}
+
+/// Credentials of a client or a group of clients. A client id is bound to credentials by
+/// `client_id`, `client_id_prefix` and `client_id_equals_username`, all of which have
+/// to match if set, so a device can't impersonate another one by using its client id.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ClientCredentials {
-    client_id: String,
+    client_id: Option<String>,
+    client_id_prefix: Option<String>,
+    #[serde(default)]
+    client_id_equals_username: bool,
     username: String,
     password: String,
 }
 
+impl ClientCredentials {
+    fn is_bound(&self) -> bool {
+        self.client_id.is_some()
+            || self.client_id_prefix.is_some()
+            || self.client_id_equals_username
+    }
+
+    fn accepts(&self, client_id: &str) -> bool {
+        self.client_id.as_ref().is_none_or(|id| id == client_id)
+            && self
+                .client_id_prefix
+                .as_ref()
+                .is_none_or(|prefix| client_id.starts_with(prefix.as_str()))
+            && (!self.client_id_equals_username || self.username == client_id)
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TopicRule {
     pub access: Option<AccessType>,
     pub topic: Topic,
+    pub max_qos: Option<u8>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -248,3 +373,99 @@ pub struct ClientRules {
     pub client_id: String,
     pub topic_rules: Vec<TopicRule>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(
+        client_id: Option<&str>,
+        client_id_prefix: Option<&str>,
+        client_id_equals_username: bool,
+    ) -> ClientCredentials {
+        ClientCredentials {
+            client_id: client_id.map(String::from),
+            client_id_prefix: client_id_prefix.map(String::from),
+            client_id_equals_username,
+            username: "fleet".into(),
+            password: AuthenticatorFile::get_hash_password("secret"),
+        }
+    }
+
+    fn authenticator(credentials: Vec<ClientCredentials>) -> AuthenticatorFile {
+        AuthenticatorFile {
+            anonymous_allowed: false,
+            topic_all_rules: None,
+            topic_client_rules: None,
+            topic_pattern_rules: vec![],
+            credentials: Some(credentials),
+            ip_whitelist: None,
+            ip_blacklist: None,
+        }
+    }
+
+    fn login(authenticator: &AuthenticatorFile, client_id: &str, password: &str) -> LoginOutcome {
+        authenticator.login(
+            "127.0.0.1:40000".parse().unwrap(),
+            &client_id.to_string(),
+            Some("fleet".into()),
+            Some(password.into()),
+        )
+    }
+
+    #[test]
+    fn client_id_is_bound_to_credentials() {
+        assert!(credentials(Some("DEVICE1"), None, false).accepts("DEVICE1"));
+        assert!(!credentials(Some("DEVICE1"), None, false).accepts("DEVICE2"));
+        assert!(credentials(None, Some("SITE1"), false).accepts("SITE1DEVICE7"));
+        assert!(!credentials(None, Some("SITE1"), false).accepts("SITE2DEVICE7"));
+        assert!(credentials(None, None, true).accepts("fleet"));
+        assert!(!credentials(None, None, true).accepts("DEVICE1"));
+        assert!(!credentials(None, None, false).is_bound());
+    }
+
+    #[test]
+    fn foreign_client_id_is_rejected() {
+        let authenticator = authenticator(vec![
+            credentials(None, Some("SITE1"), false),
+            credentials(Some("GATEWAY"), None, false),
+        ]);
+
+        assert_eq!(
+            login(&authenticator, "SITE1DEVICE7", "secret"),
+            LoginOutcome::Allowed
+        );
+        assert_eq!(
+            login(&authenticator, "GATEWAY", "secret"),
+            LoginOutcome::Allowed
+        );
+        assert_eq!(
+            login(&authenticator, "SITE2DEVICE7", "secret"),
+            LoginOutcome::ClientIdRejected
+        );
+        assert_eq!(
+            login(&authenticator, "SITE1DEVICE7", "wrong"),
+            LoginOutcome::Denied
+        );
+    }
+
+    #[test]
+    fn client_id_and_username_are_substituted() {
+        let substitute = AuthenticatorFile::substitute;
+        assert_eq!(
+            substitute("devices/%c/%u/#", "DEVICE1", Some("fleet")),
+            Some("devices/DEVICE1/fleet/#".into())
+        );
+        assert_eq!(
+            substitute("devices/{client_id}/state", "DEVICE1", None),
+            Some("devices/DEVICE1/state".into())
+        );
+        assert_eq!(
+            substitute("public/#", "DEVICE1", None),
+            Some("public/#".into())
+        );
+        assert_eq!(substitute("users/%u/#", "DEVICE1", None), None);
+        assert_eq!(substitute("devices/%c/#", "DEVICE1/+", None), None);
+        assert_eq!(substitute("users/%u/#", "DEVICE1", Some("#")), None);
+    }
+}
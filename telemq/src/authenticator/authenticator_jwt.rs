@@ -0,0 +1,491 @@
+use std::{fs::read_to_string as read_file, time::SystemTime};
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use log::warn;
+use mqtt_packets::v_3_1_1::topic::Topic;
+use plugin_types::authenticator::{TopicACL, TopicAccess};
+use ring::{hmac, signature};
+use serde_json::{Map, Value};
+
+use super::{authenticator_error::*, authenticator_file::TopicRuleSrc};
+use crate::config::{JwtAlgorithm, JwtAuthConfig};
+use crate::logger::TARGET_AUTH;
+
+/// Authenticates clients by a JWT provided as an MQTT password. A token should be signed
+/// with a configured algorithm and key, its client id claim should be equal to a client id
+/// of a connection, and topic rules are taken from its ACL claim.
+pub struct AuthenticatorJwt {
+    algorithm: JwtAlgorithm,
+    key: JwtKey,
+    client_id_claim: String,
+    acl_claim: String,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+enum JwtKey {
+    Hmac(hmac::Key),
+    /// DER `RSAPublicKey` for RSA, an uncompressed point for ECDSA.
+    Public(Vec<u8>),
+}
+
+type Claims = Map<String, Value>;
+
+impl AuthenticatorJwt {
+    const CLIENT_ID_PATTERN: &'static str = "{client_id}";
+    const DEFAULT_CLIENT_ID_CLAIM: &'static str = "sub";
+    const DEFAULT_ACL_CLAIM: &'static str = "acl";
+
+    pub fn new(config: &JwtAuthConfig) -> AuthenticatorInitResult<Self> {
+        let key = match (&config.secret, &config.public_key_file) {
+            (Some(secret), _) if config.algorithm.is_hmac() => JwtKey::Hmac(hmac::Key::new(
+                hmac_algorithm(config.algorithm),
+                secret.as_bytes(),
+            )),
+            (_, Some(public_key_file)) if !config.algorithm.is_hmac() => {
+                let pem = read_file(public_key_file)
+                    .map_err(|err| AuthenticatorInitError::Jwt(format!("{:?}", err)))?;
+                JwtKey::Public(parse_public_key(&pem).map_err(AuthenticatorInitError::Jwt)?)
+            }
+            _ => {
+                return Err(AuthenticatorInitError::Jwt(format!(
+                    "no key for {:?}",
+                    config.algorithm
+                )));
+            }
+        };
+
+        Ok(AuthenticatorJwt {
+            algorithm: config.algorithm,
+            key,
+            client_id_claim: config
+                .client_id_claim
+                .clone()
+                .unwrap_or_else(|| Self::DEFAULT_CLIENT_ID_CLAIM.into()),
+            acl_claim: config
+                .acl_claim
+                .clone()
+                .unwrap_or_else(|| Self::DEFAULT_ACL_CLAIM.into()),
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+        })
+    }
+
+    /// Returns topic rules of a client if its token is valid, `None` otherwise.
+    pub fn login(&self, client_id: &str, maybe_password: Option<String>) -> Option<Vec<TopicACL>> {
+        let token = maybe_password?;
+        match self.verify(&token, client_id, unix_now()) {
+            Ok(topics_acl) => Some(topics_acl),
+            Err(err) => {
+                warn!(target: TARGET_AUTH,
+                    "[Authenticator JWT] Token is rejected. Client ID {}. {}",
+                    client_id, err
+                );
+                None
+            }
+        }
+    }
+
+    fn verify(&self, token: &str, client_id: &str, now: u64) -> Result<Vec<TopicACL>, String> {
+        let (signing_input, signature) = token
+            .rsplit_once('.')
+            .ok_or("a token should consist of 3 parts")?;
+        let (header, payload) = match signing_input.split_once('.') {
+            Some((header, payload)) if !payload.contains('.') => (header, payload),
+            _ => return Err("a token should consist of 3 parts".into()),
+        };
+
+        // `alg` is checked to not let a token choose how it's verified (e.g. "none")
+        let header = decode_json(header)?;
+        if header.get("alg").and_then(Value::as_str) != Some(algorithm_name(self.algorithm)) {
+            return Err(format!("alg should be {:?}", self.algorithm));
+        }
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|err| format!("invalid signature encoding. {}", err))?;
+        self.verify_signature(signing_input.as_bytes(), &signature)?;
+
+        let claims = decode_json(payload)?;
+        self.verify_claims(&claims, client_id, now)?;
+
+        self.topics_acl(&claims, client_id)
+    }
+
+    fn verify_signature(&self, message: &[u8], signature: &[u8]) -> Result<(), String> {
+        let verified = match &self.key {
+            JwtKey::Hmac(key) => hmac::verify(key, message, signature).is_ok(),
+            JwtKey::Public(public_key) => {
+                signature::UnparsedPublicKey::new(public_key_algorithm(self.algorithm), public_key)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+        };
+        if verified {
+            Ok(())
+        } else {
+            Err("invalid signature".into())
+        }
+    }
+
+    fn verify_claims(&self, claims: &Claims, client_id: &str, now: u64) -> Result<(), String> {
+        if let Some(exp) = claims.get("exp") {
+            if exp.as_u64().is_none_or(|exp| exp <= now) {
+                return Err("the token has expired".into());
+            }
+        }
+        if let Some(nbf) = claims.get("nbf") {
+            if nbf.as_u64().is_none_or(|nbf| nbf > now) {
+                return Err("the token is not valid yet".into());
+            }
+        }
+        if let Some(ref issuer) = self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err(format!("iss should be {:?}", issuer));
+            }
+        }
+        if let Some(ref audience) = self.audience {
+            // `aud` is either a string or an array of strings
+            let audience_matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !audience_matches {
+                return Err(format!("aud should contain {:?}", audience));
+            }
+        }
+        if claims.get(&self.client_id_claim).and_then(Value::as_str) != Some(client_id) {
+            return Err(format!(
+                "{} claim should be equal to the client id",
+                self.client_id_claim
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// A token without an ACL claim grants no topics, the same as a client without rules in
+    /// an auth file.
+    fn topics_acl(&self, claims: &Claims, client_id: &str) -> Result<Vec<TopicACL>, String> {
+        let rules: Vec<TopicRuleSrc> = match claims.get(&self.acl_claim) {
+            Some(acl) => serde_json::from_value(acl.clone())
+                .map_err(|err| format!("invalid {} claim. {}", self.acl_claim, err))?,
+            None => vec![],
+        };
+
+        Ok(rules
+            .iter()
+            .map(|rule| TopicACL {
+                topic: Topic::make_from_string(
+                    rule.topic.replace(Self::CLIENT_ID_PATTERN, client_id),
+                ),
+                access: rule
+                    .access
+                    .as_ref()
+                    .map(TopicAccess::from)
+                    .unwrap_or(TopicAccess::ReadWrite),
+                max_qos: rule.max_qos,
+            })
+            .collect())
+    }
+}
+
+fn decode_json(part: &str) -> Result<Claims, String> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|err| format!("invalid encoding. {}", err))?;
+    serde_json::from_slice(&bytes).map_err(|err| format!("invalid JSON. {}", err))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0)
+}
+
+fn algorithm_name(algorithm: JwtAlgorithm) -> &'static str {
+    match algorithm {
+        JwtAlgorithm::HS256 => "HS256",
+        JwtAlgorithm::HS384 => "HS384",
+        JwtAlgorithm::HS512 => "HS512",
+        JwtAlgorithm::RS256 => "RS256",
+        JwtAlgorithm::RS384 => "RS384",
+        JwtAlgorithm::RS512 => "RS512",
+        JwtAlgorithm::ES256 => "ES256",
+        JwtAlgorithm::ES384 => "ES384",
+    }
+}
+
+fn hmac_algorithm(algorithm: JwtAlgorithm) -> hmac::Algorithm {
+    match algorithm {
+        JwtAlgorithm::HS384 => hmac::HMAC_SHA384,
+        JwtAlgorithm::HS512 => hmac::HMAC_SHA512,
+        _ => hmac::HMAC_SHA256,
+    }
+}
+
+fn public_key_algorithm(algorithm: JwtAlgorithm) -> &'static dyn signature::VerificationAlgorithm {
+    match algorithm {
+        JwtAlgorithm::RS384 => &signature::RSA_PKCS1_2048_8192_SHA384,
+        JwtAlgorithm::RS512 => &signature::RSA_PKCS1_2048_8192_SHA512,
+        JwtAlgorithm::ES256 => &signature::ECDSA_P256_SHA256_FIXED,
+        JwtAlgorithm::ES384 => &signature::ECDSA_P384_SHA384_FIXED,
+        _ => &signature::RSA_PKCS1_2048_8192_SHA256,
+    }
+}
+
+/// Parses a PEM public key, either `PUBLIC KEY` (SubjectPublicKeyInfo) or `RSA PUBLIC KEY`
+/// (PKCS#1), into a form ring verifies signatures with.
+fn parse_public_key(pem: &str) -> Result<Vec<u8>, String> {
+    let is_pkcs1 = pem.contains("BEGIN RSA PUBLIC KEY");
+    let base64: String = pem
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("-----"))
+        .collect();
+    let der = STANDARD
+        .decode(base64)
+        .map_err(|err| format!("invalid PEM public key. {}", err))?;
+    if is_pkcs1 {
+        return Ok(der);
+    }
+    subject_public_key(&der).ok_or_else(|| "invalid SubjectPublicKeyInfo".into())
+}
+
+/// Extracts the `subjectPublicKey` bit string from a DER SubjectPublicKeyInfo:
+/// `SEQUENCE { SEQUENCE { algorithm }, BIT STRING { key } }`.
+fn subject_public_key(der: &[u8]) -> Option<Vec<u8>> {
+    const SEQUENCE: u8 = 0x30;
+    const BIT_STRING: u8 = 0x03;
+
+    let (spki, _) = der_element(der, SEQUENCE)?;
+    let (_, rest) = der_element(spki, SEQUENCE)?;
+    let (key, _) = der_element(rest, BIT_STRING)?;
+    // the first byte is a number of unused bits, always 0 for keys
+    match key.split_first() {
+        Some((0, key)) => Some(key.to_vec()),
+        _ => None,
+    }
+}
+
+/// Returns contents of a DER element with a given tag and the rest of input after it.
+fn der_element(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&actual_tag, input) = input.split_first()?;
+    if actual_tag != tag {
+        return None;
+    }
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let len_bytes = (first & 0x7f) as usize;
+        if len_bytes == 0 || len_bytes > 4 || input.len() < len_bytes {
+            return None;
+        }
+        let len = input[..len_bytes]
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        input = &input[len_bytes..];
+        len
+    };
+    if input.len() < len {
+        return None;
+    }
+    Some(input.split_at(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+    };
+    use serde_json::json;
+
+    const NOW: u64 = 1700000000;
+    const SECRET: &str = "secret";
+
+    fn config(algorithm: JwtAlgorithm) -> JwtAuthConfig {
+        JwtAuthConfig {
+            algorithm,
+            secret: Some(SECRET.into()),
+            public_key_file: None,
+            client_id_claim: None,
+            acl_claim: None,
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    fn unsigned(alg: &str, claims: &Value) -> String {
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(json!({"alg": alg, "typ": "JWT"}).to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        )
+    }
+
+    fn hs256_token(claims: Value) -> String {
+        let signing_input = unsigned("HS256", &claims);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+        let signature = hmac::sign(&key, signing_input.as_bytes());
+        format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        )
+    }
+
+    fn rules(topics_acl: &[TopicACL]) -> Vec<(String, &'static str)> {
+        topics_acl
+            .iter()
+            .map(|acl| {
+                let access = match acl.access {
+                    TopicAccess::Read => "Read",
+                    TopicAccess::Write => "Write",
+                    TopicAccess::ReadWrite => "ReadWrite",
+                    TopicAccess::Deny => "Deny",
+                };
+                (acl.topic.original.clone(), access)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn valid_hmac_token_grants_topics_of_acl_claim() {
+        let authenticator = AuthenticatorJwt::new(&config(JwtAlgorithm::HS256)).unwrap();
+        let token = hs256_token(json!({
+            "sub": "DEVICE_1",
+            "exp": NOW + 60,
+            "acl": [
+                {"topic": "devices/{client_id}/#", "access": "ReadWrite"},
+                {"topic": "firmware/#", "access": "Read"},
+                {"topic": "broadcast"}
+            ]
+        }));
+
+        let topics_acl = authenticator.verify(&token, "DEVICE_1", NOW).unwrap();
+        assert_eq!(
+            rules(&topics_acl),
+            vec![
+                ("devices/DEVICE_1/#".to_string(), "ReadWrite"),
+                ("firmware/#".to_string(), "Read"),
+                ("broadcast".to_string(), "ReadWrite"),
+            ]
+        );
+    }
+
+    #[test]
+    fn token_without_acl_claim_grants_nothing() {
+        let authenticator = AuthenticatorJwt::new(&config(JwtAlgorithm::HS256)).unwrap();
+        let token = hs256_token(json!({"sub": "DEVICE_1"}));
+
+        assert!(authenticator
+            .verify(&token, "DEVICE_1", NOW)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn invalid_tokens_are_rejected() {
+        let authenticator = AuthenticatorJwt::new(&config(JwtAlgorithm::HS256)).unwrap();
+        let valid = hs256_token(json!({"sub": "DEVICE_1"}));
+        let cases = vec![
+            // another client
+            (valid.clone(), "DEVICE_2"),
+            // expired
+            (
+                hs256_token(json!({"sub": "DEVICE_1", "exp": NOW})),
+                "DEVICE_1",
+            ),
+            // not valid yet
+            (
+                hs256_token(json!({"sub": "DEVICE_1", "nbf": NOW + 1})),
+                "DEVICE_1",
+            ),
+            // tampered claims
+            (
+                format!(
+                    "{}.{}",
+                    unsigned("HS256", &json!({"sub": "DEVICE_2"})),
+                    valid.rsplit('.').next().unwrap()
+                ),
+                "DEVICE_2",
+            ),
+            // unsigned
+            (
+                format!("{}.", unsigned("none", &json!({"sub": "DEVICE_1"}))),
+                "DEVICE_1",
+            ),
+            ("not a token".to_string(), "DEVICE_1"),
+        ];
+
+        for (token, client_id) in cases {
+            assert!(
+                authenticator.verify(&token, client_id, NOW).is_err(),
+                "{} is accepted for {}",
+                token,
+                client_id
+            );
+        }
+    }
+
+    #[test]
+    fn issuer_and_audience_are_checked() {
+        let mut config = config(JwtAlgorithm::HS256);
+        config.issuer = Some("fleet".into());
+        config.audience = Some("telemq".into());
+        let authenticator = AuthenticatorJwt::new(&config).unwrap();
+
+        let token = hs256_token(json!({"sub": "D", "iss": "fleet", "aud": ["api", "telemq"]}));
+        assert!(authenticator.verify(&token, "D", NOW).is_ok());
+        let token = hs256_token(json!({"sub": "D", "iss": "fleet", "aud": "api"}));
+        assert!(authenticator.verify(&token, "D", NOW).is_err());
+        let token = hs256_token(json!({"sub": "D", "aud": "telemq"}));
+        assert!(authenticator.verify(&token, "D", NOW).is_err());
+    }
+
+    #[test]
+    fn es256_token_is_verified_with_a_pem_public_key() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap();
+        // SubjectPublicKeyInfo of a P-256 key is a fixed header followed by the point
+        let mut spki = vec![
+            0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06,
+            0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+        ];
+        spki.extend_from_slice(key_pair.public_key().as_ref());
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            STANDARD.encode(&spki)
+        );
+
+        let authenticator = AuthenticatorJwt {
+            algorithm: JwtAlgorithm::ES256,
+            key: JwtKey::Public(parse_public_key(&pem).unwrap()),
+            client_id_claim: "device".into(),
+            acl_claim: "acl".into(),
+            issuer: None,
+            audience: None,
+        };
+        let signing_input = unsigned("ES256", &json!({"device": "DEVICE_1"}));
+        let signature = key_pair.sign(&rng, signing_input.as_bytes()).unwrap();
+        let token = format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        );
+
+        assert!(authenticator.verify(&token, "DEVICE_1", NOW).is_ok());
+        let hs256 = hs256_token(json!({"device": "DEVICE_1"}));
+        assert!(authenticator.verify(&hs256, "DEVICE_1", NOW).is_err());
+    }
+}
@@ -1,5 +1,9 @@
+mod acl;
 mod authenticator;
 mod authenticator_error;
 mod authenticator_file;
+mod authenticator_jwt;
 
+pub use acl::{granted_qos, publish_allowed, subscribe_allowed};
 pub use authenticator::*;
+pub use authenticator_file::AuthenticatorFile;
@@ -0,0 +1,111 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+
+/// Aggregate bandwidth cap shared by all connections of a listener.
+///
+/// Implemented as a leaky bucket which drains `rate` bytes per second. A connection which
+/// writes more than the bucket can take waits until enough bytes have leaked. Up to one second
+/// worth of traffic can be sent as a burst.
+#[derive(Debug, Clone)]
+pub struct BandwidthLimiter {
+    bucket: Arc<Mutex<LeakyBucket>>,
+}
+
+impl BandwidthLimiter {
+    /// `rate` is a number of bytes per second.
+    pub fn new(rate: usize) -> Self {
+        BandwidthLimiter {
+            bucket: Arc::new(Mutex::new(LeakyBucket::new(rate, Instant::now()))),
+        }
+    }
+
+    /// Waits until `bytes` can be written without exceeding the cap.
+    pub async fn acquire(&self, bytes: usize) {
+        let delay = self.bucket.lock().unwrap().reserve(bytes, Instant::now());
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LeakyBucket {
+    rate: f64,
+    capacity: f64,
+    /// Number of bytes in the bucket at `updated_at`. Reserved bytes are counted as well,
+    /// so it may exceed `capacity`.
+    level: f64,
+    updated_at: Instant,
+}
+
+impl LeakyBucket {
+    fn new(rate: usize, now: Instant) -> Self {
+        let rate = rate.max(1) as f64;
+        LeakyBucket {
+            rate,
+            capacity: rate,
+            level: 0.0,
+            updated_at: now,
+        }
+    }
+
+    /// Puts `bytes` into the bucket and returns a time a writer should wait before writing them.
+    fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.level = (self.level - elapsed * self.rate).max(0.0);
+        self.updated_at = now;
+        self.level += bytes as f64;
+
+        let overflow = self.level - self.capacity;
+        if overflow > 0.0 {
+            Duration::from_secs_f64(overflow / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_within_capacity() {
+        let now = Instant::now();
+        let mut bucket = LeakyBucket::new(1000, now);
+
+        assert_eq!(bucket.reserve(400, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(600, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn overflow_is_delayed() {
+        let now = Instant::now();
+        let mut bucket = LeakyBucket::new(1000, now);
+
+        assert_eq!(bucket.reserve(1000, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, now), Duration::from_millis(500));
+        // reserved bytes delay next writers even more
+        assert_eq!(bucket.reserve(500, now), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn bucket_leaks_over_time() {
+        let now = Instant::now();
+        let mut bucket = LeakyBucket::new(1000, now);
+
+        assert_eq!(bucket.reserve(1500, now), Duration::from_millis(500));
+        assert_eq!(
+            bucket.reserve(250, now + Duration::from_millis(750)),
+            Duration::ZERO
+        );
+        assert_eq!(
+            bucket.reserve(2000, now + Duration::from_secs(10)),
+            Duration::from_secs(1)
+        );
+    }
+}
@@ -0,0 +1,289 @@
+//! Retained bootstrap messages of new clients, e.g. config blobs and feature flags a device
+//! reads from its own topics once it's provisioned.
+//!
+//! Once a client without a history connects for the first time, every `bootstrap` rule
+//! matching its client id publishes retained messages to topics of the client: `messages` of
+//! the rule, with `%c` of topics replaced by the client id, and messages returned by
+//! `endpoint` of the rule in the same format. Messages are published as if they were
+//! published by a client, so they are subject to the same routing, retention and limits.
+//! An endpoint is requested up to `ENDPOINT_ATTEMPTS` times, after which the client is left
+//! without its messages.
+use std::{sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{error, info, warn};
+use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic, QoS};
+use reqwest::Client;
+use serde::Serialize;
+use tokio::{
+    spawn,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::sleep,
+};
+
+use crate::{
+    admin_api::PayloadEncoding,
+    authenticator::AuthenticatorFile,
+    config::{BootstrapConfig, BootstrapMessageConfig, TeleMQServerConfig},
+    control::{ControlMessage, ControlSender},
+    publish_metadata::PublishMetadata,
+    stats::StatsMessage,
+};
+
+/// Client ids of clients connected for the first time.
+pub type BootstrapSender = UnboundedSender<String>;
+
+const ENDPOINT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Requests to an endpoint for a single client, the delay between them is doubled every time.
+const ENDPOINT_ATTEMPTS: u32 = 3;
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Metadata key of bootstrap messages, its value is a tag of the rule.
+const BOOTSTRAP_METADATA_KEY: &str = "bootstrap";
+
+/// A body of a request to an endpoint.
+#[derive(Serialize)]
+struct EndpointRequest<'a> {
+    client_id: &'a str,
+    tag: &'a str,
+}
+
+/// A topic, a payload and QoS of a message for a client, or an error if the message is not
+/// valid. A topic has to contain `%c`, so a client receives messages on its own topics only.
+pub fn bootstrap_message(
+    message: &BootstrapMessageConfig,
+    client_id: &str,
+) -> Result<(Topic, Vec<u8>, QoS), String> {
+    if !AuthenticatorFile::has_client_id(&message.topic) {
+        return Err(format!(
+            "topic {:?} should contain %c, the client id",
+            message.topic
+        ));
+    }
+    let topic = AuthenticatorFile::substitute(&message.topic, client_id, None)
+        .and_then(|topic| Topic::try_from(topic).ok())
+        .filter(|topic| topic.is_valid())
+        .ok_or_else(|| {
+            format!(
+                "topic {:?} is not a valid topic name for client {:?}",
+                message.topic, client_id
+            )
+        })?;
+    let qos = QoS::try_from(message.qos.unwrap_or(0))
+        .map_err(|_| format!("QoS of {:?} should be 0, 1 or 2", message.topic))?;
+    let payload = match message.encoding.unwrap_or_default() {
+        PayloadEncoding::Utf8 => message.payload.clone().into_bytes(),
+        PayloadEncoding::Base64 => STANDARD
+            .decode(&message.payload)
+            .map_err(|err| format!("payload of {:?} is not base64. {}", message.topic, err))?,
+    };
+
+    Ok((topic, payload, qos))
+}
+
+pub struct Bootstrap {
+    rules: Arc<Vec<BootstrapConfig>>,
+    client: Client,
+    receiver: UnboundedReceiver<String>,
+}
+
+impl Bootstrap {
+    /// `None` if there are no `bootstrap` rules.
+    pub fn new(config: &TeleMQServerConfig) -> Result<Option<(Self, BootstrapSender)>, String> {
+        if config.bootstrap.is_empty() {
+            return Ok(None);
+        }
+        let client = Client::builder()
+            .timeout(ENDPOINT_TIMEOUT)
+            .build()
+            .map_err(|err| format!("Unable to create a bootstrap client. {:?}", err))?;
+        let (sender, receiver) = unbounded_channel();
+        Ok(Some((
+            Bootstrap {
+                rules: Arc::new(config.bootstrap.clone()),
+                client,
+                receiver,
+            },
+            sender,
+        )))
+    }
+
+    pub async fn run(mut self, control_sender: ControlSender) {
+        while let Some(client_id) = self.receiver.recv().await {
+            let rules = self.rules.iter().filter(|rule| {
+                rule.client_id_prefix
+                    .as_ref()
+                    .is_none_or(|prefix| client_id.starts_with(prefix.as_str()))
+            });
+            for rule in rules {
+                let messages = rule.messages.as_deref().unwrap_or_default();
+                publish(&control_sender, &client_id, &rule.tag, messages);
+                if let Some(ref endpoint) = rule.endpoint {
+                    // a slow endpoint doesn't hold back other clients
+                    spawn(fetch_and_publish(
+                        self.client.clone(),
+                        endpoint.clone(),
+                        client_id.clone(),
+                        rule.tag.clone(),
+                        control_sender.clone(),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// A client id of a client which has connected for the first time.
+pub fn first_connection(message: &StatsMessage) -> Option<String> {
+    match message {
+        StatsMessage::ClientConnected {
+            connection,
+            first_connection: true,
+            ..
+        } => Some(connection.client_id.clone()),
+        _ => None,
+    }
+}
+
+async fn fetch_and_publish(
+    client: Client,
+    endpoint: String,
+    client_id: String,
+    tag: String,
+    control_sender: ControlSender,
+) {
+    let mut retry_delay = MIN_RETRY_DELAY;
+    for attempt in 1..=ENDPOINT_ATTEMPTS {
+        let response = client
+            .post(&endpoint)
+            .json(&EndpointRequest {
+                client_id: &client_id,
+                tag: &tag,
+            })
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let messages = match response {
+            Ok(response) => response.json::<Vec<BootstrapMessageConfig>>().await,
+            Err(err) => Err(err),
+        };
+        match messages {
+            Ok(messages) => {
+                publish(&control_sender, &client_id, &tag, &messages);
+                return;
+            }
+            Err(err) if attempt < ENDPOINT_ATTEMPTS => {
+                warn!(
+                    "[Bootstrap]: unable to fetch messages of {} for {} from {}, retrying in {:?}. {:?}",
+                    tag, client_id, endpoint, retry_delay, err
+                );
+                sleep(retry_delay).await;
+                retry_delay *= 2;
+            }
+            Err(err) => {
+                error!(
+                    "[Bootstrap]: unable to fetch messages of {} for {} from {}, giving up. {:?}",
+                    tag, client_id, endpoint, err
+                );
+            }
+        }
+    }
+}
+
+/// Publishes retained messages to Control, invalid messages are skipped.
+fn publish(
+    control_sender: &ControlSender,
+    client_id: &str,
+    tag: &str,
+    messages: &[BootstrapMessageConfig],
+) {
+    let mut published = 0;
+    for message in messages {
+        let (topic, payload, qos) = match bootstrap_message(message, client_id) {
+            Ok(message) => message,
+            Err(err) => {
+                error!("[Bootstrap]: message of {} is skipped. {}", tag, err);
+                continue;
+            }
+        };
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(topic)
+            .with_payload(payload)
+            .with_qos(&qos)
+            .with_retained(true);
+        let mut metadata = PublishMetadata::new();
+        metadata.insert(BOOTSTRAP_METADATA_KEY, tag);
+        let message = ControlMessage::Publish {
+            publisher: None,
+            packet: builder.build(),
+            metadata,
+            sequence: None,
+            subscribers: None,
+        };
+        if let Err(err) = control_sender.send(message) {
+            error!(
+                "[Bootstrap]: unable to send ControlMessage::Publish. {:?}",
+                err
+            );
+            return;
+        }
+        published += 1;
+    }
+    if published > 0 {
+        info!(
+            "[Bootstrap]: {} retained messages of {} are published for {}",
+            published, tag, client_id
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(
+        topic: &str,
+        payload: &str,
+        encoding: Option<PayloadEncoding>,
+    ) -> BootstrapMessageConfig {
+        BootstrapMessageConfig {
+            topic: topic.into(),
+            payload: payload.into(),
+            encoding,
+            qos: Some(1),
+        }
+    }
+
+    #[test]
+    fn messages_are_published_to_topics_of_client() {
+        let (topic, payload, qos) =
+            bootstrap_message(&message("devices/%c/config", "{}", None), "thermo1").unwrap();
+        assert_eq!(topic.original, "devices/thermo1/config");
+        assert_eq!(payload, b"{}");
+        assert_eq!(qos, QoS::One);
+
+        let (topic, payload, _) = bootstrap_message(
+            &message(
+                "devices/{client_id}/flags",
+                "AAE=",
+                Some(PayloadEncoding::Base64),
+            ),
+            "thermo1",
+        )
+        .unwrap();
+        assert_eq!(topic.original, "devices/thermo1/flags");
+        assert_eq!(payload, vec![0, 1]);
+    }
+
+    #[test]
+    fn topics_of_other_clients_are_rejected() {
+        assert!(bootstrap_message(&message("devices/config", "{}", None), "thermo1").is_err());
+        assert!(bootstrap_message(&message("devices/%c/#", "{}", None), "thermo1").is_err());
+        assert!(bootstrap_message(&message("devices/%u/%c", "{}", None), "thermo1").is_err());
+        assert!(bootstrap_message(
+            &message("devices/%c", "not base64", Some(PayloadEncoding::Base64)),
+            "thermo1"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,574 @@
+//! Bridges to remote MQTT brokers.
+//!
+//! Every `[[bridge]]` config section makes TeleMQ open an outbound MQTT 3.1.1 connection to
+//! a remote broker, similarly to mosquitto bridges. Topics are relayed according to topic
+//! mappings:
+//!
+//! - `in` (and `both`) mappings subscribe on the remote broker to `remote_prefix + pattern`
+//!   and publish received messages locally, with `remote_prefix` replaced by `local_prefix`.
+//! - `out` (and `both`) mappings subscribe locally to `local_prefix + pattern` as an
+//!   in-process client `$bridge/{name}` and publish messages to the remote broker, with
+//!   `local_prefix` replaced by `remote_prefix`.
+//!
+//! Messages a bridge has published locally carry a `bridge` metadata key, so they are never
+//! sent back to the broker they came from. Messages a bridge has sent to the remote broker
+//! and receives back because of a `both` mapping are dropped as echoes.
+//!
+//! A bridge reconnects after `reconnect_interval` if the remote broker is not available
+//! or the connection is lost. Messages are relayed at most QoS 1 and are not retransmitted
+//! once the connection is lost.
+use std::{collections::VecDeque, io, time::Duration};
+
+use bytes::Bytes;
+use futures::SinkExt;
+use log::{debug, info, warn};
+use mqtt_packets::v_3_1_1::{
+    builders::{
+        ConnectBuilder, PingreqPacketBuilder, PubackPacketBuilder, PublishPacketBuilder,
+        SubscribePacketBuilder,
+    },
+    connack::return_code::ReturnCode,
+    publish::fixed_header::{get_qos_level, is_retained},
+    suback::return_code::ReturnCode as SubackReturnCode,
+    topic::{Subscription, Topic},
+    variable::Variable,
+    CPType, ControlPacket, ControlPacketCodec, PacketId, QoS,
+};
+use tokio::{
+    net::TcpStream,
+    select,
+    time::{interval_at, sleep, timeout, Instant},
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+
+use crate::{
+    broker_handle::{recv_local, BrokerHandle, InProcessMessage},
+    broker_state::{state_packet, BrokerState},
+    config::{BridgeConfig, BridgeDirection, BridgeTopicConfig, TeleMQServerConfig},
+    publish_metadata::PublishMetadata,
+};
+
+/// Metadata key of messages published locally by a bridge, its value is a bridge name.
+pub const BRIDGE_METADATA_KEY: &str = "bridge";
+/// Max time to establish a connection and to receive CONNACK.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of messages sent to the remote broker which are remembered to detect echoes.
+const ECHO_FILTER_CAPACITY: usize = 1024;
+const SUBSCRIBE_PACKET_ID: u16 = 1;
+
+/// Maps topics between TeleMQ and a remote broker.
+#[derive(Debug)]
+struct TopicMapping {
+    direction: BridgeDirection,
+    qos: QoS,
+    local_prefix: String,
+    remote_prefix: String,
+    local_filter: Subscription,
+    remote_filter: Subscription,
+}
+
+impl TopicMapping {
+    fn new(config: &BridgeTopicConfig) -> io::Result<Self> {
+        let local_prefix = config.local_prefix.clone().unwrap_or_default();
+        let remote_prefix = config.remote_prefix.clone().unwrap_or_default();
+        Ok(TopicMapping {
+            direction: config.direction,
+            qos: QoS::try_from(config.qos.unwrap_or(0))?,
+            local_filter: Subscription::try_from(format!("{}{}", local_prefix, config.pattern))?,
+            remote_filter: Subscription::try_from(format!("{}{}", remote_prefix, config.pattern))?,
+            local_prefix,
+            remote_prefix,
+        })
+    }
+
+    fn is_in(&self) -> bool {
+        self.direction != BridgeDirection::Out
+    }
+
+    fn is_out(&self) -> bool {
+        self.direction != BridgeDirection::In
+    }
+
+    /// Returns a remote topic of a local one if it should be sent to the remote broker.
+    fn to_remote(&self, local_topic: &str) -> Option<String> {
+        if !self.is_out() {
+            return None;
+        }
+        rewrite(
+            local_topic,
+            &self.local_filter,
+            &self.local_prefix,
+            &self.remote_prefix,
+        )
+    }
+
+    /// Returns a local topic of a remote one if it should be published locally.
+    fn to_local(&self, remote_topic: &str) -> Option<String> {
+        if !self.is_in() {
+            return None;
+        }
+        rewrite(
+            remote_topic,
+            &self.remote_filter,
+            &self.remote_prefix,
+            &self.local_prefix,
+        )
+    }
+}
+
+fn rewrite(
+    topic: &str,
+    filter: &Subscription,
+    from_prefix: &str,
+    to_prefix: &str,
+) -> Option<String> {
+    let matches = Topic::try_from(topic)
+        .map(|topic| filter.topic_matches(&topic))
+        .unwrap_or(false);
+    if !matches {
+        return None;
+    }
+    topic
+        .strip_prefix(from_prefix)
+        .map(|rest| format!("{}{}", to_prefix, rest))
+}
+
+/// Remembers messages sent to the remote broker, so they are not published locally
+/// once the remote broker sends them back.
+#[derive(Debug, Default)]
+struct EchoFilter {
+    sent: VecDeque<(String, Bytes)>,
+}
+
+impl EchoFilter {
+    fn sent(&mut self, topic: String, payload: Bytes) {
+        if self.sent.len() == ECHO_FILTER_CAPACITY {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((topic, payload));
+    }
+
+    /// Returns `true` (and forgets a message) if a message has been sent by the bridge.
+    fn is_echo(&mut self, topic: &str, payload: &[u8]) -> bool {
+        match self
+            .sent
+            .iter()
+            .position(|(t, p)| t == topic && p == payload)
+        {
+            Some(index) => {
+                self.sent.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+type RemoteConnection = Framed<TcpStream, ControlPacketCodec>;
+
+pub struct Bridge {
+    config: BridgeConfig,
+    mappings: Vec<TopicMapping>,
+    broker: BrokerHandle,
+    echo_filter: EchoFilter,
+    next_packet_id: u16,
+    broker_id: String,
+}
+
+impl Bridge {
+    pub fn new(config: BridgeConfig, broker_id: &str, broker: BrokerHandle) -> io::Result<Self> {
+        let mappings = config
+            .topics
+            .iter()
+            .map(TopicMapping::new)
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Bridge {
+            config,
+            mappings,
+            broker,
+            echo_filter: EchoFilter::default(),
+            next_packet_id: 1,
+            broker_id: broker_id.to_string(),
+        })
+    }
+
+    /// Relays messages until the broker shuts down, reconnecting to the remote broker
+    /// whenever a connection is lost.
+    pub async fn run(mut self) {
+        let reconnect_interval = Duration::from_secs(
+            self.config
+                .reconnect_interval
+                .unwrap_or(TeleMQServerConfig::DEFAULT_BRIDGE_RECONNECT_INTERVAL),
+        );
+        loop {
+            match self.run_session().await {
+                Ok(()) => {
+                    info!("[Bridge {}]: stopped", self.config.name);
+                    return;
+                }
+                Err(err) => {
+                    warn!(
+                        "[Bridge {}]: connection to {} failed. {}. Reconnecting in {:?}",
+                        self.config.name, self.config.address, err, reconnect_interval
+                    );
+                }
+            }
+            sleep(reconnect_interval).await;
+        }
+    }
+
+    /// Returns `Ok` once the broker is shutting down.
+    async fn run_session(&mut self) -> io::Result<()> {
+        let mut remote = self.connect().await?;
+        info!(
+            "[Bridge {}]: connected to {}",
+            self.config.name, self.config.address
+        );
+
+        let remote_filters: Vec<&TopicMapping> =
+            self.mappings.iter().filter(|m| m.is_in()).collect();
+        if !remote_filters.is_empty() {
+            let mut builder = SubscribePacketBuilder::new(PacketId::new(SUBSCRIBE_PACKET_ID));
+            for mapping in remote_filters {
+                builder =
+                    builder.with_subscription(mapping.remote_filter.clone(), mapping.qos.clone());
+            }
+            remote.send(&builder.build()).await?;
+        }
+
+        let local_filters: Vec<&str> = self
+            .mappings
+            .iter()
+            .filter(|m| m.is_out())
+            .map(|m| m.local_filter.original.as_str())
+            .collect();
+        let mut subscriber = if local_filters.is_empty() {
+            None
+        } else {
+            let client_id = format!("$bridge/{}", self.config.name);
+            Some(
+                self.broker
+                    .subscribe(client_id, &local_filters)
+                    .map_err(|err| io::Error::other(err.0))?,
+            )
+        };
+
+        let keep_alive = self.keep_alive();
+        let ping_interval = if keep_alive.is_zero() {
+            // no keep alive, pings are never sent
+            Duration::from_secs(u32::MAX as u64)
+        } else {
+            keep_alive / 2
+        };
+        let mut ping = interval_at(Instant::now() + ping_interval, ping_interval);
+        let mut awaiting_pingresp = false;
+
+        loop {
+            select! {
+                packet = remote.next() => match packet {
+                    Some(packet) => {
+                        let packet = packet?;
+                        if packet.fixed_header.cp_type == CPType::Pingresp {
+                            awaiting_pingresp = false;
+                        } else if !self.on_remote_packet(&mut remote, packet).await? {
+                            return Ok(());
+                        }
+                    }
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "remote broker closed the connection",
+                        ))
+                    }
+                },
+                message = recv_local(&mut subscriber) => match message {
+                    Some(message) => self.forward_to_remote(&mut remote, message).await?,
+                    None => return Ok(()),
+                },
+                _ = ping.tick() => {
+                    if awaiting_pingresp {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "remote broker did not respond to PINGREQ",
+                        ));
+                    }
+                    remote.send(&PingreqPacketBuilder::new().build()).await?;
+                    awaiting_pingresp = true;
+                }
+            }
+        }
+    }
+
+    async fn connect(&self) -> io::Result<RemoteConnection> {
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(&self.config.address))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection timed out"))??;
+        let mut remote = Framed::new(stream, ControlPacketCodec::new());
+
+        let client_id = self
+            .config
+            .client_id
+            .clone()
+            .unwrap_or_else(|| default_client_id(&self.config.name));
+        let mut connect_builder = ConnectBuilder::new(
+            client_id,
+            self.keep_alive().as_secs() as u16,
+            true,
+            self.config.username.clone(),
+            self.config.password.clone(),
+        );
+        if self.config.broker_state_will.unwrap_or(false) {
+            let offline = state_packet(&self.broker_id, BrokerState::Offline);
+            if let Variable::Publish(variable) = offline.variable {
+                connect_builder = connect_builder.with_will(
+                    variable.topic_name,
+                    variable.payload.to_vec(),
+                    &QoS::One,
+                    true,
+                );
+            }
+        }
+        let connect_packet = connect_builder.build();
+        remote.send(&connect_packet).await?;
+
+        let connack = timeout(CONNECT_TIMEOUT, remote.next())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "CONNACK timed out"))?;
+        match connack {
+            Some(Ok(ControlPacket {
+                variable: Variable::Connack(variable),
+                ..
+            })) => match variable.return_code {
+                ReturnCode::Accepted => Ok(remote),
+                return_code => Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("remote broker rejected the connection: {:?}", return_code),
+                )),
+            },
+            Some(Ok(packet)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected CONNACK, got {:?}", packet.fixed_header.cp_type),
+            )),
+            Some(Err(err)) => Err(err.into()),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "remote broker closed the connection",
+            )),
+        }
+    }
+
+    fn keep_alive(&self) -> Duration {
+        Duration::from_secs(
+            self.config
+                .keep_alive
+                .unwrap_or(TeleMQServerConfig::DEFAULT_BRIDGE_KEEP_ALIVE),
+        )
+    }
+
+    /// Returns `false` if the broker is shutting down.
+    async fn on_remote_packet(
+        &mut self,
+        remote: &mut RemoteConnection,
+        packet: ControlPacket,
+    ) -> io::Result<bool> {
+        let qos = get_qos_level(&packet.fixed_header).unwrap_or(QoS::Zero);
+        let retain = is_retained(&packet.fixed_header);
+        match packet.variable {
+            Variable::Publish(variable) => {
+                if let Some(packet_id) = &variable.packet_id {
+                    if qos == QoS::One {
+                        remote
+                            .send(&PubackPacketBuilder::new(packet_id).build())
+                            .await?;
+                    }
+                }
+
+                let remote_topic = variable.topic_name.original;
+                if self.echo_filter.is_echo(&remote_topic, &variable.payload) {
+                    return Ok(true);
+                }
+                let mapping = self
+                    .mappings
+                    .iter()
+                    .find_map(|mapping| mapping.to_local(&remote_topic).map(|t| (mapping, t)));
+                let (mapping, local_topic) = match mapping {
+                    Some(mapping) => mapping,
+                    None => {
+                        debug!(
+                            "[Bridge {}]: no topic mapping for {:?}, message is dropped",
+                            self.config.name, remote_topic
+                        );
+                        return Ok(true);
+                    }
+                };
+
+                let mut metadata = PublishMetadata::new();
+                metadata.insert(BRIDGE_METADATA_KEY, self.config.name.as_str());
+                let qos = if qos > mapping.qos {
+                    mapping.qos.clone()
+                } else {
+                    qos
+                };
+                Ok(self
+                    .broker
+                    .publish_with_metadata(local_topic, variable.payload, qos, retain, metadata)
+                    .is_ok())
+            }
+            Variable::Suback(variable) => {
+                if variable.return_codes.contains(&SubackReturnCode::Failure) {
+                    warn!(
+                        "[Bridge {}]: remote broker rejected some of subscriptions {:?}",
+                        self.config.name, variable.return_codes
+                    );
+                }
+                Ok(true)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    async fn forward_to_remote(
+        &mut self,
+        remote: &mut RemoteConnection,
+        message: InProcessMessage,
+    ) -> io::Result<()> {
+        if message.metadata.get(BRIDGE_METADATA_KEY) == Some(self.config.name.as_str()) {
+            return Ok(());
+        }
+        let (remote_topic, max_qos) = match self.mappings.iter().find_map(|mapping| {
+            mapping
+                .to_remote(&message.topic)
+                .map(|topic| (topic, mapping.qos.clone()))
+        }) {
+            Some(mapping) => mapping,
+            None => return Ok(()),
+        };
+
+        let qos = if message.qos > max_qos {
+            max_qos
+        } else {
+            message.qos
+        };
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(Topic::try_from(&remote_topic)?)
+            .with_payload(message.payload.clone())
+            .with_qos(&qos)
+            .with_retained(message.retain);
+        if qos != QoS::Zero {
+            builder.with_packet_id(self.next_packet_id());
+        }
+        remote.send(&builder.build()).await?;
+
+        // the remote broker sends a message back if the bridge is subscribed to its topic
+        let echoes = self
+            .mappings
+            .iter()
+            .any(|mapping| mapping.to_local(&remote_topic).is_some());
+        if echoes {
+            self.echo_filter.sent(remote_topic, message.payload);
+        }
+        Ok(())
+    }
+
+    fn next_packet_id(&mut self) -> PacketId {
+        let packet_id = self.next_packet_id;
+        // packet id 0 is not allowed
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        PacketId::new(packet_id)
+    }
+}
+
+/// MQTT 3.1.1 servers are only required to accept up to 23 alphanumeric characters.
+fn default_client_id(bridge_name: &str) -> String {
+    format!("telemqbridge{}", bridge_name)
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .take(23)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(direction: BridgeDirection, local: &str, remote: &str) -> TopicMapping {
+        TopicMapping::new(&BridgeTopicConfig {
+            pattern: "sensors/#".into(),
+            direction,
+            qos: Some(1),
+            local_prefix: Some(local.into()),
+            remote_prefix: Some(remote.into()),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn prefixes_are_rewritten() {
+        let mapping = mapping(BridgeDirection::Both, "local/", "site-1/");
+        assert_eq!(mapping.local_filter.original, "local/sensors/#");
+        assert_eq!(mapping.remote_filter.original, "site-1/sensors/#");
+
+        assert_eq!(
+            mapping.to_remote("local/sensors/1/temp"),
+            Some("site-1/sensors/1/temp".into())
+        );
+        assert_eq!(
+            mapping.to_local("site-1/sensors/1/temp"),
+            Some("local/sensors/1/temp".into())
+        );
+        assert_eq!(mapping.to_remote("local/actuators/1"), None);
+        assert_eq!(mapping.to_local("local/sensors/1/temp"), None);
+    }
+
+    #[test]
+    fn direction_is_respected() {
+        let inbound = mapping(BridgeDirection::In, "", "remote/");
+        assert_eq!(inbound.to_remote("sensors/1"), None);
+        assert_eq!(
+            inbound.to_local("remote/sensors/1"),
+            Some("sensors/1".into())
+        );
+
+        let outbound = mapping(BridgeDirection::Out, "", "remote/");
+        assert_eq!(
+            outbound.to_remote("sensors/1"),
+            Some("remote/sensors/1".into())
+        );
+        assert_eq!(outbound.to_local("remote/sensors/1"), None);
+    }
+
+    #[test]
+    fn default_client_id_is_accepted_by_any_broker() {
+        assert_eq!(default_client_id("site"), "telemqbridgesite");
+        assert_eq!(default_client_id("site-1_a"), "telemqbridgesite1a");
+        assert_eq!(
+            default_client_id("a-very-long-bridge-name"),
+            "telemqbridgeaverylongbr"
+        );
+    }
+
+    #[test]
+    fn echoes_are_detected_once() {
+        let mut filter = EchoFilter::default();
+        filter.sent("a".into(), Bytes::from_static(b"1"));
+
+        assert!(!filter.is_echo("a", b"2"));
+        assert!(!filter.is_echo("b", b"1"));
+        assert!(filter.is_echo("a", b"1"));
+        assert!(!filter.is_echo("a", b"1"));
+    }
+
+    #[test]
+    fn echo_filter_is_bounded() {
+        let mut filter = EchoFilter::default();
+        for i in 0..=ECHO_FILTER_CAPACITY {
+            filter.sent(i.to_string(), Bytes::new());
+        }
+
+        assert_eq!(filter.sent.len(), ECHO_FILTER_CAPACITY);
+        assert!(!filter.is_echo("0", &[]));
+        assert!(filter.is_echo("1", &[]));
+    }
+}
@@ -0,0 +1,130 @@
+//! Optional subsystems of the broker, so tooling can adapt to a fleet of brokers which run
+//! different versions or configurations during a rollout.
+//!
+//! The same JSON document is served by `GET /features` of Admin API and kept as a retained
+//! message on `$SYS/broker/features`. A subsystem is `compiled` if this build of the broker
+//! has it and `enabled` if it's also turned on by the config. Subsystems may be added to the
+//! document, so consumers should treat a missing one as not compiled.
+use log::error;
+use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic, ControlPacket};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::TeleMQServerConfig,
+    control::{ControlMessage, ControlSender},
+    publish_metadata::PublishMetadata,
+};
+
+pub const FEATURES_TOPIC: &str = "$SYS/broker/features";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Feature {
+    pub compiled: bool,
+    pub enabled: bool,
+}
+
+impl Feature {
+    fn compiled(enabled: bool) -> Self {
+        Feature {
+            compiled: true,
+            enabled,
+        }
+    }
+
+    fn not_compiled() -> Self {
+        Feature {
+            compiled: false,
+            enabled: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BrokerFeatures {
+    /// Topics relayed with remote brokers, see `bridge` in the config.
+    pub bridge: Feature,
+    /// Rules which transform or route messages, not available yet.
+    pub rule_engine: Feature,
+    /// Activity of clients across their connections, served by `GET /devices/{client_id}`.
+    pub history: Feature,
+    /// MQTT 5.0 clients.
+    pub mqtt5: Feature,
+    /// Subscriptions shared with peer brokers, see `cluster_peers` in the config.
+    pub clustering: Feature,
+}
+
+impl BrokerFeatures {
+    pub fn new(config: &TeleMQServerConfig) -> Self {
+        BrokerFeatures {
+            bridge: Feature::compiled(!config.bridges.is_empty()),
+            rule_engine: Feature::not_compiled(),
+            history: Feature::compiled(true),
+            mqtt5: Feature::compiled(true),
+            clustering: Feature::compiled(config.cluster_addr.is_some()),
+        }
+    }
+
+    /// A retained message with the features document.
+    pub fn packet(&self) -> ControlPacket {
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(Topic::make_from_string(FEATURES_TOPIC))
+            // the document has no maps with non-string keys, so it's always serialized
+            .with_payload(serde_json::to_vec(self).unwrap())
+            .with_retained(true);
+        builder.build()
+    }
+
+    pub fn announce(&self, control_sender: &ControlSender) {
+        let message = ControlMessage::Publish {
+            publisher: None,
+            packet: self.packet(),
+            metadata: PublishMetadata::default(),
+            sequence: None,
+            subscribers: None,
+        };
+        if control_sender.send(message).is_err() {
+            error!("[Server Worker]: unable to publish broker features");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_packets::v_3_1_1::{publish::fixed_header::is_retained, variable::Variable};
+    use serde_json::{json, to_value};
+
+    #[test]
+    fn features_shape() {
+        let features = BrokerFeatures::new(&TeleMQServerConfig::default());
+        assert_eq!(
+            to_value(features).unwrap(),
+            json!({
+                "bridge": {"compiled": true, "enabled": false},
+                "rule_engine": {"compiled": false, "enabled": false},
+                "history": {"compiled": true, "enabled": true},
+                "mqtt5": {"compiled": true, "enabled": true},
+                "clustering": {"compiled": true, "enabled": false}
+            })
+        );
+    }
+
+    #[test]
+    fn features_are_retained() {
+        let features = BrokerFeatures::new(&TeleMQServerConfig::default());
+        let packet = features.packet();
+
+        assert!(is_retained(&packet.fixed_header));
+        match packet.variable {
+            Variable::Publish(variable) => {
+                assert_eq!(variable.topic_name.original, FEATURES_TOPIC);
+                assert_eq!(
+                    serde_json::from_slice::<BrokerFeatures>(&variable.payload).unwrap(),
+                    features
+                );
+            }
+            _ => panic!("Publish is expected"),
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    future::pending,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use mqtt_packets::v_3_1_1::{
+    builders::PublishPacketBuilder,
+    publish::fixed_header::{get_qos_level, is_retained},
+    topic::{Subscription, Topic},
+    variable::Variable,
+    ControlPacket, QoS,
+};
+use tokio::sync::oneshot;
+
+use crate::{
+    clock::Clock,
+    connection::ConnectionMessage,
+    connection_channel::{connection_channel, ChannelLimit, ConnectionReceiver},
+    connection_gate::ConnectionTransport,
+    connection_info::ConnectionInfo,
+    control::{ControlMessage, ControlSender},
+    publish_metadata::PublishMetadata,
+    server_error::ServerResult,
+};
+
+/// In-process clients have no network address, an unspecified one is reported instead.
+const IN_PROCESS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// Handle to a broker which allows an embedding application to publish and to subscribe
+/// without opening a network connection.
+#[derive(Clone)]
+pub struct BrokerHandle {
+    control_sender: ControlSender,
+    clock: Clock,
+}
+
+impl BrokerHandle {
+    pub(crate) fn new(control_sender: ControlSender, clock: Clock) -> Self {
+        BrokerHandle {
+            control_sender,
+            clock,
+        }
+    }
+
+    /// Publishes a message to all subscribers of a given topic.
+    pub fn publish<T: AsRef<str>, P: Into<Bytes>>(
+        &self,
+        topic: T,
+        payload: P,
+        qos: QoS,
+        retain: bool,
+    ) -> ServerResult<()> {
+        self.publish_with_metadata(topic, payload, qos, retain, PublishMetadata::default())
+    }
+
+    /// Same as `publish`, but attaches metadata (e.g. a trace id) to the message.
+    /// Metadata is available to in-process subscribers and is not sent to network clients.
+    pub fn publish_with_metadata<T: AsRef<str>, P: Into<Bytes>>(
+        &self,
+        topic: T,
+        payload: P,
+        qos: QoS,
+        retain: bool,
+        metadata: PublishMetadata,
+    ) -> ServerResult<()> {
+        let topic = Topic::try_from(topic)?;
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(topic)
+            .with_payload(payload)
+            .with_qos(&qos)
+            .with_retained(retain);
+
+        self.send(ControlMessage::Publish {
+            publisher: None,
+            packet: builder.build(),
+            metadata,
+            sequence: None,
+            subscribers: None,
+        })
+    }
+
+    /// Registers an in-process client with a given client id and subscribes it to topic
+    /// filters. Similarly to network clients, a client with the same id connected later
+    /// takes the subscription over. The client is disconnected when the subscriber is dropped.
+    pub fn subscribe<T: AsRef<str>>(
+        &self,
+        client_id: String,
+        filters: &[T],
+    ) -> ServerResult<InProcessSubscriber> {
+        let mut subscriptions = Vec::with_capacity(filters.len());
+        for filter in filters {
+            let subscription = Subscription::try_from(filter)?;
+            if !subscription.is_valid() {
+                return Err(format!("Invalid topic filter {:?}", filter.as_ref()).into());
+            }
+            subscriptions.push(subscription);
+        }
+
+        let connection = Arc::new(ConnectionInfo {
+            client_id,
+            ..ConnectionInfo::accepted(
+                IN_PROCESS_ADDR,
+                ConnectionTransport::InProcess,
+                None,
+                self.clock.now(),
+            )
+        });
+        let (sender, receiver) = connection_channel(ChannelLimit::default(), None);
+        self.send(ControlMessage::ClientConnected {
+            connection: connection.clone(),
+            clean_session: true,
+            sender,
+            will_packet: None,
+            taken_over: None,
+        })?;
+        self.send(ControlMessage::AddSubscriptions {
+            connection: connection.clone(),
+            subscriptions,
+        })?;
+
+        Ok(InProcessSubscriber {
+            connection,
+            receiver,
+            control_sender: self.control_sender.clone(),
+            connected: true,
+        })
+    }
+
+    /// Lists topic filters of the subscription tree along with their subscribers.
+    pub(crate) async fn dump_subscriptions(&self) -> ServerResult<Vec<(String, Vec<String>)>> {
+        let (reply, response) = oneshot::channel();
+        self.send(ControlMessage::DumpSubscriptions { reply })?;
+        response
+            .await
+            .map_err(|_| "[Broker Handle]: broker is not available".into())
+    }
+
+    /// Initiates a graceful broker shut down, `Server::start` returns once it's completed.
+    pub fn shut_down(&self) -> ServerResult<()> {
+        self.send(ControlMessage::ShutDown)
+    }
+
+    fn send(&self, message: ControlMessage) -> ServerResult<()> {
+        let message_type = message.get_name();
+        self.control_sender.send(message).map_err(|err| {
+            format!(
+                "[Broker Handle]: unable to send {}. {:?}",
+                message_type, err
+            )
+            .into()
+        })
+    }
+}
+
+/// Message delivered to an in-process subscriber.
+#[derive(Debug, Clone)]
+pub struct InProcessMessage {
+    pub topic: String,
+    /// Shared with other subscribers of the message.
+    pub payload: Bytes,
+    pub qos: QoS,
+    pub retain: bool,
+    pub metadata: PublishMetadata,
+    /// Topic filter of a new subscription a retained message is sent for, `None` for
+    /// messages which have just been published.
+    pub retained_for: Option<String>,
+}
+
+impl InProcessMessage {
+    pub(crate) fn from_packet(
+        packet: ControlPacket,
+        metadata: PublishMetadata,
+        retained_for: Option<String>,
+    ) -> Option<Self> {
+        let qos = get_qos_level(&packet.fixed_header).ok()?;
+        let retain = is_retained(&packet.fixed_header);
+        match packet.variable {
+            Variable::Publish(variable) => Some(InProcessMessage {
+                topic: variable.topic_name.original,
+                payload: variable.payload,
+                qos,
+                retain,
+                metadata,
+                retained_for,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// In-process client subscribed via `BrokerHandle::subscribe`.
+///
+/// A broker waits for all clients to disconnect during a graceful shut down, so
+/// the subscriber should either be polled with `recv` or dropped.
+pub struct InProcessSubscriber {
+    connection: Arc<ConnectionInfo>,
+    receiver: ConnectionReceiver,
+    control_sender: ControlSender,
+    connected: bool,
+}
+
+impl InProcessSubscriber {
+    pub fn client_id(&self) -> &str {
+        &self.connection.client_id
+    }
+
+    /// Waits for a next message. Returns `None` once the subscriber has been disconnected,
+    /// either because the broker is shutting down or because a client with the same id
+    /// has connected.
+    pub async fn recv(&mut self) -> Option<InProcessMessage> {
+        while self.connected {
+            match self.receiver.recv().await {
+                Some(ConnectionMessage::Publish {
+                    packet,
+                    metadata,
+                    retained_for,
+                }) => {
+                    if let Some(message) =
+                        InProcessMessage::from_packet(packet, metadata, retained_for)
+                    {
+                        return Some(message);
+                    }
+                }
+                Some(ConnectionMessage::ShutDown) | Some(ConnectionMessage::DropOverLimit) => {
+                    self.disconnect();
+                }
+                Some(ConnectionMessage::Disconnect { .. }) | None => {
+                    // Control has already forgotten this client
+                    self.connected = false;
+                }
+            }
+        }
+
+        None
+    }
+
+    fn disconnect(&mut self) {
+        if !self.connected {
+            return;
+        }
+        self.connected = false;
+        let _ = self
+            .control_sender
+            .send(ControlMessage::ClientDisconnected {
+                connection: self.connection.clone(),
+                clean_session: true,
+                will_packet: None,
+                undelivered: Vec::new(),
+            });
+    }
+}
+
+impl Drop for InProcessSubscriber {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+/// Waits for a next message of an optional subscriber, never completes if there is none.
+pub(crate) async fn recv_local(
+    subscriber: &mut Option<InProcessSubscriber>,
+) -> Option<InProcessMessage> {
+    match subscriber {
+        Some(subscriber) => subscriber.recv().await,
+        None => pending().await,
+    }
+}
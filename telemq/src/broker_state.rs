@@ -0,0 +1,85 @@
+//! Liveness of the broker announced over MQTT.
+//!
+//! While the broker is running `$SYS/broker/{broker_id}/state` keeps a retained `online`
+//! message, which is replaced by `offline` on a graceful shut down. A crashed broker can't
+//! publish anything, so `offline` is published for it by others: a bridge registers it as
+//! a will on a remote broker and an external watchdog may publish it to a local one.
+use log::error;
+use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic, ControlPacket, QoS};
+
+use crate::{
+    control::{ControlMessage, ControlSender},
+    publish_metadata::PublishMetadata,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokerState {
+    Online,
+    Offline,
+}
+
+impl BrokerState {
+    pub fn payload(&self) -> &'static [u8] {
+        match self {
+            BrokerState::Online => b"online",
+            BrokerState::Offline => b"offline",
+        }
+    }
+}
+
+pub fn state_topic(broker_id: &str) -> String {
+    format!("$SYS/broker/{}/state", broker_id)
+}
+
+/// A retained QoS 1 PUBLISH of a broker state.
+pub fn state_packet(broker_id: &str, state: BrokerState) -> ControlPacket {
+    // broker_id has no wildcards, it's validated with the config
+    let topic = Topic::try_from(state_topic(broker_id)).unwrap();
+    let mut builder = PublishPacketBuilder::new();
+    builder
+        .with_topic(topic)
+        .with_payload(state.payload())
+        .with_qos(&QoS::One)
+        .with_retained(true);
+    builder.build()
+}
+
+pub fn announce(control_sender: &ControlSender, broker_id: &str, state: BrokerState) {
+    let message = ControlMessage::Publish {
+        publisher: None,
+        packet: state_packet(broker_id, state),
+        metadata: PublishMetadata::default(),
+        sequence: None,
+        subscribers: None,
+    };
+    if control_sender.send(message).is_err() {
+        error!(
+            "[Server Worker]: unable to announce the broker is {:?}",
+            state
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_packets::v_3_1_1::{
+        publish::fixed_header::{get_qos_level, is_retained},
+        variable::Variable,
+    };
+
+    #[test]
+    fn state_is_retained() {
+        let packet = state_packet("b1", BrokerState::Offline);
+
+        assert!(is_retained(&packet.fixed_header));
+        assert_eq!(get_qos_level(&packet.fixed_header).unwrap(), QoS::One);
+        match packet.variable {
+            Variable::Publish(variable) => {
+                assert_eq!(variable.topic_name.original, "$SYS/broker/b1/state");
+                assert_eq!(variable.payload, &b"offline"[..]);
+            }
+            _ => panic!("Publish is expected"),
+        }
+    }
+}
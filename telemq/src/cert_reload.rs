@@ -0,0 +1,149 @@
+//! Certificate of the TLS and the Websocket TLS listeners reloaded without a restart, so
+//! a certificate renewed by e.g. Let's Encrypt is served without dropping connections.
+//!
+//! `cert_file` and `key_file` are loaded again on SIGHUP and, with `tls_cert_watch_interval`
+//! set, once the modification time of either of them changes. New handshakes use the new
+//! certificate, established connections keep the one they have been accepted with. If the
+//! new files are not valid, the error is logged and the listeners keep the current certificate.
+use std::{
+    fs, io,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use log::{error, info};
+use tokio::time::sleep;
+
+use crate::{
+    config::TeleMQServerConfig,
+    tls_listener::{SharedServerConfig, TlsOptions},
+};
+
+pub struct CertReloader {
+    cert_path: String,
+    key_path: String,
+    options: TlsOptions,
+    configs: Vec<SharedServerConfig>,
+    /// Modification times of the certificate and the key once they have been loaded.
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl CertReloader {
+    /// `None` if the certificate or the key is not configured.
+    pub fn new(config: &TeleMQServerConfig) -> Option<Self> {
+        match (&config.cert_file, &config.key_file) {
+            (Some(cert_path), Some(key_path)) => Some(CertReloader {
+                cert_path: cert_path.clone(),
+                key_path: key_path.clone(),
+                options: TlsOptions::new(config),
+                configs: vec![],
+                modified: Mutex::new(modified(cert_path, key_path).ok()),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Loads a config of a listener, which is reloaded along with the others.
+    pub fn listener_config(
+        &mut self,
+        alpn_protocols: Vec<Vec<u8>>,
+    ) -> io::Result<SharedServerConfig> {
+        let config = SharedServerConfig::load(
+            &self.cert_path,
+            &self.key_path,
+            &self.options,
+            alpn_protocols,
+        )?;
+        self.configs.push(config.clone());
+        Ok(config)
+    }
+
+    pub fn reload(&self) {
+        self.files_changed();
+        for config in self.configs.iter() {
+            if let Err(err) = config.reload(&self.cert_path, &self.key_path, &self.options) {
+                error!(
+                    "[Cert Reloader]: unable to reload {}, the current certificate is kept. {:?}",
+                    self.cert_path, err
+                );
+                return;
+            }
+        }
+        info!("[Cert Reloader]: reloaded {}", self.cert_path);
+    }
+
+    /// Reloads the certificate once its files are changed.
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        loop {
+            sleep(interval).await;
+            if self.files_changed() {
+                self.reload();
+            }
+        }
+    }
+
+    /// Files which can't be read, e.g. while they are being replaced, are not changed yet.
+    fn files_changed(&self) -> bool {
+        let current = match modified(&self.cert_path, &self.key_path) {
+            Ok(current) => current,
+            Err(_) => return false,
+        };
+        let mut last = self.modified.lock().unwrap_or_else(|err| err.into_inner());
+        if *last == Some(current) {
+            return false;
+        }
+        *last = Some(current);
+        true
+    }
+}
+
+/// Symlinks are followed, so a renewal which points a link to new files is noticed too.
+fn modified(cert_path: &str, key_path: &str) -> io::Result<(SystemTime, SystemTime)> {
+    Ok((
+        fs::metadata(cert_path)?.modified()?,
+        fs::metadata(key_path)?.modified()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, path::PathBuf, time::UNIX_EPOCH};
+
+    fn touch(path: &PathBuf, secs: u64) {
+        File::create(path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn changes_of_cert_and_key_are_noticed() {
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("telemq_cert_reload_{}.crt", std::process::id()));
+        let key_path = dir.join(format!("telemq_cert_reload_{}.key", std::process::id()));
+        touch(&cert_path, 1_000);
+        touch(&key_path, 1_000);
+
+        let config = TeleMQServerConfig {
+            cert_file: Some(cert_path.to_string_lossy().into_owned()),
+            key_file: Some(key_path.to_string_lossy().into_owned()),
+            ..TeleMQServerConfig::default()
+        };
+        let reloader = CertReloader::new(&config).unwrap();
+        assert!(!reloader.files_changed());
+
+        touch(&key_path, 2_000);
+        assert!(reloader.files_changed());
+        assert!(!reloader.files_changed());
+
+        fs::remove_file(&cert_path).unwrap();
+        assert!(!reloader.files_changed());
+        touch(&cert_path, 3_000);
+        assert!(reloader.files_changed());
+
+        fs::remove_file(&cert_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+        assert!(CertReloader::new(&TeleMQServerConfig::default()).is_none());
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::disconnect_reason::DisconnectReason;
+
+/// Activity of a client id across its connections. It's kept in the Session State Store for
+/// clients with clean and persistent sessions alike, so it's available while a client is
+/// offline and its logs have been rotated.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ClientHistory {
+    /// Not persisted, all clients are offline once the broker is restarted.
+    #[serde(skip)]
+    pub online: bool,
+    pub last_connected_at: Option<SystemTime>,
+    pub last_disconnected_at: Option<SystemTime>,
+    pub last_disconnect_reason: Option<DisconnectReason>,
+    /// PUBLISH packets received from a client, counted once its connection is closed.
+    pub messages_received: u64,
+    /// PUBLISH packets sent to a client, counted once its connection is closed.
+    pub messages_sent: u64,
+}
+
+impl ClientHistory {
+    pub fn connected(&mut self, connected_at: SystemTime) {
+        self.online = true;
+        self.last_connected_at = Some(connected_at);
+    }
+
+    pub fn disconnected(
+        &mut self,
+        disconnected_at: SystemTime,
+        reason: DisconnectReason,
+        messages_received: u64,
+        messages_sent: u64,
+    ) {
+        self.online = false;
+        self.last_disconnected_at = Some(disconnected_at);
+        self.last_disconnect_reason = Some(reason);
+        self.messages_received += messages_received;
+        self.messages_sent += messages_sent;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn counters_are_cumulative_across_connections() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut history = ClientHistory::default();
+
+        history.connected(start);
+        history.disconnected(
+            start + Duration::from_secs(10),
+            DisconnectReason::KeepAliveTimeout,
+            3,
+            5,
+        );
+        history.connected(start + Duration::from_secs(20));
+
+        assert!(history.online);
+        assert_eq!(
+            history.last_connected_at,
+            Some(start + Duration::from_secs(20))
+        );
+
+        history.disconnected(
+            start + Duration::from_secs(30),
+            DisconnectReason::ClientDisconnect,
+            1,
+            0,
+        );
+
+        assert!(!history.online);
+        assert_eq!(
+            history.last_disconnect_reason,
+            Some(DisconnectReason::ClientDisconnect)
+        );
+        assert_eq!((history.messages_received, history.messages_sent), (4, 5));
+
+        // `online` is not persisted
+        let stored = serde_json::to_string(&ClientHistory {
+            online: true,
+            ..history.clone()
+        })
+        .unwrap();
+        assert_eq!(
+            serde_json::from_str::<ClientHistory>(&stored).unwrap(),
+            history
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Client ids the broker assigns to clients which connect with an empty client id.
+//!
+//! Ids are generated by `assigned_client_id_scheme` and start with `assigned_client_id_prefix`,
+//! so an id found in logs or in a store is traceable to a broker and a tenant which have
+//! assigned it. An application embedding TeleMQ may provide its own generator instead.
+use std::{
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+
+use crate::{clock::Clock, config::TeleMQServerConfig};
+
+const RANDOM_ID_LEN: usize = 16;
+const ALPHANUMERIC: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Generator of client ids, which can be provided by an application embedding TeleMQ via
+/// `ServerBuilder::with_client_id_generator`. It's called by a connection once a client
+/// connects with an empty client id, ids should be unique among connected clients.
+pub trait ClientIdGenerator: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+impl<F> ClientIdGenerator for F
+where
+    F: Fn() -> String + Send + Sync,
+{
+    fn generate(&self) -> String {
+        self()
+    }
+}
+
+/// A generator shared by all connections.
+pub type SharedClientIdGenerator = Arc<dyn ClientIdGenerator>;
+
+/// How assigned client ids are generated.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientIdScheme {
+    /// random UUID (version 4), e.g. `4b1c8e0a-5f7d-4c52-9a3e-1d2f6b7c8e9f`
+    Uuid,
+    /// 16 random alphanumeric characters
+    Random,
+    /// `{broker_id}-{unix time in milliseconds}-{sequence number}`, ids are ordered by time
+    Snowflake,
+}
+
+/// Generator of `assigned_client_id_scheme`.
+pub struct SchemeClientIdGenerator {
+    scheme: ClientIdScheme,
+    prefix: String,
+    broker_id: String,
+    clock: Clock,
+    random: SystemRandom,
+    /// Millisecond and sequence number of the last snowflake id.
+    last_snowflake: Mutex<(u128, u32)>,
+}
+
+impl SchemeClientIdGenerator {
+    pub fn new(config: &TeleMQServerConfig, clock: Clock) -> Self {
+        SchemeClientIdGenerator {
+            scheme: config.assigned_client_id_scheme,
+            prefix: config
+                .assigned_client_id_prefix
+                .replace("{broker_id}", &config.broker_id)
+                .replace("{account_id}", &config.account_id),
+            broker_id: config.broker_id.clone(),
+            clock,
+            random: SystemRandom::new(),
+            last_snowflake: Mutex::new((0, 0)),
+        }
+    }
+
+    fn random_bytes<const N: usize>(&self) -> [u8; N] {
+        let mut bytes = [0u8; N];
+        // the system random source fails only if it's not available at all
+        self.random
+            .fill(&mut bytes)
+            .expect("system random source is not available");
+        bytes
+    }
+
+    fn uuid(&self) -> String {
+        let mut bytes: [u8; 16] = self.random_bytes();
+        // version 4, variant 1
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+
+    fn random(&self) -> String {
+        let bytes: [u8; RANDOM_ID_LEN] = self.random_bytes();
+        bytes
+            .iter()
+            .map(|byte| ALPHANUMERIC[*byte as usize % ALPHANUMERIC.len()] as char)
+            .collect()
+    }
+
+    fn snowflake(&self) -> String {
+        let millis = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_millis())
+            .unwrap_or_default();
+        let mut last = self
+            .last_snowflake
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        // a clock going backwards doesn't repeat ids
+        *last = if millis > last.0 {
+            (millis, 0)
+        } else {
+            (last.0, last.1 + 1)
+        };
+        format!("{}-{}-{}", self.broker_id, last.0, last.1)
+    }
+}
+
+impl ClientIdGenerator for SchemeClientIdGenerator {
+    fn generate(&self) -> String {
+        let id = match self.scheme {
+            ClientIdScheme::Uuid => self.uuid(),
+            ClientIdScheme::Random => self.random(),
+            ClientIdScheme::Snowflake => self.snowflake(),
+        };
+        format!("{}{}", self.prefix, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn generator(scheme: ClientIdScheme, prefix: &str, clock: Clock) -> SchemeClientIdGenerator {
+        let config = TeleMQServerConfig {
+            broker_id: "node1".into(),
+            account_id: "acme".into(),
+            assigned_client_id_scheme: scheme,
+            assigned_client_id_prefix: prefix.into(),
+            ..TeleMQServerConfig::default()
+        };
+        SchemeClientIdGenerator::new(&config, clock)
+    }
+
+    #[test]
+    fn ids_have_prefix_of_broker_and_account() {
+        let uuid = generator(
+            ClientIdScheme::Uuid,
+            "{account_id}-{broker_id}-",
+            Clock::system(),
+        );
+        let id = uuid.generate();
+        assert!(id.starts_with("acme-node1-"), "{}", id);
+        assert_eq!(id.len(), "acme-node1-".len() + 36);
+        assert_eq!(&id["acme-node1-".len() + 14..][..1], "4");
+
+        let random = generator(ClientIdScheme::Random, "", Clock::system());
+        let (first, second) = (random.generate(), random.generate());
+        assert_eq!(first.len(), RANDOM_ID_LEN);
+        assert!(first.bytes().all(|byte| byte.is_ascii_alphanumeric()));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn snowflake_ids_are_unique_within_millisecond() {
+        let clock = Clock::manual(UNIX_EPOCH + Duration::from_millis(1_000));
+        let snowflake = generator(ClientIdScheme::Snowflake, "", clock.clone());
+        assert_eq!(snowflake.generate(), "node1-1000-0");
+        assert_eq!(snowflake.generate(), "node1-1000-1");
+
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(snowflake.generate(), "node1-1005-0");
+    }
+}
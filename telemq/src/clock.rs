@@ -0,0 +1,60 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// Source of wall clock time for everything the broker stamps or expires: retained messages,
+/// queued messages and connection times.
+///
+/// The system clock is used by default. A manual clock stands still until it's advanced, so
+/// tests (and an embedding application) can fast-forward time deterministically instead of
+/// sleeping. Monotonic timers (Keep Alive, retries) use `tokio::time` and are controlled
+/// with `tokio::time::pause` and `tokio::time::advance` instead.
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    /// `None` for the system clock.
+    manual: Option<Arc<Mutex<SystemTime>>>,
+}
+
+impl Clock {
+    pub fn system() -> Self {
+        Clock { manual: None }
+    }
+
+    /// A clock which shows `start` until it's advanced. Clones share the same time.
+    pub fn manual(start: SystemTime) -> Self {
+        Clock {
+            manual: Some(Arc::new(Mutex::new(start))),
+        }
+    }
+
+    pub fn now(&self) -> SystemTime {
+        match self.manual {
+            Some(ref time) => *time.lock().unwrap(),
+            None => SystemTime::now(),
+        }
+    }
+
+    /// Moves a manual clock forward, does nothing for the system clock.
+    pub fn advance(&self, by: Duration) {
+        if let Some(ref time) = self.manual {
+            *time.lock().unwrap() += by;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_is_shared_by_clones() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Clock::manual(start);
+        let clone = clock.clone();
+
+        clone.advance(Duration::from_secs(90));
+
+        assert_eq!(clock.now(), start + Duration::from_secs(90));
+    }
+}
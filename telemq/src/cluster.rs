@@ -0,0 +1,435 @@
+//! Cluster of brokers sharing subscriptions of their clients.
+//!
+//! Brokers with the same `cluster_id` listen for peer links on `cluster_port` and connect to
+//! every address of `cluster_peers`. A link is one-way: a broker which has connected to a peer
+//! forwards publishes to it, while the peer sends back a digest of topic filters its clients
+//! are subscribed to whenever it changes. A publish is forwarded only to peers with matching
+//! subscribers, so a client may connect to any broker of a cluster.
+//!
+//! Messages received from a peer are published locally with a `cluster` metadata key and are
+//! never forwarded again, so every broker should list all other brokers as its peers (full
+//! mesh). Peers exchange newline delimited JSON over plain TCP, messages are forwarded at most
+//! once and without the retain flag: retained messages and persistent sessions stay on a broker
+//! a client is connected to.
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::SinkExt;
+use log::{info, warn};
+use mqtt_packets::v_3_1_1::QoS;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select, spawn,
+    time::{interval, sleep, timeout, Instant},
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::{
+    broker_handle::{recv_local, BrokerHandle, InProcessMessage, InProcessSubscriber},
+    config::TeleMQServerConfig,
+    publish_metadata::PublishMetadata,
+};
+
+/// Metadata key of messages received from a peer, its value is a peer broker id.
+pub const CLUSTER_METADATA_KEY: &str = "cluster";
+/// Client id prefix of in-process clients which subscribe to topic filters of peers.
+const CLUSTER_CLIENT_PREFIX: &str = "$cluster/";
+/// Max time to establish a link and to exchange hellos.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// Local subscriptions are checked for changes this often.
+const DIGEST_INTERVAL: Duration = Duration::from_secs(1);
+/// An unchanged digest is re-sent this often, so a peer knows the link is alive.
+const DIGEST_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+/// A link is considered lost if no digest has been received for this long.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_PEER_MESSAGE_LENGTH: usize = 16 * 1024 * 1024;
+
+type PeerLink = Framed<TcpStream, LinesCodec>;
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PeerMessage {
+    Hello {
+        cluster_id: String,
+        broker_id: String,
+    },
+    /// Topic filters clients of a broker are subscribed to.
+    Subscriptions { filters: Vec<String> },
+    Publish {
+        topic: String,
+        /// Base64 encoded.
+        payload: String,
+        qos: u8,
+    },
+}
+
+pub struct Cluster {
+    cluster_id: String,
+    broker_id: String,
+    peers: Vec<String>,
+    broker: BrokerHandle,
+}
+
+impl Cluster {
+    pub fn new(config: &TeleMQServerConfig, broker: BrokerHandle) -> Self {
+        Cluster {
+            cluster_id: config.cluster_id.clone(),
+            broker_id: config.broker_id.clone(),
+            peers: config.cluster_peers.clone(),
+            broker,
+        }
+    }
+
+    /// Starts listening for peer links and connects to peers.
+    pub async fn start(self, addr: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("[Cluster]: listening for peers on {}", addr);
+
+        let cluster = Arc::new(self);
+        for address in cluster.peers.iter().cloned() {
+            spawn(cluster.clone().connect_peer(address));
+        }
+        spawn(cluster.accept_peers(listener));
+
+        Ok(())
+    }
+
+    async fn accept_peers(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    spawn(self.clone().serve_peer(stream, addr));
+                }
+                Err(err) => {
+                    warn!("[Cluster]: unable to accept a peer link. {:?}", err);
+                    sleep(DIGEST_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn serve_peer(self: Arc<Self>, stream: TcpStream, addr: SocketAddr) {
+        let mut link = Framed::new(
+            stream,
+            LinesCodec::new_with_max_length(MAX_PEER_MESSAGE_LENGTH),
+        );
+        match self.receive_publishes(&mut link).await {
+            Ok(()) => info!("[Cluster]: link from {} is closed", addr),
+            Err(err) => warn!("[Cluster]: link from {} failed. {}", addr, err),
+        }
+    }
+
+    /// Publishes messages a peer forwards and keeps the peer informed about local
+    /// subscriptions. Returns `Ok` once the peer or the broker closes the link.
+    async fn receive_publishes(&self, link: &mut PeerLink) -> io::Result<()> {
+        let peer_id = match timeout(CONNECT_TIMEOUT, recv_message(link)).await {
+            Ok(message) => self.check_hello(message?)?,
+            Err(_) => return Err(timed_out("hello timed out")),
+        };
+        send_message(link, &self.hello()).await?;
+        info!("[Cluster]: receiving publishes from {}", peer_id);
+
+        let mut digest_timer = interval(DIGEST_INTERVAL);
+        let mut sent_digest: Option<(Vec<String>, Instant)> = None;
+        loop {
+            select! {
+                _ = digest_timer.tick() => {
+                    let filters = match self.broker.dump_subscriptions().await {
+                        Ok(entries) => digest(entries),
+                        // the broker is shutting down
+                        Err(_) => return Ok(()),
+                    };
+                    let is_fresh = sent_digest.as_ref().is_some_and(|(sent, sent_at)| {
+                        *sent == filters && sent_at.elapsed() < DIGEST_REFRESH_INTERVAL
+                    });
+                    if !is_fresh {
+                        send_message(link, &PeerMessage::Subscriptions { filters: filters.clone() }).await?;
+                        sent_digest = Some((filters, Instant::now()));
+                    }
+                }
+                line = link.next() => match line {
+                    Some(line) => {
+                        let message = parse_message(&line.map_err(io::Error::other)?)?;
+                        self.publish_locally(&peer_id, message)?;
+                    }
+                    None => return Ok(()),
+                }
+            }
+        }
+    }
+
+    fn publish_locally(&self, peer_id: &str, message: PeerMessage) -> io::Result<()> {
+        match message {
+            PeerMessage::Publish {
+                topic,
+                payload,
+                qos,
+            } => {
+                let payload = STANDARD
+                    .decode(payload)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                let mut metadata = PublishMetadata::new();
+                metadata.insert(CLUSTER_METADATA_KEY, peer_id);
+                self.broker
+                    .publish_with_metadata(topic, payload, QoS::try_from(qos)?, false, metadata)
+                    .map_err(|err| io::Error::other(err.0))
+            }
+            message => Err(unexpected(&message)),
+        }
+    }
+
+    async fn connect_peer(self: Arc<Self>, address: String) {
+        loop {
+            match self.forward_publishes(&address).await {
+                Ok(()) => {
+                    info!("[Cluster]: stopped forwarding publishes to {}", address);
+                    return;
+                }
+                Err(err) => {
+                    warn!(
+                        "[Cluster]: link to {} failed. {}. Reconnecting in {:?}",
+                        address, err, RECONNECT_INTERVAL
+                    );
+                }
+            }
+            sleep(RECONNECT_INTERVAL).await;
+        }
+    }
+
+    /// Forwards publishes matching subscriptions of a peer. Returns `Ok` once the broker
+    /// shuts down.
+    async fn forward_publishes(&self, address: &str) -> io::Result<()> {
+        let mut link = timeout(CONNECT_TIMEOUT, self.connect(address))
+            .await
+            .map_err(|_| timed_out("connection timed out"))??;
+        let peer_id = match recv_message(&mut link).await {
+            Ok(message) => self.check_hello(message)?,
+            Err(err) => return Err(err),
+        };
+        info!(
+            "[Cluster]: forwarding publishes to {} ({})",
+            peer_id, address
+        );
+
+        let mut filters: Vec<String> = vec![];
+        let mut subscriber: Option<InProcessSubscriber> = None;
+        loop {
+            select! {
+                line = timeout(PEER_TIMEOUT, link.next()) => match line {
+                    Ok(Some(line)) => match parse_message(&line.map_err(io::Error::other)?)? {
+                        PeerMessage::Subscriptions { filters: peer_filters } => {
+                            if peer_filters != filters {
+                                // dropped first, otherwise a new subscriber would take over
+                                // the client id and the old one would disconnect it
+                                drop(subscriber.take());
+                                subscriber = self.subscribe(&peer_id, &peer_filters)?;
+                                filters = peer_filters;
+                            }
+                        }
+                        message => return Err(unexpected(&message)),
+                    },
+                    Ok(None) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "peer closed the link",
+                        ))
+                    }
+                    Err(_) => return Err(timed_out("peer has not sent subscriptions")),
+                },
+                message = recv_local(&mut subscriber) => match message {
+                    Some(message) => {
+                        if let Some(publish) = to_forward(message) {
+                            send_message(&mut link, &publish).await?;
+                        }
+                    }
+                    None => return Ok(()),
+                }
+            }
+        }
+    }
+
+    async fn connect(&self, address: &str) -> io::Result<PeerLink> {
+        let stream = TcpStream::connect(address).await?;
+        let mut link = Framed::new(
+            stream,
+            LinesCodec::new_with_max_length(MAX_PEER_MESSAGE_LENGTH),
+        );
+        send_message(&mut link, &self.hello()).await?;
+        Ok(link)
+    }
+
+    fn subscribe(
+        &self,
+        peer_id: &str,
+        filters: &[String],
+    ) -> io::Result<Option<InProcessSubscriber>> {
+        if filters.is_empty() {
+            return Ok(None);
+        }
+        self.broker
+            .subscribe(format!("{}{}", CLUSTER_CLIENT_PREFIX, peer_id), filters)
+            .map(Some)
+            .map_err(|err| io::Error::other(err.0))
+    }
+
+    fn hello(&self) -> PeerMessage {
+        PeerMessage::Hello {
+            cluster_id: self.cluster_id.clone(),
+            broker_id: self.broker_id.clone(),
+        }
+    }
+
+    /// Returns a broker id of a peer.
+    fn check_hello(&self, message: PeerMessage) -> io::Result<String> {
+        match message {
+            PeerMessage::Hello {
+                cluster_id,
+                broker_id,
+            } => {
+                if cluster_id != self.cluster_id {
+                    Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("peer {} belongs to cluster {}", broker_id, cluster_id),
+                    ))
+                } else if broker_id == self.broker_id {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("peer has the same broker_id {}", broker_id),
+                    ))
+                } else {
+                    Ok(broker_id)
+                }
+            }
+            message => Err(unexpected(&message)),
+        }
+    }
+}
+
+/// Topic filters of local clients, subscriptions made on behalf of peers are left out.
+fn digest(entries: Vec<(String, Vec<String>)>) -> Vec<String> {
+    let mut filters: Vec<String> = entries
+        .into_iter()
+        .filter(|(_, subscribers)| {
+            subscribers
+                .iter()
+                .any(|subscriber| !subscriber.starts_with(CLUSTER_CLIENT_PREFIX))
+        })
+        .map(|(filter, _)| filter)
+        .collect();
+    filters.sort();
+    filters
+}
+
+/// Messages received from peers are never forwarded again and retained messages are sent to
+/// new local subscriptions only.
+fn to_forward(message: InProcessMessage) -> Option<PeerMessage> {
+    if message.metadata.get(CLUSTER_METADATA_KEY).is_some() || message.retained_for.is_some() {
+        return None;
+    }
+    Some(PeerMessage::Publish {
+        topic: message.topic,
+        payload: STANDARD.encode(message.payload),
+        qos: message.qos.bits(),
+    })
+}
+
+async fn send_message(link: &mut PeerLink, message: &PeerMessage) -> io::Result<()> {
+    let line = serde_json::to_string(message)?;
+    link.send(line).await.map_err(io::Error::other)
+}
+
+async fn recv_message(link: &mut PeerLink) -> io::Result<PeerMessage> {
+    match link.next().await {
+        Some(line) => parse_message(&line.map_err(io::Error::other)?),
+        None => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "peer closed the link",
+        )),
+    }
+}
+
+fn parse_message(line: &str) -> io::Result<PeerMessage> {
+    serde_json::from_str(line).map_err(Into::into)
+}
+
+fn unexpected(message: &PeerMessage) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected peer message {:?}", message),
+    )
+}
+
+fn timed_out(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use serde_json::json;
+
+    #[test]
+    fn peer_message_shapes() {
+        let publish = PeerMessage::Publish {
+            topic: "devices/1/status".into(),
+            payload: STANDARD.encode(b"online"),
+            qos: 1,
+        };
+        assert_eq!(
+            serde_json::to_value(&publish).unwrap(),
+            json!({"type": "publish", "topic": "devices/1/status", "payload": "b25saW5l", "qos": 1})
+        );
+        assert_eq!(
+            parse_message(r#"{"type":"subscriptions","filters":["a/#"]}"#).unwrap(),
+            PeerMessage::Subscriptions {
+                filters: vec!["a/#".into()]
+            }
+        );
+    }
+
+    #[test]
+    fn digest_leaves_out_subscriptions_of_peers() {
+        let entries = vec![
+            ("b/+".to_string(), vec!["device".to_string()]),
+            ("c".to_string(), vec!["$cluster/node-2".to_string()]),
+            (
+                "a/#".to_string(),
+                vec!["$cluster/node-2".to_string(), "$bridge/site".to_string()],
+            ),
+        ];
+
+        assert_eq!(digest(entries), vec!["a/#".to_string(), "b/+".to_string()]);
+    }
+
+    #[test]
+    fn only_new_local_messages_are_forwarded() {
+        let message = |metadata: PublishMetadata, retained_for: Option<String>| InProcessMessage {
+            topic: "a/b".into(),
+            payload: Bytes::from_static(b"1"),
+            qos: QoS::One,
+            retain: true,
+            metadata,
+            retained_for,
+        };
+        let mut from_peer = PublishMetadata::new();
+        from_peer.insert(CLUSTER_METADATA_KEY, "node-2");
+
+        assert_eq!(
+            to_forward(message(PublishMetadata::new(), None)),
+            Some(PeerMessage::Publish {
+                topic: "a/b".into(),
+                payload: STANDARD.encode(b"1"),
+                qos: 1,
+            })
+        );
+        assert_eq!(to_forward(message(from_peer, None)), None);
+        assert_eq!(
+            to_forward(message(PublishMetadata::new(), Some("a/#".into()))),
+            None
+        );
+    }
+}
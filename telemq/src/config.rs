@@ -1,4 +1,6 @@
 use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
     fs::read_to_string as read_file,
     io::Error as IoError,
     net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
@@ -8,10 +10,27 @@ use std::{
 };
 
 use ipnet::IpNet;
+use mqtt_packets::v_3_1_1::topic::{Subscription, Topic};
 use regex::Regex;
 use serde::Deserialize;
-use serde_json::{from_str as json_from_str, Error as JsonError};
-use toml::{de::Error as TomlError, from_str as toml_from_str};
+use serde_json::{
+    from_str as json_from_str, to_value as json_to_value, Error as JsonError, Value as JsonValue,
+};
+use tokio_rustls::rustls::SupportedCipherSuite;
+use toml::{de::Error as TomlError, from_str as toml_from_str, Value as TomlValue};
+
+use crate::{
+    admin_api::PayloadEncoding,
+    bootstrap::bootstrap_message,
+    client_id_generator::ClientIdScheme,
+    config_schema::{config_schema, unknown_fields},
+    connection_channel::ChannelFullPolicy,
+    connection_gate::ConnectionTransport,
+    quiet_hours::minute_of_day,
+    session_state::QueueOverflowPolicy,
+    tls_listener::{cipher_suite, TlsVersion},
+    ws_listener::HEALTH_PATH,
+};
 
 type OptPort = Option<u16>;
 type OptUsize = Option<usize>;
@@ -26,40 +45,410 @@ pub struct TeleMQServerConfigSrc {
     pub broker_id: OptString,
     pub cluster_id: OptString,
     pub account_id: OptString,
+    pub assigned_client_id_scheme: Option<ClientIdScheme>,
+    /// `{broker_id}` and `{account_id}` are replaced
+    pub assigned_client_id_prefix: OptString,
     pub max_connections: OptUsize,
+    pub connection_drain_rate: OptUsize,
     pub tcp_port: OptPort,
     pub tls_port: OptPort,
     pub cert_file: OptString,
     pub key_file: OptString,
+    pub tls_fingerprint: OptBool,
+    pub tls_alpn_protocols: OptList<String>,
+    pub tls_alpn_required: OptBool,
+    /// apply to both TLS and Websocket TLS listeners
+    pub tls_min_version: Option<TlsVersion>,
+    pub tls_cipher_suites: OptList<String>,
+    pub tls_cert_watch_interval: OptDuration,
     pub ws_port: OptPort,
     pub wss_port: OptPort,
+    pub mqttsn_port: OptPort,
+    /// full socket addresses, e.g. 127.0.0.1:1883 or [::]:1883, instead of ports
+    pub tcp_bind: OptSocketAddr,
+    pub tls_bind: OptSocketAddr,
+    pub ws_bind: OptSocketAddr,
+    pub wss_bind: OptSocketAddr,
+    pub mqttsn_bind: OptSocketAddr,
+    pub ws_path: OptString,
+    pub ws_subprotocols: OptList<String>,
+    pub ws_ping_interval: OptDuration,
+    pub ws_pong_as_activity: OptBool,
     pub activity_check_interval: OptDuration,
     pub backup_interval: OptDuration,
     pub keep_alive: OptDuration,
     /// stdout, stderr, file:telemq.log
     pub log_dest: OptString,
     pub log_level: OptString,
+    pub log_format: Option<LogFormat>,
+    /// log target => level, e.g. `"telemq::auth" = "debug"`
+    pub log_targets: Option<BTreeMap<String, String>>,
     pub max_packet_size: OptUsize,
     pub max_subs_per_client: OptUsize,
+    pub subscription_blacklist: OptList<String>,
     pub max_storage_duration: OptDuration,
     pub anonymous_allowed: OptBool,
     pub auth_endpoint: OptString,
+    pub auth_endpoint_timeout: OptDuration,
+    pub auth_endpoint_max_retries: OptUsize,
+    pub auth_endpoint_cache_ttl: OptDuration,
+    pub auth_endpoint_negative_cache_ttl: OptDuration,
+    pub auth_unreachable_policy: Option<AuthUnreachablePolicy>,
+    pub auth_unreachable_topics: OptList<String>,
     pub auth_file: OptString,
+    pub auth_file_shadow: OptString,
+    pub auth_jwt: Option<JwtAuthConfig>,
     pub sys_topics_update_interval: OptDuration,
+    pub sys_topics_per_client: OptBool,
+    pub transport_byte_counters: OptBool,
     pub session_state_store_url: OptString,
     pub admin_api_port: OptPort,
+    pub admin_api_bind: OptSocketAddr,
+    pub admin_api_tls: OptBool,
+    pub admin_api_auth: OptList<AdminApiCredentials>,
+    pub metrics_port: OptPort,
     pub ip_whitelist: OptList<String>,
+    pub reject_on_session_recovery_failure: OptBool,
+    pub handover_socket: OptString,
+    pub tcp_bandwidth_limit: OptUsize,
+    pub tls_bandwidth_limit: OptUsize,
+    pub ws_bandwidth_limit: OptUsize,
+    pub wss_bandwidth_limit: OptUsize,
+    pub tcp_topic_prefix: OptString,
+    pub tls_topic_prefix: OptString,
+    pub ws_topic_prefix: OptString,
+    pub wss_topic_prefix: OptString,
+    pub topic_strip_trailing_slash: OptBool,
+    pub topic_collapse_separators: OptBool,
+    pub topic_case_insensitive: OptBool,
+    pub payload_size_buckets: OptList<usize>,
+    pub publish_disconnect_reason: OptBool,
+    pub wait_for_state_store: OptBool,
+    pub wait_for_auth_endpoint: OptBool,
+    pub startup_wait_timeout: OptDuration,
+    pub startup_wait_retry_interval: OptDuration,
+    pub retained_store_file: OptString,
+    pub internal_subscriptions_file: OptString,
+    pub retained_max_qos: Option<u8>,
+    pub erase_retained_topics: OptList<String>,
+    pub max_queued_messages_per_client: OptUsize,
+    pub queue_overflow_policy: Option<QueueOverflowPolicy>,
+    pub queue_qos0_messages: OptBool,
+    pub session_expiry_interval: OptDuration,
+    pub message_expiry_interval: OptDuration,
+    pub will_delay_interval: OptDuration,
+    pub retry_interval: OptDuration,
+    pub max_retries: OptUsize,
+    pub max_inflight_messages: OptUsize,
+    pub connection_channel_capacity: OptUsize,
+    pub connection_channel_full_policy: Option<ChannelFullPolicy>,
+    pub subscription_tree_warning_nodes: OptUsize,
+    pub routing_cache_size: OptUsize,
+    pub time_sync_topic: OptString,
+    pub time_sync_interval: OptDuration,
+    pub time_sync_request_topic: OptString,
+    pub queue_depth_request_topic: OptString,
+    pub webhook_url: OptString,
+    pub webhook_outbox_file: OptString,
+    pub webhook_outbox_size: OptUsize,
+    pub webhook_retry_max_interval: OptDuration,
+    pub webhook_timeout: OptDuration,
+    pub cluster_port: OptPort,
+    pub cluster_peers: OptList<String>,
+    pub bridge: OptList<BridgeConfig>,
+    pub retained_bypass: OptList<RetainedBypassConfig>,
+    /// megabytes
+    pub load_shedding_rss_watermark: OptUsize,
+    /// milliseconds
+    pub load_shedding_lag_watermark: OptDuration,
+    pub load_shedding_topics: OptList<String>,
+    pub priority_topics: OptList<PriorityTopicConfig>,
+    pub priority_starvation_limit: OptUsize,
+    pub quiet_hours: OptList<QuietHoursConfig>,
+    pub bootstrap: OptList<BootstrapConfig>,
+    pub payload_formats: OptList<PayloadFormatConfig>,
+    pub payload_dead_letter_topic: OptString,
+}
+
+/// Direction in which messages of a bridged topic are relayed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeDirection {
+    /// from the remote broker to TeleMQ
+    In,
+    /// from TeleMQ to the remote broker
+    Out,
+    Both,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BridgeTopicConfig {
+    /// topic filter, relative to prefixes
+    pub pattern: String,
+    pub direction: BridgeDirection,
+    /// max QoS of relayed messages, 0 or 1
+    pub qos: Option<u8>,
+    /// prepended to `pattern` on the TeleMQ side
+    pub local_prefix: OptString,
+    /// prepended to `pattern` on the remote broker side
+    pub remote_prefix: OptString,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct BridgeConfig {
+    pub name: String,
+    /// host:port of the remote broker
+    pub address: String,
+    pub client_id: OptString,
+    pub username: OptString,
+    pub password: OptString,
+    pub keep_alive: OptDuration,
+    pub reconnect_interval: OptDuration,
+    /// If true, the remote broker publishes a retained `offline` broker state of this broker
+    /// once the bridge connection is lost
+    pub broker_state_will: OptBool,
+    pub topics: Vec<BridgeTopicConfig>,
+}
+
+// the config is logged at start up, so the password is not printed
+impl fmt::Debug for BridgeConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BridgeConfig")
+            .field("name", &self.name)
+            .field("address", &self.address)
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("keep_alive", &self.keep_alive)
+            .field("reconnect_interval", &self.reconnect_interval)
+            .field("broker_state_will", &self.broker_state_will)
+            .field("topics", &self.topics)
+            .finish()
+    }
+}
+
+/// Whether retained messages are sent when a subscription is made, similarly to
+/// Retain Handling of MQTT 5 subscription options.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetainHandling {
+    /// only if a client hasn't been subscribed to the same filter yet, e.g. by
+    /// a persistent session
+    SendIfNew,
+    DoNotSend,
+}
+
+/// A rule which suppresses retained messages sent to new subscriptions. If both `filter` and
+/// `client_id_prefix` are set, both have to match.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RetainedBypassConfig {
+    /// topic filter matching topics of retained messages
+    pub filter: OptString,
+    pub client_id_prefix: OptString,
+    pub retain_handling: Option<RetainHandling>,
+}
+
+/// Class of messages served ahead of lower ones by Control Worker and connections.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+/// A priority class of messages published to topics matching `filter`. If several filters
+/// match a topic, the highest priority applies.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PriorityTopicConfig {
+    pub filter: String,
+    pub priority: Priority,
+}
+
+/// Format of payloads of messages published to topics of a `payload_formats` rule.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PayloadFormat {
+    /// valid UTF-8
+    Utf8,
+    /// valid UTF-8 JSON
+    Json,
+}
+
+/// A format payloads of messages published by clients to topics matching `filter` should
+/// have. If several filters match a topic, the first one applies.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PayloadFormatConfig {
+    pub filter: String,
+    pub format: PayloadFormat,
+}
+
+/// A daily window during which messages which are not `high` priority are held for clients
+/// which ids start with `client_id_prefix`, all clients if it's not set. `start` and `end`
+/// are UTC times of day, `HH:MM`, a window ending before it starts spans midnight.
+#[derive(Deserialize, Debug, Clone)]
+pub struct QuietHoursConfig {
+    /// name of a group of clients, e.g. `battery`, used in logs
+    pub tag: String,
+    pub client_id_prefix: OptString,
+    pub start: String,
+    pub end: String,
+}
+
+/// A retained message published to a topic of a new client. `topic` should contain `%c`
+/// (or `{client_id}`), which is replaced by the client id.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BootstrapMessageConfig {
+    pub topic: String,
+    pub payload: String,
+    /// `utf8` if omitted
+    pub encoding: Option<PayloadEncoding>,
+    /// 0 if omitted
+    pub qos: Option<u8>,
+}
+
+/// Retained messages published once a client which id starts with `client_id_prefix` (any
+/// client if it's not set) connects for the first time: `messages` and messages returned by
+/// `endpoint`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BootstrapConfig {
+    /// name of a group of clients, e.g. `thermostat`, sent to `endpoint`
+    pub tag: String,
+    pub client_id_prefix: OptString,
+    /// an http(s) URL messages are fetched from
+    pub endpoint: OptString,
+    pub messages: OptList<BootstrapMessageConfig>,
+}
+
+/// Access Admin API credentials are granted.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AdminApiRole {
+    /// only GET requests
+    ReadOnly,
+    Admin,
+}
+
+/// Credentials of an Admin API client, either a bearer `token` or a `username` and
+/// a `password` of basic authentication.
+#[derive(Deserialize, Clone)]
+pub struct AdminApiCredentials {
+    pub token: OptString,
+    pub username: OptString,
+    pub password: OptString,
+    pub role: AdminApiRole,
+    /// path prefixes of allowed routes without `/v1`, e.g. `/devices`. If None => all routes
+    pub routes: OptList<String>,
+}
+
+// the config is logged at start up, so secrets are not printed
+impl fmt::Debug for AdminApiCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminApiCredentials")
+            .field("token", &self.token.as_ref().map(|_| "***"))
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("role", &self.role)
+            .field("routes", &self.routes)
+            .finish()
+    }
+}
+
+/// Format of log records.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// plain text lines
+    Text,
+    /// a JSON object per line with a timestamp, level, module, message and, for records of
+    /// a client connection, its client id and address
+    Json,
+}
+
+/// How clients are authenticated while `auth_endpoint` is unreachable.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthUnreachablePolicy {
+    /// clients are rejected with CONNACK "Server unavailable"
+    Deny,
+    /// clients are accepted, but may use only `auth_unreachable_topics`
+    AllowAnonymousTopicsOnly,
+    /// clients allowed by the endpoint earlier are accepted with the same credentials and
+    /// topic rules, the rest are rejected as with `Deny`
+    AllowCachedKnownClients,
+}
+
+/// Signature algorithm of JWTs accepted by `auth_jwt`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    HS256,
+    HS384,
+    HS512,
+    RS256,
+    RS384,
+    RS512,
+    ES256,
+    ES384,
+}
+
+impl JwtAlgorithm {
+    /// HMAC algorithms are verified with a shared secret, the rest with a public key.
+    pub fn is_hmac(&self) -> bool {
+        matches!(self, Self::HS256 | Self::HS384 | Self::HS512)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct JwtAuthConfig {
+    pub algorithm: JwtAlgorithm,
+    /// shared secret of HS* algorithms
+    pub secret: OptString,
+    /// path to a PEM public key of RS* and ES* algorithms
+    pub public_key_file: OptString,
+    /// claim which should be equal to a client id, `sub` by default
+    pub client_id_claim: OptString,
+    /// claim with topic rules, `acl` by default
+    pub acl_claim: OptString,
+    /// expected `iss` claim
+    pub issuer: OptString,
+    /// expected `aud` claim
+    pub audience: OptString,
+}
+
+// the config is logged at start up, so the secret is not printed
+impl fmt::Debug for JwtAuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtAuthConfig")
+            .field("algorithm", &self.algorithm)
+            .field("secret", &self.secret.as_ref().map(|_| "***"))
+            .field("public_key_file", &self.public_key_file)
+            .field("client_id_claim", &self.client_id_claim)
+            .field("acl_claim", &self.acl_claim)
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .finish()
+    }
 }
 
 impl TeleMQServerConfigSrc {
     pub const LOG_DEST_STDOUT: &'static str = "stdout";
     pub const LOG_DEST_STDERR: &'static str = "stderr";
     pub const LOG_DEST_FILE_REGEX: &'static str = "^(file:)";
-    pub const LOG_LEVEL: &'static [&'static str; 4] = &["error", "warn", "info", "debug"];
+    pub const LOG_LEVEL: &'static [&'static str; 5] = &["error", "warn", "info", "debug", "trace"];
     const FILE_TOML_EXTENSION: &'static str = "toml";
     const FILE_JSON_EXTENSION: &'static str = "json";
 
     pub fn from_file<P: AsRef<Path>>(path: P) -> ConfigResult<Self> {
+        Self::read(path, false)
+    }
+
+    /// The same as `from_file`, but fields missing from the config schema, e.g. misspelled
+    /// ones, are errors rather than ignored.
+    pub fn from_file_strict<P: AsRef<Path>>(path: P) -> ConfigResult<Self> {
+        Self::read(path, true)
+    }
+
+    fn read<P: AsRef<Path>>(path: P, strict: bool) -> ConfigResult<Self> {
         let config_file_content = read_file(&path)?;
         let config_file_extension = path.as_ref().extension().and_then(|os_str| os_str.to_str());
         let config_src: TeleMQServerConfigSrc = match config_file_extension {
@@ -69,6 +458,19 @@ impl TeleMQServerConfigSrc {
                 unimplemented!();
             }
         };
+        if strict {
+            // the content has been parsed already
+            let content: JsonValue = match config_file_extension {
+                Some(Self::FILE_TOML_EXTENSION) => {
+                    json_to_value(toml_from_str::<TomlValue>(&config_file_content)?)?
+                }
+                _ => json_from_str(&config_file_content)?,
+            };
+            let unknown = unknown_fields(&content, &config_schema());
+            if !unknown.is_empty() {
+                return Err(TeleMQServerConfigError::UnknownFields(unknown));
+            }
+        }
         Self::validate(&config_src)?;
         Ok(config_src)
     }
@@ -76,18 +478,76 @@ impl TeleMQServerConfigSrc {
     fn validate(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
         Self::validate_log_dest(&config_src.log_dest)
             .and_then(|_| Self::validate_log_level(&config_src.log_level))
+            .and_then(|_| Self::validate_log_targets(&config_src.log_targets))
             .and_then(|_| {
                 Self::validate_auth(
                     &config_src.anonymous_allowed,
                     &config_src.auth_file,
                     &config_src.auth_endpoint,
+                    &config_src.auth_jwt,
                 )
             })
+            .and_then(|_| Self::validate_auth_endpoint(config_src))
             .and_then(|_| Self::validate_state_store_url(&config_src.session_state_store_url))
             .and_then(|_| Self::validate_broker_id(&config_src.broker_id))
             .and_then(|_| Self::validate_cluster_id(&config_src.cluster_id))
             .and_then(|_| Self::validate_account_id(&config_src.account_id))
             .and_then(|_| Self::validate_ip_whitelist(&config_src.ip_whitelist))
+            .and_then(|_| {
+                Self::validate_bandwidth_limits(&[
+                    ("tcp_bandwidth_limit", &config_src.tcp_bandwidth_limit),
+                    ("tls_bandwidth_limit", &config_src.tls_bandwidth_limit),
+                    ("ws_bandwidth_limit", &config_src.ws_bandwidth_limit),
+                    ("wss_bandwidth_limit", &config_src.wss_bandwidth_limit),
+                ])
+            })
+            .and_then(|_| {
+                Self::validate_topic_prefixes(&[
+                    ("tcp_topic_prefix", &config_src.tcp_topic_prefix),
+                    ("tls_topic_prefix", &config_src.tls_topic_prefix),
+                    ("ws_topic_prefix", &config_src.ws_topic_prefix),
+                    ("wss_topic_prefix", &config_src.wss_topic_prefix),
+                ])
+            })
+            .and_then(|_| Self::validate_payload_size_buckets(&config_src.payload_size_buckets))
+            .and_then(|_| Self::validate_startup_wait(config_src))
+            .and_then(|_| Self::validate_bridges(&config_src.bridge))
+            .and_then(|_| Self::validate_retained_bypass(&config_src.retained_bypass))
+            .and_then(|_| Self::validate_retained_max_qos(&config_src.retained_max_qos))
+            .and_then(|_| Self::validate_erase_retained_topics(&config_src.erase_retained_topics))
+            .and_then(|_| Self::validate_will_delay_interval(&config_src.will_delay_interval))
+            .and_then(|_| {
+                Self::validate_message_expiry_interval(&config_src.message_expiry_interval)
+            })
+            .and_then(|_| Self::validate_time_sync(config_src))
+            .and_then(|_| Self::validate_queue_depth(&config_src.queue_depth_request_topic))
+            .and_then(|_| Self::validate_webhook(config_src))
+            .and_then(|_| Self::validate_binds(config_src))
+            .and_then(|_| Self::validate_metrics_port(config_src))
+            .and_then(|_| Self::validate_admin_api(config_src))
+            .and_then(|_| {
+                Self::validate_max_queued_messages(&config_src.max_queued_messages_per_client)
+            })
+            .and_then(|_| Self::validate_connection_limit(config_src))
+            .and_then(|_| Self::validate_retry_interval(&config_src.retry_interval))
+            .and_then(|_| Self::validate_max_inflight_messages(&config_src.max_inflight_messages))
+            .and_then(|_| {
+                Self::validate_connection_channel_capacity(&config_src.connection_channel_capacity)
+            })
+            .and_then(|_| Self::validate_cluster(config_src))
+            .and_then(|_| Self::validate_tls_alpn(config_src))
+            .and_then(|_| Self::validate_tls_cipher_suites(config_src))
+            .and_then(|_| Self::validate_tls_cert_watch_interval(config_src))
+            .and_then(|_| Self::validate_ws(config_src))
+            .and_then(|_| Self::validate_load_shedding(config_src))
+            .and_then(|_| Self::validate_subscription_blacklist(&config_src.subscription_blacklist))
+            .and_then(|_| Self::validate_priority_topics(config_src))
+            .and_then(|_| Self::validate_quiet_hours(&config_src.quiet_hours))
+            .and_then(|_| Self::validate_bootstrap(&config_src.bootstrap))
+            .and_then(|_| Self::validate_payload_formats(config_src))
+            .and_then(|_| {
+                Self::validate_assigned_client_id_prefix(&config_src.assigned_client_id_prefix)
+            })
     }
 
     fn validate_log_dest(maybe_log_dest: &OptString) -> ConfigResult<()> {
@@ -132,11 +592,47 @@ impl TeleMQServerConfigSrc {
         }
     }
 
+    fn validate_log_targets(
+        maybe_log_targets: &Option<BTreeMap<String, String>>,
+    ) -> ConfigResult<()> {
+        for (target, log_level) in maybe_log_targets.iter().flatten() {
+            if target.is_empty() {
+                return Err(TeleMQServerConfigError::WrongValue(
+                    "log_targets should not contain an empty target".into(),
+                ));
+            }
+            Self::validate_log_level(&Some(log_level.clone()))?;
+        }
+        Ok(())
+    }
+
     fn validate_auth(
         anonymous_allowed: &OptBool,
         auth_file: &OptString,
         auth_endpoint: &OptString,
+        auth_jwt: &Option<JwtAuthConfig>,
     ) -> ConfigResult<()> {
+        if let Some(jwt) = auth_jwt {
+            if auth_file.is_some() || auth_endpoint.is_some() {
+                return Err(TeleMQServerConfigError::WrongValue(
+                    "auth_jwt cannot be combined with auth_file or auth_endpoint".into(),
+                ));
+            }
+            if jwt.algorithm.is_hmac() && jwt.secret.as_ref().is_none_or(|secret| secret.is_empty())
+            {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "auth_jwt algorithm {:?} requires a secret",
+                    jwt.algorithm
+                )));
+            }
+            if !jwt.algorithm.is_hmac() && jwt.public_key_file.is_none() {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "auth_jwt algorithm {:?} requires a public_key_file",
+                    jwt.algorithm
+                )));
+            }
+            return Ok(());
+        }
         if !anonymous_allowed.unwrap_or(true) && auth_file.is_none() && auth_endpoint.is_none() {
             return Err(TeleMQServerConfigError::WrongValue(format!("Invalid authentication configuration. Allow anonymous usage, or provide authentication endpoint or provide authentication file.")));
         }
@@ -165,13 +661,19 @@ impl TeleMQServerConfigSrc {
     }
 
     fn validate_broker_id(broker_id: &OptString) -> ConfigResult<()> {
-        if broker_id.is_some() {
-            return Ok(());
+        match broker_id {
+            // broker_id is a level of $SYS/broker/{broker_id}/state
+            Some(broker_id) if broker_id.is_empty() || broker_id.contains(['/', '+', '#']) => {
+                Err(TeleMQServerConfigError::WrongValue(format!(
+                    "broker_id {:?} should be non-empty and should not contain '/', '+' or '#'",
+                    broker_id
+                )))
+            }
+            Some(_) => Ok(()),
+            None => Err(TeleMQServerConfigError::WrongValue(
+                "broker_id is a mandatory field".into(),
+            )),
         }
-
-        return Err(TeleMQServerConfigError::WrongValue(
-            "broker_id is a mandatory field".into(),
-        ));
     }
 
     fn validate_cluster_id(cluster_id: &OptString) -> ConfigResult<()> {
@@ -210,58 +712,993 @@ impl TeleMQServerConfigSrc {
 
         return Ok(());
     }
+
+    fn validate_bandwidth_limits(limits: &[(&str, &OptUsize)]) -> ConfigResult<()> {
+        for (name, limit) in limits {
+            if **limit == Some(0) {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "{} should be greater than 0",
+                    name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_topic_prefixes(prefixes: &[(&str, &OptString)]) -> ConfigResult<()> {
+        for (name, prefix) in prefixes {
+            if let Some(prefix) = prefix {
+                if !prefix.ends_with('/')
+                    || prefix.starts_with('$')
+                    || prefix.contains(['+', '#', '\0'])
+                {
+                    return Err(TeleMQServerConfigError::WrongValue(format!(
+                        "{} should end with / and contain neither wildcards nor a leading $",
+                        name
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_payload_size_buckets(buckets: &OptList<usize>) -> ConfigResult<()> {
+        match buckets {
+            Some(buckets) if buckets.is_empty() => Err(TeleMQServerConfigError::WrongValue(
+                "payload_size_buckets should contain at least one value".into(),
+            )),
+            Some(buckets) if buckets.windows(2).any(|pair| pair[0] >= pair[1]) => {
+                Err(TeleMQServerConfigError::WrongValue(
+                    "payload_size_buckets should be sorted in ascending order without duplicates"
+                        .into(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_startup_wait(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        if config_src.wait_for_state_store == Some(true)
+            && config_src.session_state_store_url.is_none()
+        {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "wait_for_state_store requires session_state_store_url".into(),
+            ));
+        }
+        if config_src.wait_for_auth_endpoint == Some(true) && config_src.auth_endpoint.is_none() {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "wait_for_auth_endpoint requires auth_endpoint".into(),
+            ));
+        }
+        if config_src.startup_wait_retry_interval == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "startup_wait_retry_interval should be greater than 0".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_binds(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        for (bind_name, bind, port) in [
+            ("tcp_bind", config_src.tcp_bind, config_src.tcp_port),
+            ("tls_bind", config_src.tls_bind, config_src.tls_port),
+            ("ws_bind", config_src.ws_bind, config_src.ws_port),
+            ("wss_bind", config_src.wss_bind, config_src.wss_port),
+            (
+                "mqttsn_bind",
+                config_src.mqttsn_bind,
+                config_src.mqttsn_port,
+            ),
+            (
+                "admin_api_bind",
+                config_src.admin_api_bind,
+                config_src.admin_api_port,
+            ),
+        ] {
+            if bind.is_some() && port.is_some() {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "{} and {} can't be set together",
+                    bind_name,
+                    bind_name.replace("_bind", "_port")
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_metrics_port(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        let admin_api_port = config_src
+            .admin_api_bind
+            .map(|addr| addr.port())
+            .or(config_src.admin_api_port);
+        if config_src.metrics_port.is_some() && config_src.metrics_port == admin_api_port {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "metrics_port should differ from admin_api_port, Admin API serves /metrics already"
+                    .into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_admin_api(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        let wrong_value = |msg: &str| Err(TeleMQServerConfigError::WrongValue(msg.into()));
+        if config_src.admin_api_tls == Some(true)
+            && (config_src.cert_file.is_none() || config_src.key_file.is_none())
+        {
+            return wrong_value("admin_api_tls requires cert_file and key_file");
+        }
+        for credentials in config_src.admin_api_auth.iter().flatten() {
+            let with_token = credentials
+                .token
+                .as_ref()
+                .is_some_and(|token| !token.is_empty());
+            let with_password = credentials.username.is_some() && credentials.password.is_some();
+            if with_token == with_password
+                || credentials.username.is_some() != credentials.password.is_some()
+            {
+                return wrong_value(
+                    "admin_api_auth entries should have either a token or a username and a password",
+                );
+            }
+            if credentials
+                .username
+                .as_ref()
+                .is_some_and(|username| username.contains(':'))
+            {
+                return wrong_value("admin_api_auth usernames should not contain ':'");
+            }
+            let valid_routes = credentials
+                .routes
+                .iter()
+                .flatten()
+                .all(|route| route.starts_with('/'));
+            if !valid_routes {
+                return wrong_value("admin_api_auth routes should start with '/', e.g. /devices");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_max_queued_messages(max_queued_messages: &OptUsize) -> ConfigResult<()> {
+        if *max_queued_messages == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "max_queued_messages_per_client should be greater than 0".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_connection_limit(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        if config_src.max_connections == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "max_connections should be greater than 0".into(),
+            ));
+        }
+        if config_src.connection_drain_rate == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "connection_drain_rate should be greater than 0".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_retry_interval(retry_interval: &OptDuration) -> ConfigResult<()> {
+        if *retry_interval == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "retry_interval should be greater than 0".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_auth_endpoint(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        if config_src.auth_endpoint_timeout == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "auth_endpoint_timeout should be greater than 0".into(),
+            ));
+        }
+        let has_endpoint_options = config_src.auth_endpoint_timeout.is_some()
+            || config_src.auth_endpoint_max_retries.is_some()
+            || config_src.auth_endpoint_cache_ttl.is_some()
+            || config_src.auth_endpoint_negative_cache_ttl.is_some();
+        if has_endpoint_options && config_src.auth_endpoint.is_none() {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "auth_endpoint_* options require auth_endpoint".into(),
+            ));
+        }
+        if config_src.auth_unreachable_policy.is_some() && config_src.auth_endpoint.is_none() {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "auth_unreachable_policy requires auth_endpoint".into(),
+            ));
+        }
+        let allows_topics = config_src.auth_unreachable_policy
+            == Some(AuthUnreachablePolicy::AllowAnonymousTopicsOnly);
+        let has_topics = config_src
+            .auth_unreachable_topics
+            .as_ref()
+            .is_some_and(|topics| !topics.is_empty());
+        if allows_topics != has_topics {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "auth_unreachable_topics should be set if and only if auth_unreachable_policy is allow-anonymous-topics-only".into(),
+            ));
+        }
+        for topic in config_src.auth_unreachable_topics.iter().flatten() {
+            if let Err(err) = Subscription::try_from(topic.as_str()) {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "Invalid topic filter {:?} in auth_unreachable_topics. {}",
+                    topic, err
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_max_inflight_messages(max_inflight_messages: &OptUsize) -> ConfigResult<()> {
+        if *max_inflight_messages == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "max_inflight_messages should be greater than 0".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_connection_channel_capacity(capacity: &OptUsize) -> ConfigResult<()> {
+        if *capacity == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "connection_channel_capacity should be greater than 0".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_cluster(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        let has_peers = config_src
+            .cluster_peers
+            .as_ref()
+            .is_some_and(|peers| !peers.is_empty());
+        if has_peers && config_src.cluster_port.is_none() {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "cluster_port is required by cluster_peers, peers forward publishes to it".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_assigned_client_id_prefix(prefix: &OptString) -> ConfigResult<()> {
+        // client ids are substituted into topics, e.g. of erase_retained_topics
+        if prefix
+            .as_ref()
+            .is_some_and(|prefix| prefix.contains(['/', '+', '#']))
+        {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "assigned_client_id_prefix should not contain '/', '+' or '#'".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_tls_alpn(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        let protocols = config_src.tls_alpn_protocols.as_deref();
+        // protocol ids are prefixed by their length of a single byte in TLS extensions
+        if protocols.is_some_and(|protocols| {
+            protocols
+                .iter()
+                .any(|protocol| protocol.is_empty() || protocol.len() > 255)
+        }) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "tls_alpn_protocols should contain protocols of 1 to 255 bytes".into(),
+            ));
+        }
+        if config_src.tls_alpn_required == Some(true)
+            && protocols.is_some_and(|protocols| protocols.is_empty())
+        {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "tls_alpn_required requires at least one of tls_alpn_protocols".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_tls_cipher_suites(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        if let Some(ref names) = config_src.tls_cipher_suites {
+            let mut has_tls13_suite = false;
+            for name in names {
+                match cipher_suite(name) {
+                    Some(suite) => {
+                        has_tls13_suite |= matches!(suite, SupportedCipherSuite::Tls13(_))
+                    }
+                    None => {
+                        return Err(TeleMQServerConfigError::WrongValue(format!(
+                            "tls_cipher_suites: {} is not a supported cipher suite",
+                            name
+                        )))
+                    }
+                }
+            }
+            // TLS 1.2 suites are of no use to TLS 1.3 connections
+            if config_src.tls_min_version == Some(TlsVersion::V1_3)
+                && !names.is_empty()
+                && !has_tls13_suite
+            {
+                return Err(TeleMQServerConfigError::WrongValue(
+                    "tls_cipher_suites should contain a TLS 1.3 suite if tls_min_version is 1.3"
+                        .into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_ws(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        if let Some(ref ws_path) = config_src.ws_path {
+            if !ws_path.starts_with('/') {
+                return Err(TeleMQServerConfigError::WrongValue(
+                    "ws_path should start with /".into(),
+                ));
+            }
+            if ws_path == HEALTH_PATH {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "ws_path should differ from {}, websocket listeners serve health checks on it",
+                    HEALTH_PATH
+                )));
+            }
+        }
+        if let Some(ref ws_subprotocols) = config_src.ws_subprotocols {
+            if ws_subprotocols.is_empty() || ws_subprotocols.iter().any(|s| s.is_empty()) {
+                return Err(TeleMQServerConfigError::WrongValue(
+                    "ws_subprotocols should contain at least one non-empty subprotocol".into(),
+                ));
+            }
+        }
+        if config_src.ws_ping_interval == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "ws_ping_interval should be greater than 0".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_retained_max_qos(retained_max_qos: &Option<u8>) -> ConfigResult<()> {
+        if retained_max_qos.is_some_and(|qos| qos > 2) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "retained_max_qos should be 0, 1 or 2".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_erase_retained_topics(erase_retained_topics: &OptList<String>) -> ConfigResult<()> {
+        for topic in erase_retained_topics.iter().flatten() {
+            if let Err(err) = Subscription::try_from(topic.as_str()) {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "Invalid topic filter {:?} in erase_retained_topics. {}",
+                    topic, err
+                )));
+            }
+            // otherwise erasing data of one client removes retained messages of every client
+            if !topic.contains("{client_id}") {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "Topic filter {:?} in erase_retained_topics should contain {{client_id}}",
+                    topic
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_will_delay_interval(will_delay_interval: &OptDuration) -> ConfigResult<()> {
+        if *will_delay_interval == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "will_delay_interval should be greater than 0".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_message_expiry_interval(message_expiry_interval: &OptDuration) -> ConfigResult<()> {
+        if *message_expiry_interval == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "message_expiry_interval should be greater than 0".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_tls_cert_watch_interval(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        if config_src.tls_cert_watch_interval == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "tls_cert_watch_interval should be greater than 0".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn validate_time_sync(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        if config_src.time_sync_interval == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "time_sync_interval should be greater than 0".into(),
+            ));
+        }
+        let topics = [
+            ("time_sync_topic", &config_src.time_sync_topic),
+            (
+                "time_sync_request_topic",
+                &config_src.time_sync_request_topic,
+            ),
+        ];
+        for (name, topic) in topics {
+            if let Some(topic) = topic {
+                if topic.is_empty() || Topic::try_from(topic.as_str()).is_err() {
+                    return Err(TeleMQServerConfigError::WrongValue(format!(
+                        "{} {:?} should be a non-empty topic without wildcards",
+                        name, topic
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_queue_depth(request_topic: &OptString) -> ConfigResult<()> {
+        if let Some(topic) = request_topic {
+            if topic.is_empty() || Topic::try_from(topic.as_str()).is_err() {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "queue_depth_request_topic {:?} should be a non-empty topic without wildcards",
+                    topic
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_webhook(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        if let Some(ref url) = config_src.webhook_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "webhook_url {:?} should be an http:// or https:// URL",
+                    url
+                )));
+            }
+        }
+        for (name, value) in [
+            (
+                "webhook_outbox_size",
+                config_src.webhook_outbox_size.map(|size| size as u64),
+            ),
+            (
+                "webhook_retry_max_interval",
+                config_src.webhook_retry_max_interval,
+            ),
+            ("webhook_timeout", config_src.webhook_timeout),
+        ] {
+            if value == Some(0) {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "{} should be greater than 0",
+                    name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_retained_bypass(rules: &OptList<RetainedBypassConfig>) -> ConfigResult<()> {
+        for rule in rules.iter().flatten() {
+            if rule.filter.is_none() && rule.client_id_prefix.is_none() {
+                return Err(TeleMQServerConfigError::WrongValue(
+                    "retained_bypass rule should have a filter or a client_id_prefix".into(),
+                ));
+            }
+            if let Some(ref filter) = rule.filter {
+                let filter_is_valid = Subscription::try_from(filter)
+                    .map(|filter| filter.is_valid())
+                    .unwrap_or(false);
+                if !filter_is_valid {
+                    return Err(TeleMQServerConfigError::WrongValue(format!(
+                        "retained_bypass filter {:?} is not a valid topic filter",
+                        filter
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_load_shedding(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        if config_src.load_shedding_rss_watermark == Some(0)
+            || config_src.load_shedding_lag_watermark == Some(0)
+        {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "load_shedding_rss_watermark and load_shedding_lag_watermark should be greater than 0"
+                    .into(),
+            ));
+        }
+        for filter in config_src.load_shedding_topics.iter().flatten() {
+            let filter_is_valid = Subscription::try_from(filter)
+                .map(|filter| filter.is_valid())
+                .unwrap_or(false);
+            if !filter_is_valid {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "load_shedding_topics filter {:?} is not a valid topic filter",
+                    filter
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_subscription_blacklist(blacklist: &OptList<String>) -> ConfigResult<()> {
+        for filter in blacklist.iter().flatten() {
+            let filter_is_valid = Subscription::try_from(filter.as_str())
+                .map(|filter| filter.is_valid())
+                .unwrap_or(false);
+            if !filter_is_valid {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "subscription_blacklist filter {:?} is not a valid topic filter",
+                    filter
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_priority_topics(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        if config_src.priority_starvation_limit == Some(0) {
+            return Err(TeleMQServerConfigError::WrongValue(
+                "priority_starvation_limit should be greater than 0".into(),
+            ));
+        }
+        for rule in config_src.priority_topics.iter().flatten() {
+            let filter_is_valid = Subscription::try_from(rule.filter.as_str())
+                .map(|filter| filter.is_valid())
+                .unwrap_or(false);
+            if !filter_is_valid {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "priority_topics filter {:?} is not a valid topic filter",
+                    rule.filter
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_quiet_hours(windows: &OptList<QuietHoursConfig>) -> ConfigResult<()> {
+        for window in windows.iter().flatten() {
+            if window.tag.is_empty() {
+                return Err(TeleMQServerConfigError::WrongValue(
+                    "quiet_hours tag should not be empty".into(),
+                ));
+            }
+            let (start, end) = match (minute_of_day(&window.start), minute_of_day(&window.end)) {
+                (Some(start), Some(end)) => (start, end),
+                _ => {
+                    return Err(TeleMQServerConfigError::WrongValue(format!(
+                        "quiet_hours {:?}: start and end should be times of day, HH:MM",
+                        window.tag
+                    )))
+                }
+            };
+            if start == end {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "quiet_hours {:?}: start and end should differ",
+                    window.tag
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_bootstrap(rules: &OptList<BootstrapConfig>) -> ConfigResult<()> {
+        for rule in rules.iter().flatten() {
+            if rule.tag.is_empty() {
+                return Err(TeleMQServerConfigError::WrongValue(
+                    "bootstrap tag should not be empty".into(),
+                ));
+            }
+            if rule.endpoint.is_none() && rule.messages.as_ref().is_none_or(Vec::is_empty) {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "bootstrap {:?} should have an endpoint or messages",
+                    rule.tag
+                )));
+            }
+            if let Some(ref endpoint) = rule.endpoint {
+                if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                    return Err(TeleMQServerConfigError::WrongValue(format!(
+                        "bootstrap endpoint {:?} should be an http:// or https:// URL",
+                        endpoint
+                    )));
+                }
+            }
+            for message in rule.messages.iter().flatten() {
+                bootstrap_message(message, "client").map_err(|err| {
+                    TeleMQServerConfigError::WrongValue(format!(
+                        "bootstrap {:?}: {}",
+                        rule.tag, err
+                    ))
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_payload_formats(config_src: &TeleMQServerConfigSrc) -> ConfigResult<()> {
+        for rule in config_src.payload_formats.iter().flatten() {
+            let filter_is_valid = Subscription::try_from(rule.filter.as_str())
+                .map(|filter| filter.is_valid())
+                .unwrap_or(false);
+            if !filter_is_valid {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "payload_formats filter {:?} is not a valid topic filter",
+                    rule.filter
+                )));
+            }
+        }
+        if let Some(ref dead_letter_topic) = config_src.payload_dead_letter_topic {
+            // topics of rejected messages are appended to it
+            let topic_is_valid = Topic::try_from(format!("{}/x", dead_letter_topic))
+                .map(|topic| topic.is_valid())
+                .unwrap_or(false);
+            if dead_letter_topic.is_empty() || !topic_is_valid {
+                return Err(TeleMQServerConfigError::WrongValue(format!(
+                    "payload_dead_letter_topic {:?} is not a valid topic name",
+                    dead_letter_topic
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_bridges(bridges: &OptList<BridgeConfig>) -> ConfigResult<()> {
+        let bridges = match bridges {
+            Some(bridges) => bridges,
+            None => return Ok(()),
+        };
+        let wrong_value = |msg: String| Err(TeleMQServerConfigError::WrongValue(msg));
+
+        let mut names = HashSet::new();
+        for bridge in bridges {
+            if bridge.name.is_empty() || bridge.name.contains(['/', '+', '#']) {
+                return wrong_value(format!(
+                    "bridge name {:?} should be non-empty and should not contain '/', '+' or '#'",
+                    bridge.name
+                ));
+            }
+            if !names.insert(&bridge.name) {
+                return wrong_value(format!("bridge name {:?} is not unique", bridge.name));
+            }
+            let valid_address = bridge
+                .address
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            if !valid_address {
+                return wrong_value(format!(
+                    "bridge {:?} address should be in the host:port format",
+                    bridge.name
+                ));
+            }
+            if bridge.topics.is_empty() {
+                return wrong_value(format!(
+                    "bridge {:?} should have at least one topic",
+                    bridge.name
+                ));
+            }
+            for topic in &bridge.topics {
+                let filter_is_valid = Subscription::try_from(&topic.pattern)
+                    .map(|filter| filter.is_valid())
+                    .unwrap_or(false);
+                if !filter_is_valid {
+                    return wrong_value(format!(
+                        "bridge {:?} topic pattern {:?} is not a valid topic filter",
+                        bridge.name, topic.pattern
+                    ));
+                }
+                if topic.qos.is_some_and(|qos| qos > 1) {
+                    return wrong_value(format!(
+                        "bridge {:?} topic {:?} qos should be 0 or 1",
+                        bridge.name, topic.pattern
+                    ));
+                }
+                for prefix in [&topic.local_prefix, &topic.remote_prefix]
+                    .into_iter()
+                    .flatten()
+                {
+                    if prefix.contains(['+', '#']) {
+                        return wrong_value(format!(
+                            "bridge {:?} topic prefix {:?} should not contain wildcards",
+                            bridge.name, prefix
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct TeleMQServerConfig {
+    pub broker_id: String,
+    // tenant the broker serves
+    pub account_id: String,
+    // how client ids are generated for clients which connect with an empty one
+    pub assigned_client_id_scheme: ClientIdScheme,
+    // placeholders are replaced once a generator is created
+    pub assigned_client_id_prefix: String,
     pub max_connections: usize,
+    // if None => connections above a lowered max_connections are kept open
+    pub connection_drain_rate: OptUsize,
     // TCP listener
     pub tcp_addr: SocketAddr,
     // TLS Listener
     pub tls_addr: OptSocketAddr,
     pub cert_file: OptString,
     pub key_file: OptString,
+    // if true => JA3 fingerprints of ClientHello are recorded for TLS connections
+    pub tls_fingerprint: bool,
+    // in order of preference of the broker, if empty => ALPN is not negotiated
+    pub tls_alpn_protocols: Vec<String>,
+    // if true => clients which don't negotiate ALPN are rejected at handshake
+    pub tls_alpn_required: bool,
+    // the lowest TLS version of TLS and Websocket TLS connections
+    pub tls_min_version: TlsVersion,
+    // names of allowed cipher suites, if empty => all suites supported by rustls
+    pub tls_cipher_suites: Vec<String>,
+    // if set => cert_file and key_file are reloaded once they are changed, besides SIGHUP
+    pub tls_cert_watch_interval: Option<Duration>,
     // Websocket listener
     pub ws_addr: OptSocketAddr,
     pub wss_addr: OptSocketAddr,
+    // if None => MQTT-SN gateway (UDP) is disabled
+    pub mqttsn_addr: OptSocketAddr,
+    // both websocket listeners accept upgrades on this path only
+    pub ws_path: String,
+    // in order of preference of the broker
+    pub ws_subprotocols: Vec<String>,
+    // if None => websocket pings are not sent
+    pub ws_ping_interval: Option<Duration>,
+    // websocket ping and pong frames prolong keep alive of a client
+    pub ws_pong_as_activity: bool,
     pub activity_check_interval: Duration,
     pub backup_interval: Duration,
     pub keep_alive: Duration,
     /// stdout, stderr, file:telemq.log
     pub log_dest: String,
     pub log_level: String,
+    pub log_format: LogFormat,
+    /// levels of log targets which differ from `log_level`
+    pub log_targets: BTreeMap<String, String>,
     // if None => unlimited
     pub max_packet_size: OptUsize,
     // if None => unlimited
     pub max_subs_per_client: OptUsize,
+    // subscriptions to filters as broad as any of these are rejected
+    pub subscription_blacklist: Vec<String>,
     // if None => unlimited
     pub max_storage_duration: OptDuration,
     pub anonymous_allowed: bool,
     pub auth_endpoint: OptString,
+    pub auth_endpoint_timeout: Duration,
+    // failed requests (transport errors and 5xx statuses) are retried this many times
+    pub auth_endpoint_max_retries: usize,
+    // if None => allowed logins are not cached
+    pub auth_endpoint_cache_ttl: Option<Duration>,
+    // if None => denied logins are not cached
+    pub auth_endpoint_negative_cache_ttl: Option<Duration>,
+    pub auth_unreachable_policy: AuthUnreachablePolicy,
+    // topic filters granted to clients accepted by `AllowAnonymousTopicsOnly`,
+    // `{client_id}` is replaced by a client id
+    pub auth_unreachable_topics: Vec<String>,
     pub auth_file: OptString,
+    // if Some => topic rules of this file are evaluated, but not enforced, and decisions
+    // diverging from the active ACL are logged
+    pub auth_file_shadow: OptString,
+    pub auth_jwt: Option<JwtAuthConfig>,
     pub sys_topics_update_interval: Duration,
+    // if true => counters of every connected client are published to $SYS topics as well
+    pub sys_topics_per_client: bool,
+    // if true => bytes on the wire are counted per listener, including TLS and WS overhead
+    pub transport_byte_counters: bool,
     pub session_state_store_url: OptSocketAddr,
     pub admin_api: OptSocketAddr,
+    // if true => Admin API (and `metrics`) is served over TLS with `cert_file` and `key_file`
+    pub admin_api_tls: bool,
+    // if empty => Admin API is not authenticated
+    pub admin_api_auth: Vec<AdminApiCredentials>,
+    // a dedicated listener of Prometheus `/metrics`
+    pub metrics: OptSocketAddr,
     pub ip_whitelist: Option<Vec<IpNet>>,
+    // if true, a client which requested a persistent session is rejected
+    // when its state cannot be read from the Session State Store
+    pub reject_on_session_recovery_failure: bool,
+    // path of a Unix socket used for a zero-downtime upgrade
+    pub handover_socket: OptString,
+    // aggregate outgoing bytes per second of all connections of a listener
+    // if None => unlimited
+    pub tcp_bandwidth_limit: OptUsize,
+    pub tls_bandwidth_limit: OptUsize,
+    pub ws_bandwidth_limit: OptUsize,
+    pub wss_bandwidth_limit: OptUsize,
+    // prefix of topics of all clients of a listener, e.g. `tenant-a/`
+    // if None => topics are not prefixed
+    pub tcp_topic_prefix: OptString,
+    pub tls_topic_prefix: OptString,
+    pub ws_topic_prefix: OptString,
+    pub wss_topic_prefix: OptString,
+    // topics of packets received from clients are normalized, `$` topics are kept as is
+    pub topic_strip_trailing_slash: bool,
+    pub topic_collapse_separators: bool,
+    pub topic_case_insensitive: bool,
+    // upper bounds in bytes of payload size histogram buckets published to $SYS topics
+    pub payload_size_buckets: Vec<usize>,
+    // if true, a client which is being disconnected by the broker receives a reason
+    // in devices/{client_id}/$disconnect_reason
+    pub publish_disconnect_reason: bool,
+    // if true, the broker doesn't start until session_state_store_url accepts connections
+    pub wait_for_state_store: bool,
+    // if true, the broker doesn't start until auth_endpoint responds
+    pub wait_for_auth_endpoint: bool,
+    // if zero => wait indefinitely
+    pub startup_wait_timeout: Duration,
+    pub startup_wait_retry_interval: Duration,
+    // if None => retained messages are kept in memory only
+    pub retained_store_file: OptString,
+    // if None => subscriptions of in-process clients are kept in memory only
+    pub internal_subscriptions_file: OptString,
+    // if None => retained messages are delivered with their own QoS (up to a subscription QoS)
+    pub retained_max_qos: Option<u8>,
+    // retained messages removed by `DELETE /devices/{client_id}/data` of Admin API,
+    // `{client_id}` is replaced by a client id
+    pub erase_retained_topics: Vec<String>,
+    // if None => queues of offline clients are unlimited
+    pub max_queued_messages_per_client: OptUsize,
+    pub queue_overflow_policy: QueueOverflowPolicy,
+    // QoS 0 messages are queued for offline clients as well
+    pub queue_qos0_messages: bool,
+    // if None => persistent sessions are stored until their clients reconnect
+    pub session_expiry_interval: Option<Duration>,
+    // if None => queued messages are kept until they are sent
+    pub message_expiry_interval: Option<Duration>,
+    // if None => wills are published as soon as their connections terminate
+    pub will_delay_interval: Option<Duration>,
+    // if None => unacknowledged messages are re-sent only when a persistent session is resumed
+    pub retry_interval: Option<Duration>,
+    // if None => messages are re-sent until they are acknowledged
+    pub max_retries: OptUsize,
+    // if None => unlimited
+    pub max_inflight_messages: OptUsize,
+    // messages waiting to be sent to a connection, publishes above it are dropped
+    pub connection_channel_capacity: usize,
+    // whether a connection which channel is full is disconnected as well
+    pub connection_channel_full_policy: ChannelFullPolicy,
+    // if None => growth of the subscription tree is not reported in logs
+    pub subscription_tree_warning_nodes: OptUsize,
+    // subscribers of this many recently published topics are cached, 0 disables the cache
+    pub routing_cache_size: usize,
+    // retained broker time and replies to time requests are published under this topic
+    pub time_sync_topic: String,
+    // if None => broker time is published only on request
+    pub time_sync_interval: Option<Duration>,
+    // if None => time requests are not served
+    pub time_sync_request_topic: OptString,
+    // replies go to `{queue_depth_request_topic}/{client_id}`, if None => requests are not served
+    pub queue_depth_request_topic: OptString,
+    // lifecycle events of clients are POSTed to this URL, if None => events are not exported
+    pub webhook_url: OptString,
+    // events waiting to be delivered to webhook_url
+    pub webhook_outbox_file: String,
+    // if the outbox is full => the oldest events are dropped
+    pub webhook_outbox_size: usize,
+    // the delay between retries of a failed webhook request is doubled up to this value
+    pub webhook_retry_max_interval: Duration,
+    pub webhook_timeout: Duration,
+    // brokers sharing clients' subscriptions, only brokers with the same cluster_id are linked
+    pub cluster_id: String,
+    // if None => the broker doesn't join a cluster
+    pub cluster_addr: OptSocketAddr,
+    pub cluster_peers: Vec<String>,
+    // outbound connections to remote MQTT brokers
+    pub bridges: Vec<BridgeConfig>,
+    // rules suppressing retained messages sent to new subscriptions
+    pub retained_bypass: Vec<RetainedBypassConfig>,
+    // if None => resident memory of the broker doesn't make it overloaded
+    pub load_shedding_rss_watermark: Option<u64>,
+    // if None => lag of the event loop doesn't make the broker overloaded
+    pub load_shedding_lag_watermark: Option<Duration>,
+    // topic filters QoS 0 messages of which are dropped while the broker is overloaded
+    pub load_shedding_topics: Vec<String>,
+    // if empty => messages are served in order they are published
+    pub priority_topics: Vec<PriorityTopicConfig>,
+    // a waiting priority class is passed over at most this many times in a row
+    pub priority_starvation_limit: usize,
+    // if empty => messages are never held for quiet hours of clients
+    pub quiet_hours: Vec<QuietHoursConfig>,
+    // retained messages published to topics of clients connecting for the first time
+    pub bootstrap: Vec<BootstrapConfig>,
+    // if empty => payloads are not checked
+    pub payload_formats: Vec<PayloadFormatConfig>,
+    // if None => messages with payloads of a wrong format are dropped
+    pub payload_dead_letter_topic: OptString,
 }
 
 impl From<TeleMQServerConfigSrc> for TeleMQServerConfig {
     fn from(src: TeleMQServerConfigSrc) -> Self {
         let with_tls = src.cert_file.is_some();
         TeleMQServerConfig {
+            broker_id: src
+                .broker_id
+                .unwrap_or_else(|| Self::DEFAULT_BROKER_ID.to_string()),
+            account_id: src.account_id.unwrap_or_default(),
+            assigned_client_id_scheme: src
+                .assigned_client_id_scheme
+                .unwrap_or(Self::DEFAULT_ASSIGNED_CLIENT_ID_SCHEME),
+            assigned_client_id_prefix: src.assigned_client_id_prefix.unwrap_or_default(),
             max_connections: src.max_connections.unwrap_or(Self::DEFAULT_MAX_CONNECTIONS),
-            tcp_addr: local_listener(src.tcp_port.unwrap_or(Self::DEFAULT_TCP_PORT)),
+            connection_drain_rate: src.connection_drain_rate,
+            tcp_addr: src
+                .tcp_bind
+                .unwrap_or_else(|| local_listener(src.tcp_port.unwrap_or(Self::DEFAULT_TCP_PORT))),
             tls_addr: if with_tls {
-                Some(local_listener(
-                    src.tls_port.unwrap_or(Self::DEFAULT_TLS_PORT),
-                ))
+                Some(src.tls_bind.unwrap_or_else(|| {
+                    local_listener(src.tls_port.unwrap_or(Self::DEFAULT_TLS_PORT))
+                }))
             } else {
                 None
             },
             cert_file: src.cert_file,
             key_file: src.key_file,
-            ws_addr: src.ws_port.map(local_listener),
-            wss_addr: src.wss_port.map(local_listener),
+            tls_fingerprint: src.tls_fingerprint.unwrap_or(false),
+            tls_alpn_protocols: src
+                .tls_alpn_protocols
+                .unwrap_or_else(Self::default_tls_alpn_protocols),
+            tls_alpn_required: src.tls_alpn_required.unwrap_or(false),
+            tls_min_version: src.tls_min_version.unwrap_or(Self::DEFAULT_TLS_MIN_VERSION),
+            tls_cipher_suites: src.tls_cipher_suites.unwrap_or_default(),
+            tls_cert_watch_interval: src.tls_cert_watch_interval.map(Duration::from_secs),
+            ws_addr: src.ws_bind.or(src.ws_port.map(local_listener)),
+            wss_addr: src.wss_bind.or(src.wss_port.map(local_listener)),
+            mqttsn_addr: src.mqttsn_bind.or(src.mqttsn_port.map(local_listener)),
+            ws_path: src
+                .ws_path
+                .unwrap_or_else(|| Self::DEFAULT_WS_PATH.to_string()),
+            ws_subprotocols: src
+                .ws_subprotocols
+                .unwrap_or_else(Self::default_ws_subprotocols),
+            ws_ping_interval: src.ws_ping_interval.map(Duration::from_secs),
+            ws_pong_as_activity: src
+                .ws_pong_as_activity
+                .unwrap_or(Self::DEFAULT_WS_PONG_AS_ACTIVITY),
             activity_check_interval: Duration::from_secs(
                 src.activity_check_interval
                     .unwrap_or(Self::DEFAULT_ACTIVITY_CHECK_INTERVAL),
@@ -276,19 +1713,41 @@ impl From<TeleMQServerConfigSrc> for TeleMQServerConfig {
             log_level: src
                 .log_level
                 .unwrap_or_else(|| Self::DEFAULT_LOG_LEVEL.to_string()),
+            log_format: src.log_format.unwrap_or(Self::DEFAULT_LOG_FORMAT),
+            // if None => all targets are logged with log_level
+            log_targets: src.log_targets.unwrap_or_default(),
             max_packet_size: src.max_packet_size,
             max_subs_per_client: src.max_subs_per_client,
+            subscription_blacklist: src.subscription_blacklist.unwrap_or_default(),
             max_storage_duration: src.max_storage_duration,
             anonymous_allowed: match src.anonymous_allowed {
                 Some(v) => v,
                 None => {
                     src.auth_endpoint.is_none()
                         && src.auth_file.is_none()
+                        && src.auth_jwt.is_none()
                         && Self::DEFAULT_ANONYMOUS_ALLOWED
                 }
             },
             auth_endpoint: src.auth_endpoint,
+            auth_endpoint_timeout: Duration::from_secs(
+                src.auth_endpoint_timeout
+                    .unwrap_or(Self::DEFAULT_AUTH_ENDPOINT_TIMEOUT),
+            ),
+            auth_endpoint_max_retries: src
+                .auth_endpoint_max_retries
+                .unwrap_or(Self::DEFAULT_AUTH_ENDPOINT_MAX_RETRIES),
+            auth_endpoint_cache_ttl: src.auth_endpoint_cache_ttl.map(Duration::from_secs),
+            auth_endpoint_negative_cache_ttl: src
+                .auth_endpoint_negative_cache_ttl
+                .map(Duration::from_secs),
+            auth_unreachable_policy: src
+                .auth_unreachable_policy
+                .unwrap_or(Self::DEFAULT_AUTH_UNREACHABLE_POLICY),
+            auth_unreachable_topics: src.auth_unreachable_topics.unwrap_or_default(),
             auth_file: src.auth_file,
+            auth_file_shadow: src.auth_file_shadow,
+            auth_jwt: src.auth_jwt,
             sys_topics_update_interval: src
                 .sys_topics_update_interval
                 .map(|secs| {
@@ -299,14 +1758,129 @@ impl From<TeleMQServerConfigSrc> for TeleMQServerConfig {
                     }
                 })
                 .unwrap_or_else(|| Duration::from_secs(Self::DEFAULT_SYS_TOPICS_UPDATE_INTERVAL)),
+            sys_topics_per_client: src
+                .sys_topics_per_client
+                .unwrap_or(Self::DEFAULT_SYS_TOPICS_PER_CLIENT),
+            transport_byte_counters: src.transport_byte_counters.unwrap_or(false),
             session_state_store_url: src.session_state_store_url.map(|url| url.parse().unwrap()),
-            admin_api: src.admin_api_port.map(|port| local_listener(port)),
+            admin_api: src
+                .admin_api_bind
+                .or(src.admin_api_port.map(|port| local_listener(port))),
+            admin_api_tls: src.admin_api_tls.unwrap_or(false),
+            admin_api_auth: src.admin_api_auth.unwrap_or_default(),
+            metrics: src.metrics_port.map(local_listener),
             ip_whitelist: src.ip_whitelist.map(|ip_net_strs| {
                 ip_net_strs
                     .iter()
                     .map(|ip_net_str| ip_net_str.parse().unwrap())
                     .collect()
             }),
+            reject_on_session_recovery_failure: src
+                .reject_on_session_recovery_failure
+                .unwrap_or(Self::DEFAULT_REJECT_ON_SESSION_RECOVERY_FAILURE),
+            handover_socket: src.handover_socket,
+            tcp_bandwidth_limit: src.tcp_bandwidth_limit,
+            tls_bandwidth_limit: src.tls_bandwidth_limit,
+            ws_bandwidth_limit: src.ws_bandwidth_limit,
+            wss_bandwidth_limit: src.wss_bandwidth_limit,
+            tcp_topic_prefix: src.tcp_topic_prefix,
+            tls_topic_prefix: src.tls_topic_prefix,
+            ws_topic_prefix: src.ws_topic_prefix,
+            wss_topic_prefix: src.wss_topic_prefix,
+            topic_strip_trailing_slash: src
+                .topic_strip_trailing_slash
+                .unwrap_or(Self::DEFAULT_TOPIC_NORMALIZATION),
+            topic_collapse_separators: src
+                .topic_collapse_separators
+                .unwrap_or(Self::DEFAULT_TOPIC_NORMALIZATION),
+            topic_case_insensitive: src
+                .topic_case_insensitive
+                .unwrap_or(Self::DEFAULT_TOPIC_NORMALIZATION),
+            payload_size_buckets: src
+                .payload_size_buckets
+                .unwrap_or_else(|| Self::DEFAULT_PAYLOAD_SIZE_BUCKETS.to_vec()),
+            publish_disconnect_reason: src
+                .publish_disconnect_reason
+                .unwrap_or(Self::DEFAULT_PUBLISH_DISCONNECT_REASON),
+            wait_for_state_store: src.wait_for_state_store.unwrap_or(false),
+            wait_for_auth_endpoint: src.wait_for_auth_endpoint.unwrap_or(false),
+            startup_wait_timeout: Duration::from_secs(
+                src.startup_wait_timeout
+                    .unwrap_or(Self::DEFAULT_STARTUP_WAIT_TIMEOUT),
+            ),
+            startup_wait_retry_interval: Duration::from_secs(
+                src.startup_wait_retry_interval
+                    .unwrap_or(Self::DEFAULT_STARTUP_WAIT_RETRY_INTERVAL),
+            ),
+            retained_store_file: src.retained_store_file,
+            internal_subscriptions_file: src.internal_subscriptions_file,
+            retained_max_qos: src.retained_max_qos,
+            erase_retained_topics: src
+                .erase_retained_topics
+                .unwrap_or_else(|| vec![Self::DEFAULT_ERASE_RETAINED_TOPIC.into()]),
+            max_queued_messages_per_client: src.max_queued_messages_per_client,
+            queue_overflow_policy: src
+                .queue_overflow_policy
+                .unwrap_or(Self::DEFAULT_QUEUE_OVERFLOW_POLICY),
+            queue_qos0_messages: src
+                .queue_qos0_messages
+                .unwrap_or(Self::DEFAULT_QUEUE_QOS0_MESSAGES),
+            session_expiry_interval: src.session_expiry_interval.map(Duration::from_secs),
+            message_expiry_interval: src.message_expiry_interval.map(Duration::from_secs),
+            will_delay_interval: src.will_delay_interval.map(Duration::from_secs),
+            retry_interval: src.retry_interval.map(Duration::from_secs),
+            max_retries: src.max_retries,
+            max_inflight_messages: src.max_inflight_messages,
+            connection_channel_capacity: src
+                .connection_channel_capacity
+                .unwrap_or(Self::DEFAULT_CONNECTION_CHANNEL_CAPACITY),
+            connection_channel_full_policy: src
+                .connection_channel_full_policy
+                .unwrap_or(Self::DEFAULT_CONNECTION_CHANNEL_FULL_POLICY),
+            subscription_tree_warning_nodes: src.subscription_tree_warning_nodes,
+            routing_cache_size: src
+                .routing_cache_size
+                .unwrap_or(Self::DEFAULT_ROUTING_CACHE_SIZE),
+            time_sync_topic: src
+                .time_sync_topic
+                .unwrap_or_else(|| Self::DEFAULT_TIME_SYNC_TOPIC.to_string()),
+            time_sync_interval: src.time_sync_interval.map(Duration::from_secs),
+            time_sync_request_topic: src.time_sync_request_topic,
+            queue_depth_request_topic: src.queue_depth_request_topic,
+            webhook_url: src.webhook_url,
+            webhook_outbox_file: src
+                .webhook_outbox_file
+                .unwrap_or_else(|| Self::DEFAULT_WEBHOOK_OUTBOX_FILE.to_string()),
+            webhook_outbox_size: src
+                .webhook_outbox_size
+                .unwrap_or(Self::DEFAULT_WEBHOOK_OUTBOX_SIZE),
+            webhook_retry_max_interval: Duration::from_secs(
+                src.webhook_retry_max_interval
+                    .unwrap_or(Self::DEFAULT_WEBHOOK_RETRY_MAX_INTERVAL),
+            ),
+            webhook_timeout: Duration::from_secs(
+                src.webhook_timeout.unwrap_or(Self::DEFAULT_WEBHOOK_TIMEOUT),
+            ),
+            cluster_id: src
+                .cluster_id
+                .unwrap_or_else(|| Self::DEFAULT_CLUSTER_ID.to_string()),
+            cluster_addr: src.cluster_port.map(local_listener),
+            cluster_peers: src.cluster_peers.unwrap_or_default(),
+            bridges: src.bridge.unwrap_or_default(),
+            retained_bypass: src.retained_bypass.unwrap_or_default(),
+            load_shedding_rss_watermark: src
+                .load_shedding_rss_watermark
+                .map(|megabytes| megabytes as u64 * 1024 * 1024),
+            load_shedding_lag_watermark: src.load_shedding_lag_watermark.map(Duration::from_millis),
+            load_shedding_topics: src.load_shedding_topics.unwrap_or_default(),
+            priority_topics: src.priority_topics.unwrap_or_default(),
+            priority_starvation_limit: src
+                .priority_starvation_limit
+                .unwrap_or(Self::DEFAULT_PRIORITY_STARVATION_LIMIT),
+            quiet_hours: src.quiet_hours.unwrap_or_default(),
+            bootstrap: src.bootstrap.unwrap_or_default(),
+            payload_formats: src.payload_formats.unwrap_or_default(),
+            payload_dead_letter_topic: src.payload_dead_letter_topic,
         }
     }
 }
@@ -314,52 +1888,217 @@ impl From<TeleMQServerConfigSrc> for TeleMQServerConfig {
 impl Default for TeleMQServerConfig {
     fn default() -> Self {
         TeleMQServerConfig {
+            broker_id: Self::DEFAULT_BROKER_ID.to_string(),
+            account_id: String::new(),
+            assigned_client_id_scheme: Self::DEFAULT_ASSIGNED_CLIENT_ID_SCHEME,
+            assigned_client_id_prefix: String::new(),
             max_connections: Self::DEFAULT_MAX_CONNECTIONS,
+            connection_drain_rate: None,
             tcp_addr: local_listener(Self::DEFAULT_TCP_PORT),
             tls_addr: None,
             cert_file: None,
             key_file: None,
+            tls_fingerprint: false,
+            tls_alpn_protocols: Self::default_tls_alpn_protocols(),
+            tls_alpn_required: false,
+            tls_min_version: Self::DEFAULT_TLS_MIN_VERSION,
+            tls_cipher_suites: vec![],
+            tls_cert_watch_interval: None,
             ws_addr: None,
             wss_addr: None,
+            mqttsn_addr: None,
+            ws_path: Self::DEFAULT_WS_PATH.to_string(),
+            ws_subprotocols: Self::default_ws_subprotocols(),
+            ws_ping_interval: None,
+            ws_pong_as_activity: Self::DEFAULT_WS_PONG_AS_ACTIVITY,
             activity_check_interval: Duration::from_secs(Self::DEFAULT_ACTIVITY_CHECK_INTERVAL),
             backup_interval: Duration::from_secs(Self::DEFAULT_BACKUP_INTERVAL),
             keep_alive: Duration::from_secs(Self::DEFAULT_KEEP_ALIVE),
             log_dest: Self::DEFAULT_LOG.to_string(),
             log_level: Self::DEFAULT_LOG_LEVEL.to_string(),
+            log_format: Self::DEFAULT_LOG_FORMAT,
+            log_targets: BTreeMap::new(),
             // Infinite
             max_packet_size: None,
             // Infinite
             max_subs_per_client: None,
+            subscription_blacklist: vec![],
             // Infinite
             max_storage_duration: None,
             anonymous_allowed: Self::DEFAULT_ANONYMOUS_ALLOWED,
             auth_endpoint: None,
+            auth_endpoint_timeout: Duration::from_secs(Self::DEFAULT_AUTH_ENDPOINT_TIMEOUT),
+            auth_endpoint_max_retries: Self::DEFAULT_AUTH_ENDPOINT_MAX_RETRIES,
+            auth_endpoint_cache_ttl: None,
+            auth_endpoint_negative_cache_ttl: None,
+            auth_unreachable_policy: Self::DEFAULT_AUTH_UNREACHABLE_POLICY,
+            auth_unreachable_topics: vec![],
             auth_file: None,
+            auth_file_shadow: None,
+            auth_jwt: None,
             sys_topics_update_interval: Duration::from_secs(
                 Self::DEFAULT_SYS_TOPICS_UPDATE_INTERVAL,
             ),
+            sys_topics_per_client: Self::DEFAULT_SYS_TOPICS_PER_CLIENT,
+            transport_byte_counters: false,
             session_state_store_url: None,
             admin_api: None,
+            admin_api_tls: false,
+            admin_api_auth: vec![],
+            metrics: None,
             ip_whitelist: None,
+            reject_on_session_recovery_failure: Self::DEFAULT_REJECT_ON_SESSION_RECOVERY_FAILURE,
+            handover_socket: None,
+            // Infinite
+            tcp_bandwidth_limit: None,
+            tls_bandwidth_limit: None,
+            ws_bandwidth_limit: None,
+            wss_bandwidth_limit: None,
+            tcp_topic_prefix: None,
+            tls_topic_prefix: None,
+            ws_topic_prefix: None,
+            wss_topic_prefix: None,
+            topic_strip_trailing_slash: Self::DEFAULT_TOPIC_NORMALIZATION,
+            topic_collapse_separators: Self::DEFAULT_TOPIC_NORMALIZATION,
+            topic_case_insensitive: Self::DEFAULT_TOPIC_NORMALIZATION,
+            payload_size_buckets: Self::DEFAULT_PAYLOAD_SIZE_BUCKETS.to_vec(),
+            publish_disconnect_reason: Self::DEFAULT_PUBLISH_DISCONNECT_REASON,
+            wait_for_state_store: false,
+            wait_for_auth_endpoint: false,
+            startup_wait_timeout: Duration::from_secs(Self::DEFAULT_STARTUP_WAIT_TIMEOUT),
+            startup_wait_retry_interval: Duration::from_secs(
+                Self::DEFAULT_STARTUP_WAIT_RETRY_INTERVAL,
+            ),
+            retained_store_file: None,
+            internal_subscriptions_file: None,
+            retained_max_qos: None,
+            erase_retained_topics: vec![Self::DEFAULT_ERASE_RETAINED_TOPIC.into()],
+            max_queued_messages_per_client: None,
+            queue_overflow_policy: Self::DEFAULT_QUEUE_OVERFLOW_POLICY,
+            queue_qos0_messages: Self::DEFAULT_QUEUE_QOS0_MESSAGES,
+            session_expiry_interval: None,
+            message_expiry_interval: None,
+            will_delay_interval: None,
+            retry_interval: None,
+            max_retries: None,
+            max_inflight_messages: None,
+            connection_channel_capacity: Self::DEFAULT_CONNECTION_CHANNEL_CAPACITY,
+            connection_channel_full_policy: Self::DEFAULT_CONNECTION_CHANNEL_FULL_POLICY,
+            subscription_tree_warning_nodes: None,
+            routing_cache_size: Self::DEFAULT_ROUTING_CACHE_SIZE,
+            time_sync_topic: Self::DEFAULT_TIME_SYNC_TOPIC.to_string(),
+            time_sync_interval: None,
+            time_sync_request_topic: None,
+            queue_depth_request_topic: None,
+            webhook_url: None,
+            webhook_outbox_file: Self::DEFAULT_WEBHOOK_OUTBOX_FILE.to_string(),
+            webhook_outbox_size: Self::DEFAULT_WEBHOOK_OUTBOX_SIZE,
+            webhook_retry_max_interval: Duration::from_secs(
+                Self::DEFAULT_WEBHOOK_RETRY_MAX_INTERVAL,
+            ),
+            webhook_timeout: Duration::from_secs(Self::DEFAULT_WEBHOOK_TIMEOUT),
+            cluster_id: Self::DEFAULT_CLUSTER_ID.to_string(),
+            cluster_addr: None,
+            cluster_peers: vec![],
+            bridges: vec![],
+            retained_bypass: vec![],
+            load_shedding_rss_watermark: None,
+            load_shedding_lag_watermark: None,
+            load_shedding_topics: vec![],
+            priority_topics: vec![],
+            priority_starvation_limit: Self::DEFAULT_PRIORITY_STARVATION_LIMIT,
+            quiet_hours: vec![],
+            bootstrap: vec![],
+            payload_formats: vec![],
+            payload_dead_letter_topic: None,
         }
     }
 }
 
 impl TeleMQServerConfig {
+    pub const DEFAULT_BROKER_ID: &'static str = "telemq";
+    pub const DEFAULT_CLUSTER_ID: &'static str = "telemq";
+    pub const DEFAULT_ASSIGNED_CLIENT_ID_SCHEME: ClientIdScheme = ClientIdScheme::Uuid;
     pub const DEFAULT_MAX_CONNECTIONS: usize = 10_000;
     pub const DEFAULT_TCP_PORT: u16 = 1883;
     pub const DEFAULT_TLS_PORT: u16 = 8883;
+    pub const DEFAULT_WS_PATH: &'static str = "/mqtt";
+    pub const DEFAULT_TLS_MIN_VERSION: TlsVersion = TlsVersion::V1_2;
+    pub const DEFAULT_TLS_ALPN_PROTOCOLS: &'static [&'static str] = &["mqtt"];
+    pub const DEFAULT_WS_SUBPROTOCOLS: &'static [&'static str] = &["mqtt"];
+    pub const DEFAULT_WS_PONG_AS_ACTIVITY: bool = true;
     pub const DEFAULT_ACTIVITY_CHECK_INTERVAL: u64 = 120;
     pub const DEFAULT_BACKUP_INTERVAL: u64 = 30;
     pub const DEFAULT_KEEP_ALIVE: u64 = 120;
     pub const DEFAULT_LOG: &'static str = "stdout";
     pub const DEFAULT_LOG_LEVEL: &'static str = "info";
+    pub const DEFAULT_LOG_FORMAT: LogFormat = LogFormat::Text;
     pub const DEFAULT_ANONYMOUS_ALLOWED: bool = true;
+    pub const DEFAULT_AUTH_ENDPOINT_TIMEOUT: u64 = 5;
+    pub const DEFAULT_AUTH_ENDPOINT_MAX_RETRIES: usize = 2;
+    pub const DEFAULT_AUTH_UNREACHABLE_POLICY: AuthUnreachablePolicy = AuthUnreachablePolicy::Deny;
     pub const DEFAULT_SYS_TOPICS_UPDATE_INTERVAL: u64 = 30;
+    pub const DEFAULT_SYS_TOPICS_PER_CLIENT: bool = false;
+    pub const DEFAULT_REJECT_ON_SESSION_RECOVERY_FAILURE: bool = false;
+    pub const DEFAULT_PUBLISH_DISCONNECT_REASON: bool = false;
+    pub const DEFAULT_TOPIC_NORMALIZATION: bool = false;
+    pub const DEFAULT_STARTUP_WAIT_TIMEOUT: u64 = 60;
+    pub const DEFAULT_STARTUP_WAIT_RETRY_INTERVAL: u64 = 2;
+    pub const DEFAULT_QUEUE_OVERFLOW_POLICY: QueueOverflowPolicy = QueueOverflowPolicy::DropOldest;
+    pub const DEFAULT_QUEUE_QOS0_MESSAGES: bool = false;
+    pub const DEFAULT_ROUTING_CACHE_SIZE: usize = 1024;
+    pub const DEFAULT_TIME_SYNC_TOPIC: &'static str = "$SYS/broker/time";
+    pub const DEFAULT_WEBHOOK_OUTBOX_FILE: &'static str = "./webhook_outbox.json";
+    pub const DEFAULT_WEBHOOK_OUTBOX_SIZE: usize = 10_000;
+    pub const DEFAULT_WEBHOOK_RETRY_MAX_INTERVAL: u64 = 300;
+    pub const DEFAULT_WEBHOOK_TIMEOUT: u64 = 10;
+    pub const DEFAULT_BRIDGE_KEEP_ALIVE: u64 = 60;
+    pub const DEFAULT_BRIDGE_RECONNECT_INTERVAL: u64 = 5;
+    pub const DEFAULT_PAYLOAD_SIZE_BUCKETS: &'static [usize] =
+        &[64, 256, 1024, 4096, 16_384, 65_536, 262_144, 1_048_576];
+    pub const DEFAULT_PRIORITY_STARVATION_LIMIT: usize = 10;
+    pub const DEFAULT_ERASE_RETAINED_TOPIC: &'static str = "devices/{client_id}/#";
+    pub const DEFAULT_CONNECTION_CHANNEL_CAPACITY: usize = 10_000;
+    pub const DEFAULT_CONNECTION_CHANNEL_FULL_POLICY: ChannelFullPolicy = ChannelFullPolicy::Drop;
 
     pub fn from_file<P: AsRef<Path>>(path: P) -> ConfigResult<Self> {
         TeleMQServerConfigSrc::from_file(path).map(From::from)
     }
+
+    pub fn from_file_strict<P: AsRef<Path>>(path: P) -> ConfigResult<Self> {
+        TeleMQServerConfigSrc::from_file_strict(path).map(From::from)
+    }
+
+    /// Addresses of MQTT listeners along with their transports.
+    pub fn listeners(&self) -> Vec<(ConnectionTransport, SocketAddr)> {
+        let mut listeners = vec![(ConnectionTransport::Tcp, self.tcp_addr)];
+        for (transport, addr) in [
+            (ConnectionTransport::Tls, self.tls_addr),
+            (ConnectionTransport::Ws, self.ws_addr),
+            (ConnectionTransport::Wss, self.wss_addr),
+            (ConnectionTransport::MqttSn, self.mqttsn_addr),
+        ] {
+            if let Some(addr) = addr {
+                listeners.push((transport, addr));
+            }
+        }
+
+        listeners
+    }
+
+    fn default_tls_alpn_protocols() -> Vec<String> {
+        Self::DEFAULT_TLS_ALPN_PROTOCOLS
+            .iter()
+            .map(|protocol| protocol.to_string())
+            .collect()
+    }
+
+    fn default_ws_subprotocols() -> Vec<String> {
+        Self::DEFAULT_WS_SUBPROTOCOLS
+            .iter()
+            .map(|subprotocol| subprotocol.to_string())
+            .collect()
+    }
 }
 
 fn local_listener(port: u16) -> SocketAddr {
@@ -370,6 +2109,8 @@ fn local_listener(port: u16) -> SocketAddr {
 pub enum TeleMQServerConfigError {
     ConfigFile(String),
     WrongValue(String),
+    /// paths of fields rejected by the strict mode
+    UnknownFields(Vec<String>),
 }
 
 impl From<IoError> for TeleMQServerConfigError {
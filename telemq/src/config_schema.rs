@@ -0,0 +1,578 @@
+//! A machine-readable schema of the config file, printed by `telemq --print-config-schema`,
+//! and the strict mode of `--strict-config`, which rejects fields missing from the schema.
+//!
+//! Field names are kept in sync with the config structs by a test comparing them with names
+//! serde expects. A field without a default value is not set unless the config sets it, see
+//! docs/telemq_config.md for what it means for every field.
+use serde::{
+    de::{self, value::Error as NamesError, Deserializer, Visitor},
+    forward_to_deserialize_any, Deserialize, Serialize,
+};
+use serde_json::{json, Value as JsonValue};
+
+use crate::{
+    admin_api::PayloadEncoding,
+    client_id_generator::ClientIdScheme,
+    config::{
+        AdminApiRole, AuthUnreachablePolicy, BridgeDirection, JwtAlgorithm, LogFormat,
+        PayloadFormat, Priority, RetainHandling, TeleMQServerConfig as Config,
+    },
+    connection_channel::ChannelFullPolicy,
+    session_state::QueueOverflowPolicy,
+    tls_listener::TlsVersion,
+};
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Kind {
+    String,
+    Integer,
+    Boolean,
+    /// a socket address, e.g. `127.0.0.1:1883` or `[::]:1883`
+    Address,
+    Enum {
+        values: &'static [&'static str],
+    },
+    Array {
+        items: Box<Kind>,
+    },
+    /// a table of known fields
+    Table {
+        fields: Vec<FieldSchema>,
+    },
+    /// a table of arbitrary keys
+    Map {
+        values: Box<Kind>,
+    },
+}
+
+#[derive(Serialize, Debug)]
+pub struct FieldSchema {
+    pub name: &'static str,
+    #[serde(flatten)]
+    pub kind: Kind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<&'static str>,
+    pub required: bool,
+    /// `null` if the field is not set by default
+    pub default: JsonValue,
+}
+
+impl FieldSchema {
+    fn new(name: &'static str, kind: Kind) -> Self {
+        FieldSchema {
+            name,
+            kind,
+            unit: None,
+            required: false,
+            default: JsonValue::Null,
+        }
+    }
+
+    fn with_default(mut self, default: JsonValue) -> Self {
+        self.default = default;
+        self
+    }
+
+    fn with_unit(mut self, unit: &'static str) -> Self {
+        self.unit = Some(unit);
+        self
+    }
+
+    fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+}
+
+fn string(name: &'static str) -> FieldSchema {
+    FieldSchema::new(name, Kind::String)
+}
+
+fn integer(name: &'static str) -> FieldSchema {
+    FieldSchema::new(name, Kind::Integer)
+}
+
+fn seconds(name: &'static str) -> FieldSchema {
+    integer(name).with_unit("seconds")
+}
+
+fn boolean(name: &'static str) -> FieldSchema {
+    FieldSchema::new(name, Kind::Boolean)
+}
+
+fn address(name: &'static str) -> FieldSchema {
+    FieldSchema::new(name, Kind::Address)
+}
+
+fn enumeration<'de, T: Deserialize<'de>>(name: &'static str) -> FieldSchema {
+    FieldSchema::new(
+        name,
+        Kind::Enum {
+            values: names_of::<T>(),
+        },
+    )
+}
+
+fn array(name: &'static str, items: Kind) -> FieldSchema {
+    FieldSchema::new(
+        name,
+        Kind::Array {
+            items: Box::new(items),
+        },
+    )
+}
+
+fn table(name: &'static str, fields: Vec<FieldSchema>) -> FieldSchema {
+    FieldSchema::new(name, Kind::Table { fields })
+}
+
+fn tables(name: &'static str, fields: Vec<FieldSchema>) -> FieldSchema {
+    array(name, Kind::Table { fields })
+}
+
+/// Fields of the config file.
+pub fn config_schema() -> Vec<FieldSchema> {
+    vec![
+        string("broker_id").required(),
+        string("cluster_id").required(),
+        string("account_id").required(),
+        enumeration::<ClientIdScheme>("assigned_client_id_scheme").with_default(json!("uuid")),
+        string("assigned_client_id_prefix").with_default(json!("")),
+        integer("max_connections").with_default(json!(Config::DEFAULT_MAX_CONNECTIONS)),
+        integer("connection_drain_rate").with_unit("connections per second"),
+        integer("tcp_port").with_default(json!(Config::DEFAULT_TCP_PORT)),
+        integer("tls_port").with_default(json!(Config::DEFAULT_TLS_PORT)),
+        string("cert_file"),
+        string("key_file"),
+        boolean("tls_fingerprint").with_default(json!(false)),
+        array("tls_alpn_protocols", Kind::String)
+            .with_default(json!(Config::DEFAULT_TLS_ALPN_PROTOCOLS)),
+        boolean("tls_alpn_required").with_default(json!(false)),
+        enumeration::<TlsVersion>("tls_min_version").with_default(json!("1.2")),
+        array("tls_cipher_suites", Kind::String),
+        seconds("tls_cert_watch_interval"),
+        integer("ws_port"),
+        integer("wss_port"),
+        integer("mqttsn_port"),
+        address("tcp_bind"),
+        address("tls_bind"),
+        address("ws_bind"),
+        address("wss_bind"),
+        address("mqttsn_bind"),
+        string("ws_path").with_default(json!(Config::DEFAULT_WS_PATH)),
+        array("ws_subprotocols", Kind::String).with_default(json!(Config::DEFAULT_WS_SUBPROTOCOLS)),
+        seconds("ws_ping_interval"),
+        boolean("ws_pong_as_activity").with_default(json!(Config::DEFAULT_WS_PONG_AS_ACTIVITY)),
+        seconds("activity_check_interval")
+            .with_default(json!(Config::DEFAULT_ACTIVITY_CHECK_INTERVAL)),
+        seconds("backup_interval").with_default(json!(Config::DEFAULT_BACKUP_INTERVAL)),
+        seconds("keep_alive").with_default(json!(Config::DEFAULT_KEEP_ALIVE)),
+        string("log_dest").with_default(json!(Config::DEFAULT_LOG)),
+        string("log_level").with_default(json!(Config::DEFAULT_LOG_LEVEL)),
+        enumeration::<LogFormat>("log_format").with_default(json!("text")),
+        FieldSchema::new(
+            "log_targets",
+            Kind::Map {
+                values: Box::new(Kind::String),
+            },
+        ),
+        integer("max_packet_size").with_unit("bytes"),
+        integer("max_subs_per_client"),
+        array("subscription_blacklist", Kind::String),
+        seconds("max_storage_duration"),
+        boolean("anonymous_allowed").with_default(json!(Config::DEFAULT_ANONYMOUS_ALLOWED)),
+        string("auth_endpoint"),
+        seconds("auth_endpoint_timeout").with_default(json!(Config::DEFAULT_AUTH_ENDPOINT_TIMEOUT)),
+        integer("auth_endpoint_max_retries")
+            .with_default(json!(Config::DEFAULT_AUTH_ENDPOINT_MAX_RETRIES)),
+        seconds("auth_endpoint_cache_ttl"),
+        seconds("auth_endpoint_negative_cache_ttl"),
+        enumeration::<AuthUnreachablePolicy>("auth_unreachable_policy").with_default(json!("deny")),
+        array("auth_unreachable_topics", Kind::String),
+        string("auth_file"),
+        string("auth_file_shadow"),
+        table(
+            "auth_jwt",
+            vec![
+                enumeration::<JwtAlgorithm>("algorithm").required(),
+                string("secret"),
+                string("public_key_file"),
+                string("client_id_claim").with_default(json!("sub")),
+                string("acl_claim").with_default(json!("acl")),
+                string("issuer"),
+                string("audience"),
+            ],
+        ),
+        seconds("sys_topics_update_interval")
+            .with_default(json!(Config::DEFAULT_SYS_TOPICS_UPDATE_INTERVAL)),
+        boolean("sys_topics_per_client").with_default(json!(Config::DEFAULT_SYS_TOPICS_PER_CLIENT)),
+        boolean("transport_byte_counters").with_default(json!(false)),
+        string("session_state_store_url"),
+        integer("admin_api_port"),
+        address("admin_api_bind"),
+        boolean("admin_api_tls").with_default(json!(false)),
+        tables(
+            "admin_api_auth",
+            vec![
+                string("token"),
+                string("username"),
+                string("password"),
+                enumeration::<AdminApiRole>("role").required(),
+                array("routes", Kind::String),
+            ],
+        ),
+        integer("metrics_port"),
+        array("ip_whitelist", Kind::String),
+        boolean("reject_on_session_recovery_failure")
+            .with_default(json!(Config::DEFAULT_REJECT_ON_SESSION_RECOVERY_FAILURE)),
+        string("handover_socket"),
+        integer("tcp_bandwidth_limit").with_unit("bytes per second"),
+        integer("tls_bandwidth_limit").with_unit("bytes per second"),
+        integer("ws_bandwidth_limit").with_unit("bytes per second"),
+        integer("wss_bandwidth_limit").with_unit("bytes per second"),
+        string("tcp_topic_prefix"),
+        string("tls_topic_prefix"),
+        string("ws_topic_prefix"),
+        string("wss_topic_prefix"),
+        boolean("topic_strip_trailing_slash")
+            .with_default(json!(Config::DEFAULT_TOPIC_NORMALIZATION)),
+        boolean("topic_collapse_separators")
+            .with_default(json!(Config::DEFAULT_TOPIC_NORMALIZATION)),
+        boolean("topic_case_insensitive").with_default(json!(Config::DEFAULT_TOPIC_NORMALIZATION)),
+        array("payload_size_buckets", Kind::Integer)
+            .with_unit("bytes")
+            .with_default(json!(Config::DEFAULT_PAYLOAD_SIZE_BUCKETS)),
+        boolean("publish_disconnect_reason")
+            .with_default(json!(Config::DEFAULT_PUBLISH_DISCONNECT_REASON)),
+        boolean("wait_for_state_store").with_default(json!(false)),
+        boolean("wait_for_auth_endpoint").with_default(json!(false)),
+        seconds("startup_wait_timeout").with_default(json!(Config::DEFAULT_STARTUP_WAIT_TIMEOUT)),
+        seconds("startup_wait_retry_interval")
+            .with_default(json!(Config::DEFAULT_STARTUP_WAIT_RETRY_INTERVAL)),
+        string("retained_store_file"),
+        string("internal_subscriptions_file"),
+        integer("retained_max_qos"),
+        array("erase_retained_topics", Kind::String)
+            .with_default(json!([Config::DEFAULT_ERASE_RETAINED_TOPIC])),
+        integer("max_queued_messages_per_client"),
+        enumeration::<QueueOverflowPolicy>("queue_overflow_policy")
+            .with_default(json!("drop-oldest")),
+        boolean("queue_qos0_messages").with_default(json!(Config::DEFAULT_QUEUE_QOS0_MESSAGES)),
+        seconds("session_expiry_interval"),
+        seconds("message_expiry_interval"),
+        seconds("will_delay_interval"),
+        seconds("retry_interval"),
+        integer("max_retries"),
+        integer("max_inflight_messages"),
+        integer("connection_channel_capacity")
+            .with_default(json!(Config::DEFAULT_CONNECTION_CHANNEL_CAPACITY)),
+        enumeration::<ChannelFullPolicy>("connection_channel_full_policy")
+            .with_default(json!("drop")),
+        integer("subscription_tree_warning_nodes"),
+        integer("routing_cache_size").with_default(json!(Config::DEFAULT_ROUTING_CACHE_SIZE)),
+        string("time_sync_topic").with_default(json!(Config::DEFAULT_TIME_SYNC_TOPIC)),
+        seconds("time_sync_interval"),
+        string("time_sync_request_topic"),
+        string("queue_depth_request_topic"),
+        string("webhook_url"),
+        string("webhook_outbox_file").with_default(json!(Config::DEFAULT_WEBHOOK_OUTBOX_FILE)),
+        integer("webhook_outbox_size").with_default(json!(Config::DEFAULT_WEBHOOK_OUTBOX_SIZE)),
+        seconds("webhook_retry_max_interval")
+            .with_default(json!(Config::DEFAULT_WEBHOOK_RETRY_MAX_INTERVAL)),
+        seconds("webhook_timeout").with_default(json!(Config::DEFAULT_WEBHOOK_TIMEOUT)),
+        integer("cluster_port"),
+        array("cluster_peers", Kind::String),
+        tables(
+            "bridge",
+            vec![
+                string("name").required(),
+                string("address").required(),
+                string("client_id"),
+                string("username"),
+                string("password"),
+                seconds("keep_alive").with_default(json!(Config::DEFAULT_BRIDGE_KEEP_ALIVE)),
+                seconds("reconnect_interval")
+                    .with_default(json!(Config::DEFAULT_BRIDGE_RECONNECT_INTERVAL)),
+                boolean("broker_state_will").with_default(json!(false)),
+                tables(
+                    "topics",
+                    vec![
+                        string("pattern").required(),
+                        enumeration::<BridgeDirection>("direction").required(),
+                        integer("qos").with_default(json!(0)),
+                        string("local_prefix"),
+                        string("remote_prefix"),
+                    ],
+                )
+                .required(),
+            ],
+        ),
+        tables(
+            "retained_bypass",
+            vec![
+                string("filter"),
+                string("client_id_prefix"),
+                enumeration::<RetainHandling>("retain_handling"),
+            ],
+        ),
+        integer("load_shedding_rss_watermark").with_unit("megabytes"),
+        integer("load_shedding_lag_watermark").with_unit("milliseconds"),
+        array("load_shedding_topics", Kind::String),
+        tables(
+            "priority_topics",
+            vec![
+                string("filter").required(),
+                enumeration::<Priority>("priority").required(),
+            ],
+        ),
+        integer("priority_starvation_limit")
+            .with_default(json!(Config::DEFAULT_PRIORITY_STARVATION_LIMIT)),
+        tables(
+            "quiet_hours",
+            vec![
+                string("tag").required(),
+                string("client_id_prefix"),
+                string("start").required(),
+                string("end").required(),
+            ],
+        ),
+        tables(
+            "bootstrap",
+            vec![
+                string("tag").required(),
+                string("client_id_prefix"),
+                string("endpoint"),
+                tables(
+                    "messages",
+                    vec![
+                        string("topic").required(),
+                        string("payload").required(),
+                        enumeration::<PayloadEncoding>("encoding").with_default(json!("utf8")),
+                        integer("qos").with_default(json!(0)),
+                    ],
+                ),
+            ],
+        ),
+        tables(
+            "payload_formats",
+            vec![
+                string("filter").required(),
+                enumeration::<PayloadFormat>("format").required(),
+            ],
+        ),
+        string("payload_dead_letter_topic"),
+    ]
+}
+
+/// The schema printed by `--print-config-schema`, a JSON object with `fields` of the config.
+pub fn config_schema_json() -> serde_json::Result<String> {
+    #[derive(Serialize)]
+    struct Schema {
+        fields: Vec<FieldSchema>,
+    }
+
+    serde_json::to_string_pretty(&Schema {
+        fields: config_schema(),
+    })
+}
+
+/// Paths of fields of a config which are not in `schema`, e.g. `keep_alve` or
+/// `bridge[0].topics[1].qso`.
+pub fn unknown_fields(config: &JsonValue, schema: &[FieldSchema]) -> Vec<String> {
+    let mut unknown = vec![];
+    collect_unknown_fields(config, schema, "", &mut unknown);
+    unknown.sort();
+    unknown
+}
+
+fn collect_unknown_fields(
+    table: &JsonValue,
+    schema: &[FieldSchema],
+    path: &str,
+    unknown: &mut Vec<String>,
+) {
+    let table = match table.as_object() {
+        Some(table) => table,
+        // a wrong type is reported by serde
+        None => return,
+    };
+    for (name, value) in table {
+        let field_path = if path.is_empty() {
+            name.clone()
+        } else {
+            format!("{}.{}", path, name)
+        };
+        match schema.iter().find(|field| field.name == name) {
+            Some(field) => collect_unknown_nested(value, &field.kind, &field_path, unknown),
+            None => unknown.push(field_path),
+        }
+    }
+}
+
+fn collect_unknown_nested(value: &JsonValue, kind: &Kind, path: &str, unknown: &mut Vec<String>) {
+    match (kind, value) {
+        (Kind::Table { fields }, _) => collect_unknown_fields(value, fields, path, unknown),
+        (Kind::Array { items }, JsonValue::Array(values)) => {
+            for (i, value) in values.iter().enumerate() {
+                collect_unknown_nested(value, items, &format!("{}[{}]", path, i), unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Names of fields of a struct or of variants of an enum, as serde expects them.
+fn names_of<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut names: &'static [&'static str] = &[];
+    // the recorder always fails once it has the names
+    let _ = T::deserialize(NamesRecorder(&mut names));
+    names
+}
+
+/// A deserializer which records names a struct or an enum passes to it.
+struct NamesRecorder<'a>(&'a mut &'static [&'static str]);
+
+impl<'de, 'a> Deserializer<'de> for NamesRecorder<'a> {
+    type Error = NamesError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("neither a struct nor an enum"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("fields are recorded"))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = variants;
+        Err(de::Error::custom("variants are recorded"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        AdminApiCredentials, BootstrapConfig, BootstrapMessageConfig, BridgeConfig,
+        BridgeTopicConfig, JwtAuthConfig, PayloadFormatConfig, PriorityTopicConfig,
+        QuietHoursConfig, RetainedBypassConfig, TeleMQServerConfigSrc,
+    };
+
+    fn field<'a>(schema: &'a [FieldSchema], name: &str) -> &'a FieldSchema {
+        schema.iter().find(|field| field.name == name).unwrap()
+    }
+
+    fn fields_of(kind: &Kind) -> &[FieldSchema] {
+        match kind {
+            Kind::Table { fields } => fields,
+            Kind::Array { items } => fields_of(items),
+            kind => panic!("Table is expected, got {:?}", kind),
+        }
+    }
+
+    fn assert_same_names(schema: &[FieldSchema], expected: &[&str]) {
+        let names: Vec<&str> = schema.iter().map(|field| field.name).collect();
+        assert_eq!(names, expected);
+    }
+
+    fn assert_enum_defaults_are_variants(schema: &[FieldSchema]) {
+        for field in schema {
+            match field.kind {
+                Kind::Enum { values } if !field.default.is_null() => assert!(
+                    values.contains(&field.default.as_str().unwrap()),
+                    "default of {} is not one of {:?}",
+                    field.name,
+                    values
+                ),
+                Kind::Table { ref fields } => assert_enum_defaults_are_variants(fields),
+                Kind::Array { ref items } => {
+                    if let Kind::Table { ref fields } = **items {
+                        assert_enum_defaults_are_variants(fields)
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn schema_has_fields_of_config_structs() {
+        let schema = config_schema();
+        assert_same_names(&schema, names_of::<TeleMQServerConfigSrc>());
+        assert_same_names(
+            fields_of(&field(&schema, "auth_jwt").kind),
+            names_of::<JwtAuthConfig>(),
+        );
+        assert_same_names(
+            fields_of(&field(&schema, "admin_api_auth").kind),
+            names_of::<AdminApiCredentials>(),
+        );
+        let bridge = fields_of(&field(&schema, "bridge").kind);
+        assert_same_names(bridge, names_of::<BridgeConfig>());
+        assert_same_names(
+            fields_of(&field(bridge, "topics").kind),
+            names_of::<BridgeTopicConfig>(),
+        );
+        assert_same_names(
+            fields_of(&field(&schema, "retained_bypass").kind),
+            names_of::<RetainedBypassConfig>(),
+        );
+        assert_same_names(
+            fields_of(&field(&schema, "priority_topics").kind),
+            names_of::<PriorityTopicConfig>(),
+        );
+        assert_same_names(
+            fields_of(&field(&schema, "quiet_hours").kind),
+            names_of::<QuietHoursConfig>(),
+        );
+        assert_same_names(
+            fields_of(&field(&schema, "payload_formats").kind),
+            names_of::<PayloadFormatConfig>(),
+        );
+        let bootstrap = fields_of(&field(&schema, "bootstrap").kind);
+        assert_same_names(bootstrap, names_of::<BootstrapConfig>());
+        assert_same_names(
+            fields_of(&field(bootstrap, "messages").kind),
+            names_of::<BootstrapMessageConfig>(),
+        );
+
+        assert_enum_defaults_are_variants(&schema);
+    }
+
+    #[test]
+    fn unknown_fields_are_found_in_nested_tables() {
+        let config = json!({
+            "broker_id": "node-1",
+            "keep_alve": 60,
+            "log_targets": {"telemq::auth": "debug"},
+            "auth_jwt": {"algorithm": "HS256", "secert": "x"},
+            "bridge": [{
+                "name": "cloud",
+                "address": "cloud:1883",
+                "topics": [{"pattern": "#", "direction": "out"}, {"pattern": "#", "qso": 1}],
+            }],
+        });
+
+        assert_eq!(
+            unknown_fields(&config, &config_schema()),
+            vec!["auth_jwt.secert", "bridge[0].topics[1].qso", "keep_alve"]
+        );
+    }
+}
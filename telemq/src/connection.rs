@@ -1,18 +1,43 @@
 use crate::{
-    authenticator::Authenticator,
+    authenticator::{granted_qos, publish_allowed, subscribe_allowed, Authenticator},
+    client_id_generator::SharedClientIdGenerator,
+    config::PayloadFormat,
+    connection_channel::{connection_channel, ConnectionReceiver, ConnectionSender},
+    connection_config::ConnectionConfig,
+    connection_gate::{ConnectionMetadata, ConnectionTransport},
+    connection_info::ConnectionInfo,
     connection_provider::SessionConnectionProvider,
     control::{ControlMessage, ControlSender},
+    disconnect_reason::DisconnectReason,
+    load_shedding::Overload,
+    logger::{self, TARGET_AUTH},
+    mqtt_codec::{InboundPacket, MqttCodec, ProtocolVersion},
     net_connection::NetConnection,
-    session_state::SessionState,
+    payload_format::PayloadFormats,
+    priority::{PriorityScheduler, PRIORITY_BATCH_SIZE},
+    publish_metadata::PublishMetadata,
+    publish_ordering::PublishSequencer,
+    queue_depth::QueueDepth,
+    quiet_hours::QuietHours,
+    session_persistence::{PersistenceJob, PersistenceSender},
+    session_state::{PendingMessage, SessionState},
     session_state_store::SessionStateStore,
     stats::{StatsMessage, StatsSender},
-    transaction::TransactionSendState,
+    subscription_limits::SubscriptionLimits,
+    subscription_tree::SharedSubscriptionTree,
+    tls_fingerprint::Ja3Fingerprint,
+    topic_normalization::TopicNormalization,
+    topic_prefix::TopicPrefix,
+    transaction::{RetryPolicy, TransactionSendState},
+    transport_bytes::{CountingStream, TransportBytes},
+    ws_listener::WsKeepAlive,
 };
 
-use plugin_types::authenticator::{LoginResponse as AuthenticatorConnectResponse, TopicAccess};
+use plugin_types::authenticator::{LoginResponse as AuthenticatorConnectResponse, TopicACL};
 
 // FIXME: define logging levels
-use log::{error, info};
+use futures::future::pending;
+use log::{error, info, warn};
 use mqtt_packets::v_3_1_1::{
     builders::{
         ConnackBuilder, PingrespPacketBuilder, PubackPacketBuilder, PubcompPacketBuilder,
@@ -23,21 +48,23 @@ use mqtt_packets::v_3_1_1::{
     publish::fixed_header::{get_qos_level, set_qos_level},
     suback::return_code::ReturnCode as SubackReturnCode,
     subscribe::topic_subscription::TopicSubscription,
-    topic::{topics_match, Subscription, Topic},
+    topic::{Subscription, Topic},
     unsubscribe::variable::Variable as UnsubscribeVariable,
     utils::getters_setters,
     variable::Variable,
-    CPType, ControlPacket, ControlPacketCodec, QoS,
+    CPType, ControlPacket, QoS,
 };
-use std::{io, net::SocketAddr, sync::Arc, time};
+use mqtt_packets::v_5_0::{Properties, ReasonCode};
+use mqtt_packets::PacketCodecError;
+use std::{collections::VecDeque, io, net::SocketAddr, sync::Arc, time};
 use tokio::{
     net::TcpStream,
     select,
     sync::{
-        mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender},
-        RwLock,
+        mpsc::{channel, Receiver, Sender},
+        Notify, OwnedMutexGuard, RwLock,
     },
-    time::sleep,
+    time::{interval, sleep_until, timeout, Instant, Interval},
 };
 use tokio_rustls::server::TlsStream;
 use tokio_util::codec::Framed;
@@ -54,11 +81,12 @@ macro_rules! id {
 
 macro_rules! send_control {
     ($control_message: expr, $self: expr) => {
-        let message_type = $control_message.get_name();
-        if let Err(err) = $self.control_sender.send($control_message) {
+        let control_message = $control_message;
+        let message_type = control_message.get_name();
+        if let Err(err) = $self.control_sender.send(control_message) {
             error!(
-                "[Connection Worker@{:?}]: unable to send {}. {:?}",
-                $self.addr, message_type, err
+                "[Connection Worker@{}]: unable to send {}. {:?}",
+                $self.info, message_type, err
             );
         }
     };
@@ -66,11 +94,12 @@ macro_rules! send_control {
 
 macro_rules! send_stats {
     ($stat_message: expr, $self: expr) => {
-        let message_type = $stat_message.get_name();
-        if let Err(err) = $self.stats_sender.send($stat_message) {
+        let stat_message = $stat_message;
+        let message_type = stat_message.get_name();
+        if let Err(err) = $self.stats_sender.send(stat_message) {
             error!(
-                "[Connection Worker@{:?}]: unable to send {}. {:?}",
-                $self.addr, message_type, err
+                "[Connection Worker@{}]: unable to send {}. {:?}",
+                $self.info, message_type, err
             );
         }
     };
@@ -78,72 +107,118 @@ macro_rules! send_stats {
 
 macro_rules! disconnect {
     ($self: expr) => {{
-        if $self.state.is_connected() {
-            send_control!(
-                ControlMessage::ClientDisconnected {
-                    addr: $self.addr.clone(),
-                    clean_session: $self.state.has_clean_session(),
-                    client_id: id!($self),
-                    will_packet: $self.state.get_will_data().map(|will_data| {
-                        PublishPacketBuilder::new()
-                            .with_retained(will_data.3)
-                            .with_qos(&will_data.1)
-                            .with_topic(will_data.0)
-                            .with_payload(will_data.2)
-                            .produce()
-                    })
-                },
-                $self
-            );
-        }
+        let will_packet = $self.state.get_will_data().map(will_packet);
+        $self.close_session(will_packet);
         if let Err(err) = $self.disconnect.0.send(()).await {
             error!(
-                "[Connection Worker@{:?}]: Unable to close connection. {:?}",
-                $self.addr, err
+                "[Connection Worker@{}]: Unable to close connection. {:?}",
+                $self.info, err
             );
         }
     }};
 }
 
 macro_rules! send {
-    ($package: expr, $self: expr) => {
-        if $self.packets.send_packet($package).await.is_err() {
-            error!(
-                "[Connection Worker@{:?}]: Unable to send message, disconnecting",
-                $self.addr
-            );
-            Err::<(), ()>(())
-        } else {
-            send_stats!(
-                StatsMessage::new_packet_processed_send(id!($self), &$package),
-                $self
-            );
+    ($package: expr, $self: expr) => {{
+        // topics are sent to a client without a prefix of its listener
+        let package = $self.topic_prefix.unmount($package);
+        match $self.packets.send_packet(&package).await {
+            Ok(()) => {
+                send_stats!(
+                    StatsMessage::new_packet_processed_send(id!($self), &package),
+                    $self
+                );
 
-            Ok::<(), ()>(())
+                Ok::<(), PacketCodecError>(())
+            }
+            Err(PacketCodecError::Encoding(err)) => {
+                // the broker has built a packet it can't encode, the client is not to blame
+                error!(
+                    "[Connection Worker@{}]: Unable to encode {:?}. {}",
+                    $self.info, package, err
+                );
+                Err(PacketCodecError::Encoding(err))
+            }
+            Err(err) => {
+                error!(
+                    "[Connection Worker@{}]: Unable to send message. {}",
+                    $self.info, err
+                );
+                Err(err)
+            }
         }
-    };
+    }};
 }
 
+/// The connection is kept if a packet can't be encoded, it's dropped and logged by `send!`.
 macro_rules! send_or_disconnect {
     ($package: expr, $self: expr) => {
-        if (send!($package, $self)).is_err() {
-            error!("Unable to send message, disconnecting");
-            disconnect!($self);
+        match send!($package, $self) {
+            Ok(()) | Err(PacketCodecError::Encoding(_)) => {}
+            Err(_) => {
+                error!("Unable to send message, disconnecting");
+                disconnect!($self);
+            }
         }
     };
 }
 
-pub type ConnectionSender = UnboundedSender<ConnectionMessage>;
-pub type ConnectionReceiver = UnboundedReceiver<ConnectionMessage>;
+/// Max time a connection waits for a session to be released by another connection
+/// with the same client id.
+const SESSION_TAKEOVER_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+const SESSION_TAKEOVER_RETRY_INTERVAL: time::Duration = time::Duration::from_millis(100);
+/// Writes to a client disconnected by the broker, as its session has been taken over or it
+/// doesn't keep up with messages, are abandoned after this time, as its peer is likely gone
+/// or stalled.
+const FORCED_DISCONNECT_WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+fn will_packet((topic, qos, message, retain): (Topic, QoS, Vec<u8>, bool)) -> ControlPacket {
+    PublishPacketBuilder::new()
+        .with_retained(retain)
+        .with_qos(&qos)
+        .with_topic(topic)
+        .with_payload(message)
+        .produce()
+}
+
+/// A connection is closed if no packet is received within one and a half Keep Alive intervals
+/// requested by a client in CONNECT. `server_keep_alive` is used if a client sends 0.
+pub(crate) fn keep_alive_timeout(
+    client_keep_alive: time::Duration,
+    server_keep_alive: time::Duration,
+) -> time::Duration {
+    if client_keep_alive.is_zero() {
+        server_keep_alive
+    } else {
+        client_keep_alive * 3 / 2
+    }
+}
+
+/// Ticks of a retransmission timer, never completes if retransmission is turned off.
+async fn next_retry(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => pending().await,
+    }
+}
 
 #[derive(Debug)]
 pub enum ConnectionMessage {
     Publish {
         packet: ControlPacket,
+        metadata: PublishMetadata,
         retained_for: Option<String>,
     },
-    // disconnect a single client (when a new client with the same id has connected)
-    Disconnect,
+    // disconnect a single client (when a new client with the same id has connected),
+    // a persistent session is saved before the connection is closed
+    Disconnect {
+        reason: DisconnectReason,
+    },
+    // disconnect a client above a lowered max_connections, a persistent session is saved
+    // before the connection is closed
+    DropOverLimit,
     // will be sent during the whole server shut down
     ShutDown,
 }
@@ -152,169 +227,343 @@ impl ConnectionMessage {
     pub fn get_name(&self) -> String {
         match self {
             ConnectionMessage::Publish { .. } => "ConnectionMessage::Publish".into(),
-            ConnectionMessage::Disconnect => "ConnectionMessage::Disconnect".into(),
+            ConnectionMessage::Disconnect { .. } => "ConnectionMessage::Disconnect".into(),
+            ConnectionMessage::DropOverLimit => "ConnectionMessage::DropOverLimit".into(),
             ConnectionMessage::ShutDown => "ConnectionMessage::ShutDown".into(),
         }
     }
 }
 
 pub struct Connection {
-    addr: SocketAddr,
+    info: Arc<ConnectionInfo>,
     pub packets: NetConnection,
     self_sender: Option<ConnectionSender>,
     message_receiver: ConnectionReceiver,
     state: SessionState,
-    last_activity: time::Instant,
+    last_activity: Instant,
     authenticator: Arc<RwLock<Authenticator>>,
     disconnect: (Sender<()>, Receiver<()>),
+    /// Notified by Control once a new connection takes the session over.
+    taken_over: Arc<Notify>,
+    /// Notified by the channel once a publish is dropped under the `disconnect` policy.
+    overflowed: Arc<Notify>,
     control_sender: ControlSender,
     stats_sender: StatsSender,
+    persistence_sender: PersistenceSender,
     inactivity_interval: time::Duration,
     acl: Option<AuthenticatorConnectResponse>,
+    /// Rules of `auth_file_shadow`, decisions are compared with `acl` but never enforced.
+    shadow_acl: Option<Vec<TopicACL>>,
     state_store: Arc<RwLock<SessionStateStore>>,
-    max_subs_per_client: Option<usize>,
+    subscription_limits: SubscriptionLimits,
+    topic_prefix: TopicPrefix,
+    topic_normalization: TopicNormalization,
+    /// Order in which publishes waiting for the connection are sent.
+    priority: PriorityScheduler,
+    reject_on_session_recovery_failure: bool,
+    publish_disconnect_reason: bool,
+    // if None => unacknowledged packets are re-sent only when a persistent session is resumed
+    retry_policy: Option<RetryPolicy>,
+    // if None => QoS 1 and QoS 2 messages are sent without waiting for acknowledgements
+    max_inflight_messages: Option<usize>,
+    publish_sequencer: PublishSequencer,
+    /// Exclusive ownership of a client id session, released once the connection is dropped.
+    session_guard: Option<OwnedMutexGuard<()>>,
+    /// Recorded in a client history once the connection is closed.
+    disconnect_reason: DisconnectReason,
+    messages_received: u64,
+    messages_sent: u64,
+    ws_keep_alive: WsKeepAlive,
+    last_ws_ping: Instant,
+    /// New clients are rejected while the broker is overloaded.
+    overload: Overload,
+    /// Subscribers of publishes are looked up here rather than by Control Worker.
+    subscription_tree: SharedSubscriptionTree,
+    /// Generates a client id for a client which connects with an empty one.
+    client_id_generator: SharedClientIdGenerator,
+    /// Answers requests of the client for its queue depth, `None` if they are not enabled.
+    queue_depth: Option<QueueDepth>,
+    quiet_hours: QuietHours,
+    /// Messages held while a quiet hours window of the client is open, in order they have
+    /// been received.
+    held_messages: VecDeque<PendingMessage>,
+    /// The end of the open quiet hours window, `None` if no window has been found open.
+    quiet_until: Option<Instant>,
+    /// Publishes with payloads of a wrong format are rejected.
+    payload_formats: PayloadFormats,
 }
 
 impl Connection {
     pub async fn new_tcp(
-        framed: Framed<TcpStream, ControlPacketCodec>,
+        framed: Framed<CountingStream<TcpStream>, MqttCodec>,
         addr: SocketAddr,
-        control_sender: ControlSender,
-        stats_sender: StatsSender,
-        authenticator: Arc<RwLock<Authenticator>>,
-        inactivity_interval: time::Duration,
-        state_store: Arc<RwLock<SessionStateStore>>,
-        max_subs_per_client: Option<usize>,
+        config: ConnectionConfig,
     ) -> io::Result<Self> {
-        let (tx_self, rx_self) = unbounded_channel();
-        let disconnect = channel(1);
-
-        let state = SessionState::NonConnected;
-        let last_activity = time::Instant::now();
-        let packets = NetConnection::new_tcp(framed);
+        let accepted_at = config.state_store.read().await.clock().now();
+        let info = ConnectionInfo::accepted(addr, ConnectionTransport::Tcp, None, accepted_at);
+        let packets = NetConnection::new_tcp(framed, config.bandwidth_limiter.clone());
 
-        Ok(Connection {
-            addr,
-            packets,
-            message_receiver: rx_self,
-            self_sender: Some(tx_self),
-            state,
-            last_activity,
-            authenticator,
-            disconnect,
-            control_sender,
-            stats_sender,
-            inactivity_interval,
-            acl: None,
-            state_store,
-            max_subs_per_client,
-        })
+        Ok(Self::new(info, packets, WsKeepAlive::default(), config))
     }
 
     pub async fn new_tls(
-        framed: Framed<TlsStream<TcpStream>, ControlPacketCodec>,
+        framed: Framed<TlsStream<CountingStream<TcpStream>>, MqttCodec>,
         addr: SocketAddr,
-        control_sender: ControlSender,
-        stats_sender: StatsSender,
-        authenticator: Arc<RwLock<Authenticator>>,
-        inactivity_interval: time::Duration,
-        state_store: Arc<RwLock<SessionStateStore>>,
-        max_subs_per_client: Option<usize>,
+        ja3: Option<Ja3Fingerprint>,
+        config: ConnectionConfig,
     ) -> io::Result<Self> {
-        let (tx_self, rx_self) = unbounded_channel();
-
-        let state = SessionState::NonConnected;
-        let last_activity = time::Instant::now();
-        let packets = NetConnection::new_tls(framed);
-        let disconnect = channel(1);
+        let tls = ConnectionMetadata::from_tls(framed.get_ref(), addr, ja3).tls;
+        let accepted_at = config.state_store.read().await.clock().now();
+        let info = ConnectionInfo::accepted(addr, ConnectionTransport::Tls, tls, accepted_at);
+        let packets = NetConnection::new_tls(framed, config.bandwidth_limiter.clone());
 
-        Ok(Connection {
-            addr,
-            packets,
-            message_receiver: rx_self,
-            self_sender: Some(tx_self),
-            state,
-            last_activity,
-            authenticator,
-            disconnect,
-            control_sender,
-            stats_sender,
-            inactivity_interval,
-            acl: None,
-            state_store,
-            max_subs_per_client,
-        })
+        Ok(Self::new(info, packets, WsKeepAlive::default(), config))
     }
 
     pub async fn new_ws(
         websocket: WebSocket,
-        codec: ControlPacketCodec,
+        codec: MqttCodec,
         addr: SocketAddr,
-        control_sender: ControlSender,
-        stats_sender: StatsSender,
-        authenticator: Arc<RwLock<Authenticator>>,
-        inactivity_interval: time::Duration,
-        state_store: Arc<RwLock<SessionStateStore>>,
-        max_subs_per_client: Option<usize>,
+        transport: ConnectionTransport,
+        transport_bytes: Option<Arc<TransportBytes>>,
+        ws_keep_alive: WsKeepAlive,
+        config: ConnectionConfig,
     ) -> io::Result<Self> {
-        let (tx_self, rx_self) = unbounded_channel();
-        let packets = NetConnection::new_ws((websocket, codec));
-        let disconnect = channel(1);
-        let state = SessionState::NonConnected;
-        let last_activity = time::Instant::now();
-
-        Ok(Connection {
-            addr,
+        let accepted_at = config.state_store.read().await.clock().now();
+        let info = ConnectionInfo::accepted(addr, transport, None, accepted_at);
+        let packets = NetConnection::new_ws(
+            (websocket, codec),
+            config.bandwidth_limiter.clone(),
+            transport_bytes,
+        );
+
+        Ok(Self::new(info, packets, ws_keep_alive, config))
+    }
+
+    fn new(
+        info: ConnectionInfo,
+        packets: NetConnection,
+        ws_keep_alive: WsKeepAlive,
+        config: ConnectionConfig,
+    ) -> Self {
+        let ConnectionConfig {
+            control_sender,
+            stats_sender,
+            persistence_sender,
+            authenticator,
+            inactivity_interval,
+            state_store,
+            subscription_limits,
+            priority_topics,
+            reject_on_session_recovery_failure,
+            publish_disconnect_reason,
+            retry_policy,
+            max_inflight_messages,
+            channel_limit,
+            bandwidth_limiter: _,
+            topic_prefix,
+            topic_normalization,
+            overload,
+            subscription_tree,
+            client_id_generator,
+            queue_depth,
+            quiet_hours,
+            payload_formats,
+        } = config;
+        let (tx_self, rx_self) = connection_channel(channel_limit, Some(stats_sender.clone()));
+        let overflowed = tx_self.overflowed();
+        let last_activity = Instant::now();
+
+        Connection {
+            info: Arc::new(info),
             packets,
             message_receiver: rx_self,
             self_sender: Some(tx_self),
-            state,
+            state: SessionState::NonConnected,
             last_activity,
             authenticator,
-            disconnect,
+            disconnect: channel(1),
+            taken_over: Arc::new(Notify::new()),
+            overflowed,
             control_sender,
             stats_sender,
+            persistence_sender,
             inactivity_interval,
             acl: None,
+            shadow_acl: None,
             state_store,
-            max_subs_per_client,
-        })
+            subscription_limits,
+            topic_prefix,
+            topic_normalization,
+            priority: PriorityScheduler::new(priority_topics),
+            reject_on_session_recovery_failure,
+            publish_disconnect_reason,
+            retry_policy,
+            max_inflight_messages,
+            publish_sequencer: PublishSequencer::default(),
+            session_guard: None,
+            disconnect_reason: DisconnectReason::ConnectionLost,
+            messages_received: 0,
+            messages_sent: 0,
+            ws_keep_alive,
+            last_ws_ping: last_activity,
+            overload,
+            subscription_tree,
+            client_id_generator,
+            queue_depth,
+            quiet_hours,
+            payload_formats,
+            held_messages: VecDeque::new(),
+            quiet_until: None,
+        }
     }
 }
 
 impl Connection {
-    pub async fn run(mut self) -> io::Result<()> {
+    pub async fn run(self) -> io::Result<()> {
+        let info = self.info.clone();
+        logger::with_connection(info, self.run_in_context()).await
+    }
+
+    async fn run_in_context(mut self) -> io::Result<()> {
+        let taken_over = self.taken_over.clone();
+        let overflowed = self.overflowed.clone();
+        let result = select! {
+            result = self.serve() => result,
+            // the connection may be blocked on writing to a peer which is gone, e.g. after
+            // NAT rebinding, so the session is released without waiting for the write
+            _ = taken_over.notified() => {
+                self.on_disconnect_requested(DisconnectReason::SessionTakenOver).await;
+                Ok(())
+            }
+            _ = overflowed.notified() => {
+                self.on_disconnect_requested(DisconnectReason::SlowConsumer).await;
+                Ok(())
+            }
+        };
+        // e.g. CONNACK of a rejected client
+        let flush = if matches!(
+            self.disconnect_reason,
+            DisconnectReason::SessionTakenOver | DisconnectReason::SlowConsumer
+        ) {
+            timeout(FORCED_DISCONNECT_WRITE_TIMEOUT, self.packets.flush())
+                .await
+                .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into()))
+        } else {
+            self.packets.flush().await
+        };
+        if let Err(err) = flush {
+            info!(
+                "[Connection Worker@{}]: Unable to flush packets. {}",
+                self.info, err
+            );
+        }
+        self.report_disconnect();
+        self.save_history();
+        result
+    }
+
+    async fn serve(&mut self) -> io::Result<()> {
+        let mut retry_timer = self
+            .retry_policy
+            .map(|retry_policy| interval(retry_policy.interval));
         loop {
+            // packets of a fan-out are sent together once Control Worker has no more of them
+            if self.packets.has_unflushed() && self.message_receiver.is_empty() {
+                if let Err(err) = self.packets.flush().await {
+                    info!(
+                        event = "connection_lost";
+                        "[Connection Worker@{}]: Connection is lost. {}",
+                        self.info, err
+                    );
+                    break;
+                }
+            }
+            let next_ws_ping = self.ws_keep_alive.next_ping(
+                self.last_activity,
+                self.packets.last_ws_frame(),
+                self.last_ws_ping,
+            );
+            let keep_alive_deadline = self.keep_alive_deadline();
+            let quiet_until = self.quiet_until.filter(|_| !self.held_messages.is_empty());
             select! {
               Some(cmd_message) = self.message_receiver.recv() => {
+                let cmd_message = match cmd_message {
+                  ConnectionMessage::Publish{packet, retained_for, ..} if self.priority.is_enabled() => {
+                    match self.forward_publishes_by_priority(packet, retained_for).await {
+                      Some(cmd_message) => cmd_message,
+                      None => continue,
+                    }
+                  }
+                  cmd_message => cmd_message,
+                };
                 match cmd_message {
-                  ConnectionMessage::Publish{packet, retained_for} => {
+                  ConnectionMessage::Publish{packet, retained_for, ..} => {
                     self.forward_publish(packet, retained_for).await;
                   }
-                  ConnectionMessage::Disconnect => {
-                    info!("[Connection Worker@{:?}]: Disconnecting client. New clinet with the same id connected", self.addr);
+                  ConnectionMessage::Disconnect{reason} => {
+                    info!(event = "disconnect_requested"; "[Connection Worker@{}]: Disconnecting client. {}", self.info, reason);
+                    self.send_disconnect_reason(reason).await;
+                    self.shut_down().await;
+                    return Ok(());
+                  },
+                  ConnectionMessage::DropOverLimit => {
+                    info!("[Connection Worker@{}]: Disconnecting client. Too many connections are open", self.info);
+                    self.send_disconnect_reason(DisconnectReason::ConnectionLimit).await;
+                    self.shut_down().await;
                     return Ok(());
                   },
                   ConnectionMessage::ShutDown => {
+                    self.send_disconnect_reason(DisconnectReason::ServerShuttingDown).await;
                     self.shut_down().await;
                   }
                 }
               }
               Some(_) = self.disconnect.1.recv() => {
-                info!("[Connection Worker@{:?}]: Disconnecting client. Signal", self.addr);
+                info!("[Connection Worker@{}]: Disconnecting client. Signal", self.info);
                 return Ok(());
               }
-              _ = sleep(self.inactivity_interval) => {
-                info!("[Connection Worker@{:?}]: Disconnecting client due to inactivity", self.addr);
+              _ = sleep_until(keep_alive_deadline) => {
+                if self.keep_alive_deadline() > Instant::now() {
+                  // a websocket pong has been received in the meantime
+                  continue;
+                }
+                info!(event = "keep_alive_timeout"; "[Connection Worker@{}]: Disconnecting client due to inactivity", self.info);
+                self.send_disconnect_reason(DisconnectReason::KeepAliveTimeout).await;
                 disconnect!(self);
                 break;
               }
+              _ = sleep_until(next_ws_ping.unwrap_or_else(Instant::now)), if next_ws_ping.is_some() => {
+                self.last_ws_ping = Instant::now();
+                if let Err(err) = self.packets.send_ws_ping().await {
+                  info!(event = "connection_lost"; "[Connection Worker@{}]: Connection is lost. {}", self.info, err);
+                  break;
+                }
+              }
+              _ = sleep_until(quiet_until.unwrap_or_else(Instant::now)), if quiet_until.is_some() => {
+                self.end_quiet_hours().await;
+              }
+              _ = next_retry(&mut retry_timer) => {
+                if !self.retransmit().await {
+                  self.send_disconnect_reason(DisconnectReason::RetriesExhausted).await;
+                  disconnect!(self);
+                  break;
+                }
+              }
               res = self.packets.next_packet() => match res {
-                Some(Ok(control_packet)) => {
-                  info!("[Connection Worker@{:?}]: control packet received: {:?}", self.addr, control_packet);
-                  self.handle_control_packet(control_packet).await;
+                Some(Ok(packet)) => {
+                  info!("[Connection Worker@{}]: packet received: {:?}", self.info, packet);
+                  self.handle_packet(packet).await;
                 },
-                Some(Err(err)) => {error!("{:?}", err);}
+                Some(Err(PacketCodecError::MalformedPacket(reason))) => {
+                  error!(event = "malformed_packet"; "[Connection Worker@{}]: Malformed packet received. {}. Disconnecting", self.info, reason);
+                  self.disconnect_with_reason(ReasonCode::MalformedPacket).await;
+                  break;
+                }
+                Some(Err(err)) => {
+                  info!(event = "connection_lost"; "[Connection Worker@{}]: Connection is lost. {}", self.info, err);
+                  break;
+                }
                 None => {
                   break;
                 }
@@ -322,51 +571,62 @@ impl Connection {
             }
         }
 
-        if self.state.is_connected() {
-            if let Err(err) = self
-                .control_sender
-                .send(ControlMessage::ClientDisconnected {
-                    addr: self.addr.clone(),
-                    clean_session: self.state.has_clean_session(),
-                    client_id: id!(self),
-                    will_packet: self.state.get_will_data().map(|will_data| {
-                        PublishPacketBuilder::new()
-                            .with_retained(will_data.3)
-                            .with_qos(&will_data.1)
-                            .with_topic(will_data.0)
-                            .with_payload(will_data.2)
-                            .produce()
-                    }),
-                })
-            {
-                error!(
-          "[Connection Worker@{:?}]: Unable to send ControlMessage::ClientDisconnected. {:?}",
-          self.addr, err
-        );
-            }
-        }
-
-        send_stats!(
-            StatsMessage::ClientDisconnected {
-                client_id: id!(self),
-            },
-            self
-        );
+        // the connection is lost, a persistent session outlives it
+        let will_packet = self.state.get_will_data().map(will_packet);
+        self.close_session(will_packet);
 
         info!(
-            "[Connection Worker@{:?}]: Client has been disconnected",
-            self.addr
+            "[Connection Worker@{}]: Client has been disconnected",
+            self.info
         );
 
         Ok(())
     }
 
-    async fn handle_control_packet(&mut self, control_packet: ControlPacket) {
-        self.last_activity = time::Instant::now();
+    fn keep_alive_deadline(&self) -> Instant {
+        self.ws_keep_alive
+            .last_activity(self.last_activity, self.packets.last_ws_frame())
+            + self.inactivity_interval
+    }
+
+    async fn handle_packet(&mut self, packet: InboundPacket) {
+        match packet {
+            InboundPacket::Packet { packet, properties } => {
+                self.handle_control_packet(packet, properties).await;
+            }
+            InboundPacket::Auth(auth) => {
+                // enhanced authentication is never negotiated, so a client must not send AUTH
+                error!(
+                    "[Connection Worker@{}]: AUTH {:?} received, enhanced authentication is not supported. Disconnecting",
+                    self.info, auth.reason_code
+                );
+                self.disconnect_with_reason(ReasonCode::ProtocolError).await;
+            }
+            InboundPacket::ProtocolError(reason_code) => {
+                error!(
+                    "[Connection Worker@{}]: Protocol error {:?}. Disconnecting",
+                    self.info, reason_code
+                );
+                self.disconnect_with_reason(reason_code).await;
+            }
+        }
+    }
+
+    /// `properties` are MQTT 5.0 properties of a packet, always empty for MQTT 3.1.1 clients.
+    async fn handle_control_packet(
+        &mut self,
+        control_packet: ControlPacket,
+        properties: Properties,
+    ) {
+        self.last_activity = Instant::now();
+        // topics are normalized and prefixed before ACL checks and routing
+        let control_packet = self
+            .topic_prefix
+            .mount(self.topic_normalization.normalize(control_packet));
 
         match control_packet.fixed_header.cp_type {
             CPType::Connect => {
-                self.connect(control_packet).await;
+                self.connect(control_packet, &properties).await;
             }
             CPType::Disconnect => {
                 self.disconnect().await;
@@ -381,7 +641,8 @@ impl Connection {
                 self.unsubscribe(control_packet).await;
             }
             CPType::Publish => {
-                self.publish(control_packet).await;
+                self.publish(control_packet, PublishMetadata::from(&properties))
+                    .await;
             }
             CPType::Puback => {
                 self.puback(&control_packet).await;
@@ -398,22 +659,109 @@ impl Connection {
             CPType::Connack | CPType::Suback | CPType::Pingresp | CPType::Unsuback => {
                 // disconnecting a client which sends broker's packets
                 error!(
-          "[Connection Worker@{:?}] Unexpected packet received from a client. {:?}. Disconnecting",
-          self.addr, control_packet
+          "[Connection Worker@{}] Unexpected packet received from a client. {:?}. Disconnecting",
+          self.info, control_packet
         );
+                self.send_disconnect_reason(DisconnectReason::ProtocolError)
+                    .await;
                 disconnect!(self);
             }
         }
     }
 
+    async fn disconnect_with_reason(&mut self, reason_code: ReasonCode) {
+        self.send_disconnect_reason(reason_code.into()).await;
+        if let Err(err) = self.packets.send_disconnect(reason_code).await {
+            error!(
+                "[Connection Worker@{}]: Unable to send DISCONNECT. {:?}",
+                self.info, err
+            );
+        }
+        disconnect!(self);
+    }
+
+    /// Tells a connected client why the broker is about to close its connection, by sending
+    /// a QoS 0 message to `devices/{client_id}/$disconnect_reason`. It's sent straight to
+    /// the client, regardless of its subscriptions, and only if `publish_disconnect_reason`
+    /// is enabled.
+    async fn send_disconnect_reason(&mut self, reason: DisconnectReason) {
+        self.disconnect_reason = reason;
+        if !self.publish_disconnect_reason || !self.state.is_connected() {
+            return;
+        }
+
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(Topic::make_from_string(format!(
+                "devices/{}/$disconnect_reason",
+                id!(self)
+            )))
+            .with_payload(reason.as_str().as_bytes().to_vec());
+        let packet = builder.build();
+        // the connection is closed anyway, so a failure is only logged
+        let _ = send!(&packet, self);
+    }
+
+    /// Counts a publish which payload doesn't have a format of its topic and publishes it to
+    /// the dead letter topic instead, if there is one.
+    fn reject_payload(&mut self, control_packet: &ControlPacket, format: PayloadFormat) {
+        let variable = match control_packet.variable {
+            Variable::Publish(ref variable) => variable,
+            _ => return,
+        };
+        warn!(
+            "[Connection Worker@{}]: Payload published to {:?} is not valid {:?}, the message is rejected",
+            self.info, variable.topic_name.original, format
+        );
+        send_stats!(StatsMessage::PayloadRejected { format }, self);
+
+        let dead_letter_topic = match self.payload_formats.dead_letter_topic(&variable.topic_name) {
+            Some(dead_letter_topic) => dead_letter_topic,
+            None => return,
+        };
+        let qos = get_qos_level(&control_packet.fixed_header).unwrap_or(QoS::Zero);
+        // a dead letter is never retained, so it doesn't replace a valid retained message
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(dead_letter_topic)
+            .with_payload(variable.payload.clone())
+            .with_qos(&qos);
+        send_control!(
+            ControlMessage::Publish {
+                publisher: Some(self.info.clone()),
+                packet: builder.build(),
+                metadata: PublishMetadata::new(),
+                sequence: Some(self.publish_sequencer.next()),
+                subscribers: None,
+            },
+            self
+        );
+    }
+
+    /// Replies to a queue depth request with a number of messages waiting to be sent to
+    /// the client.
+    async fn send_queue_depth(&mut self) {
+        let depth =
+            self.message_receiver.len() + self.state.pending_messages() + self.held_messages.len();
+        let reply = match self.queue_depth {
+            Some(ref queue_depth) => queue_depth.reply(&id!(self), depth),
+            None => return,
+        };
+        if let Some(packet) = reply {
+            send_or_disconnect!(&packet, self);
+        }
+    }
+
     // Packet Handlers
 
-    async fn connect(&mut self, mut control_packet: ControlPacket) {
+    async fn connect(&mut self, mut control_packet: ControlPacket, properties: &Properties) {
         if !self.state.is_non_connected() {
             error!(
-        "[Connection Worker@{:?}]: state is not in non connected state. Unable to connect a client",
-        self.addr.clone()
+        "[Connection Worker@{}]: state is not in non connected state. Unable to connect a client",
+        self.info
       );
+            self.send_disconnect_reason(DisconnectReason::ProtocolError)
+                .await;
             disconnect!(self);
             return;
         }
@@ -429,36 +777,101 @@ impl Connection {
             );
         }
 
+        if self.overload.is_overloaded() {
+            warn!(
+                "[Connection Worker@{}]: Broker is overloaded. Unable to connect a client",
+                self.info
+            );
+            let connack = ConnackBuilder::new()
+                .with_return_code(ConnackReturnCode::Unavailable)
+                .with_session_presented(false)
+                .build();
+            send_or_disconnect!(&connack, self);
+            disconnect!(self);
+            return;
+        }
+
+        if let Some(authentication_method) = properties.authentication_method() {
+            info!(
+                target: TARGET_AUTH,
+                "[Connection Worker@{}]: Authentication method {:?} is not supported",
+                self.info, authentication_method
+            );
+            let connack = ConnackBuilder::new()
+                .with_return_code(ConnackReturnCode::NotAuthorized)
+                .with_session_presented(false)
+                .build();
+            send_or_disconnect!(&connack, self);
+            return;
+        }
+
         if let Variable::Connect(ref mut variable) = control_packet.variable {
-            let client_id = variable.client_identifier.clone();
             let clean_session = variable.connect_flags.has_clean_session();
+            if variable.client_identifier.is_empty() {
+                // MQTT 3.1.1 leaves no way to resume a session of an assigned client id
+                if !clean_session && self.packets.protocol() == Some(ProtocolVersion::V3_1_1) {
+                    info!(
+                        target: TARGET_AUTH,
+                        "[Connection Worker@{}]: Empty client id with persistent session is rejected",
+                        self.info
+                    );
+                    let connack = ConnackBuilder::new()
+                        .with_return_code(ConnackReturnCode::IdRejected)
+                        .with_session_presented(false)
+                        .build();
+                    send_or_disconnect!(&connack, self);
+                    return;
+                }
+                variable.client_identifier = self.client_id_generator.generate();
+                self.packets
+                    .assign_client_id(variable.client_identifier.clone());
+            }
+            let client_id = variable.client_identifier.clone();
+            let username = variable.username.take();
 
             let allowed_res = self
                 .authenticator
                 .read()
                 .await
                 .connect(
-                    self.addr,
+                    self.info.addr,
                     client_id.clone(),
-                    variable.username.take(),
+                    username.clone(),
                     variable.password.take(),
+                    self.info.tls_fingerprint().map(String::from),
                 )
                 .await;
 
             match allowed_res {
                 Ok(response) => {
                     if !response.connection_allowed {
+                        let return_code = if response.client_id_rejected {
+                            ConnackReturnCode::IdRejected
+                        } else {
+                            ConnackReturnCode::BadUsernameOrPassword
+                        };
+                        info!(
+                            target: TARGET_AUTH,
+                            event = "connection_refused", client_id = client_id.as_str();
+                            "[Connection Worker@{}]: Client {:?} is not allowed to connect. {:?}",
+                            self.info, client_id, return_code
+                        );
                         let connack = ConnackBuilder::new()
-                            .with_return_code(ConnackReturnCode::BadUsernameOrPassword)
+                            .with_return_code(return_code)
                             .with_session_presented(false)
                             .build();
                         send_or_disconnect!(&connack, self);
                         return;
                     }
                     self.acl = Some(response);
+                    self.shadow_acl = self
+                        .authenticator
+                        .read()
+                        .await
+                        .shadow_topics_acl(&client_id, username.as_deref());
                 }
                 Err(err) => {
-                    error!("[Authenticator Error]: {:?}", err);
+                    error!(target: TARGET_AUTH, "[Authenticator Error]: {:?}", err);
                     let connack = ConnackBuilder::new()
                         .with_return_code(ConnackReturnCode::Unavailable)
                         .with_session_presented(false)
@@ -468,12 +881,25 @@ impl Connection {
                 }
             }
 
-            match self.state_store.write().await.take_state(&client_id).await {
+            self.inactivity_interval =
+                keep_alive_timeout(variable.keep_alive.as_duration(), self.inactivity_interval);
+
+            if !self.acquire_session(&client_id).await {
+                let connack = ConnackBuilder::new()
+                    .with_return_code(ConnackReturnCode::Unavailable)
+                    .with_session_presented(false)
+                    .build();
+                send_or_disconnect!(&connack, self);
+                return;
+            }
+
+            let saved_state = self.state_store.write().await.take_state(&client_id).await;
+            match saved_state {
                 Ok(Some(connected_state)) => {
                     if !clean_session {
                         info!(
-                            "[Connection Worker@{:?}]: Recovering saved state\n\t{:?}",
-                            self.addr, connected_state
+                            "[Connection Worker@{}]: Recovering saved state\n\t{:?}",
+                            self.info, connected_state
                         );
                         self.state.make_connected(connected_state);
                         let connack = ConnackBuilder::new()
@@ -482,10 +908,7 @@ impl Connection {
                             .build();
                         send_or_disconnect!(&connack, self);
                     } else {
-                        info!(
-                            "[Connection Worker@{:?}]: Creating default state",
-                            self.addr
-                        );
+                        info!("[Connection Worker@{}]: Creating default state", self.info);
                         self.state.into_connected(SessionConnectionProvider {
                             client_id,
                             clean_session: variable.connect_flags.has_clean_session(),
@@ -501,10 +924,7 @@ impl Connection {
                     }
                 }
                 Ok(None) => {
-                    info!(
-                        "[Connection Worker@{:?}]: Creating default state",
-                        self.addr
-                    );
+                    info!("[Connection Worker@{}]: Creating default state", self.info);
                     self.state.into_connected(SessionConnectionProvider {
                         client_id,
                         clean_session: variable.connect_flags.has_clean_session(),
@@ -519,10 +939,22 @@ impl Connection {
                     send_or_disconnect!(&connack, self);
                 }
                 Err(err) => {
+                    // a client which asked for a persistent session should know
+                    // that its session has not been recovered
+                    let reject = !clean_session && self.reject_on_session_recovery_failure;
                     error!(
-            "[Connection Worker@{:?}]: Unable to connect with SessionStateStore, falling back to default state. {:?}",
-            self.addr, err
-          );
+                        "[Connection Worker@{}]: event=session_recovery_failed client_id={:?} clean_session={} rejected={} error={:?}",
+                        self.info, client_id, clean_session, reject, err
+                    );
+                    send_stats!(StatsMessage::SessionRecoveryFailed, self);
+                    if reject {
+                        let connack = ConnackBuilder::new()
+                            .with_return_code(ConnackReturnCode::Unavailable)
+                            .with_session_presented(false)
+                            .build();
+                        send_or_disconnect!(&connack, self);
+                        return;
+                    }
                     self.state.into_connected(SessionConnectionProvider {
                         client_id,
                         clean_session,
@@ -538,13 +970,39 @@ impl Connection {
                 }
             }
 
+            let (connected_at, first_connection) = {
+                let mut state_store = self.state_store.write().await;
+                let connected_at = state_store.clock().now();
+                let first_connection = state_store.client_connected(&id!(self), connected_at);
+                (connected_at, first_connection)
+            };
+            self.info = Arc::new(self.info.connected(
+                id!(self),
+                self.packets.protocol(),
+                connected_at,
+            ));
+            logger::update_connection(self.info.clone());
+            match self.info.tls_fingerprint() {
+                Some(tls_fingerprint) => info!(
+                    event = "client_connected", tls_fingerprint;
+                    "[Connection Worker@{}]: Client has been connected",
+                    self.info
+                ),
+                None => info!(
+                    event = "client_connected";
+                    "[Connection Worker@{}]: Client has been connected",
+                    self.info
+                ),
+            }
+            let will_packet = self.state.peek_will_data().map(will_packet);
             if let SessionState::Connected(ref connected) = self.state {
                 send_control!(
                     ControlMessage::ClientConnected {
                         sender: self.self_sender.clone().unwrap(),
-                        addr: self.addr.clone(),
-                        client_id: id!(self),
-                        clean_session: connected.clean_session
+                        connection: self.info.clone(),
+                        clean_session: connected.clean_session,
+                        will_packet,
+                        taken_over: Some(self.taken_over.clone()),
                     },
                     self
                 );
@@ -552,13 +1010,12 @@ impl Connection {
                 if !connected.subscriptions.is_empty() {
                     send_control!(
                         ControlMessage::AddSubscriptions {
-                            addr: self.addr.clone(),
+                            connection: self.info.clone(),
                             subscriptions: connected
                                 .subscriptions
                                 .iter()
                                 .map(|(_, s)| s.clone())
                                 .collect(),
-                            client_id: id!(self)
                         },
                         self
                     );
@@ -594,95 +1051,247 @@ impl Connection {
                 // unreachable
                 return;
             }
+            self.state.restart_retries(Instant::now());
 
             send_stats!(
                 StatsMessage::ClientConnected {
-                    addr: self.addr.clone(),
+                    connection: self.info.clone(),
                     clean_session: self.state.has_clean_session(),
-                    client_id: id!(self)
+                    first_connection,
                 },
                 self
             );
 
-            for cp in self.state.get_queued_messages() {
+            let now = self.state_store.read().await.clock().now();
+            let expired = self.state.remove_expired_messages(now);
+            self.on_messages_expired(expired);
+            let queued_messages = self.state.get_queued_messages();
+            for cp in self.priority.drain(queued_messages, |packet| packet) {
                 self.forward_publish(cp, None).await;
             }
         } else {
             error!(
-                "[Connection Worker@{:?}]: Wrong type of variable. Connect is expected",
-                self.addr
+                "[Connection Worker@{}]: Wrong type of variable. Connect is expected",
+                self.info
             );
             disconnect!(self);
             return;
         }
     }
 
-    async fn disconnect(&mut self) {
-        let client_id = id!(self);
-        if let Ok(connected_state) = self.state.into_closed() {
-            send_stats!(
-                StatsMessage::ClientDisconnected {
-                    client_id: client_id.clone()
+    /// Takes an exclusive ownership of a client id session. If the session is owned by
+    /// another connection, the connection is asked to save the session and to close.
+    /// Returns `false` if the session has not been released in time.
+    async fn acquire_session(&mut self, client_id: &String) -> bool {
+        let session_lock = self.state_store.write().await.session_lock(client_id);
+        if let Ok(guard) = session_lock.clone().try_lock_owned() {
+            self.session_guard = Some(guard);
+            return true;
+        }
+
+        let started_at = Instant::now();
+        loop {
+            // the owner may be still connecting and not known to Control yet, so the request
+            // is repeated until the session is released
+            send_control!(
+                ControlMessage::TakeOverSession {
+                    client_id: client_id.clone(),
+                    addr: self.info.addr,
                 },
                 self
             );
-
-            if let Err(err) = self
-                .state_store
-                .write()
-                .await
-                .save_state(connected_state)
-                .await
+            match timeout(
+                SESSION_TAKEOVER_RETRY_INTERVAL,
+                session_lock.clone().lock_owned(),
+            )
+            .await
             {
-                error!(
-                    "[Connection Worker@{:?}]: Unable save state in a State Store. {:?}",
-                    self.addr, err
-                );
+                Ok(guard) => {
+                    self.session_guard = Some(guard);
+                    let latency = started_at.elapsed();
+                    info!(
+                        event = "session_taken_over", latency_ms = latency.as_millis() as u64;
+                        "[Connection Worker@{}]: Session of {:?} has been taken over in {:?}",
+                        self.info, client_id, latency
+                    );
+                    send_stats!(StatsMessage::SessionTakenOver { latency }, self);
+                    return true;
+                }
+                Err(_) if started_at.elapsed() < SESSION_TAKEOVER_TIMEOUT => {}
+                Err(_) => {
+                    error!(
+                        "[Connection Worker@{}]: Session of {:?} has not been released in {:?}",
+                        self.info, client_id, SESSION_TAKEOVER_TIMEOUT
+                    );
+                    return false;
+                }
             }
         }
+    }
+
+    async fn disconnect(&mut self) {
+        self.disconnect_reason = DisconnectReason::ClientDisconnect;
+        // a will message is discarded on DISCONNECT
+        self.close_session(None);
 
         self.disconnect.0.send(()).await.expect(&format!(
-            "[Connection Worker@{:?}]: Unable to disconnect a client",
-            self.addr
+            "[Connection Worker@{}]: Unable to disconnect a client",
+            self.info
         ));
+    }
 
-        send_control!(
-            ControlMessage::ClientDisconnected {
-                addr: self.addr.clone(),
-                clean_session: self.state.has_clean_session(),
-                client_id: client_id.clone(),
-                will_packet: None,
-            },
-            self
+    async fn on_disconnect_requested(&mut self, reason: DisconnectReason) {
+        info!(
+            event = "disconnect_requested";
+            "[Connection Worker@{}]: Disconnecting client. {}",
+            self.info, reason
         );
+        // the reason is set before anything is written, so a timeout only skips the message
+        let send_reason = self.send_disconnect_reason(reason);
+        let _ = timeout(FORCED_DISCONNECT_WRITE_TIMEOUT, send_reason).await;
+        self.shut_down().await;
     }
 
     async fn shut_down(&mut self) {
-        if let Ok(connected_state) = self.state.into_closed() {
-            let client_id = connected_state.client_id.clone();
-            if !connected_state.clean_session {
-                if let Err(err) = self
-                    .state_store
-                    .write()
-                    .await
-                    .save_state(connected_state)
-                    .await
+        self.close_session(None);
+    }
+
+    /// Closes a connected session. A persistent session is handed over to the persistence
+    /// task together with the session lock, and Control Worker is informed about the
+    /// disconnection once the session is saved. The state of a clean session is discarded.
+    fn close_session(&mut self, will_packet: Option<ControlPacket>) {
+        let clean_session = self.state.has_clean_session();
+        if !clean_session {
+            // held messages are queued for the session like ones of an offline client
+            for pending in self.held_messages.drain(..) {
+                if get_qos_level(&pending.packet.fixed_header).is_ok_and(|qos| qos != QoS::Zero) {
+                    self.state.push_pending_message(pending);
+                }
+            }
+        }
+        let state = match self.state.into_closed() {
+            Ok(state) => state,
+            // not connected yet or already closed
+            Err(_) => return,
+        };
+        let mut undelivered = Vec::new();
+        if !clean_session {
+            // publishes sent from now on are queued by Control Worker
+            self.message_receiver.close();
+            while let Ok(message) = self.message_receiver.try_recv() {
+                if let ConnectionMessage::Publish {
+                    packet, metadata, ..
+                } = message
                 {
-                    error!(
-                        "[Connection Worker@{:?}]: Unable save state in a State Store. {:?}",
-                        self.addr, err
-                    );
+                    undelivered.push((packet, metadata));
                 }
             }
+        }
+        let disconnected = ControlMessage::ClientDisconnected {
+            connection: self.info.clone(),
+            clean_session,
+            will_packet,
+            undelivered,
+        };
+
+        if clean_session {
+            send_control!(disconnected, self);
+            // a new connection may be waiting for the session
+            self.session_guard = None;
+            return;
+        }
+        let job = PersistenceJob::SaveSession {
+            state: Box::new(state),
+            session_guard: self.session_guard.take(),
+            then: Box::new(disconnected),
+        };
+        if let Err(err) = self.persistence_sender.send(job) {
+            error!(
+                "[Connection Worker@{}]: Unable to save session. {:?}",
+                self.info, err
+            );
+        }
+    }
+
+    fn report_disconnect(&mut self) {
+        if self.info.client_id.is_empty() {
+            return;
+        }
+        info!(
+            event = "client_disconnected", reason:% = self.disconnect_reason;
+            "[Connection Worker@{}]: Connection is closed. {}",
+            self.info, self.disconnect_reason
+        );
+        // a client which has taken the session over is online
+        if self.disconnect_reason == DisconnectReason::SessionTakenOver {
+            return;
+        }
+        send_stats!(
+            StatsMessage::ClientDisconnected {
+                client_id: self.info.client_id.clone(),
+                reason: self.disconnect_reason,
+            },
+            self
+        );
+    }
+
+    fn save_history(&mut self) {
+        // connections closed before CONNECT has been accepted don't belong to any client
+        if self.info.client_id.is_empty() {
+            return;
+        }
+        let job = PersistenceJob::RecordDisconnect {
+            client_id: self.info.client_id.clone(),
+            reason: self.disconnect_reason,
+            messages_received: self.messages_received,
+            messages_sent: self.messages_sent,
+        };
+        if let Err(err) = self.persistence_sender.send(job) {
+            error!(
+                "[Connection Worker@{}]: Unable to record disconnection. {:?}",
+                self.info, err
+            );
+        }
+    }
 
-            let disconnect_message = ControlMessage::ClientDisconnected {
-                addr: self.addr.clone(),
-                clean_session: self.state.has_clean_session(),
-                client_id,
-                will_packet: None,
+    /// Re-sends PUBLISH and PUBREL packets which have not been acknowledged within a retry
+    /// interval. Returns `false` if a packet has run out of retries or can't be sent. MQTT 5.0
+    /// doesn't allow re-sending on a live connection, so MQTT 5.0 clients receive
+    /// unacknowledged packets only once their session is resumed.
+    async fn retransmit(&mut self) -> bool {
+        let retry_policy = match self.retry_policy {
+            Some(retry_policy) => retry_policy,
+            None => return true,
+        };
+        if self.packets.protocol() != Some(ProtocolVersion::V3_1_1) {
+            return true;
+        }
+
+        for transaction in self
+            .state
+            .retry_transactions(retry_policy.interval, Instant::now())
+        {
+            if retry_policy.is_exhausted(&transaction) {
+                info!(
+                    "[Connection Worker@{}]: Disconnecting client. Packet {:?} has not been acknowledged after {} retries",
+                    self.info, transaction.packet_id, transaction.retries - 1
+                );
+                return false;
+            }
+            let sent = match transaction.state {
+                TransactionSendState::NonAcked => send!(&transaction.control_packet, self),
+                TransactionSendState::PubReced => {
+                    let pubrel_packet = PubrelPacketBuilder::new(&transaction.packet_id).build();
+                    send!(&pubrel_packet, self)
+                }
+                _ => continue,
             };
-            send_control!(disconnect_message, self);
+            if sent.is_err() {
+                return false;
+            }
         }
+
+        true
     }
 
     async fn pingreq(&mut self, control_packet: ControlPacket) {
@@ -705,14 +1314,23 @@ impl Connection {
             Variable::Subscribe(variable) => variable,
             _ => {
                 error!(
-                    "[Connection Worker@{:?}]: Unexpected type of a variable",
-                    self.addr
+                    "[Connection Worker@{}]: Unexpected type of a variable",
+                    self.info
                 );
                 disconnect!(self);
                 return;
             }
         };
-        let topic_subs = &variable.subscriptions;
+        // QoS of a subscription is downgraded to `max_qos` of the ACL rule which allows it,
+        // so it's granted in SUBACK and caps QoS of messages sent to the client
+        let topic_subs = variable
+            .subscriptions
+            .iter()
+            .map(|sub| TopicSubscription {
+                qos: granted_qos(self.topics_acl(), &sub.topic_filter, &sub.qos),
+                topic_filter: sub.topic_filter.clone(),
+            })
+            .collect::<Vec<TopicSubscription>>();
         let packet_id = variable.packet_id;
         let subscriptions = topic_subs
             .iter()
@@ -737,8 +1355,8 @@ impl Connection {
 
         if let Err(err) = self.state.subscribe(allowed_subscriptions.clone()) {
             error!(
-                "[Connection Worker@{:?}]: Unable to add subscriptoins to a connection state. {:?}",
-                self.addr, err
+                "[Connection Worker@{}]: Unable to add subscriptoins to a connection state. {:?}",
+                self.info, err
             );
         }
 
@@ -761,19 +1379,27 @@ impl Connection {
             .with_return_codes(return_codes)
             .build();
 
-        send_or_disconnect!(&package, self);
-
+        // a topic filter repeated in a packet is subscribed to once, with the last QoS in the state
+        let mut filters: Vec<Subscription> = Vec::with_capacity(allowed_subscriptions.len());
+        for sub in allowed_subscriptions {
+            if !filters
+                .iter()
+                .any(|filter| filter.original == sub.topic_filter.original)
+            {
+                filters.push(sub.topic_filter);
+            }
+        }
+        // subscribers are registered before SUBACK, so a message published right after
+        // a client receives SUBACK is routed to it
         send_control!(
             ControlMessage::AddSubscriptions {
-                addr: self.addr.clone(),
-                subscriptions: allowed_subscriptions
-                    .iter()
-                    .map(|s| s.topic_filter.clone())
-                    .collect(),
-                client_id: id!(self)
+                connection: self.info.clone(),
+                subscriptions: filters,
             },
             self
         );
+
+        send_or_disconnect!(&package, self);
     }
 
     async fn unsubscribe(&mut self, control_packet: ControlPacket) {
@@ -787,22 +1413,23 @@ impl Connection {
                 packet_id,
                 subscriptions: to_unsubscribe,
             }) => {
-                send_control!(
-                    ControlMessage::RemoveSubscriptions {
-                        addr: self.addr.clone(),
-                        subscriptions: to_unsubscribe.clone(),
-                        client_id: id!(self),
-                    },
-                    self
-                );
-                if let Err(err) = self.state.unsubscribe(to_unsubscribe) {
+                // topic filters which have never been subscribed to are acknowledged as well,
+                // so an unsubscribe may be repeated; only a session which isn't connected fails
+                if let Err(err) = self.state.unsubscribe(to_unsubscribe.clone()) {
                     error!(
-                        "[Connection Worker@{:?}]: Unable to unsubscribe. {:?}",
-                        self.addr, err
+                        "[Connection Worker@{}]: Unable to unsubscribe. {:?}",
+                        self.info, err
                     );
                     disconnect!(self);
                     return;
                 }
+                send_control!(
+                    ControlMessage::RemoveSubscriptions {
+                        connection: self.info.clone(),
+                        subscriptions: to_unsubscribe,
+                    },
+                    self
+                );
                 let unsuback_packet = UnsubackPacketBuilder::new(packet_id).build();
                 send_or_disconnect!(&unsuback_packet, self);
             }
@@ -813,42 +1440,74 @@ impl Connection {
         }
     }
 
-    async fn publish(&mut self, control_packet: ControlPacket) {
+    async fn publish(&mut self, control_packet: ControlPacket, metadata: PublishMetadata) {
         send_stats!(
             StatsMessage::new_packet_processed_received(id!(self), &control_packet),
             self
         );
+        self.messages_received += 1;
 
         let variable = match &control_packet.variable {
             &Variable::Publish(ref variable) => variable,
             _ => {
                 error!(
-                    "[Connection Worker@{:?}]: Variable Header type does not match packet CPType",
-                    self.addr
+                    "[Connection Worker@{}]: Variable Header type does not match packet CPType",
+                    self.info
                 );
                 disconnect!(self);
                 return;
             }
         };
+        if let (Ok(QoS::Two), Some(packet_id)) = (
+            get_qos_level(&control_packet.fixed_header),
+            &variable.packet_id,
+        ) {
+            if self.state.is_receiving(packet_id) {
+                // the client has not received PUBREC, the message has been routed already
+                info!(
+                    "[Connection Worker@{}]: Duplicate of QoS 2 message {:?}, resending PUBREC",
+                    self.info, packet_id
+                );
+                let pubrec_packet = PubrecPacketBuilder::new(packet_id).build();
+                send_or_disconnect!(&pubrec_packet, self);
+                return;
+            }
+        }
+
         let topic = &variable.topic_name;
         let allowed = self.check_publish(&topic);
 
         if !allowed {
             info!(
-                "[Connection Worker@{:?}]: Unable to publish to {:?}. Publish is not allowed.",
-                self.addr, topic
+                target: TARGET_AUTH,
+                event = "publish_denied";
+                "[Connection Worker@{}]: Unable to publish to {:?}. Publish is not allowed.",
+                self.info, topic
             );
             return;
         }
 
-        send_control!(
-            ControlMessage::Publish {
-                addr: Some(self.addr.clone()),
-                packet: control_packet.clone(),
-                client_id: Some(id!(self))
-            },
-            self
-        );
+        if let Some(format) = self.payload_formats.violated(topic, &variable.payload) {
+            // the message is acknowledged anyway, so the client doesn't send it again
+            self.reject_payload(&control_packet, format);
+        } else if self
+            .queue_depth
+            .as_ref()
+            .is_some_and(|queue_depth| queue_depth.is_request(topic))
+        {
+            self.send_queue_depth().await;
+        } else {
+            send_control!(
+                ControlMessage::Publish {
+                    publisher: Some(self.info.clone()),
+                    packet: control_packet.clone(),
+                    metadata: metadata.clone(),
+                    sequence: Some(self.publish_sequencer.next()),
+                    subscribers: Some(self.subscription_tree.resolve(&topic.path)),
+                },
+                self
+            );
+        }
 
         let maybe_packet_id = getters_setters::get_packet_id(&control_packet.variable);
         match self
@@ -923,6 +1582,37 @@ impl Connection {
         }
     }
 
+    /// Forwards a publish along with publishes waiting in the channel, higher priority first.
+    /// Returns a message which has been received after them, it's handled once they are sent.
+    async fn forward_publishes_by_priority(
+        &mut self,
+        packet: ControlPacket,
+        retained_for: Option<String>,
+    ) -> Option<ConnectionMessage> {
+        let mut publishes = VecDeque::from([(packet, retained_for)]);
+        let mut next_message = None;
+        while publishes.len() < PRIORITY_BATCH_SIZE {
+            match self.message_receiver.try_recv() {
+                Ok(ConnectionMessage::Publish {
+                    packet,
+                    retained_for,
+                    ..
+                }) => publishes.push_back((packet, retained_for)),
+                Ok(cmd_message) => {
+                    next_message = Some(cmd_message);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+
+        for (packet, retained_for) in self.priority.drain(publishes, |(packet, _)| packet) {
+            self.forward_publish(packet, retained_for).await;
+        }
+
+        next_message
+    }
+
     async fn send_once(&mut self, qos: &QoS, qos_iter: &QoS, control_packet: &ControlPacket) {
         let mut packet_to_send = control_packet.clone();
         let mut qos_to_use = qos;
@@ -933,6 +1623,130 @@ impl Connection {
             qos_to_use = qos_iter;
         }
 
+        if self.hold_for_quiet_hours(&packet_to_send).await {
+            return;
+        }
+
+        if qos_to_use != &QoS::Zero && self.is_inflight_window_full() {
+            // sent once acknowledgements make room in the window
+            let pending = self
+                .state_store
+                .read()
+                .await
+                .pending_message(packet_to_send, PublishMetadata::new());
+            self.state.push_pending_message(pending);
+            return;
+        }
+
+        self.transmit(packet_to_send, qos_to_use).await;
+    }
+
+    /// Holds a message which is not high priority while a quiet hours window of the client is
+    /// open. Returns `false` if the message should be sent right away.
+    async fn hold_for_quiet_hours(&mut self, packet: &ControlPacket) -> bool {
+        let info = self.info.clone();
+        if !self.quiet_hours.applies(&info.client_id) || !self.quiet_hours.holds(packet) {
+            return false;
+        }
+        let now = self.state_store.read().await.clock().now();
+        let is_quiet = self.quiet_until.is_some_and(|until| until > Instant::now())
+            || self.update_quiet_window(&info.client_id, now);
+        if !is_quiet {
+            // the window has ended before its timer has fired
+            self.release_held_messages().await;
+            return false;
+        }
+
+        let held = self
+            .state_store
+            .read()
+            .await
+            .pending_message(packet.clone(), PublishMetadata::new());
+        self.held_messages.push_back(held);
+        if let Some(max_held_messages) = self.quiet_hours.max_held_messages() {
+            if self.held_messages.len() > max_held_messages {
+                self.held_messages.pop_front();
+                warn!(
+                    "[Connection Worker@{}]: max_queued_messages_per_client messages are held for quiet hours, the oldest one is dropped",
+                    self.info
+                );
+            }
+        }
+        true
+    }
+
+    /// Looks for an open quiet hours window of the client, returns `false` if there is none.
+    fn update_quiet_window(&mut self, client_id: &str, now: time::SystemTime) -> bool {
+        match self.quiet_hours.open_window(client_id, now) {
+            Some((tag, remaining)) => {
+                if self.quiet_until.is_none() {
+                    info!(
+                        "[Connection Worker@{}]: quiet hours {} have started, messages are held for {:?}",
+                        self.info, tag, remaining
+                    );
+                }
+                self.quiet_until = Some(Instant::now() + remaining);
+                true
+            }
+            None => {
+                self.quiet_until = None;
+                false
+            }
+        }
+    }
+
+    /// Sends held messages once the quiet hours window has ended, unless another window of
+    /// the client is open by then.
+    async fn end_quiet_hours(&mut self) {
+        let now = self.state_store.read().await.clock().now();
+        let info = self.info.clone();
+        if !self.update_quiet_window(&info.client_id, now) {
+            self.release_held_messages().await;
+        }
+    }
+
+    async fn release_held_messages(&mut self) {
+        if self.held_messages.is_empty() {
+            return;
+        }
+        let now = self.state_store.read().await.clock().now();
+        let mut held_messages = std::mem::take(&mut self.held_messages);
+        let before = held_messages.len();
+        held_messages.retain(|pending| !pending.is_expired(now));
+        self.on_messages_expired(before - held_messages.len());
+        info!(
+            "[Connection Worker@{}]: quiet hours have ended, sending {} held messages",
+            self.info,
+            held_messages.len()
+        );
+        for pending in self
+            .priority
+            .drain(held_messages, |pending| &pending.packet)
+        {
+            match get_qos_level(&pending.packet.fixed_header) {
+                Ok(qos) if qos != QoS::Zero && self.is_inflight_window_full() => {
+                    self.state.push_pending_message(pending);
+                }
+                Ok(qos) => self.transmit(pending.packet, &qos).await,
+                Err(err) => error!("Held message has a malformed QoS. {:?}", err),
+            }
+        }
+    }
+
+    /// Counts messages which have been queued for longer than `message_expiry_interval`.
+    fn on_messages_expired(&self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        info!(
+            "[Connection Worker@{}]: {} queued messages have expired and are dropped",
+            self.info, count
+        );
+        send_stats!(StatsMessage::MessagesExpired { count }, self);
+    }
+
+    /// Assigns a packet id to a QoS 1 or QoS 2 message, sends it and starts its transaction.
+    async fn transmit(&mut self, mut packet_to_send: ControlPacket, qos_to_use: &QoS) {
         let new_packet_id = if qos_to_use == &QoS::One || qos_to_use == &QoS::Two {
             match self
                 .state
@@ -961,6 +1775,49 @@ impl Connection {
         }
 
         send_or_disconnect!(&packet_to_send, self);
+        self.messages_sent += 1;
+    }
+
+    /// Messages wait in the pending queue while `max_inflight_messages` of them are not
+    /// acknowledged, as well as while earlier messages are waiting, so the order is kept.
+    fn is_inflight_window_full(&self) -> bool {
+        match self.max_inflight_messages {
+            Some(max_inflight_messages) => {
+                self.state.inflight_messages() >= max_inflight_messages
+                    || self.state.has_pending_messages()
+            }
+            None => false,
+        }
+    }
+
+    /// Sends messages which have been waiting for room in the in-flight window.
+    async fn send_pending_messages(&mut self) {
+        let max_inflight_messages = match self.max_inflight_messages {
+            Some(max_inflight_messages) => max_inflight_messages,
+            None => return,
+        };
+
+        let now = self.state_store.read().await.clock().now();
+        let mut expired = 0;
+        while self.state.inflight_messages() < max_inflight_messages {
+            let priority = &mut self.priority;
+            let pending = match self
+                .state
+                .pop_pending_message(|pending| priority.next(pending, |message| &message.packet))
+            {
+                Some(pending) => pending,
+                None => break,
+            };
+            if pending.is_expired(now) {
+                expired += 1;
+                continue;
+            }
+            match get_qos_level(&pending.packet.fixed_header) {
+                Ok(qos) => self.transmit(pending.packet, &qos).await,
+                Err(err) => error!("Pending message has a malformed QoS. {:?}", err),
+            }
+        }
+        self.on_messages_expired(expired);
     }
 
     async fn puback(&mut self, control_packet: &ControlPacket) {
@@ -980,6 +1837,7 @@ impl Connection {
         if let Err(err) = self.state.puback(&packet_id) {
             error!("Unable to puback packet {:?}. Error {:?}", packet_id, err);
         }
+        self.send_pending_messages().await;
     }
 
     async fn pubcomp(&mut self, control_packet: &ControlPacket) {
@@ -999,6 +1857,7 @@ impl Connection {
         if let Err(err) = self.state.pubcomp(&packet_id) {
             error!("Unable to pubcomp packet {:?}. Error {:?}", packet_id, err);
         }
+        self.send_pending_messages().await;
     }
 
     async fn pubrec(&mut self, control_packet: &ControlPacket) {
@@ -1053,72 +1912,117 @@ impl Connection {
         }
     }
 
+    /// A filter has to be allowed by ACL and not blacklisted. A filter the client is not
+    /// subscribed to yet, neither earlier in the same packet, takes a place of
+    /// `max_subs_per_client`, re-subscriptions only replace QoS.
     fn check_subscriptions(&self, subscriptions: &[Subscription]) -> Vec<bool> {
-        let subscriptions_number = self.state.get_subscriptions_number().unwrap_or(0);
-        let maybe_max_subs_per_client = self.max_subs_per_client;
-        match self.acl {
-            Some(ref client_rules) => {
-                let mut results: Vec<bool> = Vec::with_capacity(subscriptions.len());
-                for (i, sub) in subscriptions.iter().enumerate() {
-                    if let Some(max_subs_per_client) = maybe_max_subs_per_client {
-                        if subscriptions_number + i + 1 > max_subs_per_client {
-                            results.push(false);
-                            continue;
-                        }
-                    }
-                    match client_rules.topics_acl.as_ref().map(|topics| {
-                        topics
-                            .iter()
-                            .find(|r| topics_match(&sub.path, &r.topic.path))
-                    }) {
-                        Some(Some(topic_rule)) => match topic_rule.access {
-                            TopicAccess::ReadWrite | TopicAccess::Read => {
-                                results.push(true);
-                            }
-                            TopicAccess::Deny | TopicAccess::Write => {
-                                results.push(false);
-                            }
-                        },
-                        Some(None) => {
-                            results.push(false);
-                        }
-                        None => {
-                            results.push(true);
-                        }
-                    }
+        let mut subscriptions_number = self.state.get_subscriptions_number().unwrap_or(0);
+        let mut added: Vec<&str> = Vec::new();
+        let mut checks = Vec::with_capacity(subscriptions.len());
+
+        for sub in subscriptions {
+            let allowed = subscribe_allowed(self.topics_acl(), sub);
+            if let Some(ref shadow_acl) = self.shadow_acl {
+                if subscribe_allowed(Some(shadow_acl), sub) != allowed {
+                    self.on_acl_shadow_divergence("subscribe", &sub.original, allowed);
                 }
-                return results;
             }
-            None => {
-                return subscriptions
-                    .iter()
-                    .enumerate()
-                    .map(|(i, _)| {
-                        if let Some(max_subs_per_client) = maybe_max_subs_per_client {
-                            return subscriptions_number + i + 1 <= max_subs_per_client;
-                        }
-                        return true;
-                    })
-                    .collect();
+            if !allowed {
+                info!(
+                    target: TARGET_AUTH,
+                    event = "subscription_rejected";
+                    "[Connection Worker@{}]: Subscription to {:?} is not allowed",
+                    self.info, sub.original
+                );
+                checks.push(false);
+                continue;
+            }
+
+            if self.subscription_limits.is_blacklisted(sub) {
+                info!(
+                    event = "subscription_rejected";
+                    "[Connection Worker@{}]: Subscription to {:?} is blacklisted",
+                    self.info, sub.original
+                );
+                checks.push(false);
+                continue;
             }
+
+            let is_new = !self.state.is_subscribed(sub) && !added.contains(&sub.original.as_str());
+            if is_new {
+                let limit_reached = self
+                    .subscription_limits
+                    .max_subs_per_client
+                    .is_some_and(|max_subs_per_client| subscriptions_number >= max_subs_per_client);
+                if limit_reached {
+                    info!(
+                        event = "subscription_rejected";
+                        "[Connection Worker@{}]: Subscription to {:?} exceeds max_subs_per_client",
+                        self.info, sub.original
+                    );
+                    checks.push(false);
+                    continue;
+                }
+                subscriptions_number += 1;
+                added.push(&sub.original);
+            }
+            checks.push(true);
         }
+
+        checks
     }
 
     fn check_publish(&self, topic: &Topic) -> bool {
-        match self.acl {
-            Some(ref client_rules) => match client_rules.topics_acl.as_ref().map(|topics| {
-                topics
-                    .iter()
-                    .find(|r| topics_match(&topic.path, &r.topic.path))
-            }) {
-                Some(Some(topic_rule)) => match topic_rule.access {
-                    TopicAccess::ReadWrite | TopicAccess::Write => true,
-                    TopicAccess::Deny | TopicAccess::Read => false,
-                },
-                Some(None) => false,
-                None => true,
-            },
-            None => true,
+        let allowed = publish_allowed(self.topics_acl(), topic);
+        if let Some(ref shadow_acl) = self.shadow_acl {
+            if publish_allowed(Some(shadow_acl), topic) != allowed {
+                self.on_acl_shadow_divergence("publish", &topic.original, allowed);
+            }
         }
+        allowed
+    }
+
+    /// `None` if the client is not restricted by an ACL.
+    fn topics_acl(&self) -> Option<&[TopicACL]> {
+        self.acl
+            .as_ref()
+            .and_then(|response| response.topics_acl.as_deref())
+    }
+
+    fn on_acl_shadow_divergence(&self, action: &str, topic: &str, allowed: bool) {
+        let decision = |allowed| if allowed { "allow" } else { "deny" };
+        warn!(
+            target: TARGET_AUTH,
+            "[Connection Worker@{}]: event=acl_shadow_divergence action={} topic={:?} active={} shadow={}",
+            self.info,
+            action,
+            topic,
+            decision(allowed),
+            decision(!allowed)
+        );
+        send_stats!(StatsMessage::AclShadowDivergence, self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_alive_timeout_is_one_and_a_half_of_client_keep_alive() {
+        let server_keep_alive = time::Duration::from_secs(120);
+
+        assert_eq!(
+            keep_alive_timeout(time::Duration::from_secs(60), server_keep_alive),
+            time::Duration::from_secs(90)
+        );
+        assert_eq!(
+            keep_alive_timeout(time::Duration::from_secs(1), server_keep_alive),
+            time::Duration::from_millis(1500)
+        );
+        assert_eq!(
+            keep_alive_timeout(time::Duration::ZERO, server_keep_alive),
+            server_keep_alive
+        );
     }
 }
@@ -0,0 +1,210 @@
+//! Channels of messages from Control Worker to connections. A channel holds at most
+//! `connection_channel_capacity` messages, so a slow client subscribed to a busy topic doesn't
+//! make the broker buffer messages for it without a limit. Publishes which don't fit are
+//! dropped and counted, and the client is disconnected if `connection_channel_full_policy` is
+//! `disconnect`. Other messages are never dropped, they are delivered once there is room.
+use std::sync::Arc;
+
+use log::debug;
+use serde::Deserialize;
+use tokio::{
+    spawn,
+    sync::{
+        mpsc::{
+            channel,
+            error::{SendError, TrySendError},
+            Receiver, Sender,
+        },
+        Notify,
+    },
+};
+
+use crate::{
+    config::TeleMQServerConfig,
+    connection::ConnectionMessage,
+    stats::{StatsMessage, StatsSender},
+};
+
+/// What happens when a publish is sent to a connection which channel is full.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChannelFullPolicy {
+    /// the publish is dropped
+    Drop,
+    /// the publish is dropped and the client is disconnected
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelLimit {
+    pub capacity: usize,
+    pub full_policy: ChannelFullPolicy,
+}
+
+impl ChannelLimit {
+    pub fn new(config: &TeleMQServerConfig) -> Self {
+        ChannelLimit {
+            capacity: config.connection_channel_capacity,
+            full_policy: config.connection_channel_full_policy,
+        }
+    }
+}
+
+impl Default for ChannelLimit {
+    fn default() -> Self {
+        ChannelLimit {
+            capacity: TeleMQServerConfig::DEFAULT_CONNECTION_CHANNEL_CAPACITY,
+            full_policy: TeleMQServerConfig::DEFAULT_CONNECTION_CHANNEL_FULL_POLICY,
+        }
+    }
+}
+
+pub type ConnectionReceiver = Receiver<ConnectionMessage>;
+
+#[derive(Debug, Clone)]
+pub struct ConnectionSender {
+    sender: Sender<ConnectionMessage>,
+    full_policy: ChannelFullPolicy,
+    /// Notified once a publish has been dropped under the `disconnect` policy.
+    overflowed: Arc<Notify>,
+    /// Dropped publishes are reported to Stats Worker, `None` for in-process clients.
+    stats_sender: Option<StatsSender>,
+}
+
+/// A channel of a connection. `stats_sender` is `None` for in-process clients.
+pub fn connection_channel(
+    limit: ChannelLimit,
+    stats_sender: Option<StatsSender>,
+) -> (ConnectionSender, ConnectionReceiver) {
+    let (sender, receiver) = channel(limit.capacity);
+    let sender = ConnectionSender {
+        sender,
+        full_policy: limit.full_policy,
+        overflowed: Arc::new(Notify::new()),
+        stats_sender,
+    };
+
+    (sender, receiver)
+}
+
+impl ConnectionSender {
+    /// Sends a message without waiting for room in the channel. Fails only if the connection
+    /// is gone, the message is handed back then.
+    #[allow(clippy::result_large_err)]
+    pub fn send(&self, message: ConnectionMessage) -> Result<(), SendError<ConnectionMessage>> {
+        match self.sender.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Closed(message)) => Err(SendError(message)),
+            Err(TrySendError::Full(ConnectionMessage::Publish { .. })) => {
+                self.on_publish_dropped();
+                Ok(())
+            }
+            Err(TrySendError::Full(message)) => {
+                let sender = self.sender.clone();
+                spawn(async move {
+                    // the connection may be gone by then
+                    let _ = sender.send(message).await;
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Notified once the connection should be closed as it doesn't keep up with messages.
+    pub fn overflowed(&self) -> Arc<Notify> {
+        self.overflowed.clone()
+    }
+
+    fn on_publish_dropped(&self) {
+        debug!("[Connection Channel]: Channel is full, a publish is dropped");
+        if let Some(ref stats_sender) = self.stats_sender {
+            // Stats Worker is gone only during a shut down
+            let _ = stats_sender.send(StatsMessage::ConnectionChannelFull);
+        }
+        if self.full_policy == ChannelFullPolicy::Disconnect {
+            self.overflowed.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::publish_metadata::PublishMetadata;
+    use mqtt_packets::v_3_1_1::builders::PublishPacketBuilder;
+
+    fn publish() -> ConnectionMessage {
+        ConnectionMessage::Publish {
+            packet: PublishPacketBuilder::new().build(),
+            metadata: PublishMetadata::new(),
+            retained_for: None,
+        }
+    }
+
+    fn limit(full_policy: ChannelFullPolicy) -> ChannelLimit {
+        ChannelLimit {
+            capacity: 1,
+            full_policy,
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_are_dropped_once_channel_is_full() {
+        let (sender, mut receiver) = connection_channel(limit(ChannelFullPolicy::Drop), None);
+
+        assert!(sender.send(publish()).is_ok());
+        assert!(sender.send(publish()).is_ok());
+
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(ConnectionMessage::Publish { .. })
+        ));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn other_messages_wait_for_room() {
+        let (sender, mut receiver) = connection_channel(limit(ChannelFullPolicy::Drop), None);
+
+        assert!(sender.send(publish()).is_ok());
+        assert!(sender.send(ConnectionMessage::ShutDown).is_ok());
+
+        assert!(matches!(
+            receiver.recv().await,
+            Some(ConnectionMessage::Publish { .. })
+        ));
+        assert!(matches!(
+            receiver.recv().await,
+            Some(ConnectionMessage::ShutDown)
+        ));
+    }
+
+    #[tokio::test]
+    async fn slow_connection_is_notified_under_disconnect_policy() {
+        let (sender, _receiver) = connection_channel(limit(ChannelFullPolicy::Disconnect), None);
+        let overflowed = sender.overflowed();
+
+        assert!(sender.send(publish()).is_ok());
+        assert!(sender.send(publish()).is_ok());
+
+        // a permit is stored, so the notification isn't lost before it's awaited
+        overflowed.notified().await;
+    }
+
+    #[tokio::test]
+    async fn publishes_to_closed_connections_are_handed_back() {
+        let (sender, mut receiver) = connection_channel(limit(ChannelFullPolicy::Drop), None);
+        assert!(sender.send(publish()).is_ok());
+        receiver.close();
+
+        assert!(matches!(
+            sender.send(publish()),
+            Err(SendError(ConnectionMessage::Publish { .. }))
+        ));
+        // sent before the connection has closed
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(ConnectionMessage::Publish { .. })
+        ));
+    }
+}
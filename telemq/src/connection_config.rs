@@ -0,0 +1,63 @@
+//! Settings and shared handles of client connections.
+//!
+//! `Server` builds a config once and clones it for every accepted connection. Listeners differ
+//! only by a bandwidth limit and a topic prefix, so each of them gets its own copy of the config.
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::RwLock;
+
+use crate::{
+    authenticator::Authenticator, bandwidth_limiter::BandwidthLimiter,
+    client_id_generator::SharedClientIdGenerator, connection_channel::ChannelLimit,
+    control::ControlSender, load_shedding::Overload, payload_format::PayloadFormats,
+    priority::PriorityTopics, queue_depth::QueueDepth, quiet_hours::QuietHours,
+    session_persistence::PersistenceSender, session_state_store::SessionStateStore,
+    stats::StatsSender, subscription_limits::SubscriptionLimits,
+    subscription_tree::SharedSubscriptionTree, topic_normalization::TopicNormalization,
+    topic_prefix::TopicPrefix, transaction::RetryPolicy,
+};
+
+#[derive(Clone)]
+pub struct ConnectionConfig {
+    pub control_sender: ControlSender,
+    pub stats_sender: StatsSender,
+    pub persistence_sender: PersistenceSender,
+    pub authenticator: Arc<RwLock<Authenticator>>,
+    /// `keep_alive`, applied to clients which connect with Keep Alive 0.
+    pub inactivity_interval: Duration,
+    pub state_store: Arc<RwLock<SessionStateStore>>,
+    pub subscription_limits: SubscriptionLimits,
+    pub priority_topics: PriorityTopics,
+    pub reject_on_session_recovery_failure: bool,
+    pub publish_disconnect_reason: bool,
+    // if None => unacknowledged packets are re-sent only when a persistent session is resumed
+    pub retry_policy: Option<RetryPolicy>,
+    pub max_inflight_messages: Option<usize>,
+    pub channel_limit: ChannelLimit,
+    /// Shared by all connections of a listener, `None` if its bandwidth is not limited.
+    pub bandwidth_limiter: Option<BandwidthLimiter>,
+    pub topic_prefix: TopicPrefix,
+    pub topic_normalization: TopicNormalization,
+    pub overload: Overload,
+    pub subscription_tree: SharedSubscriptionTree,
+    pub client_id_generator: SharedClientIdGenerator,
+    pub queue_depth: Option<QueueDepth>,
+    pub quiet_hours: QuietHours,
+    pub payload_formats: PayloadFormats,
+}
+
+impl ConnectionConfig {
+    /// The config of connections of a listener with `<listener>_bandwidth_limit` and
+    /// `<listener>_topic_prefix`.
+    pub fn for_listener(
+        &self,
+        bandwidth_limit: Option<usize>,
+        topic_prefix: &Option<String>,
+    ) -> Self {
+        ConnectionConfig {
+            bandwidth_limiter: bandwidth_limit.map(BandwidthLimiter::new),
+            topic_prefix: TopicPrefix::new(topic_prefix),
+            ..self.clone()
+        }
+    }
+}
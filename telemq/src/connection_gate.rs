@@ -0,0 +1,152 @@
+use std::net::SocketAddr;
+
+use log::info;
+use tokio::net::TcpStream;
+use tokio_rustls::{rustls::ServerConnection, server::TlsStream};
+
+use crate::{tls_fingerprint::Ja3Fingerprint, transport_bytes::CountingStream};
+
+/// Transport a connection has been accepted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionTransport {
+    Tcp,
+    Tls,
+    Ws,
+    Wss,
+    /// A client of the MQTT-SN gateway, it sends datagrams over UDP.
+    MqttSn,
+    /// A client registered via `BrokerHandle`, it has no network connection.
+    InProcess,
+}
+
+/// TLS session details negotiated with a client.
+#[derive(Debug, Clone, Default)]
+pub struct TlsMetadata {
+    /// Server name a client has requested via SNI.
+    pub server_name: Option<String>,
+    /// Negotiated ALPN protocol.
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// Negotiated protocol version, e.g. `TLSv1_3`.
+    pub protocol_version: Option<String>,
+    /// DER encoded client certificates chain (if a client has provided one).
+    pub peer_certificates: Vec<Vec<u8>>,
+    /// JA3 fingerprint of ClientHello, if `tls_fingerprint` is enabled.
+    pub ja3: Option<Ja3Fingerprint>,
+}
+
+/// Information about a newly accepted connection, which is available before
+/// any MQTT packet is read from it.
+#[derive(Debug, Clone)]
+pub struct ConnectionMetadata {
+    pub peer_addr: SocketAddr,
+    pub local_addr: Option<SocketAddr>,
+    pub transport: ConnectionTransport,
+    pub tls: Option<TlsMetadata>,
+}
+
+impl ConnectionMetadata {
+    pub(crate) fn from_tcp(stream: &TcpStream, peer_addr: SocketAddr) -> Self {
+        ConnectionMetadata {
+            peer_addr,
+            local_addr: stream.local_addr().ok(),
+            transport: ConnectionTransport::Tcp,
+            tls: None,
+        }
+    }
+
+    pub(crate) fn from_tls(
+        stream: &TlsStream<CountingStream<TcpStream>>,
+        peer_addr: SocketAddr,
+        ja3: Option<Ja3Fingerprint>,
+    ) -> Self {
+        let (tcp_stream, session) = stream.get_ref();
+
+        ConnectionMetadata {
+            peer_addr,
+            local_addr: tcp_stream.get_ref().local_addr().ok(),
+            transport: ConnectionTransport::Tls,
+            tls: Some(TlsMetadata::new(session, ja3)),
+        }
+    }
+
+    /// A connection of a websocket listener, before the upgrade.
+    pub(crate) fn from_ws(stream: &TcpStream, peer_addr: SocketAddr) -> Self {
+        ConnectionMetadata {
+            transport: ConnectionTransport::Ws,
+            ..Self::from_tcp(stream, peer_addr)
+        }
+    }
+
+    /// A connection of a secure websocket listener, after the TLS handshake and before
+    /// the upgrade.
+    pub(crate) fn from_wss(stream: &TlsStream<TcpStream>, peer_addr: SocketAddr) -> Self {
+        let (tcp_stream, session) = stream.get_ref();
+
+        ConnectionMetadata {
+            peer_addr,
+            local_addr: tcp_stream.local_addr().ok(),
+            transport: ConnectionTransport::Wss,
+            tls: Some(TlsMetadata::new(session, None)),
+        }
+    }
+}
+
+impl TlsMetadata {
+    fn new(session: &ServerConnection, ja3: Option<Ja3Fingerprint>) -> Self {
+        TlsMetadata {
+            server_name: session.server_name().map(String::from),
+            alpn_protocol: session.alpn_protocol().map(Vec::from),
+            protocol_version: session.protocol_version().map(|v| format!("{:?}", v)),
+            peer_certificates: session
+                .peer_certificates()
+                .map(|certs| certs.iter().map(|cert| cert.0.clone()).collect())
+                .unwrap_or_default(),
+            ja3,
+        }
+    }
+}
+
+/// Decision made by a `ConnectionGate` for a newly accepted connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GateDecision {
+    Accept,
+    /// Connection is closed straight away, a reason is logged.
+    Reject(String),
+}
+
+/// Custom connection policy (geo-IP, per-device quotas, etc.) which can be
+/// provided by an application embedding TeleMQ via `ServerBuilder::with_connection_gate`.
+///
+/// A gate is invoked for every accepted TCP (after the IP whitelist check), TLS, WS and WSS
+/// connection, before `max_connections` is checked and a `Connection` is spawned. Websocket
+/// connections are checked before any HTTP request is read from them.
+/// It's called from the accept loop and should not block.
+pub trait ConnectionGate: Send + Sync {
+    fn check(&self, metadata: &ConnectionMetadata) -> GateDecision;
+}
+
+impl<F> ConnectionGate for F
+where
+    F: Fn(&ConnectionMetadata) -> GateDecision + Send + Sync,
+{
+    fn check(&self, metadata: &ConnectionMetadata) -> GateDecision {
+        self(metadata)
+    }
+}
+
+/// Checks a connection with `gate`, all connections are allowed if there is none.
+pub(crate) fn is_allowed_by_gate(
+    gate: Option<&dyn ConnectionGate>,
+    metadata: &ConnectionMetadata,
+) -> bool {
+    match gate.map(|gate| gate.check(metadata)) {
+        Some(GateDecision::Reject(reason)) => {
+            info!(
+                "[Connection Gate]: connection from {:?} rejected. {}",
+                metadata.peer_addr, reason
+            );
+            false
+        }
+        Some(GateDecision::Accept) | None => true,
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::SocketAddr,
+    time::SystemTime,
+};
+
+use crate::{
+    connection_gate::{ConnectionTransport, TlsMetadata},
+    mqtt_codec::ProtocolVersion,
+};
+
+/// Identity of a client connection. It's shared (behind an `Arc`) by a `Connection`,
+/// messages it sends to Control and Stats, the Admin API and logs, so all of them
+/// describe a client the same way.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Empty until a client is connected.
+    pub client_id: String,
+    pub addr: SocketAddr,
+    /// Listener a connection has been accepted by.
+    pub transport: ConnectionTransport,
+    /// `None` until a client is connected and for in-process clients.
+    pub protocol: Option<ProtocolVersion>,
+    /// Time a network connection has been accepted at, replaced by a time of CONNECT
+    /// once a client is connected.
+    pub connected_at: SystemTime,
+    pub tls: Option<TlsMetadata>,
+}
+
+impl ConnectionInfo {
+    /// Information about a connection which has not sent CONNECT yet.
+    pub fn accepted(
+        addr: SocketAddr,
+        transport: ConnectionTransport,
+        tls: Option<TlsMetadata>,
+        accepted_at: SystemTime,
+    ) -> Self {
+        ConnectionInfo {
+            client_id: String::new(),
+            addr,
+            transport,
+            protocol: None,
+            connected_at: accepted_at,
+            tls,
+        }
+    }
+
+    /// JA3 hash of ClientHello of a TLS connection, if `tls_fingerprint` is enabled.
+    pub fn tls_fingerprint(&self) -> Option<&str> {
+        self.tls
+            .as_ref()
+            .and_then(|tls| tls.ja3.as_ref())
+            .map(|ja3| ja3.hash.as_str())
+    }
+
+    /// Information about the same connection once a client is connected.
+    pub fn connected(
+        &self,
+        client_id: String,
+        protocol: Option<ProtocolVersion>,
+        connected_at: SystemTime,
+    ) -> Self {
+        ConnectionInfo {
+            client_id,
+            protocol,
+            connected_at,
+            ..self.clone()
+        }
+    }
+}
+
+/// `client_id@addr`, or just `addr` if a client is not connected yet.
+impl Display for ConnectionInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        if self.client_id.is_empty() {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}@{}", self.client_id, self.addr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn displayed_with_client_id_once_connected() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let accepted =
+            ConnectionInfo::accepted(addr, ConnectionTransport::Tcp, None, SystemTime::now());
+        assert_eq!(accepted.to_string(), "127.0.0.1:5000");
+
+        let connected = accepted.connected(
+            "device-1".into(),
+            Some(ProtocolVersion::V5_0),
+            SystemTime::now(),
+        );
+        assert_eq!(connected.to_string(), "device-1@127.0.0.1:5000");
+        assert_eq!(connected.transport, ConnectionTransport::Tcp);
+        assert_eq!(connected.protocol, Some(ProtocolVersion::V5_0));
+    }
+}
@@ -0,0 +1,138 @@
+//! Limit of open network connections shared by all listeners.
+//!
+//! `max_connections` can be changed while the broker is running (via Admin API or SIGHUP).
+//! A lowered limit applies to new connections immediately. Connections above the limit are
+//! kept open unless a drain rate is given, then they are disconnected gradually, so their
+//! clients don't reconnect to other brokers all at once.
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{error, info};
+use tokio::time::interval;
+
+use crate::control::{ControlMessage, ControlSender};
+
+const DRAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    current: AtomicUsize,
+    max: AtomicUsize,
+    /// Incremented by every change of `max`, so a drain of a previous change stops.
+    generation: AtomicUsize,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        ConnectionLimit {
+            current: AtomicUsize::new(0),
+            max: AtomicUsize::new(max),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts a new connection in, returns `false` if the limit has been reached.
+    pub fn try_acquire(&self) -> bool {
+        let max = self.max();
+        self.current
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                if current >= max {
+                    None
+                } else {
+                    Some(current + 1)
+                }
+            })
+            .is_ok()
+    }
+
+    /// Counts a closed connection out.
+    pub fn release(&self) {
+        self.current.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    pub fn max(&self) -> usize {
+        self.max.load(Ordering::SeqCst)
+    }
+
+    /// Number of open connections above the limit.
+    pub fn excess(&self) -> usize {
+        self.current().saturating_sub(self.max())
+    }
+
+    /// Changes the limit. If `drain_rate` (connections per second) is provided, connections
+    /// above the new limit are disconnected gradually by Control.
+    pub fn set_max(
+        self: &Arc<Self>,
+        max: usize,
+        drain_rate: Option<usize>,
+        control_sender: &ControlSender,
+    ) {
+        self.max.store(max, Ordering::SeqCst);
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        info!(
+            "[Server Worker]: max_connections is {}, {} connections are open",
+            max,
+            self.current()
+        );
+
+        if let (Some(rate), true) = (drain_rate, self.excess() > 0) {
+            tokio::spawn(self.clone().drain(generation, rate, control_sender.clone()));
+        }
+    }
+
+    async fn drain(self: Arc<Self>, generation: usize, rate: usize, control_sender: ControlSender) {
+        let mut ticks = interval(DRAIN_INTERVAL);
+        loop {
+            ticks.tick().await;
+            if self.generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+            let excess = self.excess();
+            if excess == 0 {
+                info!("[Server Worker]: connections above max_connections are drained");
+                return;
+            }
+
+            let message = ControlMessage::DropConnections {
+                count: excess.min(rate),
+            };
+            if let Err(err) = control_sender.send(message) {
+                error!(
+                    "[Server Worker]: unable to send ControlMessage::DropConnections. {:?}",
+                    err
+                );
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowered_limit_rejects_new_connections() {
+        let limit = ConnectionLimit::new(3);
+        assert!((0..3).all(|_| limit.try_acquire()));
+        assert!(!limit.try_acquire());
+
+        limit.max.store(1, Ordering::SeqCst);
+        assert_eq!(limit.excess(), 2);
+        limit.release();
+        assert!(!limit.try_acquire());
+        limit.release();
+        limit.release();
+        assert!(limit.try_acquire());
+        assert_eq!(limit.current(), 1);
+    }
+}
@@ -0,0 +1,126 @@
+//! Cleanup after connection tasks which terminate abnormally.
+//!
+//! A connection informs Control and Stats when it's closed. If a connection task panics, it
+//! never does, so Control would keep a sender of a dead connection and the will of the client
+//! would never be published. A watchdog awaits a connection task and reports its panic instead.
+use std::net::SocketAddr;
+
+use log::error;
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::{
+    control::{ControlMessage, ControlSender},
+    disconnect_reason::DisconnectReason,
+    stats::{StatsMessage, StatsSender},
+};
+
+pub struct ConnectionWatchdog {
+    addr: SocketAddr,
+    control_sender: ControlSender,
+    stats_sender: StatsSender,
+}
+
+impl ConnectionWatchdog {
+    pub fn new(
+        addr: SocketAddr,
+        control_sender: &ControlSender,
+        stats_sender: &StatsSender,
+    ) -> Self {
+        ConnectionWatchdog {
+            addr,
+            control_sender: control_sender.clone(),
+            stats_sender: stats_sender.clone(),
+        }
+    }
+
+    /// Waits until a connection task finishes. If the task has panicked, Control publishes
+    /// the will of the client and forgets the connection.
+    pub async fn watch(self, connection_task: JoinHandle<()>) {
+        let err = match connection_task.await {
+            Ok(()) => return,
+            Err(err) if err.is_panic() => err,
+            // cancelled during a runtime shut down
+            Err(_) => return,
+        };
+        error!(
+            "[Connection Watchdog]: connection task of {} has panicked. {:?}",
+            self.addr, err
+        );
+
+        let (reply, response) = oneshot::channel();
+        let message = ControlMessage::ConnectionAborted {
+            addr: self.addr,
+            reply,
+        };
+        if let Err(err) = self.control_sender.send(message) {
+            error!(
+                "[Connection Watchdog]: unable to send ControlMessage::ConnectionAborted. {:?}",
+                err
+            );
+            return;
+        }
+
+        if let Ok(Some(client_id)) = response.await {
+            if let Err(err) = self.stats_sender.send(StatsMessage::ClientDisconnected {
+                client_id,
+                // the connection task has gone without closing the connection
+                reason: DisconnectReason::ConnectionLost,
+            }) {
+                error!(
+                    "[Connection Watchdog]: unable to send StatsMessage::ClientDisconnected. {:?}",
+                    err
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{spawn, sync::mpsc::unbounded_channel};
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:40000".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn panicked_connection_is_reported() {
+        let (control_sender, mut control_receiver) = unbounded_channel();
+        let (stats_sender, mut stats_receiver) = unbounded_channel();
+        let watchdog = ConnectionWatchdog::new(addr(), &control_sender, &stats_sender);
+
+        let watching = spawn(watchdog.watch(spawn(async { panic!("connection failure") })));
+        match control_receiver.recv().await {
+            Some(ControlMessage::ConnectionAborted {
+                addr: aborted,
+                reply,
+            }) => {
+                assert_eq!(aborted, addr());
+                reply.send(Some("client".into())).unwrap();
+            }
+            other => panic!("unexpected control message {:?}", other),
+        }
+        watching.await.unwrap();
+
+        match stats_receiver.try_recv() {
+            Ok(StatsMessage::ClientDisconnected { client_id, reason }) => {
+                assert_eq!(client_id, "client");
+                assert_eq!(reason, DisconnectReason::ConnectionLost);
+            }
+            other => panic!("unexpected stats message {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn finished_connection_is_not_reported() {
+        let (control_sender, mut control_receiver) = unbounded_channel();
+        let (stats_sender, mut stats_receiver) = unbounded_channel();
+        let watchdog = ConnectionWatchdog::new(addr(), &control_sender, &stats_sender);
+
+        watchdog.watch(spawn(async {})).await;
+
+        assert!(control_receiver.try_recv().is_err());
+        assert!(stats_receiver.try_recv().is_err());
+    }
+}
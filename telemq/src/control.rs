@@ -1,56 +1,143 @@
 use crate::{
+    broker_state::{state_packet, BrokerState},
+    clock::Clock,
     config::TeleMQServerConfig,
-    connection::{ConnectionMessage, ConnectionSender},
+    connection::ConnectionMessage,
+    connection_channel::ConnectionSender,
+    connection_gate::ConnectionTransport,
+    connection_info::ConnectionInfo,
+    disconnect_reason::DisconnectReason,
+    internal_subscriptions::InternalSubscriptions,
+    load_shedding::overload_packet,
+    priority::{PriorityScheduler, PriorityTopics, PRIORITY_BATCH_SIZE},
+    publish_metadata::PublishMetadata,
+    publish_ordering::{PublishOrdering, PublishSequence},
+    reserved_topics::{find_collisions, warn_about_collisions},
+    retained_bypass::RetainedBypass,
+    retained_store::RetainedStore,
+    session_state::{QueueLimit, QueueOutcome},
     session_state_store::SessionStateStore,
-    subscription_tree::SubscriptionTree,
+    subscription_tree::{ResolvedSubscribers, SharedSubscriptionTree, SubscriptionTree, TreeUsage},
+    time_sync::TimeSync,
+    will_delay::DelayedWills,
 };
 use futures::future::join_all;
-use log::{error, info};
+use log::{error, info, log_enabled, trace, warn, Level};
 use mqtt_packets::v_3_1_1::{
-    publish::fixed_header::is_retained,
-    topic::{Subscription, Topic},
+    publish::fixed_header::{get_qos_level, is_retained},
+    topic::Subscription,
     variable::Variable,
-    ControlPacket,
+    ControlPacket, QoS,
+};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
 };
-use std::{collections::HashMap, io, net::SocketAddr, sync::Arc};
 use tokio::{
     select,
     sync::{
-        mpsc::{unbounded_channel, Sender, UnboundedReceiver, UnboundedSender},
-        RwLock,
+        mpsc::{error::SendError, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender},
+        oneshot, Notify, RwLock,
     },
+    time::{interval, sleep_until, Instant},
 };
 
 #[derive(Debug)]
 pub enum ControlMessage {
     ClientConnected {
-        addr: SocketAddr,
-        client_id: String,
+        connection: Arc<ConnectionInfo>,
         clean_session: bool,
         sender: ConnectionSender,
+        /// Published if the connection terminates abnormally.
+        will_packet: Option<ControlPacket>,
+        /// Notified once a new connection takes the session over, `None` for in-process
+        /// subscribers and MQTT-SN clients.
+        taken_over: Option<Arc<Notify>>,
     },
     ClientDisconnected {
-        addr: SocketAddr,
-        client_id: String,
+        connection: Arc<ConnectionInfo>,
         clean_session: bool,
         will_packet: Option<ControlPacket>,
+        /// Publishes sent to the connection which it hasn't forwarded, queued for a persistent
+        /// session.
+        undelivered: Vec<(ControlPacket, PublishMetadata)>,
     },
     AddSubscriptions {
-        addr: SocketAddr,
-        client_id: String,
+        connection: Arc<ConnectionInfo>,
         subscriptions: Vec<Subscription>,
     },
     RemoveSubscriptions {
-        addr: SocketAddr,
-        client_id: String,
+        connection: Arc<ConnectionInfo>,
         subscriptions: Vec<Subscription>,
     },
     Publish {
-        addr: Option<SocketAddr>,
-        client_id: Option<String>,
+        /// `None` for messages produced by the broker itself.
+        publisher: Option<Arc<ConnectionInfo>>,
         packet: ControlPacket,
+        metadata: PublishMetadata,
+        /// Sequence number assigned by a publisher connection, `None` for messages
+        /// produced by the broker itself.
+        sequence: Option<PublishSequence>,
+        /// Subscribers looked up by a publisher connection, `None` if Control Worker looks
+        /// them up.
+        subscribers: Option<ResolvedSubscribers>,
+    },
+    /// Counts subscribers which would receive messages published to a topic
+    /// or to topics matching a topic filter.
+    CountSubscribers {
+        filter: Subscription,
+        reply: oneshot::Sender<usize>,
+    },
+    /// Removes retained messages which topics match a topic filter.
+    PurgeRetained {
+        filter: Subscription,
+        reply: oneshot::Sender<usize>,
+    },
+    /// Asks a connection which owns a session of a client id to save the session and to close,
+    /// so another connection with the same client id can take the session over.
+    TakeOverSession {
+        client_id: String,
+        /// Address of the new connection.
+        addr: SocketAddr,
+    },
+    /// Lists topic filters of the subscription tree along with their subscribers.
+    DumpSubscriptions {
+        reply: oneshot::Sender<Vec<(String, Vec<String>)>>,
+    },
+    /// Reports size of the subscription tree along with whether it's over
+    /// `subscription_tree_warning_nodes`.
+    SubscriptionTreeUsage {
+        reply: oneshot::Sender<(TreeUsage, bool)>,
+    },
+    /// Lists connected clients.
+    ListConnections {
+        reply: oneshot::Sender<Vec<Arc<ConnectionInfo>>>,
+    },
+    /// A connection task has terminated abnormally (panicked), so it hasn't sent
+    /// `ClientDisconnected`. Replies with a client id of the connection if it has been connected.
+    ConnectionAborted {
+        addr: SocketAddr,
+        reply: oneshot::Sender<Option<String>>,
+    },
+    /// Disconnects up to `count` clients, the most recently connected first, when
+    /// `max_connections` has been lowered below a number of open connections.
+    DropConnections {
+        count: usize,
+    },
+    /// Rebuilds the subscription tree from the Session State Store.
+    ReloadSubscriptions,
+    /// The broker has become overloaded or has recovered, see `load_shedding`.
+    Overload {
+        overloaded: bool,
     },
     ShutDown,
+    /// Same as `ShutDown`, but the broker isn't announced offline, since a new broker process
+    /// keeps serving its clients.
+    HandOver,
 }
 
 impl ControlMessage {
@@ -63,7 +150,20 @@ impl ControlMessage {
             ControlMessage::AddSubscriptions { .. } => "ControlMessage::AddSubscriptions".into(),
             ControlMessage::RemoveSubscriptions { .. } => "ControlMessage::AddSubscriptions".into(),
             ControlMessage::Publish { .. } => "ControlMessage::Publish".into(),
+            ControlMessage::CountSubscribers { .. } => "ControlMessage::CountSubscribers".into(),
+            ControlMessage::PurgeRetained { .. } => "ControlMessage::PurgeRetained".into(),
+            ControlMessage::TakeOverSession { .. } => "ControlMessage::TakeOverSession".into(),
+            ControlMessage::DumpSubscriptions { .. } => "ControlMessage::DumpSubscriptions".into(),
+            ControlMessage::SubscriptionTreeUsage { .. } => {
+                "ControlMessage::SubscriptionTreeUsage".into()
+            }
+            ControlMessage::ListConnections { .. } => "ControlMessage::ListConnections".into(),
+            ControlMessage::ConnectionAborted { .. } => "ControlMessage::ConnectionAborted".into(),
+            ControlMessage::DropConnections { .. } => "ControlMessage::DropConnections".into(),
+            ControlMessage::ReloadSubscriptions => "ControlMessage::ReloadSubscriptions".into(),
+            ControlMessage::Overload { .. } => "ControlMessage::Overload".into(),
             ControlMessage::ShutDown => "ControlMessage::ShutDown".into(),
+            ControlMessage::HandOver => "ControlMessage::HandOver".into(),
         }
     }
 }
@@ -72,95 +172,365 @@ pub type ControlSender = UnboundedSender<ControlMessage>;
 pub type ControlReceiver = UnboundedReceiver<ControlMessage>;
 
 type ClientId = String;
+type QueuedPublish = (ControlPacket, PublishMetadata, Option<ResolvedSubscribers>);
+
+/// An outcome of handing a message over to a client.
+enum Delivery {
+    Done,
+    /// A queue of an offline client has overflowed, its session should be discarded.
+    Overflowed(ClientId),
+    /// A connection of a persistent session has closed before it received a publish.
+    Undelivered(ClientId, Box<ControlPacket>, PublishMetadata),
+}
+
+#[derive(Debug)]
+struct ConnectedClient {
+    info: Arc<ConnectionInfo>,
+    sender: ConnectionSender,
+    clean_session: bool,
+    will_packet: Option<ControlPacket>,
+    taken_over: Option<Arc<Notify>>,
+}
 
-// TODO: add retained messages max number to remove old ones
 #[derive(Debug)]
 pub struct Control {
     receiver: ControlReceiver,
-    connections: HashMap<ClientId, ConnectionSender>,
-    subscription_tree: SubscriptionTree,
-    retained_messages: Vec<(Topic, ControlPacket)>,
+    connections: HashMap<ClientId, ConnectedClient>,
+    /// Changed only by Control Worker, connections look subscribers of their publishes up.
+    subscription_tree: SharedSubscriptionTree,
+    /// Subscribers of recently published topics, kept in sync with `subscription_tree`.
+    retained_store: RetainedStore,
+    /// Subscriptions of in-process clients, which have no stored sessions to restore them from.
+    internal_subscriptions: InternalSubscriptions,
+    publish_ordering: PublishOrdering<QueuedPublish>,
     state_store: Arc<RwLock<SessionStateStore>>,
+    /// Limit of messages queued for offline clients.
+    queue_limit: QueueLimit,
+    /// Publishes are traced at most once per `PUBLISH_TRACE_INTERVAL`.
+    last_publish_trace: Option<Instant>,
+    untraced_publishes: usize,
     is_shutting_down: bool,
     shut_down_channel: Sender<()>,
+    broker_id: String,
+    retained_bypass: RetainedBypass,
+    /// Retained messages sent to new subscriptions are downgraded to this QoS.
+    retained_max_qos: Option<QoS>,
+    /// Stored sessions are discarded once their clients have been disconnected for this long.
+    session_expiry_interval: Option<Duration>,
+    /// A warning is logged once the subscription tree grows over this many nodes.
+    subscription_tree_warning_nodes: Option<usize>,
+    is_subscription_tree_over_warning: bool,
+    time_sync: Option<TimeSync>,
+    /// `None` if wills are published right away.
+    delayed_wills: Option<DelayedWills>,
+    clock: Clock,
+    is_overloaded: bool,
+    /// QoS 0 messages to these topic filters are dropped while the broker is overloaded.
+    load_shedding_topics: Vec<Subscription>,
+    /// Messages dropped since the broker has become overloaded.
+    shed_messages: usize,
+    priority: PriorityScheduler,
+    /// Publishes of a batch of control messages, fanned out by priority.
+    publish_queue: VecDeque<QueuedPublish>,
+    /// Publishes to closed connections of persistent sessions, queued for the sessions once
+    /// they have been saved and their clients are offline.
+    undelivered: HashMap<ClientId, Vec<(ControlPacket, PublishMetadata)>>,
 }
 
 impl Control {
+    const PUBLISH_TRACE_INTERVAL: Duration = Duration::from_secs(1);
+    const SESSION_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+    const SUBSCRIPTION_TREE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
     pub async fn new(
         config: &TeleMQServerConfig,
         state_store: Arc<RwLock<SessionStateStore>>,
         shut_down_channel: Sender<()>,
     ) -> (Self, ControlSender) {
         let (tx, rx) = unbounded_channel();
+        let clock = state_store.read().await.clock().clone();
+        let mut retained_store = RetainedStore::new(
+            config.max_storage_duration.map(Duration::from_secs),
+            config.retained_store_file.clone(),
+            clock.clone(),
+        );
+        let sessions = state_store.read().await.as_inner_data().await;
+        warn_about_collisions(&find_collisions(&sessions, &mut retained_store));
+        let internal_subscriptions =
+            InternalSubscriptions::new(config.internal_subscriptions_file.clone());
+        let mut subscription_tree =
+            SubscriptionTree::from_session_state_store(state_store.clone()).await;
+        internal_subscriptions.restore(&mut subscription_tree);
         (
             Control {
                 receiver: rx,
                 connections: HashMap::with_capacity(config.max_connections),
-                subscription_tree: SubscriptionTree::from_session_state_store(state_store.clone())
-                    .await,
-                retained_messages: vec![],
+                subscription_tree: SharedSubscriptionTree::new(
+                    subscription_tree,
+                    config.routing_cache_size,
+                ),
+                retained_store,
+                internal_subscriptions,
+                publish_ordering: PublishOrdering::default(),
                 state_store,
+                queue_limit: QueueLimit {
+                    max_messages: config.max_queued_messages_per_client,
+                    overflow_policy: config.queue_overflow_policy,
+                    queue_qos0_messages: config.queue_qos0_messages,
+                },
+                last_publish_trace: None,
+                untraced_publishes: 0,
                 is_shutting_down: false,
                 shut_down_channel,
+                broker_id: config.broker_id.clone(),
+                retained_bypass: RetainedBypass::new(&config.retained_bypass),
+                // the value is validated with the config
+                retained_max_qos: config
+                    .retained_max_qos
+                    .and_then(|qos| QoS::try_from(qos).ok()),
+                session_expiry_interval: config.session_expiry_interval,
+                subscription_tree_warning_nodes: config.subscription_tree_warning_nodes,
+                is_subscription_tree_over_warning: false,
+                time_sync: TimeSync::new(config),
+                delayed_wills: config.will_delay_interval.map(DelayedWills::new),
+                clock,
+                is_overloaded: false,
+                // filters are validated with the config
+                load_shedding_topics: config
+                    .load_shedding_topics
+                    .iter()
+                    .filter_map(|filter| Subscription::try_from(filter).ok())
+                    .collect(),
+                shed_messages: 0,
+                priority: PriorityScheduler::new(PriorityTopics::new(config)),
+                publish_queue: VecDeque::new(),
+                undelivered: HashMap::new(),
             },
             tx,
         )
     }
 
+    /// The subscription tree for connections to look subscribers of their publishes up in.
+    pub fn subscription_tree(&self) -> SharedSubscriptionTree {
+        self.subscription_tree.clone()
+    }
+
     pub async fn run(mut self) -> io::Result<()> {
+        let mut session_expiry_check = interval(Self::SESSION_EXPIRY_CHECK_INTERVAL);
+        let mut subscription_tree_check = interval(Self::SUBSCRIPTION_TREE_CHECK_INTERVAL);
+        let time_sync_interval = self.time_sync.as_ref().and_then(TimeSync::interval);
+        // the period doesn't matter if the branch is disabled
+        let mut time_sync_tick = interval(time_sync_interval.unwrap_or(Duration::from_secs(60)));
         loop {
+            let next_will = self
+                .delayed_wills
+                .as_ref()
+                .and_then(DelayedWills::next_deadline);
             select! {
+              _ = sleep_until(next_will.unwrap_or_else(Instant::now)), if next_will.is_some() => {
+                self.publish_due_wills().await;
+              }
+              _ = session_expiry_check.tick() => {
+                self.on_session_expiry_check().await;
+              }
+              _ = subscription_tree_check.tick() => {
+                self.check_subscription_tree();
+              }
+              _ = time_sync_tick.tick(), if time_sync_interval.is_some() => {
+                self.publish_time().await;
+              }
               Some(control_message) = self.receiver.recv() => {
-                match control_message {
-                  ControlMessage::ClientConnected{sender, client_id, clean_session, ..} => {
-                    self.on_add_connection(sender, client_id, clean_session).await;
-                  },
-                  ControlMessage::AddSubscriptions{subscriptions, client_id, .. } => {
-                    self.on_add_subscriptions(client_id, subscriptions).await;
-                  }
-                  ControlMessage::RemoveSubscriptions{subscriptions, client_id, ..} => {
-                    self.on_remove_subscriptions(client_id, subscriptions);
-                  }
-                  ControlMessage::Publish{packet, ..} => {
-                    self.on_publish(packet).await;
-                  }
-                  ControlMessage::ClientDisconnected{client_id, clean_session, will_packet, ..} => {
-                    self.on_client_disconnect(client_id, clean_session, will_packet).await;
-                  }
-                  ControlMessage::ShutDown => {
-                    self.on_shut_down().await;
+                self.on_control_message(control_message).await;
+                if self.priority.is_enabled() {
+                  // publishes waiting in the channel are fanned out together by priority
+                  for _ in 1..PRIORITY_BATCH_SIZE {
+                    let control_message = match self.receiver.try_recv() {
+                      Ok(control_message) => control_message,
+                      Err(_) => break,
+                    };
+                    if !matches!(control_message, ControlMessage::Publish{..}) {
+                      self.fan_out_queued().await;
+                    }
+                    self.on_control_message(control_message).await;
                   }
+                  self.fan_out_queued().await;
                 }
               }
             }
         }
     }
 
+    async fn on_control_message(&mut self, control_message: ControlMessage) {
+        match control_message {
+            ControlMessage::ClientConnected {
+                sender,
+                connection,
+                clean_session,
+                will_packet,
+                taken_over,
+            } => {
+                self.on_add_connection(sender, connection, clean_session, will_packet, taken_over)
+                    .await;
+            }
+            ControlMessage::AddSubscriptions {
+                subscriptions,
+                connection,
+            } => {
+                self.on_add_subscriptions(connection.client_id.clone(), subscriptions)
+                    .await;
+            }
+            ControlMessage::RemoveSubscriptions {
+                subscriptions,
+                connection,
+            } => {
+                self.on_remove_subscriptions(connection.client_id.clone(), subscriptions);
+            }
+            ControlMessage::Publish {
+                packet,
+                metadata,
+                publisher,
+                sequence,
+                subscribers,
+            } => {
+                let time_reply = publisher
+                    .as_ref()
+                    .and_then(|publisher| self.time_sync_reply(&packet, &publisher.client_id));
+                let addr = publisher.map(|publisher| publisher.addr);
+                self.on_sequenced_publish(addr, sequence, (packet, metadata, subscribers))
+                    .await;
+                if let Some(reply) = time_reply {
+                    self.accept_publish((reply, PublishMetadata::default(), None))
+                        .await;
+                }
+            }
+            ControlMessage::ClientDisconnected {
+                connection,
+                clean_session,
+                will_packet,
+                undelivered,
+            } => {
+                for publish in self.publish_ordering.remove_publisher(&connection.addr) {
+                    self.on_queued_publish(publish).await;
+                }
+                if !undelivered.is_empty() {
+                    // sent before the ones Control Worker has failed to send
+                    let failed = self.undelivered.remove(&connection.client_id);
+                    self.undelivered.insert(
+                        connection.client_id.clone(),
+                        undelivered
+                            .into_iter()
+                            .chain(failed.into_iter().flatten())
+                            .collect(),
+                    );
+                }
+                self.on_client_disconnect(connection, clean_session, will_packet)
+                    .await;
+            }
+            ControlMessage::CountSubscribers { filter, reply } => {
+                self.on_count_subscribers(filter, reply);
+            }
+            ControlMessage::PurgeRetained { filter, reply } => {
+                self.on_purge_retained(filter, reply);
+            }
+            ControlMessage::TakeOverSession { client_id, addr } => {
+                self.on_take_over_session(client_id, addr);
+            }
+            ControlMessage::DumpSubscriptions { reply } => {
+                self.on_dump_subscriptions(reply);
+            }
+            ControlMessage::SubscriptionTreeUsage { reply } => {
+                let usage = self.check_subscription_tree();
+                if reply.send(usage).is_err() {
+                    error!("[Control Worker]: Unable to reply with subscription tree usage");
+                }
+            }
+            ControlMessage::ListConnections { reply } => {
+                self.on_list_connections(reply);
+            }
+            ControlMessage::ConnectionAborted { addr, reply } => {
+                for publish in self.publish_ordering.remove_publisher(&addr) {
+                    self.on_queued_publish(publish).await;
+                }
+                self.on_connection_aborted(addr, reply).await;
+            }
+            ControlMessage::DropConnections { count } => {
+                self.on_drop_connections(count);
+            }
+            ControlMessage::ReloadSubscriptions => {
+                let mut subscription_tree =
+                    SubscriptionTree::from_session_state_store(self.state_store.clone()).await;
+                // in-process clients of this process stay connected
+                self.internal_subscriptions.restore(&mut subscription_tree);
+                self.subscription_tree.update(|tree, routing_cache| {
+                    *tree = subscription_tree;
+                    routing_cache.clear();
+                });
+                self.retained_store.reload();
+            }
+            ControlMessage::Overload { overloaded } => {
+                self.on_overload(overloaded).await;
+            }
+            ControlMessage::ShutDown => {
+                self.on_publish(
+                    state_packet(&self.broker_id, BrokerState::Offline),
+                    PublishMetadata::default(),
+                )
+                .await;
+                self.on_shut_down().await;
+            }
+            ControlMessage::HandOver => {
+                self.on_shut_down().await;
+            }
+        }
+    }
+
     async fn on_add_connection(
         &mut self,
         sender: ConnectionSender,
-        client_id: String,
+        connection: Arc<ConnectionInfo>,
         clean_session: bool,
+        will_packet: Option<ControlPacket>,
+        taken_over: Option<Arc<Notify>>,
     ) {
+        let client_id = connection.client_id.clone();
+        if self
+            .delayed_wills
+            .as_mut()
+            .is_some_and(|delayed_wills| delayed_wills.cancel(&client_id))
+        {
+            info!(
+                "[Control Worker]: {} has reconnected, its will is discarded",
+                connection
+            );
+        }
         if clean_session {
-            self.subscription_tree.disconnect_subscriber(&client_id);
+            self.disconnect_subscriber(&client_id);
             let _ = self.state_store.write().await.take_state(&client_id).await;
         }
+        if connection.transport == ConnectionTransport::InProcess {
+            // an in-process client subscribes anew every time it connects
+            self.internal_subscriptions.forget(&client_id);
+        }
 
-        if let Some(connected_client_sender) = self.connections.remove(&client_id) {
+        if let Some(connected_client) = self.connections.remove(&client_id) {
             // there is already a connected client with the same id
             // disconnect it
-            info!("Disconnecting already connected client {:?}", client_id);
-            let message = ConnectionMessage::Disconnect;
-            let message_type = message.get_name();
-            if let Err(err) = connected_client_sender.send(message) {
-                error!(
-                    "[Control Worker]: Unable to send {} to {:?}. {:?}",
-                    message_type, client_id, err
-                );
-            }
+            info!(
+                "Disconnecting already connected client {}",
+                connected_client.info
+            );
+            disconnect_taken_over(&connected_client);
         }
-        self.connections.insert(client_id, sender);
+        self.connections.insert(
+            client_id,
+            ConnectedClient {
+                info: connection,
+                sender,
+                clean_session,
+                will_packet,
+                taken_over,
+            },
+        );
     }
 
     async fn on_add_subscriptions(
@@ -168,64 +538,273 @@ impl Control {
         client_id: ClientId,
         subscriptions: Vec<Subscription>,
     ) {
-        if self.connections.get(&client_id).is_none() {
-            return;
-        }
-
-        for sub in &subscriptions {
-            self.subscription_tree
-                .add_subscriber(&sub.path, client_id.clone());
+        match self.connections.get(&client_id) {
+            Some(connected_client) if is_in_process(connected_client) => {
+                self.internal_subscriptions.add(&client_id, &subscriptions);
+            }
+            Some(_) => {}
+            None => return,
         }
 
-        let mut futs = Vec::new();
+        let mut retained_messages = Vec::new();
         for sub in &subscriptions {
-            for (topic, publish_packet) in &self.retained_messages {
-                if sub.topic_matches(&topic) {
-                    futs.push(self.inform_connection(
-                        client_id.clone(),
-                        ConnectionMessage::Publish {
-                            packet: publish_packet.clone(),
-                            retained_for: Some(sub.original.clone()),
-                        },
-                    ));
+            let is_new = self.add_subscriber(sub, client_id.clone());
+            for mut retained in self.retained_store.matching(sub) {
+                if self.retained_bypass.allows(&client_id, &retained, is_new) {
+                    if let Some(ref max_qos) = self.retained_max_qos {
+                        retained.cap_qos(max_qos);
+                    }
+                    retained_messages.push((sub.original.clone(), retained));
                 }
             }
         }
 
-        join_all(futs).await;
+        let mut futs = Vec::with_capacity(retained_messages.len());
+        for (retained_for, retained) in retained_messages {
+            futs.push(self.inform_connection(
+                client_id.clone(),
+                ConnectionMessage::Publish {
+                    packet: retained.packet,
+                    metadata: retained.metadata,
+                    retained_for: Some(retained_for),
+                },
+            ));
+        }
+
+        for delivery in join_all(futs).await {
+            self.on_delivery(delivery).await;
+        }
     }
 
     fn on_remove_subscriptions(&mut self, client_id: ClientId, subscriptions: Vec<Subscription>) {
+        if self.connections.get(&client_id).is_some_and(is_in_process) {
+            self.internal_subscriptions
+                .remove(&client_id, &subscriptions);
+        }
         for sub in subscriptions {
-            self.subscription_tree
-                .remove_subscriber(&sub.path, client_id.clone());
+            self.remove_subscriber(&sub, client_id.clone());
         }
     }
 
     async fn on_client_disconnect(
         &mut self,
-        client_id: ClientId,
+        connection: Arc<ConnectionInfo>,
         clean_session: bool,
         will_packet: Option<ControlPacket>,
     ) {
+        // a connection which has been taken over must not affect the one which took it over
+        let is_current = match self.connections.get(&connection.client_id) {
+            Some(connected_client) => Arc::ptr_eq(&connected_client.info, &connection),
+            None => true,
+        };
+
         if let Some(to_send) = will_packet {
-            self.on_publish(to_send).await;
+            // a client which took its session over has reconnected within the will delay
+            if is_current || self.delayed_wills.is_none() {
+                self.publish_will(connection.client_id.clone(), to_send)
+                    .await;
+            }
         }
 
+        if is_current {
+            if clean_session {
+                self.disconnect_subscriber(&connection.client_id);
+            }
+            self.connections.remove(&connection.client_id);
+        }
         if clean_session {
-            self.subscription_tree.disconnect_subscriber(&client_id);
+            self.undelivered.remove(&connection.client_id);
+        } else {
+            self.queue_undelivered(&connection.client_id).await;
         }
-        self.connections.remove(&client_id);
 
-        if self.connections.is_empty() && self.is_shutting_down {
-            if let Err(err) = self.state_store.read().await.commit().await {
-                error!("[Control Worker]: unable to commit State Store. {:?}", err);
+        self.complete_shut_down_if_idle().await;
+    }
+
+    async fn on_connection_aborted(
+        &mut self,
+        addr: SocketAddr,
+        reply: oneshot::Sender<Option<String>>,
+    ) {
+        let client_id = self
+            .connections
+            .iter()
+            .find(|(_, connected_client)| connected_client.info.addr == addr)
+            .map(|(client_id, _)| client_id.clone());
+
+        if let Some(connected_client) = client_id
+            .as_ref()
+            .and_then(|client_id| self.connections.remove(client_id))
+        {
+            error!(
+                "[Control Worker]: Connection {} has terminated abnormally",
+                connected_client.info
+            );
+            if connected_client.clean_session {
+                self.disconnect_subscriber(&connected_client.info.client_id);
             }
+            self.queue_undelivered(&connected_client.info.client_id)
+                .await;
+            if let Some(will_packet) = connected_client.will_packet {
+                self.publish_will(connected_client.info.client_id.clone(), will_packet)
+                    .await;
+            }
+        }
+
+        if reply.send(client_id).is_err() {
+            error!("[Control Worker]: Unable to reply to a connection watchdog");
+        }
+        self.complete_shut_down_if_idle().await;
+    }
+
+    /// Publishes a will right away or once `will_delay_interval` elapses.
+    async fn publish_will(&mut self, client_id: ClientId, will_packet: ControlPacket) {
+        match self.delayed_wills {
+            Some(ref mut delayed_wills) => delayed_wills.schedule(client_id, will_packet),
+            None => {
+                self.on_publish(will_packet, PublishMetadata::default())
+                    .await
+            }
+        }
+    }
+
+    async fn publish_due_wills(&mut self) {
+        let due = match self.delayed_wills {
+            Some(ref mut delayed_wills) => delayed_wills.take_due(Instant::now()),
+            None => return,
+        };
+        for will_packet in due {
+            self.on_publish(will_packet, PublishMetadata::default())
+                .await;
+        }
+    }
+
+    /// Publishes delayed wills before the broker stops, so they are not lost.
+    async fn publish_pending_wills(&mut self) {
+        let pending = match self.delayed_wills {
+            Some(ref mut delayed_wills) => delayed_wills.take_all(),
+            None => return,
+        };
+        for will_packet in pending {
+            self.on_publish(will_packet, PublishMetadata::default())
+                .await;
+        }
+    }
+
+    /// Finishes a graceful shut down once all connections are closed.
+    async fn complete_shut_down_if_idle(&mut self) {
+        if self.connections.is_empty() && self.is_shutting_down {
+            self.publish_pending_wills().await;
+            self.commit_stores().await;
             self.shut_down_channel.send(()).await.unwrap();
         }
     }
 
-    async fn on_publish(&mut self, control_packet: ControlPacket) {
+    fn on_take_over_session(&mut self, client_id: ClientId, addr: SocketAddr) {
+        if let Some(connected_client) = self.connections.remove(&client_id) {
+            // e.g. a device behind a NAT which has rebound its mapping
+            let address = if connected_client.info.addr.ip() == addr.ip() {
+                "the same address"
+            } else {
+                "a new address"
+            };
+            info!(
+                "[Control Worker]: Session of {} is taken over by a new connection from {} {}",
+                connected_client.info, address, addr
+            );
+            disconnect_taken_over(&connected_client);
+        }
+    }
+
+    fn on_list_connections(&self, reply: oneshot::Sender<Vec<Arc<ConnectionInfo>>>) {
+        let mut connections: Vec<Arc<ConnectionInfo>> = self
+            .connections
+            .values()
+            .map(|connected_client| connected_client.info.clone())
+            .collect();
+        connections.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        if reply.send(connections).is_err() {
+            error!("[Control Worker]: Unable to reply with a list of connections");
+        }
+    }
+
+    fn on_drop_connections(&self, count: usize) {
+        // in-process clients are not limited by max_connections
+        let mut connected: Vec<&ConnectedClient> = self
+            .connections
+            .values()
+            .filter(|connected_client| {
+                connected_client.info.transport != ConnectionTransport::InProcess
+            })
+            .collect();
+        connected.sort_by_key(|connected_client| Reverse(connected_client.info.connected_at));
+
+        for connected_client in connected.into_iter().take(count) {
+            info!(
+                "[Control Worker]: Disconnecting {} above max_connections",
+                connected_client.info
+            );
+            if let Err(err) = connected_client
+                .sender
+                .send(ConnectionMessage::DropOverLimit)
+            {
+                error!(
+                    "[Control Worker]: Unable to send ConnectionMessage::DropOverLimit to {}. {:?}",
+                    connected_client.info, err
+                );
+            }
+        }
+    }
+
+    async fn on_sequenced_publish(
+        &mut self,
+        addr: Option<SocketAddr>,
+        sequence: Option<PublishSequence>,
+        publish: QueuedPublish,
+    ) {
+        match (addr, sequence) {
+            (Some(addr), Some(sequence)) => {
+                for publish in self.publish_ordering.accept(addr, sequence, publish) {
+                    self.accept_publish(publish).await;
+                }
+            }
+            _ => self.accept_publish(publish).await,
+        }
+    }
+
+    /// Fans out a publish, unless priorities are enabled: then it's queued until the rest of
+    /// publishes waiting in the channel are received.
+    async fn accept_publish(&mut self, publish: QueuedPublish) {
+        if self.priority.is_enabled() {
+            self.publish_queue.push_back(publish);
+        } else {
+            self.on_queued_publish(publish).await;
+        }
+    }
+
+    async fn fan_out_queued(&mut self) {
+        let queue = std::mem::take(&mut self.publish_queue);
+        for publish in self.priority.drain(queue, |(packet, _, _)| packet) {
+            self.on_queued_publish(publish).await;
+        }
+    }
+
+    async fn on_queued_publish(&mut self, (packet, metadata, subscribers): QueuedPublish) {
+        self.route_publish(packet, metadata, subscribers).await;
+    }
+
+    async fn on_publish(&mut self, control_packet: ControlPacket, metadata: PublishMetadata) {
+        self.route_publish(control_packet, metadata, None).await;
+    }
+
+    /// Fans out a publish to subscribers looked up by its publisher connection, unless the
+    /// subscription tree has changed since, or to subscribers looked up by Control Worker.
+    async fn route_publish(
+        &mut self,
+        control_packet: ControlPacket,
+        metadata: PublishMetadata,
+        resolved: Option<ResolvedSubscribers>,
+    ) {
         let variable = match &control_packet.variable {
             &Variable::Publish(ref variable) => variable,
             _ => {
@@ -235,54 +814,262 @@ impl Control {
         let topic = &variable.topic_name;
 
         if is_retained(&control_packet.fixed_header) {
-            self.retained_messages
-                .push((topic.clone(), control_packet.clone()));
+            self.retained_store.set(&control_packet, &metadata);
         }
 
-        let subscribers = self.subscription_tree.find_subscribers(&topic.path);
+        if self.is_overloaded
+            && get_qos_level(&control_packet.fixed_header).is_ok_and(|qos| qos == QoS::Zero)
+            && self
+                .load_shedding_topics
+                .iter()
+                .any(|filter| filter.topic_matches(topic))
+        {
+            self.shed_messages += 1;
+            return;
+        }
+
+        let subscribers = match resolved {
+            Some(resolved) if self.subscription_tree.is_current(&resolved) => resolved.subscribers,
+            _ => self.subscription_tree.resolve(&topic.path).subscribers,
+        };
+        if log_enabled!(Level::Trace) {
+            self.trace_publish(&topic.original, subscribers.len());
+        }
 
         // allowed
         let mut futs = Vec::with_capacity(subscribers.len());
-        for client_id in subscribers {
+        for client_id in subscribers.iter() {
             futs.push(self.inform_connection(
                 client_id.clone(),
                 ConnectionMessage::Publish {
                     packet: control_packet.clone(),
+                    metadata: metadata.clone(),
                     retained_for: None,
                 },
             ));
         }
 
-        join_all(futs).await;
+        for delivery in join_all(futs).await {
+            self.on_delivery(delivery).await;
+        }
+    }
+
+    async fn on_overload(&mut self, overloaded: bool) {
+        if overloaded && !self.is_overloaded {
+            warn!("[Control Worker]: Broker is overloaded, new connections are rejected and QoS 0 messages to load_shedding_topics are dropped");
+        } else if !overloaded && self.is_overloaded {
+            info!(
+                "[Control Worker]: Broker has recovered from overload, {} messages have been dropped",
+                self.shed_messages
+            );
+        }
+        self.is_overloaded = overloaded;
+        self.shed_messages = 0;
+        self.on_publish(overload_packet(overloaded), PublishMetadata::default())
+            .await;
+    }
+
+    /// Returns `false` if a client has already been subscribed to a topic filter.
+    fn add_subscriber(&mut self, sub: &Subscription, client_id: ClientId) -> bool {
+        self.subscription_tree.update(|tree, routing_cache| {
+            routing_cache.invalidate_filter(&sub.path);
+            tree.add_subscriber(&sub.path, client_id)
+        })
+    }
+
+    fn remove_subscriber(&mut self, sub: &Subscription, client_id: ClientId) {
+        self.subscription_tree.update(|tree, routing_cache| {
+            routing_cache.invalidate_filter(&sub.path);
+            tree.remove_subscriber(&sub.path, client_id);
+        });
+    }
+
+    fn disconnect_subscriber(&mut self, client_id: &ClientId) {
+        self.subscription_tree.update(|tree, routing_cache| {
+            routing_cache.remove_subscriber(client_id);
+            tree.disconnect_subscriber(client_id);
+        });
+    }
+
+    fn trace_publish(&mut self, topic: &str, subscribers: usize) {
+        let now = Instant::now();
+        if self
+            .last_publish_trace
+            .is_some_and(|last| now.duration_since(last) < Self::PUBLISH_TRACE_INTERVAL)
+        {
+            self.untraced_publishes += 1;
+            return;
+        }
+
+        trace!(
+            "[Control Worker]: Publish to {:?} is routed to {} subscribers ({} more publishes since a previous trace)",
+            topic, subscribers, self.untraced_publishes
+        );
+        self.last_publish_trace = Some(now);
+        self.untraced_publishes = 0;
+    }
+
+    async fn publish_time(&mut self) {
+        if let Some(ref time_sync) = self.time_sync {
+            let packet = time_sync.time_packet(self.clock.now());
+            self.on_publish(packet, PublishMetadata::default()).await;
+        }
+    }
+
+    fn time_sync_reply(&self, packet: &ControlPacket, client_id: &str) -> Option<ControlPacket> {
+        self.time_sync
+            .as_ref()
+            .and_then(|time_sync| time_sync.reply(packet, client_id, self.clock.now()))
+    }
+
+    fn on_dump_subscriptions(&self, reply: oneshot::Sender<Vec<(String, Vec<String>)>>) {
+        if reply
+            .send(self.subscription_tree.read(SubscriptionTree::entries))
+            .is_err()
+        {
+            error!("[Control Worker]: Unable to reply with subscriptions");
+        }
+    }
+
+    /// Measures the subscription tree and logs when it crosses `subscription_tree_warning_nodes`.
+    fn check_subscription_tree(&mut self) -> (TreeUsage, bool) {
+        let usage = self.subscription_tree.read(SubscriptionTree::usage);
+        let warning_nodes = match self.subscription_tree_warning_nodes {
+            Some(warning_nodes) => warning_nodes,
+            None => return (usage, false),
+        };
+
+        let is_over_warning = usage.nodes > warning_nodes;
+        if is_over_warning && !self.is_subscription_tree_over_warning {
+            warn!(
+                "[Control Worker]: subscription tree has grown to {} nodes ({} subscriptions, ~{} bytes), over subscription_tree_warning_nodes of {}",
+                usage.nodes, usage.subscriptions, usage.estimated_bytes, warning_nodes
+            );
+        } else if !is_over_warning && self.is_subscription_tree_over_warning {
+            info!(
+                "[Control Worker]: subscription tree has shrunk to {} nodes",
+                usage.nodes
+            );
+        }
+        self.is_subscription_tree_over_warning = is_over_warning;
+
+        (usage, is_over_warning)
+    }
+
+    fn on_count_subscribers(&self, filter: Subscription, reply: oneshot::Sender<usize>) {
+        let subscribers = self
+            .subscription_tree
+            .read(|tree| tree.find_matching_subscribers(&filter.path));
+        if reply.send(subscribers.len()).is_err() {
+            error!("[Control Worker]: Unable to reply with a number of subscribers");
+        }
+    }
+
+    fn on_purge_retained(&mut self, filter: Subscription, reply: oneshot::Sender<usize>) {
+        let removed = self.retained_store.purge(&filter);
+        info!(
+            "[Control Worker]: {} retained messages matching {:?} have been removed",
+            removed, filter.original
+        );
+        if reply.send(removed).is_err() {
+            error!("[Control Worker]: Unable to reply with a number of removed retained messages");
+        }
     }
 
     async fn on_shut_down(&mut self) {
         if self.connections.is_empty() {
+            self.publish_pending_wills().await;
+            // stored sessions of offline clients should survive a restart as well
+            self.commit_stores().await;
             self.shut_down_channel.send(()).await.unwrap();
             return;
         }
 
         self.is_shutting_down = true;
 
-        for (con, ch) in &self.connections {
-            if let Err(err) = ch.send(ConnectionMessage::ShutDown) {
+        for connected_client in self.connections.values() {
+            if let Err(err) = connected_client.sender.send(ConnectionMessage::ShutDown) {
                 error!(
-                    "[Control Worker]: unable to gracefully shut down connection {:?}. {:?}",
-                    con, err
+                    "[Control Worker]: unable to gracefully shut down connection {}. {:?}",
+                    connected_client.info, err
                 );
             }
         }
     }
 
-    async fn inform_connection(&self, client_id: ClientId, message: ConnectionMessage) {
+    async fn commit_stores(&self) {
+        if let Err(err) = self.state_store.read().await.commit().await {
+            error!("[Control Worker]: unable to commit State Store. {:?}", err);
+        }
+        if let Err(err) = self.retained_store.commit() {
+            error!(
+                "[Control Worker]: unable to commit Retained Store. {:?}",
+                err
+            );
+        }
+        if let Err(err) = self.internal_subscriptions.commit() {
+            error!(
+                "[Control Worker]: unable to commit Internal Subscriptions. {:?}",
+                err
+            );
+        }
+    }
+
+    /// Drops stored sessions of clients offline for `session_expiry_interval` or longer.
+    async fn on_session_expiry_check(&mut self) {
+        let expiry_interval = match self.session_expiry_interval {
+            Some(expiry_interval) => expiry_interval,
+            None => return,
+        };
+
+        let expired = self
+            .state_store
+            .write()
+            .await
+            .remove_expired(expiry_interval);
+        for client_id in expired {
+            info!(
+                "[Control Worker]: Session of {} has expired and is discarded",
+                client_id
+            );
+            self.disconnect_subscriber(&client_id);
+        }
+    }
+
+    /// Drops a stored session of an offline client which queue has overflowed, so the client
+    /// gets a clean session once it reconnects.
+    async fn discard_session(&mut self, client_id: &ClientId) {
+        info!(
+            "[Control Worker]: Queue of {} is full. Its session is discarded",
+            client_id
+        );
+        if let Err(err) = self.state_store.write().await.take_state(client_id).await {
+            error!(
+                "[Control Worker]: Unable to discard a session of {}. {:?}",
+                client_id, err
+            );
+        }
+        self.disconnect_subscriber(client_id);
+    }
+
+    async fn inform_connection(&self, client_id: ClientId, message: ConnectionMessage) -> Delivery {
         match self.connections.get(&client_id) {
-            Some(connection_sender) => {
+            Some(connected_client) => {
                 let message_type = message.get_name();
-                if let Err(err) = connection_sender.send(message) {
-                    error!(
-                        "[Control Worker]: Unable to send {} to {:?}. {:?}",
-                        message_type, client_id, err
-                    );
+                match connected_client.sender.send(message) {
+                    Ok(()) => {}
+                    // the connection has closed, its session is being saved
+                    Err(SendError(ConnectionMessage::Publish {
+                        packet, metadata, ..
+                    })) if !connected_client.clean_session => {
+                        return Delivery::Undelivered(client_id, Box::new(packet), metadata);
+                    }
+                    Err(err) => {
+                        error!(
+                            "[Control Worker]: Unable to send {} to {}. {:?}",
+                            message_type, connected_client.info, err
+                        );
+                    }
                 }
             }
             None => {
@@ -291,23 +1078,97 @@ impl Control {
                 // we assume it has a stored session (clean session = false) in
                 // the Session State Store
                 match message {
-                    ConnectionMessage::Publish { packet, .. } => {
-                        if let Err(err) = self
+                    ConnectionMessage::Publish {
+                        packet, metadata, ..
+                    } => {
+                        let queued = self
                             .state_store
                             .read()
                             .await
-                            .new_publish(&client_id, packet)
-                            .await
-                        {
-                            error!(
+                            .new_publish(&client_id, packet, metadata, &self.queue_limit)
+                            .await;
+                        match queued {
+                            Ok(Some(QueueOutcome::Overflowed)) => {
+                                return Delivery::Overflowed(client_id)
+                            }
+                            Ok(_) => {}
+                            Err(err) => {
+                                error!(
                 "[Control Worker]: Unable to update State Store with a new Publish. {:?}",
                 err
               );
+                            }
                         }
                     }
                     _ => {}
                 }
             }
         }
+
+        Delivery::Done
+    }
+
+    async fn on_delivery(&mut self, delivery: Delivery) {
+        match delivery {
+            Delivery::Done => {}
+            Delivery::Overflowed(client_id) => self.discard_session(&client_id).await,
+            Delivery::Undelivered(client_id, packet, metadata) => {
+                self.undelivered
+                    .entry(client_id)
+                    .or_default()
+                    .push((*packet, metadata));
+            }
+        }
+    }
+
+    /// Queues publishes which a closed connection hasn't received for its saved session.
+    async fn queue_undelivered(&mut self, client_id: &ClientId) {
+        let undelivered = match self.undelivered.remove(client_id) {
+            Some(undelivered) => undelivered,
+            None => return,
+        };
+        for (packet, metadata) in undelivered {
+            let queued = self
+                .state_store
+                .read()
+                .await
+                .new_publish(client_id, packet, metadata, &self.queue_limit)
+                .await;
+            match queued {
+                Ok(Some(QueueOutcome::Overflowed)) => {
+                    self.discard_session(client_id).await;
+                    return;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    error!(
+                        "[Control Worker]: Unable to update State Store with a new Publish. {:?}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn is_in_process(connected_client: &ConnectedClient) -> bool {
+    connected_client.info.transport == ConnectionTransport::InProcess
+}
+
+/// Disconnects a client which session has been taken over. A connection blocked on writing to
+/// a dead peer, e.g. after NAT rebinding, doesn't handle the message, so it's notified as well.
+fn disconnect_taken_over(connected_client: &ConnectedClient) {
+    let message = ConnectionMessage::Disconnect {
+        reason: DisconnectReason::SessionTakenOver,
+    };
+    let message_type = message.get_name();
+    if let Err(err) = connected_client.sender.send(message) {
+        error!(
+            "[Control Worker]: Unable to send {} to {}. {:?}",
+            message_type, connected_client.info, err
+        );
+    }
+    if let Some(ref taken_over) = connected_client.taken_over {
+        taken_over.notify_one();
     }
 }
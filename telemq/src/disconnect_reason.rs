@@ -0,0 +1,141 @@
+//! Why a connection of a client has been closed.
+//!
+//! A reason is recorded in a client history, counted under `$SYS/broker/disconnects/` and,
+//! if `publish_disconnect_reason` is on, sent to a client the broker disconnects on its own.
+//! Reasons are serialized in snake case, as they have been stored in client histories.
+use std::fmt;
+
+use mqtt_packets::v_5_0::ReasonCode;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    /// A client has sent DISCONNECT.
+    ClientDisconnect,
+    /// A network connection has been closed without DISCONNECT.
+    ConnectionLost,
+    /// A new connection with the same client id has taken the session over.
+    SessionTakenOver,
+    KeepAliveTimeout,
+    /// A connection above a lowered `max_connections` has been dropped.
+    ConnectionLimit,
+    /// A message has not been acknowledged within `max_retries`.
+    RetriesExhausted,
+    /// A client has sent an unexpected packet.
+    ProtocolError,
+    /// A client has sent a packet which can't be decoded.
+    MalformedPacket,
+    /// A client has sent a packet larger than the broker accepts.
+    PacketTooLarge,
+    /// An MQTT 5.0 client has used a topic alias it hasn't set or one above the maximum.
+    TopicAliasInvalid,
+    /// A client has exceeded a quota of the broker.
+    QuotaExceeded,
+    ServerShuttingDown,
+    /// A channel of messages to a client has been full, see `connection_channel_full_policy`.
+    SlowConsumer,
+}
+
+impl DisconnectReason {
+    pub const ALL: [DisconnectReason; 13] = [
+        DisconnectReason::ClientDisconnect,
+        DisconnectReason::ConnectionLost,
+        DisconnectReason::SessionTakenOver,
+        DisconnectReason::KeepAliveTimeout,
+        DisconnectReason::ConnectionLimit,
+        DisconnectReason::RetriesExhausted,
+        DisconnectReason::ProtocolError,
+        DisconnectReason::MalformedPacket,
+        DisconnectReason::PacketTooLarge,
+        DisconnectReason::TopicAliasInvalid,
+        DisconnectReason::QuotaExceeded,
+        DisconnectReason::ServerShuttingDown,
+        DisconnectReason::SlowConsumer,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::ClientDisconnect => "client_disconnect",
+            DisconnectReason::ConnectionLost => "connection_lost",
+            DisconnectReason::SessionTakenOver => "session_taken_over",
+            DisconnectReason::KeepAliveTimeout => "keep_alive_timeout",
+            DisconnectReason::ConnectionLimit => "connection_limit",
+            DisconnectReason::RetriesExhausted => "retries_exhausted",
+            DisconnectReason::ProtocolError => "protocol_error",
+            DisconnectReason::MalformedPacket => "malformed_packet",
+            DisconnectReason::PacketTooLarge => "packet_too_large",
+            DisconnectReason::TopicAliasInvalid => "topic_alias_invalid",
+            DisconnectReason::QuotaExceeded => "quota_exceeded",
+            DisconnectReason::ServerShuttingDown => "server_shutting_down",
+            DisconnectReason::SlowConsumer => "slow_consumer",
+        }
+    }
+
+    /// The broker has closed a connection on its own, rather than a client or a network.
+    pub fn by_broker(&self) -> bool {
+        !matches!(
+            self,
+            DisconnectReason::ClientDisconnect | DisconnectReason::ConnectionLost
+        )
+    }
+}
+
+/// A reason of a DISCONNECT the broker sends to a client which has broken the protocol.
+/// Reason codes without a reason of their own are recorded as `ProtocolError`.
+impl From<ReasonCode> for DisconnectReason {
+    fn from(reason_code: ReasonCode) -> Self {
+        match reason_code {
+            ReasonCode::MalformedPacket => DisconnectReason::MalformedPacket,
+            ReasonCode::PacketTooLarge => DisconnectReason::PacketTooLarge,
+            ReasonCode::TopicAliasInvalid => DisconnectReason::TopicAliasInvalid,
+            ReasonCode::QuotaExceeded => DisconnectReason::QuotaExceeded,
+            _ => DisconnectReason::ProtocolError,
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{from_value, to_value, Value};
+
+    #[test]
+    fn serialized_as_str() {
+        for reason in DisconnectReason::ALL {
+            let value = to_value(reason).unwrap();
+            assert_eq!(value, Value::String(reason.as_str().into()));
+            assert_eq!(from_value::<DisconnectReason>(value).unwrap(), reason);
+        }
+    }
+    #[test]
+    fn reason_codes_of_server_disconnects_are_recorded() {
+        assert_eq!(
+            DisconnectReason::from(ReasonCode::MalformedPacket),
+            DisconnectReason::MalformedPacket
+        );
+        assert_eq!(
+            DisconnectReason::from(ReasonCode::TopicAliasInvalid),
+            DisconnectReason::TopicAliasInvalid
+        );
+        assert_eq!(
+            DisconnectReason::from(ReasonCode::QuotaExceeded),
+            DisconnectReason::QuotaExceeded
+        );
+        assert_eq!(
+            DisconnectReason::from(ReasonCode::ProtocolError),
+            DisconnectReason::ProtocolError
+        );
+        // no reason of its own
+        assert_eq!(
+            DisconnectReason::from(ReasonCode::ReceiveMaximumExceeded),
+            DisconnectReason::ProtocolError
+        );
+    }
+}
@@ -0,0 +1,74 @@
+//! Limit of open file descriptors of the broker process.
+//!
+//! Every connection takes a file descriptor, so with a low `ulimit -n` the broker runs out
+//! of them before `max_connections` is reached and listeners can't accept connections.
+use std::io;
+
+use log::{info, warn};
+
+/// File descriptors taken by listeners, the admin API, logs, HTTP clients, etc.
+const RESERVED_FDS: u64 = 64;
+
+/// The soft limit of open files (`ulimit -n`), `None` if it's unlimited or unknown.
+#[cfg(unix)]
+pub fn open_files_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid pointer to `rlimit` for the duration of the call
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    // rlim_t is not u64 on every platform
+    #[allow(clippy::unnecessary_cast)]
+    Some(limit.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+pub fn open_files_limit() -> Option<u64> {
+    None
+}
+
+/// Warns if the broker can run out of file descriptors before `max_connections` clients
+/// are connected. Returns the limit of open files.
+pub fn check_open_files_limit(max_connections: usize) -> Option<u64> {
+    let limit = open_files_limit()?;
+    let required = max_connections as u64 + RESERVED_FDS;
+    if limit < required {
+        warn!(
+            "[Startup]: open files limit is {}, but up to {} may be required for max_connections = {}. Raise it with `ulimit -n` or LimitNOFILE.",
+            limit, required, max_connections
+        );
+    } else {
+        info!("[Startup]: open files limit is {}", limit);
+    }
+    Some(limit)
+}
+
+/// Whether `err` is caused by the process (EMFILE) or the system (ENFILE) running out of
+/// file descriptors.
+pub fn is_fd_exhaustion(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fd_exhaustion_is_detected() {
+        assert!(is_fd_exhaustion(&io::Error::from_raw_os_error(
+            libc::EMFILE
+        )));
+        assert!(is_fd_exhaustion(&io::Error::from_raw_os_error(
+            libc::ENFILE
+        )));
+        assert!(!is_fd_exhaustion(&io::Error::from_raw_os_error(
+            libc::ECONNABORTED
+        )));
+        assert!(!is_fd_exhaustion(&io::ErrorKind::Other.into()));
+    }
+}
@@ -0,0 +1,325 @@
+//! Zero-downtime upgrade of a running broker.
+//!
+//! A broker with `handover_socket` configured listens on a Unix control socket. A new broker
+//! process started with `--take-over` connects to it and the processes run a handshake:
+//!
+//! 1. new -> old: `hello` with a handover protocol version and a broker version. The old process
+//!    replies with `welcome` or `rejected` if the protocol version is not supported.
+//! 2. new: binds TCP and TLS listeners on the same ports and sends `ready`. Both processes
+//!    set `SO_REUSEPORT` on these listeners only for the handover, the old one right before
+//!    `welcome` and the new one until the old process exits.
+//! 3. old: closes its TCP and TLS listeners, so all new connections go to the new process,
+//!    gracefully shuts down existing connections (persistent sessions are written to the Session
+//!    State Store) and sends `drained`.
+//! 4. new: reloads the Session State Store, waits for the old process to exit, binds the rest
+//!    of listeners (Websocket, Admin API) and starts serving.
+//!
+//! Disconnected clients reconnect to the new process and resume their persistent sessions.
+//! Messages are newline delimited JSON documents.
+use std::{
+    io,
+    net::SocketAddr,
+    os::unix::{io::AsRawFd, net::UnixStream as StdUnixStream},
+    time::Duration,
+};
+
+use futures::SinkExt;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, TcpSocket, UnixListener, UnixStream},
+    time::timeout,
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+
+pub const HANDOVER_PROTOCOL_VERSION: u32 = 1;
+const BROKER_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Max time to wait for a handshake message.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Max time to wait for the old process to disconnect all clients and to exit.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(120);
+const LISTEN_BACKLOG: u32 = 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum HandoverMessage {
+    Hello { protocol: u32, version: String },
+    Welcome { version: String },
+    Rejected { reason: String },
+    Ready,
+    Drained,
+}
+
+/// Binds a TCP listener. If `reuse_port` is `true` the listener is bound with `SO_REUSEPORT`
+/// to share the address with a broker process which is being taken over.
+pub fn bind_tcp(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(reuse_port)?;
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// Sets or clears `SO_REUSEPORT` of bound listeners, so another broker process is able to bind
+/// their addresses only while a handover is in progress.
+pub fn set_reuse_port(listeners: &[&TcpListener], reuse_port: bool) -> io::Result<()> {
+    let value = libc::c_int::from(reuse_port);
+    for listener in listeners {
+        // SAFETY: the descriptor is open for the lifetime of `listener` and `value` is a valid
+        // pointer to `c_int` for the duration of the call
+        let result = unsafe {
+            libc::setsockopt(
+                listener.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_REUSEPORT,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+struct HandoverChannel(Framed<UnixStream, LinesCodec>);
+
+impl HandoverChannel {
+    fn new(stream: UnixStream) -> Self {
+        HandoverChannel(Framed::new(stream, LinesCodec::new()))
+    }
+
+    async fn send(&mut self, message: HandoverMessage) -> io::Result<()> {
+        let line = serde_json::to_string(&message)?;
+        self.0.send(line).await.map_err(codec_error)
+    }
+
+    async fn recv(&mut self, wait_for: Duration) -> io::Result<HandoverMessage> {
+        match timeout(wait_for, self.0.next()).await {
+            Ok(Some(Ok(line))) => serde_json::from_str(&line).map_err(Into::into),
+            Ok(Some(Err(err))) => Err(codec_error(err)),
+            Ok(None) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "handover peer closed the connection",
+            )),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "handover peer did not respond in time",
+            )),
+        }
+    }
+}
+
+/// Control socket of a running broker.
+pub struct HandoverListener {
+    listener: UnixListener,
+}
+
+impl HandoverListener {
+    /// Binds the control socket. Fails if another broker process is listening on it, a socket
+    /// file left by a process which is gone is replaced.
+    pub fn bind(path: &str) -> io::Result<Self> {
+        match StdUnixStream::connect(path) {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("handover socket {} is used by a running broker", path),
+                ));
+            }
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(path)?;
+            }
+            // there is no socket file, other errors are reported by bind
+            Err(_) => {}
+        }
+        Ok(HandoverListener {
+            listener: UnixListener::bind(path)?,
+        })
+    }
+
+    /// Accepts a handover request from a new broker process. `SO_REUSEPORT` is set on `shared`
+    /// listeners before the request is welcomed, so the new process is able to bind them.
+    pub async fn accept(&self, shared: &[&TcpListener]) -> io::Result<HandoverPeer> {
+        let (stream, _) = self.listener.accept().await?;
+        let mut channel = HandoverChannel::new(stream);
+
+        match channel.recv(HANDSHAKE_TIMEOUT).await? {
+            HandoverMessage::Hello { protocol, version } => {
+                if protocol != HANDOVER_PROTOCOL_VERSION {
+                    let reason = format!(
+                        "unsupported handover protocol {}, expected {}",
+                        protocol, HANDOVER_PROTOCOL_VERSION
+                    );
+                    channel
+                        .send(HandoverMessage::Rejected {
+                            reason: reason.clone(),
+                        })
+                        .await?;
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+                }
+                info!(
+                    "[Handover]: TeleMQ {} requested a handover from TeleMQ {}",
+                    version, BROKER_VERSION
+                );
+                set_reuse_port(shared, true)?;
+                channel
+                    .send(HandoverMessage::Welcome {
+                        version: BROKER_VERSION.into(),
+                    })
+                    .await?;
+                Ok(HandoverPeer { channel })
+            }
+            message => Err(unexpected_message(message)),
+        }
+    }
+}
+
+/// New broker process which is taking over from the current one.
+pub struct HandoverPeer {
+    channel: HandoverChannel,
+}
+
+impl HandoverPeer {
+    /// Waits until the new process has bound its listeners.
+    pub async fn wait_ready(&mut self) -> io::Result<()> {
+        match self.channel.recv(HANDSHAKE_TIMEOUT).await? {
+            HandoverMessage::Ready => Ok(()),
+            message => Err(unexpected_message(message)),
+        }
+    }
+
+    /// Notifies the new process that all connections are closed and sessions are saved.
+    pub async fn drained(&mut self) -> io::Result<()> {
+        self.channel.send(HandoverMessage::Drained).await
+    }
+}
+
+/// Connection to a running broker which is being taken over.
+pub struct HandoverClient {
+    channel: HandoverChannel,
+}
+
+impl HandoverClient {
+    pub async fn connect(path: &str) -> io::Result<Self> {
+        let mut channel = HandoverChannel::new(UnixStream::connect(path).await?);
+        channel
+            .send(HandoverMessage::Hello {
+                protocol: HANDOVER_PROTOCOL_VERSION,
+                version: BROKER_VERSION.into(),
+            })
+            .await?;
+
+        match channel.recv(HANDSHAKE_TIMEOUT).await? {
+            HandoverMessage::Welcome { version } => {
+                info!(
+                    "[Handover]: taking over from TeleMQ {} running on {}",
+                    version, path
+                );
+                Ok(HandoverClient { channel })
+            }
+            HandoverMessage::Rejected { reason } => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("handover rejected. {}", reason),
+            )),
+            message => Err(unexpected_message(message)),
+        }
+    }
+
+    /// Notifies the old process that listeners are bound and waits until it drains
+    /// its connections. Then waits until the old process exits.
+    pub async fn take_over(mut self) -> io::Result<()> {
+        self.channel.send(HandoverMessage::Ready).await?;
+
+        match self.channel.recv(DRAIN_TIMEOUT).await? {
+            HandoverMessage::Drained => {}
+            message => return Err(unexpected_message(message)),
+        }
+
+        // the control connection is closed once the old process exits
+        match self.channel.recv(DRAIN_TIMEOUT).await {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            Err(err) => Err(err),
+            Ok(message) => {
+                warn!("[Handover]: unexpected message after drain {:?}", message);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn unexpected_message(message: HandoverMessage) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected handover message {:?}", message),
+    )
+}
+
+fn codec_error(err: LinesCodecError) -> io::Error {
+    match err {
+        LinesCodecError::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "telemq_handover_{}_{}.sock",
+                name,
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[tokio::test]
+    async fn listeners_are_shared_only_during_take_over() {
+        let path = socket_path("take_over");
+        let handover_listener = HandoverListener::bind(&path).unwrap();
+        let listener = bind_tcp("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(bind_tcp(addr, true).is_err());
+
+        let old = async {
+            let mut peer = handover_listener.accept(&[&listener]).await.unwrap();
+            peer.wait_ready().await.unwrap();
+            peer.drained().await.unwrap();
+            // the old process exits
+        };
+        let new = async {
+            let client = HandoverClient::connect(&path).await.unwrap();
+            let new_listener = bind_tcp(addr, true).unwrap();
+            client.take_over().await.unwrap();
+            new_listener
+        };
+        let ((), _new_listener) = tokio::join!(old, new);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn socket_of_running_broker_is_kept() {
+        let path = socket_path("running");
+        let running = HandoverListener::bind(&path).unwrap();
+        assert_eq!(
+            HandoverListener::bind(&path).err().map(|err| err.kind()),
+            Some(io::ErrorKind::AddrInUse)
+        );
+
+        drop(running);
+        // the socket file is left behind
+        assert!(HandoverListener::bind(&path).is_ok());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
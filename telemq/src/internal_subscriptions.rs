@@ -0,0 +1,197 @@
+//! Subscriptions of in-process clients: bridges (`$bridge/{name}`), cluster peers and
+//! subscribers of an embedding application. Unlike network clients with persistent sessions
+//! they have no stored session, so the subscription tree, which is built from stored sessions
+//! at startup and once sessions are reloaded after a handover, would not route messages to
+//! them. The registry is kept apart from sessions and its subscriptions are added to the tree
+//! along with subscriptions of stored sessions.
+//!
+//! Subscriptions of a client are replaced once it connects again, so filters a bridge or
+//! a peer no longer subscribes to are not kept. They are not removed when a client
+//! disconnects, e.g. during a graceful shut down, which is when the registry is written to
+//! `internal_subscriptions_file`.
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use log::{error, info};
+use mqtt_packets::v_3_1_1::topic::Subscription;
+use serde_json::to_vec;
+
+use crate::{
+    store_check::{read_entries, warn_about_corrupted, StoreEntries},
+    subscription_tree::SubscriptionTree,
+};
+
+type ClientId = String;
+
+#[derive(Debug)]
+pub struct InternalSubscriptions {
+    /// client id => topic filters
+    subscriptions: HashMap<ClientId, BTreeSet<String>>,
+    file_path: Option<String>,
+}
+
+impl InternalSubscriptions {
+    pub fn new(file_path: Option<String>) -> Self {
+        let subscriptions = match file_path {
+            Some(ref file_path) => Self::read_file(file_path),
+            None => HashMap::new(),
+        };
+
+        InternalSubscriptions {
+            subscriptions,
+            file_path,
+        }
+    }
+
+    /// Forgets subscriptions of a client, e.g. once it connects again and subscribes anew.
+    pub fn forget(&mut self, client_id: &str) {
+        self.subscriptions.remove(client_id);
+    }
+
+    pub fn add(&mut self, client_id: &str, subscriptions: &[Subscription]) {
+        self.subscriptions
+            .entry(client_id.to_string())
+            .or_default()
+            .extend(subscriptions.iter().map(|sub| sub.original.clone()));
+    }
+
+    pub fn remove(&mut self, client_id: &str, subscriptions: &[Subscription]) {
+        if let Some(filters) = self.subscriptions.get_mut(client_id) {
+            for sub in subscriptions {
+                filters.remove(&sub.original);
+            }
+            if filters.is_empty() {
+                self.subscriptions.remove(client_id);
+            }
+        }
+    }
+
+    /// Adds all subscriptions to a subscription tree.
+    pub fn restore(&self, tree: &mut SubscriptionTree) {
+        for (client_id, filters) in &self.subscriptions {
+            // filters have been valid once subscribed to, a hand edited file may break them
+            for sub in filters
+                .iter()
+                .filter_map(|f| Subscription::try_from(f).ok())
+            {
+                tree.add_subscriber(&sub.path, client_id.clone());
+            }
+        }
+    }
+
+    /// Writes the registry to its file, does nothing if the registry is not persistent.
+    pub fn commit(&self) -> io::Result<()> {
+        let file_path = match self.file_path {
+            Some(ref file_path) => file_path,
+            None => return Ok(()),
+        };
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_path)?;
+        file.write_all(&to_vec(&self.subscriptions).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unable to serialize internal subscriptions",
+            )
+        })?)?;
+        file.sync_all()
+    }
+
+    fn read_file(file_path: &str) -> HashMap<ClientId, BTreeSet<String>> {
+        match File::open(Path::new(file_path)) {
+            Ok(reader) => match read_entries(reader) {
+                Ok(StoreEntries {
+                    entries: subscriptions,
+                    corrupted,
+                }) => {
+                    warn_about_corrupted("Internal Subscriptions", file_path, &corrupted);
+                    info!(
+                        "[Internal Subscriptions]: recovered from a local file {}",
+                        file_path
+                    );
+                    subscriptions
+                }
+                Err(err) => {
+                    error!(
+                        "[Internal Subscriptions]: unable to parse data from file {}. {:?}. Continue without internal subscriptions.",
+                        file_path, err
+                    );
+                    HashMap::new()
+                }
+            },
+            Err(_) => {
+                info!(
+                    "[Internal Subscriptions]: unable to find data file {}. Continue without internal subscriptions.",
+                    file_path
+                );
+                HashMap::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_packets::v_3_1_1::topic::Topic;
+    use std::fs::remove_file;
+
+    fn subscriptions(filters: &[&str]) -> Vec<Subscription> {
+        filters
+            .iter()
+            .map(|filter| Subscription::try_from(filter).unwrap())
+            .collect()
+    }
+
+    fn subscribers(tree: &SubscriptionTree, topic: &str) -> Vec<ClientId> {
+        let mut subscribers: Vec<ClientId> = tree
+            .find_subscribers(&Topic::try_from(topic).unwrap().path)
+            .into_iter()
+            .collect();
+        subscribers.sort();
+        subscribers
+    }
+
+    #[test]
+    fn subscriptions_are_replaced_once_client_connects_again() {
+        let mut registry = InternalSubscriptions::new(None);
+        registry.add("$bridge/cloud", &subscriptions(&["a/#", "b/#"]));
+        registry.add("$cluster/peer", &subscriptions(&["a/1"]));
+        registry.remove("$cluster/peer", &subscriptions(&["a/1"]));
+
+        registry.forget("$bridge/cloud");
+        registry.add("$bridge/cloud", &subscriptions(&["b/#"]));
+
+        let mut tree = SubscriptionTree::default();
+        registry.restore(&mut tree);
+        assert!(subscribers(&tree, "a/1").is_empty());
+        assert_eq!(subscribers(&tree, "b/1"), vec!["$bridge/cloud"]);
+    }
+
+    #[test]
+    fn subscriptions_survive_restart() {
+        let path = std::env::temp_dir()
+            .join(format!(
+                "telemq_internal_subscriptions_{}.json",
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .into_owned();
+        let mut registry = InternalSubscriptions::new(Some(path.clone()));
+        registry.add("$bridge/cloud", &subscriptions(&["a/#"]));
+        registry.commit().unwrap();
+
+        let recovered = InternalSubscriptions::new(Some(path.clone()));
+        remove_file(&path).unwrap();
+        let mut tree = SubscriptionTree::default();
+        recovered.restore(&mut tree);
+        assert_eq!(subscribers(&tree, "a/1"), vec!["$bridge/cloud"]);
+    }
+}
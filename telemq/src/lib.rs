@@ -0,0 +1,134 @@
+//! TeleMQ - an experimental MQTT broker.
+//!
+//! Besides being run as a standalone `telemq` binary, the broker can be embedded into
+//! a Rust application:
+//!
+//! ```no_run
+//! use telemq::{QoS, ServerBuilder, TeleMQServerConfig};
+//!
+//! # async fn run() -> telemq::ServerResult<()> {
+//! let server = ServerBuilder::new(TeleMQServerConfig::default())
+//!     .with_os_signals(false)
+//!     .build()
+//!     .await?;
+//! let handle = server.handle();
+//! let mut subscriber = handle.subscribe("embedded".into(), &["devices/+/status"])?;
+//! tokio::spawn(server.start());
+//!
+//! handle.publish("devices/1/status", b"online".to_vec(), QoS::Zero, false)?;
+//! let message = subscriber.recv().await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The library doesn't initialize any logger, an embedding application can use any
+//! `log` compatible implementation (or `logger::init_logger` to get the same logging
+//! as the `telemq` binary).
+extern crate base64;
+extern crate bytes;
+extern crate crypto;
+extern crate futures;
+extern crate ipnet;
+extern crate libc;
+extern crate log;
+extern crate log4rs;
+#[cfg(test)]
+extern crate maplit;
+extern crate mqtt_packets;
+extern crate percent_encoding;
+extern crate regex;
+extern crate reqwest;
+extern crate ring;
+extern crate serde;
+extern crate serde_json;
+extern crate signal_hook;
+extern crate signal_hook_tokio;
+extern crate tokio;
+extern crate tokio_rustls;
+extern crate tokio_stream;
+extern crate tokio_util;
+extern crate toml;
+extern crate warp;
+
+mod accept_backoff;
+mod admin_api;
+mod authenticator;
+mod bandwidth_limiter;
+mod bootstrap;
+mod bridge;
+mod broker_features;
+mod broker_handle;
+mod broker_state;
+mod cert_reload;
+mod client_history;
+mod client_id_generator;
+mod clock;
+mod cluster;
+pub mod config;
+mod config_schema;
+mod connection;
+mod connection_channel;
+mod connection_config;
+mod connection_gate;
+mod connection_info;
+mod connection_limit;
+mod connection_provider;
+mod connection_watchdog;
+mod control;
+mod disconnect_reason;
+mod fd_limit;
+mod handover;
+mod internal_subscriptions;
+mod load_shedding;
+pub mod logger;
+mod mqtt_codec;
+mod mqttsn_listener;
+mod net_connection;
+mod payload_format;
+mod priority;
+mod publish_metadata;
+mod publish_ordering;
+mod queue_depth;
+mod quiet_hours;
+mod reserved_topics;
+mod retained_bypass;
+mod retained_store;
+mod routing_cache;
+mod server;
+mod server_error;
+mod session_error;
+mod session_persistence;
+mod session_state;
+mod session_state_store;
+mod startup_wait;
+mod stats;
+mod store_check;
+mod subscription_limits;
+mod subscription_tree;
+mod time_sync;
+mod tls_fingerprint;
+mod tls_listener;
+mod topic_normalization;
+mod topic_prefix;
+mod transaction;
+mod transport_bytes;
+mod webhook;
+mod webhook_outbox;
+mod will_delay;
+mod ws_listener;
+mod wss_listener;
+
+pub use broker_handle::{BrokerHandle, InProcessMessage, InProcessSubscriber};
+pub use client_id_generator::{ClientIdGenerator, ClientIdScheme};
+pub use clock::Clock;
+pub use config::TeleMQServerConfig;
+pub use config_schema::config_schema_json;
+pub use connection_gate::{
+    ConnectionGate, ConnectionMetadata, ConnectionTransport, GateDecision, TlsMetadata,
+};
+pub use mqtt_packets::v_3_1_1::QoS;
+pub use publish_metadata::PublishMetadata;
+pub use server::{Server, ServerBuilder};
+pub use server_error::{ServerError, ServerResult};
+pub use store_check::{fsck_state_store, FsckReport};
+pub use tls_fingerprint::Ja3Fingerprint;
@@ -0,0 +1,231 @@
+//! Self-protection of the broker under pressure.
+//!
+//! With `load_shedding_rss_watermark` or `load_shedding_lag_watermark` set, the broker
+//! samples its resident memory and the lag of its event loop every second. Once either of
+//! them crosses its watermark, the broker is overloaded: new clients are rejected with
+//! CONNACK "Server unavailable" and QoS 0 messages published to `load_shedding_topics` are
+//! not sent to subscribers. The broker recovers once both of them drop below
+//! `RECOVERY_RATIO` of their watermarks, so it doesn't flap around a watermark.
+//!
+//! `$SYS/broker/overloaded` keeps a retained `1` while the broker is overloaded and `0`
+//! otherwise.
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::error;
+use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic, ControlPacket};
+use tokio::time::{sleep, Instant};
+
+use crate::{
+    config::TeleMQServerConfig,
+    control::{ControlMessage, ControlSender},
+};
+
+pub const OVERLOADED_TOPIC: &str = "$SYS/broker/overloaded";
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const RECOVERY_RATIO: f64 = 0.8;
+
+/// Whether the broker is overloaded, shared by connections.
+#[derive(Debug, Clone, Default)]
+pub struct Overload(Arc<AtomicBool>);
+
+impl Overload {
+    pub fn is_overloaded(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, overloaded: bool) {
+        self.0.store(overloaded, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pressure {
+    /// `None` if resident memory is unknown on this platform.
+    pub rss_bytes: Option<u64>,
+    /// How late the sampling task has been woken up.
+    pub lag: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    pub rss_bytes: Option<u64>,
+    pub lag: Option<Duration>,
+}
+
+impl Watermarks {
+    /// `None` if load shedding is off.
+    pub fn new(config: &TeleMQServerConfig) -> Option<Self> {
+        if config.load_shedding_rss_watermark.is_none()
+            && config.load_shedding_lag_watermark.is_none()
+        {
+            return None;
+        }
+
+        Some(Watermarks {
+            rss_bytes: config.load_shedding_rss_watermark,
+            lag: config.load_shedding_lag_watermark,
+        })
+    }
+
+    /// Whether the broker is overloaded under `pressure`, given it's been `overloaded` so far.
+    pub fn is_overloaded(&self, overloaded: bool, pressure: &Pressure) -> bool {
+        let ratio = if overloaded { RECOVERY_RATIO } else { 1.0 };
+        let rss_over = match (self.rss_bytes, pressure.rss_bytes) {
+            (Some(watermark), Some(rss_bytes)) => rss_bytes as f64 >= watermark as f64 * ratio,
+            _ => false,
+        };
+        let lag_over = self
+            .lag
+            .is_some_and(|watermark| pressure.lag >= watermark.mul_f64(ratio));
+
+        rss_over || lag_over
+    }
+}
+
+pub struct LoadShedder {
+    watermarks: Watermarks,
+    overload: Overload,
+    control_sender: ControlSender,
+}
+
+impl LoadShedder {
+    pub fn new(watermarks: Watermarks, overload: Overload, control_sender: ControlSender) -> Self {
+        LoadShedder {
+            watermarks,
+            overload,
+            control_sender,
+        }
+    }
+
+    pub async fn run(self) {
+        // a flag retained before a restart is reset
+        self.report(false);
+        loop {
+            let started_at = Instant::now();
+            sleep(SAMPLE_INTERVAL).await;
+            let pressure = Pressure {
+                rss_bytes: resident_memory(),
+                lag: started_at.elapsed().saturating_sub(SAMPLE_INTERVAL),
+            };
+
+            let was_overloaded = self.overload.is_overloaded();
+            let overloaded = self.watermarks.is_overloaded(was_overloaded, &pressure);
+            if overloaded != was_overloaded {
+                self.overload.set(overloaded);
+                if !self.report(overloaded) {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Returns `false` once Control Worker has stopped.
+    fn report(&self, overloaded: bool) -> bool {
+        let message = ControlMessage::Overload { overloaded };
+        if self.control_sender.send(message).is_err() {
+            error!("[Load Shedder]: unable to report overload {}", overloaded);
+            return false;
+        }
+
+        true
+    }
+}
+
+/// A retained message with an overload flag.
+pub fn overload_packet(overloaded: bool) -> ControlPacket {
+    let payload = if overloaded { b"1" } else { b"0" };
+    let mut builder = PublishPacketBuilder::new();
+    builder
+        .with_topic(Topic::make_from_string(OVERLOADED_TOPIC))
+        .with_payload(payload.to_vec())
+        .with_retained(true);
+    builder.build()
+}
+
+/// Resident memory of the broker process.
+#[cfg(target_os = "linux")]
+fn resident_memory() -> Option<u64> {
+    // the second field of statm is a number of resident pages
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    u64::try_from(page_size)
+        .ok()
+        .map(|page_size| pages * page_size)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn pressure(rss_mb: u64, lag_ms: u64) -> Pressure {
+        Pressure {
+            rss_bytes: Some(rss_mb * MB),
+            lag: Duration::from_millis(lag_ms),
+        }
+    }
+
+    #[test]
+    fn overload_is_entered_at_watermark_and_left_below_recovery_ratio() {
+        let watermarks = Watermarks {
+            rss_bytes: Some(100 * MB),
+            lag: Some(Duration::from_millis(100)),
+        };
+
+        assert!(!watermarks.is_overloaded(false, &pressure(99, 99)));
+        assert!(watermarks.is_overloaded(false, &pressure(100, 0)));
+        assert!(watermarks.is_overloaded(false, &pressure(0, 100)));
+
+        assert!(watermarks.is_overloaded(true, &pressure(90, 0)));
+        assert!(watermarks.is_overloaded(true, &pressure(0, 90)));
+        assert!(!watermarks.is_overloaded(true, &pressure(79, 79)));
+    }
+
+    #[test]
+    fn missing_watermarks_are_ignored() {
+        let watermarks = Watermarks {
+            rss_bytes: None,
+            lag: Some(Duration::from_millis(100)),
+        };
+        assert!(!watermarks.is_overloaded(false, &pressure(u32::MAX as u64, 0)));
+
+        let watermarks = Watermarks {
+            rss_bytes: Some(100 * MB),
+            lag: None,
+        };
+        let unknown_rss = Pressure {
+            rss_bytes: None,
+            lag: Duration::from_secs(60),
+        };
+        assert!(!watermarks.is_overloaded(false, &unknown_rss));
+    }
+
+    #[test]
+    fn shedding_is_off_without_watermarks() {
+        let mut config = TeleMQServerConfig::default();
+        assert_eq!(Watermarks::new(&config), None);
+
+        config.load_shedding_lag_watermark = Some(Duration::from_millis(500));
+        assert_eq!(
+            Watermarks::new(&config),
+            Some(Watermarks {
+                rss_bytes: None,
+                lag: Some(Duration::from_millis(500)),
+            })
+        );
+    }
+}
@@ -1,25 +1,161 @@
-use crate::config::{TeleMQServerConfig, TeleMQServerConfigSrc};
-use log::LevelFilter;
+use crate::{
+    config::{LogFormat, TeleMQServerConfig, TeleMQServerConfigSrc},
+    connection_info::ConnectionInfo,
+};
+use chrono::{SecondsFormat, Utc};
+use log::{
+    kv::{self, Key, Value as KvValue, VisitSource},
+    LevelFilter, Record,
+};
 use log4rs::{
     append::{
         console::{ConsoleAppender, Target as ConsoleAppenderTarget},
         file::FileAppender,
     },
     config::{Appender, Config, Logger, Root},
+    encode::{pattern::PatternEncoder, Encode, Write},
     init_config,
 };
+use serde_json::{Map, Value};
+use std::{cell::RefCell, future::Future, sync::Arc};
 
-pub fn init_logger(server_config: &TeleMQServerConfig) {
-    let config_builder = Config::builder();
-    let level_filter = match server_config.log_level.as_str() {
+/// Records of connections, e.g. a packet received from or sent to a client.
+pub const TARGET_CONNECTION: &str = "telemq::connection";
+/// Records of Control Worker, e.g. routing of publishes and subscriptions.
+pub const TARGET_CONTROL: &str = "telemq::control";
+/// Records of authentication and authorization, e.g. a refused login or a denied publish.
+pub const TARGET_AUTH: &str = "telemq::auth";
+/// Records of parsing and serializing of packets.
+pub const TARGET_CODEC: &str = mqtt_packets::LOG_TARGET;
+
+/// Log targets which are kept stable across releases, levels of them can be overridden
+/// with `log_targets`. A module path of the broker can be overridden as well, though it may
+/// change once the module is moved.
+pub const LOG_TARGETS: [&str; 4] = [TARGET_CONNECTION, TARGET_CONTROL, TARGET_AUTH, TARGET_CODEC];
+
+tokio::task_local! {
+    /// Connection a task is serving, so records it logs can be attributed to a client.
+    static CONNECTION: RefCell<Arc<ConnectionInfo>>;
+}
+
+/// Runs a connection task, records logged within it carry its client id and address.
+pub async fn with_connection<F: Future>(info: Arc<ConnectionInfo>, task: F) -> F::Output {
+    CONNECTION.scope(RefCell::new(info), task).await
+}
+
+/// Replaces a connection of the current task, e.g. once a client is connected.
+pub fn update_connection(info: Arc<ConnectionInfo>) {
+    // records logged outside of connection tasks have no connection
+    let _ = CONNECTION.try_with(|connection| *connection.borrow_mut() = info);
+}
+
+/// Connection of the current task, `None` outside of connection tasks.
+pub fn current_connection() -> Option<Arc<ConnectionInfo>> {
+    CONNECTION
+        .try_with(|connection| connection.borrow().clone())
+        .ok()
+}
+
+/// Writes a record as a JSON line, e.g.
+/// `{"addr":"10.0.0.5:53211","client_id":"device-1","event":"client_connected","level":"info",
+/// "message":"...","module":"telemq::connection","target":"telemq::connection",
+/// "timestamp":"2024-05-01T10:00:00.000Z"}`.
+/// Key-values of a record (e.g. `info!(event = "client_connected"; "...")`) are added as
+/// fields, `client_id` and `addr` are taken from a connection of the current task unless
+/// a record has them.
+#[derive(Debug)]
+pub struct JsonEncoder;
+
+impl JsonEncoder {
+    fn to_json(record: &Record) -> Map<String, Value> {
+        let mut fields = Map::new();
+        fields.insert(
+            "timestamp".into(),
+            Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+                .into(),
+        );
+        fields.insert(
+            "level".into(),
+            record.level().as_str().to_lowercase().into(),
+        );
+        fields.insert(
+            "module".into(),
+            record.module_path().unwrap_or(record.target()).into(),
+        );
+        fields.insert("target".into(), record.target().into());
+        if let Some(connection) = current_connection() {
+            if !connection.client_id.is_empty() {
+                fields.insert("client_id".into(), connection.client_id.clone().into());
+            }
+            fields.insert("addr".into(), connection.addr.to_string().into());
+        }
+        // a record can't fail to be written because of its key-values
+        let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+        fields.insert("message".into(), record.args().to_string().into());
+
+        fields
+    }
+}
+
+impl Encode for JsonEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &Record) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut *w, &Self::to_json(record))?;
+        w.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: KvValue<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(number) = value.to_u64() {
+            number.into()
+        } else if let Some(flag) = value.to_bool() {
+            flag.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+fn encoder(log_format: LogFormat) -> Box<dyn Encode> {
+    match log_format {
+        LogFormat::Text => Box::new(PatternEncoder::default()),
+        LogFormat::Json => Box::new(JsonEncoder),
+    }
+}
+
+/// A level of a `log_level` or of a `log_targets` value, which are validated by the config.
+fn level_filter(level: &str) -> LevelFilter {
+    match level {
         "error" => LevelFilter::Error,
         "warn" => LevelFilter::Warn,
         "info" => LevelFilter::Info,
         "debug" => LevelFilter::Debug,
+        "trace" => LevelFilter::Trace,
         level => {
             panic!("Unsupported logging level {}", level);
         }
-    };
+    }
+}
+
+/// Loggers of targets with overridden levels. They have no appenders of their own, records
+/// are written by appenders of the root.
+fn target_loggers(server_config: &TeleMQServerConfig) -> Vec<Logger> {
+    server_config
+        .log_targets
+        .iter()
+        .map(|(target, level)| Logger::builder().build(target, level_filter(level)))
+        .collect()
+}
+
+pub fn init_logger(server_config: &TeleMQServerConfig) {
+    let config_builder = Config::builder().loggers(target_loggers(server_config));
+    let level_filter = level_filter(&server_config.log_level);
 
     let config = if server_config.log_dest == TeleMQServerConfigSrc::LOG_DEST_STDOUT {
         config_builder
@@ -29,6 +165,7 @@ pub fn init_logger(server_config: &TeleMQServerConfig) {
                     Box::new(
                         ConsoleAppender::builder()
                             .target(ConsoleAppenderTarget::Stdout)
+                            .encoder(encoder(server_config.log_format))
                             .build(),
                     ),
                 ),
@@ -44,6 +181,7 @@ pub fn init_logger(server_config: &TeleMQServerConfig) {
                     Box::new(
                         ConsoleAppender::builder()
                             .target(ConsoleAppenderTarget::Stderr)
+                            .encoder(encoder(server_config.log_format))
                             .build(),
                     ),
                 ),
@@ -58,6 +196,7 @@ pub fn init_logger(server_config: &TeleMQServerConfig) {
                     "file",
                     Box::new(
                         FileAppender::builder()
+                            .encoder(encoder(server_config.log_format))
                             .build(server_config.log_dest.trim_start_matches("file:"))
                             .expect("Unable to build a logger according to a provided config"),
                     ),
@@ -72,3 +211,101 @@ pub fn init_logger(server_config: &TeleMQServerConfig) {
 
     init_config(config).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_gate::ConnectionTransport;
+    use log::Level;
+    use std::{net::SocketAddr, time::SystemTime};
+
+    fn to_json(record: &Record) -> Value {
+        let mut line = vec![];
+        JsonEncoder
+            .encode(
+                &mut log4rs::encode::writer::simple::SimpleWriter(&mut line),
+                record,
+            )
+            .unwrap();
+        assert_eq!(line.last(), Some(&b'\n'));
+        serde_json::from_slice(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn records_of_connections_carry_client_id() {
+        let addr = SocketAddr::from(([10, 0, 0, 5], 53211));
+        let accepted = Arc::new(ConnectionInfo::accepted(
+            addr,
+            ConnectionTransport::Tcp,
+            None,
+            SystemTime::now(),
+        ));
+        let connected = Arc::new(accepted.connected("device-1".into(), None, SystemTime::now()));
+        let key_values = [("event", "client_connected")];
+        let args = format_args!("connected");
+        let record = Record::builder()
+            .level(Level::Info)
+            .module_path(Some("telemq::connection"))
+            .key_values(&key_values)
+            .args(args)
+            .build();
+
+        let (before, after) = with_connection(accepted, async {
+            let before = to_json(&record);
+            update_connection(connected);
+            (before, to_json(&record))
+        })
+        .await;
+
+        assert_eq!(before["addr"], "10.0.0.5:53211");
+        assert!(before.get("client_id").is_none());
+        assert_eq!(after["client_id"], "device-1");
+        assert_eq!(after["level"], "info");
+        assert_eq!(after["module"], "telemq::connection");
+        assert_eq!(after["event"], "client_connected");
+        assert_eq!(after["message"], "connected");
+        assert!(after["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn records_outside_of_connections_have_no_client() {
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("telemq")
+            .args(format_args!("listening"))
+            .build();
+
+        let fields = to_json(&record);
+        assert_eq!(fields["level"], "warn");
+        assert_eq!(fields["module"], "telemq");
+        assert_eq!(fields["target"], "telemq");
+        assert!(fields.get("addr").is_none());
+        assert!(fields.get("event").is_none());
+    }
+
+    #[test]
+    fn auth_records_are_filtered_apart() {
+        let mut config = TeleMQServerConfig::default();
+        config
+            .log_targets
+            .insert(TARGET_AUTH.to_string(), "debug".to_string());
+
+        let loggers = target_loggers(&config);
+        assert_eq!(loggers.len(), 1);
+        assert_eq!(loggers[0].name(), "telemq::auth");
+        assert_eq!(loggers[0].level(), LevelFilter::Debug);
+
+        let record = Record::builder()
+            .level(Level::Info)
+            .module_path(Some("telemq::authenticator::authenticator_file"))
+            .target(TARGET_AUTH)
+            .args(format_args!("IP blacklisted"))
+            .build();
+        let fields = to_json(&record);
+        assert_eq!(
+            fields["module"],
+            "telemq::authenticator::authenticator_file"
+        );
+        assert_eq!(fields["target"], "telemq::auth");
+    }
+}
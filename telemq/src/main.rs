@@ -1,68 +1,43 @@
-extern crate bytes;
 extern crate clap;
-extern crate crypto;
-extern crate futures;
-extern crate ipnet;
-extern crate log;
-extern crate log4rs;
-#[cfg(test)]
-extern crate maplit;
-extern crate mqtt_packets;
-extern crate regex;
-extern crate reqwest;
-extern crate serde;
-extern crate serde_json;
-extern crate signal_hook;
-extern crate signal_hook_tokio;
+extern crate telemq;
 extern crate tokio;
-extern crate tokio_rustls;
-extern crate tokio_stream;
-extern crate tokio_util;
-extern crate toml;
-extern crate warp;
 
-mod admin_api;
 mod args;
-mod authenticator;
-mod config;
-mod connection;
-mod connection_provider;
-mod control;
-mod logger;
-mod net_connection;
-mod server;
-mod server_error;
-mod session_error;
-mod session_state;
-mod session_state_store;
-mod stats;
-mod subscription_tree;
-mod tls_listener;
-mod transaction;
-mod ws_listener;
-mod wss_listener;
 
 use args::parse_args;
-use config::TeleMQServerConfig;
-use logger::init_logger;
-use server::Server;
 use std::{
     error::Error,
     io::{stderr, Write},
     process::exit,
 };
+use telemq::{
+    config_schema_json, fsck_state_store, logger::init_logger, ServerBuilder, TeleMQServerConfig,
+};
 
 #[tokio::main(worker_threads = 25)]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = parse_args();
+    if args.is_present("PRINT_CONFIG_SCHEMA") {
+        println!("{}", config_schema_json()?);
+        exit(0);
+    }
+
+    let strict_config = args.is_present("STRICT_CONFIG");
     let mut config = match args.value_of("CONFIG_FILE") {
-        Some(config_file) => match TeleMQServerConfig::from_file(config_file) {
-            Ok(c) => c,
-            Err(err) => {
-                stderr().write(format!("{:?}\n", err).as_bytes()).unwrap();
-                exit(1);
+        Some(config_file) => {
+            let loaded = if strict_config {
+                TeleMQServerConfig::from_file_strict(config_file)
+            } else {
+                TeleMQServerConfig::from_file(config_file)
+            };
+            match loaded {
+                Ok(c) => c,
+                Err(err) => {
+                    stderr().write(format!("{:?}\n", err).as_bytes()).unwrap();
+                    exit(1);
+                }
             }
-        },
+        }
         None => TeleMQServerConfig::default(),
     };
 
@@ -78,11 +53,28 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    if args.is_present("FSCK_STATE_STORE") {
+        let report = fsck_state_store(&config, args.is_present("QUARANTINE"));
+        print!("{}", report);
+        exit(if report.is_clean() { 0 } else { 1 });
+    }
+
     init_logger(&config);
 
-    if let Some(server) = Server::new(config).await {
-        server.start().await?;
+    let mut builder = ServerBuilder::new(config)
+        .with_take_over(args.is_present("TAKE_OVER"))
+        .with_strict_config(strict_config);
+    if let Some(config_file) = args.value_of("CONFIG_FILE") {
+        builder = builder.with_config_file(config_file);
+    }
+    let server = match builder.build().await {
+        Ok(server) => server,
+        Err(err) => {
+            stderr().write_all(format!("{}\n", err).as_bytes()).unwrap();
+            exit(1);
+        }
     };
+    server.start().await?;
 
-    Ok(())
+    exit(0);
 }
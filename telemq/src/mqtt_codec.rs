@@ -0,0 +1,465 @@
+//! Network codec which speaks MQTT 3.1.1 or MQTT 5.0, depending on a protocol level
+//! a client has requested in its CONNECT packet.
+//!
+//! The broker is built around MQTT 3.1.1 packets, so MQTT 5.0 packets are converted
+//! to/from MQTT 3.1.1 ones right here. Properties of incoming packets are passed along
+//! with a converted packet, protocol state which exists only in MQTT 5.0 (topic aliases,
+//! UNSUBACK reason codes, server capabilities in CONNACK) is handled by the codec.
+//! A packet which can't be converted is reported as malformed when it's received and as
+//! an encoding error when it's sent.
+use std::{collections::HashMap, io};
+
+use bytes::BytesMut;
+use mqtt_packets::{
+    v_3_1_1::{ControlPacket, ControlPacketCodec},
+    v_5_0::{
+        utils::peek_variable_int, Connect, Packet, PacketCodec, Properties, Property, ReasonCode,
+        ReasonPacket, TopicAliases,
+    },
+    PacketCodecError,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Number of topic aliases an MQTT 5.0 client may establish per connection.
+const TOPIC_ALIAS_MAXIMUM: u16 = 64;
+const CONNECT_PACKET_TYPE: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V3_1_1,
+    V5_0,
+}
+
+/// A packet received from a client.
+#[derive(Debug)]
+pub enum InboundPacket {
+    /// MQTT 3.1.1 packet or MQTT 5.0 packet converted to an MQTT 3.1.1 one. Properties are
+    /// always empty for MQTT 3.1.1 clients.
+    Packet {
+        packet: ControlPacket,
+        properties: Properties,
+    },
+    /// MQTT 5.0 AUTH packet.
+    Auth(ReasonPacket),
+    /// A packet violates the protocol, a client should be disconnected with a given reason.
+    ProtocolError(ReasonCode),
+}
+
+pub struct MqttCodec {
+    protocol: Option<ProtocolVersion>,
+    v_3_1_1: ControlPacketCodec,
+    v_5_0: PacketCodec,
+    topic_aliases: TopicAliases,
+    /// Number of topic filters of pending UNSUBSCRIBE requests by packet ids.
+    unsubscribe_requests: HashMap<u16, usize>,
+    /// Client id assigned by the broker, announced to a 5.0 client by CONNACK.
+    assigned_client_id: Option<String>,
+}
+
+impl Default for MqttCodec {
+    fn default() -> Self {
+        MqttCodec::new()
+    }
+}
+
+impl MqttCodec {
+    pub fn new() -> Self {
+        MqttCodec {
+            protocol: None,
+            v_3_1_1: ControlPacketCodec::new(),
+            v_5_0: PacketCodec::new(),
+            topic_aliases: TopicAliases::new(TOPIC_ALIAS_MAXIMUM),
+            unsubscribe_requests: HashMap::new(),
+            assigned_client_id: None,
+        }
+    }
+
+    /// Protocol version negotiated with a client. `None` until CONNECT is received.
+    pub fn protocol(&self) -> Option<ProtocolVersion> {
+        self.protocol
+    }
+
+    /// Announces a client id assigned by the broker in the next CONNACK.
+    pub fn assign_client_id(&mut self, client_id: String) {
+        self.assigned_client_id = Some(client_id);
+    }
+
+    fn inbound_from_v_5_0(&mut self, packet: Packet) -> io::Result<InboundPacket> {
+        let properties = match packet {
+            Packet::Auth(auth) => return Ok(InboundPacket::Auth(auth)),
+            Packet::Publish(mut publish) => {
+                if let Err(reason_code) = self.topic_aliases.resolve(&mut publish) {
+                    return Ok(InboundPacket::ProtocolError(reason_code));
+                }
+                let properties = publish.properties.clone();
+                return Ok(InboundPacket::Packet {
+                    packet: Packet::Publish(publish).into_v_3_1_1()?,
+                    properties,
+                });
+            }
+            Packet::Connect(ref connect) => connect.properties.clone(),
+            Packet::Unsubscribe(ref unsubscribe) => {
+                self.unsubscribe_requests
+                    .insert(unsubscribe.packet_id, unsubscribe.topic_filters.len());
+                Properties::new()
+            }
+            _ => Properties::new(),
+        };
+
+        Ok(InboundPacket::Packet {
+            packet: packet.into_v_3_1_1()?,
+            properties,
+        })
+    }
+
+    fn outbound_to_v_5_0(&mut self, packet: &ControlPacket) -> io::Result<Packet> {
+        let mut packet = Packet::from_v_3_1_1(packet)?;
+
+        match packet {
+            Packet::Connack(ref mut connack) => {
+                connack
+                    .properties
+                    .push(Property::TopicAliasMaximum(self.topic_aliases.maximum()));
+                // neither is implemented by the broker
+                connack
+                    .properties
+                    .push(Property::SubscriptionIdentifierAvailable(0));
+                connack
+                    .properties
+                    .push(Property::SharedSubscriptionAvailable(0));
+                if let Some(client_id) = self.assigned_client_id.take() {
+                    connack
+                        .properties
+                        .push(Property::AssignedClientIdentifier(client_id));
+                }
+            }
+            Packet::Unsuback(ref mut unsuback) => {
+                let topic_filters = self
+                    .unsubscribe_requests
+                    .remove(&unsuback.packet_id)
+                    .unwrap_or(1);
+                unsuback.reason_codes = vec![ReasonCode::Success; topic_filters];
+            }
+            _ => {}
+        }
+
+        Ok(packet)
+    }
+}
+
+impl Decoder for MqttCodec {
+    type Item = InboundPacket;
+    type Error = PacketCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<InboundPacket>, PacketCodecError> {
+        let protocol = match self.protocol {
+            Some(protocol) => protocol,
+            None => match detect_protocol(src).map_err(PacketCodecError::malformed)? {
+                Some(protocol) => {
+                    self.protocol = Some(protocol);
+                    protocol
+                }
+                None => return Ok(None),
+            },
+        };
+
+        match protocol {
+            ProtocolVersion::V3_1_1 => {
+                Ok(self
+                    .v_3_1_1
+                    .decode(src)?
+                    .map(|packet| InboundPacket::Packet {
+                        packet,
+                        properties: Properties::new(),
+                    }))
+            }
+            ProtocolVersion::V5_0 => match self.v_5_0.decode(src)? {
+                Some(packet) => self
+                    .inbound_from_v_5_0(packet)
+                    .map(Some)
+                    .map_err(PacketCodecError::malformed),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+impl Encoder<&ControlPacket> for MqttCodec {
+    type Error = PacketCodecError;
+
+    fn encode(&mut self, item: &ControlPacket, dst: &mut BytesMut) -> Result<(), PacketCodecError> {
+        match self.protocol {
+            Some(ProtocolVersion::V5_0) => {
+                let packet = self
+                    .outbound_to_v_5_0(item)
+                    .map_err(PacketCodecError::encoding)?;
+                self.v_5_0.encode(&packet, dst)
+            }
+            _ => self.v_3_1_1.encode(item, dst),
+        }
+    }
+}
+
+impl Encoder<&Packet> for MqttCodec {
+    type Error = PacketCodecError;
+
+    fn encode(&mut self, item: &Packet, dst: &mut BytesMut) -> Result<(), PacketCodecError> {
+        self.v_5_0.encode(item, dst)
+    }
+}
+
+/// It reads a protocol level of a CONNECT packet at the beginning of `src`. Returns `None`
+/// if there are not enough bytes yet. Anything but CONNECT is handed over to
+/// the MQTT 3.1.1 codec, so the packet is rejected the usual way.
+fn detect_protocol(src: &[u8]) -> io::Result<Option<ProtocolVersion>> {
+    let first_byte = match src.first() {
+        Some(first_byte) => first_byte,
+        None => return Ok(None),
+    };
+    if first_byte >> 4 != CONNECT_PACKET_TYPE {
+        return Ok(Some(ProtocolVersion::V3_1_1));
+    }
+
+    let protocol_name_offset = match peek_variable_int(&src[1..])? {
+        Some((_, remaining_length_len)) => 1 + remaining_length_len,
+        None => return Ok(None),
+    };
+    let protocol_name_len = match src.get(protocol_name_offset..protocol_name_offset + 2) {
+        Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+        None => return Ok(None),
+    };
+
+    // protocol level follows a protocol name
+    match src.get(protocol_name_offset + 2 + protocol_name_len) {
+        Some(&Connect::PROTOCOL_LEVEL) => Ok(Some(ProtocolVersion::V5_0)),
+        Some(_) => Ok(Some(ProtocolVersion::V3_1_1)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_packets::v_3_1_1::{
+        builders::{ConnackBuilder, UnsubackPacketBuilder},
+        variable::Variable,
+        CPType,
+    };
+    use mqtt_packets::v_3_1_1::{topic::Subscription, PacketId, QoS};
+    use mqtt_packets::v_5_0::{Publish, Unsubscribe};
+
+    const V3_CONNECT: [u8; 15] = [
+        0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 4, 0b10, 0, 10, 0, 1, b'a',
+    ];
+    const V5_CONNECT: [u8; 16] = [
+        0x10, 14, 0, 4, b'M', b'Q', b'T', b'T', 5, 0b10, 0, 10, 0, 0, 1, b'a',
+    ];
+
+    fn encode_v_5_0(packet: Packet) -> BytesMut {
+        let mut buf = BytesMut::new();
+        PacketCodec::new().encode(&packet, &mut buf).unwrap();
+        buf
+    }
+
+    fn decode_v_5_0(buf: &mut BytesMut) -> Packet {
+        PacketCodec::new().decode(buf).unwrap().unwrap()
+    }
+
+    #[test]
+    fn protocol_is_detected_from_connect() {
+        let mut codec = MqttCodec::new();
+        let mut buf = BytesMut::from(&V3_CONNECT[..5]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(codec.protocol(), None);
+        buf.extend_from_slice(&V3_CONNECT[5..]);
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(InboundPacket::Packet { .. })
+        ));
+        assert_eq!(codec.protocol(), Some(ProtocolVersion::V3_1_1));
+
+        let mut codec = MqttCodec::new();
+        let mut buf = BytesMut::from(&V5_CONNECT[..]);
+        match codec.decode(&mut buf).unwrap() {
+            Some(InboundPacket::Packet { packet, .. }) => {
+                assert_eq!(packet.fixed_header.cp_type, CPType::Connect)
+            }
+            packet => panic!("Connect is expected, got {:?}", packet),
+        }
+        assert_eq!(codec.protocol(), Some(ProtocolVersion::V5_0));
+    }
+
+    #[test]
+    fn v_5_0_connack_announces_capabilities() {
+        let mut codec = MqttCodec::new();
+        codec.decode(&mut BytesMut::from(&V5_CONNECT[..])).unwrap();
+
+        let mut buf = BytesMut::new();
+        codec
+            .encode(&ConnackBuilder::new().build(), &mut buf)
+            .unwrap();
+        match decode_v_5_0(&mut buf) {
+            Packet::Connack(connack) => {
+                assert_eq!(connack.reason_code, ReasonCode::Success);
+                assert!(connack
+                    .properties
+                    .iter()
+                    .any(|p| *p == Property::TopicAliasMaximum(TOPIC_ALIAS_MAXIMUM)));
+            }
+            packet => panic!("Connack is expected, got {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn v_5_0_connack_announces_assigned_client_id() {
+        let mut codec = MqttCodec::new();
+        codec.decode(&mut BytesMut::from(&V5_CONNECT[..])).unwrap();
+        codec.assign_client_id("generated".into());
+
+        let mut buf = BytesMut::new();
+        codec
+            .encode(&ConnackBuilder::new().build(), &mut buf)
+            .unwrap();
+        match decode_v_5_0(&mut buf) {
+            Packet::Connack(connack) => assert!(connack
+                .properties
+                .iter()
+                .any(|p| *p == Property::AssignedClientIdentifier("generated".into()))),
+            packet => panic!("Connack is expected, got {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn v_5_0_publish_properties_and_topic_aliases() {
+        let mut codec = MqttCodec::new();
+        codec.decode(&mut BytesMut::from(&V5_CONNECT[..])).unwrap();
+
+        let publish = |topic_name: &str, properties: Vec<Property>| {
+            Packet::Publish(Publish {
+                dup: false,
+                qos: QoS::Zero,
+                retain: false,
+                topic_name: topic_name.into(),
+                packet_id: None,
+                properties: Properties::from(properties),
+                payload: vec![1].into(),
+            })
+        };
+
+        let mut buf = encode_v_5_0(publish(
+            "a/b",
+            vec![
+                Property::TopicAlias(1),
+                Property::UserProperty("k".into(), "v".into()),
+            ],
+        ));
+        match codec.decode(&mut buf).unwrap() {
+            Some(InboundPacket::Packet { properties, .. }) => {
+                assert_eq!(
+                    properties.user_properties().collect::<Vec<_>>(),
+                    vec![("k", "v")]
+                );
+            }
+            packet => panic!("Publish is expected, got {:?}", packet),
+        }
+
+        let mut buf = encode_v_5_0(publish("", vec![Property::TopicAlias(1)]));
+        match codec.decode(&mut buf).unwrap() {
+            Some(InboundPacket::Packet { packet, .. }) => match packet.variable {
+                Variable::Publish(ref variable) => assert_eq!(variable.topic_name.original, "a/b"),
+                _ => panic!("Publish is expected"),
+            },
+            packet => panic!("Publish is expected, got {:?}", packet),
+        }
+
+        let mut buf = encode_v_5_0(publish("", vec![Property::TopicAlias(2)]));
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(InboundPacket::ProtocolError(ReasonCode::ProtocolError))
+        ));
+    }
+
+    #[test]
+    fn publish_with_wildcard_topic_is_malformed() {
+        let publish = |topic_name: &str| {
+            encode_v_5_0(Packet::Publish(Publish {
+                dup: false,
+                qos: QoS::One,
+                retain: false,
+                topic_name: topic_name.into(),
+                packet_id: Some(1),
+                properties: Properties::new(),
+                payload: vec![1].into(),
+            }))
+        };
+
+        for topic_name in ["a/+", "#"] {
+            let mut codec = MqttCodec::new();
+            codec.decode(&mut BytesMut::from(&V5_CONNECT[..])).unwrap();
+
+            let mut buf = publish(topic_name);
+            buf.extend_from_slice(&publish("a/b"));
+            assert!(matches!(
+                codec.decode(&mut buf),
+                Err(PacketCodecError::MalformedPacket(_))
+            ));
+            // the rejected packet is consumed as a whole
+            assert!(matches!(
+                codec.decode(&mut buf).unwrap(),
+                Some(InboundPacket::Packet { .. })
+            ));
+        }
+
+        let mut codec = MqttCodec::new();
+        codec.decode(&mut BytesMut::from(&V3_CONNECT[..])).unwrap();
+        let mut buf = BytesMut::from(&[0x30u8, 5, 0, 3, b'a', b'/', b'#'][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(PacketCodecError::MalformedPacket(_))
+        ));
+    }
+
+    #[test]
+    fn v_5_0_unsuback_has_reason_code_per_topic_filter() {
+        let mut codec = MqttCodec::new();
+        codec.decode(&mut BytesMut::from(&V5_CONNECT[..])).unwrap();
+
+        let mut buf = encode_v_5_0(Packet::Unsubscribe(Unsubscribe {
+            packet_id: 3,
+            properties: Properties::new(),
+            topic_filters: vec![
+                Subscription::try_from("a").unwrap(),
+                Subscription::try_from("b").unwrap(),
+            ],
+        }));
+        codec.decode(&mut buf).unwrap();
+
+        let mut buf = BytesMut::new();
+        codec
+            .encode(
+                &UnsubackPacketBuilder::new(PacketId::new(3)).build(),
+                &mut buf,
+            )
+            .unwrap();
+        match decode_v_5_0(&mut buf) {
+            Packet::Unsuback(unsuback) => {
+                assert_eq!(unsuback.reason_codes, vec![ReasonCode::Success; 2])
+            }
+            packet => panic!("Unsuback is expected, got {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn auth_is_passed_through() {
+        let mut codec = MqttCodec::new();
+        codec.decode(&mut BytesMut::from(&V5_CONNECT[..])).unwrap();
+
+        let mut buf = encode_v_5_0(Packet::Auth(ReasonPacket::new(
+            ReasonCode::ContinueAuthentication,
+        )));
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(InboundPacket::Auth(_))
+        ));
+    }
+}
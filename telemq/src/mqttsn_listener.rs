@@ -0,0 +1,750 @@
+//! MQTT-SN gateway: sensors which can't keep a TCP connection publish and subscribe over UDP.
+//!
+//! Every datagram is a single MQTT-SN message. A client is identified by its address, a CONNECT
+//! from a new address starts a client which is known to Control like any other connection, so
+//! its messages are routed through the same subscription tree and it's authenticated and
+//! restricted by ACLs the same way (MQTT-SN has no credentials, only a client id).
+//!
+//! Topic names are replaced by two byte topic ids: a client REGISTERs a topic name before
+//! publishing to it and the gateway REGISTERs a topic name before delivering the first message
+//! of a topic to a client. Topic names of two characters are sent as short topic names instead.
+//!
+//! The gateway is transparent and keeps no state of a client once it disconnects: sessions are
+//! not persistent, wills and sleeping clients are not supported, messages are delivered to
+//! clients with QoS 0 and publishes of QoS -1 (without a connection) are dropped.
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use log::{debug, error, info, warn};
+use mqtt_packets::{
+    mqtt_sn::{
+        Ack, Connect, Packet, PacketCodec, Publish, Register, ReturnCode, Suback, Subscribe,
+        TopicFilter, TopicId, NO_TOPIC_ID,
+    },
+    v_3_1_1::{
+        builders::PublishPacketBuilder,
+        topic::{Subscription, Topic},
+        QoS,
+    },
+};
+use plugin_types::authenticator::TopicACL;
+use tokio::{
+    net::UdpSocket,
+    select, spawn,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{sleep_until, Instant},
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    authenticator::{publish_allowed, subscribe_allowed},
+    broker_handle::InProcessMessage,
+    clock::Clock,
+    connection::{keep_alive_timeout, ConnectionMessage},
+    connection_channel::{connection_channel, ConnectionReceiver},
+    connection_config::ConnectionConfig,
+    connection_gate::ConnectionTransport,
+    connection_info::ConnectionInfo,
+    connection_limit::ConnectionLimit,
+    control::{ControlMessage, ControlSender},
+    logger::TARGET_AUTH,
+    publish_metadata::PublishMetadata,
+    subscription_limits::SubscriptionLimits,
+    subscription_tree::SharedSubscriptionTree,
+};
+
+/// Gateway id sent in GWINFO.
+const GATEWAY_ID: u8 = 1;
+/// A datagram can't be longer than that.
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+pub struct MqttSnGateway {
+    /// Shared handles and settings of MQTT connections, the gateway uses those which apply to
+    /// MQTT-SN clients.
+    connection_config: ConnectionConfig,
+    connection_limit: Arc<ConnectionLimit>,
+    clock: Clock,
+}
+
+impl MqttSnGateway {
+    pub fn new(
+        connection_config: ConnectionConfig,
+        connection_limit: Arc<ConnectionLimit>,
+        clock: Clock,
+    ) -> Self {
+        MqttSnGateway {
+            connection_config,
+            connection_limit,
+            clock,
+        }
+    }
+
+    /// Binds a UDP socket and serves clients in background.
+    pub async fn start(self, addr: SocketAddr) -> io::Result<()> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        info!("MQTT-SN gateway is listening on {:?}", addr);
+        spawn(Arc::new(self).serve(socket));
+
+        Ok(())
+    }
+
+    /// Dispatches datagrams to clients by their addresses.
+    async fn serve(self: Arc<Self>, socket: Arc<UdpSocket>) {
+        let mut clients: HashMap<SocketAddr, UnboundedSender<Packet>> = HashMap::new();
+        let (ended_sender, mut ended) = unbounded_channel::<SocketAddr>();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (length, addr) = match received {
+                        Ok(received) => received,
+                        Err(err) => {
+                            // e.g. ICMP port unreachable of a previous send
+                            debug!("[MQTT-SN Gateway]: unable to receive a datagram. {:?}", err);
+                            continue;
+                        }
+                    };
+                    let packet = match PacketCodec::new().decode(&mut BytesMut::from(&buf[..length])) {
+                        Ok(Some(packet)) => packet,
+                        Ok(None) => {
+                            debug!("[MQTT-SN Gateway@{}]: truncated datagram", addr);
+                            continue;
+                        }
+                        Err(err) => {
+                            debug!("[MQTT-SN Gateway@{}]: {}", addr, err);
+                            continue;
+                        }
+                    };
+
+                    let packet = match clients.get(&addr) {
+                        Some(client) => match client.send(packet) {
+                            Ok(()) => continue,
+                            // the client has just ended, a new one may be starting
+                            Err(err) => err.0,
+                        },
+                        None => packet,
+                    };
+                    match packet {
+                        Packet::Connect(connect) => {
+                            let (sender, receiver) = unbounded_channel();
+                            clients.insert(addr, sender);
+                            spawn(self.clone().run_client(
+                                addr,
+                                connect,
+                                socket.clone(),
+                                receiver,
+                                ended_sender.clone(),
+                            ));
+                        }
+                        Packet::SearchGw { .. } => {
+                            let gw_info = Packet::GwInfo { gw_id: GATEWAY_ID, gw_add: vec![] };
+                            send_packet(&socket, addr, &gw_info).await;
+                        }
+                        packet => {
+                            debug!(
+                                "[MQTT-SN Gateway@{}]: {} from a client which is not connected",
+                                addr,
+                                packet.name()
+                            );
+                        }
+                    }
+                }
+                Some(addr) = ended.recv() => {
+                    // a client with the same address may have connected again
+                    if clients.get(&addr).is_some_and(|client| client.is_closed()) {
+                        clients.remove(&addr);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run_client(
+        self: Arc<Self>,
+        addr: SocketAddr,
+        connect: Connect,
+        socket: Arc<UdpSocket>,
+        packets: UnboundedReceiver<Packet>,
+        ended_sender: UnboundedSender<SocketAddr>,
+    ) {
+        match MqttSnClient::connect(addr, connect, socket.clone(), &self).await {
+            Ok(client) => client.serve(packets).await,
+            Err(return_code) => {
+                drop(packets);
+                send_packet(&socket, addr, &Packet::Connack(return_code)).await;
+            }
+        }
+        // datagrams of this address are not sent to the client any longer,
+        // so the next CONNECT starts a new client
+        let _ = ended_sender.send(addr);
+    }
+}
+
+async fn send_packet(socket: &UdpSocket, addr: SocketAddr, packet: &Packet) -> bool {
+    let mut buf = BytesMut::new();
+    if let Err(err) = PacketCodec::new().encode(packet, &mut buf) {
+        error!("[MQTT-SN Gateway@{}]: {}", addr, err);
+        return false;
+    }
+    match socket.send_to(&buf, addr).await {
+        Ok(_) => true,
+        Err(err) => {
+            debug!(
+                "[MQTT-SN Gateway@{}]: unable to send {}. {:?}",
+                addr,
+                packet.name(),
+                err
+            );
+            false
+        }
+    }
+}
+
+/// Why a client task ends.
+enum Ending {
+    /// The client is known to Control, which is notified.
+    Disconnected,
+    /// Control has already forgotten the client or has never known it.
+    Forgotten,
+}
+
+/// A connected MQTT-SN client, similar to `Connection` of MQTT clients.
+struct MqttSnClient {
+    addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    info: Arc<ConnectionInfo>,
+    control_sender: ControlSender,
+    connection_limit: Arc<ConnectionLimit>,
+    subscription_limits: SubscriptionLimits,
+    subscription_tree: SharedSubscriptionTree,
+    receiver: ConnectionReceiver,
+    topics_acl: Option<Vec<TopicACL>>,
+    inactivity_interval: Duration,
+    /// topic id => topic name, registered either by the client or by the gateway
+    topic_names: HashMap<u16, String>,
+    topic_ids: HashMap<String, u16>,
+    next_topic_id: u16,
+    next_msg_id: u16,
+    /// Messages waiting for REGACK of their topic id.
+    unregistered: HashMap<u16, Vec<Publish>>,
+    /// Message ids of QoS 2 messages which haven't been released by PUBREL.
+    receiving: HashSet<u16>,
+    subscriptions: HashSet<String>,
+}
+
+impl MqttSnClient {
+    /// Authenticates a client and registers it with Control. Returns a return code
+    /// of CONNACK if the client is rejected.
+    async fn connect(
+        addr: SocketAddr,
+        connect: Connect,
+        socket: Arc<UdpSocket>,
+        gateway: &MqttSnGateway,
+    ) -> Result<Self, ReturnCode> {
+        if connect.client_id.is_empty() || connect.will {
+            info!(
+                "[MQTT-SN Gateway@{}]: CONNECT without a client id or with a will is not supported",
+                addr
+            );
+            return Err(ReturnCode::NotSupported);
+        }
+
+        let config = &gateway.connection_config;
+        let response = config
+            .authenticator
+            .read()
+            .await
+            .connect(addr, connect.client_id.clone(), None, None, None)
+            .await;
+        let topics_acl = match response {
+            Ok(response) if response.connection_allowed => response.topics_acl,
+            Ok(_) => {
+                info!(
+                    target: TARGET_AUTH,
+                    event = "connection_refused", client_id = connect.client_id.as_str();
+                    "[MQTT-SN Gateway@{}]: Client {:?} is not allowed to connect",
+                    addr, connect.client_id
+                );
+                return Err(ReturnCode::NotSupported);
+            }
+            Err(err) => {
+                error!(target: TARGET_AUTH, "[Authenticator Error]: {:?}", err);
+                return Err(ReturnCode::Congestion);
+            }
+        };
+        if !gateway.connection_limit.try_acquire() {
+            warn!(
+                "[MQTT-SN Gateway@{}]: max_connections is reached, unable to connect a client",
+                addr
+            );
+            return Err(ReturnCode::Congestion);
+        }
+
+        let connected_at = gateway.clock.now();
+        let info = Arc::new(ConnectionInfo {
+            client_id: connect.client_id,
+            ..ConnectionInfo::accepted(addr, ConnectionTransport::MqttSn, None, connected_at)
+        });
+        let (sender, receiver) =
+            connection_channel(config.channel_limit, Some(config.stats_sender.clone()));
+        let client = MqttSnClient {
+            addr,
+            socket,
+            info,
+            control_sender: config.control_sender.clone(),
+            connection_limit: gateway.connection_limit.clone(),
+            subscription_limits: config.subscription_limits.clone(),
+            subscription_tree: config.subscription_tree.clone(),
+            receiver,
+            topics_acl,
+            inactivity_interval: keep_alive_timeout(
+                Duration::from_secs(connect.duration as u64),
+                config.inactivity_interval,
+            ),
+            topic_names: HashMap::new(),
+            topic_ids: HashMap::new(),
+            next_topic_id: 1,
+            next_msg_id: 1,
+            unregistered: HashMap::new(),
+            receiving: HashSet::new(),
+            subscriptions: HashSet::new(),
+        };
+        client.send_control(ControlMessage::ClientConnected {
+            connection: client.info.clone(),
+            // sessions of MQTT-SN clients are not persistent
+            clean_session: true,
+            sender,
+            will_packet: None,
+            taken_over: None,
+        });
+        info!(
+            event = "client_connected";
+            "[MQTT-SN Gateway@{}]: Client has been connected",
+            client.info
+        );
+
+        Ok(client)
+    }
+
+    async fn serve(mut self, mut packets: UnboundedReceiver<Packet>) {
+        self.send(&Packet::Connack(ReturnCode::Accepted)).await;
+
+        let mut deadline = Instant::now() + self.inactivity_interval;
+        let ending = loop {
+            select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => {
+                        deadline = Instant::now() + self.inactivity_interval;
+                        if let Some(ending) = self.handle_packet(packet).await {
+                            break ending;
+                        }
+                    }
+                    None => break Ending::Disconnected,
+                },
+                message = self.receiver.recv() => match message {
+                    Some(ConnectionMessage::Publish { packet, metadata, retained_for }) => {
+                        if let Some(message) =
+                            InProcessMessage::from_packet(packet, metadata, retained_for)
+                        {
+                            self.deliver(message).await;
+                        }
+                    }
+                    Some(ConnectionMessage::ShutDown) | Some(ConnectionMessage::DropOverLimit) => {
+                        self.send(&Packet::Disconnect(None)).await;
+                        break Ending::Disconnected;
+                    }
+                    Some(ConnectionMessage::Disconnect { .. }) | None => {
+                        self.send(&Packet::Disconnect(None)).await;
+                        break Ending::Forgotten;
+                    }
+                },
+                _ = sleep_until(deadline) => {
+                    info!(
+                        "[MQTT-SN Gateway@{}]: Keep alive timeout of {:?} has expired",
+                        self.info, self.inactivity_interval
+                    );
+                    break Ending::Disconnected;
+                }
+            }
+        };
+
+        self.connection_limit.release();
+        if let Ending::Disconnected = ending {
+            self.send_control(ControlMessage::ClientDisconnected {
+                connection: self.info.clone(),
+                clean_session: true,
+                will_packet: None,
+                undelivered: Vec::new(),
+            });
+        }
+        info!(
+            "[MQTT-SN Gateway@{}]: Client has been disconnected",
+            self.info
+        );
+    }
+
+    /// Returns `Some` once the client should be disconnected.
+    async fn handle_packet(&mut self, packet: Packet) -> Option<Ending> {
+        match packet {
+            Packet::Connect(connect) => {
+                if connect.client_id == self.info.client_id {
+                    // CONNACK has been lost
+                    self.send(&Packet::Connack(ReturnCode::Accepted)).await;
+                } else {
+                    // the address is reused by another client, it has to connect again
+                    self.send(&Packet::Disconnect(None)).await;
+                    return Some(Ending::Disconnected);
+                }
+            }
+            Packet::Register(register) => self.register(register).await,
+            Packet::Regack(ack) => self.regack(ack).await,
+            Packet::Publish(publish) => self.publish(publish).await,
+            Packet::Pubrel(msg_id) => {
+                self.receiving.remove(&msg_id);
+                self.send(&Packet::Pubcomp(msg_id)).await;
+            }
+            Packet::Subscribe(subscribe) => self.subscribe(subscribe).await,
+            Packet::Unsubscribe(unsubscribe) => self.unsubscribe(unsubscribe).await,
+            Packet::Pingreq(_) => self.send(&Packet::Pingresp).await,
+            Packet::Disconnect(_) => {
+                self.send(&Packet::Disconnect(None)).await;
+                return Some(Ending::Disconnected);
+            }
+            Packet::SearchGw { .. } => {
+                let gw_info = Packet::GwInfo {
+                    gw_id: GATEWAY_ID,
+                    gw_add: vec![],
+                };
+                self.send(&gw_info).await;
+            }
+            // messages are delivered with QoS 0, so there is nothing to acknowledge
+            packet => {
+                debug!(
+                    "[MQTT-SN Gateway@{}]: unexpected {}",
+                    self.info,
+                    packet.name()
+                );
+            }
+        }
+
+        None
+    }
+
+    async fn register(&mut self, register: Register) {
+        let topic_id = match Topic::try_from(&register.topic_name) {
+            Ok(ref topic) if topic.is_valid() => self.topic_id(&register.topic_name),
+            _ => None,
+        };
+        let (topic_id, return_code) = match topic_id {
+            Some(topic_id) => (topic_id, ReturnCode::Accepted),
+            None => (NO_TOPIC_ID, ReturnCode::NotSupported),
+        };
+        self.send(&Packet::Regack(Ack {
+            topic_id,
+            msg_id: register.msg_id,
+            return_code,
+        }))
+        .await;
+    }
+
+    async fn regack(&mut self, ack: Ack) {
+        let publishes = self.unregistered.remove(&ack.topic_id).unwrap_or_default();
+        if ack.return_code != ReturnCode::Accepted {
+            warn!(
+                "[MQTT-SN Gateway@{}]: Client has rejected topic id {}. {:?}",
+                self.info, ack.topic_id, ack.return_code
+            );
+            return;
+        }
+        for publish in publishes {
+            self.send(&Packet::Publish(publish)).await;
+        }
+    }
+
+    async fn publish(&mut self, publish: Publish) {
+        let qos = publish.qos.clone().unwrap_or(QoS::Zero);
+        if qos == QoS::Two && self.receiving.contains(&publish.msg_id) {
+            // the client has not received PUBREC, the message has been routed already
+            self.send(&Packet::Pubrec(publish.msg_id)).await;
+            return;
+        }
+
+        let topic_id = match publish.topic_id {
+            TopicId::Normal(id) => id,
+            TopicId::Predefined(id) => id,
+            TopicId::Short(_) => NO_TOPIC_ID,
+        };
+        let topic = match self
+            .topic_name(&publish.topic_id)
+            .and_then(|name| Topic::try_from(name).ok())
+            .filter(Topic::is_valid)
+        {
+            Some(topic) => topic,
+            None => {
+                self.puback(&publish, topic_id, ReturnCode::InvalidTopicId)
+                    .await;
+                return;
+            }
+        };
+        if !publish_allowed(self.topics_acl(), &topic) {
+            info!(
+                target: TARGET_AUTH,
+                event = "publish_denied";
+                "[MQTT-SN Gateway@{}]: Unable to publish to {:?}. Publish is not allowed.",
+                self.info, topic
+            );
+            self.puback(&publish, topic_id, ReturnCode::NotSupported)
+                .await;
+            return;
+        }
+
+        let subscribers = self.subscription_tree.resolve(&topic.path);
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(topic)
+            .with_payload(publish.data.clone())
+            .with_qos(&qos)
+            .with_retained(publish.retain);
+        self.send_control(ControlMessage::Publish {
+            publisher: Some(self.info.clone()),
+            packet: builder.build(),
+            metadata: PublishMetadata::default(),
+            sequence: None,
+            subscribers: Some(subscribers),
+        });
+
+        match qos {
+            QoS::Zero => {}
+            QoS::One => self.puback(&publish, topic_id, ReturnCode::Accepted).await,
+            QoS::Two => {
+                self.receiving.insert(publish.msg_id);
+                self.send(&Packet::Pubrec(publish.msg_id)).await;
+            }
+        }
+    }
+
+    /// Acknowledges or rejects a publish, QoS 0 publishes are only rejected.
+    async fn puback(&self, publish: &Publish, topic_id: u16, return_code: ReturnCode) {
+        let qos = publish.qos.clone().unwrap_or(QoS::Zero);
+        if qos == QoS::Zero && return_code == ReturnCode::Accepted {
+            return;
+        }
+        self.send(&Packet::Puback(Ack {
+            topic_id,
+            msg_id: publish.msg_id,
+            return_code,
+        }))
+        .await;
+    }
+
+    async fn subscribe(&mut self, subscribe: Subscribe) {
+        let (return_code, topic_id) = match self.check_subscription(&subscribe.topic) {
+            Ok(subscription) => {
+                // a topic id is assigned to a topic name, messages of a topic filter with
+                // wildcards are delivered once their topic ids are registered
+                let topic_id = match subscribe.topic {
+                    TopicFilter::Name(ref name)
+                        if !is_filter(name) && short_name(name).is_none() =>
+                    {
+                        self.topic_id(name).unwrap_or(NO_TOPIC_ID)
+                    }
+                    _ => NO_TOPIC_ID,
+                };
+                self.subscriptions.insert(subscription.original.clone());
+                // subscribers are registered before SUBACK, so a message published right
+                // after a client receives SUBACK is routed to it
+                self.send_control(ControlMessage::AddSubscriptions {
+                    connection: self.info.clone(),
+                    subscriptions: vec![subscription],
+                });
+                (ReturnCode::Accepted, topic_id)
+            }
+            Err(return_code) => (return_code, NO_TOPIC_ID),
+        };
+        self.send(&Packet::Suback(Suback {
+            qos: QoS::Zero,
+            topic_id,
+            msg_id: subscribe.msg_id,
+            return_code,
+        }))
+        .await;
+    }
+
+    async fn unsubscribe(&mut self, unsubscribe: Subscribe) {
+        let subscription = self
+            .filter_name(&unsubscribe.topic)
+            .and_then(|name| Subscription::try_from(name).ok());
+        if let Some(subscription) = subscription {
+            self.subscriptions.remove(&subscription.original);
+            self.send_control(ControlMessage::RemoveSubscriptions {
+                connection: self.info.clone(),
+                subscriptions: vec![subscription],
+            });
+        }
+        // topic filters which have never been subscribed to are acknowledged as well
+        self.send(&Packet::Unsuback(unsubscribe.msg_id)).await;
+    }
+
+    fn check_subscription(&self, topic: &TopicFilter) -> Result<Subscription, ReturnCode> {
+        let subscription = self
+            .filter_name(topic)
+            .and_then(|name| Subscription::try_from(name).ok())
+            .filter(|subscription| subscription.is_valid())
+            .ok_or(ReturnCode::InvalidTopicId)?;
+
+        if !subscribe_allowed(self.topics_acl(), &subscription) {
+            info!(
+                target: TARGET_AUTH,
+                event = "subscription_rejected";
+                "[MQTT-SN Gateway@{}]: Subscription to {:?} is not allowed",
+                self.info, subscription.original
+            );
+            return Err(ReturnCode::NotSupported);
+        }
+        if self.subscription_limits.is_blacklisted(&subscription) {
+            info!(
+                event = "subscription_rejected";
+                "[MQTT-SN Gateway@{}]: Subscription to {:?} is blacklisted",
+                self.info, subscription.original
+            );
+            return Err(ReturnCode::NotSupported);
+        }
+        let limit_reached = !self.subscriptions.contains(&subscription.original)
+            && self
+                .subscription_limits
+                .max_subs_per_client
+                .is_some_and(|max_subs_per_client| self.subscriptions.len() >= max_subs_per_client);
+        if limit_reached {
+            info!(
+                event = "subscription_rejected";
+                "[MQTT-SN Gateway@{}]: Subscription to {:?} exceeds max_subs_per_client",
+                self.info, subscription.original
+            );
+            return Err(ReturnCode::Congestion);
+        }
+
+        Ok(subscription)
+    }
+
+    /// Sends a message to the client, registering its topic first if needed.
+    async fn deliver(&mut self, message: InProcessMessage) {
+        let (topic_id, registered) = match (
+            short_name(&message.topic),
+            self.topic_ids.get(&message.topic),
+        ) {
+            (Some(name), _) => (TopicId::Short(name), true),
+            (None, Some(&id)) => (TopicId::Normal(id), !self.unregistered.contains_key(&id)),
+            (None, None) => match self.topic_id(&message.topic) {
+                Some(id) => {
+                    let register = Packet::Register(Register {
+                        topic_id: id,
+                        msg_id: self.next_msg_id(),
+                        topic_name: message.topic.clone(),
+                    });
+                    self.unregistered.insert(id, vec![]);
+                    self.send(&register).await;
+                    (TopicId::Normal(id), false)
+                }
+                None => {
+                    warn!(
+                        "[MQTT-SN Gateway@{}]: No topic ids are left, message to {:?} is dropped",
+                        self.info, message.topic
+                    );
+                    return;
+                }
+            },
+        };
+
+        let publish = Publish {
+            dup: false,
+            qos: Some(QoS::Zero),
+            retain: message.retain,
+            topic_id: topic_id.clone(),
+            msg_id: 0,
+            data: message.payload,
+        };
+        match topic_id {
+            TopicId::Normal(id) if !registered => {
+                self.unregistered.entry(id).or_default().push(publish);
+            }
+            _ => {
+                self.send(&Packet::Publish(publish)).await;
+            }
+        }
+    }
+
+    /// Topic id of a topic name, a new one is assigned if the name has none.
+    /// `None` if all topic ids are taken.
+    fn topic_id(&mut self, topic_name: &str) -> Option<u16> {
+        if let Some(&id) = self.topic_ids.get(topic_name) {
+            return Some(id);
+        }
+        if self.next_topic_id == NO_TOPIC_ID {
+            return None;
+        }
+        let id = self.next_topic_id;
+        self.next_topic_id = self.next_topic_id.wrapping_add(1);
+        self.topic_ids.insert(topic_name.to_string(), id);
+        self.topic_names.insert(id, topic_name.to_string());
+
+        Some(id)
+    }
+
+    /// Predefined topic ids are not supported.
+    fn topic_name(&self, topic_id: &TopicId) -> Option<String> {
+        match *topic_id {
+            TopicId::Normal(id) => self.topic_names.get(&id).cloned(),
+            TopicId::Predefined(_) => None,
+            TopicId::Short(name) => String::from_utf8(name.to_vec()).ok(),
+        }
+    }
+
+    fn filter_name(&self, topic: &TopicFilter) -> Option<String> {
+        match *topic {
+            TopicFilter::Name(ref name) => Some(name.clone()),
+            TopicFilter::Predefined(id) => self.topic_name(&TopicId::Predefined(id)),
+            TopicFilter::Short(name) => self.topic_name(&TopicId::Short(name)),
+        }
+    }
+
+    fn next_msg_id(&mut self) -> u16 {
+        let msg_id = self.next_msg_id;
+        // 0x0000 is not a valid message id
+        self.next_msg_id = self.next_msg_id.checked_add(1).unwrap_or(1);
+        msg_id
+    }
+
+    /// `None` if the client is not restricted by an ACL.
+    fn topics_acl(&self) -> Option<&[TopicACL]> {
+        self.topics_acl.as_deref()
+    }
+
+    async fn send(&self, packet: &Packet) {
+        send_packet(&self.socket, self.addr, packet).await;
+    }
+
+    fn send_control(&self, message: ControlMessage) {
+        let message_type = message.get_name();
+        if let Err(err) = self.control_sender.send(message) {
+            error!(
+                "[MQTT-SN Gateway@{}]: unable to send {}. {:?}",
+                self.info, message_type, err
+            );
+        }
+    }
+}
+
+fn is_filter(topic: &str) -> bool {
+    topic.contains(['+', '#'])
+}
+
+/// Topic names of two bytes are sent as short topic names, they need no topic ids.
+fn short_name(topic: &str) -> Option<[u8; 2]> {
+    <[u8; 2]>::try_from(topic.as_bytes()).ok()
+}
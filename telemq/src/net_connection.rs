@@ -1,70 +1,209 @@
-use std::io;
+use std::{io, sync::Arc};
 
 use bytes::BytesMut;
 use futures::{SinkExt, StreamExt};
-use mqtt_packets::v_3_1_1::{ControlPacket, ControlPacketCodec};
-use tokio::net::TcpStream;
+use mqtt_packets::{
+    v_3_1_1::ControlPacket,
+    v_5_0::{Packet, ReasonCode, ReasonPacket},
+    PacketCodecError,
+};
+use tokio::{net::TcpStream, time::Instant};
 use tokio_rustls::server::TlsStream;
 use tokio_util::codec::{Decoder, Encoder, Framed};
 use warp::filters::ws::{Message, WebSocket};
 
-pub enum NetConnection {
-    Tcp(Framed<TcpStream, ControlPacketCodec>),
-    Tls(Framed<TlsStream<TcpStream>, ControlPacketCodec>),
+use crate::{
+    bandwidth_limiter::BandwidthLimiter,
+    mqtt_codec::{InboundPacket, MqttCodec, ProtocolVersion},
+    transport_bytes::{ws_frame_size, CountingStream, TransportBytes},
+};
+
+/// Packets are written to a buffer and flushed together, e.g. while Control Worker fans out
+/// a burst of messages, once there are this many of them or once the connection is idle.
+const MAX_UNFLUSHED_PACKETS: usize = 64;
+/// Bytes written without a flush, same as `MAX_UNFLUSHED_PACKETS`.
+const MAX_UNFLUSHED_BYTES: usize = 64 * 1024;
+
+pub struct NetConnection {
+    stream: NetStream,
+    /// Bandwidth cap of a listener the connection was accepted on.
+    bandwidth_limiter: Option<BandwidthLimiter>,
+    unflushed_packets: usize,
+    unflushed_bytes: usize,
+}
+
+enum NetStream {
+    Tcp(Framed<CountingStream<TcpStream>, MqttCodec>),
+    Tls(Framed<TlsStream<CountingStream<TcpStream>>, MqttCodec>),
     Ws {
         websocket: WebSocket,
-        codec: ControlPacketCodec,
+        codec: MqttCodec,
         buf_in: BytesMut,
+        /// When the last ping or pong frame has been received.
+        last_frame: Option<Instant>,
+        /// Frames are counted here, since warp doesn't expose the underlying stream.
+        transport_bytes: Option<Arc<TransportBytes>>,
     },
 }
 
 impl NetConnection {
-    pub fn new_tcp(framed_tcp: Framed<TcpStream, ControlPacketCodec>) -> Self {
-        NetConnection::Tcp(framed_tcp)
+    pub fn new_tcp(
+        framed_tcp: Framed<CountingStream<TcpStream>, MqttCodec>,
+        bandwidth_limiter: Option<BandwidthLimiter>,
+    ) -> Self {
+        NetConnection {
+            stream: NetStream::Tcp(framed_tcp),
+            bandwidth_limiter,
+            unflushed_packets: 0,
+            unflushed_bytes: 0,
+        }
+    }
+
+    pub fn new_tls(
+        framed_tls: Framed<TlsStream<CountingStream<TcpStream>>, MqttCodec>,
+        bandwidth_limiter: Option<BandwidthLimiter>,
+    ) -> Self {
+        NetConnection {
+            stream: NetStream::Tls(framed_tls),
+            bandwidth_limiter,
+            unflushed_packets: 0,
+            unflushed_bytes: 0,
+        }
+    }
+
+    pub fn new_ws(
+        arg: (WebSocket, MqttCodec),
+        bandwidth_limiter: Option<BandwidthLimiter>,
+        transport_bytes: Option<Arc<TransportBytes>>,
+    ) -> Self {
+        NetConnection {
+            stream: NetStream::Ws {
+                websocket: arg.0,
+                codec: arg.1,
+                buf_in: BytesMut::new(),
+                last_frame: None,
+                transport_bytes,
+            },
+            bandwidth_limiter,
+            unflushed_packets: 0,
+            unflushed_bytes: 0,
+        }
+    }
+
+    /// Protocol version negotiated with a client. `None` until CONNECT is received.
+    pub fn protocol(&self) -> Option<ProtocolVersion> {
+        match &self.stream {
+            NetStream::Tcp(tcp_stream) => tcp_stream.codec().protocol(),
+            NetStream::Tls(tls_stream) => tls_stream.codec().protocol(),
+            NetStream::Ws { codec, .. } => codec.protocol(),
+        }
+    }
+
+    /// Announces a client id assigned by the broker in the next CONNACK.
+    pub fn assign_client_id(&mut self, client_id: String) {
+        match &mut self.stream {
+            NetStream::Tcp(tcp_stream) => tcp_stream.codec_mut().assign_client_id(client_id),
+            NetStream::Tls(tls_stream) => tls_stream.codec_mut().assign_client_id(client_id),
+            NetStream::Ws { codec, .. } => codec.assign_client_id(client_id),
+        }
+    }
+
+    /// When the last websocket ping or pong frame has been received, `None` for other
+    /// transports.
+    pub fn last_ws_frame(&self) -> Option<Instant> {
+        match &self.stream {
+            NetStream::Ws { last_frame, .. } => *last_frame,
+            _ => None,
+        }
+    }
+
+    /// Sends a websocket ping along with unflushed packets, does nothing for other transports.
+    pub async fn send_ws_ping(&mut self) -> Result<(), PacketCodecError> {
+        let result = match &mut self.stream {
+            NetStream::Ws {
+                websocket,
+                transport_bytes,
+                ..
+            } => {
+                if let Some(transport_bytes) = transport_bytes {
+                    transport_bytes.add_sent(ws_frame_size(0, false));
+                }
+                websocket
+                    .send(Message::ping(Vec::new()))
+                    .await
+                    .map_err(ws_error)
+            }
+            _ => return Ok(()),
+        };
+        self.unflushed_packets = 0;
+        self.unflushed_bytes = 0;
+
+        result
     }
 
-    pub fn new_tls(framed_tls: Framed<TlsStream<TcpStream>, ControlPacketCodec>) -> Self {
-        NetConnection::Tls(framed_tls)
+    /// Whether some packets have been written, but not flushed yet.
+    pub fn has_unflushed(&self) -> bool {
+        self.unflushed_packets > 0
     }
 
-    pub fn new_ws(arg: (WebSocket, ControlPacketCodec)) -> Self {
-        NetConnection::Ws {
-            websocket: arg.0,
-            codec: arg.1,
-            buf_in: BytesMut::new(),
+    /// Sends packets written since the last flush.
+    pub async fn flush(&mut self) -> Result<(), PacketCodecError> {
+        if !self.has_unflushed() {
+            return Ok(());
+        }
+
+        self.unflushed_packets = 0;
+        self.unflushed_bytes = 0;
+        match &mut self.stream {
+            NetStream::Tcp(tcp_stream) => SinkExt::<&ControlPacket>::flush(tcp_stream).await,
+            NetStream::Tls(tls_stream) => SinkExt::<&ControlPacket>::flush(tls_stream).await,
+            NetStream::Ws { websocket, .. } => websocket.flush().await.map_err(ws_error),
         }
     }
 
-    pub async fn next_packet(&mut self) -> Option<io::Result<ControlPacket>> {
-        match self {
-            NetConnection::Tcp(tcp_stream) => tcp_stream.next().await,
-            NetConnection::Tls(tls_stream) => tls_stream.next().await,
-            NetConnection::Ws {
+    pub async fn next_packet(&mut self) -> Option<Result<InboundPacket, PacketCodecError>> {
+        match &mut self.stream {
+            NetStream::Tcp(tcp_stream) => tcp_stream.next().await,
+            NetStream::Tls(tls_stream) => tls_stream.next().await,
+            NetStream::Ws {
                 websocket,
                 codec,
                 buf_in: ref mut buf,
+                last_frame,
+                transport_bytes,
             } => loop {
+                // a packet may span several frames and a frame may carry several packets,
+                // so packets left in the buffer are decoded before the next frame is read
+                if !buf.is_empty() {
+                    match codec.decode(buf) {
+                        Ok(Some(packet)) => return Some(Ok(packet)),
+                        Ok(None) => {}
+                        Err(err) => return Some(Err(err)),
+                    }
+                }
                 match websocket.next().await {
                     Some(Ok(message)) => {
-                        buf.extend_from_slice(message.as_bytes());
-                        let m = codec.decode(buf);
-                        match m {
-                            Ok(Some(packet)) => {
-                                return Some(Ok(packet));
-                            }
-                            Ok(None) => {
-                                continue;
-                            }
-                            Err(err) => {
-                                return Some(Err(err));
+                        if let Some(transport_bytes) = transport_bytes {
+                            let payload_len = message.as_bytes().len();
+                            transport_bytes.add_received(ws_frame_size(payload_len, true));
+                            if message.is_ping() {
+                                // the websocket answers with a pong of the same payload
+                                transport_bytes.add_sent(ws_frame_size(payload_len, false));
                             }
                         }
+                        if message.is_ping() || message.is_pong() {
+                            // pings are answered by the websocket itself
+                            *last_frame = Some(Instant::now());
+                            continue;
+                        }
+                        if message.is_close() {
+                            // the stream ends right after
+                            continue;
+                        }
+                        buf.extend_from_slice(message.as_bytes());
                     }
                     Some(Err(err)) => {
-                        return Some(Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("[Websocket Error] {:?}", err),
-                        )));
+                        return Some(Err(ws_error(err)));
                     }
                     None => {
                         return None;
@@ -74,27 +213,220 @@ impl NetConnection {
         }
     }
 
-    pub async fn send_packet(&mut self, control_packet: &ControlPacket) -> io::Result<()> {
-        match self {
-            NetConnection::Tcp(tcp_stream) => tcp_stream.send(&control_packet).await,
-            NetConnection::Tls(tls_stream) => tls_stream.send(&control_packet).await,
-            NetConnection::Ws {
-                websocket, codec, ..
+    /// Writes a packet, which is flushed once enough packets are written or by `flush`.
+    pub async fn send_packet(
+        &mut self,
+        control_packet: &ControlPacket,
+    ) -> Result<(), PacketCodecError> {
+        let size = encoded_size(control_packet);
+        if let Some(ref bandwidth_limiter) = self.bandwidth_limiter {
+            bandwidth_limiter.acquire(size).await;
+        }
+
+        self.feed_packet(control_packet).await?;
+        self.unflushed_packets += 1;
+        self.unflushed_bytes += size;
+        if self.unflushed_packets >= MAX_UNFLUSHED_PACKETS
+            || self.unflushed_bytes >= MAX_UNFLUSHED_BYTES
+        {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends DISCONNECT with a reason code to an MQTT 5.0 client. MQTT 3.1.1 has no
+    /// DISCONNECT sent by a server, so nothing is sent to MQTT 3.1.1 clients.
+    pub async fn send_disconnect(
+        &mut self,
+        reason_code: ReasonCode,
+    ) -> Result<(), PacketCodecError> {
+        if self.protocol() != Some(ProtocolVersion::V5_0) {
+            return Ok(());
+        }
+
+        // the connection is closed right after, so the bandwidth cap is not applied
+        let packet = Packet::Disconnect(ReasonPacket::new(reason_code));
+        self.feed_packet(&packet).await?;
+        self.unflushed_packets += 1;
+        self.flush().await
+    }
+
+    /// Writes a packet to a buffer without flushing it.
+    async fn feed_packet<P>(&mut self, packet: P) -> Result<(), PacketCodecError>
+    where
+        MqttCodec: Encoder<P, Error = PacketCodecError>,
+    {
+        match &mut self.stream {
+            NetStream::Tcp(tcp_stream) => tcp_stream.feed(packet).await,
+            NetStream::Tls(tls_stream) => tls_stream.feed(packet).await,
+            NetStream::Ws {
+                websocket,
+                codec,
+                transport_bytes,
+                ..
             } => {
                 let mut bytes = BytesMut::new();
-                match codec.encode(control_packet, &mut bytes) {
-                    Ok(_) => websocket
-                        .send(Message::binary(bytes.as_ref()))
-                        .await
-                        .map_err(|err| {
-                            io::Error::new(
-                                io::ErrorKind::Other,
-                                format!("[Websocket Error] {:?}", err),
-                            )
-                        }),
+                match codec.encode(packet, &mut bytes) {
+                    Ok(_) => {
+                        if let Some(transport_bytes) = transport_bytes {
+                            transport_bytes.add_sent(ws_frame_size(bytes.len(), false));
+                        }
+                        websocket
+                            .feed(Message::binary(bytes.as_ref()))
+                            .await
+                            .map_err(ws_error)
+                    }
                     err => err,
                 }
             }
         }
     }
 }
+
+fn ws_error(err: warp::Error) -> PacketCodecError {
+    PacketCodecError::Io(io::Error::new(
+        io::ErrorKind::Other,
+        format!("[Websocket Error] {:?}", err),
+    ))
+}
+
+/// Number of bytes a packet takes on the wire: a fixed header byte, remaining length
+/// (1 to 4 bytes) and the rest of a packet.
+fn encoded_size(control_packet: &ControlPacket) -> usize {
+    let remaining_length = control_packet.fixed_header.remaining_length.as_value() as usize;
+    let remaining_length_bytes = match remaining_length {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    };
+
+    1 + remaining_length_bytes + remaining_length
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_packets::v_3_1_1::builders::PingrespPacketBuilder;
+    use tokio::{
+        io::AsyncReadExt,
+        net::TcpListener,
+        sync::mpsc::{unbounded_channel, UnboundedReceiver},
+        time::{timeout, Duration},
+    };
+    use warp::{test::WsClient, Filter};
+
+    use super::*;
+
+    async fn connected_pair() -> (NetConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let server = CountingStream::new(server, None);
+        let connection = NetConnection::new_tcp(Framed::new(server, MqttCodec::new()), None);
+
+        (connection, client)
+    }
+
+    /// A websocket client along with packets its connection has received.
+    async fn ws_pair() -> (WsClient, UnboundedReceiver<InboundPacket>) {
+        let (tx, rx) = unbounded_channel();
+        let route = warp::ws().map(move |ws: warp::ws::Ws| {
+            let tx = tx.clone();
+            ws.on_upgrade(move |websocket| async move {
+                let mut connection =
+                    NetConnection::new_ws((websocket, MqttCodec::new()), None, None);
+                while let Some(Ok(packet)) = connection.next_packet().await {
+                    let _ = tx.send(packet);
+                }
+            })
+        });
+        let client = warp::test::ws().handshake(route).await.unwrap();
+
+        (client, rx)
+    }
+
+    /// CONNECT of MQTT 3.1.1 with an empty client id followed by PINGREQ.
+    const CONNECT_AND_PINGREQ: [u8; 16] = [
+        0x10, 0x0c, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3c, 0x00, 0x00, 0xc0,
+        0x00,
+    ];
+
+    async fn received_packets(packets: &mut UnboundedReceiver<InboundPacket>) -> usize {
+        let mut received = 0;
+        while let Ok(Some(_)) = timeout(Duration::from_millis(50), packets.recv()).await {
+            received += 1;
+        }
+
+        received
+    }
+
+    /// Reads whatever has arrived within a short time.
+    async fn read_available(client: &mut TcpStream) -> usize {
+        let mut buf = vec![0; 4096];
+        let mut read = 0;
+        while let Ok(Ok(n)) = timeout(Duration::from_millis(50), client.read(&mut buf)).await {
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+
+        read
+    }
+
+    #[tokio::test]
+    async fn packets_are_sent_once_flushed() {
+        let (mut connection, mut client) = connected_pair().await;
+        let pingresp = PingrespPacketBuilder::new().build();
+
+        for _ in 0..3 {
+            connection.send_packet(&pingresp).await.unwrap();
+        }
+        assert!(connection.has_unflushed());
+        assert_eq!(read_available(&mut client).await, 0);
+
+        connection.flush().await.unwrap();
+        assert!(!connection.has_unflushed());
+        assert_eq!(read_available(&mut client).await, 3 * 2);
+    }
+
+    #[tokio::test]
+    async fn ws_frame_with_several_packets() {
+        let (mut client, mut packets) = ws_pair().await;
+
+        client.send(Message::binary(&CONNECT_AND_PINGREQ[..])).await;
+        assert_eq!(received_packets(&mut packets).await, 2);
+    }
+
+    #[tokio::test]
+    async fn ws_packet_split_across_frames() {
+        let (mut client, mut packets) = ws_pair().await;
+
+        client
+            .send(Message::binary(&CONNECT_AND_PINGREQ[..5]))
+            .await;
+        client
+            .send(Message::binary(&CONNECT_AND_PINGREQ[5..13]))
+            .await;
+        assert_eq!(received_packets(&mut packets).await, 0);
+        client
+            .send(Message::binary(&CONNECT_AND_PINGREQ[13..]))
+            .await;
+        assert_eq!(received_packets(&mut packets).await, 2);
+    }
+
+    #[tokio::test]
+    async fn packets_are_flushed_after_max_unflushed_packets() {
+        let (mut connection, mut client) = connected_pair().await;
+        let pingresp = PingrespPacketBuilder::new().build();
+
+        for _ in 0..MAX_UNFLUSHED_PACKETS {
+            connection.send_packet(&pingresp).await.unwrap();
+        }
+        assert!(!connection.has_unflushed());
+        assert_eq!(read_available(&mut client).await, MAX_UNFLUSHED_PACKETS * 2);
+    }
+}
@@ -0,0 +1,140 @@
+//! Formats of payloads enforced on topics, e.g. JSON telemetry which downstream consumers
+//! parse without checking it.
+//!
+//! A message a client publishes to a topic matching a `payload_formats` rule has to have
+//! a payload of the rule's format, otherwise it's not routed to subscribers of the topic.
+//! A rejected message is published to `{payload_dead_letter_topic}/{topic}` instead if it's
+//! set, so bad payloads can be inspected, and is acknowledged to a client either way, so
+//! the client doesn't send it again.
+use std::{str::from_utf8, sync::Arc};
+
+use mqtt_packets::v_3_1_1::topic::{Subscription, Topic};
+use serde::de::IgnoredAny;
+
+use crate::config::{PayloadFormat, TeleMQServerConfig};
+
+/// Payload formats of topic filters, shared by connections.
+#[derive(Debug, Clone, Default)]
+pub struct PayloadFormats {
+    rules: Arc<Vec<(Subscription, PayloadFormat)>>,
+    dead_letter_topic: Option<String>,
+}
+
+impl PayloadFormats {
+    pub fn new(config: &TeleMQServerConfig) -> Self {
+        PayloadFormats {
+            // filters are validated by the config
+            rules: Arc::new(
+                config
+                    .payload_formats
+                    .iter()
+                    .filter_map(|rule| {
+                        Subscription::try_from(rule.filter.as_str())
+                            .ok()
+                            .map(|filter| (filter, rule.format))
+                    })
+                    .collect(),
+            ),
+            dead_letter_topic: config.payload_dead_letter_topic.clone(),
+        }
+    }
+
+    /// A format of a topic a payload doesn't have, `None` if the payload is valid or the topic
+    /// has no format. Empty payloads are always valid, as a retained empty message clears a
+    /// retained message of a topic.
+    pub fn violated(&self, topic: &Topic, payload: &[u8]) -> Option<PayloadFormat> {
+        if payload.is_empty() {
+            return None;
+        }
+
+        let (_, format) = self
+            .rules
+            .iter()
+            .find(|(filter, _)| filter.topic_matches(topic))?;
+        let is_valid = match format {
+            PayloadFormat::Utf8 => from_utf8(payload).is_ok(),
+            PayloadFormat::Json => serde_json::from_slice::<IgnoredAny>(payload).is_ok(),
+        };
+
+        (!is_valid).then_some(*format)
+    }
+
+    /// A topic a rejected message of a topic is published to, `None` if it's dropped.
+    pub fn dead_letter_topic(&self, topic: &Topic) -> Option<Topic> {
+        let dead_letter_topic = self.dead_letter_topic.as_ref()?;
+        Topic::try_from(format!("{}/{}", dead_letter_topic, topic.original)).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PayloadFormatConfig;
+
+    fn payload_formats(dead_letter_topic: Option<&str>) -> PayloadFormats {
+        let rule = |filter: &str, format| PayloadFormatConfig {
+            filter: filter.into(),
+            format,
+        };
+        PayloadFormats::new(&TeleMQServerConfig {
+            payload_formats: vec![
+                rule("telemetry/raw/#", PayloadFormat::Utf8),
+                rule("telemetry/#", PayloadFormat::Json),
+            ],
+            payload_dead_letter_topic: dead_letter_topic.map(String::from),
+            ..TeleMQServerConfig::default()
+        })
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let payload_formats = payload_formats(None);
+        let topic = |t: &str| Topic::make_from_string(t);
+
+        assert_eq!(
+            payload_formats.violated(&topic("telemetry/1"), br#"{"t": 21.5}"#),
+            None
+        );
+        assert_eq!(
+            payload_formats.violated(&topic("telemetry/1"), b"21.5 C"),
+            Some(PayloadFormat::Json)
+        );
+        assert_eq!(
+            payload_formats.violated(&topic("telemetry/raw/1"), b"21.5 C"),
+            None
+        );
+        assert_eq!(
+            payload_formats.violated(&topic("telemetry/raw/1"), &[0xff, 0xfe]),
+            Some(PayloadFormat::Utf8)
+        );
+        assert_eq!(
+            payload_formats.violated(&topic("firmware/1"), &[0xff]),
+            None
+        );
+    }
+
+    #[test]
+    fn rejected_messages_keep_their_topics() {
+        let topic = Topic::make_from_string("telemetry/1");
+
+        assert_eq!(
+            payload_formats(Some("dead-letter"))
+                .dead_letter_topic(&topic)
+                .map(|topic| topic.original),
+            Some("dead-letter/telemetry/1".to_string())
+        );
+        assert!(payload_formats(None).dead_letter_topic(&topic).is_none());
+    }
+
+    #[test]
+    fn empty_payloads_are_valid() {
+        let formats = payload_formats(None);
+
+        assert!(formats
+            .violated(&Topic::make_from_string("telemetry/1"), b"")
+            .is_none());
+        assert!(formats
+            .violated(&Topic::make_from_string("telemq/raw/1"), b"")
+            .is_none());
+    }
+}
@@ -0,0 +1,260 @@
+//! Priority classes of messages by topic. Publishes waiting for Control Worker and messages
+//! waiting for a connection (a burst of fan-outs, the in-flight window or a queue of
+//! an offline client) are served by class, e.g. alarms before telemetry, which matters when
+//! devices drain slowly over constrained links. Messages of the same class keep their order.
+//!
+//! A waiting class is passed over at most `priority_starvation_limit` times in a row, so
+//! a steady stream of high priority messages doesn't starve the rest.
+use std::{collections::VecDeque, sync::Arc};
+
+use mqtt_packets::v_3_1_1::{topic::Subscription, variable::Variable, ControlPacket};
+
+use crate::config::{Priority, TeleMQServerConfig};
+
+const CLASSES: usize = 3;
+
+/// At most this many messages waiting in a channel are taken at once to be served by priority.
+pub const PRIORITY_BATCH_SIZE: usize = 1024;
+
+/// Priority classes of topic filters, shared by Control Worker and connections.
+#[derive(Debug, Clone, Default)]
+pub struct PriorityTopics {
+    rules: Arc<Vec<(Subscription, Priority)>>,
+    starvation_limit: usize,
+}
+
+impl PriorityTopics {
+    pub fn new(config: &TeleMQServerConfig) -> Self {
+        PriorityTopics {
+            // filters are validated by the config
+            rules: Arc::new(
+                config
+                    .priority_topics
+                    .iter()
+                    .filter_map(|rule| {
+                        Subscription::try_from(rule.filter.as_str())
+                            .ok()
+                            .map(|filter| (filter, rule.priority))
+                    })
+                    .collect(),
+            ),
+            starvation_limit: config.priority_starvation_limit,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// The highest priority of filters matching a topic of a PUBLISH packet, `Normal` if none.
+    pub fn priority(&self, packet: &ControlPacket) -> Priority {
+        let topic = match packet.variable {
+            Variable::Publish(ref variable) => &variable.topic_name,
+            _ => return Priority::Normal,
+        };
+        self.rules
+            .iter()
+            .filter(|(filter, _)| filter.topic_matches(topic))
+            .map(|(_, priority)| *priority)
+            .min()
+            .unwrap_or(Priority::Normal)
+    }
+}
+
+/// Picks the next message to serve out of a queue of waiting ones. Control Worker and every
+/// connection have their own scheduler, as it counts how many times classes are passed over.
+#[derive(Debug, Clone, Default)]
+pub struct PriorityScheduler {
+    topics: PriorityTopics,
+    /// Times a waiting class has been passed over since it has been served.
+    skipped: [usize; CLASSES],
+}
+
+impl PriorityScheduler {
+    pub fn new(topics: PriorityTopics) -> Self {
+        PriorityScheduler {
+            topics,
+            skipped: [0; CLASSES],
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.topics.is_enabled()
+    }
+
+    /// Index of a message to serve next: the first one of the highest waiting class, unless
+    /// a lower class has been passed over `starvation_limit` times.
+    pub fn next<T>(
+        &mut self,
+        queue: &VecDeque<T>,
+        packet: impl Fn(&T) -> &ControlPacket,
+    ) -> Option<usize> {
+        if queue.is_empty() || !self.is_enabled() {
+            return if queue.is_empty() { None } else { Some(0) };
+        }
+
+        let mut first = [None; CLASSES];
+        for (index, message) in queue.iter().enumerate() {
+            let class = self.topics.priority(packet(message)) as usize;
+            if first[class].is_none() {
+                first[class] = Some(index);
+                if first.iter().all(Option::is_some) {
+                    break;
+                }
+            }
+        }
+
+        let highest = first.iter().position(Option::is_some)?;
+        // the lowest starving class goes first, it has waited the longest
+        let served = (highest + 1..CLASSES)
+            .rev()
+            .find(|class| {
+                first[*class].is_some() && self.skipped[*class] >= self.topics.starvation_limit
+            })
+            .unwrap_or(highest);
+        for (class, skipped) in self.skipped.iter_mut().enumerate() {
+            if class == served || first[class].is_none() {
+                *skipped = 0;
+            } else if class > served {
+                *skipped += 1;
+            }
+        }
+
+        first[served]
+    }
+
+    /// Takes every message out of a queue in the order they should be served.
+    pub fn drain<T>(
+        &mut self,
+        mut queue: VecDeque<T>,
+        packet: impl Fn(&T) -> &ControlPacket,
+    ) -> Vec<T> {
+        let mut ordered = Vec::with_capacity(queue.len());
+        while let Some(index) = self.next(&queue, &packet) {
+            if let Some(message) = queue.remove(index) {
+                ordered.push(message);
+            }
+        }
+
+        ordered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PriorityTopicConfig;
+    use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic, QoS};
+
+    fn topics(starvation_limit: usize) -> PriorityTopics {
+        let config = TeleMQServerConfig {
+            priority_topics: vec![
+                PriorityTopicConfig {
+                    filter: "alarms/#".into(),
+                    priority: Priority::High,
+                },
+                PriorityTopicConfig {
+                    filter: "logs/#".into(),
+                    priority: Priority::Low,
+                },
+            ],
+            priority_starvation_limit: starvation_limit,
+            ..TeleMQServerConfig::default()
+        };
+        PriorityTopics::new(&config)
+    }
+
+    fn publish(topic: &str) -> ControlPacket {
+        let mut builder = PublishPacketBuilder::new();
+        builder.with_topic(Topic::try_from(topic).unwrap());
+        builder.with_qos(&QoS::Zero);
+        builder.build()
+    }
+
+    fn topic_of(packet: &ControlPacket) -> &str {
+        match packet.variable {
+            Variable::Publish(ref variable) => &variable.topic_name.original,
+            _ => unreachable!(),
+        }
+    }
+
+    fn drain(scheduler: &mut PriorityScheduler, topics: &[&str]) -> Vec<String> {
+        let queue: VecDeque<ControlPacket> = topics.iter().map(|topic| publish(topic)).collect();
+        scheduler
+            .drain(queue, |packet| packet)
+            .iter()
+            .map(|packet| topic_of(packet).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn priority_is_the_highest_of_matching_filters() {
+        let topics = topics(10);
+
+        assert_eq!(topics.priority(&publish("alarms/1")), Priority::High);
+        assert_eq!(topics.priority(&publish("logs/1")), Priority::Low);
+        assert_eq!(topics.priority(&publish("telemetry/1")), Priority::Normal);
+    }
+
+    #[test]
+    fn higher_classes_are_served_first_in_order() {
+        let mut scheduler = PriorityScheduler::new(topics(10));
+
+        assert_eq!(
+            drain(
+                &mut scheduler,
+                &[
+                    "logs/1",
+                    "telemetry/1",
+                    "alarms/1",
+                    "telemetry/2",
+                    "alarms/2"
+                ]
+            ),
+            vec![
+                "alarms/1",
+                "alarms/2",
+                "telemetry/1",
+                "telemetry/2",
+                "logs/1"
+            ]
+        );
+    }
+
+    #[test]
+    fn waiting_classes_are_not_starved() {
+        let mut scheduler = PriorityScheduler::new(topics(2));
+
+        assert_eq!(
+            drain(
+                &mut scheduler,
+                &[
+                    "logs/1",
+                    "telemetry/1",
+                    "alarms/1",
+                    "alarms/2",
+                    "alarms/3",
+                    "alarms/4"
+                ]
+            ),
+            vec![
+                "alarms/1",
+                "alarms/2",
+                "logs/1",
+                "telemetry/1",
+                "alarms/3",
+                "alarms/4"
+            ]
+        );
+    }
+
+    #[test]
+    fn order_is_kept_without_priority_topics() {
+        let mut scheduler = PriorityScheduler::default();
+
+        assert_eq!(
+            drain(&mut scheduler, &["logs/1", "alarms/1"]),
+            vec!["logs/1", "alarms/1"]
+        );
+    }
+}
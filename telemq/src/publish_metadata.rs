@@ -0,0 +1,98 @@
+use mqtt_packets::v_5_0::Properties;
+use serde::{Deserialize, Serialize};
+
+/// Metadata attached to a published message on its way through the broker, e.g. trace ids,
+/// tenant ids or MQTT 5 user properties. It is not a part of an MQTT 3.1.1 packet, so it's
+/// never sent to clients, but it travels with a message through Control and is stored
+/// together with messages queued for offline clients.
+///
+/// Similarly to MQTT 5 user properties, a key may appear more than once and the order of
+/// entries is preserved.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct PublishMetadata(Vec<(String, String)>);
+
+impl PublishMetadata {
+    pub fn new() -> Self {
+        PublishMetadata::default()
+    }
+
+    /// Sets a value of a key, replacing all existing values of it.
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        let key = key.into();
+        self.0.retain(|(k, _)| k != &key);
+        self.0.push((key, value.into()));
+    }
+
+    /// Adds a value of a key, keeping existing values of it.
+    pub fn append<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.0.push((key.into(), value.into()));
+    }
+
+    /// Returns the first value of a key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// MQTT 5.0 user properties of a PUBLISH packet.
+impl From<&Properties> for PublishMetadata {
+    fn from(properties: &Properties) -> Self {
+        let mut metadata = PublishMetadata::new();
+        for (key, value) in properties.user_properties() {
+            metadata.append(key, value);
+        }
+        metadata
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_append() {
+        let mut metadata = PublishMetadata::new();
+        assert!(metadata.is_empty());
+
+        metadata.append("trace_id", "1");
+        metadata.append("tenant", "a");
+        metadata.append("trace_id", "2");
+        assert_eq!(metadata.get("trace_id"), Some("1"));
+        assert_eq!(
+            metadata.iter().collect::<Vec<_>>(),
+            vec![("trace_id", "1"), ("tenant", "a"), ("trace_id", "2")]
+        );
+
+        metadata.insert("trace_id", "3");
+        assert_eq!(
+            metadata.iter().collect::<Vec<_>>(),
+            vec![("tenant", "a"), ("trace_id", "3")]
+        );
+        assert_eq!(metadata.get("unknown"), None);
+    }
+
+    #[test]
+    fn serialized_as_list_of_pairs() {
+        let mut metadata = PublishMetadata::new();
+        metadata.append("tenant", "a");
+
+        let json = serde_json::to_string(&metadata).unwrap();
+        assert_eq!(json, r#"[["tenant","a"]]"#);
+        assert_eq!(
+            serde_json::from_str::<PublishMetadata>(&json).unwrap(),
+            metadata
+        );
+    }
+}
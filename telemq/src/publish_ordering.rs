@@ -0,0 +1,166 @@
+//! Per-publisher ordering of PUBLISH messages.
+//!
+//! TeleMQ guarantees that messages published over the same network connection are
+//! dispatched to every subscriber in the order the broker has received them. Each
+//! `Connection` assigns a sequence number to every message it passes to Control
+//! (`PublishSequencer`), and Control dispatches messages only in a sequence order
+//! (`PublishOrdering`), holding back a message if any preceding one hasn't arrived yet.
+//! Since a channel between Control and a subscriber connection is FIFO, the order is
+//! preserved up to the subscriber, regardless of how messages reach Control.
+//!
+//! Messages produced by the broker itself (will messages, $SYS topics, in-process
+//! publishes) have no sequence number and are dispatched straight away.
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+};
+
+use log::warn;
+
+pub type PublishSequence = u64;
+
+/// Generates sequence numbers for messages of a single publisher connection.
+#[derive(Debug, Default)]
+pub struct PublishSequencer {
+    next: PublishSequence,
+}
+
+impl PublishSequencer {
+    pub fn next(&mut self) -> PublishSequence {
+        let sequence = self.next;
+        self.next += 1;
+        sequence
+    }
+}
+
+#[derive(Debug)]
+struct PublisherQueue<T> {
+    next_expected: PublishSequence,
+    pending: BTreeMap<PublishSequence, T>,
+}
+
+impl<T> Default for PublisherQueue<T> {
+    fn default() -> Self {
+        PublisherQueue {
+            next_expected: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+}
+
+/// Restores a publisher order of messages before they are dispatched to subscribers.
+#[derive(Debug)]
+pub struct PublishOrdering<T> {
+    publishers: HashMap<SocketAddr, PublisherQueue<T>>,
+}
+
+impl<T> Default for PublishOrdering<T> {
+    fn default() -> Self {
+        PublishOrdering {
+            publishers: HashMap::new(),
+        }
+    }
+}
+
+impl<T> PublishOrdering<T> {
+    /// Accepts a message with a given sequence number and returns messages of the
+    /// publisher which are ready to be dispatched, in the publisher order.
+    pub fn accept(
+        &mut self,
+        publisher: SocketAddr,
+        sequence: PublishSequence,
+        message: T,
+    ) -> Vec<T> {
+        let queue = self.publishers.entry(publisher).or_default();
+
+        if sequence < queue.next_expected || queue.pending.contains_key(&sequence) {
+            warn!(
+                "[Publish Ordering]: duplicate sequence number {} from {:?}, message is dropped",
+                sequence, publisher
+            );
+            return vec![];
+        }
+
+        queue.pending.insert(sequence, message);
+
+        let mut ready = vec![];
+        while let Some(message) = queue.pending.remove(&queue.next_expected) {
+            ready.push(message);
+            queue.next_expected += 1;
+        }
+
+        ready
+    }
+
+    /// Forgets a publisher (e.g. once its connection is closed) and returns messages
+    /// which have been held back, in the publisher order.
+    pub fn remove_publisher(&mut self, publisher: &SocketAddr) -> Vec<T> {
+        self.publishers
+            .remove(publisher)
+            .map(|queue| queue.pending.into_values().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn sequencer_is_monotonic() {
+        let mut sequencer = PublishSequencer::default();
+        assert_eq!(
+            (0..3).map(|_| sequencer.next()).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn in_order_messages_are_dispatched_immediately() {
+        let mut ordering = PublishOrdering::default();
+        assert_eq!(ordering.accept(addr(1), 0, "a"), vec!["a"]);
+        assert_eq!(ordering.accept(addr(1), 1, "b"), vec!["b"]);
+        assert_eq!(ordering.accept(addr(1), 2, "c"), vec!["c"]);
+    }
+
+    #[test]
+    fn out_of_order_messages_are_held_back() {
+        let mut ordering = PublishOrdering::default();
+        assert!(ordering.accept(addr(1), 2, "c").is_empty());
+        assert!(ordering.accept(addr(1), 1, "b").is_empty());
+        assert_eq!(ordering.accept(addr(1), 0, "a"), vec!["a", "b", "c"]);
+        assert_eq!(ordering.accept(addr(1), 3, "d"), vec!["d"]);
+    }
+
+    #[test]
+    fn publishers_are_ordered_independently() {
+        let mut ordering = PublishOrdering::default();
+        assert!(ordering.accept(addr(1), 1, "1b").is_empty());
+        assert_eq!(ordering.accept(addr(2), 0, "2a"), vec!["2a"]);
+        assert_eq!(ordering.accept(addr(1), 0, "1a"), vec!["1a", "1b"]);
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let mut ordering = PublishOrdering::default();
+        assert_eq!(ordering.accept(addr(1), 0, "a"), vec!["a"]);
+        assert!(ordering.accept(addr(1), 0, "a").is_empty());
+        assert!(ordering.accept(addr(1), 2, "c").is_empty());
+        assert!(ordering.accept(addr(1), 2, "c").is_empty());
+        assert_eq!(ordering.accept(addr(1), 1, "b"), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn removed_publisher_flushes_held_back_messages() {
+        let mut ordering = PublishOrdering::default();
+        assert!(ordering.accept(addr(1), 3, "d").is_empty());
+        assert!(ordering.accept(addr(1), 1, "b").is_empty());
+        assert_eq!(ordering.remove_publisher(&addr(1)), vec!["b", "d"]);
+        // a new connection from the same address starts from scratch
+        assert_eq!(ordering.accept(addr(1), 0, "a"), vec!["a"]);
+    }
+}
@@ -0,0 +1,83 @@
+//! Depth of the broker-side send queue of a connection reported to its client, so a constrained
+//! device can slow down its publish rate while the broker is behind delivering messages to it.
+//!
+//! A client publishes any message to `queue_depth_request_topic` and receives a QoS 0 reply on
+//! `{queue_depth_request_topic}/{client_id}`. The reply is sent straight to the client,
+//! regardless of its subscriptions, and the request is not routed to subscribers. The payload
+//! is a number of messages waiting to be sent to the client: messages in the channel of
+//! the connection and messages waiting for room in the in-flight window.
+use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic, ControlPacket};
+
+use crate::config::TeleMQServerConfig;
+
+#[derive(Debug, Clone)]
+pub struct QueueDepth {
+    request_topic: String,
+}
+
+impl QueueDepth {
+    /// `None` if queue depth requests are not enabled.
+    pub fn new(config: &TeleMQServerConfig) -> Option<Self> {
+        config
+            .queue_depth_request_topic
+            .as_ref()
+            .map(|request_topic| QueueDepth {
+                request_topic: request_topic.clone(),
+            })
+    }
+
+    pub fn is_request(&self, topic: &Topic) -> bool {
+        topic.original == self.request_topic
+    }
+
+    /// A reply with a queue depth. Client ids with wildcards can't be a topic level, so their
+    /// requests are not answered.
+    pub fn reply(&self, client_id: &str, depth: usize) -> Option<ControlPacket> {
+        let topic = Topic::try_from(format!("{}/{}", self.request_topic, client_id)).ok()?;
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(topic)
+            .with_payload(depth.to_string().into_bytes());
+        Some(builder.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_packets::v_3_1_1::variable::Variable;
+
+    fn queue_depth() -> QueueDepth {
+        let config = TeleMQServerConfig {
+            queue_depth_request_topic: Some("$SYS/broker/queue_depth".into()),
+            ..TeleMQServerConfig::default()
+        };
+        QueueDepth::new(&config).unwrap()
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        assert!(QueueDepth::new(&TeleMQServerConfig::default()).is_none());
+    }
+
+    #[test]
+    fn depth_is_replied_on_topic_of_client() {
+        let queue_depth = queue_depth();
+        assert!(queue_depth.is_request(&Topic::make_from_string("$SYS/broker/queue_depth")));
+        assert!(!queue_depth.is_request(&Topic::make_from_string("$SYS/broker/queue_depth/c")));
+
+        let reply = queue_depth.reply("device-1", 42).unwrap();
+        match reply.variable {
+            Variable::Publish(ref variable) => {
+                assert_eq!(
+                    variable.topic_name.original,
+                    "$SYS/broker/queue_depth/device-1"
+                );
+                assert_eq!(&variable.payload[..], b"42");
+            }
+            _ => panic!("Publish is expected"),
+        }
+
+        assert!(queue_depth.reply("device/+", 0).is_none());
+    }
+}
@@ -0,0 +1,230 @@
+//! Quiet hours of groups of clients, e.g. battery powered devices which should not be woken up
+//! by telemetry overnight.
+//!
+//! While a `quiet_hours` window of a client is open, its connection holds messages which are
+//! not `high` priority (see `priority_topics`) instead of sending them, and sends them by
+//! priority once the window ends. High priority messages, e.g. alarms or commands, are sent
+//! right away. Messages held for a persistent session which is closed in the meantime are
+//! queued for it like messages of an offline client.
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use mqtt_packets::v_3_1_1::ControlPacket;
+
+use crate::{
+    config::{Priority, TeleMQServerConfig},
+    priority::PriorityTopics,
+};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Minutes since midnight of a time of day, `HH:MM`.
+pub fn minute_of_day(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+
+    Some(hours * 60 + minutes)
+}
+
+#[derive(Debug)]
+struct Window {
+    tag: String,
+    client_id_prefix: Option<String>,
+    /// seconds since midnight, UTC
+    start: u64,
+    end: u64,
+}
+
+impl Window {
+    fn applies(&self, client_id: &str) -> bool {
+        self.client_id_prefix
+            .as_ref()
+            .is_none_or(|prefix| client_id.starts_with(prefix.as_str()))
+    }
+
+    /// Time left until the window ends, `None` if it's not open at a second of a day.
+    fn remaining(&self, second_of_day: u64) -> Option<u64> {
+        let is_open = if self.start < self.end {
+            self.start <= second_of_day && second_of_day < self.end
+        } else {
+            // spans midnight
+            self.start <= second_of_day || second_of_day < self.end
+        };
+        is_open.then(|| (self.end + SECONDS_PER_DAY - second_of_day) % SECONDS_PER_DAY)
+    }
+}
+
+/// Quiet hours windows, shared by connections.
+#[derive(Debug, Clone, Default)]
+pub struct QuietHours {
+    windows: Arc<Vec<Window>>,
+    priority_topics: PriorityTopics,
+    /// Held messages above it are dropped, the oldest first.
+    max_held_messages: Option<usize>,
+}
+
+impl QuietHours {
+    pub fn new(config: &TeleMQServerConfig, priority_topics: PriorityTopics) -> Self {
+        QuietHours {
+            // times are validated by the config
+            windows: Arc::new(
+                config
+                    .quiet_hours
+                    .iter()
+                    .filter_map(|window| {
+                        let start = minute_of_day(&window.start)?;
+                        let end = minute_of_day(&window.end)?;
+                        Some(Window {
+                            tag: window.tag.clone(),
+                            client_id_prefix: window.client_id_prefix.clone(),
+                            start: start as u64 * 60,
+                            end: end as u64 * 60,
+                        })
+                    })
+                    .collect(),
+            ),
+            priority_topics,
+            max_held_messages: config.max_queued_messages_per_client,
+        }
+    }
+
+    /// Whether windows of a client can hold its messages at all.
+    pub fn applies(&self, client_id: &str) -> bool {
+        self.windows.iter().any(|window| window.applies(client_id))
+    }
+
+    /// Whether a message is held while a window is open, i.e. it's not `high` priority.
+    pub fn holds(&self, packet: &ControlPacket) -> bool {
+        self.priority_topics.priority(packet) != Priority::High
+    }
+
+    /// A tag of an open window of a client and time left until it ends. If several windows
+    /// are open, the one ending last is picked.
+    pub fn open_window(&self, client_id: &str, now: SystemTime) -> Option<(&str, Duration)> {
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let second_of_day = since_epoch.as_secs() % SECONDS_PER_DAY;
+        self.windows
+            .iter()
+            .filter(|window| window.applies(client_id))
+            .filter_map(|window| {
+                window
+                    .remaining(second_of_day)
+                    .map(|remaining| (window.tag.as_str(), remaining))
+            })
+            .max_by_key(|(_, remaining)| *remaining)
+            .map(|(tag, remaining)| {
+                let remaining = Duration::from_secs(remaining)
+                    .saturating_sub(Duration::from_nanos(since_epoch.subsec_nanos() as u64));
+                (tag, remaining)
+            })
+    }
+
+    pub fn max_held_messages(&self) -> Option<usize> {
+        self.max_held_messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PriorityTopicConfig, QuietHoursConfig};
+    use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic};
+
+    fn quiet_hours() -> QuietHours {
+        let config = TeleMQServerConfig {
+            quiet_hours: vec![
+                QuietHoursConfig {
+                    tag: "battery".into(),
+                    client_id_prefix: Some("sensor-".into()),
+                    start: "22:00".into(),
+                    end: "06:30".into(),
+                },
+                QuietHoursConfig {
+                    tag: "maintenance".into(),
+                    client_id_prefix: None,
+                    start: "03:00".into(),
+                    end: "04:00".into(),
+                },
+            ],
+            priority_topics: vec![PriorityTopicConfig {
+                filter: "alarms/#".into(),
+                priority: Priority::High,
+            }],
+            ..TeleMQServerConfig::default()
+        };
+        QuietHours::new(&config, PriorityTopics::new(&config))
+    }
+
+    fn at(hours: u64, minutes: u64) -> SystemTime {
+        // 2023-11-14 00:00:00 UTC
+        UNIX_EPOCH + Duration::from_secs(1_699_920_000 + hours * 3600 + minutes * 60)
+    }
+
+    fn publish(topic: &str) -> ControlPacket {
+        let mut builder = PublishPacketBuilder::new();
+        builder.with_topic(Topic::try_from(topic).unwrap());
+        builder.build()
+    }
+
+    #[test]
+    fn times_of_day_are_parsed() {
+        assert_eq!(minute_of_day("00:00"), Some(0));
+        assert_eq!(minute_of_day("06:30"), Some(390));
+        assert_eq!(minute_of_day("23:59"), Some(1439));
+        assert_eq!(minute_of_day("24:00"), None);
+        assert_eq!(minute_of_day("6:30"), None);
+        assert_eq!(minute_of_day("06:60"), None);
+        assert_eq!(minute_of_day("0630"), None);
+    }
+
+    #[test]
+    fn windows_spanning_midnight_are_open_until_they_end() {
+        let quiet_hours = quiet_hours();
+
+        assert_eq!(quiet_hours.open_window("sensor-1", at(21, 59)), None);
+        assert_eq!(
+            quiet_hours.open_window("sensor-1", at(22, 0)),
+            Some(("battery", Duration::from_secs(8 * 3600 + 30 * 60)))
+        );
+        assert_eq!(
+            quiet_hours.open_window("sensor-1", at(6, 0)),
+            Some(("battery", Duration::from_secs(30 * 60)))
+        );
+        assert_eq!(quiet_hours.open_window("sensor-1", at(6, 30)), None);
+    }
+
+    #[test]
+    fn windows_apply_to_clients_by_prefix() {
+        let quiet_hours = quiet_hours();
+
+        assert!(quiet_hours.applies("sensor-1"));
+        assert!(quiet_hours.applies("gateway-1"));
+        assert_eq!(quiet_hours.open_window("gateway-1", at(23, 0)), None);
+        assert_eq!(
+            quiet_hours.open_window("gateway-1", at(3, 15)),
+            Some(("maintenance", Duration::from_secs(45 * 60)))
+        );
+        // both windows are open, the one ending last holds messages
+        assert_eq!(
+            quiet_hours.open_window("sensor-1", at(3, 15)),
+            Some(("battery", Duration::from_secs(3 * 3600 + 15 * 60)))
+        );
+        assert!(!QuietHours::default().applies("sensor-1"));
+    }
+
+    #[test]
+    fn high_priority_messages_are_not_held() {
+        let quiet_hours = quiet_hours();
+
+        assert!(!quiet_hours.holds(&publish("alarms/1")));
+        assert!(quiet_hours.holds(&publish("telemetry/1")));
+    }
+}
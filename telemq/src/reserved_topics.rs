@@ -0,0 +1,116 @@
+//! `$telemq/...` is reserved for control topics of broker extensions, e.g. delayed publish,
+//! diagnostics or a rule engine. Clients without an ACL may neither publish nor subscribe
+//! there, clients with an ACL need a rule naming the namespace explicitly, since `#` and `+`
+//! at the first level don't match topics starting with `$`.
+//!
+//! Topics of a fleet which already use the namespace keep working for clients granted by an
+//! ACL. The broker looks for them at startup and logs a warning for every collision.
+use std::collections::HashMap;
+
+use log::warn;
+use mqtt_packets::v_3_1_1::{topic::Subscription, variable::Variable};
+
+use crate::{retained_store::RetainedStore, session_state::SessionConnectedState};
+
+pub const RESERVED_NAMESPACE: &str = "$telemq";
+
+/// Whether a topic or a topic filter is within the reserved namespace.
+pub fn is_reserved(path: &[String]) -> bool {
+    path.first().is_some_and(|level| level == RESERVED_NAMESPACE)
+}
+
+/// Subscriptions of stored sessions and retained messages within the reserved namespace.
+pub fn find_collisions(
+    sessions: &HashMap<String, SessionConnectedState>,
+    retained_store: &mut RetainedStore,
+) -> Vec<String> {
+    let mut collisions: Vec<String> = sessions
+        .values()
+        .flat_map(|session| {
+            session
+                .subscriptions
+                .iter()
+                .filter(|(_, filter)| is_reserved(&filter.path))
+                .map(move |(_, filter)| {
+                    format!(
+                        "client {:?} is subscribed to {:?}",
+                        session.client_id, filter.original
+                    )
+                })
+        })
+        .collect();
+
+    // the filter is valid, it's a constant
+    let namespace_filter = format!("{}/#", RESERVED_NAMESPACE);
+    let namespace = Subscription::try_from(namespace_filter.as_str()).unwrap();
+    for message in retained_store.matching(&namespace) {
+        if let Variable::Publish(variable) = message.packet.variable {
+            collisions.push(format!(
+                "{:?} has a retained message",
+                variable.topic_name.original
+            ));
+        }
+    }
+    collisions.sort();
+
+    collisions
+}
+
+pub fn warn_about_collisions(collisions: &[String]) {
+    for collision in collisions {
+        warn!(
+            "[Control Worker]: {} within the reserved namespace {}/. Only clients granted by an ACL can use it",
+            collision, RESERVED_NAMESPACE
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic, QoS};
+
+    use super::*;
+    use crate::{clock::Clock, publish_metadata::PublishMetadata};
+
+    fn path(topic: &str) -> Vec<String> {
+        Topic::make_from_string(topic).path
+    }
+
+    #[test]
+    fn namespace_is_matched_by_first_level() {
+        assert!(is_reserved(&path("$telemq")));
+        assert!(is_reserved(&path("$telemq/delayed/60/a")));
+        assert!(!is_reserved(&path("telemq/a")));
+        assert!(!is_reserved(&path("$telemqx/a")));
+        assert!(!is_reserved(&path("a/$telemq")));
+        assert!(!is_reserved(&path("#")));
+    }
+
+    #[test]
+    fn collisions_of_sessions_and_retained_messages_are_found() {
+        let mut session = SessionConnectedState::new("device-1".into(), false, None, None, None);
+        session.subscriptions = vec![
+            (QoS::One, Subscription::try_from("$telemq/rules/#").unwrap()),
+            (QoS::One, Subscription::try_from("devices/#").unwrap()),
+        ];
+        let sessions = HashMap::from([("device-1".to_string(), session)]);
+
+        let mut retained_store = RetainedStore::new(None, None, Clock::system());
+        for topic in ["$telemq/diag", "devices/1"] {
+            let mut builder = PublishPacketBuilder::new();
+            builder
+                .with_topic(Topic::make_from_string(topic))
+                .with_payload(b"1".to_vec())
+                .with_retained(true);
+            retained_store.set(&builder.build(), &PublishMetadata::default());
+        }
+
+        assert_eq!(
+            find_collisions(&sessions, &mut retained_store),
+            vec![
+                "\"$telemq/diag\" has a retained message".to_string(),
+                "client \"device-1\" is subscribed to \"$telemq/rules/#\"".to_string(),
+            ]
+        );
+    }
+}
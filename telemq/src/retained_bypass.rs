@@ -0,0 +1,139 @@
+//! Broker-side policy of retained messages sent to new subscriptions.
+//!
+//! Clients which reconnect frequently (e.g. mobile apps) and subscribe to wide filters receive
+//! all matching retained messages every time. `retained_bypass` rules suppress them either
+//! always or when a persistent session already has the subscription, like Retain Handling of
+//! MQTT 5 subscription options does for a client which asks for it.
+use mqtt_packets::v_3_1_1::topic::Subscription;
+
+use crate::{
+    config::{RetainHandling, RetainedBypassConfig},
+    retained_store::RetainedMessage,
+};
+
+#[derive(Debug)]
+struct Rule {
+    filter: Option<Subscription>,
+    client_id_prefix: Option<String>,
+    retain_handling: RetainHandling,
+}
+
+impl Rule {
+    fn applies(&self, client_id: &str, message: &RetainedMessage) -> bool {
+        self.client_id_prefix
+            .as_ref()
+            .is_none_or(|prefix| client_id.starts_with(prefix.as_str()))
+            && self
+                .filter
+                .as_ref()
+                .is_none_or(|filter| message.topic_matches(filter))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RetainedBypass {
+    rules: Vec<Rule>,
+}
+
+impl RetainedBypass {
+    pub fn new(configs: &[RetainedBypassConfig]) -> Self {
+        let rules = configs
+            .iter()
+            .map(|config| Rule {
+                // filters are validated with the config
+                filter: config
+                    .filter
+                    .as_ref()
+                    .and_then(|filter| Subscription::try_from(filter).ok()),
+                client_id_prefix: config.client_id_prefix.clone(),
+                retain_handling: config.retain_handling.unwrap_or(RetainHandling::DoNotSend),
+            })
+            .collect();
+
+        RetainedBypass { rules }
+    }
+
+    /// Whether a retained message is sent to a client which has just subscribed. If several
+    /// rules apply, the most restrictive one wins.
+    pub fn allows(&self, client_id: &str, message: &RetainedMessage, is_new: bool) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| rule.applies(client_id, message))
+            .all(|rule| match rule.retain_handling {
+                RetainHandling::SendIfNew => is_new,
+                RetainHandling::DoNotSend => false,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic};
+    use std::time::SystemTime;
+
+    fn retained(topic: &str) -> RetainedMessage {
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(Topic::try_from(topic).unwrap())
+            .with_payload(b"on".to_vec())
+            .with_retained(true);
+        RetainedMessage {
+            packet: builder.build(),
+            metadata: Default::default(),
+            retained_at: SystemTime::now(),
+        }
+    }
+
+    fn rule(
+        filter: Option<&str>,
+        client_id_prefix: Option<&str>,
+        retain_handling: Option<RetainHandling>,
+    ) -> RetainedBypassConfig {
+        RetainedBypassConfig {
+            filter: filter.map(String::from),
+            client_id_prefix: client_id_prefix.map(String::from),
+            retain_handling,
+        }
+    }
+
+    #[test]
+    fn everything_is_sent_without_rules() {
+        let bypass = RetainedBypass::default();
+
+        assert!(bypass.allows("MOBILE_1", &retained("devices/1/state"), false));
+    }
+
+    #[test]
+    fn filter_and_prefix_have_to_match() {
+        let bypass = RetainedBypass::new(&[rule(Some("devices/+/state"), Some("mobile-"), None)]);
+
+        assert!(!bypass.allows("mobile-1", &retained("devices/1/state"), true));
+        assert!(bypass.allows("dashboard", &retained("devices/1/state"), true));
+        assert!(bypass.allows("mobile-1", &retained("devices/1/config"), true));
+    }
+
+    #[test]
+    fn send_if_new_skips_existing_subscriptions() {
+        let bypass =
+            RetainedBypass::new(&[rule(None, Some("mobile-"), Some(RetainHandling::SendIfNew))]);
+
+        assert!(bypass.allows("mobile-1", &retained("devices/1/state"), true));
+        assert!(!bypass.allows("mobile-1", &retained("devices/1/state"), false));
+    }
+
+    #[test]
+    fn most_restrictive_rule_wins() {
+        let bypass = RetainedBypass::new(&[
+            rule(Some("devices/#"), None, Some(RetainHandling::SendIfNew)),
+            rule(
+                Some("devices/+/state"),
+                None,
+                Some(RetainHandling::DoNotSend),
+            ),
+        ]);
+
+        assert!(!bypass.allows("mobile-1", &retained("devices/1/state"), true));
+        assert!(bypass.allows("mobile-1", &retained("devices/1/config"), true));
+    }
+}
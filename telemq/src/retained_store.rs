@@ -0,0 +1,323 @@
+use crate::{
+    clock::Clock,
+    publish_metadata::PublishMetadata,
+    store_check::{read_entries, warn_about_corrupted, StoreEntries},
+};
+use log::{error, info};
+use mqtt_packets::v_3_1_1::{
+    publish::fixed_header::{get_qos_level, set_qos_level},
+    topic::Subscription,
+    variable::Variable,
+    ControlPacket, QoS,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::to_vec;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+/// Retained message of a topic.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetainedMessage {
+    pub packet: ControlPacket,
+    #[serde(default)]
+    pub metadata: PublishMetadata,
+    /// Time the message has been retained.
+    pub retained_at: SystemTime,
+}
+
+impl RetainedMessage {
+    pub fn topic_matches(&self, filter: &Subscription) -> bool {
+        match self.packet.variable {
+            Variable::Publish(ref variable) => filter.topic_matches(&variable.topic_name),
+            _ => false,
+        }
+    }
+
+    /// Downgrades the message to `max_qos` if it has been published with a higher QoS.
+    pub fn cap_qos(&mut self, max_qos: &QoS) {
+        match get_qos_level(&self.packet.fixed_header) {
+            Ok(qos) if &qos > max_qos => set_qos_level(&mut self.packet.fixed_header, max_qos),
+            _ => {}
+        }
+    }
+}
+
+/// Store of retained messages, at most one message per topic.
+///
+/// A retained message replaces a previous one of the same topic, and a retained message with
+/// an empty payload removes it (MQTT 3.1.1, section 3.3.1.3). Messages older than `max_age`
+/// are dropped. If `file_path` is provided, the store is recovered from the file when it's
+/// created and written to the file by `commit` (during a graceful shut down), similarly to
+/// `SessionStateStore`.
+#[derive(Debug)]
+pub struct RetainedStore {
+    messages: HashMap<String, RetainedMessage>,
+    max_age: Option<Duration>,
+    file_path: Option<String>,
+    clock: Clock,
+}
+
+impl RetainedStore {
+    pub fn new(
+        max_age: Option<Duration>,
+        file_path: Option<String>,
+        clock: Clock,
+    ) -> RetainedStore {
+        let messages = match file_path {
+            Some(ref file_path) => Self::read_file(file_path),
+            None => HashMap::new(),
+        };
+
+        RetainedStore {
+            messages,
+            max_age,
+            file_path,
+            clock,
+        }
+    }
+
+    /// Stores, replaces or removes a retained message of a topic of a PUBLISH packet.
+    pub fn set(&mut self, packet: &ControlPacket, metadata: &PublishMetadata) {
+        let variable = match packet.variable {
+            Variable::Publish(ref variable) => variable,
+            _ => return,
+        };
+
+        if variable.payload.is_empty() {
+            self.messages.remove(&variable.topic_name.original);
+        } else {
+            self.messages.insert(
+                variable.topic_name.original.clone(),
+                RetainedMessage {
+                    packet: packet.clone(),
+                    metadata: metadata.clone(),
+                    retained_at: self.clock.now(),
+                },
+            );
+        }
+    }
+
+    /// Returns retained messages which topics match a topic filter.
+    pub fn matching(&mut self, filter: &Subscription) -> Vec<RetainedMessage> {
+        self.remove_expired();
+
+        self.messages
+            .values()
+            .filter(|message| message.topic_matches(filter))
+            .cloned()
+            .collect()
+    }
+
+    /// Removes retained messages which topics match a topic filter and returns their number.
+    pub fn purge(&mut self, filter: &Subscription) -> usize {
+        let before = self.messages.len();
+        self.messages
+            .retain(|_, message| !message.topic_matches(filter));
+
+        before - self.messages.len()
+    }
+
+    /// Re-reads the store from its file, e.g. after another broker process has handed over.
+    pub fn reload(&mut self) {
+        if let Some(ref file_path) = self.file_path {
+            self.messages = Self::read_file(file_path);
+        }
+    }
+
+    /// Writes the store to its file, does nothing if the store is not persistent.
+    pub fn commit(&self) -> io::Result<()> {
+        let file_path = match self.file_path {
+            Some(ref file_path) => file_path,
+            None => return Ok(()),
+        };
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_path)?;
+        file.write_all(&to_vec(&self.messages).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unable to serialize retained messages",
+            )
+        })?)?;
+        file.sync_all()
+    }
+
+    fn remove_expired(&mut self) {
+        if let Some(max_age) = self.max_age {
+            let now = self.clock.now();
+            self.messages.retain(|_, message| {
+                now.duration_since(message.retained_at)
+                    .map(|age| age <= max_age)
+                    .unwrap_or(true)
+            });
+        }
+    }
+
+    fn read_file(file_path: &str) -> HashMap<String, RetainedMessage> {
+        match File::open(Path::new(file_path)) {
+            Ok(reader) => match read_entries(reader) {
+                Ok(StoreEntries {
+                    entries: messages,
+                    corrupted,
+                }) => {
+                    warn_about_corrupted("Retained Store", file_path, &corrupted);
+                    info!(
+                        "[Retained Store]: recovered from a local file {}",
+                        file_path
+                    );
+                    messages
+                }
+                Err(err) => {
+                    error!(
+                        "[Retained Store]: unable to parse data from file {}. {:?}. Continue using an empty store.",
+                        file_path, err
+                    );
+                    HashMap::new()
+                }
+            },
+            Err(_) => {
+                info!(
+                    "[Retained Store]: unable to find data file {}. Continue using an empty store.",
+                    file_path
+                );
+                HashMap::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_packets::v_3_1_1::{
+        builders::PublishPacketBuilder, publish::fixed_header::is_retained, topic::Topic,
+    };
+
+    fn retained(topic: &str, payload: &[u8]) -> ControlPacket {
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_retained(true)
+            .with_topic(Topic::make_from_string(topic))
+            .with_payload(payload.to_vec());
+        builder.build()
+    }
+
+    fn payloads(store: &mut RetainedStore, filter: &str) -> Vec<Vec<u8>> {
+        let mut payloads = store
+            .matching(&Subscription::try_from(filter).unwrap())
+            .into_iter()
+            .map(|message| match message.packet.variable {
+                Variable::Publish(variable) => variable.payload.to_vec(),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        payloads.sort();
+        payloads
+    }
+
+    #[test]
+    fn message_is_replaced_per_topic() {
+        let mut store = RetainedStore::new(None, None, Clock::system());
+        store.set(&retained("a/b", b"1"), &PublishMetadata::new());
+        store.set(&retained("a/c", b"2"), &PublishMetadata::new());
+        store.set(&retained("a/b", b"3"), &PublishMetadata::new());
+
+        assert_eq!(store.messages.len(), 2);
+        assert_eq!(payloads(&mut store, "a/b"), vec![b"3".to_vec()]);
+        assert_eq!(
+            payloads(&mut store, "a/+"),
+            vec![b"2".to_vec(), b"3".to_vec()]
+        );
+    }
+
+    #[test]
+    fn qos_is_capped() {
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_retained(true)
+            .with_qos(&QoS::Two)
+            .with_topic(Topic::make_from_string("a/b"))
+            .with_payload(b"1".to_vec());
+        let mut message = RetainedMessage {
+            packet: builder.build(),
+            metadata: PublishMetadata::new(),
+            retained_at: SystemTime::now(),
+        };
+
+        message.cap_qos(&QoS::One);
+        assert_eq!(
+            get_qos_level(&message.packet.fixed_header).unwrap(),
+            QoS::One
+        );
+        message.cap_qos(&QoS::Two);
+        assert_eq!(
+            get_qos_level(&message.packet.fixed_header).unwrap(),
+            QoS::One
+        );
+        assert!(is_retained(&message.packet.fixed_header));
+    }
+
+    #[test]
+    fn empty_payload_removes_message() {
+        let mut store = RetainedStore::new(None, None, Clock::system());
+        store.set(&retained("a/b", b"1"), &PublishMetadata::new());
+        store.set(&retained("a/b", b""), &PublishMetadata::new());
+
+        assert_eq!(store.messages.len(), 0);
+        assert!(payloads(&mut store, "#").is_empty());
+    }
+
+    #[test]
+    fn expired_messages_are_dropped() {
+        let clock = Clock::manual(SystemTime::UNIX_EPOCH);
+        let mut store = RetainedStore::new(Some(Duration::from_secs(60)), None, clock.clone());
+        store.set(&retained("a/b", b"1"), &PublishMetadata::new());
+        clock.advance(Duration::from_secs(30));
+        store.set(&retained("a/c", b"2"), &PublishMetadata::new());
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(
+            payloads(&mut store, "a/#"),
+            vec![b"1".to_vec(), b"2".to_vec()]
+        );
+
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(payloads(&mut store, "a/#"), vec![b"2".to_vec()]);
+        assert_eq!(store.messages.len(), 1);
+    }
+
+    #[test]
+    fn purge_by_filter() {
+        let mut store = RetainedStore::new(None, None, Clock::system());
+        store.set(&retained("a/b", b"1"), &PublishMetadata::new());
+        store.set(&retained("a/c", b"2"), &PublishMetadata::new());
+        store.set(&retained("b/c", b"3"), &PublishMetadata::new());
+
+        assert_eq!(store.purge(&Subscription::try_from("a/#").unwrap()), 2);
+        assert_eq!(payloads(&mut store, "#"), vec![b"3".to_vec()]);
+    }
+
+    #[test]
+    fn persisted_in_file() {
+        let file_path =
+            std::env::temp_dir().join(format!("telemq_retained_store_{}.json", std::process::id()));
+        let file_path = file_path.to_str().unwrap().to_string();
+
+        let mut store = RetainedStore::new(None, Some(file_path.clone()), Clock::system());
+        store.set(&retained("a/b", b"1"), &PublishMetadata::new());
+        store.commit().unwrap();
+
+        let mut recovered = RetainedStore::new(None, Some(file_path.clone()), Clock::system());
+        assert_eq!(payloads(&mut recovered, "a/b"), vec![b"1".to_vec()]);
+        let _ = std::fs::remove_file(file_path);
+    }
+}
@@ -0,0 +1,186 @@
+//! Subscribers resolved for recently published topics.
+//!
+//! Walking the subscription tree for every message dominates routing of high-rate telemetry,
+//! which is usually published to a few hot topics with stable sets of subscribers. The shared
+//! subscription tree keeps subscribers of the last `routing_cache_size` topics and looks a
+//! topic up in the tree only on a miss. The least recently used topic is evicted once the cache
+//! is full.
+//!
+//! A topic is invalidated when a topic filter which may match it is subscribed to or
+//! unsubscribed from. A disconnected subscriber is removed from all cached topics.
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use mqtt_packets::v_3_1_1::topic::{SINGLE_LEVEL_WILD_CARD, WILD_CARD};
+
+type PathStep = String;
+type ClientID = String;
+
+pub type Subscribers = Arc<HashSet<ClientID>>;
+
+#[derive(Debug)]
+pub struct RoutingCache {
+    capacity: usize,
+    next_use: u64,
+    /// Cached topics by their last use, the least recently used one comes first.
+    uses: BTreeMap<u64, Vec<PathStep>>,
+    entries: HashMap<Vec<PathStep>, (u64, Subscribers)>,
+}
+
+impl RoutingCache {
+    /// A cache of `capacity` 0 keeps nothing.
+    pub fn new(capacity: usize) -> Self {
+        RoutingCache {
+            capacity,
+            next_use: 0,
+            uses: BTreeMap::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, topic: &[PathStep]) -> Option<Subscribers> {
+        let next_use = self.next_use;
+        let (last_use, subscribers) = self.entries.get_mut(topic)?;
+        if let Some(topic) = self.uses.remove(last_use) {
+            self.uses.insert(next_use, topic);
+        }
+        *last_use = next_use;
+        self.next_use += 1;
+
+        Some(subscribers.clone())
+    }
+
+    pub fn insert(&mut self, topic: Vec<PathStep>, subscribers: Subscribers) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&topic);
+        while self.entries.len() >= self.capacity {
+            match self.uses.pop_first() {
+                Some((_, evicted)) => {
+                    self.entries.remove(&evicted);
+                }
+                None => break,
+            }
+        }
+
+        self.uses.insert(self.next_use, topic.clone());
+        self.entries.insert(topic, (self.next_use, subscribers));
+        self.next_use += 1;
+    }
+
+    /// Invalidates topics a subscription to `filter` may route to.
+    pub fn invalidate_filter(&mut self, filter: &[PathStep]) {
+        let invalidated: Vec<Vec<PathStep>> = self
+            .entries
+            .keys()
+            .filter(|topic| may_match(topic, filter))
+            .cloned()
+            .collect();
+        for topic in invalidated {
+            self.remove(&topic);
+        }
+    }
+
+    /// Removes a client which has lost all its subscriptions from cached topics.
+    pub fn remove_subscriber(&mut self, client_id: &ClientID) {
+        for (_, subscribers) in self.entries.values_mut() {
+            if subscribers.contains(client_id) {
+                Arc::make_mut(subscribers).remove(client_id);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.uses.clear();
+        self.entries.clear();
+    }
+
+    fn remove(&mut self, topic: &[PathStep]) {
+        if let Some((last_use, _)) = self.entries.remove(topic) {
+            self.uses.remove(&last_use);
+        }
+    }
+}
+
+/// Whether `filter` may match `topic`. It errs on the side of a match (e.g. `$SYS` topics are
+/// not treated specially), so a stale topic is never kept.
+fn may_match(topic: &[PathStep], filter: &[PathStep]) -> bool {
+    for (i, pattern) in filter.iter().enumerate() {
+        if pattern == WILD_CARD {
+            return true;
+        }
+        match topic.get(i) {
+            Some(level) if pattern == SINGLE_LEVEL_WILD_CARD || pattern == level => {}
+            _ => return false,
+        }
+    }
+
+    topic.len() == filter.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(topic: &str) -> Vec<PathStep> {
+        topic.split('/').map(String::from).collect()
+    }
+
+    fn subscribers(client_ids: &[&str]) -> Subscribers {
+        Arc::new(client_ids.iter().map(|id| id.to_string()).collect())
+    }
+
+    #[test]
+    fn least_recently_used_topic_is_evicted() {
+        let mut cache = RoutingCache::new(2);
+        cache.insert(path("a"), subscribers(&["1"]));
+        cache.insert(path("b"), subscribers(&["2"]));
+        assert!(cache.get(&path("a")).is_some());
+
+        cache.insert(path("c"), subscribers(&["3"]));
+
+        assert!(cache.get(&path("b")).is_none());
+        assert_eq!(cache.get(&path("a")), Some(subscribers(&["1"])));
+        assert_eq!(cache.get(&path("c")), Some(subscribers(&["3"])));
+    }
+
+    #[test]
+    fn topics_matching_filter_are_invalidated() {
+        let mut cache = RoutingCache::new(10);
+        for topic in ["a/b/c", "a/x/c", "a/b", "b/b/c", "$SYS/a"] {
+            cache.insert(path(topic), subscribers(&["1"]));
+        }
+
+        cache.invalidate_filter(&path("a/+/c"));
+        assert!(cache.get(&path("a/b/c")).is_none());
+        assert!(cache.get(&path("a/x/c")).is_none());
+        assert!(cache.get(&path("a/b")).is_some());
+
+        cache.invalidate_filter(&path("#"));
+        assert!(cache.get(&path("b/b/c")).is_none());
+        assert!(cache.get(&path("$SYS/a")).is_none());
+    }
+
+    #[test]
+    fn disconnected_subscriber_is_removed() {
+        let mut cache = RoutingCache::new(10);
+        cache.insert(path("a"), subscribers(&["1", "2"]));
+        cache.insert(path("b"), subscribers(&["2"]));
+
+        cache.remove_subscriber(&"2".into());
+
+        assert_eq!(cache.get(&path("a")), Some(subscribers(&["1"])));
+        assert_eq!(cache.get(&path("b")), Some(subscribers(&[])));
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut cache = RoutingCache::new(0);
+        cache.insert(path("a"), subscribers(&["1"]));
+
+        assert!(cache.get(&path("a")).is_none());
+    }
+}
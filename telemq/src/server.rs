@@ -1,39 +1,70 @@
 use std::{
     io,
     net::SocketAddr,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
-    time,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use crate::{
+    accept_backoff::{is_connection_error, AcceptBackoff},
     admin_api,
     authenticator::Authenticator,
+    bootstrap::Bootstrap,
+    bridge::Bridge,
+    broker_features::BrokerFeatures,
+    broker_handle::BrokerHandle,
+    broker_state::{announce, BrokerState},
+    cert_reload::CertReloader,
+    client_id_generator::{ClientIdGenerator, SchemeClientIdGenerator, SharedClientIdGenerator},
+    clock::Clock,
+    cluster::Cluster,
     config::TeleMQServerConfig,
     connection::Connection,
+    connection_channel::ChannelLimit,
+    connection_config::ConnectionConfig,
+    connection_gate::{is_allowed_by_gate, ConnectionGate, ConnectionMetadata},
+    connection_limit::ConnectionLimit,
+    connection_watchdog::ConnectionWatchdog,
     control::{Control, ControlMessage, ControlSender},
+    fd_limit::{check_open_files_limit, is_fd_exhaustion},
+    handover::{bind_tcp, set_reuse_port, HandoverClient, HandoverListener, HandoverPeer},
+    load_shedding::{LoadShedder, Overload, Watermarks},
+    mqtt_codec::MqttCodec,
+    mqttsn_listener::MqttSnGateway,
+    payload_format::PayloadFormats,
+    priority::PriorityTopics,
+    queue_depth::QueueDepth,
+    quiet_hours::QuietHours,
     server_error::ServerResult,
+    session_persistence::SessionPersistence,
     session_state_store::SessionStateStore,
-    stats::{Stats, StatsConfig, StatsSender},
-    tls_listener::TlsListener,
-    ws_listener::WsListener,
-    wss_listener::WssListener,
+    startup_wait::{probe_http, probe_tcp, wait_for},
+    stats::{Stats, StatsConfig, StatsMessage, StatsSender},
+    subscription_limits::SubscriptionLimits,
+    tls_fingerprint::Ja3Fingerprint,
+    tls_listener::{Alpn, TlsListener},
+    topic_normalization::TopicNormalization,
+    topic_prefix::TopicPrefix,
+    transaction::RetryPolicy,
+    transport_bytes::{CountingStream, ListenerTransportBytes},
+    webhook::Webhook,
+    ws_listener::{WsKeepAlive, WsListener, WsOptions},
+    wss_listener::{WssListener, ALPN_HTTP_1_1},
 };
 
+use futures::future::pending;
 use ipnet::IpNet;
-use log::{debug, error, info};
-use mqtt_packets::v_3_1_1::ControlPacketCodec;
-use signal_hook::{consts::signal::*, low_level::exit};
+use log::{debug, error, info, warn};
+use signal_hook::consts::signal::*;
 use signal_hook_tokio::{Handle, Signals};
 use tokio::{
     net::{TcpListener, TcpStream},
     select, spawn,
     sync::{
-        mpsc::{channel, Receiver, UnboundedSender},
+        mpsc::{channel, Receiver},
         RwLock,
     },
+    time::sleep,
 };
 use tokio_rustls::server::TlsStream;
 use tokio_stream::StreamExt;
@@ -43,136 +74,592 @@ pub struct Server {
     control_sender: ControlSender,
     stats_sender: StatsSender,
     config: TeleMQServerConfig,
-    authenticator: Arc<RwLock<Authenticator>>,
     state_store: Arc<RwLock<SessionStateStore>>,
     shut_down_channel: Receiver<()>,
-    connections_number: Arc<AtomicUsize>,
+    connection_limit: Arc<ConnectionLimit>,
+    handle_os_signals: bool,
+    connection_gate: Option<Arc<dyn ConnectionGate>>,
+    take_over: bool,
+    clock: Clock,
+    config_file: Option<PathBuf>,
+    strict_config: bool,
+    /// Settings of connections, listeners get copies with their own bandwidth limits and
+    /// topic prefixes.
+    connection_config: ConnectionConfig,
+    tcp_connection_config: ConnectionConfig,
+    tls_connection_config: ConnectionConfig,
+    /// `None` unless `transport_byte_counters` is enabled.
+    transport_bytes: Option<ListenerTransportBytes>,
+    /// `None` until TLS listeners are started.
+    cert_reloader: Option<Arc<CertReloader>>,
 }
 
-impl Server {
-    pub async fn new(config: TeleMQServerConfig) -> Option<Self> {
+/// `Server` builder. It allows to create a broker from a programmatic config,
+/// e.g. when TeleMQ is embedded into another application.
+pub struct ServerBuilder {
+    config: TeleMQServerConfig,
+    handle_os_signals: bool,
+    connection_gate: Option<Arc<dyn ConnectionGate>>,
+    client_id_generator: Option<SharedClientIdGenerator>,
+    take_over: bool,
+    clock: Clock,
+    config_file: Option<PathBuf>,
+    strict_config: bool,
+}
+
+impl ServerBuilder {
+    pub fn new(config: TeleMQServerConfig) -> Self {
+        ServerBuilder {
+            config,
+            handle_os_signals: true,
+            connection_gate: None,
+            client_id_generator: None,
+            take_over: false,
+            clock: Clock::system(),
+            config_file: None,
+            strict_config: false,
+        }
+    }
+
+    /// If `false`, the server won't install SIGHUP/SIGTERM/SIGINT/SIGQUIT handlers
+    /// and an embedding application is responsible for shutting the broker down via
+    /// `BrokerHandle::shut_down`. Default is `true`.
+    pub fn with_os_signals(mut self, handle_os_signals: bool) -> Self {
+        self.handle_os_signals = handle_os_signals;
+        self
+    }
+
+    /// Sets a custom policy which is checked for every accepted TCP and TLS connection.
+    pub fn with_connection_gate<G: ConnectionGate + 'static>(mut self, gate: G) -> Self {
+        self.connection_gate = Some(Arc::new(gate));
+        self
+    }
+
+    /// Sets a generator of client ids assigned to clients which connect with an empty client id,
+    /// instead of one of `assigned_client_id_scheme`.
+    pub fn with_client_id_generator<G: ClientIdGenerator + 'static>(
+        mut self,
+        generator: G,
+    ) -> Self {
+        self.client_id_generator = Some(Arc::new(generator));
+        self
+    }
+
+    /// If `true`, the server takes listeners and sessions over from a broker process
+    /// listening on `handover_socket` before it starts serving. Default is `false`.
+    pub fn with_take_over(mut self, take_over: bool) -> Self {
+        self.take_over = take_over;
+        self
+    }
+
+    /// Sets a clock used for timestamps and expiry of retained and queued messages, e.g. a manual
+    /// one which tests advance instead of sleeping. Default is the system clock.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// A file `config` has been read from. On SIGHUP it's read again and options which can be
+    /// changed at runtime (`max_connections` and `connection_drain_rate`) are applied.
+    pub fn with_config_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.config_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// If `true`, the config file is reloaded in the strict mode, as it has been read, so
+    /// unknown fields fail a reload. Default is `false`.
+    pub fn with_strict_config(mut self, strict_config: bool) -> Self {
+        self.strict_config = strict_config;
+        self
+    }
+
+    /// Spawns Control and Stats workers and returns a server which is ready to be started.
+    /// It should be called within a Tokio runtime.
+    pub async fn build(self) -> ServerResult<Server> {
+        let config = self.config;
+        wait_for_dependencies(&config).await?;
+        let authenticator = Arc::new(RwLock::new(
+            Authenticator::new(&config).map_err(|err| format!("{:?}", err))?,
+        ));
+
         let (shutdown_sender, shutdown_receiver) = channel(1);
-        let state_store = Arc::new(RwLock::new(SessionStateStore::new()));
+        let state_store = Arc::new(RwLock::new(
+            SessionStateStore::new(self.clock.clone())
+                .with_message_expiry_interval(config.message_expiry_interval),
+        ));
 
         let (control, control_sender) =
             Control::new(&config, state_store.clone(), shutdown_sender).await;
+        let subscription_tree = control.subscription_tree();
         spawn(async move {
             if let Err(err) = control.run().await {
                 error!("[Control Worker]: finished with error {:?}", err);
             }
         });
 
+        let (persistence, persistence_sender) =
+            SessionPersistence::new(state_store.clone(), control_sender.clone());
+        spawn(persistence.run());
+
+        let connection_limit = Arc::new(ConnectionLimit::new(config.max_connections));
+        let transport_bytes = config
+            .transport_byte_counters
+            .then(ListenerTransportBytes::default);
+        let webhook = Webhook::new(&config, self.clock.clone())?;
+        let bootstrap = Bootstrap::new(&config)?;
         let (stats, stats_sender) = Stats::new(StatsConfig {
             update_interval: config.sys_topics_update_interval,
+            payload_size_buckets: config.payload_size_buckets.clone(),
+            per_client: config.sys_topics_per_client,
             control_sender: control_sender.clone(),
+            connection_limit: connection_limit.clone(),
+            transport_bytes: transport_bytes.clone(),
+            webhook_sender: webhook.as_ref().map(|(_, sender)| sender.clone()),
+            bootstrap_sender: bootstrap.as_ref().map(|(_, sender)| sender.clone()),
         });
         spawn(async move {
             if let Err(err) = stats.run().await {
                 error!("[Stats Worker]: finished with error {:?}", err);
             }
         });
+        if let Some((webhook, _)) = webhook {
+            spawn(webhook.run(stats_sender.clone()));
+        }
+        if let Some((bootstrap, _)) = bootstrap {
+            spawn(bootstrap.run(control_sender.clone()));
+        }
 
-        let authenticator = Arc::new(RwLock::new(Authenticator::new(&config).ok()?));
+        let overload = Overload::default();
+        if let Some(watermarks) = Watermarks::new(&config) {
+            spawn(LoadShedder::new(watermarks, overload.clone(), control_sender.clone()).run());
+        }
+
+        let priority_topics = PriorityTopics::new(&config);
+        let connection_config = ConnectionConfig {
+            control_sender: control_sender.clone(),
+            stats_sender: stats_sender.clone(),
+            persistence_sender,
+            authenticator,
+            inactivity_interval: config.keep_alive,
+            state_store: state_store.clone(),
+            subscription_limits: SubscriptionLimits::new(&config),
+            priority_topics: priority_topics.clone(),
+            reject_on_session_recovery_failure: config.reject_on_session_recovery_failure,
+            publish_disconnect_reason: config.publish_disconnect_reason,
+            retry_policy: retry_policy(&config),
+            max_inflight_messages: config.max_inflight_messages,
+            channel_limit: ChannelLimit::new(&config),
+            bandwidth_limiter: None,
+            topic_prefix: TopicPrefix::default(),
+            topic_normalization: TopicNormalization::new(&config),
+            overload,
+            subscription_tree,
+            client_id_generator: self.client_id_generator.unwrap_or_else(|| {
+                Arc::new(SchemeClientIdGenerator::new(&config, self.clock.clone()))
+            }),
+            queue_depth: QueueDepth::new(&config),
+            quiet_hours: QuietHours::new(&config, priority_topics),
+            payload_formats: PayloadFormats::new(&config),
+        };
+        let tcp_connection_config =
+            connection_config.for_listener(config.tcp_bandwidth_limit, &config.tcp_topic_prefix);
+        let tls_connection_config =
+            connection_config.for_listener(config.tls_bandwidth_limit, &config.tls_topic_prefix);
 
-        Some(Server {
+        Ok(Server {
             control_sender,
             stats_sender,
             config,
-            authenticator,
             state_store,
             shut_down_channel: shutdown_receiver,
-            connections_number: Arc::new(AtomicUsize::new(0)),
+            connection_limit,
+            handle_os_signals: self.handle_os_signals,
+            connection_gate: self.connection_gate,
+            take_over: self.take_over,
+            clock: self.clock,
+            config_file: self.config_file,
+            strict_config: self.strict_config,
+            connection_config,
+            tcp_connection_config,
+            tls_connection_config,
+            transport_bytes,
+            cert_reloader: None,
         })
     }
+}
+
+impl Server {
+    pub async fn new(config: TeleMQServerConfig) -> ServerResult<Self> {
+        ServerBuilder::new(config).build().await
+    }
+
+    /// Returns a handle which can be used to publish and subscribe in-process.
+    pub fn handle(&self) -> BrokerHandle {
+        BrokerHandle::new(self.control_sender.clone(), self.clock.clone())
+    }
 
+    /// Starts listeners and serves connections until the broker is shut down.
     pub async fn start(mut self) -> ServerResult<()> {
-        let tcp_listener = TcpListener::bind(&self.config.tcp_addr).await?;
-        println!("TCP Listener is listening on {:?}", self.config.tcp_addr);
+        let handover_client = match (self.take_over, &self.config.handover_socket) {
+            (true, Some(path)) => Some(HandoverClient::connect(path).await?),
+            (true, None) => {
+                return Err("Unable to take over, handover_socket is not configured".into());
+            }
+            (false, _) => None,
+        };
+
+        if let Some(limit) = check_open_files_limit(self.config.max_connections) {
+            if let Err(err) = self
+                .stats_sender
+                .send(StatsMessage::OpenFilesLimit { limit })
+            {
+                error!(
+                    "[Server Worker]: unable to send StatsMessage::OpenFilesLimit. {:?}",
+                    err
+                );
+            }
+        }
+
+        // listeners are shared with the broker process which is being taken over
+        let reuse_port = handover_client.is_some();
+        let tcp_listener = bind_tcp(self.config.tcp_addr, reuse_port)?;
+        info!("TCP Listener is listening on {:?}", self.config.tcp_addr);
 
+        let mut cert_reloader = CertReloader::new(&self.config);
+        let tls_config = match (self.config.tls_addr, cert_reloader.as_mut()) {
+            (Some(_), Some(reloader)) => {
+                Some(reloader.listener_config(Alpn::new(&self.config).protocol_ids())?)
+            }
+            _ => None,
+        };
         let tls_listener = TlsListener::new(
             self.config.tls_addr.clone(),
-            &self.config.cert_file,
-            &self.config.key_file,
+            tls_config,
             self.config.keep_alive.clone(),
+            reuse_port,
+            self.transport_bytes.as_ref().map(|bytes| bytes.tls.clone()),
+            self.config.tls_fingerprint,
+            Alpn::new(&self.config),
         )
         .await?;
 
         if let Some(tls_addr) = self.config.tls_addr {
-            println!("TLS Listener is listening on {:?}", tls_addr);
+            info!("TLS Listener is listening on {:?}", tls_addr);
         }
 
+        if let Some(handover_client) = handover_client {
+            if let Err(err) = handover_client.take_over().await {
+                error!(
+                    "[Server Worker]: previous broker process has not completed the handover. {:?}",
+                    err
+                );
+            }
+            set_reuse_port(&shared_listeners(&tcp_listener, &tls_listener), false)?;
+            self.reload_sessions().await?;
+        }
+
+        let handover_listener = match self.config.handover_socket {
+            Some(ref path) => Some(HandoverListener::bind(path)?),
+            None => None,
+        };
+
         if let Some(web_addr) = self.config.ws_addr {
             WsListener::bind(
                 web_addr,
-                self.connections_number.clone(),
-                self.authenticator.clone(),
-                self.control_sender.clone(),
-                self.stats_sender.clone(),
-                self.config.keep_alive.clone(),
-                self.state_store.clone(),
-                self.config.max_connections,
-                self.config.max_subs_per_client,
+                self.connection_gate.clone(),
+                self.connection_limit.clone(),
+                self.connection_config
+                    .for_listener(self.config.ws_bandwidth_limit, &self.config.ws_topic_prefix),
+                self.transport_bytes.as_ref().map(|bytes| bytes.ws.clone()),
+                ws_options(&self.config),
+            )?;
+            info!(
+                "Websocket is listening on {:?}, path {}",
+                web_addr, self.config.ws_path
             );
-            println!("Websocket is listening on {:?}", web_addr);
         }
 
-        if let (Some(web_tls_addr), &Some(ref cert_path), &Some(ref key_path)) = (
-            self.config.wss_addr,
-            &self.config.cert_file,
-            &self.config.key_file,
-        ) {
+        if let (Some(web_tls_addr), Some(reloader)) = (self.config.wss_addr, cert_reloader.as_mut())
+        {
+            let tls_config = reloader.listener_config(vec![ALPN_HTTP_1_1.to_vec()])?;
             WssListener::bind(
                 web_tls_addr,
-                self.connections_number.clone(),
-                self.authenticator.clone(),
-                self.control_sender.clone(),
-                self.stats_sender.clone(),
-                self.config.keep_alive.clone(),
-                self.state_store.clone(),
-                self.config.max_connections,
-                self.config.max_subs_per_client,
-                cert_path.clone(),
-                key_path.clone(),
+                self.connection_gate.clone(),
+                self.connection_limit.clone(),
+                self.connection_config.for_listener(
+                    self.config.wss_bandwidth_limit,
+                    &self.config.wss_topic_prefix,
+                ),
+                self.transport_bytes.as_ref().map(|bytes| bytes.wss.clone()),
+                ws_options(&self.config),
+                tls_config,
+            )?;
+            info!(
+                "Websocket TLS is listening on {:?}, path {}",
+                web_tls_addr, self.config.ws_path
             );
-            println!("Websocket TLS is listening on {:?}", web_tls_addr);
         }
 
-        let mut signals = Signals::new(&[SIGHUP, SIGTERM, SIGINT, SIGQUIT])?;
+        if let Some(reloader) = cert_reloader {
+            let reloader = Arc::new(reloader);
+            if let Some(interval) = self.config.tls_cert_watch_interval {
+                spawn(reloader.clone().watch(interval));
+            }
+            self.cert_reloader = Some(reloader);
+        }
 
+        let mut signals = if self.handle_os_signals {
+            Some(Signals::new(&[SIGHUP, SIGTERM, SIGINT, SIGQUIT])?)
+        } else {
+            None
+        };
+
+        let features = Arc::new(BrokerFeatures::new(&self.config));
+        let admin_api_context = admin_api::AdminApiContext {
+            state_store: self.state_store.clone(),
+            control_sender: self.control_sender.clone(),
+            stats_sender: self.stats_sender.clone(),
+            connection_limit: self.connection_limit.clone(),
+            features: features.clone(),
+            listeners: Arc::new(self.config.listeners()),
+            auth: Arc::new(admin_api::AdminApiAuth::new(
+                self.config.admin_api_auth.clone(),
+            )),
+            erase_retained_topics: Arc::new(self.config.erase_retained_topics.clone()),
+        };
+        let admin_api_tls = admin_api::AdminApiTls::new(&self.config);
         if let Some(admin_api_origin) = self.config.admin_api {
-            // let stats = self.stats.clone();
-            // let authenticator = self.authenticator.clone();
+            let context = admin_api_context.clone();
+            let tls = admin_api_tls.clone();
             spawn(async move {
-                admin_api::run(admin_api_origin).await;
+                admin_api::run(admin_api_origin, context, tls).await;
             });
         }
+        if let Some(metrics_origin) = self.config.metrics {
+            let context = admin_api_context.clone();
+            let tls = admin_api_tls.clone();
+            spawn(async move {
+                admin_api::run_metrics(metrics_origin, context, tls).await;
+            });
+        }
+
+        for bridge_config in self.config.bridges.iter().cloned() {
+            info!(
+                "Bridge {} is relaying topics with {}",
+                bridge_config.name, bridge_config.address
+            );
+            spawn(Bridge::new(bridge_config, &self.config.broker_id, self.handle())?.run());
+        }
 
-        loop {
+        if let Some(cluster_addr) = self.config.cluster_addr {
+            Cluster::new(&self.config, self.handle())
+                .start(cluster_addr)
+                .await?;
+        }
+
+        if let Some(mqttsn_addr) = self.config.mqttsn_addr {
+            MqttSnGateway::new(
+                self.connection_config.clone(),
+                self.connection_limit.clone(),
+                self.clock.clone(),
+            )
+            .start(mqttsn_addr)
+            .await?;
+        }
+
+        announce(
+            &self.control_sender,
+            &self.config.broker_id,
+            BrokerState::Online,
+        );
+        features.announce(&self.control_sender);
+
+        let mut tcp_backoff = AcceptBackoff::new();
+        let mut tls_backoff = AcceptBackoff::new();
+        let shared = shared_listeners(&tcp_listener, &tls_listener);
+        let mut handover_peer = loop {
             select! {
-              Ok((stream, addr)) = tcp_listener.accept() => {
-                on_accept_tcp(stream, addr, &self)?;
-              }
-              Ok((stream, addr)) = tls_listener.accept() => {
-                on_accept_tls(stream, addr, &self);
-              }
-              Some(signal) = signals.next() => {
-                if handle_os_signal(signal, self.control_sender.clone(), signals.handle()).await? {
-                  exit(0);
+              accepted = tcp_listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                  tcp_backoff.reset();
+                  on_accept_tcp(stream, addr, &self);
+                }
+                Err(err) => on_accept_error("TCP", err, &mut tcp_backoff, &self).await,
+              },
+              accepted = tls_listener.accept() => match accepted {
+                Ok((stream, addr, ja3)) => {
+                  tls_backoff.reset();
+                  on_accept_tls(stream, addr, ja3, &self);
+                }
+                Err(err) => on_accept_error("TLS", err, &mut tls_backoff, &self).await,
+              },
+              Some((signal, handle)) = next_os_signal(&mut signals) => {
+                if handle_os_signal(signal, &self, handle).await? {
+                  return Ok(());
                 } else {
                   debug!("continue");
                 }
               }
               Some(_) = self.shut_down_channel.recv() => {
-                  println!("[Server Worker]: Shutting down complete. Bye.");
-                  signals.handle().close();
-                  exit(0);
+                  info!("[Server Worker]: Shutting down complete. Bye.");
+                  if let Some(ref signals) = signals {
+                    signals.handle().close();
+                  }
+                  return Ok(());
+              }
+              handover_request = accept_handover(&handover_listener, &shared) => {
+                match handover_request {
+                  Ok(mut peer) => match peer.wait_ready().await {
+                    Ok(()) => break peer,
+                    Err(err) => error!("[Server Worker]: handover aborted. {:?}", err),
+                  },
+                  Err(err) => error!("[Server Worker]: handover request failed. {:?}", err),
+                }
+                // the listeners are not shared until the next handover request
+                set_reuse_port(&shared, false)?;
               }
             }
+        };
+
+        info!("[Server Worker]: handing listeners over to a new broker process");
+        // new connections are accepted by a new process from now on
+        drop(tcp_listener);
+        drop(tls_listener);
+
+        self.control_sender
+            .send(ControlMessage::HandOver)
+            .map_err(|err| format!("Unable to shut down Control. {:?}", err))?;
+        self.shut_down_channel.recv().await;
+        if let Err(err) = handover_peer.drained().await {
+            error!(
+                "[Server Worker]: unable to complete the handover. {:?}",
+                err
+            );
+        }
+        if let Some(ref signals) = signals {
+            signals.handle().close();
+        }
+        info!("[Server Worker]: Handover complete. Bye.");
+
+        Ok(())
+    }
+
+    /// Re-reads the config file and applies options which can be changed at runtime, others
+    /// require a restart.
+    fn reload_config(&self) {
+        let path = match self.config_file {
+            Some(ref path) => path,
+            None => {
+                info!("[Server Worker]: no config file to reload");
+                return;
+            }
+        };
+
+        let reloaded = if self.strict_config {
+            TeleMQServerConfig::from_file_strict(path)
+        } else {
+            TeleMQServerConfig::from_file(path)
+        };
+        match reloaded {
+            Ok(config) => {
+                info!(
+                    "[Server Worker]: reloaded {:?}, max_connections and connection_drain_rate are applied",
+                    path
+                );
+                self.connection_limit.set_max(
+                    config.max_connections,
+                    config.connection_drain_rate,
+                    &self.control_sender,
+                );
+            }
+            Err(err) => error!(
+                "[Server Worker]: unable to reload {:?}, the config is not changed. {:?}",
+                path, err
+            ),
+        }
+    }
+
+    /// Reloads sessions saved by a previous broker process.
+    async fn reload_sessions(&self) -> ServerResult<()> {
+        *self.state_store.write().await = SessionStateStore::new(self.clock.clone())
+            .with_message_expiry_interval(self.config.message_expiry_interval);
+        self.control_sender
+            .send(ControlMessage::ReloadSubscriptions)
+            .map_err(|err| format!("Unable to reload subscriptions. {:?}", err).into())
+    }
+}
+
+async fn accept_handover(
+    listener: &Option<HandoverListener>,
+    shared: &[&TcpListener],
+) -> io::Result<HandoverPeer> {
+    match listener {
+        Some(listener) => listener.accept(shared).await,
+        None => pending().await,
+    }
+}
+
+/// Listeners a new broker process binds before the current one closes them.
+fn shared_listeners<'a>(
+    tcp_listener: &'a TcpListener,
+    tls_listener: &'a TlsListener,
+) -> Vec<&'a TcpListener> {
+    let mut listeners = vec![tcp_listener];
+    listeners.extend(tls_listener.tcp_listener());
+    listeners
+}
+
+async fn next_os_signal(signals: &mut Option<Signals>) -> Option<(i32, Handle)> {
+    match signals {
+        Some(signals) => {
+            let handle = signals.handle();
+            signals.next().await.map(|signal| (signal, handle))
         }
+        None => pending().await,
     }
 }
 
-fn on_accept_tcp(stream: TcpStream, addr: SocketAddr, server: &Server) -> io::Result<()> {
+/// Logs a failed `accept` and, unless only a single incoming connection has failed, pauses
+/// the listener, so it doesn't spin while the broker is out of file descriptors.
+async fn on_accept_error(
+    listener: &str,
+    err: io::Error,
+    backoff: &mut AcceptBackoff,
+    server: &Server,
+) {
+    let fd_exhausted = is_fd_exhaustion(&err);
+    if let Err(err) = server
+        .stats_sender
+        .send(StatsMessage::AcceptFailed { fd_exhausted })
+    {
+        error!(
+            "[Server Worker]: unable to send StatsMessage::AcceptFailed. {:?}",
+            err
+        );
+    }
+    if is_connection_error(&err) {
+        debug!(
+            "[Server Worker]: {} Listener could not accept a connection. {:?}",
+            listener, err
+        );
+        return;
+    }
+
+    let delay = backoff.next_delay();
+    error!(
+        "[Server Worker]: {} Listener could not accept a connection, retrying in {:?}. {:?}",
+        listener, delay, err
+    );
+    if fd_exhausted {
+        warn!(
+            "[Server Worker]: out of file descriptors with {} connections open, consider raising the open files limit (ulimit -n)",
+            server.connection_limit.current()
+        );
+    }
+    sleep(delay).await;
+}
+
+fn on_accept_tcp(stream: TcpStream, addr: SocketAddr, server: &Server) {
     let add_ip_net = IpNet::from(addr.ip());
     let ip_allowed = server
         .config
@@ -186,155 +673,152 @@ fn on_accept_tcp(stream: TcpStream, addr: SocketAddr, server: &Server) -> io::Re
         })
         .unwrap_or(true);
     if !ip_allowed {
-        return Ok(());
+        return;
     }
-    let connections_number = server.connections_number.clone();
-    if connections_number
-        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |prev_value| {
-            if prev_value >= server.config.max_connections {
-                None
-            } else {
-                Some(prev_value + 1)
-            }
-        })
-        .is_err()
-    {
-        return Ok(());
+    if !is_allowed_by_gate(
+        server.connection_gate.as_deref(),
+        &ConnectionMetadata::from_tcp(&stream, addr),
+    ) {
+        return;
+    }
+    if let Err(err) = stream.set_ttl(server.config.keep_alive.as_secs() as u32) {
+        error!("Could not add new TCP connection: {:?}: {:?}", addr, err);
+        return;
+    }
+    let stream = CountingStream::new(
+        stream,
+        server
+            .transport_bytes
+            .as_ref()
+            .map(|bytes| bytes.tcp.clone()),
+    );
+    let connection_limit = server.connection_limit.clone();
+    if !connection_limit.try_acquire() {
+        return;
     }
-    let authenticator = server.authenticator.clone();
-    let control_sender = server.control_sender.clone();
-    let stats_sender = server.stats_sender.clone();
-    let inactivity_interval = server.config.keep_alive.clone();
-    let state_store = server.state_store.clone();
-    let max_subs_per_client = server.config.max_subs_per_client.clone();
-    stream.set_ttl(server.config.keep_alive.as_secs() as u32)?;
+    let config = server.tcp_connection_config.clone();
 
-    spawn(async move {
-        if let Err(err) = peer_process_tcp(
-            stream,
-            addr,
-            control_sender,
-            stats_sender,
-            authenticator,
-            inactivity_interval,
-            state_store,
-            max_subs_per_client,
-        )
-        .await
-        {
+    let watchdog = ConnectionWatchdog::new(addr, &config.control_sender, &config.stats_sender);
+    let connection_task = spawn(async move {
+        if let Err(err) = peer_process_tcp(stream, addr, config).await {
             error!("Could not add new TCP connection: {:?}: {:?}", addr, err);
         }
-        connections_number.fetch_sub(1, Ordering::Relaxed);
     });
-
-    Ok(())
+    spawn(async move {
+        watchdog.watch(connection_task).await;
+        connection_limit.release();
+    });
 }
 
-fn on_accept_tls(stream: TlsStream<TcpStream>, addr: SocketAddr, server: &Server) -> () {
-    let connections_number = server.connections_number.clone();
-    if connections_number
-        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |prev_value| {
-            if prev_value >= server.config.max_connections {
-                None
-            } else {
-                Some(prev_value + 1)
-            }
-        })
-        .is_err()
-    {
+fn on_accept_tls(
+    stream: TlsStream<CountingStream<TcpStream>>,
+    addr: SocketAddr,
+    ja3: Option<Ja3Fingerprint>,
+    server: &Server,
+) -> () {
+    if !is_allowed_by_gate(
+        server.connection_gate.as_deref(),
+        &ConnectionMetadata::from_tls(&stream, addr, ja3.clone()),
+    ) {
+        return;
+    }
+    let connection_limit = server.connection_limit.clone();
+    if !connection_limit.try_acquire() {
         return;
     }
-    let control_sender = server.control_sender.clone();
-    let stats_sender = server.stats_sender.clone();
-    let authenticator = server.authenticator.clone();
-    let inactivity_interval = server.config.keep_alive.clone();
-    let max_subs_per_client = server.config.max_subs_per_client.clone();
-    let state_store = server.state_store.clone();
+    let config = server.tls_connection_config.clone();
 
-    spawn(async move {
-        if let Err(err) = peer_process_tls(
-            stream,
-            addr,
-            control_sender,
-            stats_sender,
-            authenticator,
-            inactivity_interval,
-            state_store,
-            max_subs_per_client,
-        )
-        .await
-        {
+    let watchdog = ConnectionWatchdog::new(addr, &config.control_sender, &config.stats_sender);
+    let connection_task = spawn(async move {
+        if let Err(err) = peer_process_tls(stream, addr, ja3, config).await {
             error!("Could not add new TCP connection: {:?}: {:?}", addr, err);
         }
-        connections_number.fetch_sub(1, Ordering::SeqCst);
+    });
+    spawn(async move {
+        watchdog.watch(connection_task).await;
+        connection_limit.release();
     });
 }
 
+/// Waits until backends the broker depends on are reachable, if it's configured to.
+async fn wait_for_dependencies(config: &TeleMQServerConfig) -> ServerResult<()> {
+    if let (true, Some(addr)) = (config.wait_for_state_store, config.session_state_store_url) {
+        wait_for(
+            "Session State Store",
+            config.startup_wait_timeout,
+            config.startup_wait_retry_interval,
+            || probe_tcp(addr),
+        )
+        .await?;
+    }
+
+    if let (true, Some(url)) = (config.wait_for_auth_endpoint, &config.auth_endpoint) {
+        wait_for(
+            "Authentication endpoint",
+            config.startup_wait_timeout,
+            config.startup_wait_retry_interval,
+            || probe_http(url),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+fn retry_policy(config: &TeleMQServerConfig) -> Option<RetryPolicy> {
+    config.retry_interval.map(|interval| RetryPolicy {
+        interval,
+        max_retries: config.max_retries,
+    })
+}
+
+fn ws_options(config: &TeleMQServerConfig) -> WsOptions {
+    WsOptions {
+        path: config.ws_path.clone(),
+        subprotocols: config.ws_subprotocols.clone(),
+        keep_alive: WsKeepAlive {
+            ping_interval: config.ws_ping_interval,
+            pong_as_activity: config.ws_pong_as_activity,
+        },
+    }
+}
+
 async fn peer_process_tcp(
-    stream: TcpStream,
+    stream: CountingStream<TcpStream>,
     addr: SocketAddr,
-    control_sender: ControlSender,
-    stats_sender: StatsSender,
-    authenticator: Arc<RwLock<Authenticator>>,
-    inactivity_interval: time::Duration,
-    state_store: Arc<RwLock<SessionStateStore>>,
-    max_subs_per_client: Option<usize>,
+    config: ConnectionConfig,
 ) -> ServerResult<()> {
-    let packets = Framed::new(stream, ControlPacketCodec::new());
-
-    let connection = Connection::new_tcp(
-        packets,
-        addr,
-        control_sender,
-        stats_sender,
-        authenticator,
-        inactivity_interval,
-        state_store,
-        max_subs_per_client,
-    )
-    .await
-    .map_err(|err| format!("{:?}", err))?;
+    let packets = Framed::new(stream, MqttCodec::new());
+
+    let connection = Connection::new_tcp(packets, addr, config)
+        .await
+        .map_err(|err| format!("{:?}", err))?;
 
     connection.run().await.map_err(Into::into)
 }
 
 async fn peer_process_tls(
-    stream: TlsStream<TcpStream>,
+    stream: TlsStream<CountingStream<TcpStream>>,
     addr: SocketAddr,
-    control_sender: ControlSender,
-    stats_sender: StatsSender,
-    authenticator: Arc<RwLock<Authenticator>>,
-    inactivity_interval: time::Duration,
-    state_store: Arc<RwLock<SessionStateStore>>,
-    max_subs_per_client: Option<usize>,
+    ja3: Option<Ja3Fingerprint>,
+    config: ConnectionConfig,
 ) -> ServerResult<()> {
-    let packets = Framed::new(stream, ControlPacketCodec::new());
-
-    let connection = Connection::new_tls(
-        packets,
-        addr,
-        control_sender,
-        stats_sender,
-        authenticator,
-        inactivity_interval,
-        state_store,
-        max_subs_per_client,
-    )
-    .await
-    .map_err(|err| format!("{:?}", err))?;
+    let packets = Framed::new(stream, MqttCodec::new());
+
+    let connection = Connection::new_tls(packets, addr, ja3, config)
+        .await
+        .map_err(|err| format!("{:?}", err))?;
 
     connection.run().await.map_err(Into::into)
 }
 
-async fn handle_os_signal(
-    signal: i32,
-    control_sender: UnboundedSender<ControlMessage>,
-    handle: Handle,
-) -> io::Result<bool> {
+async fn handle_os_signal(signal: i32, server: &Server, handle: Handle) -> io::Result<bool> {
     match signal {
         SIGHUP => {
-            // reload configuation
-            info!("reload configuration");
+            server.reload_config();
+            if let Some(ref reloader) = server.cert_reloader {
+                reloader.reload();
+            }
             Ok(false)
         }
         SIGQUIT => {
@@ -343,7 +827,8 @@ async fn handle_os_signal(
         }
         signal if signal == SIGTERM || signal == SIGINT => {
             info!("Shuting down TeleMQ... Please wait, it can take some time");
-            control_sender
+            server
+                .control_sender
                 .send(ControlMessage::ShutDown)
                 .map_err(|err| {
                     io::Error::new(
@@ -0,0 +1,193 @@
+//! Saving sessions of closed connections off connection tasks.
+//!
+//! When thousands of clients disconnect at once (e.g. after a network outage), saving their
+//! sessions one by one under the State Store lock would keep every connection task and its
+//! socket alive until its turn comes. Connections hand their closed sessions over to the
+//! persistence task instead, which saves up to `MAX_CONCURRENT_JOBS` of them at a time.
+//!
+//! A connection passes its session lock along with a session, so a client which reconnects
+//! right away takes the session over only once it has been saved. Control Worker is informed
+//! about a disconnection only once a session is saved as well. Publishes the connection
+//! hasn't forwarded and ones Control Worker fails to send to it until then are queued in the
+//! stored session, not dropped with the closed connection.
+use std::sync::Arc;
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::{error, info};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        OwnedMutexGuard, RwLock,
+    },
+};
+
+use crate::{
+    control::{ControlMessage, ControlSender},
+    disconnect_reason::DisconnectReason,
+    session_state::SessionConnectedState,
+    session_state_store::SessionStateStore,
+};
+
+pub type PersistenceSender = UnboundedSender<PersistenceJob>;
+pub type PersistenceReceiver = UnboundedReceiver<PersistenceJob>;
+
+#[derive(Debug)]
+pub enum PersistenceJob {
+    SaveSession {
+        state: Box<SessionConnectedState>,
+        /// Released once the session is saved.
+        session_guard: Option<OwnedMutexGuard<()>>,
+        /// Sent to Control Worker once the session is saved.
+        then: Box<ControlMessage>,
+    },
+    /// Records a closed connection in a client history.
+    RecordDisconnect {
+        client_id: String,
+        reason: DisconnectReason,
+        messages_received: u64,
+        messages_sent: u64,
+    },
+}
+
+pub struct SessionPersistence {
+    receiver: PersistenceReceiver,
+    state_store: Arc<RwLock<SessionStateStore>>,
+    control_sender: ControlSender,
+}
+
+impl SessionPersistence {
+    const MAX_CONCURRENT_JOBS: usize = 32;
+
+    pub fn new(
+        state_store: Arc<RwLock<SessionStateStore>>,
+        control_sender: ControlSender,
+    ) -> (Self, PersistenceSender) {
+        let (sender, receiver) = unbounded_channel();
+        (
+            SessionPersistence {
+                receiver,
+                state_store,
+                control_sender,
+            },
+            sender,
+        )
+    }
+
+    pub async fn run(mut self) {
+        let mut in_progress = FuturesUnordered::new();
+        loop {
+            select! {
+              Some(()) = in_progress.next(), if !in_progress.is_empty() => {}
+              job = self.receiver.recv(), if in_progress.len() < Self::MAX_CONCURRENT_JOBS => {
+                match job {
+                  Some(job) => {
+                    in_progress.push(Self::process(self.state_store.clone(), self.control_sender.clone(), job));
+                  }
+                  None => break,
+                }
+              }
+            }
+        }
+
+        while in_progress.next().await.is_some() {}
+        info!("[Session Persistence Worker]: all senders are gone, finishing");
+    }
+
+    async fn process(
+        state_store: Arc<RwLock<SessionStateStore>>,
+        control_sender: ControlSender,
+        job: PersistenceJob,
+    ) {
+        match job {
+            PersistenceJob::SaveSession {
+                state,
+                session_guard,
+                then,
+            } => {
+                let client_id = state.client_id.clone();
+                if let Err(err) = state_store.write().await.save_state(*state).await {
+                    error!(
+                        "[Session Persistence Worker]: Unable to save session of {:?}. {:?}",
+                        client_id, err
+                    );
+                }
+                if let Err(err) = control_sender.send(*then) {
+                    error!(
+                        "[Session Persistence Worker]: Unable to inform Control Worker about {:?}. {:?}",
+                        client_id, err
+                    );
+                }
+                drop(session_guard);
+            }
+            PersistenceJob::RecordDisconnect {
+                client_id,
+                reason,
+                messages_received,
+                messages_sent,
+            } => {
+                state_store.write().await.client_disconnected(
+                    &client_id,
+                    reason,
+                    messages_received,
+                    messages_sent,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::Clock, connection_gate::ConnectionTransport, connection_info::ConnectionInfo,
+    };
+    use std::{collections::HashMap, time::SystemTime};
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn session_is_released_once_saved() {
+        let state_store = Arc::new(RwLock::new(SessionStateStore::from_inner_data(
+            HashMap::new(),
+            Clock::system(),
+        )));
+        let (control_sender, mut control_receiver) = unbounded_channel();
+        let (persistence, persistence_sender) =
+            SessionPersistence::new(state_store.clone(), control_sender);
+        tokio::spawn(persistence.run());
+
+        let session_lock = Arc::new(Mutex::new(()));
+        let connection = Arc::new(ConnectionInfo::accepted(
+            "127.0.0.1:1883".parse().unwrap(),
+            ConnectionTransport::Tcp,
+            None,
+            SystemTime::now(),
+        ));
+        persistence_sender
+            .send(PersistenceJob::SaveSession {
+                state: Box::new(SessionConnectedState::new(
+                    "a".into(),
+                    false,
+                    None,
+                    None,
+                    None,
+                )),
+                session_guard: Some(session_lock.clone().try_lock_owned().unwrap()),
+                then: Box::new(ControlMessage::ClientDisconnected {
+                    connection,
+                    clean_session: false,
+                    will_packet: None,
+                    undelivered: Vec::new(),
+                }),
+            })
+            .unwrap();
+
+        let _guard = session_lock.lock().await;
+        assert!(state_store.read().await.has_session(&"a".into()));
+        assert!(matches!(
+            control_receiver.try_recv(),
+            Ok(ControlMessage::ClientDisconnected { .. })
+        ));
+    }
+}
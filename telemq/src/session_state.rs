@@ -13,17 +13,21 @@
 // limitations under the License.
 use std::collections::{HashMap, VecDeque};
 use std::mem::replace as mem_replace;
+use std::time::{Duration, SystemTime};
 
 use mqtt_packets::v_3_1_1::{
     publish::fixed_header::{get_qos_level, set_dup},
     subscribe::topic_subscription::TopicSubscription,
     topic::{Subscription, Topic},
     utils::getters_setters,
+    variable::Variable,
     ControlPacket, PacketId, QoS,
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::time::Instant;
 
 use super::connection_provider::SessionConnectionProvider;
+use super::publish_metadata::PublishMetadata;
 use super::session_error::*;
 use super::transaction::{CreateTransaction, TransactionReceive, TransactionSend};
 
@@ -179,8 +183,10 @@ impl SessionState {
                 messages_received_not_acked,
                 ..
             }) => {
-                let transaction = TransactionReceive::new(packet_id, control_packet);
-                messages_received_not_acked.insert(packet_id.clone(), transaction);
+                // a duplicate of a QoS 2 message doesn't restart its transaction
+                messages_received_not_acked
+                    .entry(*packet_id)
+                    .or_insert_with(|| TransactionReceive::new(packet_id, control_packet));
                 Ok(())
             }
             SessionState::Closed => Err(SessionError::new(
@@ -193,7 +199,7 @@ impl SessionState {
     pub fn create_send_transaction_from_packet(
         &mut self,
         control_packet: &ControlPacket,
-    ) -> SessionResult<Option<Vec<u8>>> {
+    ) -> SessionResult<Option<PacketId>> {
         let qos = get_qos_level(&control_packet.fixed_header).map_err(|_| {
             SessionError::new(
                 SessionErrorKind::TransactionError,
@@ -217,42 +223,118 @@ impl SessionState {
             return mem_replace(
                 &mut connected_state.messages_pending_transmition,
                 VecDeque::new(),
-            );
+            )
+            .into_iter()
+            .map(|pending| pending.packet)
+            .collect();
         }
         VecDeque::new()
     }
 
-    // generates a unique packet id for a send transaction
-    fn generate_packet_id(&self) -> SessionResult<PacketId> {
-        let mut packet_id = vec![0u8, 0u8];
+    /// Number of QoS 1 and QoS 2 messages sent to the client which are not acknowledged yet.
+    pub fn inflight_messages(&self) -> usize {
+        match self {
+            SessionState::Connected(connected_state) => {
+                connected_state.messages_sent_not_acked.len()
+            }
+            _ => 0,
+        }
+    }
 
-        loop {
-            if !self.check_packet_id(&packet_id) {
-                break;
+    /// Number of messages waiting for room in the in-flight window.
+    pub fn pending_messages(&self) -> usize {
+        match self {
+            SessionState::Connected(connected_state) => {
+                connected_state.messages_pending_transmition.len()
             }
+            _ => 0,
+        }
+    }
 
-            match packet_id[1].checked_add(1) {
-                Some(sum) => {
-                    packet_id[1] = sum;
-                }
-                None => {
-                    packet_id[1] = 0;
-                    match packet_id[0].checked_add(1) {
-                        Some(sum) => {
-                            packet_id[0] = sum;
-                        }
-                        None => {
-                            return Err(SessionError::new(
-                                SessionErrorKind::MqttPolicyError,
-                                "Unable to generate unique packet id",
-                            ));
-                        }
+    pub fn has_pending_messages(&self) -> bool {
+        match self {
+            SessionState::Connected(connected_state) => {
+                !connected_state.messages_pending_transmition.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    /// Queues a message which can't be sent to a connected client yet.
+    pub fn push_pending_message(&mut self, message: PendingMessage) {
+        if let SessionState::Connected(ref mut connected_state) = self {
+            connected_state
+                .messages_pending_transmition
+                .push_back(message);
+        }
+    }
+
+    /// Drops queued messages which have expired and returns their number.
+    pub fn remove_expired_messages(&mut self, now: SystemTime) -> usize {
+        match self {
+            SessionState::Connected(connected_state) => {
+                connected_state.remove_expired_messages(now)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Takes a pending message at an index picked out of the waiting ones.
+    pub fn pop_pending_message(
+        &mut self,
+        pick: impl FnOnce(&VecDeque<PendingMessage>) -> Option<usize>,
+    ) -> Option<PendingMessage> {
+        match self {
+            SessionState::Connected(connected_state) => {
+                let pending = &mut connected_state.messages_pending_transmition;
+                pick(pending).and_then(|index| pending.remove(index))
+            }
+            _ => None,
+        }
+    }
+
+    /// Sent transactions which haven't been acknowledged for `interval`, their packets should
+    /// be re-sent. Retries of the returned transactions are already counted in.
+    pub fn retry_transactions(&mut self, interval: Duration, now: Instant) -> Vec<TransactionSend> {
+        match self {
+            SessionState::Connected(SessionConnectedState {
+                messages_sent_not_acked,
+                ..
+            }) => messages_sent_not_acked
+                .values_mut()
+                .filter_map(|transaction| {
+                    if transaction.retry(interval, now) {
+                        Some(transaction.clone())
+                    } else {
+                        None
                     }
-                }
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn restart_retries(&mut self, now: Instant) {
+        if let SessionState::Connected(ref mut connected_state) = self {
+            for transaction in connected_state.messages_sent_not_acked.values_mut() {
+                transaction.restart_retries(now);
             }
         }
+    }
 
-        Ok(packet_id)
+    // generates a unique packet id for a send transaction
+    fn generate_packet_id(&mut self) -> SessionResult<PacketId> {
+        let packet_id = match self {
+            SessionState::Connected(ref mut connected_state) => connected_state.next_packet_id(),
+            _ => None,
+        };
+
+        packet_id.ok_or_else(|| {
+            SessionError::new(
+                SessionErrorKind::MqttPolicyError,
+                "Unable to generate unique packet id",
+            )
+        })
     }
 
     pub fn create_send_transaction(
@@ -261,6 +343,8 @@ impl SessionState {
         mut control_packet: ControlPacket,
     ) -> SessionResult<()> {
         set_dup(&mut control_packet.fixed_header, true);
+        // the packet is re-sent and stored as is, so it must carry its packet id
+        getters_setters::set_packet_id(&mut control_packet.variable, *packet_id);
         match self {
             SessionState::NonConnected => Err(SessionError::new(
                 SessionErrorKind::WrongState,
@@ -271,7 +355,7 @@ impl SessionState {
                 ..
             }) => {
                 let transaction = TransactionSend::new(packet_id, control_packet);
-                messages_sent_not_acked.insert(packet_id.clone(), transaction);
+                messages_sent_not_acked.insert(*packet_id, transaction);
                 Ok(())
             }
             SessionState::Closed => Err(SessionError::new(
@@ -487,11 +571,13 @@ impl SessionState {
         }
     }
 
-    pub fn check_packet_id(&self, packet_id: &PacketId) -> bool {
+    /// Whether a QoS 2 message with `packet_id` has been received and not released by PUBREL
+    /// yet, so a PUBLISH with the same packet id is a duplicate.
+    pub fn is_receiving(&self, packet_id: &PacketId) -> bool {
         match self {
-            SessionState::Connected(ref connected_session) => {
-                connected_session.check_packet_id(packet_id)
-            }
+            SessionState::Connected(ref connected_session) => connected_session
+                .messages_received_not_acked
+                .contains_key(packet_id),
             _ => false,
         }
     }
@@ -542,12 +628,41 @@ impl SessionState {
         None
     }
 
+    /// Same as `get_will_data`, but keeps the will in the session.
+    pub fn peek_will_data(&self) -> Option<(Topic, QoS, Vec<u8>, bool)> {
+        if let SessionState::Connected(ref connected_state) = self {
+            if let (Some(topic), Some(qos), Some(message)) = (
+                &connected_state.will_topic,
+                &connected_state.will_qos,
+                &connected_state.will_message,
+            ) {
+                return Some((
+                    topic.clone(),
+                    qos.clone(),
+                    message.clone(),
+                    connected_state.will_retain,
+                ));
+            }
+        }
+        None
+    }
+
     pub fn get_subscriptions_number(&self) -> Option<usize> {
         if let SessionState::Connected(connected_state) = self {
             return Some(connected_state.subscriptions.len());
         }
         None
     }
+
+    pub fn is_subscribed(&self, filter: &Subscription) -> bool {
+        match self {
+            SessionState::Connected(connected_state) => connected_state
+                .subscriptions
+                .iter()
+                .any(|(_, sub)| sub.original == filter.original),
+            _ => false,
+        }
+    }
 }
 
 /// Connected client session.
@@ -575,8 +690,10 @@ pub struct SessionConnectedState {
     /// acknowledged.
     pub messages_sent_not_acked: HashMap<PacketId, TransactionSend>,
 
-    /// QoS 1 and QoS 2 messages pending transmission to the Client.
-    pub messages_pending_transmition: VecDeque<ControlPacket>,
+    /// QoS 1 and QoS 2 messages pending transmission to the Client: queued while it's offline
+    /// or waiting for room in the in-flight window.
+    #[serde(deserialize_with = "deserialize_pending_messages")]
+    pub messages_pending_transmition: VecDeque<PendingMessage>,
 
     /// QoS 2 messages which have been received from the Client, but have not been completely
     /// acknowledged.
@@ -596,6 +713,115 @@ pub struct SessionConnectedState {
     /// If set to true Will Message will be published
     /// as a retained message.
     pub will_retain: bool,
+
+    /// Time a session has been stored since its client disconnected. `None` while a client is
+    /// connected and for sessions stored before session expiry has been introduced.
+    #[serde(default)]
+    pub disconnected_at: Option<SystemTime>,
+
+    /// Packet id of the last send transaction, ids are allocated after it in a rolling manner.
+    #[serde(default)]
+    pub last_packet_id: u16,
+}
+
+/// Publish packet queued for a client while it is offline or while its in-flight window is
+/// full.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingMessage {
+    pub packet: ControlPacket,
+
+    /// Time the packet has been put into the queue.
+    pub queued_at: SystemTime,
+
+    /// Time the packet is dropped at if it hasn't been sent by then, see
+    /// `message_expiry_interval`. `None` if it never expires.
+    #[serde(default)]
+    pub expires_at: Option<SystemTime>,
+
+    #[serde(default)]
+    pub metadata: PublishMetadata,
+}
+
+/// A queued message as it's stored. Stores written before messages have been timestamped
+/// hold bare packets.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredPendingMessage {
+    Pending(PendingMessage),
+    Packet(ControlPacket),
+}
+
+/// Reads queues of both formats, bare packets are treated as queued at the time of reading.
+fn deserialize_pending_messages<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<VecDeque<PendingMessage>, D::Error> {
+    let stored = Vec::<StoredPendingMessage>::deserialize(deserializer)?;
+    let now = SystemTime::now();
+    Ok(stored
+        .into_iter()
+        .map(|message| match message {
+            StoredPendingMessage::Pending(message) => message,
+            StoredPendingMessage::Packet(packet) => {
+                PendingMessage::new(packet, PublishMetadata::new(), now)
+            }
+        })
+        .collect())
+}
+
+/// What happens when a message is queued for an offline client which queue is full.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueOverflowPolicy {
+    /// the oldest queued message is dropped to make room for a new one
+    DropOldest,
+    /// a new message is dropped
+    DropNewest,
+    /// the session is discarded, so the client reconnects with a clean session
+    Disconnect,
+}
+
+/// Limit of messages queued for an offline client.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueLimit {
+    /// `None` means unlimited.
+    pub max_messages: Option<usize>,
+    pub overflow_policy: QueueOverflowPolicy,
+    /// Messages which would be delivered with QoS 0 are queued as well, otherwise they are
+    /// dropped while a client is offline.
+    pub queue_qos0_messages: bool,
+}
+
+/// Result of queueing a message for an offline client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueOutcome {
+    Queued,
+    /// A message has been dropped to respect a queue limit.
+    Dropped,
+    /// A queue limit has been reached and the session should be discarded.
+    Overflowed,
+    /// A QoS 0 message has not been queued, since `queue_qos0_messages` is off.
+    Skipped,
+}
+
+impl PendingMessage {
+    pub fn new(packet: ControlPacket, metadata: PublishMetadata, queued_at: SystemTime) -> Self {
+        PendingMessage {
+            packet,
+            queued_at,
+            expires_at: None,
+            metadata,
+        }
+    }
+
+    /// Makes the message expire `ttl` after it has been queued.
+    pub fn expiring_after(mut self, ttl: Option<Duration>) -> Self {
+        self.expires_at = ttl.and_then(|ttl| self.queued_at.checked_add(ttl));
+        self
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 impl SessionConnectedState {
@@ -616,6 +842,59 @@ impl SessionConnectedState {
         }
     }
 
+    /// QoS a message would be delivered with: its own QoS downgraded to the highest QoS of
+    /// matching subscriptions.
+    pub fn delivery_qos(&self, packet: &ControlPacket) -> QoS {
+        let (topic, qos) = match (&packet.variable, get_qos_level(&packet.fixed_header)) {
+            (Variable::Publish(ref variable), Ok(qos)) => (&variable.topic_name, qos),
+            _ => return QoS::Zero,
+        };
+        let mut max_subscription_qos: Option<&QoS> = None;
+        for (subscription_qos, subscription) in &self.subscriptions {
+            if subscription.topic_matches(topic)
+                && max_subscription_qos.is_none_or(|max| subscription_qos > max)
+            {
+                max_subscription_qos = Some(subscription_qos);
+            }
+        }
+
+        match max_subscription_qos.cloned() {
+            Some(max_subscription_qos) if max_subscription_qos < qos => max_subscription_qos,
+            _ => qos,
+        }
+    }
+
+    /// Queues a message for transmission once the client reconnects.
+    pub fn queue_message(&mut self, message: PendingMessage, limit: &QueueLimit) -> QueueOutcome {
+        let queue = &mut self.messages_pending_transmition;
+        match limit.max_messages {
+            Some(max_messages) if queue.len() >= max_messages => match limit.overflow_policy {
+                QueueOverflowPolicy::DropOldest => {
+                    // a queue restored from a file can be longer than a current limit
+                    while queue.len() >= max_messages {
+                        queue.pop_front();
+                    }
+                    queue.push_back(message);
+                    QueueOutcome::Dropped
+                }
+                QueueOverflowPolicy::DropNewest => QueueOutcome::Dropped,
+                QueueOverflowPolicy::Disconnect => QueueOutcome::Overflowed,
+            },
+            _ => {
+                queue.push_back(message);
+                QueueOutcome::Queued
+            }
+        }
+    }
+
+    /// Drops queued messages which have expired and returns their number.
+    pub fn remove_expired_messages(&mut self, now: SystemTime) -> usize {
+        let queue = &mut self.messages_pending_transmition;
+        let before = queue.len();
+        queue.retain(|pending| !pending.is_expired(now));
+        before - queue.len()
+    }
+
     pub fn remove_subscription(&mut self, subscription: Subscription) {
         self.subscriptions.retain(|(_, sub)| *sub != subscription);
     }
@@ -625,6 +904,22 @@ impl SessionConnectedState {
             || self.messages_received_not_acked.contains_key(packet_id)
     }
 
+    /// The next packet id after the last allocated one which is not in use, so an id is
+    /// reused as late as possible. It takes a single lookup unless ids are nearly exhausted.
+    /// `None` if all of them are in use.
+    pub fn next_packet_id(&mut self) -> Option<PacketId> {
+        for _ in 0..u16::MAX {
+            // packet id 0 is not allowed
+            self.last_packet_id = self.last_packet_id.checked_add(1).unwrap_or(1);
+            let packet_id = PacketId::new(self.last_packet_id);
+            if !self.check_packet_id(&packet_id) {
+                return Some(packet_id);
+            }
+        }
+
+        None
+    }
+
     pub fn new(
         client_id: String,
         clean_session: bool,
@@ -660,7 +955,9 @@ mod test_connected_state {
             will_message: None,
             will_qos: None,
             will_retain: false,
+            disconnected_at: None,
             will_topic: None,
+            last_packet_id: 0,
         };
         let subscription = TopicSubscription {
             qos: QoS::Zero,
@@ -699,7 +996,9 @@ mod test_connected_state {
             will_message: None,
             will_qos: None,
             will_retain: false,
+            disconnected_at: None,
             will_topic: None,
+            last_packet_id: 0,
         };
         let subscription = TopicSubscription {
             qos: QoS::One,
@@ -724,4 +1023,379 @@ mod test_connected_state {
             "should add a subscription with a proper topic"
         );
     }
+
+    #[test]
+    fn repeated_subscribe() {
+        let mut state = SessionState::Connected(SessionConnectedState::new(
+            "someid".into(),
+            true,
+            None,
+            None,
+            None,
+        ));
+        let subscription = |filter: &str, qos: QoS| TopicSubscription {
+            qos,
+            topic_filter: Subscription::try_from(filter).unwrap(),
+        };
+
+        state
+            .subscribe(vec![
+                subscription("a/+", QoS::Zero),
+                subscription("b", QoS::One),
+            ])
+            .unwrap();
+        state
+            .subscribe(vec![
+                subscription("a/+", QoS::One),
+                subscription("a/+", QoS::Two),
+            ])
+            .unwrap();
+
+        match state {
+            SessionState::Connected(state) => {
+                let subscriptions: Vec<(QoS, &str)> = state
+                    .subscriptions
+                    .iter()
+                    .map(|(qos, sub)| (qos.clone(), sub.original.as_str()))
+                    .collect();
+                assert_eq!(
+                    subscriptions,
+                    vec![(QoS::Two, "a/+"), (QoS::One, "b")],
+                    "a subscription should be replaced in place with the last QoS"
+                );
+            }
+            _ => panic!("session should stay connected"),
+        }
+    }
+
+    #[test]
+    fn repeated_unsubscribe() {
+        let mut state = SessionState::Connected(SessionConnectedState {
+            subscriptions: vec![
+                (QoS::Zero, Subscription::try_from("a").unwrap()),
+                (QoS::One, Subscription::try_from("b/#").unwrap()),
+            ],
+            ..SessionConnectedState::new("someid".into(), true, None, None, None)
+        });
+
+        for _ in 0..2 {
+            assert!(
+                state
+                    .unsubscribe(vec![
+                        Subscription::try_from("a").unwrap(),
+                        Subscription::try_from("never/subscribed").unwrap(),
+                    ])
+                    .is_ok(),
+                "unsubscribe of unknown topic filters should succeed"
+            );
+        }
+
+        match state {
+            SessionState::Connected(state) => {
+                assert_eq!(state.subscriptions.len(), 1, "other subscriptions are kept");
+                assert_eq!(state.subscriptions[0].1.original, "b/#");
+            }
+            _ => panic!("session should stay connected"),
+        }
+    }
+
+    fn pending_message(payload: u8) -> PendingMessage {
+        let mut builder = mqtt_packets::v_3_1_1::builders::PublishPacketBuilder::new();
+        builder
+            .with_topic(Topic::try_from("a/b").unwrap())
+            .with_payload(vec![payload]);
+        PendingMessage::new(builder.build(), PublishMetadata::new(), SystemTime::now())
+    }
+
+    fn queued_payloads(state: &SessionConnectedState) -> Vec<u8> {
+        state
+            .messages_pending_transmition
+            .iter()
+            .map(|pending| match pending.packet.variable {
+                mqtt_packets::v_3_1_1::variable::Variable::Publish(ref variable) => {
+                    variable.payload[0]
+                }
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    fn queue_with_limit(policy: QueueOverflowPolicy) -> (SessionConnectedState, Vec<QueueOutcome>) {
+        let mut state = SessionConnectedState::new("someid".into(), false, None, None, None);
+        let limit = QueueLimit {
+            max_messages: Some(2),
+            overflow_policy: policy,
+            queue_qos0_messages: false,
+        };
+        let outcomes = (1..=3)
+            .map(|payload| state.queue_message(pending_message(payload), &limit))
+            .collect();
+
+        (state, outcomes)
+    }
+
+    #[test]
+    fn queue_overflow_drops_oldest() {
+        let (state, outcomes) = queue_with_limit(QueueOverflowPolicy::DropOldest);
+
+        assert_eq!(
+            outcomes,
+            vec![
+                QueueOutcome::Queued,
+                QueueOutcome::Queued,
+                QueueOutcome::Dropped
+            ]
+        );
+        assert_eq!(queued_payloads(&state), vec![2, 3]);
+    }
+
+    #[test]
+    fn queue_overflow_drops_newest() {
+        let (state, outcomes) = queue_with_limit(QueueOverflowPolicy::DropNewest);
+
+        assert_eq!(outcomes[2], QueueOutcome::Dropped);
+        assert_eq!(queued_payloads(&state), vec![1, 2]);
+    }
+
+    #[test]
+    fn queue_overflow_disconnects() {
+        let (state, outcomes) = queue_with_limit(QueueOverflowPolicy::Disconnect);
+
+        assert_eq!(outcomes[2], QueueOutcome::Overflowed);
+        assert_eq!(queued_payloads(&state), vec![1, 2]);
+    }
+
+    #[test]
+    fn unlimited_queue() {
+        let mut state = SessionConnectedState::new("someid".into(), false, None, None, None);
+        let limit = QueueLimit {
+            max_messages: None,
+            overflow_policy: QueueOverflowPolicy::Disconnect,
+            queue_qos0_messages: false,
+        };
+        for payload in 0..10 {
+            assert_eq!(
+                state.queue_message(pending_message(payload), &limit),
+                QueueOutcome::Queued
+            );
+        }
+        assert_eq!(state.messages_pending_transmition.len(), 10);
+    }
+
+    #[test]
+    fn pending_message_without_metadata() {
+        let mut builder = mqtt_packets::v_3_1_1::builders::PublishPacketBuilder::new();
+        builder
+            .with_topic(Topic::try_from("a/b").unwrap())
+            .with_payload(vec![1, 2, 3]);
+        let mut metadata = PublishMetadata::new();
+        metadata.append("trace_id", "1");
+        let pending = PendingMessage::new(builder.build(), metadata, SystemTime::now());
+
+        // messages stored before metadata has been introduced
+        let mut stored = serde_json::to_value(&pending).unwrap();
+        stored.as_object_mut().unwrap().remove("metadata");
+        let restored: PendingMessage = serde_json::from_str(&stored.to_string()).unwrap();
+
+        assert!(restored.metadata.is_empty());
+        assert_eq!(restored.queued_at, pending.queued_at);
+    }
+
+    #[test]
+    fn unacked_transactions_are_retried_after_interval() {
+        use mqtt_packets::v_3_1_1::publish::fixed_header::is_dup;
+
+        let mut state = SessionState::Connected(SessionConnectedState::new(
+            "someid".into(),
+            false,
+            None,
+            None,
+            None,
+        ));
+        let mut builder = mqtt_packets::v_3_1_1::builders::PublishPacketBuilder::new();
+        builder
+            .with_topic(Topic::try_from("a/b").unwrap())
+            .with_qos(&QoS::One)
+            .with_payload(vec![1]);
+        let packet_id = state
+            .create_send_transaction_from_packet(&builder.build())
+            .unwrap()
+            .unwrap();
+        let interval = Duration::from_secs(10);
+        let sent_at = Instant::now();
+
+        assert!(state.retry_transactions(interval, sent_at).is_empty());
+
+        let retried = state.retry_transactions(interval, sent_at + interval);
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].packet_id, packet_id);
+        assert_eq!(retried[0].retries, 1);
+        assert!(is_dup(&retried[0].control_packet.fixed_header));
+        assert!(state
+            .retry_transactions(interval, sent_at + interval + Duration::from_secs(5))
+            .is_empty());
+
+        state.puback(&packet_id).unwrap();
+        assert!(state
+            .retry_transactions(interval, sent_at + interval * 3)
+            .is_empty());
+    }
+
+    fn qos1_publish() -> ControlPacket {
+        let mut builder = mqtt_packets::v_3_1_1::builders::PublishPacketBuilder::new();
+        builder
+            .with_topic(Topic::try_from("a/b").unwrap())
+            .with_qos(&QoS::One)
+            .with_payload(vec![1]);
+        builder.build()
+    }
+
+    #[test]
+    fn packet_ids_roll_over_skipping_ids_in_use() {
+        let mut connected_state =
+            SessionConnectedState::new("someid".into(), false, None, None, None);
+        connected_state.last_packet_id = u16::MAX - 1;
+        let mut state = SessionState::Connected(connected_state);
+
+        let ids: Vec<_> = (0..3)
+            .map(|_| {
+                state
+                    .create_send_transaction_from_packet(&qos1_publish())
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            ids,
+            vec![PacketId::new(u16::MAX), PacketId::new(1), PacketId::new(2)]
+        );
+
+        state.puback(&PacketId::new(1)).unwrap();
+        match state {
+            SessionState::Connected(ref mut connected_state) => {
+                connected_state.last_packet_id = 0;
+                // 1 is free again, 2 is still in use
+                assert_eq!(connected_state.next_packet_id(), Some(PacketId::new(1)));
+                assert_eq!(connected_state.next_packet_id(), Some(PacketId::new(3)));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn session_with_transactions_is_serializable() {
+        let mut state = SessionState::Connected(SessionConnectedState::new(
+            "someid".into(),
+            false,
+            None,
+            None,
+            None,
+        ));
+        let packet_id = state
+            .create_send_transaction_from_packet(&qos1_publish())
+            .unwrap()
+            .unwrap();
+        let stored = match state {
+            SessionState::Connected(ref connected_state) => {
+                serde_json::to_string(connected_state).unwrap()
+            }
+            _ => unreachable!(),
+        };
+
+        let restored: SessionConnectedState = serde_json::from_str(&stored).unwrap();
+        assert!(restored.messages_sent_not_acked.contains_key(&packet_id));
+        assert_eq!(restored.last_packet_id, packet_id.value());
+    }
+
+    #[test]
+    fn pending_messages_wait_in_order() {
+        let mut state = SessionState::Connected(SessionConnectedState::new(
+            "someid".into(),
+            false,
+            None,
+            None,
+            None,
+        ));
+        let mut builder = mqtt_packets::v_3_1_1::builders::PublishPacketBuilder::new();
+        builder
+            .with_topic(Topic::try_from("a/b").unwrap())
+            .with_qos(&QoS::One)
+            .with_payload(vec![0]);
+        let packet_id = state
+            .create_send_transaction_from_packet(&builder.build())
+            .unwrap()
+            .unwrap();
+        state.push_pending_message(pending_message(1));
+        state.push_pending_message(pending_message(2));
+
+        assert_eq!(state.inflight_messages(), 1);
+        assert!(state.has_pending_messages());
+
+        state.puback(&packet_id).unwrap();
+        assert_eq!(state.inflight_messages(), 0);
+        if let SessionState::Connected(ref connected_state) = state {
+            assert_eq!(queued_payloads(connected_state), vec![1, 2]);
+        }
+        assert!(state.pop_pending_message(|_| Some(0)).is_some());
+        assert!(state.pop_pending_message(|_| Some(0)).is_some());
+        assert!(!state.has_pending_messages());
+    }
+
+    #[test]
+    fn expired_messages_are_dropped_from_queue() {
+        let mut state = SessionConnectedState::new("someid".into(), false, None, None, None);
+        let limit = QueueLimit {
+            max_messages: None,
+            overflow_policy: QueueOverflowPolicy::DropOldest,
+            queue_qos0_messages: true,
+        };
+        let ttl = Some(Duration::from_secs(60));
+        state.queue_message(pending_message(1).expiring_after(ttl), &limit);
+        state.queue_message(pending_message(2), &limit);
+        state.queue_message(pending_message(3).expiring_after(ttl), &limit);
+
+        let now = SystemTime::now();
+        assert_eq!(state.remove_expired_messages(now), 0);
+        assert_eq!(
+            state.remove_expired_messages(now + Duration::from_secs(120)),
+            2
+        );
+        assert_eq!(queued_payloads(&state), vec![2]);
+    }
+
+    #[test]
+    fn qos2_message_is_received_until_released() {
+        let mut state = SessionState::Connected(SessionConnectedState::new(
+            "someid".into(),
+            false,
+            None,
+            None,
+            None,
+        ));
+        let mut builder = mqtt_packets::v_3_1_1::builders::PublishPacketBuilder::new();
+        builder
+            .with_topic(Topic::try_from("a/b").unwrap())
+            .with_qos(&QoS::Two)
+            .with_packet_id(PacketId::new(7))
+            .with_payload(vec![0]);
+        let packet = builder.build();
+        let packet_id = PacketId::new(7);
+
+        assert!(!state.is_receiving(&packet_id));
+        state
+            .create_receive_transaction_from_packet(packet.clone())
+            .unwrap();
+        state.pubreced(&packet_id).unwrap();
+        assert!(state.is_receiving(&packet_id));
+
+        state
+            .create_receive_transaction_from_packet(packet.clone())
+            .unwrap();
+        state.pubrel(&packet_id).unwrap();
+        state.pubcomped(&packet_id).unwrap();
+        assert!(
+            !state.is_receiving(&packet_id),
+            "a released packet id can be reused by a new message"
+        );
+    }
 }
@@ -1,7 +1,15 @@
-use crate::session_state::SessionConnectedState;
+use crate::{
+    client_history::ClientHistory,
+    clock::Clock,
+    disconnect_reason::DisconnectReason,
+    publish_metadata::PublishMetadata,
+    session_state::{PendingMessage, QueueLimit, QueueOutcome, SessionConnectedState},
+    store_check::{read_entries, warn_about_corrupted, StoreEntries},
+};
 use log::{error, info};
-use mqtt_packets::v_3_1_1::ControlPacket;
-use serde_json::{from_reader, to_vec};
+use mqtt_packets::v_3_1_1::{topic::Subscription, variable::Variable, ControlPacket, QoS};
+use serde::Serialize;
+use serde_json::to_vec;
 use std::{
     collections::HashMap,
     fmt::Debug,
@@ -9,33 +17,59 @@ use std::{
     io,
     io::Write,
     path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 
 type ClientId = String;
 type InnerData = HashMap<ClientId, SessionConnectedState>;
 
+/// What has been removed from the store by `erase_client`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErasedClient {
+    pub session: bool,
+    pub queued_messages: usize,
+    pub history: bool,
+}
+
 /// Session state store where TeleMQ stores all sessions which have `clean_session: false`.
 /// For the whole TeleMQ lifetime it keeps states in memory by default. If `commit` is ever called
 /// `SessionStateStore` writes its inner data to file `./session_state_store.json`. In current
 /// TeleMQ implementation `commit` is called just once -- during TeleMQ graceful shut down.
 /// When `SessionStateStore` is being instantiated it tries to recover a state from
 /// `./session_state_store.json`. If file is not found or <b>in case of any other error an empty
-/// `SessionStateStore` will be created.</b>
+/// `SessionStateStore` will be created.</b> Corrupted sessions are skipped one by one, see
+/// `telemq --fsck-state-store`.
+/// History of all clients, including ones with clean sessions, is stored the same way in
+/// `./client_history.json`.
 #[derive(Debug)]
 pub struct SessionStateStore {
     /// We have locks per state, so two different states can be read/modified simultanously.
     states: HashMap<ClientId, RwLock<SessionConnectedState>>,
+    /// A connection holds a lock of its client id for its whole lifetime, from taking a state
+    /// to saving it back, so two connections with the same client id never own a session
+    /// at the same time.
+    session_locks: HashMap<ClientId, Arc<Mutex<()>>>,
+    history: HashMap<ClientId, ClientHistory>,
+    /// Shared by Control, connections and the Admin API, which reach the store anyway.
+    clock: Clock,
+    /// Messages queued for clients are dropped if they haven't been sent for this long.
+    message_expiry_interval: Option<Duration>,
 }
 
 impl SessionStateStore {
-    const DATA_FILE_PATH: &'static str = "./session_state_store.json";
+    pub(crate) const DATA_FILE_PATH: &'static str = "./session_state_store.json";
+    pub(crate) const HISTORY_FILE_PATH: &'static str = "./client_history.json";
 
-    pub fn new() -> SessionStateStore {
+    pub fn new(clock: Clock) -> SessionStateStore {
         match File::open(Path::new(Self::DATA_FILE_PATH)) {
             // try to restore an in-memory store from ./session_state_store.json
-            Ok(store_data_reader) => match from_reader(store_data_reader) {
-                Ok(inner_data) => Self::from_inner_data(inner_data),
+            Ok(store_data_reader) => match read_entries(store_data_reader) {
+                Ok(StoreEntries { entries, corrupted }) => {
+                    warn_about_corrupted("Session State Store", Self::DATA_FILE_PATH, &corrupted);
+                    Self::from_inner_data(entries, clock)
+                }
                 Err(err) => {
                     error!(
               "[Session State Store]: to parse data from file {}. {:?}. Continue using an empty store.",
@@ -43,6 +77,10 @@ impl SessionStateStore {
             );
                     return SessionStateStore {
                         states: HashMap::new(),
+                        session_locks: HashMap::new(),
+                        history: Self::read_history(),
+                        clock,
+                        message_expiry_interval: None,
                     };
                 }
             },
@@ -53,13 +91,37 @@ impl SessionStateStore {
         );
                 return SessionStateStore {
                     states: HashMap::new(),
+                    session_locks: HashMap::new(),
+                    history: Self::read_history(),
+                    clock,
+                    message_expiry_interval: None,
                 };
             }
         }
     }
 
-    pub async fn save_state(&mut self, state: SessionConnectedState) -> io::Result<()> {
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub fn with_message_expiry_interval(mut self, interval: Option<Duration>) -> Self {
+        self.message_expiry_interval = interval;
+        self
+    }
+
+    /// A message queued for a client now, which expires after `message_expiry_interval`.
+    pub fn pending_message(
+        &self,
+        packet: ControlPacket,
+        metadata: PublishMetadata,
+    ) -> PendingMessage {
+        PendingMessage::new(packet, metadata, self.clock.now())
+            .expiring_after(self.message_expiry_interval)
+    }
+
+    pub async fn save_state(&mut self, mut state: SessionConnectedState) -> io::Result<()> {
         let client_id = state.client_id.clone();
+        state.disconnected_at = Some(self.clock.now());
         self.states.insert(client_id, RwLock::new(state));
 
         Ok(())
@@ -69,32 +131,194 @@ impl SessionStateStore {
         &mut self,
         client_id: &ClientId,
     ) -> io::Result<Option<SessionConnectedState>> {
-        Ok(self
-            .states
-            .remove(client_id)
-            .map(|maybe_state_rw_lock| maybe_state_rw_lock.into_inner()))
+        Ok(self.states.remove(client_id).map(|maybe_state_rw_lock| {
+            let mut state = maybe_state_rw_lock.into_inner();
+            state.disconnected_at = None;
+            state
+        }))
     }
 
-    pub async fn new_publish(&self, client_id: &ClientId, packet: ControlPacket) -> io::Result<()> {
-        if let Some(session) = self.states.get(client_id) {
-            session
-                .write()
-                .await
-                .messages_pending_transmition
-                .push_back(packet.clone());
+    /// Removes sessions which clients have been disconnected for `expiry_interval` or longer,
+    /// together with their queues, and returns their client ids. Sessions which are being
+    /// taken over by a connection are kept.
+    pub fn remove_expired(&mut self, expiry_interval: Duration) -> Vec<ClientId> {
+        let now = self.clock.now();
+        let session_locks = &self.session_locks;
+        let mut expired = Vec::new();
+
+        self.states.retain(|client_id, state| {
+            // sessions stored without a disconnect time expire a whole interval from now
+            let disconnected_at = *state.get_mut().disconnected_at.get_or_insert(now);
+            let is_taken_over = session_locks
+                .get(client_id)
+                .is_some_and(|lock| lock.try_lock().is_err());
+            let is_expired = now
+                .duration_since(disconnected_at)
+                .is_ok_and(|disconnected_for| disconnected_for >= expiry_interval);
+
+            if is_expired && !is_taken_over {
+                expired.push(client_id.clone());
+                false
+            } else {
+                true
+            }
+        });
+
+        expired
+    }
+
+    /// Records a connection in a client history. Returns `true` if the client has no history
+    /// yet, i.e. it connects for the first time.
+    pub fn client_connected(&mut self, client_id: &ClientId, connected_at: SystemTime) -> bool {
+        let first_connection = !self.history.contains_key(client_id);
+        self.history
+            .entry(client_id.clone())
+            .or_default()
+            .connected(connected_at);
+        first_connection
+    }
+
+    /// Adds messages a client has exchanged over a closed connection to its history.
+    pub fn client_disconnected(
+        &mut self,
+        client_id: &ClientId,
+        reason: DisconnectReason,
+        messages_received: u64,
+        messages_sent: u64,
+    ) {
+        let now = self.clock.now();
+        self.history
+            .entry(client_id.clone())
+            .or_default()
+            .disconnected(now, reason, messages_received, messages_sent);
+    }
+
+    pub fn get_history(&self, client_id: &ClientId) -> Option<ClientHistory> {
+        self.history.get(client_id).cloned()
+    }
+
+    /// Removes everything stored of a client: its session together with queued messages and
+    /// its history. Returns `None` if the client is connected or its session is being taken
+    /// over, as the session would be saved again once the connection is closed.
+    pub fn erase_client(&mut self, client_id: &ClientId) -> Option<ErasedClient> {
+        let is_online = self
+            .history
+            .get(client_id)
+            .is_some_and(|history| history.online);
+        let is_taken_over = self
+            .session_locks
+            .get(client_id)
+            .is_some_and(|lock| lock.try_lock().is_err());
+        if is_online || is_taken_over {
+            return None;
         }
 
-        Ok(())
+        let session = self.states.remove(client_id);
+        Some(ErasedClient {
+            session: session.is_some(),
+            queued_messages: session
+                .map(|session| session.into_inner().messages_pending_transmition.len())
+                .unwrap_or(0),
+            history: self.history.remove(client_id).is_some(),
+        })
+    }
+
+    /// Returns `true` if there is a stored persistent session for a given client id.
+    pub fn has_session(&self, client_id: &ClientId) -> bool {
+        self.states.contains_key(client_id)
+    }
+
+    /// Returns a lock which serializes session takeover for a given client id.
+    pub fn session_lock(&mut self, client_id: &ClientId) -> Arc<Mutex<()>> {
+        // forget locks which are neither held nor awaited by any connection
+        self.session_locks
+            .retain(|_, lock| Arc::strong_count(lock) > 1);
+
+        self.session_locks
+            .entry(client_id.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Queues a Publish for a stored session respecting `limit`. Returns `None` if there is no
+    /// stored session for a given client id.
+    pub async fn new_publish(
+        &self,
+        client_id: &ClientId,
+        packet: ControlPacket,
+        metadata: PublishMetadata,
+        limit: &QueueLimit,
+    ) -> io::Result<Option<QueueOutcome>> {
+        let session = match self.states.get(client_id) {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        let mut session = session.write().await;
+        if !limit.queue_qos0_messages && session.delivery_qos(&packet) == QoS::Zero {
+            return Ok(Some(QueueOutcome::Skipped));
+        }
+
+        Ok(Some(session.queue_message(
+            self.pending_message(packet, metadata),
+            limit,
+        )))
+    }
+
+    /// Returns a copy of messages queued for a stored session, or `None` if there is no
+    /// stored session for a given client id.
+    pub async fn get_queue(&self, client_id: &ClientId) -> Option<Vec<PendingMessage>> {
+        match self.states.get(client_id) {
+            Some(session) => Some(
+                session
+                    .read()
+                    .await
+                    .messages_pending_transmition
+                    .iter()
+                    .cloned()
+                    .collect(),
+            ),
+            None => None,
+        }
+    }
+
+    /// Removes queued messages of a stored session. If `filter` is provided only messages
+    /// with topics matching the filter are removed, otherwise the whole queue is cleared.
+    /// Returns a number of removed messages, or `None` if there is no stored session for
+    /// a given client id.
+    pub async fn purge_queue(
+        &self,
+        client_id: &ClientId,
+        filter: Option<&Subscription>,
+    ) -> Option<usize> {
+        let session = self.states.get(client_id)?;
+        let mut session = session.write().await;
+        let queue = &mut session.messages_pending_transmition;
+        let before = queue.len();
+
+        match filter {
+            Some(filter) => queue.retain(|pending| match pending.packet.variable {
+                Variable::Publish(ref variable) => !filter.topic_matches(&variable.topic_name),
+                _ => true,
+            }),
+            None => queue.clear(),
+        }
+
+        Some(before - queue.len())
     }
 
     pub async fn commit(&self) -> io::Result<()> {
+        Self::write_file(Self::DATA_FILE_PATH, &self.as_inner_data().await)?;
+        Self::write_file(Self::HISTORY_FILE_PATH, &self.history)
+    }
+
+    fn write_file<T: Serialize>(path: &str, data: &T) -> io::Result<()> {
         let mut new_inner_data = OpenOptions::new()
             .append(false)
             .write(true)
             .create(true)
-            .open(Self::DATA_FILE_PATH)?;
+            .open(path)?;
         let _ = new_inner_data.set_len(0);
-        new_inner_data.write_all(&to_vec(&self.as_inner_data().await).map_err(|_| {
+        new_inner_data.write_all(&to_vec(data).map_err(|_| {
             io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Unable to serialize to an inner data",
@@ -105,7 +329,7 @@ impl SessionStateStore {
         Ok(())
     }
 
-    fn from_inner_data(inner_data: InnerData) -> SessionStateStore {
+    pub fn from_inner_data(inner_data: InnerData, clock: Clock) -> SessionStateStore {
         let mut states = HashMap::new();
 
         for (client_id, state) in inner_data {
@@ -114,7 +338,25 @@ impl SessionStateStore {
 
         info!("[Session State Store]: recovered from a local file");
 
-        SessionStateStore { states }
+        SessionStateStore {
+            states,
+            session_locks: HashMap::new(),
+            history: Self::read_history(),
+            clock,
+            message_expiry_interval: None,
+        }
+    }
+
+    // a missing or broken history file is not worth an error, history starts over
+    fn read_history() -> HashMap<ClientId, ClientHistory> {
+        File::open(Path::new(Self::HISTORY_FILE_PATH))
+            .ok()
+            .and_then(|history_reader| read_entries(history_reader).ok())
+            .map(|StoreEntries { entries, corrupted }| {
+                warn_about_corrupted("Session State Store", Self::HISTORY_FILE_PATH, &corrupted);
+                entries
+            })
+            .unwrap_or_default()
     }
 
     pub async fn as_inner_data(&self) -> InnerData {
@@ -127,3 +369,267 @@ impl SessionStateStore {
         inner_data
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        session_state::QueueOverflowPolicy,
+        transaction::{CreateTransaction, TransactionSend},
+    };
+    use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic, PacketId};
+    use std::time::SystemTime;
+
+    #[tokio::test]
+    async fn session_lock_is_shared_per_client_id() {
+        let mut store = SessionStateStore::from_inner_data(HashMap::new(), Clock::system());
+
+        let guard = store.session_lock(&"a".into()).try_lock_owned().unwrap();
+        assert!(store.session_lock(&"a".into()).try_lock_owned().is_err());
+        assert!(store.session_lock(&"b".into()).try_lock_owned().is_ok());
+
+        drop(guard);
+        assert!(store.session_lock(&"a".into()).try_lock_owned().is_ok());
+        // unused locks are forgotten
+        store.session_lock(&"c".into());
+        assert_eq!(store.session_locks.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_stamped_by_store_clock() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Clock::manual(start);
+        let mut store = SessionStateStore::from_inner_data(HashMap::new(), clock.clone());
+        store
+            .save_state(SessionConnectedState::new(
+                "a".into(),
+                false,
+                None,
+                None,
+                None,
+            ))
+            .await
+            .unwrap();
+        let limit = QueueLimit {
+            max_messages: None,
+            overflow_policy: QueueOverflowPolicy::DropOldest,
+            queue_qos0_messages: true,
+        };
+        let mut builder = PublishPacketBuilder::new();
+        builder.with_topic(Topic::try_from("a/b").unwrap());
+
+        clock.advance(Duration::from_secs(60));
+        store
+            .new_publish(&"a".into(), builder.build(), PublishMetadata::new(), &limit)
+            .await
+            .unwrap();
+
+        let queue = store.get_queue(&"a".into()).await.unwrap();
+        assert_eq!(queue[0].queued_at, start + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn sessions_of_stores_with_unstamped_queues_are_restored() {
+        let mut builder = PublishPacketBuilder::new();
+        builder.with_topic(Topic::try_from("a/b").unwrap());
+        let packet = serde_json::to_value(builder.build()).unwrap();
+        // a session as stored before queued messages have been timestamped
+        let file = serde_json::json!({
+            "a": {
+                "client_id": "a",
+                "clean_session": false,
+                "subscriptions": [],
+                "messages_sent_not_acked": {},
+                "messages_pending_transmition": [packet],
+                "messages_received_not_acked": {},
+                "will_flag": false,
+                "will_topic": null,
+                "will_message": null,
+                "will_qos": null,
+                "will_retain": false
+            }
+        })
+        .to_string();
+
+        let StoreEntries { entries, corrupted } =
+            read_entries::<SessionConnectedState, _>(file.as_bytes()).unwrap();
+        assert!(corrupted.is_empty());
+        let queue = &entries["a"].messages_pending_transmition;
+        assert_eq!(queue.len(), 1);
+        assert_eq!(serde_json::to_value(&queue[0].packet).unwrap(), packet);
+        assert!(queue[0].expires_at.is_none());
+    }
+
+    #[test]
+    fn sessions_of_stores_with_two_byte_packet_ids_are_restored() {
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(Topic::try_from("a/b").unwrap())
+            .with_qos(&QoS::One)
+            .with_packet_id(PacketId::new(258));
+        let mut transaction =
+            serde_json::to_value(TransactionSend::new(&PacketId::new(258), builder.build()))
+                .unwrap();
+        // packet ids have been stored as two bytes before they became numbers
+        transaction["packet_id"] = serde_json::json!([1, 2]);
+        let file = serde_json::json!({
+            "a": {
+                "client_id": "a",
+                "clean_session": false,
+                "subscriptions": [],
+                "messages_sent_not_acked": { "258": transaction },
+                "messages_pending_transmition": [],
+                "messages_received_not_acked": {},
+                "will_flag": false,
+                "will_topic": null,
+                "will_message": null,
+                "will_qos": null,
+                "will_retain": false
+            }
+        })
+        .to_string();
+
+        let StoreEntries { entries, corrupted } =
+            read_entries::<SessionConnectedState, _>(file.as_bytes()).unwrap();
+        assert!(corrupted.is_empty());
+        let in_flight = &entries["a"].messages_sent_not_acked;
+        assert_eq!(in_flight.len(), 1);
+        assert_eq!(in_flight[&PacketId::new(258)].packet_id, PacketId::new(258));
+    }
+
+    #[tokio::test]
+    async fn qos0_messages_are_queued_only_if_enabled() {
+        let mut store = SessionStateStore::from_inner_data(HashMap::new(), Clock::system());
+        let mut state = SessionConnectedState::new("a".into(), false, None, None, None);
+        state
+            .subscriptions
+            .push((QoS::Zero, Subscription::try_from("zero/#").unwrap()));
+        state
+            .subscriptions
+            .push((QoS::One, Subscription::try_from("one/#").unwrap()));
+        store.save_state(state).await.unwrap();
+        let mut limit = QueueLimit {
+            max_messages: None,
+            overflow_policy: QueueOverflowPolicy::DropOldest,
+            queue_qos0_messages: false,
+        };
+        let publish = |topic: &str, qos: QoS| {
+            let mut builder = PublishPacketBuilder::new();
+            builder
+                .with_topic(Topic::try_from(topic).unwrap())
+                .with_qos(&qos);
+            builder.build()
+        };
+
+        let mut outcomes = Vec::new();
+        // QoS 1 published to a QoS 0 subscription is delivered with QoS 0
+        for (topic, qos) in [
+            ("one/a", QoS::Zero),
+            ("one/a", QoS::Two),
+            ("zero/a", QoS::One),
+        ] {
+            let outcome = store
+                .new_publish(
+                    &"a".into(),
+                    publish(topic, qos),
+                    PublishMetadata::new(),
+                    &limit,
+                )
+                .await
+                .unwrap();
+            outcomes.push(outcome);
+        }
+        assert_eq!(
+            outcomes,
+            vec![
+                Some(QueueOutcome::Skipped),
+                Some(QueueOutcome::Queued),
+                Some(QueueOutcome::Skipped),
+            ]
+        );
+
+        limit.queue_qos0_messages = true;
+        let outcome = store
+            .new_publish(
+                &"a".into(),
+                publish("zero/a", QoS::Zero),
+                PublishMetadata::new(),
+                &limit,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome, Some(QueueOutcome::Queued));
+        assert_eq!(store.get_queue(&"a".into()).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn erased_client_leaves_nothing_behind() {
+        let clock = Clock::manual(SystemTime::UNIX_EPOCH);
+        let mut store = SessionStateStore::from_inner_data(HashMap::new(), clock.clone());
+        store
+            .save_state(SessionConnectedState::new(
+                "a".into(),
+                false,
+                None,
+                None,
+                None,
+            ))
+            .await
+            .unwrap();
+        let limit = QueueLimit {
+            max_messages: None,
+            overflow_policy: QueueOverflowPolicy::DropOldest,
+            queue_qos0_messages: true,
+        };
+        let mut builder = PublishPacketBuilder::new();
+        builder.with_topic(Topic::try_from("a/b").unwrap());
+        store
+            .new_publish(&"a".into(), builder.build(), PublishMetadata::new(), &limit)
+            .await
+            .unwrap();
+        store.client_connected(&"a".into(), clock.now());
+        store.client_disconnected(&"a".into(), DisconnectReason::ClientDisconnect, 1, 1);
+        store.client_connected(&"b".into(), clock.now());
+
+        assert_eq!(
+            store.erase_client(&"a".into()),
+            Some(ErasedClient {
+                session: true,
+                queued_messages: 1,
+                history: true,
+            })
+        );
+        assert!(!store.has_session(&"a".into()));
+        assert!(store.get_history(&"a".into()).is_none());
+        // "b" is online, so its session would be saved again on disconnect
+        assert_eq!(store.erase_client(&"b".into()), None);
+        assert!(store.get_history(&"b".into()).is_some());
+    }
+
+    #[tokio::test]
+    async fn sessions_expire_after_disconnect() {
+        let clock = Clock::manual(SystemTime::UNIX_EPOCH);
+        let mut store = SessionStateStore::from_inner_data(HashMap::new(), clock.clone());
+        let expiry_interval = Duration::from_secs(3600);
+        for client_id in ["a", "b", "c"] {
+            store
+                .save_state(SessionConnectedState::new(
+                    client_id.into(),
+                    false,
+                    None,
+                    None,
+                    None,
+                ))
+                .await
+                .unwrap();
+            clock.advance(Duration::from_secs(1800));
+        }
+        // "b" is being taken over by a connection
+        let _guard = store.session_lock(&"b".into()).try_lock_owned().unwrap();
+
+        assert_eq!(store.remove_expired(expiry_interval), vec!["a".to_string()]);
+        assert!(store.take_state(&"a".into()).await.unwrap().is_none());
+        assert!(store.take_state(&"b".into()).await.unwrap().is_some());
+        assert!(store.take_state(&"c".into()).await.unwrap().is_some());
+    }
+}
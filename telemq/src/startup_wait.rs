@@ -0,0 +1,125 @@
+//! Startup gating on backend dependencies.
+//!
+//! When a broker is started together with its backends (e.g. in the same Kubernetes pod or
+//! a docker-compose project) they may not be reachable yet. With `wait_for_state_store` or
+//! `wait_for_auth_endpoint` enabled, the broker retries a backend until it's reachable
+//! before it starts serving, instead of relying on an external wait-for script.
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use log::{info, warn};
+use reqwest::Client;
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout, Instant},
+};
+
+use crate::server_error::{ServerError, ServerResult};
+
+/// Max time of a single connectivity check.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Retries `probe` every `retry_interval` until it succeeds. Gives up after `max_wait`,
+/// or waits indefinitely if `max_wait` is zero.
+pub async fn wait_for<F, Fut>(
+    dependency: &str,
+    max_wait: Duration,
+    retry_interval: Duration,
+    mut probe: F,
+) -> ServerResult<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let started_at = Instant::now();
+    let mut attempt = 1;
+
+    loop {
+        match probe().await {
+            Ok(()) => {
+                info!(
+                    "[Startup]: {} is reachable after {} attempt(s)",
+                    dependency, attempt
+                );
+                return Ok(());
+            }
+            Err(err) => {
+                if !max_wait.is_zero() && started_at.elapsed() + retry_interval > max_wait {
+                    return Err(ServerError(format!(
+                        "{} is not reachable after {:?}. {}",
+                        dependency, max_wait, err
+                    )));
+                }
+                warn!(
+                    "[Startup]: {} is not reachable yet, attempt {}. {}. Retrying in {:?}",
+                    dependency, attempt, err, retry_interval
+                );
+            }
+        }
+
+        attempt += 1;
+        sleep(retry_interval).await;
+    }
+}
+
+/// Succeeds once a TCP connection to `addr` is established.
+pub async fn probe_tcp(addr: SocketAddr) -> Result<(), String> {
+    match timeout(PROBE_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err("connection timed out".into()),
+    }
+}
+
+/// Succeeds once `url` returns any HTTP response.
+pub async fn probe_http(url: &str) -> Result<(), String> {
+    let client = Client::builder()
+        .timeout(PROBE_TIMEOUT)
+        .build()
+        .map_err(|err| err.to_string())?;
+
+    client
+        .head(url)
+        .send()
+        .await
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[tokio::test]
+    async fn retries_until_reachable() {
+        let attempts = Cell::new(0);
+        let res = wait_for("backend", Duration::ZERO, Duration::from_millis(1), || {
+            attempts.set(attempts.get() + 1);
+            let reachable = attempts.get() == 3;
+            async move {
+                if reachable {
+                    Ok(())
+                } else {
+                    Err("connection refused".to_string())
+                }
+            }
+        })
+        .await;
+
+        assert!(res.is_ok());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_wait() {
+        let res = wait_for(
+            "backend",
+            Duration::from_millis(20),
+            Duration::from_millis(5),
+            || async { Err("connection refused".to_string()) },
+        )
+        .await;
+
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+/// Windows of load averages and their topic suffixes.
+const WINDOWS: [(&str, f64); 3] = [("1min", 60.0), ("5min", 300.0), ("15min", 900.0)];
+
+/// Per second rates of a monotonic counter averaged over 1, 5 and 15 minutes, similarly to
+/// mosquitto `$SYS/broker/load/...` topics and Unix load averages.
+///
+/// Averages are exponentially weighted, so a rate change is reflected straight away, but
+/// reaches its full value only after a window has passed. Averages start from zero.
+#[derive(Debug, Clone, Default)]
+pub struct LoadRates {
+    last_total: u128,
+    averages: [f64; 3],
+    /// The highest 1 minute average.
+    peak: f64,
+}
+
+impl LoadRates {
+    /// Takes a new counter value, `elapsed` is a time since a previous update.
+    pub fn update(&mut self, total: u128, elapsed: Duration) {
+        let elapsed = elapsed.as_secs_f64();
+        let delta = total.saturating_sub(self.last_total) as f64;
+        self.last_total = total;
+        if elapsed <= 0.0 {
+            return;
+        }
+
+        let rate = delta / elapsed;
+        for (average, (_, window)) in self.averages.iter_mut().zip(WINDOWS) {
+            let weight = 1.0 - (-elapsed / window).exp();
+            *average += weight * (rate - *average);
+        }
+        self.peak = self.peak.max(self.averages[0]);
+    }
+
+    /// `(suffix, rate)` pairs, e.g. `("1min", 12.5)` and `("peak", 40.0)`.
+    pub fn rates(&self) -> Vec<(&'static str, f64)> {
+        WINDOWS
+            .iter()
+            .map(|(suffix, _)| *suffix)
+            .zip(self.averages)
+            .chain(Some(("peak", self.peak)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(load: &LoadRates, suffix: &str) -> f64 {
+        load.rates()
+            .into_iter()
+            .find(|(s, _)| *s == suffix)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn averages_converge_to_a_steady_rate() {
+        let mut load = LoadRates::default();
+        let mut total = 0;
+        // 10 messages per second for three hours, sampled every 30 seconds
+        for _ in 0..360 {
+            total += 300;
+            load.update(total, Duration::from_secs(30));
+        }
+
+        for suffix in ["1min", "5min", "15min", "peak"] {
+            assert!((rate(&load, suffix) - 10.0).abs() < 0.1, "{}", suffix);
+        }
+    }
+
+    #[test]
+    fn shorter_windows_react_faster() {
+        let mut load = LoadRates::default();
+        load.update(6000, Duration::from_secs(60));
+
+        let (one, five, fifteen) = (
+            rate(&load, "1min"),
+            rate(&load, "5min"),
+            rate(&load, "15min"),
+        );
+        assert!(one > five && five > fifteen);
+        assert!((one - 100.0 * (1.0 - (-1.0f64).exp())).abs() < 1e-9);
+    }
+
+    #[test]
+    fn peak_is_kept_once_load_drops() {
+        let mut load = LoadRates::default();
+        load.update(6000, Duration::from_secs(60));
+        let peak = rate(&load, "peak");
+        for _ in 0..60 {
+            load.update(6000, Duration::from_secs(60));
+        }
+
+        assert!(rate(&load, "1min") < 0.01);
+        assert_eq!(rate(&load, "peak"), peak);
+    }
+}
@@ -1,34 +1,111 @@
-use mqtt_packets::v_3_1_1::ControlPacket;
-use std::net::SocketAddr;
+use mqtt_packets::v_3_1_1::{variable::Variable, ControlPacket};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::oneshot;
+
+use crate::{
+    config::PayloadFormat, connection_info::ConnectionInfo, disconnect_reason::DisconnectReason,
+    subscription_tree::TreeUsage,
+};
 
 #[derive(Debug)]
 pub enum StatsMessage {
     ClientConnected {
-        client_id: String,
+        connection: Arc<ConnectionInfo>,
         clean_session: bool,
-        addr: SocketAddr,
+        /// The client id has no history, i.e. it has never connected before.
+        first_connection: bool,
     },
     ClientDisconnected {
         client_id: String,
+        reason: DisconnectReason,
     },
     PacketProcessedSend {
         client_id: String,
         bytes: u64,
+        /// Payload size of a PUBLISH packet.
+        payload_bytes: Option<u64>,
     },
     PacketProcessedReceived {
         client_id: String,
         bytes: u64,
+        /// Payload size of a PUBLISH packet.
+        payload_bytes: Option<u64>,
+    },
+    SessionRecoveryFailed,
+    /// A new connection has taken a session over from a connection with the same client id.
+    SessionTakenOver {
+        /// Time the new connection has waited for the session to be released.
+        latency: Duration,
+    },
+    /// A decision of the shadow ACL differs from the active one.
+    AclShadowDivergence,
+    /// A publish to a connection has been dropped, as its channel has been full.
+    ConnectionChannelFull,
+    /// Messages queued for a client have been dropped, as `message_expiry_interval` has passed
+    /// before they have been sent.
+    MessagesExpired {
+        count: usize,
+    },
+    /// A publish has been rejected, as its payload doesn't have a format of `payload_formats`.
+    PayloadRejected {
+        format: PayloadFormat,
+    },
+    /// A listener has failed to accept a connection.
+    AcceptFailed {
+        /// The process or the system has run out of file descriptors.
+        fd_exhausted: bool,
+    },
+    /// Limit of open files of the broker process, reported at startup.
+    OpenFilesLimit {
+        limit: u64,
+    },
+    /// Progress of the webhook, reported once its outbox is changed.
+    WebhookOutbox {
+        /// Events waiting to be delivered.
+        queued: usize,
+        delivered: usize,
+        /// Events dropped from a full outbox.
+        dropped: usize,
+    },
+    /// Size of the subscription tree, sampled from Control Worker.
+    SubscriptionTreeUsage {
+        usage: TreeUsage,
+    },
+    /// Requests current metrics in Prometheus text format.
+    Scrape {
+        reply: oneshot::Sender<String>,
+    },
+    /// Requests the uptime and main counters of the broker.
+    Summary {
+        reply: oneshot::Sender<StatsSummary>,
     },
 }
 
+/// Uptime and main counters of the broker, e.g. for a status overview.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsSummary {
+    pub uptime: Duration,
+    pub clients_connected: u64,
+    pub clients_maximum: u64,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
 impl StatsMessage {
     pub fn new_packet_processed_received(
         client_id: String,
         control_packet: &ControlPacket,
     ) -> StatsMessage {
         let bytes = Self::bytes_number(control_packet);
+        let payload_bytes = Self::payload_bytes_number(control_packet);
 
-        StatsMessage::PacketProcessedReceived { client_id, bytes }
+        StatsMessage::PacketProcessedReceived {
+            client_id,
+            bytes,
+            payload_bytes,
+        }
     }
 
     pub fn new_packet_processed_send(
@@ -36,20 +113,44 @@ impl StatsMessage {
         control_packet: &ControlPacket,
     ) -> StatsMessage {
         let bytes = Self::bytes_number(control_packet);
+        let payload_bytes = Self::payload_bytes_number(control_packet);
 
-        StatsMessage::PacketProcessedSend { client_id, bytes }
+        StatsMessage::PacketProcessedSend {
+            client_id,
+            bytes,
+            payload_bytes,
+        }
     }
 
     fn bytes_number(control_packet: &ControlPacket) -> u64 {
         (control_packet.fixed_header.remaining_length.as_value() + 1) as u64
     }
 
+    fn payload_bytes_number(control_packet: &ControlPacket) -> Option<u64> {
+        match control_packet.variable {
+            Variable::Publish(ref variable) => Some(variable.payload.len() as u64),
+            _ => None,
+        }
+    }
+
     pub fn get_name(&self) -> String {
         match self {
             Self::ClientConnected { .. } => "StatsMessage::ClientConnected".into(),
             Self::ClientDisconnected { .. } => "StatsMessage::ClientDisconnected".into(),
             Self::PacketProcessedReceived { .. } => "StatsMessage::PacketProcessedReceived".into(),
             Self::PacketProcessedSend { .. } => "StatsMessage::PacketProcessedReceived".into(),
+            Self::SessionRecoveryFailed => "StatsMessage::SessionRecoveryFailed".into(),
+            Self::SessionTakenOver { .. } => "StatsMessage::SessionTakenOver".into(),
+            Self::AclShadowDivergence => "StatsMessage::AclShadowDivergence".into(),
+            Self::ConnectionChannelFull => "StatsMessage::ConnectionChannelFull".into(),
+            Self::MessagesExpired { .. } => "StatsMessage::MessagesExpired".into(),
+            Self::PayloadRejected { .. } => "StatsMessage::PayloadRejected".into(),
+            Self::AcceptFailed { .. } => "StatsMessage::AcceptFailed".into(),
+            Self::OpenFilesLimit { .. } => "StatsMessage::OpenFilesLimit".into(),
+            Self::WebhookOutbox { .. } => "StatsMessage::WebhookOutbox".into(),
+            Self::SubscriptionTreeUsage { .. } => "StatsMessage::SubscriptionTreeUsage".into(),
+            Self::Scrape { .. } => "StatsMessage::Scrape".into(),
+            Self::Summary { .. } => "StatsMessage::Summary".into(),
         }
     }
 }
@@ -1,4 +1,6 @@
+mod load;
 mod message;
+mod payload_size;
 mod stats;
 mod stats_state;
 
@@ -0,0 +1,126 @@
+/// Histogram of PUBLISH payload sizes in bytes, also used for session takeover latencies
+/// in milliseconds.
+///
+/// Every bucket counts payloads which are greater than an upper bound of a previous bucket
+/// and less or equal to its own upper bound. Payloads which are greater than the last upper
+/// bound are counted by an extra `+Inf` bucket.
+#[derive(Debug, Clone)]
+pub struct PayloadSizeHistogram {
+    upper_bounds: Vec<usize>,
+    counts: Vec<u64>,
+    sum: u64,
+    max: usize,
+}
+
+impl PayloadSizeHistogram {
+    /// `upper_bounds` are expected to be sorted in ascending order.
+    pub fn new(upper_bounds: Vec<usize>) -> Self {
+        let counts = vec![0; upper_bounds.len() + 1];
+
+        PayloadSizeHistogram {
+            upper_bounds,
+            counts,
+            sum: 0,
+            max: 0,
+        }
+    }
+
+    pub fn observe(&mut self, size: usize) {
+        let bucket = self
+            .upper_bounds
+            .iter()
+            .position(|upper_bound| size <= *upper_bound)
+            .unwrap_or(self.upper_bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += size as u64;
+
+        if self.max < size {
+            self.max = size;
+        }
+    }
+
+    /// Max observed payload size.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// JSON object of bucket counts keyed by upper bounds, e.g. `{"64":10,"256":2,"+Inf":0}`.
+    pub fn to_json(&self) -> String {
+        let buckets = self
+            .upper_bounds
+            .iter()
+            .map(ToString::to_string)
+            .chain(Some("+Inf".to_string()))
+            .zip(&self.counts)
+            .map(|(upper_bound, count)| format!("\"{}\":{}", upper_bound, count))
+            .collect::<Vec<_>>();
+
+        format!("{{{}}}", buckets.join(","))
+    }
+
+    /// Samples of a Prometheus histogram `name`. `labels` are added to every sample,
+    /// e.g. `direction="sent"`, or empty.
+    pub fn to_prometheus(&self, name: &str, labels: &str) -> String {
+        let mut samples = String::new();
+        let bucket_labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{},", labels)
+        };
+        let mut cumulative_count = 0;
+        let upper_bounds = self
+            .upper_bounds
+            .iter()
+            .map(ToString::to_string)
+            .chain(Some("+Inf".to_string()));
+        for (upper_bound, count) in upper_bounds.zip(&self.counts) {
+            cumulative_count += count;
+            samples.push_str(&format!(
+                "{}_bucket{{{}le=\"{}\"}} {}\n",
+                name, bucket_labels, upper_bound, cumulative_count
+            ));
+        }
+        samples.push_str(&format!("{}_sum{{{}}} {}\n", name, labels, self.sum));
+        samples.push_str(&format!(
+            "{}_count{{{}}} {}\n",
+            name, labels, cumulative_count
+        ));
+
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_are_counted_per_bucket() {
+        let mut histogram = PayloadSizeHistogram::new(vec![10, 100]);
+        assert_eq!(histogram.to_json(), r#"{"10":0,"100":0,"+Inf":0}"#);
+        assert_eq!(histogram.max(), 0);
+
+        for size in [0, 10, 11, 100, 101, 5000] {
+            histogram.observe(size);
+        }
+        assert_eq!(histogram.to_json(), r#"{"10":2,"100":2,"+Inf":2}"#);
+        assert_eq!(histogram.max(), 5000);
+    }
+
+    #[test]
+    fn prometheus_buckets_are_cumulative() {
+        let mut histogram = PayloadSizeHistogram::new(vec![10, 100]);
+        for size in [0, 10, 11, 100, 101, 5000] {
+            histogram.observe(size);
+        }
+
+        assert_eq!(
+            histogram.to_prometheus("payload_bytes", "direction=\"sent\""),
+            "payload_bytes_bucket{direction=\"sent\",le=\"10\"} 2\n\
+             payload_bytes_bucket{direction=\"sent\",le=\"100\"} 4\n\
+             payload_bytes_bucket{direction=\"sent\",le=\"+Inf\"} 6\n\
+             payload_bytes_sum{direction=\"sent\"} 5222\n\
+             payload_bytes_count{direction=\"sent\"} 6\n"
+        );
+    }
+}
@@ -2,13 +2,23 @@ use super::{
     message::StatsMessage,
     stats_state::{StatsState, StatsStateView},
 };
-use crate::control::{ControlMessage, ControlSender};
+use crate::{
+    bootstrap::{first_connection, BootstrapSender},
+    connection_limit::ConnectionLimit,
+    control::{ControlMessage, ControlSender},
+    publish_metadata::PublishMetadata,
+    transport_bytes::ListenerTransportBytes,
+    webhook::{lifecycle_event, WebhookSender},
+};
 use log::{error, info};
 use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic, ControlPacket};
-use std::{io, time::Duration};
+use std::{io, sync::Arc, time::Duration};
 use tokio::{
     select,
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
     time::interval,
 };
 
@@ -17,7 +27,19 @@ pub type StatsReceiver = UnboundedReceiver<StatsMessage>;
 
 pub struct StatsConfig {
     pub update_interval: Duration,
+    /// Upper bounds (in bytes) of payload size histogram buckets.
+    pub payload_size_buckets: Vec<usize>,
+    /// Counters of every connected client are published under `$SYS/broker/clients/`.
+    pub per_client: bool,
     pub control_sender: ControlSender,
+    pub connection_limit: Arc<ConnectionLimit>,
+    /// Bytes on the wire per listener, `None` unless `transport_byte_counters` is enabled.
+    pub transport_bytes: Option<ListenerTransportBytes>,
+    /// Connects and disconnects are forwarded to the webhook, `None` unless `webhook_url` is set.
+    pub webhook_sender: Option<WebhookSender>,
+    /// Clients connected for the first time are forwarded to Bootstrap, `None` unless there
+    /// are `bootstrap` rules.
+    pub bootstrap_sender: Option<BootstrapSender>,
 }
 
 pub struct Stats {
@@ -25,6 +47,8 @@ pub struct Stats {
     state: StatsState,
     update_interval: Duration,
     control_sender: ControlSender,
+    webhook_sender: Option<WebhookSender>,
+    bootstrap_sender: Option<BootstrapSender>,
 }
 
 impl Stats {
@@ -34,9 +58,16 @@ impl Stats {
         (
             Stats {
                 receiver,
-                state: StatsState::new(),
+                state: StatsState::new(
+                    config.payload_size_buckets,
+                    config.connection_limit,
+                    config.per_client,
+                    config.transport_bytes,
+                ),
                 update_interval: config.update_interval,
                 control_sender: config.control_sender,
+                webhook_sender: config.webhook_sender,
+                bootstrap_sender: config.bootstrap_sender,
             },
             sender,
         )
@@ -44,26 +75,30 @@ impl Stats {
 
     pub async fn run(mut self) -> io::Result<()> {
         if self.update_interval.is_zero() {
-            info!("[Stats Worker]: update interval is zero. Ingore incomming messages");
-            loop {
-                // do nothing with a message
-                self.receiver.recv().await;
+            info!("[Stats Worker]: update interval is zero. $SYS topics are disabled");
+            // metrics are still collected for scraping
+            while let Some(stats_message) = self.receiver.recv().await {
+                self.on_stats_message(stats_message).await;
             }
+            Ok(())
         } else {
             let mut interval_stream = interval(self.update_interval);
             loop {
                 select! {
                   Some(stats_message) = self.receiver.recv() => {
-                    self.state.update(stats_message);
+                    self.on_stats_message(stats_message).await;
                   },
                   _ = interval_stream.tick() => {
+                    self.sample_subscription_tree().await;
                     let metrics = self.state.checkpoint();
                     for mtr in metrics {
                       let packet = Self::build_publish_packet(mtr);
                       if let Err(err) = self.control_sender.send(ControlMessage::Publish{
-                        addr: None,
-                        client_id: None,
-                        packet
+                        publisher: None,
+                        packet,
+                        metadata: PublishMetadata::default(),
+                        sequence: None,
+                        subscribers: None
                       }) {
                         error!("[Stats Worker]: Unable to publish stats update - {:?}", err);
                       }
@@ -74,6 +109,50 @@ impl Stats {
         }
     }
 
+    async fn on_stats_message(&mut self, stats_message: StatsMessage) {
+        if let StatsMessage::Scrape { .. } = stats_message {
+            self.sample_subscription_tree().await;
+        }
+        if let Some(ref webhook_sender) = self.webhook_sender {
+            if let Some(event) = lifecycle_event(&stats_message) {
+                if let Err(err) = webhook_sender.send(event) {
+                    error!(
+                        "[Stats Worker]: unable to send an event to Webhook. {:?}",
+                        err
+                    );
+                }
+            }
+        }
+        if let Some(ref bootstrap_sender) = self.bootstrap_sender {
+            if let Some(client_id) = first_connection(&stats_message) {
+                if let Err(err) = bootstrap_sender.send(client_id) {
+                    error!(
+                        "[Stats Worker]: unable to send a client to Bootstrap. {:?}",
+                        err
+                    );
+                }
+            }
+        }
+        self.state.update(stats_message);
+    }
+
+    /// The subscription tree is owned by Control Worker, so its size is requested
+    /// right before metrics are published or scraped.
+    async fn sample_subscription_tree(&mut self) {
+        let (reply, response) = oneshot::channel();
+        if self
+            .control_sender
+            .send(ControlMessage::SubscriptionTreeUsage { reply })
+            .is_err()
+        {
+            return;
+        }
+        if let Ok((usage, _)) = response.await {
+            self.state
+                .update(StatsMessage::SubscriptionTreeUsage { usage });
+        }
+    }
+
     fn build_publish_packet(d: StatsStateView) -> ControlPacket {
         let sys_topic = Topic::make_from_string(format!("$SYS/{}", d.0));
         let mut builder = PublishPacketBuilder::new();
@@ -1,5 +1,17 @@
-use super::message::StatsMessage;
-use std::collections::{HashMap, HashSet};
+use super::{
+    load::LoadRates,
+    message::{StatsMessage, StatsSummary},
+    payload_size::PayloadSizeHistogram,
+};
+use crate::{
+    config::PayloadFormat, connection_limit::ConnectionLimit, disconnect_reason::DisconnectReason,
+    transport_bytes::ListenerTransportBytes,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 /// Statistics state difference item, represented as a tuple
 /// `(path, new_value)`. Where `path` is a string of `a/b/c` (for example, `clients/current`) form
@@ -10,40 +22,311 @@ pub type StatsStateView = (String, String);
 
 pub struct StatsState {
     current: StatsStateInner,
+    started_at: Instant,
+    last_checkpoint: Instant,
 }
 
 impl StatsState {
-    pub fn new() -> StatsState {
+    pub fn new(
+        payload_size_buckets: Vec<usize>,
+        connection_limit: Arc<ConnectionLimit>,
+        per_client: bool,
+        transport_bytes: Option<ListenerTransportBytes>,
+    ) -> StatsState {
+        let now = Instant::now();
         StatsState {
-            current: StatsStateInner::new(),
+            current: StatsStateInner::new(
+                payload_size_buckets,
+                connection_limit,
+                per_client,
+                transport_bytes,
+            ),
+            started_at: now,
+            last_checkpoint: now,
         }
     }
 
     pub fn update(&mut self, message: StatsMessage) {
-        self.current.update(message);
+        match message {
+            StatsMessage::Summary { reply } => {
+                // a requester may be gone already
+                let _ = reply.send(self.summary());
+            }
+            message => self.current.update(message),
+        }
+    }
+
+    fn summary(&self) -> StatsSummary {
+        let metric = |name| self.current.metrics.get(name).copied().unwrap_or(0) as u64;
+        StatsSummary {
+            uptime: self.started_at.elapsed(),
+            clients_connected: metric(StatsStateInner::BROKER_CLIENTS_CONNECTED),
+            clients_maximum: metric(StatsStateInner::BROKER_CLIENTS_MAXIMUM),
+            messages_received: metric(StatsStateInner::BROKER_MESSAGES_RECEIVED_NAME),
+            messages_sent: metric(StatsStateInner::BROKER_MESSAGES_SENT_NAME),
+            bytes_received: metric(StatsStateInner::BROKER_BYTES_RECEIVED_NAME),
+            bytes_sent: metric(StatsStateInner::BROKER_BYTES_SENT_NAME),
+        }
     }
 
     /// Returns a list of metrics views.
     pub fn checkpoint(&mut self) -> Vec<StatsStateView> {
-        self.current.get_metrics()
+        let now = Instant::now();
+        self.current
+            .update_load(now.duration_since(self.last_checkpoint));
+        self.last_checkpoint = now;
+        self.current.sample_connections();
+
+        let mut metrics = self.current.get_metrics();
+        metrics.push((
+            StatsStateInner::BROKER_UPTIME.to_string(),
+            format!("{}", now.duration_since(self.started_at).as_secs()),
+        ));
+        metrics.push((
+            StatsStateInner::BROKER_VERSION.to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        ));
+        metrics
     }
 }
 
+/// Counters of a single connected client.
+#[derive(Clone, Default)]
+struct ClientCounters {
+    messages_received: u64,
+    messages_sent: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
 #[derive(Clone)]
 struct StatsStateInner {
     clients_online: HashSet<String>,
     metrics: HashMap<&'static str, u128>,
+    payload_sizes_received: PayloadSizeHistogram,
+    payload_sizes_sent: PayloadSizeHistogram,
+    /// Rates of counters, keyed by counter names.
+    loads: Vec<(&'static str, LoadRates)>,
+    /// Open network connections and their limit are sampled from listeners.
+    connection_limit: Arc<ConnectionLimit>,
+    /// Counters of connected clients, `None` unless `sys_topics_per_client` is enabled.
+    clients: Option<HashMap<String, ClientCounters>>,
+    /// Closed connections of clients by a reason.
+    disconnects: HashMap<DisconnectReason, u128>,
+    /// Bytes on the wire per listener, `None` unless `transport_byte_counters` is enabled.
+    transport_bytes: Option<ListenerTransportBytes>,
+    /// Time new connections have waited for sessions taken over, in milliseconds.
+    takeover_latencies: PayloadSizeHistogram,
 }
 
 impl StatsStateInner {
+    const BROKER_UPTIME: &'static str = "broker/uptime";
+    const BROKER_VERSION: &'static str = "broker/version";
     const BROKER_BYTES_RECEIVED_NAME: &'static str = "broker/bytes/received";
     const BROKER_BYTES_SENT_NAME: &'static str = "broker/bytes/sent";
     const BROKER_MESSAGES_RECEIVED_NAME: &'static str = "broker/messages/received";
     const BROKER_MESSAGES_SENT_NAME: &'static str = "broker/messages/sent";
     const BROKER_CLIENTS_CONNECTED: &'static str = "broker/clients/connected";
     const BROKER_CLIENTS_MAXIMUM: &'static str = "broker/clients/maximum";
+    const BROKER_SESSIONS_RECOVERY_FAILURES: &'static str = "broker/sessions/recovery_failures";
+    const BROKER_SESSIONS_TAKEOVERS: &'static str = "broker/sessions/takeovers";
+    const BROKER_SESSIONS_TAKEOVER_LATENCY: &'static str = "broker/sessions/takeover_latency";
+    const BROKER_SESSIONS_TAKEOVER_LATENCY_MAX: &'static str =
+        "broker/sessions/takeover_latency_max";
+    const BROKER_LISTENER_ACCEPT_ERRORS: &'static str = "broker/listener/accept_errors";
+    const BROKER_LISTENER_FD_EXHAUSTIONS: &'static str = "broker/listener/fd_exhaustions";
+    const BROKER_LISTENER_OPEN_FILES_LIMIT: &'static str = "broker/listener/open_files_limit";
+    const BROKER_LISTENER_CONNECTIONS: &'static str = "broker/listener/connections";
+    const BROKER_LISTENER_MAX_CONNECTIONS: &'static str = "broker/listener/max_connections";
+    const BROKER_SUBSCRIPTIONS_COUNT: &'static str = "broker/subscriptions/count";
+    const BROKER_SUBSCRIPTIONS_TREE_NODES: &'static str = "broker/subscriptions/tree/nodes";
+    const BROKER_SUBSCRIPTIONS_TREE_MEMORY: &'static str = "broker/subscriptions/tree/memory";
+    const BROKER_ACL_SHADOW_DIVERGENCES: &'static str = "broker/acl/shadow/divergences";
+    const BROKER_MESSAGES_DROPPED_CHANNEL_FULL: &'static str =
+        "broker/messages/dropped/channel_full";
+    const BROKER_MESSAGES_DROPPED_EXPIRED: &'static str = "broker/messages/dropped/expired";
+    const BROKER_MESSAGES_REJECTED_UTF8: &'static str = "broker/messages/rejected/utf8";
+    const BROKER_MESSAGES_REJECTED_JSON: &'static str = "broker/messages/rejected/json";
+    const BROKER_WEBHOOK_QUEUED: &'static str = "broker/webhook/queued";
+    const BROKER_WEBHOOK_DELIVERED: &'static str = "broker/webhook/delivered";
+    const BROKER_WEBHOOK_DROPPED: &'static str = "broker/webhook/dropped";
+    /// Followed by a disconnect reason.
+    const BROKER_DISCONNECTS: &'static str = "broker/disconnects";
+    /// Followed by a listener and `bytes/received` or `bytes/sent`.
+    const BROKER_LISTENER: &'static str = "broker/listener";
+    const BROKER_MESSAGES_SIZE_RECEIVED: &'static str = "broker/messages/size/received";
+    const BROKER_MESSAGES_SIZE_SENT: &'static str = "broker/messages/size/sent";
+    const BROKER_MESSAGES_SIZE_MAX: &'static str = "broker/messages/size/max";
+    /// `(metric, Prometheus name, Prometheus type, help)` of metrics exposed to Prometheus.
+    const PROMETHEUS_METRICS: [(&'static str, &'static str, &'static str, &'static str); 24] = [
+        (
+            Self::BROKER_BYTES_RECEIVED_NAME,
+            "telemq_bytes_received_total",
+            "counter",
+            "Bytes received from clients.",
+        ),
+        (
+            Self::BROKER_BYTES_SENT_NAME,
+            "telemq_bytes_sent_total",
+            "counter",
+            "Bytes sent to clients.",
+        ),
+        (
+            Self::BROKER_MESSAGES_RECEIVED_NAME,
+            "telemq_messages_received_total",
+            "counter",
+            "Packets received from clients.",
+        ),
+        (
+            Self::BROKER_MESSAGES_SENT_NAME,
+            "telemq_messages_sent_total",
+            "counter",
+            "Packets sent to clients.",
+        ),
+        (
+            Self::BROKER_CLIENTS_CONNECTED,
+            "telemq_clients_connected",
+            "gauge",
+            "Currently connected clients.",
+        ),
+        (
+            Self::BROKER_CLIENTS_MAXIMUM,
+            "telemq_clients_maximum",
+            "gauge",
+            "Maximal number of simultaneously connected clients.",
+        ),
+        (
+            Self::BROKER_SESSIONS_RECOVERY_FAILURES,
+            "telemq_sessions_recovery_failures_total",
+            "counter",
+            "Sessions which could not be recovered from the Session State Store.",
+        ),
+        (
+            Self::BROKER_SESSIONS_TAKEOVERS,
+            "telemq_session_takeovers_total",
+            "counter",
+            "Sessions taken over by a new connection with the same client id.",
+        ),
+        (
+            Self::BROKER_LISTENER_ACCEPT_ERRORS,
+            "telemq_accept_errors_total",
+            "counter",
+            "Connections listeners have failed to accept.",
+        ),
+        (
+            Self::BROKER_LISTENER_FD_EXHAUSTIONS,
+            "telemq_fd_exhaustions_total",
+            "counter",
+            "Accept errors caused by running out of file descriptors.",
+        ),
+        (
+            Self::BROKER_LISTENER_OPEN_FILES_LIMIT,
+            "telemq_open_files_limit",
+            "gauge",
+            "Limit of open files of the broker process, 0 if unlimited.",
+        ),
+        (
+            Self::BROKER_LISTENER_CONNECTIONS,
+            "telemq_connections",
+            "gauge",
+            "Open network connections, including ones which have not sent CONNECT yet.",
+        ),
+        (
+            Self::BROKER_LISTENER_MAX_CONNECTIONS,
+            "telemq_max_connections",
+            "gauge",
+            "Limit of open network connections (max_connections).",
+        ),
+        (
+            Self::BROKER_SUBSCRIPTIONS_COUNT,
+            "telemq_subscriptions",
+            "gauge",
+            "Pairs of a topic filter and a client id in the subscription tree.",
+        ),
+        (
+            Self::BROKER_SUBSCRIPTIONS_TREE_NODES,
+            "telemq_subscription_tree_nodes",
+            "gauge",
+            "Topic levels in the subscription tree.",
+        ),
+        (
+            Self::BROKER_SUBSCRIPTIONS_TREE_MEMORY,
+            "telemq_subscription_tree_memory_bytes",
+            "gauge",
+            "Estimated memory held by the subscription tree.",
+        ),
+        (
+            Self::BROKER_ACL_SHADOW_DIVERGENCES,
+            "telemq_acl_shadow_divergences_total",
+            "counter",
+            "Publish and subscribe decisions of the shadow ACL which differ from the active ACL.",
+        ),
+        (
+            Self::BROKER_MESSAGES_DROPPED_CHANNEL_FULL,
+            "telemq_channel_full_drops_total",
+            "counter",
+            "Messages dropped because a channel of a slow connection has been full.",
+        ),
+        (
+            Self::BROKER_MESSAGES_DROPPED_EXPIRED,
+            "telemq_expired_messages_total",
+            "counter",
+            "Queued messages dropped because they have not been sent within message_expiry_interval.",
+        ),
+        (
+            Self::BROKER_MESSAGES_REJECTED_UTF8,
+            "telemq_invalid_utf8_rejections_total",
+            "counter",
+            "Publishes rejected because their payloads are not valid UTF-8.",
+        ),
+        (
+            Self::BROKER_MESSAGES_REJECTED_JSON,
+            "telemq_invalid_json_rejections_total",
+            "counter",
+            "Publishes rejected because their payloads are not valid JSON.",
+        ),
+        (
+            Self::BROKER_WEBHOOK_QUEUED,
+            "telemq_webhook_outbox_events",
+            "gauge",
+            "Lifecycle events in the webhook outbox waiting to be delivered.",
+        ),
+        (
+            Self::BROKER_WEBHOOK_DELIVERED,
+            "telemq_webhook_delivered_total",
+            "counter",
+            "Lifecycle events delivered to the webhook.",
+        ),
+        (
+            Self::BROKER_WEBHOOK_DROPPED,
+            "telemq_webhook_dropped_total",
+            "counter",
+            "Lifecycle events dropped because the webhook outbox has been full.",
+        ),
+    ];
+    const PROMETHEUS_PAYLOAD_SIZE: &'static str = "telemq_message_payload_bytes";
+    const PROMETHEUS_TAKEOVER_LATENCY: &'static str =
+        "telemq_session_takeover_latency_milliseconds";
+    /// Upper bounds of takeover latency buckets in milliseconds.
+    const TAKEOVER_LATENCY_BUCKETS: [usize; 9] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+    const PROMETHEUS_DISCONNECTS: &'static str = "telemq_disconnects_total";
+    const PROMETHEUS_TRANSPORT_BYTES_RECEIVED: &'static str =
+        "telemq_transport_bytes_received_total";
+    const PROMETHEUS_TRANSPORT_BYTES_SENT: &'static str = "telemq_transport_bytes_sent_total";
+    /// Counters which rates are published under `broker/load/`.
+    const LOAD_COUNTERS: [&'static str; 4] = [
+        Self::BROKER_MESSAGES_RECEIVED_NAME,
+        Self::BROKER_MESSAGES_SENT_NAME,
+        Self::BROKER_BYTES_RECEIVED_NAME,
+        Self::BROKER_BYTES_SENT_NAME,
+    ];
 
-    fn new() -> Self {
+    fn new(
+        payload_size_buckets: Vec<usize>,
+        connection_limit: Arc<ConnectionLimit>,
+        per_client: bool,
+        transport_bytes: Option<ListenerTransportBytes>,
+    ) -> Self {
         let mut metrics = HashMap::new();
         metrics.insert(Self::BROKER_BYTES_RECEIVED_NAME, 0u8.into());
         metrics.insert(Self::BROKER_BYTES_SENT_NAME, 0u8.into());
@@ -51,28 +334,146 @@ impl StatsStateInner {
         metrics.insert(Self::BROKER_MESSAGES_SENT_NAME, 0u8.into());
         metrics.insert(Self::BROKER_CLIENTS_CONNECTED, 0u8.into());
         metrics.insert(Self::BROKER_CLIENTS_MAXIMUM, 0u8.into());
+        metrics.insert(Self::BROKER_SESSIONS_RECOVERY_FAILURES, 0u8.into());
+        metrics.insert(Self::BROKER_SESSIONS_TAKEOVERS, 0u8.into());
+        metrics.insert(Self::BROKER_LISTENER_ACCEPT_ERRORS, 0u8.into());
+        metrics.insert(Self::BROKER_LISTENER_FD_EXHAUSTIONS, 0u8.into());
+        metrics.insert(Self::BROKER_LISTENER_OPEN_FILES_LIMIT, 0u8.into());
+        metrics.insert(Self::BROKER_ACL_SHADOW_DIVERGENCES, 0u8.into());
+        metrics.insert(Self::BROKER_MESSAGES_DROPPED_CHANNEL_FULL, 0u8.into());
+        metrics.insert(Self::BROKER_MESSAGES_DROPPED_EXPIRED, 0u8.into());
+        metrics.insert(Self::BROKER_MESSAGES_REJECTED_UTF8, 0u8.into());
+        metrics.insert(Self::BROKER_MESSAGES_REJECTED_JSON, 0u8.into());
+        metrics.insert(Self::BROKER_WEBHOOK_QUEUED, 0u8.into());
+        metrics.insert(Self::BROKER_WEBHOOK_DELIVERED, 0u8.into());
+        metrics.insert(Self::BROKER_WEBHOOK_DROPPED, 0u8.into());
         let clients_online = HashSet::new();
 
         StatsStateInner {
             metrics,
             clients_online,
+            payload_sizes_received: PayloadSizeHistogram::new(payload_size_buckets.clone()),
+            payload_sizes_sent: PayloadSizeHistogram::new(payload_size_buckets),
+            loads: Self::LOAD_COUNTERS
+                .iter()
+                .map(|name| (*name, LoadRates::default()))
+                .collect(),
+            connection_limit,
+            clients: if per_client {
+                Some(HashMap::new())
+            } else {
+                None
+            },
+            disconnects: DisconnectReason::ALL
+                .iter()
+                .map(|reason| (*reason, 0))
+                .collect(),
+            transport_bytes,
+            takeover_latencies: PayloadSizeHistogram::new(Self::TAKEOVER_LATENCY_BUCKETS.to_vec()),
         }
     }
 
     fn update(&mut self, message: StatsMessage) {
         match message {
-            StatsMessage::ClientConnected { client_id, .. } => {
-                self.on_client_connected(client_id);
+            StatsMessage::ClientConnected { connection, .. } => {
+                self.on_client_connected(connection.client_id.clone());
+            }
+            StatsMessage::ClientDisconnected { client_id, reason } => {
+                self.on_client_disconnected(client_id, reason);
+            }
+            StatsMessage::PacketProcessedReceived {
+                client_id,
+                bytes,
+                payload_bytes,
+            } => {
+                self.on_packet_processed_received(&client_id, bytes, payload_bytes);
+            }
+            StatsMessage::PacketProcessedSend {
+                client_id,
+                bytes,
+                payload_bytes,
+            } => {
+                self.on_packet_processed_sent(&client_id, bytes, payload_bytes);
+            }
+            StatsMessage::SessionRecoveryFailed => {
+                self.on_session_recovery_failed();
+            }
+            StatsMessage::SessionTakenOver { latency } => {
+                if let Some(v) = self.metrics.get_mut(Self::BROKER_SESSIONS_TAKEOVERS) {
+                    *v += 1u128;
+                }
+                self.takeover_latencies
+                    .observe(latency.as_millis().try_into().unwrap_or(usize::MAX));
             }
-            StatsMessage::ClientDisconnected { client_id, .. } => {
-                self.on_client_disconnected(client_id);
+            StatsMessage::AclShadowDivergence => {
+                if let Some(v) = self.metrics.get_mut(Self::BROKER_ACL_SHADOW_DIVERGENCES) {
+                    *v += 1u128;
+                }
             }
-            StatsMessage::PacketProcessedReceived { bytes, .. } => {
-                self.on_packet_processed_received(bytes);
+            StatsMessage::ConnectionChannelFull => {
+                if let Some(v) = self
+                    .metrics
+                    .get_mut(Self::BROKER_MESSAGES_DROPPED_CHANNEL_FULL)
+                {
+                    *v += 1u128;
+                }
             }
-            StatsMessage::PacketProcessedSend { bytes, .. } => {
-                self.on_packet_processed_sent(bytes);
+            StatsMessage::MessagesExpired { count } => {
+                if let Some(v) = self.metrics.get_mut(Self::BROKER_MESSAGES_DROPPED_EXPIRED) {
+                    *v += count as u128;
+                }
             }
+            StatsMessage::PayloadRejected { format } => {
+                let name = match format {
+                    PayloadFormat::Utf8 => Self::BROKER_MESSAGES_REJECTED_UTF8,
+                    PayloadFormat::Json => Self::BROKER_MESSAGES_REJECTED_JSON,
+                };
+                if let Some(v) = self.metrics.get_mut(name) {
+                    *v += 1u128;
+                }
+            }
+            StatsMessage::WebhookOutbox {
+                queued,
+                delivered,
+                dropped,
+            } => {
+                self.metrics
+                    .insert(Self::BROKER_WEBHOOK_QUEUED, queued as u128);
+                for (metric, count) in [
+                    (Self::BROKER_WEBHOOK_DELIVERED, delivered),
+                    (Self::BROKER_WEBHOOK_DROPPED, dropped),
+                ] {
+                    if let Some(v) = self.metrics.get_mut(metric) {
+                        *v += count as u128;
+                    }
+                }
+            }
+            StatsMessage::AcceptFailed { fd_exhausted } => {
+                self.on_accept_failed(fd_exhausted);
+            }
+            StatsMessage::OpenFilesLimit { limit } => {
+                self.metrics
+                    .insert(Self::BROKER_LISTENER_OPEN_FILES_LIMIT, limit.into());
+            }
+            StatsMessage::SubscriptionTreeUsage { usage } => {
+                self.metrics.insert(
+                    Self::BROKER_SUBSCRIPTIONS_COUNT,
+                    usage.subscriptions as u128,
+                );
+                self.metrics
+                    .insert(Self::BROKER_SUBSCRIPTIONS_TREE_NODES, usage.nodes as u128);
+                self.metrics.insert(
+                    Self::BROKER_SUBSCRIPTIONS_TREE_MEMORY,
+                    usage.estimated_bytes as u128,
+                );
+            }
+            StatsMessage::Scrape { reply } => {
+                self.sample_connections();
+                // a requester may be gone already
+                let _ = reply.send(self.to_prometheus());
+            }
+            // answered by `StatsState`, which knows the uptime
+            StatsMessage::Summary { .. } => {}
         }
     }
 
@@ -83,10 +484,203 @@ impl StatsStateInner {
             metrics.push((k.to_string(), format!("{}", v)));
         }
 
+        for (reason, count) in &self.disconnects {
+            metrics.push((
+                format!("{}/{}", Self::BROKER_DISCONNECTS, reason),
+                format!("{}", count),
+            ));
+        }
+
+        // e.g. broker/listener/tls/bytes/received
+        if let Some(ref transport_bytes) = self.transport_bytes {
+            for (listener, bytes) in transport_bytes.listeners() {
+                metrics.push((
+                    format!("{}/{}/bytes/received", Self::BROKER_LISTENER, listener),
+                    format!("{}", bytes.received()),
+                ));
+                metrics.push((
+                    format!("{}/{}/bytes/sent", Self::BROKER_LISTENER, listener),
+                    format!("{}", bytes.sent()),
+                ));
+            }
+        }
+
+        metrics.push((
+            Self::BROKER_MESSAGES_SIZE_RECEIVED.to_string(),
+            self.payload_sizes_received.to_json(),
+        ));
+        metrics.push((
+            Self::BROKER_MESSAGES_SIZE_SENT.to_string(),
+            self.payload_sizes_sent.to_json(),
+        ));
+        metrics.push((
+            Self::BROKER_MESSAGES_SIZE_MAX.to_string(),
+            format!(
+                "{}",
+                self.payload_sizes_received
+                    .max()
+                    .max(self.payload_sizes_sent.max())
+            ),
+        ));
+
+        metrics.push((
+            Self::BROKER_SESSIONS_TAKEOVER_LATENCY.to_string(),
+            self.takeover_latencies.to_json(),
+        ));
+        metrics.push((
+            Self::BROKER_SESSIONS_TAKEOVER_LATENCY_MAX.to_string(),
+            format!("{}", self.takeover_latencies.max()),
+        ));
+
+        // client ids with wild cards can't be a part of a topic name
+        for (client_id, counters) in self.clients.iter().flatten() {
+            if client_id.contains(['+', '#']) {
+                continue;
+            }
+            for (counter, value) in [
+                ("messages/received", counters.messages_received),
+                ("messages/sent", counters.messages_sent),
+                ("bytes/received", counters.bytes_received),
+                ("bytes/sent", counters.bytes_sent),
+            ] {
+                metrics.push((
+                    format!("broker/clients/{}/{}", client_id, counter),
+                    format!("{}", value),
+                ));
+            }
+        }
+
+        // e.g. broker/messages/received -> broker/load/messages/received/1min
+        for (name, load) in &self.loads {
+            let counter = name.trim_start_matches("broker/");
+            for (suffix, rate) in load.rates() {
+                metrics.push((
+                    format!("broker/load/{}/{}", counter, suffix),
+                    format!("{:.2}", rate),
+                ));
+            }
+        }
+
         metrics
     }
 
+    /// Metrics in Prometheus text exposition format.
+    fn to_prometheus(&self) -> String {
+        let mut exposition = String::new();
+
+        for (metric, name, metric_type, help) in Self::PROMETHEUS_METRICS {
+            exposition.push_str(&format!("# HELP {} {}\n", name, help));
+            exposition.push_str(&format!("# TYPE {} {}\n", name, metric_type));
+            exposition.push_str(&format!(
+                "{} {}\n",
+                name,
+                self.metrics.get(metric).copied().unwrap_or(0)
+            ));
+        }
+
+        exposition.push_str(&format!(
+            "# HELP {} Closed connections of clients by a reason.\n",
+            Self::PROMETHEUS_DISCONNECTS
+        ));
+        exposition.push_str(&format!(
+            "# TYPE {} counter\n",
+            Self::PROMETHEUS_DISCONNECTS
+        ));
+        for reason in DisconnectReason::ALL {
+            exposition.push_str(&format!(
+                "{}{{reason=\"{}\"}} {}\n",
+                Self::PROMETHEUS_DISCONNECTS,
+                reason,
+                self.disconnects.get(&reason).copied().unwrap_or(0)
+            ));
+        }
+
+        if let Some(ref transport_bytes) = self.transport_bytes {
+            for (name, help, direction) in [
+                (
+                    Self::PROMETHEUS_TRANSPORT_BYTES_RECEIVED,
+                    "Bytes received on the wire by a listener, including TLS and WebSocket overhead.",
+                    "received",
+                ),
+                (
+                    Self::PROMETHEUS_TRANSPORT_BYTES_SENT,
+                    "Bytes sent on the wire by a listener, including TLS and WebSocket overhead.",
+                    "sent",
+                ),
+            ] {
+                exposition.push_str(&format!("# HELP {} {}\n", name, help));
+                exposition.push_str(&format!("# TYPE {} counter\n", name));
+                for (listener, bytes) in transport_bytes.listeners() {
+                    let value = if direction == "received" {
+                        bytes.received()
+                    } else {
+                        bytes.sent()
+                    };
+                    exposition.push_str(&format!(
+                        "{}{{listener=\"{}\"}} {}\n",
+                        name, listener, value
+                    ));
+                }
+            }
+        }
+
+        exposition.push_str(&format!(
+            "# HELP {} Payload sizes of PUBLISH packets.\n",
+            Self::PROMETHEUS_PAYLOAD_SIZE
+        ));
+        exposition.push_str(&format!(
+            "# TYPE {} histogram\n",
+            Self::PROMETHEUS_PAYLOAD_SIZE
+        ));
+        exposition.push_str(
+            &self
+                .payload_sizes_received
+                .to_prometheus(Self::PROMETHEUS_PAYLOAD_SIZE, "direction=\"received\""),
+        );
+        exposition.push_str(
+            &self
+                .payload_sizes_sent
+                .to_prometheus(Self::PROMETHEUS_PAYLOAD_SIZE, "direction=\"sent\""),
+        );
+
+        exposition.push_str(&format!(
+            "# HELP {} Time new connections have waited for sessions to be taken over.\n",
+            Self::PROMETHEUS_TAKEOVER_LATENCY
+        ));
+        exposition.push_str(&format!(
+            "# TYPE {} histogram\n",
+            Self::PROMETHEUS_TAKEOVER_LATENCY
+        ));
+        exposition.push_str(
+            &self
+                .takeover_latencies
+                .to_prometheus(Self::PROMETHEUS_TAKEOVER_LATENCY, ""),
+        );
+
+        exposition
+    }
+
+    fn sample_connections(&mut self) {
+        self.metrics.insert(
+            Self::BROKER_LISTENER_CONNECTIONS,
+            self.connection_limit.current() as u128,
+        );
+        self.metrics.insert(
+            Self::BROKER_LISTENER_MAX_CONNECTIONS,
+            self.connection_limit.max() as u128,
+        );
+    }
+
+    fn update_load(&mut self, elapsed: Duration) {
+        for (name, load) in &mut self.loads {
+            load.update(self.metrics.get(name).copied().unwrap_or(0), elapsed);
+        }
+    }
+
     fn on_client_connected(&mut self, client_id: String) {
+        if let Some(clients) = self.clients.as_mut() {
+            clients.insert(client_id.clone(), ClientCounters::default());
+        }
         self.clients_online.insert(client_id);
         let currently_clients = self.clients_online.len() as u128;
 
@@ -101,7 +695,11 @@ impl StatsStateInner {
         }
     }
 
-    fn on_client_disconnected(&mut self, client_id: String) {
+    fn on_client_disconnected(&mut self, client_id: String, reason: DisconnectReason) {
+        *self.disconnects.entry(reason).or_default() += 1;
+        if let Some(clients) = self.clients.as_mut() {
+            clients.remove(&client_id);
+        }
         self.clients_online.remove(&client_id);
         let currently_clients = self.clients_online.len() as u128;
 
@@ -116,7 +714,19 @@ impl StatsStateInner {
         }
     }
 
-    fn on_packet_processed_received(&mut self, bytes: u64) {
+    fn on_packet_processed_received(
+        &mut self,
+        client_id: &str,
+        bytes: u64,
+        payload_bytes: Option<u64>,
+    ) {
+        if let Some(counters) = self.clients.as_mut().and_then(|c| c.get_mut(client_id)) {
+            counters.messages_received += 1;
+            counters.bytes_received += bytes;
+        }
+        if let Some(payload_bytes) = payload_bytes {
+            self.payload_sizes_received.observe(payload_bytes as usize);
+        }
         if let Some(v) = self.metrics.get_mut(Self::BROKER_BYTES_RECEIVED_NAME) {
             *v += bytes as u128;
         }
@@ -125,7 +735,19 @@ impl StatsStateInner {
         }
     }
 
-    fn on_packet_processed_sent(&mut self, bytes: u64) {
+    fn on_packet_processed_sent(
+        &mut self,
+        client_id: &str,
+        bytes: u64,
+        payload_bytes: Option<u64>,
+    ) {
+        if let Some(counters) = self.clients.as_mut().and_then(|c| c.get_mut(client_id)) {
+            counters.messages_sent += 1;
+            counters.bytes_sent += bytes;
+        }
+        if let Some(payload_bytes) = payload_bytes {
+            self.payload_sizes_sent.observe(payload_bytes as usize);
+        }
         if let Some(v) = self.metrics.get_mut(Self::BROKER_BYTES_SENT_NAME) {
             *v += bytes as u128;
         }
@@ -133,4 +755,326 @@ impl StatsStateInner {
             *v += 1u128;
         }
     }
+
+    fn on_session_recovery_failed(&mut self) {
+        if let Some(v) = self
+            .metrics
+            .get_mut(Self::BROKER_SESSIONS_RECOVERY_FAILURES)
+        {
+            *v += 1u128;
+        }
+    }
+
+    fn on_accept_failed(&mut self, fd_exhausted: bool) {
+        if let Some(v) = self.metrics.get_mut(Self::BROKER_LISTENER_ACCEPT_ERRORS) {
+            *v += 1u128;
+        }
+        if fd_exhausted {
+            if let Some(v) = self.metrics.get_mut(Self::BROKER_LISTENER_FD_EXHAUSTIONS) {
+                *v += 1u128;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection_gate::ConnectionTransport, connection_info::ConnectionInfo,
+        subscription_tree::TreeUsage,
+    };
+    use std::{net::SocketAddr, time::SystemTime};
+    use tokio::sync::oneshot;
+
+    fn scrape(state: &mut StatsState) -> String {
+        let (reply, mut response) = oneshot::channel();
+        state.update(StatsMessage::Scrape { reply });
+        response.try_recv().unwrap()
+    }
+
+    #[test]
+    fn scrape_reports_counters_in_prometheus_format() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        state.update(StatsMessage::PacketProcessedReceived {
+            client_id: "device".into(),
+            bytes: 20,
+            payload_bytes: Some(5),
+        });
+
+        let exposition = scrape(&mut state);
+        assert!(exposition.contains(
+            "# TYPE telemq_bytes_received_total counter\ntelemq_bytes_received_total 20\n"
+        ));
+        assert!(exposition.contains("\ntelemq_messages_received_total 1\n"));
+        assert!(exposition.contains("\ntelemq_clients_connected 0\n"));
+        assert!(exposition.contains(
+            "\ntelemq_message_payload_bytes_bucket{direction=\"received\",le=\"10\"} 1\n"
+        ));
+        assert!(exposition.contains("\ntelemq_message_payload_bytes_count{direction=\"sent\"} 0\n"));
+    }
+
+    #[test]
+    fn summary_reports_main_counters() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        state.update(StatsMessage::PacketProcessedReceived {
+            client_id: "device".into(),
+            bytes: 20,
+            payload_bytes: Some(5),
+        });
+        state.update(StatsMessage::PacketProcessedSend {
+            client_id: "device".into(),
+            bytes: 8,
+            payload_bytes: None,
+        });
+
+        let (reply, mut response) = oneshot::channel();
+        state.update(StatsMessage::Summary { reply });
+        let summary = response.try_recv().unwrap();
+        assert_eq!((summary.messages_received, summary.bytes_received), (1, 20));
+        assert_eq!((summary.messages_sent, summary.bytes_sent), (1, 8));
+        assert_eq!(summary.clients_connected, 0);
+    }
+
+    #[test]
+    fn accept_failures_are_counted() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        state.update(StatsMessage::AcceptFailed { fd_exhausted: true });
+        state.update(StatsMessage::AcceptFailed {
+            fd_exhausted: false,
+        });
+        state.update(StatsMessage::OpenFilesLimit { limit: 1024 });
+
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/listener/accept_errors"], "2");
+        assert_eq!(metrics["broker/listener/fd_exhaustions"], "1");
+        assert_eq!(metrics["broker/listener/open_files_limit"], "1024");
+    }
+
+    #[test]
+    fn acl_shadow_divergences_are_counted() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        state.update(StatsMessage::AclShadowDivergence);
+        state.update(StatsMessage::AclShadowDivergence);
+
+        assert!(scrape(&mut state).contains("\ntelemq_acl_shadow_divergences_total 2\n"));
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/acl/shadow/divergences"], "2");
+    }
+
+    #[test]
+    fn channel_full_drops_are_counted() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        state.update(StatsMessage::ConnectionChannelFull);
+
+        assert!(scrape(&mut state).contains("\ntelemq_channel_full_drops_total 1\n"));
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/messages/dropped/channel_full"], "1");
+    }
+
+    #[test]
+    fn expired_messages_are_counted() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        state.update(StatsMessage::MessagesExpired { count: 3 });
+        state.update(StatsMessage::MessagesExpired { count: 2 });
+
+        assert!(scrape(&mut state).contains("\ntelemq_expired_messages_total 5\n"));
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/messages/dropped/expired"], "5");
+    }
+
+    #[test]
+    fn payload_rejections_are_counted_by_format() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        state.update(StatsMessage::PayloadRejected {
+            format: PayloadFormat::Json,
+        });
+
+        assert!(scrape(&mut state).contains("\ntelemq_invalid_json_rejections_total 1\n"));
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/messages/rejected/json"], "1");
+        assert_eq!(metrics["broker/messages/rejected/utf8"], "0");
+    }
+
+    #[test]
+    fn webhook_outbox_is_reported() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        state.update(StatsMessage::WebhookOutbox {
+            queued: 3,
+            delivered: 0,
+            dropped: 2,
+        });
+        state.update(StatsMessage::WebhookOutbox {
+            queued: 1,
+            delivered: 2,
+            dropped: 0,
+        });
+
+        let exposition = scrape(&mut state);
+        assert!(exposition.contains("\ntelemq_webhook_outbox_events 1\n"));
+        assert!(exposition.contains("\ntelemq_webhook_delivered_total 2\n"));
+        assert!(exposition.contains("\ntelemq_webhook_dropped_total 2\n"));
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/webhook/queued"], "1");
+    }
+
+    #[test]
+    fn session_takeovers_are_counted_with_latency() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        state.update(StatsMessage::SessionTakenOver {
+            latency: Duration::from_millis(40),
+        });
+        state.update(StatsMessage::SessionTakenOver {
+            latency: Duration::from_millis(700),
+        });
+
+        let exposition = scrape(&mut state);
+        assert!(exposition.contains("\ntelemq_session_takeovers_total 2\n"));
+        assert!(exposition
+            .contains("\ntelemq_session_takeover_latency_milliseconds_bucket{le=\"50\"} 1\n"));
+        assert!(exposition.contains("\ntelemq_session_takeover_latency_milliseconds_count{} 2\n"));
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/sessions/takeovers"], "2");
+        assert_eq!(metrics["broker/sessions/takeover_latency_max"], "700");
+    }
+
+    #[test]
+    fn disconnects_are_counted_by_reason() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        for reason in [
+            DisconnectReason::KeepAliveTimeout,
+            DisconnectReason::ProtocolError,
+            DisconnectReason::KeepAliveTimeout,
+        ] {
+            state.update(StatsMessage::ClientDisconnected {
+                client_id: "device".into(),
+                reason,
+            });
+        }
+
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/disconnects/keep_alive_timeout"], "2");
+        assert_eq!(metrics["broker/disconnects/protocol_error"], "1");
+        assert_eq!(metrics["broker/disconnects/client_disconnect"], "0");
+        let exposition = scrape(&mut state);
+        assert!(
+            exposition.contains("\ntelemq_disconnects_total{reason=\"keep_alive_timeout\"} 2\n")
+        );
+        assert!(
+            exposition.contains("\ntelemq_disconnects_total{reason=\"session_taken_over\"} 0\n")
+        );
+    }
+
+    #[test]
+    fn connections_are_sampled_from_limit() {
+        let connection_limit = Arc::new(ConnectionLimit::new(100));
+        let mut state = StatsState::new(vec![10], connection_limit.clone(), false, None);
+        connection_limit.try_acquire();
+
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/listener/connections"], "1");
+        assert_eq!(metrics["broker/listener/max_connections"], "100");
+        assert!(scrape(&mut state).contains("\ntelemq_max_connections 100\n"));
+    }
+
+    #[test]
+    fn transport_bytes_are_reported_per_listener() {
+        let transport_bytes = ListenerTransportBytes::default();
+        let mut state = StatsState::new(
+            vec![10],
+            Arc::new(ConnectionLimit::new(100)),
+            false,
+            Some(transport_bytes.clone()),
+        );
+        transport_bytes.tls.add_received(517);
+        transport_bytes.tls.add_sent(1200);
+
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/listener/tls/bytes/received"], "517");
+        assert_eq!(metrics["broker/listener/tls/bytes/sent"], "1200");
+        assert_eq!(metrics["broker/listener/ws/bytes/sent"], "0");
+        let exposition = scrape(&mut state);
+        assert!(
+            exposition.contains("\ntelemq_transport_bytes_received_total{listener=\"tls\"} 517\n")
+        );
+        assert!(exposition.contains("\ntelemq_transport_bytes_sent_total{listener=\"tcp\"} 0\n"));
+    }
+
+    #[test]
+    fn subscription_tree_usage_is_reported() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        state.update(StatsMessage::SubscriptionTreeUsage {
+            usage: TreeUsage {
+                nodes: 7,
+                subscriptions: 3,
+                estimated_bytes: 2048,
+            },
+        });
+
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/subscriptions/count"], "3");
+        assert_eq!(metrics["broker/subscriptions/tree/nodes"], "7");
+        assert_eq!(metrics["broker/subscriptions/tree/memory"], "2048");
+        assert!(scrape(&mut state).contains("\ntelemq_subscription_tree_nodes 7\n"));
+    }
+
+    #[test]
+    fn uptime_and_version_are_published() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), false, None);
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/uptime"], "0");
+        assert_eq!(metrics["broker/version"], env!("CARGO_PKG_VERSION"));
+        assert!(
+            !metrics
+                .keys()
+                .any(|k| k.starts_with("broker/clients/device")),
+            "per client counters should be disabled by default"
+        );
+    }
+
+    #[test]
+    fn per_client_counters_are_kept_while_connected() {
+        let mut state = StatsState::new(vec![10], Arc::new(ConnectionLimit::new(100)), true, None);
+        let connection = |client_id: &str| {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+            Arc::new(
+                ConnectionInfo::accepted(addr, ConnectionTransport::Tcp, None, SystemTime::now())
+                    .connected(client_id.into(), None, SystemTime::now()),
+            )
+        };
+        for client_id in ["device", "dev+ice"] {
+            state.update(StatsMessage::ClientConnected {
+                connection: connection(client_id),
+                clean_session: true,
+                first_connection: true,
+            });
+        }
+        state.update(StatsMessage::PacketProcessedReceived {
+            client_id: "device".into(),
+            bytes: 20,
+            payload_bytes: Some(5),
+        });
+        state.update(StatsMessage::PacketProcessedSend {
+            client_id: "device".into(),
+            bytes: 8,
+            payload_bytes: None,
+        });
+
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert_eq!(metrics["broker/clients/device/messages/received"], "1");
+        assert_eq!(metrics["broker/clients/device/bytes/received"], "20");
+        assert_eq!(metrics["broker/clients/device/messages/sent"], "1");
+        assert_eq!(metrics["broker/clients/device/bytes/sent"], "8");
+        assert!(
+            !metrics.keys().any(|k| k.contains("dev+ice")),
+            "client ids with wild cards should be skipped"
+        );
+
+        state.update(StatsMessage::ClientDisconnected {
+            client_id: "device".into(),
+            reason: DisconnectReason::KeepAliveTimeout,
+        });
+        let metrics: HashMap<String, String> = state.checkpoint().into_iter().collect();
+        assert!(!metrics.contains_key("broker/clients/device/messages/received"));
+    }
 }
@@ -0,0 +1,312 @@
+//! Files the broker persists its state to (sessions, client history, retained messages and
+//! internal subscriptions) are JSON objects keyed by a client id or a topic. They are read
+//! entry by entry, so a corrupted entry is skipped instead of the whole file.
+//!
+//! `telemq --fsck-state-store` checks the files without starting the broker, and with
+//! `--quarantine` moves corrupted entries to `<file>.quarantine`.
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    fs::{read_to_string, File},
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde_json::{from_reader, from_str, from_value, to_vec, Map, Value};
+
+use crate::{
+    client_history::ClientHistory, config::TeleMQServerConfig, retained_store::RetainedMessage,
+    session_state::SessionConnectedState, session_state_store::SessionStateStore,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptedEntry {
+    pub key: String,
+    pub error: String,
+    raw: Value,
+}
+
+#[derive(Debug)]
+pub struct StoreEntries<T> {
+    pub entries: HashMap<String, T>,
+    pub corrupted: Vec<CorruptedEntry>,
+}
+
+/// Reads a JSON object, entries which can't be deserialized are returned separately.
+/// Fails only if the file is not a JSON object at all.
+pub fn read_entries<T: DeserializeOwned, R: Read>(
+    reader: R,
+) -> serde_json::Result<StoreEntries<T>> {
+    let object: Map<String, Value> = from_reader(reader)?;
+    let mut entries = HashMap::with_capacity(object.len());
+    let mut corrupted = vec![];
+    for (key, raw) in object {
+        match from_value(raw.clone()) {
+            Ok(entry) => {
+                entries.insert(key, entry);
+            }
+            Err(err) => corrupted.push(CorruptedEntry {
+                key,
+                error: err.to_string(),
+                raw,
+            }),
+        }
+    }
+    corrupted.sort_by(|left, right| left.key.cmp(&right.key));
+
+    Ok(StoreEntries { entries, corrupted })
+}
+
+/// Logs entries skipped at startup, they stay in the file until the store is saved over it.
+pub fn warn_about_corrupted(worker: &str, path: &str, corrupted: &[CorruptedEntry]) {
+    for entry in corrupted {
+        warn!(
+            "[{}]: skipped a corrupted entry {:?} of file {}. {}",
+            worker, entry.key, path, entry.error
+        );
+    }
+    if !corrupted.is_empty() {
+        warn!(
+            "[{}]: run `telemq --fsck-state-store --quarantine` to keep corrupted entries of file {} aside",
+            worker, path
+        );
+    }
+}
+
+#[derive(Debug)]
+pub enum FileCheck {
+    /// Nothing has been persisted yet, the file is missing or empty.
+    Missing,
+    /// The file is not a JSON object, it can't be repaired entry by entry.
+    Unreadable(String),
+    Checked {
+        valid: usize,
+        corrupted: Vec<CorruptedEntry>,
+        /// `true` once corrupted entries are moved to a quarantine file.
+        quarantined: bool,
+    },
+}
+
+#[derive(Debug)]
+pub struct FsckReport {
+    pub files: Vec<(String, FileCheck)>,
+}
+
+impl FsckReport {
+    /// Whether the broker would start with every persisted entry.
+    pub fn is_clean(&self) -> bool {
+        self.files.iter().all(|(_, check)| match check {
+            FileCheck::Missing => true,
+            FileCheck::Unreadable(_) => false,
+            FileCheck::Checked {
+                corrupted,
+                quarantined,
+                ..
+            } => corrupted.is_empty() || *quarantined,
+        })
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (path, check) in &self.files {
+            match check {
+                FileCheck::Missing => writeln!(f, "{}: nothing persisted", path)?,
+                FileCheck::Unreadable(err) => writeln!(f, "{}: unreadable. {}", path, err)?,
+                FileCheck::Checked {
+                    valid,
+                    corrupted,
+                    quarantined,
+                } => {
+                    writeln!(
+                        f,
+                        "{}: {} valid, {} corrupted",
+                        path,
+                        valid,
+                        corrupted.len()
+                    )?;
+                    for entry in corrupted {
+                        writeln!(f, "  {:?}: {}", entry.key, entry.error)?;
+                    }
+                    if *quarantined {
+                        writeln!(
+                            f,
+                            "  corrupted entries are moved to {}",
+                            quarantine_path(path)
+                        )?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks every file of the state store, with `quarantine` corrupted entries are moved out.
+pub fn fsck_state_store(config: &TeleMQServerConfig, quarantine: bool) -> FsckReport {
+    let mut files = vec![
+        (
+            SessionStateStore::DATA_FILE_PATH.to_string(),
+            check_file::<SessionConnectedState>(SessionStateStore::DATA_FILE_PATH, quarantine),
+        ),
+        (
+            SessionStateStore::HISTORY_FILE_PATH.to_string(),
+            check_file::<ClientHistory>(SessionStateStore::HISTORY_FILE_PATH, quarantine),
+        ),
+    ];
+    if let Some(ref path) = config.retained_store_file {
+        files.push((
+            path.clone(),
+            check_file::<RetainedMessage>(path, quarantine),
+        ));
+    }
+    if let Some(ref path) = config.internal_subscriptions_file {
+        files.push((
+            path.clone(),
+            check_file::<BTreeSet<String>>(path, quarantine),
+        ));
+    }
+
+    FsckReport { files }
+}
+
+fn check_file<T: DeserializeOwned>(path: &str, quarantine: bool) -> FileCheck {
+    let content = match read_to_string(Path::new(path)) {
+        Ok(content) if content.trim().is_empty() => return FileCheck::Missing,
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return FileCheck::Missing,
+        Err(err) => return FileCheck::Unreadable(err.to_string()),
+    };
+    let StoreEntries { entries, corrupted } = match read_entries::<T, _>(content.as_bytes()) {
+        Ok(checked) => checked,
+        Err(err) => return FileCheck::Unreadable(err.to_string()),
+    };
+
+    let quarantined = if quarantine && !corrupted.is_empty() {
+        match move_to_quarantine(path, &corrupted) {
+            Ok(()) => true,
+            Err(err) => return FileCheck::Unreadable(format!("Unable to quarantine. {}", err)),
+        }
+    } else {
+        false
+    };
+
+    FileCheck::Checked {
+        valid: entries.len(),
+        corrupted,
+        quarantined,
+    }
+}
+
+fn quarantine_path(path: &str) -> String {
+    format!("{}.quarantine", path)
+}
+
+/// Adds corrupted entries to a quarantine file and removes them from the original one.
+/// Valid entries are written back as they were.
+fn move_to_quarantine(path: &str, corrupted: &[CorruptedEntry]) -> io::Result<()> {
+    let quarantine_path = quarantine_path(path);
+    // entries quarantined by earlier runs are kept
+    let mut quarantined: Map<String, Value> = match read_to_string(&quarantine_path) {
+        Ok(content) => from_str(&content)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Map::new(),
+        Err(err) => return Err(err),
+    };
+    for entry in corrupted {
+        quarantined.insert(entry.key.clone(), entry.raw.clone());
+    }
+    write_json(&quarantine_path, &quarantined)?;
+
+    let mut object: Map<String, Value> = from_str(&read_to_string(path)?)?;
+    for entry in corrupted {
+        object.remove(&entry.key);
+    }
+    write_json(path, &object)
+}
+
+fn write_json(path: &str, object: &Map<String, Value>) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(&to_vec(object)?)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{remove_file, write};
+
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "telemq_store_check_{}_{}.json",
+                name,
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    const HISTORY: &str = r#"{
+        "device-1": {"messages_received": 1, "messages_sent": 2},
+        "device-2": {"messages_received": "many", "messages_sent": 2},
+        "device-3": {"last_connected_at": null, "messages_received": 0, "messages_sent": 0}
+    }"#;
+
+    #[test]
+    fn corrupted_entries_are_skipped() {
+        let checked = read_entries::<ClientHistory, _>(HISTORY.as_bytes()).unwrap();
+
+        let mut valid: Vec<&String> = checked.entries.keys().collect();
+        valid.sort();
+        assert_eq!(valid, vec!["device-1", "device-3"]);
+        assert_eq!(checked.corrupted.len(), 1);
+        assert_eq!(checked.corrupted[0].key, "device-2");
+
+        assert!(read_entries::<ClientHistory, _>("[]".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn corrupted_entries_are_quarantined() {
+        let path = temp_path("history");
+        write(&path, HISTORY).unwrap();
+
+        match check_file::<ClientHistory>(&path, false) {
+            FileCheck::Checked {
+                valid: 2,
+                ref corrupted,
+                quarantined: false,
+            } if corrupted.len() == 1 => {}
+            check => panic!("unexpected check {:?}", check),
+        }
+        match check_file::<ClientHistory>(&path, true) {
+            FileCheck::Checked {
+                quarantined: true, ..
+            } => {}
+            check => panic!("unexpected check {:?}", check),
+        }
+
+        let remaining: Map<String, Value> = from_str(&read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(remaining.len(), 2);
+        let quarantined: Map<String, Value> =
+            from_str(&read_to_string(quarantine_path(&path)).unwrap()).unwrap();
+        assert_eq!(
+            quarantined.get("device-2"),
+            Some(&serde_json::json!({"messages_received": "many", "messages_sent": 2}))
+        );
+        match check_file::<ClientHistory>(&path, false) {
+            FileCheck::Checked {
+                valid: 2,
+                ref corrupted,
+                ..
+            } if corrupted.is_empty() => {}
+            check => panic!("unexpected check {:?}", check),
+        }
+
+        remove_file(&path).unwrap();
+        remove_file(quarantine_path(&path)).unwrap();
+    }
+}
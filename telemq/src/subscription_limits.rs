@@ -0,0 +1,76 @@
+//! Limits of subscriptions a client may make, on top of its ACL. In multi-tenant deployments
+//! they keep a single client from subscribing to every topic, e.g. with a bare `#`, or from
+//! growing the subscription tree unbounded.
+use std::sync::Arc;
+
+use mqtt_packets::v_3_1_1::topic::{filter_contains, Subscription};
+
+use crate::config::TeleMQServerConfig;
+
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionLimits {
+    /// `None` means unlimited.
+    pub max_subs_per_client: Option<usize>,
+    /// Requested filters as broad as any of these are rejected.
+    blacklist: Arc<Vec<Subscription>>,
+}
+
+impl SubscriptionLimits {
+    pub fn new(config: &TeleMQServerConfig) -> Self {
+        SubscriptionLimits {
+            max_subs_per_client: config.max_subs_per_client,
+            // filters are validated by the config
+            blacklist: Arc::new(
+                config
+                    .subscription_blacklist
+                    .iter()
+                    .filter_map(|filter| Subscription::try_from(filter.as_str()).ok())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Whether a requested filter matches every topic of a blacklisted one, so blacklisting
+    /// `devices/#` rejects `devices/#`, `+/#` and `#`, but not `devices/1/#`.
+    pub fn is_blacklisted(&self, filter: &Subscription) -> bool {
+        self.blacklist
+            .iter()
+            .any(|blacklisted| filter_contains(&filter.path, &blacklisted.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(blacklist: &[&str]) -> SubscriptionLimits {
+        let config = TeleMQServerConfig {
+            subscription_blacklist: blacklist.iter().map(|filter| filter.to_string()).collect(),
+            ..TeleMQServerConfig::default()
+        };
+        SubscriptionLimits::new(&config)
+    }
+
+    fn filter(filter: &str) -> Subscription {
+        Subscription::try_from(filter).unwrap()
+    }
+
+    #[test]
+    fn filters_as_broad_as_blacklisted_are_rejected() {
+        let limits = limits(&["#", "devices/#"]);
+
+        assert!(limits.is_blacklisted(&filter("#")));
+        assert!(limits.is_blacklisted(&filter("+/#")));
+        assert!(limits.is_blacklisted(&filter("devices/#")));
+        assert!(!limits.is_blacklisted(&filter("devices/1/#")));
+        assert!(!limits.is_blacklisted(&filter("devices/+")));
+        assert!(!limits.is_blacklisted(&filter("$SYS/#")));
+    }
+
+    #[test]
+    fn nothing_is_blacklisted_by_default() {
+        let limits = limits(&[]);
+
+        assert!(!limits.is_blacklisted(&filter("#")));
+    }
+}
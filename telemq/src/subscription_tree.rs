@@ -1,11 +1,15 @@
 use std::{
     collections::{HashMap, HashSet},
-    sync::Arc,
+    mem::size_of,
+    sync::{Arc, Mutex, PoisonError, RwLock as SyncRwLock},
 };
 use tokio::sync::RwLock;
 
-use crate::session_state_store::SessionStateStore;
-use mqtt_packets::v_3_1_1::topic::{SINGLE_LEVEL_WILD_CARD, WILD_CARD};
+use crate::{
+    routing_cache::{RoutingCache, Subscribers},
+    session_state_store::SessionStateStore,
+};
+use mqtt_packets::v_3_1_1::topic::{SINGLE_LEVEL_WILD_CARD, SYSTEM_PREFIX, WILD_CARD};
 
 type PathStep = String;
 type ClientID = String;
@@ -13,9 +17,22 @@ type ClientID = String;
 #[derive(Debug)]
 pub struct SubscriptionTree(SubscriptionNode);
 
+/// Size of the subscription tree. Nodes are left in the tree by subscribers of wildcard and
+/// unique topic filters, so it's watched to spot growth before it exhausts memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TreeUsage {
+    /// Topic levels of all topic filters, the root is not counted.
+    pub nodes: usize,
+    /// Pairs of a topic filter and a client id subscribed to it.
+    pub subscriptions: usize,
+    /// Memory held by nodes, their topic levels and client ids. Allocator overhead and
+    /// hash table control bytes are not counted, so the real usage is somewhat higher.
+    pub estimated_bytes: usize,
+}
+
 impl SubscriptionTree {
     pub async fn from_session_state_store(state_store: Arc<RwLock<SessionStateStore>>) -> Self {
-        let mut tree = SubscriptionTree(SubscriptionNode::new());
+        let mut tree = SubscriptionTree::default();
 
         for (_, v) in state_store.read().await.as_inner_data().await {
             for s in v.subscriptions {
@@ -26,18 +43,34 @@ impl SubscriptionTree {
         tree
     }
 
-    pub fn add_subscriber(&mut self, subscription: &[PathStep], connection: ClientID) {
+    /// Returns `false` if a client has already been subscribed to a topic filter.
+    pub fn add_subscriber(&mut self, subscription: &[PathStep], connection: ClientID) -> bool {
         if subscription.is_empty() {
             // cannot subscribe to "" topic
             // bug in topic parser and topic validator?
-            return;
+            return false;
         }
-        self.0.add(subscription, connection);
+        self.0.add(subscription, connection)
     }
 
     pub fn find_subscribers(&self, subscription: &[PathStep]) -> HashSet<ClientID> {
         let mut acc = HashSet::new();
-        self.0.find(subscription, &mut acc);
+        // wildcards at the first level don't match topics starting with `$`, e.g. `$SYS/...`
+        let match_wildcards = !subscription
+            .first()
+            .is_some_and(|level| level.starts_with(SYSTEM_PREFIX));
+        self.0.find(subscription, match_wildcards, &mut acc);
+
+        acc
+    }
+
+    /// Finds subscribers which would receive a message published to a given topic,
+    /// or to any topic matching a given topic filter.
+    pub fn find_matching_subscribers(&self, filter: &[PathStep]) -> HashSet<ClientID> {
+        let mut acc = HashSet::new();
+        if !filter.is_empty() {
+            self.0.find_overlapping(filter, &mut acc);
+        }
 
         acc
     }
@@ -54,6 +87,113 @@ impl SubscriptionTree {
     pub fn disconnect_subscriber(&mut self, connection: &ClientID) {
         self.0.disconnect(connection);
     }
+
+    /// Lists topic filters along with their subscribers, both sorted.
+    pub fn entries(&self) -> Vec<(String, Vec<ClientID>)> {
+        let mut acc = Vec::new();
+        self.0.collect_entries(&mut Vec::new(), &mut acc);
+        acc.sort();
+
+        acc
+    }
+
+    pub fn usage(&self) -> TreeUsage {
+        let mut usage = TreeUsage {
+            estimated_bytes: size_of::<SubscriptionNode>(),
+            ..TreeUsage::default()
+        };
+        self.0.collect_usage(&mut usage);
+
+        usage
+    }
+}
+
+impl Default for SubscriptionTree {
+    fn default() -> Self {
+        SubscriptionTree(SubscriptionNode::new())
+    }
+}
+
+/// The subscription tree shared by Control Worker, which alone changes it, and connections,
+/// which look subscribers of their publishes up themselves. Control Worker still delivers the
+/// publishes to their subscribers. Every change bumps the version of the tree, so subscribers
+/// looked up before a change Control Worker has made since are not used to route a publish.
+///
+/// Subscribers are looked up through the routing cache, which is changed along with the tree.
+#[derive(Debug, Clone)]
+pub struct SharedSubscriptionTree(Arc<SyncRwLock<VersionedTree>>);
+
+#[derive(Debug)]
+struct VersionedTree {
+    tree: SubscriptionTree,
+    version: u64,
+    // a lookup updates the order of cached topics, so it's locked even by readers of the tree
+    routing_cache: Mutex<RoutingCache>,
+}
+
+/// Subscribers of a topic looked up by a connection.
+#[derive(Debug, Clone)]
+pub struct ResolvedSubscribers {
+    version: u64,
+    pub subscribers: Subscribers,
+}
+
+impl SharedSubscriptionTree {
+    pub fn new(tree: SubscriptionTree, routing_cache_size: usize) -> Self {
+        SharedSubscriptionTree(Arc::new(SyncRwLock::new(VersionedTree {
+            tree,
+            version: 0,
+            routing_cache: Mutex::new(RoutingCache::new(routing_cache_size)),
+        })))
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&SubscriptionTree) -> R) -> R {
+        // the tree is changed by short non panicking calls, it's consistent even if poisoned
+        f(&self.0.read().unwrap_or_else(PoisonError::into_inner).tree)
+    }
+
+    /// Changes the tree, only Control Worker does. Topics the change may route differently
+    /// have to be invalidated in the routing cache.
+    pub fn update<R>(&self, f: impl FnOnce(&mut SubscriptionTree, &mut RoutingCache) -> R) -> R {
+        let mut versioned = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        let versioned = &mut *versioned;
+        versioned.version += 1;
+        let routing_cache = versioned
+            .routing_cache
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        f(&mut versioned.tree, routing_cache)
+    }
+
+    pub fn resolve(&self, topic: &[PathStep]) -> ResolvedSubscribers {
+        let versioned = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        let mut routing_cache = versioned
+            .routing_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let subscribers = match routing_cache.get(topic) {
+            Some(subscribers) => subscribers,
+            None => {
+                let subscribers = Arc::new(versioned.tree.find_subscribers(topic));
+                routing_cache.insert(topic.to_vec(), subscribers.clone());
+                subscribers
+            }
+        };
+
+        ResolvedSubscribers {
+            version: versioned.version,
+            subscribers,
+        }
+    }
+
+    /// Whether subscribers have been looked up in the current version of the tree.
+    pub fn is_current(&self, resolved: &ResolvedSubscribers) -> bool {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .version
+            == resolved.version
+    }
 }
 
 #[derive(Debug)]
@@ -70,20 +210,18 @@ impl SubscriptionNode {
         }
     }
 
-    fn add(&mut self, path: &[PathStep], connection: ClientID) {
+    fn add(&mut self, path: &[PathStep], connection: ClientID) -> bool {
         if path.is_empty() {
-            self.connections.insert(connection);
-            return;
+            return self.connections.insert(connection);
         }
 
         match self.children.get_mut(&path[0]) {
-            Some(ref mut node) => {
-                node.add(path.split_at(1).1, connection);
-            }
+            Some(ref mut node) => node.add(path.split_at(1).1, connection),
             None => {
                 let mut new_node = Self::new();
                 new_node.add(path.split_at(1).1, connection);
                 self.children.insert(path[0].clone(), new_node);
+                true
             }
         }
     }
@@ -108,7 +246,7 @@ impl SubscriptionNode {
             self.children.remove(&path[0]);
         }
 
-        return self.connections.is_empty() && self.children.is_empty();
+        self.connections.is_empty() && self.children.is_empty()
     }
 
     fn disconnect(&mut self, connection: &ClientID) {
@@ -118,42 +256,103 @@ impl SubscriptionNode {
         }
     }
 
-    fn find(&self, path: &[PathStep], acc: &mut HashSet<ClientID>) {
+    fn find(&self, path: &[PathStep], match_wildcards: bool, acc: &mut HashSet<ClientID>) {
         if path.is_empty() {
             // bug?
             return;
         }
 
         // exact match
-        match self.children.get(&path[0]) {
-            Some(ref node) => {
-                if path.len() == 1 {
-                    *acc = &*acc | &node.connections;
-                } else {
-                    node.find(path.split_at(1).1, acc);
-                }
-            }
-            None => {}
+        if let Some(node) = self.children.get(&path[0]) {
+            node.find_rest(path.split_at(1).1, acc);
+        }
+
+        if !match_wildcards {
+            return;
         }
 
         // single level match
-        match self.children.get(SINGLE_LEVEL_WILD_CARD) {
-            Some(ref node) => {
-                if path.len() == 1 {
+        if let Some(node) = self.children.get(SINGLE_LEVEL_WILD_CARD) {
+            node.find_rest(path.split_at(1).1, acc);
+        }
+
+        // wildcard match
+        if let Some(node) = self.children.get(WILD_CARD) {
+            *acc = &*acc | &node.connections;
+        }
+    }
+
+    // `self` has matched a level of a topic, `rest` are levels which follow it
+    fn find_rest(&self, rest: &[PathStep], acc: &mut HashSet<ClientID>) {
+        if !rest.is_empty() {
+            self.find(rest, true, acc);
+            return;
+        }
+
+        *acc = &*acc | &self.connections;
+        // `#` matches a parent level as well, e.g. `a/#` matches `a`
+        if let Some(node) = self.children.get(WILD_CARD) {
+            *acc = &*acc | &node.connections;
+        }
+    }
+
+    // same as `find`, but `path` may contain wild cards as well
+    fn find_overlapping(&self, path: &[PathStep], acc: &mut HashSet<ClientID>) {
+        let rest = path.split_at(1).1;
+
+        for (step, node) in &self.children {
+            if step == WILD_CARD || path[0] == WILD_CARD {
+                node.collect_all(acc);
+            } else if step == SINGLE_LEVEL_WILD_CARD
+                || path[0] == SINGLE_LEVEL_WILD_CARD
+                || step == &path[0]
+            {
+                if rest.is_empty() {
                     *acc = &*acc | &node.connections;
                 } else {
-                    node.find(path.split_at(1).1, acc);
+                    node.find_overlapping(rest, acc);
                 }
             }
-            None => {}
         }
+    }
 
-        // wildcard match
-        match self.children.get(WILD_CARD) {
-            Some(ref node) => {
-                *acc = &*acc | &node.connections;
-            }
-            None => {}
+    fn collect_entries<'a>(
+        &'a self,
+        path: &mut Vec<&'a str>,
+        acc: &mut Vec<(String, Vec<ClientID>)>,
+    ) {
+        if !self.connections.is_empty() {
+            let mut connections: Vec<ClientID> = self.connections.iter().cloned().collect();
+            connections.sort();
+            acc.push((path.join("/"), connections));
+        }
+
+        for (step, child) in &self.children {
+            path.push(step);
+            child.collect_entries(path, acc);
+            path.pop();
+        }
+    }
+
+    fn collect_usage(&self, usage: &mut TreeUsage) {
+        usage.subscriptions += self.connections.len();
+        usage.estimated_bytes += self.connections.capacity() * size_of::<ClientID>()
+            + self.children.capacity() * size_of::<(PathStep, SubscriptionNode)>();
+        for connection in &self.connections {
+            usage.estimated_bytes += connection.capacity();
+        }
+
+        for (step, child) in &self.children {
+            usage.nodes += 1;
+            usage.estimated_bytes += step.capacity();
+            child.collect_usage(usage);
+        }
+    }
+
+    fn collect_all(&self, acc: &mut HashSet<ClientID>) {
+        *acc = &*acc | &self.connections;
+        for child in self.children.values() {
+            child.collect_all(acc);
         }
     }
 }
@@ -188,7 +387,7 @@ mod tests {
 
         // empty subscription
         {
-            tree.add_subscriber(&vec![], make_addr(1));
+            tree.add_subscriber(&[], make_addr(1));
             assert!(
                 tree.0.children.is_empty(),
                 "should do nothing if empty-string topic is used as a new subscription (children)"
@@ -226,6 +425,20 @@ mod tests {
                 "third level should be the last one"
             );
         }
+
+        // repeated subscription
+        {
+            let subscription =
+                Subscription::try_from("a/b").expect("should create new subscription from string");
+            assert!(
+                !tree.add_subscriber(&subscription.path, make_addr(1)),
+                "repeated subscription should not be reported as a new one"
+            );
+            assert!(
+                tree.add_subscriber(&subscription.path, make_addr(2)),
+                "subscription of another client should be reported as a new one"
+            );
+        }
     }
 
     #[test]
@@ -252,7 +465,7 @@ mod tests {
             });
 
             assert_eq!(
-                tree_no_matches.find_subscribers(&vec![String::from("c")]),
+                tree_no_matches.find_subscribers(&[String::from("c")]),
                 make_hash_set(vec![])
             );
         }
@@ -291,7 +504,7 @@ mod tests {
                 },
             });
 
-            let subscribers = tree.find_subscribers(&vec![String::from("b"), String::from("c")]);
+            let subscribers = tree.find_subscribers(&[String::from("b"), String::from("c")]);
 
             assert_eq!(subscribers.len(), 3, "number of subscribers");
             assert!(
@@ -320,6 +533,24 @@ mod tests {
                 "should find newly added subscription"
             )
         }
+
+        // # matches a parent level, root wild cards don't match $ topics
+        {
+            let mut tree = new_tree();
+            tree.add_subscriber(&[String::from("a"), String::from("#")], make_addr(1));
+            tree.add_subscriber(&[String::from("#")], make_addr(2));
+            tree.add_subscriber(&[String::from("+"), String::from("x")], make_addr(3));
+            tree.add_subscriber(&[String::from("$SYS"), String::from("#")], make_addr(4));
+
+            assert_eq!(
+                tree.find_subscribers(&[String::from("a")]),
+                make_hash_set(vec![make_addr(1), make_addr(2)])
+            );
+            assert_eq!(
+                tree.find_subscribers(&[String::from("$SYS"), String::from("x")]),
+                make_hash_set(vec![make_addr(4)])
+            );
+        }
     }
 
     #[test]
@@ -346,4 +577,253 @@ mod tests {
             tree.remove_subscriber(&sub_1, make_addr(3));
         }
     }
+
+    #[test]
+    fn repeated_add_subscriber() {
+        let mut tree = new_tree();
+        let sub = vec![String::from("a"), String::from("+")];
+
+        assert!(tree.add_subscriber(&sub, make_addr(1)));
+        assert!(
+            !tree.add_subscriber(&sub, make_addr(1)),
+            "should report an existing subscription"
+        );
+        assert_eq!(
+            tree.entries(),
+            vec![("a/+".to_string(), vec![make_addr(1)])],
+            "should register a client once"
+        );
+        assert_eq!(tree.usage().subscriptions, 1);
+
+        tree.remove_subscriber(&sub, make_addr(1));
+        assert!(
+            tree.find_subscribers(&[String::from("a"), String::from("b")])
+                .is_empty(),
+            "a single removal should unsubscribe"
+        );
+    }
+
+    #[test]
+    fn remove_unknown_subscriber() {
+        let mut tree = new_tree();
+        let sub = vec![String::from("a"), String::from("b")];
+        tree.add_subscriber(&sub, make_addr(1));
+        let entries = tree.entries();
+
+        // + a topic filter which has never been subscribed to
+        tree.remove_subscriber(&[String::from("a"), String::from("c")], make_addr(1));
+        tree.remove_subscriber(&[String::from("x")], make_addr(1));
+        // + a client which hasn't subscribed to a topic filter
+        tree.remove_subscriber(&sub, make_addr(2));
+        assert_eq!(tree.entries(), entries, "should leave the tree as it is");
+        assert_eq!(tree.usage().nodes, 2, "should not add nodes");
+
+        // + repeated removal
+        tree.remove_subscriber(&sub, make_addr(1));
+        tree.remove_subscriber(&sub, make_addr(1));
+        assert!(tree.0.children.is_empty(), "should clean the tree");
+        assert!(tree.0.connections.is_empty());
+    }
+
+    #[test]
+    fn find_matching_subscribers() {
+        let mut tree = new_tree();
+        let subscribe = |tree: &mut SubscriptionTree, filter: &str, n: u16| {
+            let subscription = Subscription::try_from(filter).unwrap();
+            tree.add_subscriber(&subscription.path, make_addr(n));
+        };
+        let find = |tree: &SubscriptionTree, filter: &str| {
+            let subscription = Subscription::try_from(filter).unwrap();
+            tree.find_matching_subscribers(&subscription.path)
+        };
+
+        subscribe(&mut tree, "devices/1/telemetry", 1);
+        subscribe(&mut tree, "devices/2/telemetry", 2);
+        subscribe(&mut tree, "devices/+/telemetry", 3);
+        subscribe(&mut tree, "devices/#", 4);
+        subscribe(&mut tree, "devices/1/status", 5);
+        subscribe(&mut tree, "#", 6);
+
+        // topic names give the same result as a publish fan-out
+        assert_eq!(
+            find(&tree, "devices/1/telemetry"),
+            tree.find_subscribers(&Subscription::try_from("devices/1/telemetry").unwrap().path),
+        );
+
+        assert_eq!(
+            find(&tree, "devices/+/telemetry"),
+            make_hash_set(vec![
+                make_addr(1),
+                make_addr(2),
+                make_addr(3),
+                make_addr(4),
+                make_addr(6)
+            ]),
+            "single level wild card should match any level of subscriptions"
+        );
+        assert_eq!(
+            find(&tree, "devices/1/#"),
+            make_hash_set(vec![
+                make_addr(1),
+                make_addr(3),
+                make_addr(4),
+                make_addr(5),
+                make_addr(6)
+            ]),
+            "wild card should match all subscriptions below"
+        );
+        assert_eq!(
+            find(&tree, "sensors/+"),
+            make_hash_set(vec![make_addr(6)]),
+            "only wild card subscription should match"
+        );
+    }
+
+    #[test]
+    fn entries() {
+        let mut tree = new_tree();
+        for (filter, n) in [("a/+", 2), ("a/b", 1), ("a/+", 1), ("#", 3)] {
+            let subscription = Subscription::try_from(filter).unwrap();
+            tree.add_subscriber(&subscription.path, make_addr(n));
+        }
+        tree.remove_subscriber(&["a".into(), "b".into()], make_addr(1));
+
+        assert_eq!(
+            tree.entries(),
+            vec![
+                ("#".to_string(), vec![make_addr(3)]),
+                ("a/+".to_string(), vec![make_addr(1), make_addr(2)]),
+            ],
+            "should list non-empty filters with sorted subscribers"
+        );
+    }
+
+    #[test]
+    fn usage() {
+        let mut tree = new_tree();
+        let empty = tree.usage();
+        assert_eq!((empty.nodes, empty.subscriptions), (0, 0));
+
+        for (filter, n) in [("a/+", 1), ("a/+", 2), ("a/b/c", 1), ("#", 3)] {
+            let subscription = Subscription::try_from(filter).unwrap();
+            tree.add_subscriber(&subscription.path, make_addr(n));
+        }
+        let usage = tree.usage();
+        assert_eq!(usage.nodes, 5, "a, +, b, c and # levels");
+        assert_eq!(usage.subscriptions, 4);
+        assert!(usage.estimated_bytes > empty.estimated_bytes);
+
+        tree.remove_subscriber(&["a".into(), "b".into(), "c".into()], make_addr(1));
+        assert_eq!(
+            tree.usage().nodes,
+            3,
+            "removed levels should not be counted"
+        );
+    }
+
+    #[test]
+    fn subscribers_resolved_before_a_change_are_stale() {
+        let shared = SharedSubscriptionTree::new(new_tree(), 10);
+        let filter = Subscription::try_from("a/+").unwrap();
+        let topic = vec!["a".to_string(), "b".to_string()];
+
+        let resolved = shared.resolve(&topic);
+        assert!(resolved.subscribers.is_empty());
+        assert!(shared.is_current(&resolved));
+
+        shared.update(|tree, routing_cache| {
+            routing_cache.invalidate_filter(&filter.path);
+            tree.add_subscriber(&filter.path, make_addr(1))
+        });
+        assert!(!shared.is_current(&resolved));
+
+        let resolved = shared.resolve(&topic);
+        assert!(shared.is_current(&resolved));
+        assert_eq!(*resolved.subscribers, make_hash_set(vec![make_addr(1)]));
+    }
+}
+
+/// Properties which tie topic matching of `Subscription::topic_matches`, `topics_match` and
+/// the subscription tree to a reference implementation of the MQTT matching rules, so they
+/// can't drift apart.
+#[cfg(test)]
+mod matching_properties {
+    use super::*;
+    use mqtt_packets::v_3_1_1::topic::{topics_match, Subscription, Topic};
+    use proptest::{collection::vec, prelude::*, sample::select};
+
+    /// Matching rules of MQTT 3.1.1, section 4.7.
+    fn reference_match(filter: &[String], topic: &[String]) -> bool {
+        // wildcards at the first level don't match topics starting with `$`
+        if topic[0].starts_with(SYSTEM_PREFIX)
+            && (filter[0] == WILD_CARD || filter[0] == SINGLE_LEVEL_WILD_CARD)
+        {
+            return false;
+        }
+
+        fn levels_match(filter: &[String], topic: &[String]) -> bool {
+            match (filter.first(), topic.first()) {
+                // `#` matches a parent level as well, e.g. `a/#` matches `a`
+                (Some(pattern), _) if pattern == WILD_CARD => true,
+                (Some(pattern), Some(level)) => {
+                    (pattern == SINGLE_LEVEL_WILD_CARD || pattern == level)
+                        && levels_match(&filter[1..], &topic[1..])
+                }
+                (None, None) => true,
+                _ => false,
+            }
+        }
+
+        levels_match(filter, topic)
+    }
+
+    fn topic() -> impl Strategy<Value = Topic> {
+        vec(select(vec!["a", "b", "$sys", ""]), 1..5)
+            .prop_map(|levels| Topic::make_from_string(levels.join("/")))
+    }
+
+    fn filter() -> impl Strategy<Value = Subscription> {
+        (
+            vec(select(vec!["a", "b", "$sys", "", "+"]), 0..4),
+            any::<bool>(),
+        )
+            .prop_filter_map(
+                "a filter has at least one level",
+                |(mut levels, multi_level)| {
+                    if multi_level {
+                        levels.push(WILD_CARD);
+                    }
+                    let filter = Subscription::try_from(levels.join("/")).unwrap();
+                    (!levels.is_empty() && filter.is_valid()).then_some(filter)
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn topic_matches_agrees_with_reference(filter in filter(), topic in topic()) {
+            let expected = reference_match(&filter.path, &topic.path);
+            prop_assert_eq!(filter.topic_matches(&topic), expected);
+            prop_assert_eq!(topics_match(&topic.path, &filter.path), expected);
+        }
+
+        #[test]
+        fn tree_agrees_with_brute_force(
+            filters in vec(filter(), 1..8),
+            topic in topic(),
+        ) {
+            let mut tree = SubscriptionTree(SubscriptionNode::new());
+            for (n, filter) in filters.iter().enumerate() {
+                tree.add_subscriber(&filter.path, n.to_string());
+            }
+
+            let expected: HashSet<ClientID> = filters
+                .iter()
+                .enumerate()
+                .filter(|(_, filter)| reference_match(&filter.path, &topic.path))
+                .map(|(n, _)| n.to_string())
+                .collect();
+            prop_assert_eq!(tree.find_subscribers(&topic.path), expected);
+        }
+    }
 }
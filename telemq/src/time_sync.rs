@@ -0,0 +1,147 @@
+//! Broker time published over MQTT, so devices without a real time clock can coarse-sync
+//! their clocks when NTP isn't reachable (e.g. from behind restrictive firewalls).
+//!
+//! `time_sync_topic` keeps a retained message with the broker time, which is updated every
+//! `time_sync_interval`, so a device receives it as soon as it subscribes. A device which needs
+//! a fresh time publishes any message to `time_sync_request_topic` and receives the time on
+//! `{time_sync_topic}/{client_id}`. The payload is a number of milliseconds since the Unix
+//! epoch (UTC).
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mqtt_packets::v_3_1_1::{
+    builders::PublishPacketBuilder, topic::Topic, variable::Variable, ControlPacket,
+};
+
+use crate::config::TeleMQServerConfig;
+
+#[derive(Debug)]
+pub struct TimeSync {
+    topic: String,
+    interval: Option<Duration>,
+    request_topic: Option<String>,
+}
+
+impl TimeSync {
+    /// `None` if broker time is neither published periodically nor on request.
+    pub fn new(config: &TeleMQServerConfig) -> Option<Self> {
+        if config.time_sync_interval.is_none() && config.time_sync_request_topic.is_none() {
+            return None;
+        }
+
+        Some(TimeSync {
+            topic: config.time_sync_topic.clone(),
+            interval: config.time_sync_interval,
+            request_topic: config.time_sync_request_topic.clone(),
+        })
+    }
+
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// A retained message with the broker time.
+    pub fn time_packet(&self, now: SystemTime) -> ControlPacket {
+        // the topic is validated with the config
+        Self::packet(Topic::make_from_string(&self.topic), now, true)
+    }
+
+    /// A reply to a message if it's a time request. Client ids with wildcards can't be
+    /// a topic level, so their requests are not answered.
+    pub fn reply(
+        &self,
+        request: &ControlPacket,
+        client_id: &str,
+        now: SystemTime,
+    ) -> Option<ControlPacket> {
+        let request_topic = self.request_topic.as_ref()?;
+        match request.variable {
+            Variable::Publish(ref variable) if &variable.topic_name.original == request_topic => {
+                let topic = Topic::try_from(format!("{}/{}", self.topic, client_id)).ok()?;
+                Some(Self::packet(topic, now, false))
+            }
+            _ => None,
+        }
+    }
+
+    fn packet(topic: Topic, now: SystemTime, retained: bool) -> ControlPacket {
+        let millis = now
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_millis())
+            .unwrap_or(0);
+        let mut builder = PublishPacketBuilder::new();
+        builder
+            .with_topic(topic)
+            .with_payload(millis.to_string().into_bytes())
+            .with_retained(retained);
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_packets::v_3_1_1::publish::fixed_header::is_retained;
+
+    fn time_sync(request_topic: Option<&str>) -> TimeSync {
+        let config = TeleMQServerConfig {
+            time_sync_interval: Some(Duration::from_secs(60)),
+            time_sync_request_topic: request_topic.map(String::from),
+            ..TeleMQServerConfig::default()
+        };
+        TimeSync::new(&config).unwrap()
+    }
+
+    fn publish(topic: &str) -> ControlPacket {
+        let mut builder = PublishPacketBuilder::new();
+        builder.with_topic(Topic::make_from_string(topic));
+        builder.build()
+    }
+
+    fn topic_and_payload(packet: &ControlPacket) -> (String, String) {
+        match packet.variable {
+            Variable::Publish(ref variable) => (
+                variable.topic_name.original.clone(),
+                String::from_utf8(variable.payload.to_vec()).unwrap(),
+            ),
+            _ => panic!("Publish is expected"),
+        }
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        assert!(TimeSync::new(&TeleMQServerConfig::default()).is_none());
+    }
+
+    #[test]
+    fn time_is_retained_in_milliseconds() {
+        let now = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let packet = time_sync(None).time_packet(now);
+
+        assert!(is_retained(&packet.fixed_header));
+        assert_eq!(
+            topic_and_payload(&packet),
+            ("$SYS/broker/time".into(), "1700000000123".into())
+        );
+    }
+
+    #[test]
+    fn requests_are_answered_per_client() {
+        let time_sync = time_sync(Some("time/request"));
+        let now = UNIX_EPOCH + Duration::from_secs(1);
+
+        let reply = time_sync
+            .reply(&publish("time/request"), "device-1", now)
+            .unwrap();
+        assert!(!is_retained(&reply.fixed_header));
+        assert_eq!(
+            topic_and_payload(&reply),
+            ("$SYS/broker/time/device-1".into(), "1000".into())
+        );
+        assert!(time_sync
+            .reply(&publish("time/other"), "device-1", now)
+            .is_none());
+        assert!(time_sync
+            .reply(&publish("time/request"), "device+", now)
+            .is_none());
+    }
+}
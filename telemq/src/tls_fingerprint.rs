@@ -0,0 +1,229 @@
+//! JA3 fingerprints of TLS clients, taken from ClientHello before the handshake. A TLS stack
+//! offers its own ciphers, extensions and curves in its own order, so a device whose
+//! credentials are used by a different firmware or library gets a different fingerprint.
+//!
+//! A fingerprint is `SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats`
+//! of decimal values, with GREASE values left out, and its MD5 hash.
+use std::time::Duration;
+
+use crypto::{digest::Digest, md5::Md5};
+use tokio::{
+    net::TcpStream,
+    time::{sleep, Instant},
+};
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+const EXTENSION_EC_POINT_FORMATS: u16 = 11;
+const RECORD_HEADER_LEN: usize = 5;
+const MAX_RECORD_LEN: usize = RECORD_HEADER_LEN + 16_384;
+/// The rest of a record is peeked again after this time, if it has not arrived at once.
+const PEEK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ja3Fingerprint {
+    /// e.g. `771,4865-4866,0-10-11,29-23,0`
+    pub fingerprint: String,
+    /// MD5 of `fingerprint` in hex.
+    pub hash: String,
+}
+
+/// Reads ClientHello without consuming it, so the handshake is not affected. `None` if it
+/// doesn't arrive within `timeout` or it doesn't fit into the first TLS record.
+pub async fn peek_client_hello(stream: &TcpStream, timeout: Duration) -> Option<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; MAX_RECORD_LEN];
+    loop {
+        let peeked = tokio::time::timeout_at(deadline, stream.peek(&mut buf))
+            .await
+            .ok()?
+            .ok()?;
+        if peeked == 0 {
+            return None;
+        }
+        if peeked >= RECORD_HEADER_LEN {
+            let record_len = RECORD_HEADER_LEN + u16::from_be_bytes([buf[3], buf[4]]) as usize;
+            if peeked >= record_len {
+                buf.truncate(record_len);
+                return Some(buf);
+            }
+        }
+        if Instant::now() + PEEK_RETRY_INTERVAL >= deadline {
+            return None;
+        }
+        sleep(PEEK_RETRY_INTERVAL).await;
+    }
+}
+
+/// Fingerprint of a TLS record with ClientHello, `None` if it's not one.
+pub fn ja3(record: &[u8]) -> Option<Ja3Fingerprint> {
+    let mut record = Reader(record);
+    if record.u8()? != CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+    record.skip(2)?;
+    let mut handshake = Reader(record.vec(2)?);
+    if handshake.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let mut hello = Reader(handshake.vec(3)?);
+
+    let version = hello.u16()?;
+    // random
+    hello.skip(32)?;
+    // session id
+    hello.vec(1)?;
+    let ciphers = Reader(hello.vec(2)?).u16_list()?;
+    // compression methods
+    hello.vec(1)?;
+
+    let mut extensions = vec![];
+    let mut curves = vec![];
+    let mut point_formats = vec![];
+    // extensions are optional
+    if !hello.0.is_empty() {
+        let mut extension_list = Reader(hello.vec(2)?);
+        while !extension_list.0.is_empty() {
+            let extension = extension_list.u16()?;
+            let mut data = Reader(extension_list.vec(2)?);
+            match extension {
+                EXTENSION_SUPPORTED_GROUPS => curves = Reader(data.vec(2)?).u16_list()?,
+                EXTENSION_EC_POINT_FORMATS => {
+                    point_formats = data.vec(1)?.iter().map(|format| *format as u16).collect()
+                }
+                _ => {}
+            }
+            extensions.push(extension);
+        }
+    }
+
+    let fingerprint = format!(
+        "{},{},{},{},{}",
+        version,
+        join(&ciphers),
+        join(&extensions),
+        join(&curves),
+        join(&point_formats)
+    );
+    let mut md5 = Md5::new();
+    md5.input_str(&fingerprint);
+
+    Some(Ja3Fingerprint {
+        fingerprint,
+        hash: md5.result_str(),
+    })
+}
+
+/// GREASE values (RFC 8701) are random, so they are left out of fingerprints.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn join(values: &[u16]) -> String {
+    values
+        .iter()
+        .filter(|value| !is_grease(**value))
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A vector prefixed by its length of `len_bytes` bytes.
+    fn vec(&mut self, len_bytes: usize) -> Option<&'a [u8]> {
+        let len = self
+            .take(len_bytes)?
+            .iter()
+            .fold(0, |len, byte| len << 8 | *byte as usize);
+        self.take(len)
+    }
+
+    fn u16_list(&mut self) -> Option<Vec<u16>> {
+        let mut values = vec![];
+        while !self.0.is_empty() {
+            values.push(self.u16()?);
+        }
+
+        Some(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ClientHello record of TLS 1.2 with a GREASE cipher and a GREASE extension.
+    fn client_hello() -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        // session id
+        hello.extend_from_slice(&[0]);
+        // ciphers
+        hello.extend_from_slice(&[0x00, 0x06, 0x1a, 0x1a, 0x13, 0x01, 0xc0, 0x2f]);
+        // compression methods
+        hello.extend_from_slice(&[0x01, 0x00]);
+        let extensions: Vec<u8> = [
+            // GREASE
+            &[0x2a, 0x2a, 0x00, 0x00][..],
+            // server name
+            &[0x00, 0x00, 0x00, 0x00],
+            // supported groups: x25519, secp256r1
+            &[0x00, 0x0a, 0x00, 0x06, 0x00, 0x04, 0x00, 0x1d, 0x00, 0x17],
+            // EC point formats: uncompressed
+            &[0x00, 0x0b, 0x00, 0x02, 0x01, 0x00],
+        ]
+        .concat();
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO, 0];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+
+        record
+    }
+
+    #[test]
+    fn fingerprint_of_client_hello_without_grease() {
+        let ja3 = ja3(&client_hello()).unwrap();
+
+        assert_eq!(ja3.fingerprint, "771,4865-49199,0-10-11,29-23,0");
+        assert_eq!(ja3.hash, "bca193bf3b6d2156cfbe0e6b4b306d3e");
+    }
+
+    #[test]
+    fn truncated_or_other_records_have_no_fingerprint() {
+        let record = client_hello();
+
+        assert_eq!(ja3(&record[..record.len() - 1]), None);
+        assert_eq!(ja3(b"\x10\x0c\x00\x04MQTT"), None);
+    }
+}
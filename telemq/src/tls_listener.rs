@@ -1,76 +1,311 @@
 use std::{fs::File, io, net::SocketAddr, path::Path, sync::Arc, time::Duration};
 
+use arc_swap::ArcSwap;
+
+use crate::{
+    config::TeleMQServerConfig,
+    handover::bind_tcp,
+    tls_fingerprint::{ja3, peek_client_hello, Ja3Fingerprint},
+    transport_bytes::{CountingStream, TransportBytes},
+};
 use futures::future::pending;
-use rustls_pemfile::{certs, rsa_private_keys};
+use log::debug;
+use rustls_pemfile::{certs, read_all, Item};
+use serde::Deserialize;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::{
-    rustls::{Certificate, PrivateKey, ServerConfig},
+    rustls::{
+        server::Acceptor, version, Certificate, PrivateKey, ServerConfig, SupportedCipherSuite,
+        SupportedProtocolVersion, ALL_CIPHER_SUITES, ALL_VERSIONS,
+    },
     server::TlsStream,
-    TlsAcceptor,
+    LazyConfigAcceptor,
 };
 
+/// ClientHello which hasn't arrived in this time is not fingerprinted, a handshake goes on.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&version::TLS13];
+
+/// ALPN protocols of the TLS listener, so load balancers can route connections by ALPN.
+#[derive(Debug, Clone, Default)]
+pub struct Alpn {
+    /// In order of preference of the broker, ALPN is not negotiated if empty. Clients offering
+    /// none of them are rejected by rustls with a `no_application_protocol` alert.
+    pub protocols: Vec<String>,
+    /// Clients which don't offer ALPN at all are rejected too.
+    pub required: bool,
+}
+
+impl Alpn {
+    pub fn new(config: &TeleMQServerConfig) -> Self {
+        Alpn {
+            protocols: config.tls_alpn_protocols.clone(),
+            required: config.tls_alpn_required,
+        }
+    }
+
+    /// Protocols as they are put into rustls config.
+    pub fn protocol_ids(&self) -> Vec<Vec<u8>> {
+        self.protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect()
+    }
+}
+
+/// The lowest TLS version a client may negotiate.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    V1_2,
+    #[serde(rename = "1.3")]
+    V1_3,
+}
+
+impl TlsVersion {
+    fn protocol_versions(&self) -> &'static [&'static SupportedProtocolVersion] {
+        match *self {
+            TlsVersion::V1_2 => ALL_VERSIONS,
+            TlsVersion::V1_3 => TLS13_ONLY,
+        }
+    }
+}
+
+/// rustls settings shared by the TLS and the Websocket TLS listeners.
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub min_version: TlsVersion,
+    /// Names of cipher suites, all suites supported by rustls if empty.
+    pub cipher_suites: Vec<String>,
+}
+
+impl TlsOptions {
+    pub fn new(config: &TeleMQServerConfig) -> Self {
+        TlsOptions {
+            min_version: config.tls_min_version,
+            cipher_suites: config.tls_cipher_suites.clone(),
+        }
+    }
+}
+
+/// A cipher suite supported by rustls by its IANA name, e.g. `TLS13_AES_128_GCM_SHA256`.
+pub fn cipher_suite(name: &str) -> Option<SupportedCipherSuite> {
+    ALL_CIPHER_SUITES.iter().copied().find(|suite| {
+        suite
+            .suite()
+            .as_str()
+            .is_some_and(|suite_name| suite_name.eq_ignore_ascii_case(name))
+    })
+}
+
+/// Loads a certificate chain and a private key of the broker and applies `options`.
+pub fn server_config(
+    cert_path: &str,
+    key_path: &str,
+    options: &TlsOptions,
+) -> io::Result<ServerConfig> {
+    let certs = load_certs(Path::new(cert_path))?;
+    let key = load_key(Path::new(key_path))?;
+    let cipher_suites = if options.cipher_suites.is_empty() {
+        ALL_CIPHER_SUITES.to_vec()
+    } else {
+        options
+            .cipher_suites
+            .iter()
+            .map(|name| {
+                cipher_suite(name).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("unknown cipher suite {}", name),
+                    )
+                })
+            })
+            .collect::<io::Result<_>>()?
+    };
+    ServerConfig::builder()
+        .with_cipher_suites(&cipher_suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(options.min_version.protocol_versions())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// rustls config of a listener, which is swapped once the certificate is reloaded. Handshakes
+/// take the current config, so established connections keep theirs and are not dropped.
+#[derive(Clone)]
+pub struct SharedServerConfig {
+    current: Arc<ArcSwap<ServerConfig>>,
+    alpn_protocols: Vec<Vec<u8>>,
+}
+
+impl SharedServerConfig {
+    pub fn load(
+        cert_path: &str,
+        key_path: &str,
+        options: &TlsOptions,
+        alpn_protocols: Vec<Vec<u8>>,
+    ) -> io::Result<Self> {
+        let mut config = server_config(cert_path, key_path, options)?;
+        config.alpn_protocols = alpn_protocols.clone();
+        Ok(SharedServerConfig {
+            current: Arc::new(ArcSwap::from_pointee(config)),
+            alpn_protocols,
+        })
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.current.load_full()
+    }
+
+    /// Loads the certificate and the key again. The current config is kept if they are not
+    /// valid.
+    pub fn reload(&self, cert_path: &str, key_path: &str, options: &TlsOptions) -> io::Result<()> {
+        let mut config = server_config(cert_path, key_path, options)?;
+        config.alpn_protocols = self.alpn_protocols.clone();
+        self.current.store(Arc::new(config));
+        Ok(())
+    }
+}
+
 pub struct TlsListener {
     listener: Option<TcpListener>,
-    config: Option<ServerConfig>,
+    config: Option<SharedServerConfig>,
     keep_alive: Duration,
+    /// Bytes are counted below TLS, so handshakes and records are included.
+    transport_bytes: Option<Arc<TransportBytes>>,
+    /// JA3 fingerprints of ClientHello are taken before handshakes.
+    fingerprint: bool,
+    alpn_required: bool,
 }
 
 impl TlsListener {
     pub async fn new(
         maybe_addr: Option<SocketAddr>,
-        maybe_cert_path: &Option<String>,
-        maybe_key_path: &Option<String>,
+        maybe_config: Option<SharedServerConfig>,
         keep_alive: Duration,
+        reuse_port: bool,
+        transport_bytes: Option<Arc<TransportBytes>>,
+        fingerprint: bool,
+        alpn: Alpn,
     ) -> io::Result<Self> {
-        match (maybe_addr, maybe_cert_path, maybe_key_path) {
-            (Some(addr), Some(cert_path), Some(key_path)) => {
-                let certs = load_certs(Path::new(&cert_path))?;
-                let mut keys = load_keys(Path::new(&key_path))?;
-                let config = ServerConfig::builder()
-                    .with_safe_defaults()
-                    .with_no_client_auth()
-                    .with_single_cert(certs, keys.remove(0))
-                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        match (maybe_addr, maybe_config) {
+            (Some(addr), Some(config)) => {
                 // let config = ServerConfig::new();
                 // config
                 //     .set_single_cert(certs, keys.remove(0))
                 //     .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
                 Ok(TlsListener {
-                    listener: Some(TcpListener::bind(&addr).await?),
+                    listener: Some(bind_tcp(addr, reuse_port)?),
                     config: Some(config),
                     keep_alive,
+                    transport_bytes,
+                    fingerprint,
+                    alpn_required: alpn.required,
                 })
             }
             _ => Ok(TlsListener {
                 listener: None,
                 config: None,
                 keep_alive,
+                transport_bytes,
+                fingerprint,
+                alpn_required: alpn.required,
             }),
         }
     }
 
-    pub async fn accept(&self) -> io::Result<(TlsStream<TcpStream>, SocketAddr)> {
+    pub fn tcp_listener(&self) -> Option<&TcpListener> {
+        self.listener.as_ref()
+    }
+
+    /// Accepts a connection and completes a TLS handshake. Returns errors of the listener only,
+    /// a failed handshake is logged and the next connection is accepted.
+    pub async fn accept(
+        &self,
+    ) -> io::Result<(
+        TlsStream<CountingStream<TcpStream>>,
+        SocketAddr,
+        Option<Ja3Fingerprint>,
+    )> {
         match (&self.listener, &self.config) {
-            (Some(listener), Some(config)) => {
+            (Some(listener), Some(config)) => loop {
                 let (stream, addr) = listener.accept().await?;
-                stream.set_ttl(self.keep_alive.as_secs() as u32)?;
-                let acceptor = TlsAcceptor::from(Arc::new(config.clone()));
-                let stream = acceptor.accept(stream).await?;
-                Ok((stream, addr))
-            }
+                match self.handshake(stream, config).await {
+                    Ok((stream, ja3)) => return Ok((stream, addr, ja3)),
+                    Err(err) => debug!("TLS handshake with {:?} has failed. {:?}", addr, err),
+                }
+            },
             _ => pending().await,
         }
     }
+
+    async fn handshake(
+        &self,
+        stream: TcpStream,
+        config: &SharedServerConfig,
+    ) -> io::Result<(TlsStream<CountingStream<TcpStream>>, Option<Ja3Fingerprint>)> {
+        stream.set_ttl(self.keep_alive.as_secs() as u32)?;
+        let ja3 = if self.fingerprint {
+            peek_client_hello(&stream, CLIENT_HELLO_TIMEOUT)
+                .await
+                .and_then(|record| ja3(&record))
+        } else {
+            None
+        };
+        let start = LazyConfigAcceptor::new(
+            Acceptor::default(),
+            CountingStream::new(stream, self.transport_bytes.clone()),
+        )
+        .await?;
+        // e.g. a TLS probe of a port scanner, it's rejected before the server's hello
+        if self.alpn_required && start.client_hello().alpn().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "ClientHello doesn't offer ALPN",
+            ));
+        }
+        let stream = start.into_stream(config.current()).await?;
+        Ok((stream, ja3))
+    }
 }
 
+/// A file without certificates fails, rustls would accept an empty chain and stall handshakes.
 fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
-    certs(&mut io::BufReader::new(File::open(path)?))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))
-        .map(|mut certs| certs.drain(..).map(Certificate).collect())
+    let certs = certs(&mut io::BufReader::new(File::open(path)?))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no certificate",
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// The first private key of a file, either PKCS#1 (RSA), PKCS#8 or SEC1 (EC).
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    read_all(&mut io::BufReader::new(File::open(path)?))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no private key"))
 }
 
-fn load_keys(path: &Path) -> io::Result<Vec<PrivateKey>> {
-    rsa_private_keys(&mut io::BufReader::new(File::open(path)?))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
-        .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cipher_suites_are_found_by_iana_name() {
+        let suite = cipher_suite("tls13_aes_128_gcm_sha256").unwrap();
+        assert_eq!(suite.suite().as_str(), Some("TLS13_AES_128_GCM_SHA256"));
+        assert!(cipher_suite("TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384").is_some());
+        assert!(cipher_suite("TLS_RSA_WITH_RC4_128_SHA").is_none());
+    }
 }
@@ -0,0 +1,182 @@
+//! Normalization of topics of PUBLISH, SUBSCRIBE and UNSUBSCRIBE packets and of wills, for
+//! fleets of clients which disagree on how a topic is spelled, e.g. `Devices/1/Status/` and
+//! `devices/1/status`. Topics are normalized before ACL checks and routing, so publishers and
+//! subscribers meet on the same topic however they spell it.
+//!
+//! Topics starting with `$` (e.g. `$SYS/...`) are reserved for the broker and are kept as is.
+use std::borrow::Cow;
+
+use mqtt_packets::v_3_1_1::{
+    topic::{Subscription, Topic},
+    variable::Variable,
+    ControlPacket,
+};
+
+use crate::config::TeleMQServerConfig;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TopicNormalization {
+    /// `a/b/` is `a/b`.
+    strip_trailing_slash: bool,
+    /// `a//b` is `a/b`.
+    collapse_separators: bool,
+    /// `A/B` is `a/b`.
+    case_insensitive: bool,
+}
+
+impl TopicNormalization {
+    pub fn new(config: &TeleMQServerConfig) -> Self {
+        TopicNormalization {
+            strip_trailing_slash: config.topic_strip_trailing_slash,
+            collapse_separators: config.topic_collapse_separators,
+            case_insensitive: config.topic_case_insensitive,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.strip_trailing_slash || self.collapse_separators || self.case_insensitive
+    }
+
+    /// Normalizes topics of a packet received from a client.
+    pub fn normalize(&self, mut packet: ControlPacket) -> ControlPacket {
+        if !self.is_enabled() {
+            return packet;
+        }
+        match packet.variable {
+            Variable::Publish(ref mut variable) => {
+                if let Cow::Owned(topic) = self.normalize_str(&variable.topic_name.original) {
+                    variable.topic_name = Topic::make_from_string(topic);
+                }
+            }
+            Variable::Subscribe(ref mut variable) => {
+                for subscription in variable.subscriptions.iter_mut() {
+                    self.normalize_filter(&mut subscription.topic_filter);
+                }
+            }
+            Variable::Unsubscribe(ref mut variable) => {
+                for filter in variable.subscriptions.iter_mut() {
+                    self.normalize_filter(filter);
+                }
+            }
+            Variable::Connect(ref mut variable) => {
+                if let Some(ref mut will_topic) = variable.will_topic {
+                    if let Cow::Owned(topic) = self.normalize_str(&will_topic.original) {
+                        *will_topic = Topic::make_from_string(topic);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        packet
+    }
+
+    fn normalize_filter(&self, filter: &mut Subscription) {
+        if let Cow::Owned(normalized) = self.normalize_str(&filter.original) {
+            if let Ok(normalized) = Subscription::try_from(&normalized) {
+                *filter = normalized;
+            }
+        }
+    }
+
+    /// A topic name or a topic filter normalized, borrowed if it's normalized already.
+    pub fn normalize_str<'a>(&self, topic: &'a str) -> Cow<'a, str> {
+        if topic.starts_with('$') {
+            return Cow::Borrowed(topic);
+        }
+
+        let mut normalized = Cow::Borrowed(topic);
+        if self.collapse_separators && normalized.contains("//") {
+            let mut collapsed = String::with_capacity(normalized.len());
+            for c in normalized.chars() {
+                if !(c == '/' && collapsed.ends_with('/')) {
+                    collapsed.push(c);
+                }
+            }
+            normalized = Cow::Owned(collapsed);
+        }
+        // `/` alone is a valid topic of two empty levels, it's not stripped to an empty one
+        if self.strip_trailing_slash && normalized.len() > 1 && normalized.ends_with('/') {
+            let stripped = normalized.trim_end_matches('/');
+            normalized = Cow::Owned(if stripped.is_empty() {
+                "/".into()
+            } else {
+                stripped.into()
+            });
+        }
+        if self.case_insensitive && normalized.chars().any(char::is_uppercase) {
+            normalized = Cow::Owned(normalized.to_lowercase());
+        }
+
+        normalized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_packets::v_3_1_1::{
+        builders::{PublishPacketBuilder, SubscribePacketBuilder},
+        PacketId, QoS,
+    };
+
+    fn normalization() -> TopicNormalization {
+        TopicNormalization {
+            strip_trailing_slash: true,
+            collapse_separators: true,
+            case_insensitive: true,
+        }
+    }
+
+    #[test]
+    fn topics_are_normalized_by_enabled_rules() {
+        let only_case = TopicNormalization {
+            case_insensitive: true,
+            ..TopicNormalization::default()
+        };
+
+        assert_eq!(
+            normalization().normalize_str("Devices//1/Status/"),
+            "devices/1/status"
+        );
+        assert_eq!(
+            only_case.normalize_str("Devices//1/Status/"),
+            "devices//1/status/"
+        );
+        assert_eq!(normalization().normalize_str("//"), "/");
+        assert_eq!(
+            normalization().normalize_str("$SYS/Broker/"),
+            "$SYS/Broker/"
+        );
+        assert!(matches!(
+            normalization().normalize_str("devices/1"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn publishes_and_subscriptions_meet_on_normalized_topics() {
+        let mut builder = PublishPacketBuilder::new();
+        builder.with_topic(Topic::try_from("Devices/1/Status/").unwrap());
+        builder.with_qos(&QoS::Zero);
+        let topic = match normalization().normalize(builder.build()).variable {
+            Variable::Publish(variable) => variable.topic_name,
+            _ => unreachable!(),
+        };
+
+        let subscribe = SubscribePacketBuilder::new(PacketId::new(1))
+            .with_subscription(
+                Subscription::try_from("devices//+/STATUS").unwrap(),
+                QoS::One,
+            )
+            .build();
+        match normalization().normalize(subscribe).variable {
+            Variable::Subscribe(variable) => {
+                let filter = &variable.subscriptions[0].topic_filter;
+                assert_eq!(filter.original, "devices/+/status");
+                assert!(filter.topic_matches(&topic));
+            }
+            _ => unreachable!(),
+        }
+    }
+}
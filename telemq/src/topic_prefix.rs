@@ -0,0 +1,149 @@
+//! Topic prefixes (mount points) of listeners. Topics of PUBLISH, SUBSCRIBE and UNSUBSCRIBE
+//! packets and a will of a client connected to a listener with `*_topic_prefix` are prefixed
+//! before ACL checks and routing, and the prefix is stripped from messages sent to the client.
+//! Tenants behind listeners of a shared broker are isolated from each other, while every
+//! client keeps using the same topics.
+use std::{borrow::Cow, sync::Arc};
+
+use mqtt_packets::v_3_1_1::{
+    topic::{Subscription, Topic},
+    variable::Variable,
+    ControlPacket,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct TopicPrefix {
+    /// `None` means topics are not prefixed.
+    prefix: Option<Arc<str>>,
+}
+
+impl TopicPrefix {
+    /// A prefix is validated by the config, it ends with `/` and has no wildcards.
+    pub fn new(prefix: &Option<String>) -> Self {
+        TopicPrefix {
+            prefix: prefix.as_deref().map(Arc::from),
+        }
+    }
+
+    /// Prefixes topics of a packet received from a client.
+    pub fn mount(&self, mut packet: ControlPacket) -> ControlPacket {
+        let prefix = match self.prefix {
+            Some(ref prefix) => prefix,
+            None => return packet,
+        };
+        match packet.variable {
+            Variable::Publish(ref mut variable) => {
+                variable.topic_name = mount_topic(prefix, &variable.topic_name);
+            }
+            Variable::Subscribe(ref mut variable) => {
+                for subscription in variable.subscriptions.iter_mut() {
+                    subscription.topic_filter = mount_filter(prefix, &subscription.topic_filter);
+                }
+            }
+            Variable::Unsubscribe(ref mut variable) => {
+                for filter in variable.subscriptions.iter_mut() {
+                    *filter = mount_filter(prefix, filter);
+                }
+            }
+            Variable::Connect(ref mut variable) => {
+                if let Some(ref mut will_topic) = variable.will_topic {
+                    *will_topic = mount_topic(prefix, will_topic);
+                }
+            }
+            _ => {}
+        }
+
+        packet
+    }
+
+    /// A packet to be sent to a client, with the prefix stripped from a topic of PUBLISH.
+    /// Topics without the prefix, e.g. of messages stored for a session before, are kept.
+    pub fn unmount<'a>(&self, packet: &'a ControlPacket) -> Cow<'a, ControlPacket> {
+        let stripped = match (&self.prefix, &packet.variable) {
+            (Some(prefix), Variable::Publish(variable)) => {
+                variable.topic_name.original.strip_prefix(&**prefix)
+            }
+            _ => None,
+        };
+        match stripped {
+            Some(topic) => {
+                let mut packet = packet.clone();
+                if let Variable::Publish(ref mut variable) = packet.variable {
+                    variable.topic_name = Topic::make_from_string(topic);
+                }
+                Cow::Owned(packet)
+            }
+            None => Cow::Borrowed(packet),
+        }
+    }
+}
+
+fn mount_topic(prefix: &str, topic: &Topic) -> Topic {
+    Topic::make_from_string(format!("{}{}", prefix, topic.original))
+}
+
+fn mount_filter(prefix: &str, filter: &Subscription) -> Subscription {
+    let mounted = format!("{}{}", prefix, filter.original);
+    Subscription::try_from(&mounted).unwrap_or_else(|_| filter.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_packets::v_3_1_1::{
+        builders::{PublishPacketBuilder, SubscribePacketBuilder},
+        PacketId, QoS,
+    };
+
+    fn publish(topic: &str) -> ControlPacket {
+        let mut builder = PublishPacketBuilder::new();
+        builder.with_topic(Topic::try_from(topic).unwrap());
+        builder.with_qos(&QoS::Zero);
+        builder.build()
+    }
+
+    fn topic_of(packet: &ControlPacket) -> &str {
+        match packet.variable {
+            Variable::Publish(ref variable) => &variable.topic_name.original,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn topics_from_clients_are_prefixed() {
+        let prefix = TopicPrefix::new(&Some("tenant-a/".into()));
+
+        let published = prefix.mount(publish("devices/1"));
+        assert_eq!(topic_of(&published), "tenant-a/devices/1");
+
+        let subscribe = SubscribePacketBuilder::new(PacketId::new(1))
+            .with_subscription(Subscription::try_from("devices/#").unwrap(), QoS::One)
+            .build();
+        match prefix.mount(subscribe).variable {
+            Variable::Subscribe(variable) => {
+                let filter = &variable.subscriptions[0].topic_filter;
+                assert_eq!(filter.original, "tenant-a/devices/#");
+                assert!(filter.topic_matches(&Topic::try_from("tenant-a/devices/1").unwrap()));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn prefix_is_stripped_from_messages_to_clients() {
+        let prefix = TopicPrefix::new(&Some("tenant-a/".into()));
+
+        assert_eq!(
+            topic_of(&prefix.unmount(&publish("tenant-a/devices/1"))),
+            "devices/1"
+        );
+        assert_eq!(
+            topic_of(&prefix.unmount(&publish("devices/1"))),
+            "devices/1"
+        );
+        assert_eq!(
+            topic_of(&TopicPrefix::default().unmount(&publish("tenant-a/devices/1"))),
+            "tenant-a/devices/1"
+        );
+    }
+}
@@ -1,7 +1,8 @@
-use std::time;
+use std::time::Duration;
 
 use mqtt_packets::v_3_1_1::{publish::fixed_header::get_qos_level, ControlPacket, PacketId, QoS};
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 
 use crate::session_error::{SessionError, SessionErrorKind, SessionResult};
 
@@ -17,8 +18,27 @@ pub struct Transaction<S> {
     pub packet_id: PacketId,
     pub control_packet: ControlPacket,
     pub state: S,
-    #[serde(skip_serializing, deserialize_with = "deserialize_time")]
-    last_update: time::Instant,
+    /// Not stored, a stored transaction is as fresh as its restored session.
+    #[serde(skip, default = "Instant::now")]
+    last_update: Instant,
+    /// Number of times the packet has been re-sent on a live connection.
+    #[serde(skip)]
+    pub retries: usize,
+}
+
+/// Re-sending of PUBLISH and PUBREL packets which are not acknowledged within `interval`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub interval: Duration,
+    // if None => packets are re-sent until they are acknowledged or the connection is closed
+    pub max_retries: Option<usize>,
+}
+
+impl RetryPolicy {
+    pub fn is_exhausted(&self, transaction: &TransactionSend) -> bool {
+        self.max_retries
+            .is_some_and(|max_retries| transaction.retries > max_retries)
+    }
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
@@ -38,11 +58,28 @@ pub enum TransactionSendState {
 impl<S> Transaction<S> {
     fn new_inner(packet_id: &PacketId, control_packet: ControlPacket, state: S) -> Transaction<S> {
         Transaction {
-            packet_id: packet_id.clone(),
+            packet_id: *packet_id,
             control_packet,
             state,
-            last_update: time::Instant::now(),
+            last_update: Instant::now(),
+            retries: 0,
+        }
+    }
+
+    /// Counts a retry in if the transaction hasn't been updated for `interval`.
+    pub fn retry(&mut self, interval: Duration, now: Instant) -> bool {
+        if now.saturating_duration_since(self.last_update) < interval {
+            return false;
         }
+        self.retries += 1;
+        self.last_update = now;
+        true
+    }
+
+    /// Starts counting retries from scratch, e.g. once a session is resumed.
+    pub fn restart_retries(&mut self, now: Instant) {
+        self.retries = 0;
+        self.last_update = now;
     }
 }
 
@@ -68,7 +105,7 @@ impl TransactionSend {
         }
 
         self.state = TransactionSendState::PubAcked;
-        self.last_update = time::Instant::now();
+        self.last_update = Instant::now();
 
         Ok(())
     }
@@ -88,7 +125,7 @@ impl TransactionSend {
         }
 
         self.state = TransactionSendState::PubReced;
-        self.last_update = time::Instant::now();
+        self.last_update = Instant::now();
 
         Ok(())
     }
@@ -108,7 +145,7 @@ impl TransactionSend {
         }
 
         self.state = TransactionSendState::PubComped;
-        self.last_update = time::Instant::now();
+        self.last_update = Instant::now();
 
         Ok(())
     }
@@ -145,19 +182,12 @@ impl TransactionReceive {
         }
 
         self.state = TransactionReceiveState::PubReled;
-        self.last_update = time::Instant::now();
+        self.last_update = Instant::now();
 
         Ok(())
     }
 }
 
-fn deserialize_time<'de, D>(_deserializer: D) -> Result<time::Instant, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    Ok(time::Instant::now())
-}
-
 // FIXME:
 // #[cfg(test)]
 // mod test {
@@ -0,0 +1,180 @@
+//! Bytes sent and received on the wire per listener, as opposed to sizes of MQTT packets
+//! counted by Stats Worker, so the overhead of a transport is known, e.g. for capacity
+//! planning of cellular links.
+//!
+//! TCP and TLS streams are counted below TLS, so TLS handshakes, record headers and
+//! authentication tags are included. WebSocket frames are counted by their sizes: warp
+//! upgrades connections and terminates TLS of `wss` itself, so HTTP upgrade requests and
+//! TLS records of `wss` are not included.
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Debug, Default)]
+pub struct TransportBytes {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl TransportBytes {
+    pub fn add_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters of every listener, shared by connections and Stats Worker.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerTransportBytes {
+    pub tcp: Arc<TransportBytes>,
+    pub tls: Arc<TransportBytes>,
+    pub ws: Arc<TransportBytes>,
+    pub wss: Arc<TransportBytes>,
+}
+
+impl ListenerTransportBytes {
+    pub fn listeners(&self) -> [(&'static str, &TransportBytes); 4] {
+        [
+            ("tcp", &self.tcp),
+            ("tls", &self.tls),
+            ("ws", &self.ws),
+            ("wss", &self.wss),
+        ]
+    }
+}
+
+/// A stream which counts bytes read and written, if counters are enabled.
+#[derive(Debug)]
+pub struct CountingStream<S> {
+    inner: S,
+    bytes: Option<Arc<TransportBytes>>,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S, bytes: Option<Arc<TransportBytes>>) -> Self {
+        CountingStream { inner, bytes }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(bytes)) = (&poll, &self.bytes) {
+            bytes.add_received((buf.filled().len() - filled) as u64);
+        }
+
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(written)), Some(bytes)) = (&poll, &self.bytes) {
+            bytes.add_sent(*written as u64);
+        }
+
+        poll
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if let (Poll::Ready(Ok(written)), Some(bytes)) = (&poll, &self.bytes) {
+            bytes.add_sent(*written as u64);
+        }
+
+        poll
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Size of a WebSocket frame with a payload of `payload_len` bytes. Frames sent by clients
+/// are masked, which takes 4 more bytes.
+pub fn ws_frame_size(payload_len: usize, masked: bool) -> u64 {
+    let length_bytes = match payload_len {
+        0..=125 => 0,
+        126..=65_535 => 2,
+        _ => 8,
+    };
+    let mask_bytes = if masked { 4 } else { 0 };
+
+    (2 + length_bytes + mask_bytes + payload_len) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn bytes_are_counted_both_ways() {
+        let (client, server) = duplex(64);
+        let bytes = Arc::new(TransportBytes::default());
+        let mut server = CountingStream::new(server, Some(bytes.clone()));
+        let mut client = client;
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"hi").await.unwrap();
+
+        assert_eq!(bytes.received(), 5);
+        assert_eq!(bytes.sent(), 2);
+    }
+
+    #[test]
+    fn ws_frame_size_includes_header() {
+        assert_eq!(ws_frame_size(2, false), 4);
+        assert_eq!(ws_frame_size(2, true), 8);
+        assert_eq!(ws_frame_size(126, false), 130);
+        assert_eq!(ws_frame_size(65_536, true), 65_550);
+    }
+}
@@ -0,0 +1,238 @@
+//! Lifecycle events of clients exported to an HTTP endpoint, `webhook_url`.
+//!
+//! Connects and disconnects of clients are put into an outbox, which is written to
+//! `webhook_outbox_file` before events are sent, so they are not lost while the endpoint
+//! is down or the broker is restarted. Changes of the outbox are written together, every
+//! `COMMIT_INTERVAL` or once `COMMIT_CHANGES` events have been added or delivered, on a
+//! blocking thread, so a crash loses events of the last `COMMIT_INTERVAL` at most. Events are POSTed as a JSON array, up to
+//! `BATCH_SIZE` at a time and in order they have occurred, and are removed from the outbox
+//! once the endpoint responds with 2xx. A failed request is retried after a delay doubled
+//! with every failure up to `webhook_retry_max_interval`.
+//!
+//! Delivery is at least once: a batch is sent again if the broker stops before the endpoint
+//! responds, so a receiver should skip ids of events it has seen. Once the outbox holds
+//! `webhook_outbox_size` events, the oldest ones are dropped and counted under
+//! `$SYS/broker/webhook/dropped`.
+use std::{
+    io,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use log::{error, info, warn};
+use reqwest::Client;
+use tokio::{
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::spawn_blocking,
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
+};
+
+pub use crate::webhook_outbox::LifecycleEvent;
+use crate::{
+    clock::Clock,
+    config::TeleMQServerConfig,
+    stats::{StatsMessage, StatsSender},
+    webhook_outbox::Outbox,
+};
+
+pub type WebhookSender = UnboundedSender<LifecycleEvent>;
+
+/// Max events of a single request.
+const BATCH_SIZE: usize = 100;
+/// Delay after the first failed request in a row.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Max time changes of the outbox wait to be written.
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of added and delivered events the outbox is written after, regardless of the timer.
+const COMMIT_CHANGES: usize = 1000;
+
+pub struct Webhook {
+    url: String,
+    client: Client,
+    broker_id: String,
+    outbox: Outbox,
+    /// Added and delivered events not written to the outbox file yet.
+    uncommitted: usize,
+    receiver: UnboundedReceiver<LifecycleEvent>,
+    clock: Clock,
+    max_retry_delay: Duration,
+}
+
+impl Webhook {
+    /// `None` if `webhook_url` is not set.
+    pub fn new(
+        config: &TeleMQServerConfig,
+        clock: Clock,
+    ) -> Result<Option<(Self, WebhookSender)>, String> {
+        let url = match config.webhook_url {
+            Some(ref url) => url.clone(),
+            None => return Ok(None),
+        };
+        let client = Client::builder()
+            .timeout(config.webhook_timeout)
+            .build()
+            .map_err(|err| format!("Unable to create a webhook client. {:?}", err))?;
+        let (sender, receiver) = unbounded_channel();
+        Ok(Some((
+            Webhook {
+                url,
+                client,
+                broker_id: config.broker_id.clone(),
+                outbox: Outbox::open(
+                    Some(config.webhook_outbox_file.clone()),
+                    config.webhook_outbox_size,
+                ),
+                uncommitted: 0,
+                receiver,
+                clock,
+                max_retry_delay: config.webhook_retry_max_interval,
+            },
+            sender,
+        )))
+    }
+
+    pub async fn run(mut self, stats_sender: StatsSender) {
+        info!(
+            "[Webhook]: exporting events to {}, {} events are in the outbox",
+            self.url,
+            self.outbox.len()
+        );
+        let mut retry_delay = MIN_RETRY_DELAY;
+        let mut next_attempt = Instant::now();
+        report(&stats_sender, self.outbox.len(), 0, 0);
+        let mut commit_timer = interval(COMMIT_INTERVAL);
+        commit_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            select! {
+              event = self.receiver.recv() => {
+                let event = match event {
+                    Some(event) => event,
+                    // Stats Worker has stopped, the broker is shutting down
+                    None => {
+                        self.commit().await;
+                        return;
+                    }
+                };
+                let mut dropped = self.push(event);
+                while let Ok(event) = self.receiver.try_recv() {
+                    dropped += self.push(event);
+                }
+                if dropped > 0 {
+                    warn!("[Webhook]: the outbox is full, {} oldest events are dropped", dropped);
+                }
+                if self.uncommitted >= COMMIT_CHANGES {
+                    self.commit().await;
+                }
+                report(&stats_sender, self.outbox.len(), 0, dropped);
+              }
+              _ = commit_timer.tick(), if self.uncommitted > 0 => {
+                self.commit().await;
+              }
+              _ = sleep_until(next_attempt), if !self.outbox.is_empty() => {
+                // ids of sent events are written first, so they are not reused after a restart
+                if self.uncommitted > 0 {
+                    self.commit().await;
+                }
+                match self.deliver().await {
+                    Ok(delivered) => {
+                        self.outbox.remove(delivered);
+                        self.uncommitted += delivered;
+                        report(&stats_sender, self.outbox.len(), delivered, 0);
+                        retry_delay = MIN_RETRY_DELAY;
+                        next_attempt = Instant::now();
+                    }
+                    Err(err) => {
+                        warn!(
+                            "[Webhook]: unable to deliver events to {}, retrying in {:?}. {}",
+                            self.url, retry_delay, err
+                        );
+                        next_attempt = Instant::now() + retry_delay;
+                        retry_delay = (retry_delay * 2).min(self.max_retry_delay);
+                    }
+                }
+              }
+            }
+        }
+    }
+
+    fn push(&mut self, event: LifecycleEvent) -> usize {
+        let timestamp = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_millis() as u64)
+            .unwrap_or_default();
+        self.uncommitted += 1;
+        self.outbox.push(&self.broker_id, timestamp, event)
+    }
+
+    /// Writes the outbox on a blocking thread. Changes stay uncommitted if the write fails,
+    /// so it's retried with the next tick of the commit timer.
+    async fn commit(&mut self) {
+        let snapshot = match self.outbox.snapshot() {
+            Some(snapshot) => snapshot,
+            None => {
+                self.uncommitted = 0;
+                return;
+            }
+        };
+        let result = spawn_blocking(move || snapshot.write())
+            .await
+            .unwrap_or_else(|err| Err(io::Error::from(err)));
+        match result {
+            Ok(()) => self.uncommitted = 0,
+            Err(err) => error!("[Webhook]: unable to write the outbox. {:?}", err),
+        }
+    }
+
+    /// Sends the oldest events and returns a number of delivered ones.
+    async fn deliver(&self) -> Result<usize, String> {
+        let batch = self.outbox.batch(BATCH_SIZE);
+        self.client
+            .post(&self.url)
+            .json(&batch)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("{:?}", err))?;
+        Ok(batch.len())
+    }
+}
+
+/// An event of a stats message, so the webhook sees the same connects and disconnects
+/// as `$SYS` topics.
+pub fn lifecycle_event(message: &StatsMessage) -> Option<LifecycleEvent> {
+    match message {
+        StatsMessage::ClientConnected {
+            connection,
+            clean_session,
+            ..
+        } => Some(LifecycleEvent::ClientConnected {
+            client_id: connection.client_id.clone(),
+            address: connection.addr.to_string(),
+            clean_session: *clean_session,
+        }),
+        StatsMessage::ClientDisconnected { client_id, reason } => {
+            Some(LifecycleEvent::ClientDisconnected {
+                client_id: client_id.clone(),
+                reason: *reason,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn report(stats_sender: &StatsSender, queued: usize, delivered: usize, dropped: usize) {
+    let message = StatsMessage::WebhookOutbox {
+        queued,
+        delivered,
+        dropped,
+    };
+    if let Err(err) = stats_sender.send(message) {
+        error!(
+            "[Webhook]: unable to send StatsMessage::WebhookOutbox. {:?}",
+            err
+        );
+    }
+}
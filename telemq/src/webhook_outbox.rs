@@ -0,0 +1,226 @@
+//! Events of the webhook waiting to be delivered. The outbox is written to
+//! `webhook_outbox_file` from snapshots, so events survive a restart of the broker.
+use std::{
+    collections::VecDeque,
+    fs::{rename, File},
+    io::{self, Write},
+    path::Path,
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_vec};
+
+use crate::disconnect_reason::DisconnectReason;
+
+/// A lifecycle event of a client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LifecycleEvent {
+    ClientConnected {
+        client_id: String,
+        address: String,
+        clean_session: bool,
+    },
+    ClientDisconnected {
+        client_id: String,
+        reason: DisconnectReason,
+    },
+}
+
+/// An event as it's sent to the webhook.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebhookEvent {
+    /// Increases by one with every event of a broker, a receiver skips ids it has seen.
+    pub id: u64,
+    pub broker_id: String,
+    /// Unix time in milliseconds.
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub event: LifecycleEvent,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct OutboxFile {
+    next_id: u64,
+    events: VecDeque<WebhookEvent>,
+}
+
+pub struct Outbox {
+    /// `None` if events are kept in memory only.
+    file_path: Option<String>,
+    max_size: usize,
+    next_id: u64,
+    events: VecDeque<WebhookEvent>,
+}
+
+impl Outbox {
+    /// Recovers events from the file, the oldest events above `max_size` are dropped.
+    pub fn open(file_path: Option<String>, max_size: usize) -> Self {
+        let OutboxFile {
+            next_id,
+            mut events,
+        } = file_path
+            .as_deref()
+            .map(Self::read_file)
+            .unwrap_or_default();
+        if events.len() > max_size {
+            warn!(
+                "[Webhook]: {} events of the outbox above webhook_outbox_size are dropped",
+                events.len() - max_size
+            );
+            events.drain(..events.len() - max_size);
+        }
+        Outbox {
+            file_path,
+            max_size,
+            next_id,
+            events,
+        }
+    }
+
+    /// Adds an event and returns a number of dropped events: the oldest one is dropped if
+    /// the outbox is full.
+    pub fn push(&mut self, broker_id: &str, timestamp: u64, event: LifecycleEvent) -> usize {
+        self.events.push_back(WebhookEvent {
+            id: self.next_id,
+            broker_id: broker_id.to_string(),
+            timestamp,
+            event,
+        });
+        self.next_id += 1;
+        let dropped = self.events.len().saturating_sub(self.max_size);
+        self.events.drain(..dropped);
+        dropped
+    }
+
+    /// The oldest events, in order they have occurred.
+    pub fn batch(&self, max_len: usize) -> Vec<&WebhookEvent> {
+        self.events.iter().take(max_len).collect()
+    }
+
+    /// Removes the oldest events once they have been delivered.
+    pub fn remove(&mut self, delivered: usize) {
+        self.events.drain(..delivered.min(self.events.len()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// A copy of the outbox to be written to its file, `None` if the outbox is not persistent.
+    pub fn snapshot(&self) -> Option<OutboxSnapshot> {
+        Some(OutboxSnapshot {
+            file_path: self.file_path.clone()?,
+            file: OutboxFile {
+                next_id: self.next_id,
+                events: self.events.clone(),
+            },
+        })
+    }
+
+    fn read_file(file_path: &str) -> OutboxFile {
+        match File::open(Path::new(file_path)) {
+            Ok(reader) => match from_reader(reader) {
+                Ok(outbox) => {
+                    info!("[Webhook]: recovered the outbox from {}", file_path);
+                    outbox
+                }
+                Err(err) => {
+                    error!(
+                        "[Webhook]: unable to parse the outbox {}. {:?}. Continue using an empty outbox.",
+                        file_path, err
+                    );
+                    OutboxFile::default()
+                }
+            },
+            Err(_) => OutboxFile::default(),
+        }
+    }
+}
+
+/// The outbox at some moment. It's written with blocking IO, so it's moved to
+/// `spawn_blocking`.
+pub struct OutboxSnapshot {
+    file_path: String,
+    file: OutboxFile,
+}
+
+impl OutboxSnapshot {
+    pub fn write(self) -> io::Result<()> {
+        let data = to_vec(&self.file).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unable to serialize webhook events",
+            )
+        })?;
+        // a crash in the middle of a write keeps the previous outbox
+        let tmp_path = format!("{}.tmp", self.file_path);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        rename(tmp_path, self.file_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connected(client_id: &str) -> LifecycleEvent {
+        LifecycleEvent::ClientConnected {
+            client_id: client_id.into(),
+            address: "127.0.0.1:40000".into(),
+            clean_session: true,
+        }
+    }
+
+    #[test]
+    fn oldest_events_are_dropped_once_full() {
+        let mut outbox = Outbox::open(None, 2);
+        assert_eq!(outbox.push("node1", 1, connected("a")), 0);
+        assert_eq!(outbox.push("node1", 2, connected("b")), 0);
+        assert_eq!(outbox.push("node1", 3, connected("c")), 1);
+
+        let ids: Vec<u64> = outbox.batch(10).iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        outbox.remove(1);
+        assert_eq!(outbox.batch(10)[0].event, connected("c"));
+    }
+
+    #[test]
+    fn events_and_ids_survive_restart() {
+        let path = std::env::temp_dir()
+            .join(format!("telemq_webhook_outbox_{}.json", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let mut outbox = Outbox::open(Some(path.clone()), 10);
+        outbox.push("node1", 1, connected("a"));
+        outbox.push(
+            "node1",
+            2,
+            LifecycleEvent::ClientDisconnected {
+                client_id: "a".into(),
+                reason: DisconnectReason::KeepAliveTimeout,
+            },
+        );
+        outbox.remove(1);
+        outbox.snapshot().unwrap().write().unwrap();
+
+        let mut outbox = Outbox::open(Some(path.clone()), 10);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.batch(1)[0].id, 1);
+        outbox.push("node1", 3, connected("a"));
+        assert_eq!(outbox.batch(10)[1].id, 2);
+        assert!(Outbox::open(None, 10).snapshot().is_none());
+
+        let json = serde_json::to_value(outbox.batch(1)[0]).unwrap();
+        assert_eq!(json["event"], "client_disconnected");
+        assert_eq!(json["reason"], "keep_alive_timeout");
+    }
+}
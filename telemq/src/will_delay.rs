@@ -0,0 +1,151 @@
+//! Delayed publication of will messages.
+//!
+//! With `will_delay_interval` set, a will of a connection which terminated abnormally is
+//! published only once the interval elapses, and it's discarded if its client reconnects
+//! within the interval. A device which drops off a flaky network for a moment doesn't
+//! alarm its observers then.
+//!
+//! Deadlines are `tokio::time::Instant`s, so tests control them with `tokio::time::pause`
+//! and `tokio::time::advance`.
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use mqtt_packets::v_3_1_1::ControlPacket;
+use tokio::time::Instant;
+
+type ClientId = String;
+
+/// A deadline and a sequence number, so wills scheduled at the same instant are kept apart.
+type Deadline = (Instant, u64);
+
+#[derive(Debug)]
+pub struct DelayedWills {
+    delay: Duration,
+    next_seq: u64,
+    deadlines: BTreeMap<Deadline, ClientId>,
+    pending: HashMap<ClientId, (Deadline, ControlPacket)>,
+}
+
+impl DelayedWills {
+    pub fn new(delay: Duration) -> Self {
+        DelayedWills {
+            delay,
+            next_seq: 0,
+            deadlines: BTreeMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Schedules a will of a client, replaces a will which is already pending for it.
+    pub fn schedule(&mut self, client_id: ClientId, will_packet: ControlPacket) {
+        self.cancel(&client_id);
+        let deadline = (Instant::now() + self.delay, self.next_seq);
+        self.next_seq += 1;
+        self.deadlines.insert(deadline, client_id.clone());
+        self.pending.insert(client_id, (deadline, will_packet));
+    }
+
+    /// Discards a pending will of a client, `true` if there was one.
+    pub fn cancel(&mut self, client_id: &str) -> bool {
+        match self.pending.remove(client_id) {
+            Some((deadline, _)) => {
+                self.deadlines.remove(&deadline);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// When the earliest pending will is due.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.keys().next().map(|(instant, _)| *instant)
+    }
+
+    /// Removes wills which are due by `now`, in order of their deadlines.
+    pub fn take_due(&mut self, now: Instant) -> Vec<ControlPacket> {
+        let mut due = Vec::new();
+        while let Some(entry) = self.deadlines.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let client_id = entry.remove();
+            if let Some((_, will_packet)) = self.pending.remove(&client_id) {
+                due.push(will_packet);
+            }
+        }
+        due
+    }
+
+    /// Removes all pending wills, e.g. to publish them before the broker shuts down.
+    pub fn take_all(&mut self) -> Vec<ControlPacket> {
+        self.take_due(Instant::now() + self.delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_packets::v_3_1_1::{builders::PublishPacketBuilder, topic::Topic, variable::Variable};
+    use tokio::time::advance;
+
+    fn will(topic: &str) -> ControlPacket {
+        let mut builder = PublishPacketBuilder::new();
+        builder.with_topic(Topic::make_from_string(topic));
+        builder.build()
+    }
+
+    fn topics(packets: Vec<ControlPacket>) -> Vec<String> {
+        packets
+            .into_iter()
+            .map(|packet| match packet.variable {
+                Variable::Publish(variable) => variable.topic_name.original,
+                _ => panic!("Publish is expected"),
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wills_are_published_once_delay_elapses() {
+        let mut wills = DelayedWills::new(Duration::from_secs(10));
+        wills.schedule("a".into(), will("a/will"));
+        advance(Duration::from_secs(5)).await;
+        wills.schedule("b".into(), will("b/will"));
+
+        assert!(wills.take_due(Instant::now()).is_empty());
+        advance(Duration::from_secs(5)).await;
+        assert_eq!(topics(wills.take_due(Instant::now())), vec!["a/will"]);
+        assert_eq!(
+            wills.next_deadline(),
+            Some(Instant::now() + Duration::from_secs(5))
+        );
+        advance(Duration::from_secs(5)).await;
+        assert_eq!(topics(wills.take_due(Instant::now())), vec!["b/will"]);
+        assert_eq!(wills.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_cancels_will() {
+        let mut wills = DelayedWills::new(Duration::from_secs(10));
+        wills.schedule("a".into(), will("a/will"));
+
+        assert!(wills.cancel("a"));
+        assert!(!wills.cancel("a"));
+        advance(Duration::from_secs(10)).await;
+        assert!(wills.take_due(Instant::now()).is_empty());
+        assert_eq!(wills.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rescheduled_will_replaces_pending_one() {
+        let mut wills = DelayedWills::new(Duration::from_secs(10));
+        wills.schedule("a".into(), will("a/first"));
+        advance(Duration::from_secs(5)).await;
+        wills.schedule("a".into(), will("a/second"));
+
+        advance(Duration::from_secs(5)).await;
+        assert!(wills.take_due(Instant::now()).is_empty());
+        assert_eq!(topics(wills.take_all()), vec!["a/second"]);
+    }
+}
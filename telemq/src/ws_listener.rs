@@ -1,86 +1,194 @@
 use crate::{
-    authenticator::Authenticator, connection::Connection, control::ControlSender,
-    session_state_store::SessionStateStore, stats::StatsSender,
-};
-use log::{error, info};
-use mqtt_packets::v_3_1_1::ControlPacketCodec;
-use std::{
-    net::SocketAddr,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+    connection::Connection,
+    connection_config::ConnectionConfig,
+    connection_gate::{
+        is_allowed_by_gate, ConnectionGate, ConnectionMetadata, ConnectionTransport,
     },
-    time,
+    connection_limit::ConnectionLimit,
+    connection_watchdog::ConnectionWatchdog,
+    control::ControlSender,
+    handover::bind_tcp,
+    mqtt_codec::MqttCodec,
+    transport_bytes::TransportBytes,
+};
+use hyper::{
+    server::conn::Http,
+    service::{service_fn, Service},
+    Body, Request,
 };
-use tokio::{spawn, sync::RwLock};
-use warp::{self, filters::ws::WebSocket, Filter, Reply};
+use log::{debug, error, info};
+use std::{convert::Infallible, io, net::SocketAddr, sync::Arc, time};
+use tokio::{net::TcpListener, spawn, time::Instant};
+use warp::{
+    self,
+    filters::{path::FullPath, ws::WebSocket},
+    http::StatusCode,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+/// Path of a health check of websocket listeners, load balancers probe it with plain HTTP.
+pub const HEALTH_PATH: &str = "/healthz";
+
+/// Websocket upgrade settings shared by WS and WSS listeners.
+#[derive(Clone, Debug)]
+pub struct WsOptions {
+    /// Upgrades are accepted on this path only.
+    pub path: String,
+    /// Subprotocols the broker agrees to, in order of preference.
+    pub subprotocols: Vec<String>,
+    pub keep_alive: WsKeepAlive,
+}
+
+/// Websocket level keep alive of connections. Some clients, e.g. in browsers, rely on ping
+/// and pong frames rather than on PINGREQ to keep a connection open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WsKeepAlive {
+    /// A ping is sent once nothing has been received from a client for this long.
+    pub ping_interval: Option<time::Duration>,
+    /// Ping and pong frames of a client count as activity for its keep alive timeout.
+    pub pong_as_activity: bool,
+}
+
+impl WsKeepAlive {
+    /// The latest activity of a client a keep alive timeout is counted from.
+    pub fn last_activity(&self, last_packet: Instant, last_frame: Option<Instant>) -> Instant {
+        match last_frame {
+            Some(last_frame) if self.pong_as_activity => last_packet.max(last_frame),
+            _ => last_packet,
+        }
+    }
+
+    /// When a next ping is due, `None` if pings are not sent.
+    pub fn next_ping(
+        &self,
+        last_packet: Instant,
+        last_frame: Option<Instant>,
+        last_ping: Instant,
+    ) -> Option<Instant> {
+        let last_seen = last_frame.map_or(last_packet, |last_frame| last_packet.max(last_frame));
+        self.ping_interval
+            .map(|ping_interval| last_seen.max(last_ping) + ping_interval)
+    }
+}
 
 pub struct WsListener;
 
 impl WsListener {
     pub fn bind(
         addr: SocketAddr,
-        connections_number: Arc<AtomicUsize>,
-        authenticator: Arc<RwLock<Authenticator>>,
-        control_sender: ControlSender,
-        stats_sender: StatsSender,
-        inactivity_interval: time::Duration,
-        state_store: Arc<RwLock<SessionStateStore>>,
-        max_connections: usize,
-        max_subs_per_client: Option<usize>,
-    ) {
+        connection_gate: Option<Arc<dyn ConnectionGate>>,
+        connection_limit: Arc<ConnectionLimit>,
+        connection_config: ConnectionConfig,
+        transport_bytes: Option<Arc<TransportBytes>>,
+        ws_options: WsOptions,
+    ) -> io::Result<()> {
+        let listener = bind_tcp(addr, false)?;
         spawn(async move {
-            let routes = warp::ws()
-                .and(warp::addr::remote())
-                .and(with_telemq(TeleMQParams::new(
-                    authenticator,
-                    control_sender,
-                    stats_sender,
-                    inactivity_interval,
-                    state_store,
-                    connections_number,
-                    max_connections,
-                    max_subs_per_client,
-                )))
+            let health = health_route(connection_config.control_sender.clone());
+            let subprotocols = ws_options.subprotocols;
+            let upgrade = warp::ws()
+                .and(upgrade_path(ws_options.path))
+                .and(warp::ext::get::<PeerAddr>())
+                .and(warp::header::optional::<String>("sec-websocket-protocol"))
+                .and(with_telemq(TeleMQParams {
+                    connection_config,
+                    connection_limit,
+                    transport_bytes,
+                    ws_keep_alive: ws_options.keep_alive,
+                }))
                 .map(
-                    |ws: warp::ws::Ws, addr: Option<SocketAddr>, telemq: TeleMQParams| {
-                        let addr = addr.unwrap().clone();
-                        if telemq
-                            .connections_number
-                            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |prev_value| {
-                                if prev_value >= telemq.max_connections {
-                                    None
-                                } else {
-                                    Some(prev_value + 1)
-                                }
-                            })
-                            .is_err()
-                        {
+                    move |ws: warp::ws::Ws,
+                          PeerAddr(addr): PeerAddr,
+                          requested: Option<String>,
+                          telemq: TeleMQParams| {
+                        if !telemq.connection_limit.try_acquire() {
                             return warp::http::StatusCode::from_u16(560)
                                 .unwrap()
                                 .into_response();
                         }
+                        let subprotocol =
+                            negotiate_subprotocol(requested.as_deref(), &subprotocols);
                         // And then our closure will be called when it completes...
-                        ws.on_upgrade(move |websocket| async move {
-                            peer_process(
-                                websocket,
-                                addr,
-                                telemq.authenticator,
-                                telemq.control_sender,
-                                telemq.stats_sender,
-                                telemq.inactivity_interval,
-                                telemq.state_store,
-                                telemq.max_subs_per_client,
-                            )
-                            .await;
-                            telemq.connections_number.fetch_sub(1, Ordering::Relaxed);
-                        })
-                        .into_response()
+                        let response = ws
+                            .on_upgrade(move |websocket| async move {
+                                let config = telemq.connection_config;
+                                let watchdog = ConnectionWatchdog::new(
+                                    addr,
+                                    &config.control_sender,
+                                    &config.stats_sender,
+                                );
+                                let connection_task = spawn(peer_process(
+                                    websocket,
+                                    addr,
+                                    telemq.transport_bytes,
+                                    telemq.ws_keep_alive,
+                                    config,
+                                ));
+                                watchdog.watch(connection_task).await;
+                                telemq.connection_limit.release();
+                            })
+                            .into_response();
+                        with_subprotocol(response, subprotocol)
                     },
-                )
-                .map(|reply| warp::reply::with_header(reply, "Sec-WebSocket-Protocol", "mqtt"));
+                );
+
+            serve(listener, connection_gate, warp::service(health.or(upgrade))).await;
+        });
+
+        Ok(())
+    }
+}
+
+/// Address of a client, put into requests as websocket listeners accept connections
+/// themselves rather than via warp.
+#[derive(Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
 
-            warp::serve(routes).run(addr).await;
+/// Accepts connections and serves HTTP on them. Connections a gate rejects are closed before
+/// any request is read.
+async fn serve<S>(
+    listener: TcpListener,
+    connection_gate: Option<Arc<dyn ConnectionGate>>,
+    service: S,
+) where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!(
+                    "[WS Listener Worker] failed to accept a connection {:?}",
+                    err
+                );
+                continue;
+            }
+        };
+        if !is_allowed_by_gate(
+            connection_gate.as_deref(),
+            &ConnectionMetadata::from_ws(&stream, addr),
+        ) {
+            continue;
+        }
+        let service = service.clone();
+        spawn(async move {
+            let service = service_fn(move |mut request| {
+                request.extensions_mut().insert(PeerAddr(addr));
+                service.clone().call(request)
+            });
+            if let Err(err) = Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+            {
+                debug!(
+                    "[WS Listener Worker] connection {:?} has failed. {:?}",
+                    addr, err
+                );
+            }
         });
     }
 }
@@ -88,25 +196,20 @@ impl WsListener {
 async fn peer_process(
     websocket: WebSocket,
     addr: SocketAddr,
-    authenticator: Arc<RwLock<Authenticator>>,
-    control_sender: ControlSender,
-    stats_sender: StatsSender,
-    inactivity_interval: time::Duration,
-    state_store: Arc<RwLock<SessionStateStore>>,
-    max_subs_per_client: Option<usize>,
+    transport_bytes: Option<Arc<TransportBytes>>,
+    ws_keep_alive: WsKeepAlive,
+    config: ConnectionConfig,
 ) {
     info!("new TCP connection from {:?}", addr);
 
     let connection = match Connection::new_ws(
         websocket,
-        ControlPacketCodec::new(),
+        MqttCodec::new(),
         addr,
-        control_sender,
-        stats_sender,
-        authenticator,
-        inactivity_interval,
-        state_store,
-        max_subs_per_client,
+        ConnectionTransport::Ws,
+        transport_bytes,
+        ws_keep_alive,
+        config,
     )
     .await
     .map_err(|err| format!("{:?}", err))
@@ -126,6 +229,56 @@ async fn peer_process(
     }
 }
 
+/// `GET /healthz` replies `200 ok` while the broker is running and `503` once it's
+/// shutting down.
+pub fn health_route(
+    control_sender: ControlSender,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::get().and(warp::path!("healthz")).map(move || {
+        if control_sender.is_closed() {
+            warp::reply::with_status("unavailable", StatusCode::SERVICE_UNAVAILABLE).into_response()
+        } else {
+            "ok".into_response()
+        }
+    })
+}
+
+/// Rejects requests to any path but `path`.
+pub fn upgrade_path(path: String) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::full()
+        .and_then(move |full_path: FullPath| {
+            let matches = full_path.as_str() == path;
+            async move {
+                if matches {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Picks the most preferred of `supported` subprotocols a client has listed in its
+/// `Sec-WebSocket-Protocol` header. A client which hasn't asked for a subprotocol gets none.
+pub fn negotiate_subprotocol(requested: Option<&str>, supported: &[String]) -> Option<String> {
+    let requested: Vec<&str> = requested?.split(',').map(str::trim).collect();
+    supported
+        .iter()
+        .find(|subprotocol| requested.contains(&subprotocol.as_str()))
+        .cloned()
+}
+
+pub fn with_subprotocol(response: Response, subprotocol: Option<String>) -> Response {
+    match subprotocol {
+        Some(subprotocol) => {
+            warp::reply::with_header(response, "Sec-WebSocket-Protocol", subprotocol)
+                .into_response()
+        }
+        None => response,
+    }
+}
+
 fn with_telemq(
     telemq: TeleMQParams,
 ) -> impl Filter<Extract = (TeleMQParams,), Error = std::convert::Infallible> + Clone {
@@ -134,36 +287,139 @@ fn with_telemq(
 
 #[derive(Clone)]
 struct TeleMQParams {
-    authenticator: Arc<RwLock<Authenticator>>,
-    control_sender: ControlSender,
-    inactivity_interval: time::Duration,
-    stats_sender: StatsSender,
-    state_store: Arc<RwLock<SessionStateStore>>,
-    connections_number: Arc<AtomicUsize>,
-    max_connections: usize,
-    max_subs_per_client: Option<usize>,
+    connection_config: ConnectionConfig,
+    connection_limit: Arc<ConnectionLimit>,
+    transport_bytes: Option<Arc<TransportBytes>>,
+    ws_keep_alive: WsKeepAlive,
 }
 
-impl TeleMQParams {
-    fn new(
-        authenticator: Arc<RwLock<Authenticator>>,
-        control_sender: ControlSender,
-        stats_sender: StatsSender,
-        inactivity_interval: time::Duration,
-        state_store: Arc<RwLock<SessionStateStore>>,
-        connections_number: Arc<AtomicUsize>,
-        max_connections: usize,
-        max_subs_per_client: Option<usize>,
-    ) -> Self {
-        TeleMQParams {
-            authenticator,
-            control_sender,
-            inactivity_interval,
-            stats_sender,
-            state_store,
-            connections_number,
-            max_connections,
-            max_subs_per_client,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn subprotocol_is_negotiated_by_broker_preference() {
+        let supported = vec!["mqtt".to_string(), "mqttv3.1".to_string()];
+
+        assert_eq!(
+            negotiate_subprotocol(Some("mqttv3.1, mqtt"), &supported),
+            Some("mqtt".to_string())
+        );
+        assert_eq!(
+            negotiate_subprotocol(Some("mqttv3.1"), &supported),
+            Some("mqttv3.1".to_string())
+        );
+        assert_eq!(negotiate_subprotocol(Some("wamp"), &supported), None);
+        assert_eq!(negotiate_subprotocol(None, &supported), None);
+    }
+
+    #[test]
+    fn ws_frames_are_activity_if_configured() {
+        let last_packet = Instant::now();
+        let last_frame = last_packet + time::Duration::from_secs(10);
+        let mut keep_alive = WsKeepAlive {
+            ping_interval: None,
+            pong_as_activity: true,
+        };
+
+        assert_eq!(
+            keep_alive.last_activity(last_packet, Some(last_frame)),
+            last_frame
+        );
+        assert_eq!(
+            keep_alive.last_activity(last_frame, Some(last_packet)),
+            last_frame
+        );
+        assert_eq!(keep_alive.last_activity(last_packet, None), last_packet);
+
+        keep_alive.pong_as_activity = false;
+        assert_eq!(
+            keep_alive.last_activity(last_packet, Some(last_frame)),
+            last_packet
+        );
+    }
+
+    #[test]
+    fn pings_are_sent_on_idle() {
+        let ping_interval = time::Duration::from_secs(30);
+        let start = Instant::now();
+        let keep_alive = WsKeepAlive {
+            ping_interval: Some(ping_interval),
+            pong_as_activity: false,
+        };
+
+        assert_eq!(
+            keep_alive.next_ping(start, None, start),
+            Some(start + ping_interval)
+        );
+        // a pong postpones a next ping even if it's not activity for keep alive
+        let pong = start + time::Duration::from_secs(40);
+        assert_eq!(
+            keep_alive.next_ping(start, Some(pong), start + ping_interval),
+            Some(pong + ping_interval)
+        );
+        // pings are repeated while a client stays silent
+        let ping = start + ping_interval;
+        assert_eq!(
+            keep_alive.next_ping(start, None, ping),
+            Some(ping + ping_interval)
+        );
+        assert_eq!(WsKeepAlive::default().next_ping(start, None, start), None);
+    }
+
+    #[tokio::test]
+    async fn health_check_and_upgrade_path() {
+        let (control_sender, control_receiver) = unbounded_channel();
+        let routes =
+            health_route(control_sender).or(upgrade_path("/mqtt".into()).map(|| "upgrade"));
+
+        let reply = warp::test::request().path("/healthz").reply(&routes).await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        let reply = warp::test::request().path("/mqtt").reply(&routes).await;
+        assert_eq!(reply.body(), "upgrade");
+        let reply = warp::test::request().path("/").reply(&routes).await;
+        assert_eq!(reply.status(), StatusCode::NOT_FOUND);
+
+        drop(control_receiver);
+        let reply = warp::test::request().path("/healthz").reply(&routes).await;
+        assert_eq!(reply.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn connections_are_checked_by_gate_before_upgrade() {
+        use crate::connection_gate::GateDecision;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let request = |addr: SocketAddr| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /healthz HTTP/1.1\r\nHost: broker\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response).await;
+            response
+        };
+        let gate = |metadata: &ConnectionMetadata| {
+            assert_eq!(metadata.transport, ConnectionTransport::Ws);
+            if metadata.peer_addr.ip().is_loopback() {
+                GateDecision::Reject("loopback".into())
+            } else {
+                GateDecision::Accept
+            }
+        };
+        let (control_sender, _control_receiver) = unbounded_channel();
+        let service = warp::service(health_route(control_sender));
+
+        let listener = bind_tcp("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(serve(listener, Some(Arc::new(gate)), service.clone()));
+        assert_eq!(request(addr).await, "");
+
+        let listener = bind_tcp("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(serve(listener, None, service));
+        assert!(request(addr).await.starts_with("HTTP/1.1 200"));
     }
 }
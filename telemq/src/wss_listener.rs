@@ -1,120 +1,153 @@
 use crate::{
-  authenticator::Authenticator, connection::Connection, control::ControlSender,
-  session_state_store::SessionStateStore, stats::StatsSender,
-};
-use log::{error, info};
-use mqtt_packets::v_3_1_1::ControlPacketCodec;
-use std::{
-  net::SocketAddr,
-  sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
+  connection::Connection,
+  connection_config::ConnectionConfig,
+  connection_gate::{is_allowed_by_gate, ConnectionGate, ConnectionMetadata, ConnectionTransport},
+  connection_limit::ConnectionLimit,
+  connection_watchdog::ConnectionWatchdog,
+  handover::bind_tcp,
+  mqtt_codec::MqttCodec,
+  tls_listener::SharedServerConfig,
+  transport_bytes::TransportBytes,
+  ws_listener::{
+    health_route, negotiate_subprotocol, upgrade_path, with_subprotocol, PeerAddr, WsKeepAlive,
+    WsOptions,
   },
-  time,
 };
-use tokio::{spawn, sync::RwLock};
+use hyper::{
+  server::conn::Http,
+  service::{service_fn, Service},
+};
+use log::{debug, error, info};
+use std::{io, net::SocketAddr, sync::Arc};
+use tokio::spawn;
+use tokio_rustls::TlsAcceptor;
 use warp::{self, filters::ws::WebSocket, Filter, Reply};
 
+/// The only application protocol served over TLS.
+pub const ALPN_HTTP_1_1: &[u8] = b"http/1.1";
+
 pub struct WssListener;
 
 impl WssListener {
   pub fn bind(
     addr: SocketAddr,
-    connections_number: Arc<AtomicUsize>,
-    authenticator: Arc<RwLock<Authenticator>>,
-    control_sender: ControlSender,
-    stats_sender: StatsSender,
-    inactivity_interval: time::Duration,
-    state_store: Arc<RwLock<SessionStateStore>>,
-    max_connections: usize,
-    max_subs_per_client: Option<usize>,
-    cert_path: String,
-    key_path: String,
-  ) {
+    connection_gate: Option<Arc<dyn ConnectionGate>>,
+    connection_limit: Arc<ConnectionLimit>,
+    connection_config: ConnectionConfig,
+    transport_bytes: Option<Arc<TransportBytes>>,
+    ws_options: WsOptions,
+    tls_config: SharedServerConfig,
+  ) -> io::Result<()> {
+    let listener = bind_tcp(addr, false)?;
     spawn(async move {
-      let routes = warp::ws()
-        .and(warp::addr::remote())
-        .and(with_telemq(TeleMQParams::new(
-          authenticator,
-          control_sender,
-          stats_sender,
-          inactivity_interval,
-          state_store,
-          connections_number,
-          max_connections,
-          max_subs_per_client,
-        )))
+      let health = health_route(connection_config.control_sender.clone());
+      let subprotocols = ws_options.subprotocols;
+      let upgrade = warp::ws()
+        .and(upgrade_path(ws_options.path))
+        .and(warp::ext::get::<PeerAddr>())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(with_telemq(TeleMQParams {
+          connection_config,
+          connection_limit,
+          transport_bytes,
+          ws_keep_alive: ws_options.keep_alive,
+        }))
         .map(
-          |ws: warp::ws::Ws, addr: Option<SocketAddr>, telemq: TeleMQParams| {
+          move |ws: warp::ws::Ws,
+                PeerAddr(addr): PeerAddr,
+                requested: Option<String>,
+                telemq: TeleMQParams| {
             info!("[WSS Listener Worker] new connection {:?}", addr);
-            let addr = addr.unwrap().clone();
-            if telemq
-              .connections_number
-              .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |prev_value| {
-                if prev_value >= telemq.max_connections {
-                  None
-                } else {
-                  Some(prev_value + 1)
-                }
-              })
-              .is_err()
-            {
+            if !telemq.connection_limit.try_acquire() {
               return warp::http::StatusCode::from_u16(560)
                 .unwrap()
                 .into_response();
             }
+            let subprotocol = negotiate_subprotocol(requested.as_deref(), &subprotocols);
             // And then our closure will be called when it completes...
-            ws.on_upgrade(move |websocket| async move {
+            let response = ws.on_upgrade(move |websocket| async move {
               println!("WSS upgrade");
-              peer_process(
+              let config = telemq.connection_config;
+              let watchdog =
+                ConnectionWatchdog::new(addr, &config.control_sender, &config.stats_sender);
+              let connection_task = spawn(peer_process(
                 websocket,
                 addr,
-                telemq.authenticator,
-                telemq.control_sender,
-                telemq.stats_sender,
-                telemq.inactivity_interval,
-                telemq.state_store,
-                telemq.max_subs_per_client,
-              )
-              .await;
-              telemq.connections_number.fetch_sub(1, Ordering::Relaxed);
+                telemq.transport_bytes,
+                telemq.ws_keep_alive,
+                config,
+              ));
+              watchdog.watch(connection_task).await;
+              telemq.connection_limit.release();
             })
-            .into_response()
+            .into_response();
+            with_subprotocol(response, subprotocol)
           },
-        )
-        .map(|reply| warp::reply::with_header(reply, "Sec-WebSocket-Protocol", "mqtt"));
-      warp::serve(routes)
-        .tls()
-        .cert_path(cert_path)
-        .key_path(key_path)
-        .run(addr)
-        .await;
+        );
+      let service = warp::service(health.or(upgrade));
+      loop {
+        let (stream, addr) = match listener.accept().await {
+          Ok(accepted) => accepted,
+          Err(err) => {
+            error!("[WSS Listener Worker] failed to accept a connection {:?}", err);
+            continue;
+          }
+        };
+        // a reloaded certificate is taken by the next handshake
+        let acceptor = TlsAcceptor::from(tls_config.current());
+        let service = service.clone();
+        let connection_gate = connection_gate.clone();
+        spawn(async move {
+          let stream = match acceptor.accept(stream).await {
+            Ok(stream) => stream,
+            Err(err) => {
+              debug!("TLS handshake with {:?} has failed. {:?}", addr, err);
+              return;
+            }
+          };
+          if !is_allowed_by_gate(
+            connection_gate.as_deref(),
+            &ConnectionMetadata::from_wss(&stream, addr),
+          ) {
+            return;
+          }
+          let service = service_fn(move |mut request| {
+            request.extensions_mut().insert(PeerAddr(addr));
+            service.clone().call(request)
+          });
+          if let Err(err) = Http::new()
+            .http1_only(true)
+            .serve_connection(stream, service)
+            .with_upgrades()
+            .await
+          {
+            debug!("[WSS Listener Worker] connection {:?} has failed. {:?}", addr, err);
+          }
+        });
+      }
     });
+
+    Ok(())
   }
 }
 
 async fn peer_process(
   websocket: WebSocket,
   addr: SocketAddr,
-  authenticator: Arc<RwLock<Authenticator>>,
-  control_sender: ControlSender,
-  stats_sender: StatsSender,
-  inactivity_interval: time::Duration,
-  state_store: Arc<RwLock<SessionStateStore>>,
-  max_subs_per_client: Option<usize>,
+  transport_bytes: Option<Arc<TransportBytes>>,
+  ws_keep_alive: WsKeepAlive,
+  config: ConnectionConfig,
 ) {
   info!("new TCP connection from {:?}", addr);
 
   let connection = match Connection::new_ws(
     websocket,
-    ControlPacketCodec::new(),
+    MqttCodec::new(),
     addr,
-    control_sender,
-    stats_sender,
-    authenticator,
-    inactivity_interval,
-    state_store,
-    max_subs_per_client,
+    ConnectionTransport::Wss,
+    transport_bytes,
+    ws_keep_alive,
+    config,
   )
   .await
   .map_err(|err| format!("{:?}", err))
@@ -142,36 +175,8 @@ fn with_telemq(
 
 #[derive(Clone)]
 struct TeleMQParams {
-  authenticator: Arc<RwLock<Authenticator>>,
-  control_sender: ControlSender,
-  inactivity_interval: time::Duration,
-  stats_sender: StatsSender,
-  state_store: Arc<RwLock<SessionStateStore>>,
-  connections_number: Arc<AtomicUsize>,
-  max_connections: usize,
-  max_subs_per_client: Option<usize>,
-}
-
-impl TeleMQParams {
-  fn new(
-    authenticator: Arc<RwLock<Authenticator>>,
-    control_sender: ControlSender,
-    stats_sender: StatsSender,
-    inactivity_interval: time::Duration,
-    state_store: Arc<RwLock<SessionStateStore>>,
-    connections_number: Arc<AtomicUsize>,
-    max_connections: usize,
-    max_subs_per_client: Option<usize>,
-  ) -> Self {
-    TeleMQParams {
-      authenticator,
-      control_sender,
-      inactivity_interval,
-      stats_sender,
-      state_store,
-      connections_number,
-      max_connections,
-      max_subs_per_client,
-    }
-  }
+  connection_config: ConnectionConfig,
+  connection_limit: Arc<ConnectionLimit>,
+  transport_bytes: Option<Arc<TransportBytes>>,
+  ws_keep_alive: WsKeepAlive,
 }